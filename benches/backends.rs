@@ -0,0 +1,239 @@
+//! The same workloads run through each queue a caller can choose between: a
+//! hopper channel, a single-threaded `hopper::local::Queue` and, as the
+//! baselines, `std::sync::mpsc` and `crossbeam-channel`. The local queue has
+//! one thread, so it sits out the workloads with more than one.
+//!
+//! Runs on stable: `cargo bench --bench backends`.
+
+#[macro_use]
+extern crate criterion;
+extern crate crossbeam_channel;
+extern crate hopper;
+extern crate tempdir;
+
+use criterion::{Criterion, Throughput};
+use std::cmp;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+const ITEMS: u64 = 10_000;
+const BURST_SENDERS: u64 = 4;
+const BURST: u64 = 2_500;
+
+// Receive `total` items from a hopper Receiver, waiting on the Senders.
+fn drain(rcv: &mut hopper::Receiver<u64>, total: u64) {
+    let mut got = 0;
+    while got < total {
+        let max = cmp::min(1024, total - got) as usize;
+        got += rcv.recv_batch(max, Duration::from_millis(10)).len() as u64;
+    }
+}
+
+fn one_to_one(c: &mut Criterion) {
+    let mut group = c.benchmark_group("one_to_one");
+    group.throughput(Throughput::Elements(ITEMS));
+
+    group.bench_function("hopper_channel", |b| {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (snd, mut rcv) = hopper::channel("bench_1to1", dir.path()).unwrap();
+        b.iter(|| {
+            let mut snd = snd.clone();
+            let jh = thread::spawn(move || for i in 0..ITEMS {
+                snd.send(i);
+            });
+            drain(&mut rcv, ITEMS);
+            jh.join().unwrap();
+        });
+    });
+
+    group.bench_function("hopper_local", |b| {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let q = hopper::local::queue("bench_1to1", dir.path(), 1_048_576).unwrap();
+        b.iter(|| {
+            for i in 0..ITEMS {
+                q.send(i);
+            }
+            assert_eq!(ITEMS as usize, q.iter().count());
+        });
+    });
+
+    group.bench_function("std_mpsc", |b| {
+        let (snd, rcv) = mpsc::channel();
+        b.iter(|| {
+            let snd = snd.clone();
+            let jh = thread::spawn(move || for i in 0..ITEMS {
+                snd.send(i).unwrap();
+            });
+            for _ in 0..ITEMS {
+                rcv.recv().unwrap();
+            }
+            jh.join().unwrap();
+        });
+    });
+
+    group.bench_function("crossbeam", |b| {
+        let (snd, rcv) = crossbeam_channel::unbounded();
+        b.iter(|| {
+            let snd = snd.clone();
+            let jh = thread::spawn(move || for i in 0..ITEMS {
+                snd.send(i).unwrap();
+            });
+            for _ in 0..ITEMS {
+                rcv.recv().unwrap();
+            }
+            jh.join().unwrap();
+        });
+    });
+
+    group.finish();
+}
+
+fn n_to_one_bursty(c: &mut Criterion) {
+    let mut group = c.benchmark_group("n_to_one_bursty");
+    group.throughput(Throughput::Elements(BURST_SENDERS * BURST));
+
+    group.bench_function("hopper_channel", |b| {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (snd, mut rcv) = hopper::channel("bench_nto1", dir.path()).unwrap();
+        b.iter(|| {
+            let joins: Vec<_> = (0..BURST_SENDERS)
+                .map(|_| {
+                    let mut snd = snd.clone();
+                    thread::spawn(move || for i in 0..BURST {
+                        snd.send(i);
+                    })
+                })
+                .collect();
+            drain(&mut rcv, BURST_SENDERS * BURST);
+            for jh in joins {
+                jh.join().unwrap();
+            }
+        });
+    });
+
+    group.bench_function("hopper_sharded", |b| {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (snd, mut rcv) = hopper::ChannelBuilder::new("bench_nto1_sharded", dir.path())
+            .build_sharded(BURST_SENDERS as usize)
+            .unwrap();
+        b.iter(|| {
+            let joins: Vec<_> = (0..BURST_SENDERS)
+                .map(|_| {
+                    let mut snd = snd.clone();
+                    thread::spawn(move || for i in 0..BURST {
+                        snd.send(i);
+                    })
+                })
+                .collect();
+            let mut got = 0;
+            while got < BURST_SENDERS * BURST {
+                got += rcv.recv_batch(1024, Duration::from_millis(10)).len() as u64;
+            }
+            for jh in joins {
+                jh.join().unwrap();
+            }
+        });
+    });
+
+    group.bench_function("std_mpsc", |b| {
+        let (snd, rcv) = mpsc::channel();
+        b.iter(|| {
+            let joins: Vec<_> = (0..BURST_SENDERS)
+                .map(|_| {
+                    let snd = snd.clone();
+                    thread::spawn(move || for i in 0..BURST {
+                        snd.send(i).unwrap();
+                    })
+                })
+                .collect();
+            for _ in 0..BURST_SENDERS * BURST {
+                rcv.recv().unwrap();
+            }
+            for jh in joins {
+                jh.join().unwrap();
+            }
+        });
+    });
+
+    group.bench_function("crossbeam", |b| {
+        let (snd, rcv) = crossbeam_channel::unbounded();
+        b.iter(|| {
+            let joins: Vec<_> = (0..BURST_SENDERS)
+                .map(|_| {
+                    let snd = snd.clone();
+                    thread::spawn(move || for i in 0..BURST {
+                        snd.send(i).unwrap();
+                    })
+                })
+                .collect();
+            for _ in 0..BURST_SENDERS * BURST {
+                rcv.recv().unwrap();
+            }
+            for jh in joins {
+                jh.join().unwrap();
+            }
+        });
+    });
+
+    group.finish();
+}
+
+// Every item past the first 1024 goes to disk before it is received. The
+// mpsc and crossbeam baselines hold everything in memory.
+fn spill_heavy(c: &mut Criterion) {
+    let mut group = c.benchmark_group("spill_heavy");
+    group.throughput(Throughput::Elements(ITEMS * 5));
+
+    group.bench_function("hopper_channel", |b| {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) =
+            hopper::channel_with_max_bytes("bench_spill", dir.path(), 64 * 1024).unwrap();
+        b.iter(|| {
+            for i in 0..ITEMS * 5 {
+                snd.send(i);
+            }
+            drain(&mut rcv, ITEMS * 5);
+        });
+    });
+
+    group.bench_function("hopper_local", |b| {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let q = hopper::local::queue("bench_spill", dir.path(), 64 * 1024).unwrap();
+        b.iter(|| {
+            for i in 0..ITEMS * 5 {
+                q.send(i);
+            }
+            assert_eq!(ITEMS as usize * 5, q.iter().count());
+        });
+    });
+
+    group.bench_function("std_mpsc", |b| {
+        let (snd, rcv) = mpsc::channel();
+        b.iter(|| {
+            for i in 0..ITEMS * 5 {
+                snd.send(i).unwrap();
+            }
+            for _ in 0..ITEMS * 5 {
+                rcv.recv().unwrap();
+            }
+        });
+    });
+
+    group.bench_function("crossbeam", |b| {
+        let (snd, rcv) = crossbeam_channel::unbounded();
+        b.iter(|| {
+            for i in 0..ITEMS * 5 {
+                snd.send(i).unwrap();
+            }
+            for _ in 0..ITEMS * 5 {
+                rcv.recv().unwrap();
+            }
+        });
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, one_to_one, n_to_one_bursty, spill_heavy);
+criterion_main!(benches);
@@ -0,0 +1,124 @@
+#![feature(test)]
+
+//! The same plain-data payload encoded by its serde implementation, hopper's
+//! default, and by `hopper::fixed::FixedCodec`: alone, and carried through a
+//! channel's disk tier.
+
+extern crate bincode;
+extern crate hopper;
+extern crate tempdir;
+extern crate test;
+
+use hopper::fixed::{FixedCodec, FixedLayout};
+use self::test::Bencher;
+
+const ITEMS: u64 = 10_000;
+
+// A sensor, its readings and when they were taken, as a tuple for serde
+type Reading = (u16, [f32; 8], u64);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FixedReading(Reading);
+
+impl FixedLayout for FixedReading {
+    const SIZE: usize = 2 + 32 + 8;
+
+    fn encode(&self, buf: &mut [u8]) {
+        let (sensor, ref values, at) = self.0;
+        sensor.encode(&mut buf[..2]);
+        values.encode(&mut buf[2..34]);
+        at.encode(&mut buf[34..]);
+    }
+
+    fn decode(buf: &[u8]) -> FixedReading {
+        FixedReading((
+            u16::decode(&buf[..2]),
+            <[f32; 8]>::decode(&buf[2..34]),
+            u64::decode(&buf[34..]),
+        ))
+    }
+}
+
+fn reading(i: u64) -> Reading {
+    let x = i as f32;
+    ((i % 64) as u16, [x, x + 1.0, x + 2.0, x + 3.0, x + 4.0, x + 5.0, x + 6.0, x + 7.0], i)
+}
+
+mod encode {
+    use super::*;
+
+    #[bench]
+    fn serde(b: &mut Bencher) {
+        let mut buf = Vec::with_capacity(64);
+        b.iter(|| for i in 0..ITEMS {
+            buf.clear();
+            bincode::serialize_into(&mut buf, &reading(i), bincode::Infinite).unwrap();
+        });
+    }
+
+    #[bench]
+    fn fixed(b: &mut Bencher) {
+        let mut buf = Vec::with_capacity(64);
+        b.iter(|| for i in 0..ITEMS {
+            buf.clear();
+            let item = FixedCodec(FixedReading(reading(i)));
+            bincode::serialize_into(&mut buf, &item, bincode::Infinite).unwrap();
+        });
+    }
+}
+
+mod decode {
+    use super::*;
+
+    #[bench]
+    fn serde(b: &mut Bencher) {
+        let encoded = bincode::serialize(&reading(7), bincode::Infinite).unwrap();
+        b.iter(|| for _ in 0..ITEMS {
+            test::black_box(bincode::deserialize::<Reading>(&encoded).unwrap());
+        });
+    }
+
+    #[bench]
+    fn fixed(b: &mut Bencher) {
+        let item = FixedCodec(FixedReading(reading(7)));
+        let encoded = bincode::serialize(&item, bincode::Infinite).unwrap();
+        b.iter(|| for _ in 0..ITEMS {
+            test::black_box(bincode::deserialize::<FixedCodec<FixedReading>>(&encoded).unwrap());
+        });
+    }
+}
+
+// Through a channel that pages every item out and reads it back in
+mod disk_tier {
+    use super::*;
+
+    #[bench]
+    fn serde(b: &mut Bencher) {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = hopper::ChannelBuilder::new("bench_serde", dir.path())
+            .disk_primary(true)
+            .build()
+            .unwrap();
+        b.iter(|| {
+            for i in 0..ITEMS {
+                snd.send(reading(i));
+            }
+            assert_eq!(ITEMS as usize, rcv.try_iter().count());
+        });
+    }
+
+    #[bench]
+    fn fixed(b: &mut Bencher) {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = hopper::ChannelBuilder::new("bench_fixed", dir.path())
+            .disk_primary(true)
+            .build()
+            .unwrap();
+        b.iter(|| {
+            for i in 0..ITEMS {
+                snd.send(FixedCodec(FixedReading(reading(i))));
+            }
+            assert_eq!(ITEMS as usize, rcv.try_iter().count());
+        });
+    }
+}
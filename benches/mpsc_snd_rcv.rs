@@ -27,7 +27,7 @@ macro_rules! generate_snd_rcv {
             let (mut snd, mut rcv) = hopper::channel("bench_snd", dir.path()).unwrap();
             b.iter(|| {
                 snd.send(12 as $t);
-                rcv.iter().next().unwrap();
+                rcv.try_iter().next().unwrap();
             });
         }
     }
@@ -44,7 +44,7 @@ macro_rules! generate_snd_all_rcv {
                     snd.send(i as $t);
                 }
                 for _ in 0..$s {
-                    rcv.iter().next().unwrap();
+                    rcv.try_iter().next().unwrap();
                 }
             });
         }
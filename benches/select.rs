@@ -0,0 +1,164 @@
+//! How quickly a thread parked in a `Select` is woken by a send. Each peer
+//! thread waits in its own `Select` and answers every item it is woken for,
+//! so a round trip is two wakes. `crossbeam-channel`'s `Select` is the
+//! baseline.
+//!
+//! Runs on stable: `cargo bench --bench select`.
+
+#[macro_use]
+extern crate criterion;
+extern crate crossbeam_channel;
+extern crate hopper;
+extern crate tempdir;
+
+use criterion::{Criterion, Throughput};
+use std::thread;
+
+const ROUNDS: u64 = 1_000;
+const PARKED: u64 = 8;
+const STOP: u64 = u64::MAX;
+
+// Wait in a Select on `rcv`, answering each item on `snd` until told to stop.
+fn hopper_peer(mut rcv: hopper::Receiver<u64>, mut snd: hopper::Sender<u64>) {
+    let mut sel = hopper::Select::new();
+    sel.add(&rcv);
+    loop {
+        sel.ready();
+        for i in rcv.try_iter() {
+            if i == STOP {
+                return;
+            }
+            snd.send(i);
+        }
+    }
+}
+
+fn crossbeam_peer(rcv: crossbeam_channel::Receiver<u64>, snd: crossbeam_channel::Sender<u64>) {
+    let mut sel = crossbeam_channel::Select::new();
+    sel.recv(&rcv);
+    loop {
+        sel.ready();
+        for i in rcv.try_iter() {
+            if i == STOP {
+                return;
+            }
+            snd.send(i).unwrap();
+        }
+    }
+}
+
+// Take `total` answers from a hopper Receiver, waiting in a Select.
+fn hopper_answers(sel: &mut hopper::Select, rcv: &mut hopper::Receiver<u64>, total: u64) {
+    let mut got = 0;
+    while got < total {
+        sel.ready();
+        got += rcv.try_iter().count() as u64;
+    }
+}
+
+fn ping_pong(c: &mut Criterion) {
+    let mut group = c.benchmark_group("select_ping_pong");
+    group.throughput(Throughput::Elements(ROUNDS));
+
+    group.bench_function("hopper", |b| {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut ping, ping_rcv) = hopper::channel("bench_ping", dir.path()).unwrap();
+        let (pong_snd, mut pong) = hopper::channel("bench_pong", dir.path()).unwrap();
+        let peer = thread::spawn(move || hopper_peer(ping_rcv, pong_snd));
+        let mut sel = hopper::Select::new();
+        sel.add(&pong);
+        b.iter(|| for i in 0..ROUNDS {
+            ping.send(i);
+            hopper_answers(&mut sel, &mut pong, 1);
+        });
+        ping.send(STOP);
+        peer.join().unwrap();
+    });
+
+    group.bench_function("crossbeam", |b| {
+        let (ping, ping_rcv) = crossbeam_channel::unbounded();
+        let (pong_snd, pong) = crossbeam_channel::unbounded();
+        let peer = thread::spawn(move || crossbeam_peer(ping_rcv, pong_snd));
+        let mut sel = crossbeam_channel::Select::new();
+        sel.recv(&pong);
+        b.iter(|| for i in 0..ROUNDS {
+            ping.send(i).unwrap();
+            sel.ready();
+            pong.recv().unwrap();
+        });
+        ping.send(STOP).unwrap();
+        peer.join().unwrap();
+    });
+
+    group.finish();
+}
+
+// Several threads parked at once, each in its own Select, woken one item
+// apiece a round.
+fn fan_out(c: &mut Criterion) {
+    let mut group = c.benchmark_group("select_fan_out");
+    group.throughput(Throughput::Elements(ROUNDS / 10 * PARKED));
+
+    group.bench_function("hopper", |b| {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (pong_snd, mut pong) = hopper::channel("bench_fan_pong", dir.path()).unwrap();
+        let (mut pings, peers): (Vec<_>, Vec<_>) = (0..PARKED)
+            .map(|n| {
+                let (ping, ping_rcv) =
+                    hopper::channel(&format!("bench_fan_ping_{}", n), dir.path()).unwrap();
+                let pong_snd = pong_snd.clone();
+                (ping, thread::spawn(move || hopper_peer(ping_rcv, pong_snd)))
+            })
+            .unzip();
+        drop(pong_snd);
+        let mut sel = hopper::Select::new();
+        sel.add(&pong);
+        b.iter(|| for i in 0..ROUNDS / 10 {
+            for ping in &mut pings {
+                ping.send(i);
+            }
+            hopper_answers(&mut sel, &mut pong, PARKED);
+        });
+        for ping in &mut pings {
+            ping.send(STOP);
+        }
+        for peer in peers {
+            peer.join().unwrap();
+        }
+    });
+
+    group.bench_function("crossbeam", |b| {
+        let (pong_snd, pong) = crossbeam_channel::unbounded();
+        let (pings, peers): (Vec<_>, Vec<_>) = (0..PARKED)
+            .map(|_| {
+                let (ping, ping_rcv) = crossbeam_channel::unbounded();
+                let pong_snd = pong_snd.clone();
+                (ping, thread::spawn(move || crossbeam_peer(ping_rcv, pong_snd)))
+            })
+            .unzip();
+        drop(pong_snd);
+        let mut sel = crossbeam_channel::Select::new();
+        sel.recv(&pong);
+        b.iter(|| for i in 0..ROUNDS / 10 {
+            for ping in &pings {
+                ping.send(i).unwrap();
+            }
+            let mut got = 0;
+            while got < PARKED {
+                sel.ready();
+                got += pong.try_iter().count() as u64;
+            }
+        });
+        for ping in &pings {
+            ping.send(STOP).unwrap();
+        }
+        for peer in peers {
+            peer.join().unwrap();
+        }
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, ping_pong, fan_out);
+criterion_main!(benches);
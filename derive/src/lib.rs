@@ -0,0 +1,225 @@
+//! `#[derive(FixedLayout)]` for `hopper::fixed`
+//!
+//! Enabled in hopper by its `derive` feature and re-exported as
+//! `hopper::fixed::FixedLayout`, alongside the trait of the same name. The
+//! derive lays a struct's fields down one after the other, in the order they
+//! are declared and with no padding, each in its own `FixedLayout`. Structs
+//! with named fields, tuple structs and unit structs are supported, generic
+//! ones, enums and unions are not.
+#![deny(missing_docs)]
+extern crate proc_macro;
+
+use proc_macro::{Delimiter, Group, TokenStream, TokenTree};
+
+/// Implement `hopper::fixed::FixedLayout` for a struct whose fields all
+/// implement it
+#[proc_macro_derive(FixedLayout)]
+pub fn derive_fixed_layout(input: TokenStream) -> TokenStream {
+    let code = match expand(input) {
+        Ok(code) => code,
+        Err(msg) => format!("compile_error!({:?});", msg),
+    };
+    code.parse().expect("derived FixedLayout did not parse")
+}
+
+// The fields of the struct being derived for.
+enum Fields {
+    // Their names and types
+    Named(Vec<(String, String)>),
+    // Their types
+    Tuple(Vec<String>),
+    Unit,
+}
+
+fn expand(input: TokenStream) -> Result<String, String> {
+    let mut tokens = input.into_iter();
+    // Attributes and visibility come before `struct`.
+    loop {
+        match tokens.next() {
+            Some(TokenTree::Ident(ref ident)) if ident.to_string() == "struct" => break,
+            Some(TokenTree::Ident(ref ident))
+                if ident.to_string() == "enum" || ident.to_string() == "union" =>
+            {
+                return Err("FixedLayout can only be derived for structs".to_string())
+            }
+            Some(_) => {}
+            None => return Err("FixedLayout can only be derived for structs".to_string()),
+        }
+    }
+    let name = match tokens.next() {
+        Some(TokenTree::Ident(ident)) => ident.to_string(),
+        _ => return Err("expected the struct's name".to_string()),
+    };
+    let fields = match tokens.next() {
+        Some(TokenTree::Group(ref body)) if body.delimiter() == Delimiter::Brace => {
+            Fields::Named(named_fields(body)?)
+        }
+        Some(TokenTree::Group(ref body)) if body.delimiter() == Delimiter::Parenthesis => {
+            Fields::Tuple(field_types(body))
+        }
+        Some(TokenTree::Punct(ref punct)) if punct.as_char() == ';' => Fields::Unit,
+        Some(TokenTree::Punct(ref punct)) if punct.as_char() == '<' => {
+            return Err("FixedLayout cannot be derived for generic structs".to_string())
+        }
+        _ => return Err("expected the struct's fields".to_string()),
+    };
+    Ok(implementation(&name, &fields))
+}
+
+// Split the contents of `group` at its top-level commas, those outside any
+// generic arguments, leaving attributes and visibility off each part.
+fn split_fields(group: &Group) -> Vec<Vec<TokenTree>> {
+    let mut fields = Vec::new();
+    let mut field = Vec::new();
+    let mut depth = 0;
+    for token in group.stream() {
+        match token {
+            TokenTree::Punct(ref punct) if punct.as_char() == ',' && depth == 0 => {
+                fields.push(field);
+                field = Vec::new();
+                continue;
+            }
+            TokenTree::Punct(ref punct) if punct.as_char() == '<' => depth += 1,
+            TokenTree::Punct(ref punct) if punct.as_char() == '>' => depth -= 1,
+            _ => {}
+        }
+        field.push(token);
+    }
+    fields.push(field);
+    fields
+        .into_iter()
+        .map(strip_prefix)
+        .filter(|field| !field.is_empty())
+        .collect()
+}
+
+// Take the attributes--`#` and a bracketed group--and the visibility--`pub`
+// and any parenthesised restriction after it--off the head of a field.
+fn strip_prefix(field: Vec<TokenTree>) -> Vec<TokenTree> {
+    let mut at = 0;
+    loop {
+        match field.get(at) {
+            Some(&TokenTree::Punct(ref punct)) if punct.as_char() == '#' => at += 2,
+            Some(&TokenTree::Ident(ref ident)) if ident.to_string() == "pub" => {
+                at += 1;
+                if let Some(&TokenTree::Group(ref group)) = field.get(at) {
+                    if group.delimiter() == Delimiter::Parenthesis {
+                        at += 1;
+                    }
+                }
+            }
+            _ => break,
+        }
+    }
+    field.into_iter().skip(at).collect()
+}
+
+fn render(tokens: &[TokenTree]) -> String {
+    tokens.iter().cloned().collect::<TokenStream>().to_string()
+}
+
+fn named_fields(body: &Group) -> Result<Vec<(String, String)>, String> {
+    split_fields(body)
+        .into_iter()
+        .map(|field| match (field.first(), field.get(1)) {
+            (Some(&TokenTree::Ident(ref name)), Some(&TokenTree::Punct(ref colon)))
+                if colon.as_char() == ':' =>
+            {
+                Ok((name.to_string(), render(&field[2..])))
+            }
+            _ => Err(format!("could not read the field `{}`", render(&field))),
+        })
+        .collect()
+}
+
+fn field_types(body: &Group) -> Vec<String> {
+    split_fields(body)
+        .into_iter()
+        .map(|field| render(&field))
+        .collect()
+}
+
+// The code implementing FixedLayout for `name`, each field's bytes starting
+// where the last's ended.
+fn implementation(name: &str, fields: &Fields) -> String {
+    let types: Vec<&str> = match *fields {
+        Fields::Named(ref named) => named.iter().map(|&(_, ref ty)| ty.as_str()).collect(),
+        Fields::Tuple(ref types) => types.iter().map(String::as_str).collect(),
+        Fields::Unit => Vec::new(),
+    };
+    let sizes: Vec<String> = types
+        .iter()
+        .map(|ty| format!("<{} as ::hopper::fixed::FixedLayout>::SIZE", ty))
+        .collect();
+    // The range of the buffer each field takes
+    let ranges: Vec<String> = (0..sizes.len())
+        .map(|i| {
+            if i == 0 {
+                format!("..{}", sizes[i])
+            } else {
+                let start = sizes[..i].join(" + ");
+                format!("{}..{} + {}", start, start, sizes[i])
+            }
+        })
+        .collect();
+    let size = if sizes.is_empty() {
+        "0".to_string()
+    } else {
+        sizes.join(" + ")
+    };
+
+    let accessors: Vec<String> = match *fields {
+        Fields::Named(ref named) => named.iter().map(|&(ref name, _)| name.clone()).collect(),
+        _ => (0..types.len()).map(|i| i.to_string()).collect(),
+    };
+    let encode: String = accessors
+        .iter()
+        .zip(&ranges)
+        .map(|(field, range)| {
+            format!(
+                "::hopper::fixed::FixedLayout::encode(&self.{}, &mut buf[{}]);",
+                field, range
+            )
+        })
+        .collect();
+    let decoded: Vec<String> = types
+        .iter()
+        .zip(&ranges)
+        .map(|(ty, range)| {
+            format!("<{} as ::hopper::fixed::FixedLayout>::decode(&buf[{}])", ty, range)
+        })
+        .collect();
+    let decode = match *fields {
+        Fields::Named(_) => {
+            let inits: Vec<String> = accessors
+                .iter()
+                .zip(&decoded)
+                .map(|(field, value)| format!("{}: {}", field, value))
+                .collect();
+            format!("{} {{ {} }}", name, inits.join(", "))
+        }
+        Fields::Tuple(_) => format!("{}({})", name, decoded.join(", ")),
+        Fields::Unit => name.to_string(),
+    };
+    let unused = if types.is_empty() { "let _ = buf;" } else { "" };
+    format!(
+        "impl ::hopper::fixed::FixedLayout for {name} {{
+            const SIZE: usize = {size};
+
+            fn encode(&self, buf: &mut [u8]) {{
+                {unused}
+                {encode}
+            }}
+
+            fn decode(buf: &[u8]) -> {name} {{
+                {unused}
+                {decode}
+            }}
+        }}",
+        name = name,
+        size = size,
+        unused = unused,
+        encode = encode,
+        decode = decode,
+    )
+}
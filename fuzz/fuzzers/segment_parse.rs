@@ -0,0 +1,24 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate hopper;
+
+use hopper::segment;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(records) = segment::parse(data) {
+        let mut end = records.offset();
+        for record in records {
+            match record {
+                Ok(record) => {
+                    // Records follow one another with nothing in between.
+                    assert_eq!(end, record.offset);
+                    let tag = record.schema_version.map_or(0, |_| 4);
+                    end = record.offset + 4 + tag + record.payload.len();
+                    assert!(end <= data.len());
+                }
+                Err(_) => break,
+            }
+        }
+    }
+});
@@ -0,0 +1,148 @@
+//! Fair admission of Senders to a channel's lock
+//!
+//! Every send in `OrderMode::Global` takes the channel's single lock. A std
+//! Mutex is not fair: a Sender sending in a tight loop takes the lock back
+//! the moment it lets go, before a waiting Sender has woken, and can keep the
+//! others out indefinitely. Handing the lock over strictly in turn would cure
+//! that but costs a context switch on every contended send, so Senders race
+//! for the lock as before, trying for it up to `SPINS` times. A Sender that
+//! fails asks the others to queue and parks until its turn comes. From then
+//! on Senders queue for their turn--those already waiting first--so a Sender
+//! waiting in the queue is granted the lock before any Sender that asks for
+//! it later, however hot. Senders race again once the queue empties, or once
+//! it moves quickly enough that no one queued has waited `STARVING`. The
+//! Receiver locks the channel directly.
+use sync::{self, Condvar, Mutex, MutexGuard};
+use std::hint;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How many times a Sender tries for the lock before queueing for it. The
+// first half spin, the rest yield.
+const SPINS: u32 = 64;
+
+// How long a queued Sender may wait for its turn before the others keep
+// queueing behind it rather than racing again.
+const STARVING: Duration = Duration::from_millis(1);
+
+/// The queue a channel's Senders take their turn at its lock through
+#[derive(Debug, Default)]
+pub struct Admission {
+    tickets: Mutex<Tickets>,
+    turn: Condvar,
+    // Set while Senders must queue for the lock
+    starving: AtomicBool,
+}
+
+#[derive(Debug, Default)]
+struct Tickets {
+    next: u64,
+    serving: u64,
+}
+
+impl Admission {
+    /// Lock `mutex`, carrying on past poisoning as `private::lock` does
+    pub fn lock<'a, S>(&self, mutex: &'a Mutex<S>) -> MutexGuard<'a, S> {
+        for spin in 0..SPINS {
+            if self.starving.load(Ordering::Acquire) {
+                break;
+            }
+            if let Some(guard) = sync::try_lock(mutex) {
+                return guard;
+            }
+            if spin < SPINS / 2 {
+                hint::spin_loop();
+            } else {
+                thread::yield_now();
+            }
+        }
+        self.lock_in_turn(mutex)
+    }
+
+    // The number of Senders queued for the lock, the one it is to go to next
+    // included
+    #[cfg(test)]
+    fn queued(&self) -> u64 {
+        let tickets = sync::lock(&self.tickets);
+        tickets.next - tickets.serving
+    }
+
+    // Take a ticket and lock `mutex` once every ticket before it has.
+    fn lock_in_turn<'a, S>(&self, mutex: &'a Mutex<S>) -> MutexGuard<'a, S> {
+        // The flag changes only under the ticket lock, so it is set for as
+        // long as anyone queued is kept waiting.
+        let queued = Instant::now();
+        let mut tickets = sync::lock(&self.tickets);
+        self.starving.store(true, Ordering::Release);
+        let ticket = tickets.next;
+        tickets.next += 1;
+        while tickets.serving != ticket {
+            tickets = sync::wait(&self.turn, tickets);
+        }
+        drop(tickets);
+        let guard = sync::lock(mutex);
+        // The next in line waits on `mutex` itself from here, so is next to
+        // have it. Once the queue is empty, or moving quickly, Senders race
+        // again.
+        let mut tickets = sync::lock(&self.tickets);
+        tickets.serving += 1;
+        let waiting = tickets.next > tickets.serving;
+        if !waiting || queued.elapsed() < STARVING {
+            self.starving.store(false, Ordering::Release);
+        }
+        drop(tickets);
+        if waiting {
+            self.turn.notify_all();
+        }
+        guard
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn hot_holder_does_not_starve_the_others() {
+        let admission = Arc::new(Admission::default());
+        let held = Arc::new(Mutex::new(Vec::new()));
+
+        // The hot thread holds the lock while the others queue for it, then
+        // lets go and asks for it straight back, each round.
+        for round in 0..5 {
+            let mut guard = admission.lock(&held);
+            guard.push(0);
+            let cold: Vec<_> = (1..4)
+                .map(|thr| {
+                    let (admission, held) = (admission.clone(), held.clone());
+                    thread::spawn(move || admission.lock(&held).push(thr))
+                })
+                .collect();
+            while admission.queued() < 3 {
+                thread::sleep(Duration::from_millis(1));
+            }
+            // Long enough that the queue keeps the hot thread waiting
+            thread::sleep(STARVING);
+            drop(guard);
+            admission.lock(&held).push(0);
+            for jh in cold {
+                jh.join().unwrap();
+            }
+
+            // Every cold thread waiting had the lock before the hot thread
+            // had it back
+            let mut held = sync::lock(&held);
+            let granted: Vec<u32> = held.drain(..).collect();
+            assert_eq!(5, granted.len(), "round {}", round);
+            assert_eq!((0, 0), (granted[0], granted[4]), "round {}", round);
+            let mut cold = granted[1..4].to_vec();
+            cold.sort();
+            assert_eq!(vec![1, 2, 3], cold, "round {}", round);
+        }
+        assert!(!admission.starving.load(Ordering::Acquire));
+    }
+}
@@ -0,0 +1,278 @@
+//! Moving sealed queue files into a channel's archive directory
+//!
+//! A channel built with `ChannelBuilder::archive_dir` moves each queue file
+//! its Senders seal into the archive. The move is made in two steps, so that
+//! the send sealing the file waits on neither. First a background thread, or
+//! a job of the channel's `Runtime`, puts the file's contents under the
+//! archive as `<id>.partial`: a hard link where the archive shares the
+//! file's filesystem, else a copy. The name is not a number, so nothing
+//! takes it for a queue file. Then, with the channel's lock held, as a
+//! Sender next pages out or flushes or the Receiver moves to its next file,
+//! the link or copy is renamed into place and the original removed, so the
+//! Receiver never finds the file in both places or in neither.
+//!
+//! A file that cannot be moved stays where it is and is read from there, the
+//! failure traced. So does a file the Receiver reads past before it is
+//! moved. Files still to be moved when the channel is dropped are moved as
+//! it drops.
+use index;
+use private::{self, FlushSignal};
+use runtime::{Next, Runtime};
+use summary;
+use supervise::{Supervisor, Task};
+use sync::{self, Mutex};
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+// The extension of a queue file's contents put under the archive and not
+// yet moved into place
+const PARTIAL: &str = "partial";
+
+// The longest the archiver sleeps before looking whether its channel is
+// still open
+const ARCHIVER_IDLE: Duration = Duration::from_secs(1);
+
+// A sealed queue file whose contents are under the archive, to be moved into
+// place
+#[derive(Debug)]
+struct Staged {
+    path: PathBuf,
+    partial: PathBuf,
+    dest: PathBuf,
+}
+
+/// Moves a channel's sealed queue files into its archive directory, see the
+/// module documentation
+#[derive(Debug)]
+pub struct Archiver {
+    shared: Arc<Shared>,
+    // Wakes the background thread or job, if there is one
+    signal: Option<Arc<FlushSignal>>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    dir: PathBuf,
+    // Sealed files waiting to be put under the archive
+    waiting: Mutex<VecDeque<PathBuf>>,
+    // Files put under the archive, waiting to be moved into place
+    staged: Mutex<Vec<Staged>>,
+}
+
+impl Archiver {
+    /// An archiver moving files into `dir`, from a job of `runtime` if given
+    /// or else a thread of its own, where the target has threads
+    ///
+    /// Contents left under `dir` by a move a crash cut short are removed.
+    pub fn start(dir: PathBuf, runtime: Option<&Runtime>, supervisor: &Supervisor) -> Archiver {
+        if let Ok(entries) = fs::read_dir(&dir) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.extension().is_some_and(|ext| ext == PARTIAL) {
+                    let _ = fs::remove_file(path);
+                }
+            }
+        }
+        let shared = Arc::new(Shared {
+            dir: dir,
+            waiting: Mutex::new(VecDeque::new()),
+            staged: Mutex::new(Vec::new()),
+        });
+        let alive = Arc::downgrade(&shared);
+        let signal = match runtime {
+            Some(runtime) => {
+                let job = runtime.spawn(supervisor.job(Task::Archiver, move || {
+                    match alive.upgrade() {
+                        Some(shared) => {
+                            shared.stage_waiting();
+                            Next::After(ARCHIVER_IDLE)
+                        }
+                        None => Next::Done,
+                    }
+                }));
+                Some(Arc::new(FlushSignal::waking(job)))
+            }
+            None if private::HAS_THREADS => {
+                let signal = Arc::new(FlushSignal::default());
+                let thr_signal = Arc::clone(&signal);
+                let spawned = supervisor.spawn(Task::Archiver, move || {
+                    while let Some(shared) = alive.upgrade() {
+                        shared.stage_waiting();
+                        drop(shared);
+                        thr_signal.wait(ARCHIVER_IDLE);
+                    }
+                });
+                spawned.ok().map(|_| signal)
+            }
+            None => None,
+        };
+        Archiver {
+            shared: shared,
+            signal: signal,
+        }
+    }
+
+    /// Move the sealed queue file at `path` into the archive
+    ///
+    /// Called with the channel's lock held. Without a background thread the
+    /// file is moved before this returns.
+    pub fn archive(&self, path: PathBuf) {
+        sync::lock(&self.shared.waiting).push_back(path);
+        match self.signal {
+            Some(ref signal) => signal.notify(),
+            None => {
+                self.shared.stage_waiting();
+                self.finish();
+            }
+        }
+    }
+
+    /// Move the files put under the archive into place, removing the
+    /// originals
+    ///
+    /// Called with the channel's lock held.
+    pub fn finish(&self) {
+        let staged = {
+            let mut staged = sync::lock(&self.shared.staged);
+            if staged.is_empty() {
+                return;
+            }
+            staged.split_off(0)
+        };
+        for staged in staged {
+            if let Err(_e) = staged.finish() {
+                let _ = fs::remove_file(&staged.partial);
+                trace_event!(
+                    path = %staged.path.display(),
+                    error = %_e,
+                    "could not move queue file to archive"
+                );
+            }
+        }
+    }
+}
+
+impl Drop for Archiver {
+    fn drop(&mut self) {
+        self.shared.stage_waiting();
+        self.finish();
+    }
+}
+
+impl Shared {
+    // Put the contents of each waiting file under the archive.
+    fn stage_waiting(&self) {
+        loop {
+            let path = match sync::lock(&self.waiting).pop_front() {
+                Some(path) => path,
+                None => return,
+            };
+            match self.stage(&path) {
+                Ok(Some(staged)) => sync::lock(&self.staged).push(staged),
+                Ok(None) => {}
+                Err(_e) => {
+                    trace_event!(
+                        path = %path.display(),
+                        error = %_e,
+                        "could not copy queue file to archive"
+                    );
+                }
+            }
+        }
+    }
+
+    // Link or copy the file at `path` under the archive, if the Receiver has
+    // not read past it already.
+    fn stage(&self, path: &Path) -> io::Result<Option<Staged>> {
+        let name = match path.file_name() {
+            Some(name) => name.to_os_string(),
+            None => return Ok(None),
+        };
+        let dest = self.dir.join(&name);
+        let partial = dest.with_extension(PARTIAL);
+        let _ = fs::remove_file(&partial);
+        let staged = fs::hard_link(path, &partial).or_else(|_| fs::copy(path, &partial).map(|_| ()));
+        match staged {
+            Ok(()) => Ok(Some(Staged {
+                path: path.to_path_buf(),
+                partial: partial,
+                dest: dest,
+            })),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                let _ = fs::remove_file(&partial);
+                Ok(None)
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&partial);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Staged {
+    // Move the contents into place and remove the original, unless the
+    // Receiver has read past it since.
+    fn finish(&self) -> io::Result<()> {
+        if !self.path.exists() {
+            return fs::remove_file(&self.partial);
+        }
+        fs::rename(&self.partial, &self.dest)?;
+        let _ = fs::copy(summary::path(&self.path), summary::path(&self.dest));
+        let _ = fs::copy(index::path(&self.path), index::path(&self.dest));
+        // If the original cannot go--on Windows, because the Receiver has it
+        // open--it stays where it is and the archived file is dropped instead.
+        if let Err(e) = private::remove_segment(&self.path) {
+            let _ = private::remove_segment(&self.dest);
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::*;
+
+    #[test]
+    fn files_move_once_finished() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let archive = dir.path().join("archive");
+        fs::create_dir(&archive).unwrap();
+        fs::write(archive.join("7.partial"), b"left by a crash").unwrap();
+        let archiver = Archiver::start(archive.clone(), None, &Supervisor::new("archive", None));
+        assert!(!archive.join("7.partial").exists());
+
+        let sealed = dir.path().join("3");
+        fs::write(&sealed, b"records").unwrap();
+        archiver.archive(sealed.clone());
+        // Nothing moves until the channel's lock holder finishes the move
+        while sync::lock(&archiver.shared.staged).is_empty() {
+            ::std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(sealed.exists());
+        assert!(!archive.join("3").exists());
+        archiver.finish();
+        assert!(!sealed.exists());
+        assert_eq!(b"records".to_vec(), fs::read(archive.join("3")).unwrap());
+        assert!(!archive.join("3.partial").exists());
+
+        // A file read past before it is moved stays gone
+        let consumed = dir.path().join("4");
+        fs::write(&consumed, b"records").unwrap();
+        archiver.archive(consumed.clone());
+        while sync::lock(&archiver.shared.staged).is_empty() {
+            ::std::thread::sleep(Duration::from_millis(1));
+        }
+        fs::remove_file(&consumed).unwrap();
+        archiver.finish();
+        assert!(!archive.join("4").exists());
+        assert!(!archive.join("4.partial").exists());
+    }
+}
@@ -0,0 +1,161 @@
+//! Dump the state of a hopper channel directory
+//!
+//! ```text
+//! hopper-inspect <channel-dir> [--records hex|text|string] [--limit N] [--meta]
+//! ```
+//!
+//! Lists the directory's queue files with their sizes, record counts and
+//! record ranges, and the committed position of a cross-process Receiver if
+//! there is one. A sealed queue file with a summary is followed by its item
+//! count and range, the send times of its oldest and newest items and the
+//! bytes of its items before packing. With `--records`, also prints every record using the given
+//! codec, at most `--limit` per queue file. With `--meta`, for a channel that
+//! records provenance, each record's send time in milliseconds since the UNIX
+//! epoch and its Sender's id are printed ahead of its item.
+extern crate hopper;
+
+use hopper::inspect::{self, ChannelInfo, Codec};
+use std::env;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process;
+
+const USAGE: &str =
+    "usage: hopper-inspect <channel-dir> [--records hex|text|string] [--limit N] [--meta]";
+
+struct Args {
+    dir: PathBuf,
+    codec: Option<Codec>,
+    limit: Option<usize>,
+    meta: bool,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut dir = None;
+    let mut codec = None;
+    let mut limit = None;
+    let mut meta = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--records" => {
+                let name = args.next().ok_or("--records needs a codec")?;
+                codec = Some(Codec::from_name(&name).ok_or(format!("unknown codec {}", name))?);
+            }
+            "--limit" => {
+                let n = args.next().ok_or("--limit needs a count")?;
+                limit = Some(n.parse().map_err(|_| format!("bad limit {}", n))?);
+            }
+            "--meta" => meta = true,
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ if dir.is_none() => dir = Some(PathBuf::from(arg)),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+    Ok(Args {
+        dir: dir.ok_or(USAGE)?,
+        codec: codec,
+        limit: limit,
+        meta: meta,
+    })
+}
+
+fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            process::exit(2);
+        }
+    };
+    let info = match inspect::inspect(&args.dir) {
+        Ok(info) => info,
+        Err(e) => {
+            eprintln!("{}: {}", args.dir.display(), e);
+            process::exit(1);
+        }
+    };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    // Stop quietly if stdout goes away, as when piped into `head`.
+    let _ = report(&args, &info, &mut out);
+}
+
+fn report<W: Write>(args: &Args, info: &ChannelInfo, out: &mut W) -> io::Result<()> {
+    match info.metadata {
+        Some(ref md) => {
+            for line in md.lines() {
+                writeln!(out, "{}", line)?;
+            }
+        }
+        None => writeln!(out, "no metadata file")?,
+    }
+    match info.committed {
+        Some((seq, offset)) => writeln!(out, "committed {} @ {}", seq, offset)?,
+        None => writeln!(out, "committed -")?,
+    }
+    writeln!(out)?;
+    writeln!(
+        out,
+        "{:>10} {:>12} {:>10} {:>21} {:>8}",
+        "segment", "bytes", "records", "range", "state"
+    )?;
+    for seg in &info.segments {
+        let range = if seg.records == 0 {
+            "-".to_string()
+        } else {
+            format!("{}..{}", seg.first_record, seg.first_record + seg.records)
+        };
+        let state = if seg.sealed { "sealed" } else { "open" };
+        writeln!(
+            out,
+            "{:>10} {:>12} {:>10} {:>21} {:>8}",
+            seg.id, seg.bytes, seg.records, range, state
+        )?;
+        if let Some(ref summary) = seg.summary {
+            let sent = match (summary.first_stamp, summary.last_stamp) {
+                (Some(first), Some(last)) => format!("{}..={}", first, last),
+                _ => "-".to_string(),
+            };
+            writeln!(
+                out,
+                "{:>10} items {} seq {}..={} sent {} unpacked {}",
+                "", summary.items, summary.first_seq, summary.last_seq, sent, summary.item_bytes
+            )?;
+        }
+        if seg.trailing_bytes > 0 {
+            writeln!(out, "{:>10} {} trailing bytes", "", seg.trailing_bytes)?;
+        }
+    }
+
+    if let Some(codec) = args.codec {
+        for seg in &info.segments {
+            let records = match inspect::read_segment(&args.dir, seg.id) {
+                Ok(records) => records,
+                Err(e) => {
+                    eprintln!("segment {}: {}", seg.id, e);
+                    continue;
+                }
+            };
+            writeln!(out)?;
+            writeln!(out, "segment {}", seg.id)?;
+            let limit = args.limit.unwrap_or(records.len());
+            for (i, record) in records.iter().take(limit).enumerate() {
+                let idx = seg.first_record + i as u64;
+                match inspect::split_meta(record) {
+                    Some((stamp, sender, item)) if args.meta => writeln!(
+                        out,
+                        "{:>10} {:>13} {:>5} {}",
+                        idx,
+                        stamp,
+                        sender,
+                        inspect::render(item, codec)
+                    )?,
+                    _ => writeln!(out, "{:>10} {}", idx, inspect::render(record, codec))?,
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,55 @@
+//! Cut torn records out of a crashed hopper channel directory
+//!
+//! ```text
+//! hopper-repair <channel-dir>
+//! ```
+//!
+//! The channel must be closed. Every queue file is cut back to its last
+//! completely written record and queue files with nothing readable in them
+//! are moved into the directory's `corrupt` subdirectory. Record framing is
+//! all that is checked: the item type is unknown here, so use
+//! `hopper::repair::repair_as` to check that records decode.
+extern crate hopper;
+
+use hopper::repair;
+use std::env;
+use std::path::PathBuf;
+use std::process;
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let dir = match (args.next(), args.next()) {
+        (Some(ref dir), None) if dir != "-h" && dir != "--help" => PathBuf::from(dir),
+        _ => {
+            eprintln!("usage: hopper-repair <channel-dir>");
+            process::exit(2);
+        }
+    };
+    let report = match repair::repair(&dir) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("{}: {:?}", dir.display(), e);
+            process::exit(1);
+        }
+    };
+    if report.is_clean() {
+        println!("no damage found");
+        return;
+    }
+    for seg in &report.segments {
+        if seg.quarantined {
+            println!(
+                "segment {}: unreadable, {} bytes moved to {}",
+                seg.id,
+                seg.lost_bytes,
+                repair::QUARANTINE_DIR
+            );
+        } else {
+            println!(
+                "segment {}: kept {} records, cut {} bytes",
+                seg.id, seg.kept_records, seg.lost_bytes
+            );
+        }
+    }
+    println!("{} bytes lost", report.lost_bytes());
+}
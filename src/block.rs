@@ -0,0 +1,265 @@
+//! Records paged out in blocks
+//!
+//! A channel built with `ChannelBuilder::pack_records` writes the records it
+//! pages out in blocks of many rather than one by one, so a backlog of small
+//! items takes fewer record headers on disk and fewer reads to replay. Each
+//! block is a single queue file record whose payload is, big-endian:
+//!
+//! ```text
+//! flags      u8         COMPRESSED or DICTIONARY if the body is compressed,
+//!                       else 0
+//! count      u32        the number of records in the block
+//! body       the records, each framed as in a queue file, or with
+//!            COMPRESSED, the same compressed with LZ4, its length leading,
+//!            or with DICTIONARY, compressed as `dictionary` describes
+//! checksum   u32        CRC-32 (IEEE) of every byte before it
+//! ```
+//!
+//! The records inside are those the channel would otherwise have written,
+//! stamped and sealed as ever, so everything past the block is as for an
+//! unpacked channel. Tools reading queue files record by record, like
+//! `segment::parse`, see the blocks as records.
+use dictionary::Dictionaries;
+use private;
+use sender::u32tou8abe;
+use snapshot::crc32;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+
+/// The flag set on a block whose body is compressed
+pub const COMPRESSED: u8 = 1;
+/// The flag set on a block whose body is compressed with a dictionary
+pub const DICTIONARY: u8 = 2;
+
+// The bytes of a block's payload around its body
+const HEADER_LEN: usize = 5;
+const TRAILER_LEN: usize = 4;
+
+fn frame(out: &mut Vec<u8>, len: usize) {
+    let sz = u32tou8abe(len as u32);
+    out.extend_from_slice(&[sz[3], sz[2], sz[1], sz[0]]);
+}
+
+/// Pack `batch`, records framed back to back, into blocks framed as queue
+/// file records, each holding records of up to `block_bytes` in all
+///
+/// A block is closed once it reaches `block_bytes`, so a record larger than
+/// that has a block to itself. Each body is handed to `compress`, which
+/// returns the flag to set and the body compressed where that makes the block
+/// smaller; see `lz4`.
+pub fn pack<F>(batch: &[u8], block_bytes: usize, mut compress: F) -> Vec<u8>
+where
+    F: FnMut(&[u8]) -> Option<(u8, Vec<u8>)>,
+{
+    let mut out = Vec::with_capacity(batch.len() + HEADER_LEN + TRAILER_LEN + 4);
+    let mut rest = batch;
+    while !rest.is_empty() {
+        let mut body = 0;
+        let mut count: u32 = 0;
+        while body < block_bytes {
+            match private::next_record(&rest[body..]) {
+                Some((record, _)) => {
+                    body += 4 + record.len();
+                    count += 1;
+                }
+                None => break,
+            }
+        }
+        if count == 0 {
+            break;
+        }
+        push_block(&mut out, &rest[..body], count, &mut compress);
+        rest = &rest[body..];
+    }
+    out
+}
+
+fn push_block<F>(out: &mut Vec<u8>, body: &[u8], count: u32, compress: &mut F)
+where
+    F: FnMut(&[u8]) -> Option<(u8, Vec<u8>)>,
+{
+    let compressed = compress(body);
+    let (flags, body) = match compressed {
+        Some((flags, ref smaller)) => (flags, &smaller[..]),
+        None => (0, body),
+    };
+    frame(out, HEADER_LEN + body.len() + TRAILER_LEN);
+    let start = out.len();
+    out.push(flags);
+    out.extend_from_slice(&count.to_be_bytes());
+    out.extend_from_slice(body);
+    let crc = crc32(0, &out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// `body` compressed with LZ4, flagged `COMPRESSED`, if that makes it
+/// smaller, for `pack`
+#[cfg(feature = "compression")]
+pub fn lz4(body: &[u8]) -> Option<(u8, Vec<u8>)> {
+    let smaller = ::lz4_flex::compress_prepend_size(body);
+    if smaller.len() < body.len() {
+        Some((COMPRESSED, smaller))
+    } else {
+        None
+    }
+}
+
+/// `body` compressed with LZ4, flagged `COMPRESSED`, if that makes it
+/// smaller, for `pack`
+#[cfg(not(feature = "compression"))]
+pub fn lz4(_: &[u8]) -> Option<(u8, Vec<u8>)> {
+    None
+}
+
+#[cfg(feature = "compression")]
+fn decompressed(body: &[u8]) -> io::Result<Vec<u8>> {
+    ::lz4_flex::decompress_size_prepended(body)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompressed(_: &[u8]) -> io::Result<Vec<u8>> {
+    let msg = "queue file block is compressed and the compression feature is off";
+    Err(io::Error::new(ErrorKind::InvalidData, msg))
+}
+
+fn damaged(what: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("queue file block {}", what))
+}
+
+// Check `block`'s checksum, returning its flags, count and body.
+fn split(block: &[u8]) -> io::Result<(u8, u32, &[u8])> {
+    if block.len() < HEADER_LEN + TRAILER_LEN {
+        return Err(damaged("is too short"));
+    }
+    let (covered, crc) = block.split_at(block.len() - TRAILER_LEN);
+    if crc32(0, covered).to_be_bytes() != crc {
+        return Err(damaged("fails its checksum"));
+    }
+    let count = u32::from_be_bytes([covered[1], covered[2], covered[3], covered[4]]);
+    Ok((covered[0], count, &covered[HEADER_LEN..]))
+}
+
+/// Whether `block`, a queue file record's, passes its checksum
+pub fn intact(block: &[u8]) -> bool {
+    split(block).is_ok()
+}
+
+/// The number of records `block`, a queue file record's, claims to hold, 0 if
+/// it is too short to say
+pub fn count(block: &[u8]) -> u64 {
+    match block.get(1..HEADER_LEN) {
+        Some(count) => u64::from(u32::from_be_bytes([count[0], count[1], count[2], count[3]])),
+        None => 0,
+    }
+}
+
+/// The payloads of the records packed into `block`, a queue file record's,
+/// without copying them, or None if it is compressed or fails its checksum
+pub fn plain(block: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut rest = match split(block) {
+        Ok((0, _, body)) => body,
+        _ => return None,
+    };
+    let mut records = Vec::new();
+    while let Some((record, after)) = private::next_record(rest) {
+        records.push(record);
+        rest = after;
+    }
+    Some(records)
+}
+
+/// The payloads of the records packed into `block`, a queue file record's,
+/// decompressing with `dictionaries` if it was compressed with one
+///
+/// A block that fails its checksum or does not hold as many whole records as
+/// it claims is an error of kind `InvalidData`.
+pub fn unpack(block: &[u8], dictionaries: &mut Dictionaries) -> io::Result<VecDeque<Vec<u8>>> {
+    let (flags, count, body) = split(block)?;
+    let inflated;
+    let mut rest = if flags & COMPRESSED != 0 {
+        inflated = decompressed(body)?;
+        &inflated[..]
+    } else if flags & DICTIONARY != 0 {
+        inflated = dictionaries.decompress(body)?;
+        &inflated[..]
+    } else {
+        body
+    };
+    let mut records = VecDeque::with_capacity(count as usize);
+    while let Some((record, after)) = private::next_record(rest) {
+        records.push_back(record.to_vec());
+        rest = after;
+    }
+    if records.len() != count as usize || !rest.is_empty() {
+        return Err(damaged("holds fewer records than it claims"));
+    }
+    Ok(records)
+}
+
+/// Reseal in place each record packed into the uncompressed `block` with
+/// `reseal`, returning the number resealed
+///
+/// A compressed block, or one that fails its checksum, is left as it is.
+#[cfg(feature = "encryption")]
+pub fn reseal<F>(block: &mut [u8], mut reseal: F) -> u64
+where
+    F: FnMut(&mut [u8]) -> bool,
+{
+    match split(block) {
+        Ok((0, _, _)) => {}
+        _ => return 0,
+    }
+    let end = block.len() - TRAILER_LEN;
+    let mut resealed = 0;
+    let mut at = HEADER_LEN;
+    while let Some(len) = private::next_record(&block[at..end]).map(|(r, _)| r.len()) {
+        if reseal(&mut block[at + 4..at + 4 + len]) {
+            resealed += 1;
+        }
+        at += 4 + len;
+    }
+    let crc = crc32(0, &block[..end]);
+    block[end..].copy_from_slice(&crc.to_be_bytes());
+    resealed
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    fn framed(records: &[Vec<u8>]) -> Vec<u8> {
+        let mut batch = Vec::new();
+        for record in records {
+            frame(&mut batch, record.len());
+            batch.extend_from_slice(record);
+        }
+        batch
+    }
+
+    #[test]
+    fn records_come_back_out_of_their_blocks() {
+        let records: Vec<Vec<u8>> = (0..300u32).map(|i| vec![i as u8; (i % 40) as usize]).collect();
+        let packed = pack(&framed(&records), 1024, lz4);
+        let mut dictionaries = Dictionaries::new(Path::new("."));
+        let mut unpacked = Vec::new();
+        let mut blocks = 0;
+        let mut rest = &packed[..];
+        while let Some((block, after)) = private::next_record(rest) {
+            unpacked.extend(unpack(block, &mut dictionaries).unwrap());
+            blocks += 1;
+            rest = after;
+        }
+        assert!(rest.is_empty());
+        assert_eq!(records, unpacked);
+        assert!(blocks > 1 && blocks < 10);
+
+        // A flipped bit anywhere in a block is caught by its checksum
+        let mut damaged = packed.clone();
+        damaged[40] ^= 0x10;
+        let (block, _) = private::next_record(&damaged).unwrap();
+        let err = unpack(block, &mut dictionaries).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+    }
+}
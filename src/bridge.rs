@@ -0,0 +1,288 @@
+//! Serving a channel over a Unix domain socket, enabled with the `bridge`
+//! feature
+//!
+//! For a sidecar consuming a channel another process fills. `serve` takes the
+//! channel's Receiver and hands its items to whatever connects to a socket,
+//! one connection at a time, and a `Client` in the sidecar feeds them into a
+//! Sender of its own.
+//!
+//! The protocol is small enough to speak without this module. The server
+//! writes each item as a record: a big-endian u32 length and that many bytes
+//! of the item encoded with bincode. The client acknowledges records in the
+//! order they arrive by writing a big-endian u32 count of the records taken
+//! since its last acknowledgement, and the server has at most `WINDOW`
+//! records unacknowledged at once. Records a connection closes without
+//! acknowledging are sent again, ahead of any others, to the next client, so
+//! every item is delivered at least once for as long as the server runs.
+use bincode::{self, deserialize, Infinite};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use super::{Receiver, Sender};
+
+/// The most records a server has unacknowledged at once
+pub const WINDOW: usize = 64;
+
+// How long the server waits on items or acknowledgements before checking
+// whether it has been stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A Receiver served over a Unix domain socket, see `serve`
+///
+/// Dropping the `Server` stops it and removes the socket.
+pub struct Server<T> {
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<Receiver<T>>>,
+}
+
+impl<T> fmt::Debug for Server<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Server")
+            .field("path", &self.path)
+            .field("stopped", &self.stop.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+/// Serve the items of `rcv` on a socket bound at `path`
+///
+/// The server runs on a thread of its own until it is shut down. Fails if
+/// `path` cannot be bound, as when a file is already there.
+pub fn serve<T>(rcv: Receiver<T>, path: &Path) -> io::Result<Server<T>>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    let listener = UnixListener::bind(path)?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = Arc::clone(&stop);
+    let handle = thread::spawn(move || {
+        let mut rcv = rcv;
+        let mut unacked = VecDeque::new();
+        for stream in listener.incoming() {
+            if stopped.load(Ordering::Acquire) {
+                break;
+            }
+            // A connection that fails is dropped, leaving its unacknowledged
+            // records for the next.
+            if let Ok(stream) = stream {
+                let _ = serve_connection(&mut rcv, &mut unacked, stream, &stopped);
+            }
+        }
+        rcv
+    });
+    Ok(Server {
+        path: path.to_path_buf(),
+        stop: stop,
+        handle: Some(handle),
+    })
+}
+
+fn serve_connection<T>(
+    rcv: &mut Receiver<T>,
+    unacked: &mut VecDeque<Vec<u8>>,
+    stream: UnixStream,
+    stop: &AtomicBool,
+) -> io::Result<()>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    let mut acks = stream.try_clone()?;
+    let mut out = BufWriter::new(stream);
+    for record in unacked.iter() {
+        write_record(&mut out, record)?;
+    }
+    out.flush()?;
+    // Acknowledgements can arrive split across reads
+    let mut pending = Vec::new();
+    while !stop.load(Ordering::Acquire) {
+        if unacked.len() < WINDOW {
+            let wait = if unacked.is_empty() {
+                POLL_INTERVAL
+            } else {
+                Duration::from_millis(0)
+            };
+            for item in rcv.recv_batch(WINDOW - unacked.len(), wait) {
+                let record = bincode::serialize(&item, Infinite).expect("could not serialize");
+                write_record(&mut out, &record)?;
+                unacked.push_back(record);
+            }
+            out.flush()?;
+        }
+        if unacked.is_empty() {
+            continue;
+        }
+        let mut buf = [0; 64];
+        match acks.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => pending.extend_from_slice(&buf[..n]),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+            Err(e) => return Err(e),
+        }
+        while pending.len() >= 4 {
+            let count = u32::from_be_bytes([pending[0], pending[1], pending[2], pending[3]]);
+            pending.drain(..4);
+            if count as usize > unacked.len() {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "client acknowledged more records than were sent",
+                ));
+            }
+            unacked.drain(..count as usize);
+        }
+    }
+    Ok(())
+}
+
+fn write_record<W: Write>(out: &mut W, record: &[u8]) -> io::Result<()> {
+    out.write_all(&(record.len() as u32).to_be_bytes())?;
+    out.write_all(record)
+}
+
+impl<T> Server<T> {
+    /// The path the socket is bound at
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Stop serving, handing back the Receiver
+    ///
+    /// The items of records sent but not yet acknowledged have left the
+    /// Receiver and are lost.
+    pub fn shutdown(mut self) -> Receiver<T> {
+        self.stop().expect("bridge server panicked")
+    }
+
+    fn stop(&mut self) -> Option<Receiver<T>> {
+        self.stop.store(true, Ordering::Release);
+        let handle = self.handle.take()?;
+        // Wake the server should it be waiting on a connection
+        let _ = UnixStream::connect(&self.path);
+        let rcv = handle.join().ok();
+        let _ = fs::remove_file(&self.path);
+        rcv
+    }
+}
+
+impl<T> Drop for Server<T> {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Feeds the items of a `Server` into a local Sender
+pub struct Client<T> {
+    records: BufReader<UnixStream>,
+    acks: UnixStream,
+    snd: Sender<T>,
+}
+
+impl<T> fmt::Debug for Client<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("peer", &self.acks.peer_addr().ok())
+            .finish()
+    }
+}
+
+impl<T> Client<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    /// Connect to the server at `path`, to feed its items into `snd`
+    pub fn connect(path: &Path, snd: Sender<T>) -> io::Result<Client<T>> {
+        let stream = UnixStream::connect(path)?;
+        let acks = stream.try_clone()?;
+        Ok(Client {
+            records: BufReader::new(stream),
+            acks: acks,
+            snd: snd,
+        })
+    }
+
+    /// Take the next item from the server and send it, waiting on the server
+    /// for one
+    ///
+    /// The item is acknowledged once sent. Returns false if the server hung up
+    /// instead. Fails with `ErrorKind::InvalidData` if a record does not
+    /// decode.
+    pub fn forward(&mut self) -> io::Result<bool> {
+        let mut len = [0; 4];
+        match self.records.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        let mut record = vec![0; u32::from_be_bytes(len) as usize];
+        self.records.read_exact(&mut record)?;
+        let item = deserialize(&record).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        self.snd.send(item);
+        self.acks.write_all(&1u32.to_be_bytes())?;
+        Ok(true)
+    }
+
+    /// Forward items until the server hangs up, returning how many
+    pub fn run(&mut self) -> io::Result<u64> {
+        let mut forwarded = 0;
+        while self.forward()? {
+            forwarded += 1;
+        }
+        Ok(forwarded)
+    }
+
+    /// Disconnect, handing back the Sender
+    pub fn into_sender(self) -> Sender<T> {
+        self.snd
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::*;
+    use super::super::channel;
+    use std::io::Read;
+
+    #[test]
+    fn items_cross_the_socket_into_a_local_sender() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut src, rcv) = channel::<u64>("bridge_src", dir.path()).unwrap();
+        let (dst, mut sink) = channel::<u64>("bridge_dst", dir.path()).unwrap();
+        let sock = dir.path().join("bridge.sock");
+        let server = serve(rcv, &sock).unwrap();
+        for i in 0..200 {
+            src.send(i);
+        }
+
+        // A client hanging up before acknowledging leaves its records to be
+        // sent again
+        let mut raw = UnixStream::connect(&sock).unwrap();
+        let mut len = [0; 4];
+        raw.read_exact(&mut len).unwrap();
+        drop(raw);
+
+        let mut client = Client::connect(&sock, dst).unwrap();
+        for _ in 0..200 {
+            assert!(client.forward().unwrap());
+        }
+        let mut dst = client.into_sender();
+        dst.flush().unwrap();
+        let received: Vec<u64> = sink.try_iter().take(200).collect();
+        assert_eq!((0..200).collect::<Vec<u64>>(), received);
+
+        let mut rcv = server.shutdown();
+        assert!(!sock.exists());
+        assert_eq!(None, rcv.try_iter().next());
+    }
+}
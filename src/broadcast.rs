@@ -0,0 +1,1044 @@
+//! Broadcast channels and consumer groups
+//!
+//! A broadcast channel keeps a single log of the items sent through it, and
+//! each of its subscribers reads that log through a cursor of its own. Items
+//! are held in memory, up to 1024 of them, until every subscriber has read
+//! them. Once memory is full the oldest item a subscriber has yet to read is
+//! paged out to the channel's queue files, written once however many
+//! subscribers lag, and a lagging subscriber reads it back from there. A
+//! queue file is removed once every subscriber has read past it. A fast
+//! subscriber never waits on a slow one.
+//!
+//! A channel built with `ChannelBuilder::build_groups` is durable: every item
+//! is written to the queue files as it is sent and each subscriber, a
+//! consumer group, keeps the place it last committed in a file of its own
+//! alongside them, named for the group with an `.offset` extension. A queue
+//! file is removed once every group has committed past it. Opening the
+//! channel again resumes each group from its committed place, and a group
+//! with no committed place starts at the oldest item kept. Items received
+//! and not committed are received again.
+//!
+//! The log's queue files are named for the place in the channel of their
+//! first item and use the framing of regular queue files. They sit in the
+//! channel's directory beside its metadata and lock files, with no
+//! directories of their own. Each file is indexed as it is finished, as
+//! regular queue files are, so a group resumed partway through one reads it
+//! from the indexed record nearest before its place; see `index`.
+use bincode::{deserialize, serialize_into, Infinite};
+use index::{self, SegmentIndex};
+use layout::{self, RecordFraming};
+use metrics::{Metrics, QueueMetrics};
+use private;
+use receiver::u8tou32abe;
+use sender::u32tou8abe;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use super::{DecodeError, Error, RecvError};
+use sync::{self, Condvar, Mutex, MutexGuard};
+use std::cmp;
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+// The number of items held in memory before the oldest unread is paged out.
+const IN_MEMORY_CAPACITY: usize = 1024;
+
+/// The extension of the file a consumer group's committed place is kept in
+pub const OFFSET_EXTENSION: &str = "offset";
+
+// A queue file of the log, named for `first`
+#[derive(Debug)]
+struct Segment {
+    first: u64,
+    // The place of the item after the file's last
+    end: u64,
+    bytes: u64,
+}
+
+// The queue file being written, the last of `Log::segments`
+struct Writer {
+    fp: BufWriter<fs::File>,
+    bytes: usize,
+    // The place up to which the file's records have been handed to the OS
+    flushed: u64,
+    // The file's index so far, written as the file is finished
+    index: SegmentIndex,
+}
+
+#[derive(Debug)]
+struct Cursor {
+    name: String,
+    // The place of the next item to receive
+    next: u64,
+    // The place last committed, for a durable channel
+    committed: u64,
+    // Whether the subscriber is still open
+    live: bool,
+}
+
+struct Log<T> {
+    // The items from `ring_base` to `next_seq`, oldest first
+    ring: VecDeque<T>,
+    ring_base: u64,
+    next_seq: u64,
+    segments: VecDeque<Segment>,
+    writer: Option<Writer>,
+    cursors: Vec<Cursor>,
+    senders: usize,
+    max_bytes: usize,
+    encode_buf: Vec<u8>,
+}
+
+struct Shared<T> {
+    root: PathBuf,
+    durable: bool,
+    log: Mutex<Log<T>>,
+    // Signalled as an item is sent and as the last BroadcastSender drops
+    ready: Condvar,
+    metrics: Metrics,
+    _lock: Option<fs::File>,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, Log<T>> {
+        sync::lock(&self.log)
+    }
+
+    fn segment_path(&self, first: u64) -> PathBuf {
+        self.root.join(format!("{}", first))
+    }
+
+    fn offset_path(&self, name: &str) -> PathBuf {
+        offset_path(&self.root, name)
+    }
+}
+
+fn offset_path(root: &Path, name: &str) -> PathBuf {
+    root.join(format!("{}.{}", name, OFFSET_EXTENSION))
+}
+
+impl<T> Log<T> {
+    // The place of the oldest item an open subscriber has yet to receive
+    fn unread_floor(&self) -> u64 {
+        self.cursors
+            .iter()
+            .filter(|cursor| cursor.live)
+            .map(|cursor| cursor.next)
+            .min()
+            .unwrap_or(self.next_seq)
+    }
+
+    // The place before which no subscriber needs the log's queue files: the
+    // oldest committed place of a durable channel, open subscriber or not,
+    // else the oldest unread
+    fn kept_floor(&self, durable: bool) -> u64 {
+        if durable {
+            self.cursors
+                .iter()
+                .map(|cursor| cursor.committed)
+                .min()
+                .unwrap_or(self.next_seq)
+        } else {
+            self.unread_floor()
+        }
+    }
+
+    // Drop the items every open subscriber has received from memory, and
+    // remove the queue files no subscriber needs, bar the one being written
+    fn reclaim(&mut self, shared: &Shared<T>) {
+        let floor = self.unread_floor();
+        while self.ring_base < floor && self.ring.pop_front().is_some() {
+            self.ring_base += 1;
+        }
+        shared.metrics.in_memory_depth.store(self.ring.len(), Ordering::Relaxed);
+        let floor = self.kept_floor(shared.durable);
+        while self.segments.len() > 1 && self.segments[0].end <= floor {
+            if private::remove_segment(&shared.segment_path(self.segments[0].first)).is_err() {
+                break;
+            }
+            let segment = self.segments.pop_front().expect("segment to remove");
+            shared.metrics.segments_on_disk.fetch_sub(1, Ordering::Relaxed);
+            shared.metrics.disk_bytes.fetch_sub(segment.bytes, Ordering::Relaxed);
+        }
+    }
+
+    // The queue file holding the item at `seq`, if one does
+    fn segment_of(&self, seq: u64) -> Option<&Segment> {
+        self.segments.iter().find(|segment| segment.first <= seq && seq < segment.end)
+    }
+
+    // The place of the first item kept on disk at or after `seq`, or the
+    // first held in memory, for a cursor whose item is in neither
+    fn skip_gap(&self, seq: u64) -> u64 {
+        self.segments
+            .iter()
+            .map(|segment| segment.first)
+            .find(|&first| first > seq)
+            .map_or(self.ring_base, |first| cmp::min(first, self.ring_base))
+    }
+
+    // Hand the records written to the queue file being written to the OS, so
+    // a subscriber can read them
+    fn flush(&mut self) -> io::Result<()> {
+        let end = self.segments.back().map_or(0, |segment| segment.end);
+        if let Some(ref mut writer) = self.writer {
+            if writer.flushed < end {
+                writer.fp.flush()?;
+                writer.flushed = end;
+            }
+        }
+        Ok(())
+    }
+
+    // Start a new queue file for the item at `seq`, indexing the one before
+    fn rotate(&mut self, shared: &Shared<T>, seq: u64) -> io::Result<()> {
+        self.flush()?;
+        if let (Some(writer), Some(last)) = (self.writer.take(), self.segments.back()) {
+            if writer.index.is_useful() {
+                let _ = index::write(&shared.segment_path(last.first), &writer.index);
+            }
+        }
+        let path = shared.segment_path(seq);
+        // A file holding no records, as a failed write leaves the header of
+        // one, is started over.
+        if self.segments.back().map(|last| last.first) == Some(seq) {
+            let last = self.segments.pop_back().expect("last segment");
+            let _ = private::remove_segment(&path);
+            shared.metrics.segments_on_disk.fetch_sub(1, Ordering::Relaxed);
+            shared.metrics.disk_bytes.fetch_sub(last.bytes, Ordering::Relaxed);
+        }
+        let (fp, header) = private::open_segment(&path)?;
+        self.segments.push_back(Segment {
+            first: seq,
+            end: seq,
+            bytes: header,
+        });
+        self.writer = Some(Writer {
+            fp: BufWriter::new(fp),
+            bytes: header as usize,
+            flushed: seq,
+            index: SegmentIndex::default(),
+        });
+        shared.metrics.segments_on_disk.fetch_add(1, Ordering::Relaxed);
+        shared.metrics.disk_bytes.fetch_add(header, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl<T> Log<T>
+where
+    T: Serialize,
+{
+    // Write `item`, at `seq`, to the queue files, starting a new one if the
+    // current is full or does not end just before `seq`
+    fn append(&mut self, shared: &Shared<T>, seq: u64, item: &T) -> io::Result<()> {
+        self.encode_buf.clear();
+        serialize_into(&mut self.encode_buf, item, Infinite)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        let pyld_sz_bytes: [u8; 4] = u32tou8abe(self.encode_buf.len() as u32);
+        let header = [
+            pyld_sz_bytes[3],
+            pyld_sz_bytes[2],
+            pyld_sz_bytes[1],
+            pyld_sz_bytes[0],
+        ];
+        let len = header.len() + self.encode_buf.len();
+        let follows = self.segments.back().map(|last| last.end) == Some(seq);
+        let full = self.writer.as_ref().is_none_or(|writer| {
+            writer.bytes > layout::SEGMENT_HEADER_LEN && writer.bytes + len > self.max_bytes
+        });
+        if !follows || full {
+            self.rotate(shared, seq)?;
+        }
+        let written = {
+            let Log {
+                ref mut writer,
+                ref encode_buf,
+                ..
+            } = *self;
+            let writer = writer.as_mut().expect("queue file open");
+            writer
+                .fp
+                .write_all(&header)
+                .and_then(|()| writer.fp.write_all(encode_buf))
+        };
+        if let Err(e) = written {
+            // The file may end in part of the record, so nothing more is
+            // written to it: the next item starts a new one.
+            self.writer = None;
+            return Err(e);
+        }
+        let writer = self.writer.as_mut().expect("queue file open");
+        writer.index.add(writer.bytes as u64, 1, None::<Vec<u64>>);
+        writer.bytes += len;
+        let last = self.segments.back_mut().expect("segment being written");
+        last.end = seq + 1;
+        last.bytes += len as u64;
+        shared.metrics.disk_bytes.fetch_add(len as u64, Ordering::Relaxed);
+        shared.metrics.records_written.fetch_add(1, Ordering::Relaxed);
+        shared.metrics.write_calls.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// The 'send' side of a broadcast channel
+///
+/// Created by [`broadcast`](fn.broadcast.html),
+/// `ChannelBuilder::build_broadcast` or `ChannelBuilder::build_groups`.
+/// Every item sent is delivered to each of the channel's `Subscriber`s,
+/// which consume at their own pace from one log of the items: see the
+/// `broadcast` module documentation.
+pub struct BroadcastSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> fmt::Debug for BroadcastSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BroadcastSender")
+            .field("root", &self.shared.root)
+            .field("durable", &self.shared.durable)
+            .field("metrics", &self.shared.metrics.snapshot())
+            .finish()
+    }
+}
+
+impl<T> Clone for BroadcastSender<T> {
+    fn clone(&self) -> BroadcastSender<T> {
+        self.shared.lock().senders += 1;
+        BroadcastSender {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> Drop for BroadcastSender<T> {
+    fn drop(&mut self) {
+        let mut log = self.shared.lock();
+        log.senders -= 1;
+        if log.senders == 0 {
+            let _ = log.flush();
+        }
+        drop(log);
+        self.shared.ready.notify_all();
+    }
+}
+
+impl<T> BroadcastSender<T>
+where
+    T: Serialize,
+{
+    /// Send `event` to every subscriber
+    ///
+    /// Returns an error, and delivers `event` to no one, if the item or the
+    /// item it pages out to make room cannot be written to the channel's
+    /// queue files.
+    pub fn send(&mut self, event: T) -> io::Result<()> {
+        let shared = &*self.shared;
+        let mut log = shared.lock();
+        let seq = log.next_seq;
+        if shared.durable {
+            log.append(shared, seq, &event)?;
+        }
+        if log.ring.len() >= IN_MEMORY_CAPACITY {
+            // Items every open subscriber has received are gone from memory
+            // already, so the oldest held is one some subscriber has yet to
+            // receive: it is paged out, unless every item is on disk anyway.
+            let oldest = log.ring_base;
+            let item = log.ring.pop_front().expect("memory is full");
+            if !shared.durable {
+                if let Err(e) = log.append(shared, oldest, &item) {
+                    log.ring.push_front(item);
+                    return Err(e);
+                }
+                shared.metrics.spill_events.fetch_add(1, Ordering::Relaxed);
+            }
+            log.ring_base += 1;
+        }
+        log.ring.push_back(event);
+        log.next_seq += 1;
+        shared.metrics.total_enqueued.fetch_add(1, Ordering::Relaxed);
+        shared.metrics.in_memory_depth.store(log.ring.len(), Ordering::Relaxed);
+        drop(log);
+        shared.ready.notify_all();
+        Ok(())
+    }
+
+    /// Hand the items written to the channel's queue files to the operating
+    /// system
+    ///
+    /// Written items are handed over as a subscriber needs them and as the
+    /// last BroadcastSender is dropped in any case.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.shared.lock().flush()
+    }
+
+    /// Return the number of subscribers
+    pub fn subscribers(&self) -> usize {
+        self.shared.lock().cursors.len()
+    }
+
+    /// Return a snapshot of the channel's metrics
+    ///
+    /// `total_dequeued` counts every item received by every subscriber.
+    pub fn metrics(&self) -> QueueMetrics {
+        self.shared.metrics.snapshot()
+    }
+}
+
+// The subscriber's reader of the queue file it is reading back
+struct DiskReader {
+    first: u64,
+    // The place of the next record, and its offset in the file
+    next: u64,
+    offset: u64,
+    fp: BufReader<fs::File>,
+}
+
+/// A subscriber to a broadcast channel, the 'receive' side
+///
+/// Receives every item sent to the channel after its cursor, in the order
+/// they were sent. Each subscriber has a cursor of its own, so reads at its
+/// own pace. A subscriber of a durable channel, built with
+/// `ChannelBuilder::build_groups`, commits its place with `commit`, and as it
+/// is dropped.
+pub struct Subscriber<T> {
+    shared: Arc<Shared<T>>,
+    idx: usize,
+    name: String,
+    reader: Option<DiskReader>,
+}
+
+impl<T> fmt::Debug for Subscriber<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let log = sync::try_lock(&self.shared.log);
+        let cursor = log.as_ref().map(|log| (log.cursors[self.idx].next, log.next_seq));
+        f.debug_struct("Subscriber")
+            .field("name", &self.name)
+            .field("root", &self.shared.root)
+            .field("durable", &self.shared.durable)
+            .field("cursor", &cursor)
+            .finish()
+    }
+}
+
+impl<T> Drop for Subscriber<T> {
+    fn drop(&mut self) {
+        if self.shared.durable {
+            let _ = self.commit();
+        }
+        let shared = &*self.shared;
+        let mut log = shared.lock();
+        log.cursors[self.idx].live = false;
+        log.reclaim(shared);
+    }
+}
+
+impl<T> Subscriber<T> {
+    /// Return the subscriber's name: its consumer group, or its index among
+    /// the subscribers of `build_broadcast`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the place in the channel of the next item to receive, counting
+    /// items from the channel's first
+    pub fn position(&self) -> u64 {
+        self.shared.lock().cursors[self.idx].next
+    }
+
+    /// Return the place last committed, the same as `position` unless the
+    /// channel is durable
+    pub fn committed(&self) -> u64 {
+        let log = self.shared.lock();
+        if self.shared.durable {
+            log.cursors[self.idx].committed
+        } else {
+            log.cursors[self.idx].next
+        }
+    }
+
+    /// Return the number of items sent that this subscriber has yet to
+    /// receive
+    pub fn pending(&self) -> u64 {
+        let log = self.shared.lock();
+        log.next_seq - log.cursors[self.idx].next
+    }
+
+    /// Whether every BroadcastSender has been dropped
+    pub fn is_hung_up(&self) -> bool {
+        self.shared.lock().senders == 0
+    }
+
+    /// Return a snapshot of the channel's metrics, shared by every subscriber
+    pub fn metrics(&self) -> QueueMetrics {
+        self.shared.metrics.snapshot()
+    }
+
+    /// Commit the subscriber's place, so that the channel resumes it from
+    /// there when next opened
+    ///
+    /// The place is written aside and moved into place. A channel that is not
+    /// durable keeps no places, and this does nothing.
+    pub fn commit(&mut self) -> io::Result<()> {
+        if !self.shared.durable {
+            return Ok(());
+        }
+        let next = self.shared.lock().cursors[self.idx].next;
+        let path = self.shared.offset_path(&self.name);
+        let tmp = path.with_extension(format!("{}.tmp", OFFSET_EXTENSION));
+        {
+            let mut fp = fs::File::create(&tmp)?;
+            writeln!(fp, "{}", next)?;
+            fp.sync_data()?;
+        }
+        fs::rename(&tmp, &path)?;
+        let shared = &*self.shared;
+        let mut log = shared.lock();
+        log.cursors[self.idx].committed = next;
+        log.reclaim(shared);
+        Ok(())
+    }
+}
+
+enum Next<T> {
+    Memory(T),
+    Disk { first: u64, seq: u64 },
+    Empty,
+}
+
+impl<T> Subscriber<T>
+where
+    T: DeserializeOwned + Clone,
+{
+    /// Receive the next item, if one is waiting
+    ///
+    /// A record that cannot be read back from the channel's queue files or
+    /// decoded is returned as `RecvError::Decode` and passed over: the next
+    /// call carries on with the item after it.
+    pub fn try_recv(&mut self) -> Result<Option<T>, RecvError> {
+        let shared = Arc::clone(&self.shared);
+        let next = {
+            let mut log = shared.lock();
+            let mut seq = log.cursors[self.idx].next;
+            if seq < log.ring_base && log.segment_of(seq).is_none() {
+                seq = log.skip_gap(seq);
+                log.cursors[self.idx].next = seq;
+            }
+            if seq == log.next_seq {
+                Next::Empty
+            } else if seq >= log.ring_base {
+                let item = log.ring[(seq - log.ring_base) as usize].clone();
+                Next::Memory(item)
+            } else {
+                let first = log.segment_of(seq).expect("item on disk").first;
+                if let Err(e) = log.flush() {
+                    return Err(RecvError::Decode(self.failed(seq, first, 0, e)));
+                }
+                Next::Disk {
+                    first: first,
+                    seq: seq,
+                }
+            }
+        };
+        let item = match next {
+            Next::Empty => return Ok(None),
+            Next::Memory(item) => Ok(item),
+            Next::Disk { first, seq } => self.read(first, seq),
+        };
+        let mut log = shared.lock();
+        log.cursors[self.idx].next += 1;
+        log.reclaim(&shared);
+        drop(log);
+        shared.metrics.total_dequeued.fetch_add(1, Ordering::Relaxed);
+        item.map(Some).map_err(RecvError::Decode)
+    }
+
+    /// Receive the next item, waiting for one to be sent
+    ///
+    /// Returns `None` once every BroadcastSender has been dropped and every
+    /// item sent has been received. Records that cannot be decoded are
+    /// passed over, counted in `QueueMetrics::deserialize_failures`.
+    pub fn recv(&mut self) -> Option<T> {
+        self.wait(None)
+    }
+
+    /// Receive the next item, waiting up to `timeout` for one to be sent
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Option<T> {
+        self.wait(Some(Instant::now() + timeout))
+    }
+
+    /// An iterator over the items sent, waiting for each as `recv` does
+    pub fn iter(&mut self) -> SubscriberIter<'_, T> {
+        SubscriberIter {
+            subscriber: self,
+            wait: true,
+        }
+    }
+
+    /// An iterator over the items waiting, ending once there are none
+    pub fn try_iter(&mut self) -> SubscriberIter<'_, T> {
+        SubscriberIter {
+            subscriber: self,
+            wait: false,
+        }
+    }
+
+    fn wait(&mut self, deadline: Option<Instant>) -> Option<T> {
+        loop {
+            match self.try_recv() {
+                Ok(Some(item)) => return Some(item),
+                Ok(None) => {}
+                Err(_) => continue,
+            }
+            let shared = Arc::clone(&self.shared);
+            let mut log = shared.lock();
+            while log.cursors[self.idx].next == log.next_seq {
+                if log.senders == 0 {
+                    return None;
+                }
+                log = match deadline {
+                    None => sync::wait(&shared.ready, log),
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            return None;
+                        }
+                        sync::wait_timeout(&shared.ready, log, deadline - now)
+                    }
+                };
+            }
+        }
+    }
+
+    // Read back the item at `seq` from the queue file named for `first`
+    fn read(&mut self, first: u64, seq: u64) -> Result<T, DecodeError> {
+        let positioned = self.reader
+            .as_ref()
+            .is_some_and(|reader| reader.first == first && reader.next == seq);
+        if !positioned {
+            self.reader = None;
+            let reader = self.open_reader(first, seq).map_err(|e| self.failed(seq, first, 0, e))?;
+            self.reader = Some(reader);
+        }
+        let (record, offset) = {
+            let reader = self.reader.as_mut().expect("reader positioned");
+            let offset = reader.offset;
+            match read_record(&mut reader.fp) {
+                Ok(record) => {
+                    reader.next += 1;
+                    reader.offset += 4 + record.len() as u64;
+                    (record, offset)
+                }
+                Err(e) => {
+                    self.reader = None;
+                    return Err(self.failed(seq, first, offset, e));
+                }
+            }
+        };
+        deserialize(&record).map_err(|e| self.failed(seq, first, offset, e))
+    }
+
+    // A reader of the queue file named for `first`, at the item at `seq`,
+    // read from the file's indexed record nearest before it, if it has one
+    fn open_reader(&self, first: u64, seq: u64) -> io::Result<DiskReader> {
+        let path = self.shared.segment_path(first);
+        let mut fp = BufReader::new(fs::File::open(&path)?);
+        let (mut offset, mut at) = match index::read(&path).and_then(|i| i.by_items(seq - first)) {
+            Some(entry) => (fp.seek(SeekFrom::Start(entry.offset))?, first + entry.items),
+            None => {
+                layout::skip_header(&mut fp)?;
+                (fp.stream_position()?, first)
+            }
+        };
+        while at < seq {
+            offset += 4 + read_record(&mut fp)?.len() as u64;
+            at += 1;
+        }
+        Ok(DiskReader {
+            first: first,
+            next: seq,
+            offset: offset,
+            fp: fp,
+        })
+    }
+
+    fn failed<E>(&self, seq: u64, first: u64, offset: u64, cause: E) -> DecodeError
+    where
+        E: Into<Box<dyn error::Error + Send + Sync>>,
+    {
+        self.shared.metrics.deserialize_failures.fetch_add(1, Ordering::Relaxed);
+        DecodeError {
+            channel: self.name.clone(),
+            segment: first as usize,
+            offset: offset,
+            seq: seq,
+            cause: cause.into(),
+        }
+    }
+}
+
+fn read_record<R: Read>(fp: &mut R) -> io::Result<Vec<u8>> {
+    let mut sz_buf = [0; 4];
+    fp.read_exact(&mut sz_buf)?;
+    let mut record = vec![0; u8tou32abe(&sz_buf) as usize];
+    fp.read_exact(&mut record)?;
+    Ok(record)
+}
+
+/// An iterator over the items of a `Subscriber`, see `Subscriber::iter` and
+/// `Subscriber::try_iter`
+pub struct SubscriberIter<'a, T: 'a> {
+    subscriber: &'a mut Subscriber<T>,
+    wait: bool,
+}
+
+impl<'a, T> fmt::Debug for SubscriberIter<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SubscriberIter")
+            .field("subscriber", &self.subscriber)
+            .field("wait", &self.wait)
+            .finish()
+    }
+}
+
+impl<'a, T> Iterator for SubscriberIter<'a, T>
+where
+    T: DeserializeOwned + Clone,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.wait {
+            return self.subscriber.recv();
+        }
+        loop {
+            match self.subscriber.try_recv() {
+                Ok(item) => return item,
+                Err(_) => continue,
+            }
+        }
+    }
+}
+
+/// Open the broadcast channel in `root` with a subscriber for each of
+/// `names`, its queue files rotated at `max_bytes`, durable if `durable`
+pub(crate) fn open<T>(
+    root: &Path,
+    names: Vec<String>,
+    max_bytes: usize,
+    durable: bool,
+) -> Result<(BroadcastSender<T>, Vec<Subscriber<T>>), Error>
+where
+    T: Serialize + DeserializeOwned,
+{
+    fs::create_dir_all(root)?;
+    let lock = layout::lock(root)?;
+    layout::claim(root, ::std::any::type_name::<T>(), RecordFraming::default())?;
+    let metrics = Metrics::default();
+    metrics.in_memory_capacity.store(IN_MEMORY_CAPACITY, Ordering::Relaxed);
+    metrics.segment_max_bytes.store(max_bytes, Ordering::Relaxed);
+
+    let mut ids = private::segment_ids(root, None);
+    ids.sort();
+    let mut segments = VecDeque::new();
+    for id in ids {
+        let path = root.join(format!("{}", id));
+        if !durable {
+            private::remove_segment(&path)?;
+            continue;
+        }
+        let (records, bytes) = private::segment_contents(&path)
+            .ok_or(Error::Io(ErrorKind::InvalidData))?;
+        metrics.segments_on_disk.fetch_add(1, Ordering::Relaxed);
+        metrics.disk_bytes.fetch_add(bytes, Ordering::Relaxed);
+        segments.push_back(Segment {
+            first: id as u64,
+            end: id as u64 + records,
+            bytes: bytes,
+        });
+    }
+    if durable {
+        index::fill_in(root, None, false, false);
+    }
+    let next_seq = segments.back().map_or(0, |last| last.end);
+    let oldest = segments.front().map_or(next_seq, |first| first.first);
+    let mut cursors = Vec::with_capacity(names.len());
+    for name in names {
+        let start = if durable {
+            match fs::read_to_string(offset_path(root, &name)) {
+                Ok(offset) => offset
+                    .trim()
+                    .parse::<u64>()
+                    .map_err(|_| Error::Io(ErrorKind::InvalidData))?,
+                Err(ref e) if e.kind() == ErrorKind::NotFound => oldest,
+                Err(e) => return Err(e.into()),
+            }
+        } else {
+            next_seq
+        };
+        let start = cmp::min(cmp::max(start, oldest), next_seq);
+        cursors.push(Cursor {
+            name: name,
+            next: start,
+            committed: start,
+            live: true,
+        });
+    }
+    let shared = Arc::new(Shared {
+        root: root.to_path_buf(),
+        durable: durable,
+        log: Mutex::new(Log {
+            ring: VecDeque::with_capacity(IN_MEMORY_CAPACITY),
+            ring_base: next_seq,
+            next_seq: next_seq,
+            segments: segments,
+            writer: None,
+            cursors: Vec::new(),
+            senders: 1,
+            max_bytes: max_bytes,
+            encode_buf: Vec::with_capacity(64),
+        }),
+        ready: Condvar::new(),
+        metrics: metrics,
+        _lock: lock,
+    });
+    {
+        let mut log = shared.lock();
+        log.cursors = cursors;
+        // A durable channel writes from a file of its own, so that the files
+        // before it may go once read.
+        if durable {
+            log.rotate(&shared, next_seq)?;
+        }
+        log.reclaim(&shared);
+    }
+    let subscribers = {
+        let log = shared.lock();
+        log.cursors
+            .iter()
+            .enumerate()
+            .map(|(idx, cursor)| Subscriber {
+                shared: Arc::clone(&shared),
+                idx: idx,
+                name: cursor.name.clone(),
+                reader: None,
+            })
+            .collect()
+    };
+    Ok((BroadcastSender { shared: shared }, subscribers))
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::super::{broadcast, ChannelBuilder};
+    use index;
+    use private;
+    use std::fs;
+    use std::thread;
+
+    #[test]
+    fn every_subscriber_sees_every_item() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut subs) = broadcast("fanout", dir.path(), 3).unwrap();
+        assert_eq!(3, snd.subscribers());
+
+        for i in 0..10u64 {
+            snd.send(i).unwrap();
+        }
+        for sub in &mut subs {
+            let received: Vec<u64> = sub.try_iter().collect();
+            assert_eq!((0..10).collect::<Vec<u64>>(), received);
+        }
+        assert_eq!(30, snd.metrics().total_dequeued);
+        assert_eq!(0, snd.metrics().in_memory_depth);
+    }
+
+    #[test]
+    fn slow_subscriber_reads_back_items_written_once() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut subs) = ChannelBuilder::new("fanout_slow", dir.path())
+            .max_bytes(128)
+            .build_broadcast(3)
+            .unwrap();
+        let root = dir.path().join("fanout_slow");
+
+        for i in 0..4096u64 {
+            snd.send(i).unwrap();
+            assert_eq!(Ok(Some(i)), subs[0].try_recv().map_err(|_| ()));
+        }
+        // Two subscribers lag, but each item went to disk once
+        let metrics = snd.metrics();
+        assert_eq!(4096 - 1024, metrics.records_written);
+        assert_eq!(1024, metrics.in_memory_depth);
+        assert!(metrics.segments_on_disk > 1);
+        assert_eq!(4096, subs[1].pending());
+
+        for i in 0..4096u64 {
+            assert_eq!(Some(i), subs[1].try_iter().next());
+        }
+        assert_eq!(None, subs[1].try_iter().next());
+        // The files stay until the last subscriber has read past them
+        assert_eq!(metrics.segments_on_disk, snd.metrics().segments_on_disk);
+        let received: Vec<u64> = subs[2].try_iter().collect();
+        assert_eq!((0..4096).collect::<Vec<u64>>(), received);
+        assert_eq!(1, private::segment_ids(&root, None).len());
+        assert_eq!(0, snd.metrics().in_memory_depth);
+        let dirs = fs::read_dir(&root)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().is_dir())
+            .count();
+        assert_eq!(0, dirs);
+    }
+
+    #[test]
+    fn dropped_subscribers_hold_nothing_back() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut subs) = ChannelBuilder::new("fanout_drop", dir.path())
+            .max_bytes(128)
+            .build_broadcast(2)
+            .unwrap();
+        drop(subs.pop());
+        for i in 0..4096u64 {
+            snd.send(i).unwrap();
+            assert_eq!(Some(i), subs[0].try_iter().next());
+        }
+        assert_eq!(0, snd.metrics().records_written);
+        assert_eq!(0, snd.metrics().in_memory_depth);
+    }
+
+    #[test]
+    fn subscribers_wait_for_items_until_hung_up() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut subs) = broadcast::<u64>("fanout_wait", dir.path(), 2).unwrap();
+        let mut sub = subs.pop().unwrap();
+        let jh = thread::spawn(move || sub.iter().collect::<Vec<u64>>());
+        for i in 0..100 {
+            snd.send(i).unwrap();
+        }
+        drop(snd);
+        assert_eq!((0..100).collect::<Vec<u64>>(), jh.join().unwrap());
+        assert!(subs[0].is_hung_up());
+        assert_eq!(100, subs[0].iter().count());
+    }
+
+    #[test]
+    fn groups_resume_from_their_committed_place() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let root = dir.path().join("groups");
+        let build = || {
+            ChannelBuilder::new("groups", dir.path())
+                .max_bytes(128)
+                .build_groups::<u64, _>(&["billing", "audit"])
+                .unwrap()
+        };
+        let (mut snd, mut subs) = build();
+        assert_eq!("billing", subs[0].name());
+        assert_eq!("audit", subs[1].name());
+        for i in 0..100u64 {
+            snd.send(i).unwrap();
+        }
+        for _ in 0..60 {
+            subs[0].try_recv().unwrap();
+        }
+        subs[0].commit().unwrap();
+        for _ in 0..10 {
+            subs[0].try_recv().unwrap();
+        }
+        assert_eq!((70, 60), (subs[0].position(), subs[0].committed()));
+        for _ in 0..20 {
+            subs[1].try_recv().unwrap();
+        }
+        assert!(root.join("billing.offset").is_file());
+        assert!(!root.join("audit.offset").exists());
+        assert!(private::segment_ids(&root, None).len() > 1);
+
+        // Each group commits its place as it is dropped
+        drop((snd, subs));
+        let (mut snd, mut subs) = build();
+        assert_eq!((70, 20), (subs[0].position(), subs[1].position()));
+        snd.send(100).unwrap();
+        let billing: Vec<u64> = subs[0].try_iter().collect();
+        assert_eq!((70..101).collect::<Vec<u64>>(), billing);
+        let audit: Vec<u64> = subs[1].try_iter().collect();
+        assert_eq!((20..101).collect::<Vec<u64>>(), audit);
+    }
+
+    #[test]
+    fn groups_resume_from_the_indexed_record() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let segment = dir.path().join("indexed").join("0");
+        let build = || {
+            ChannelBuilder::new("indexed", dir.path())
+                .build_groups::<u64, _>(&["a"])
+                .unwrap()
+        };
+        let (mut snd, mut subs) = build();
+        for i in 0..1000u64 {
+            snd.send(i).unwrap();
+        }
+        for _ in 0..700 {
+            subs[0].try_recv().unwrap();
+        }
+        drop((snd, subs));
+        // Opening again indexes the file the last session left unfinished.
+        drop(build());
+        let entries = index::read(&segment).unwrap().entries().to_vec();
+        assert_eq!(640, entries[5].items);
+
+        // An index pointing ten records further on than it should shows the
+        // group is resumed from it rather than by reading from the start.
+        let doctored = format!("0 - {}\n640 - {}\n", entries[0].offset, entries[5].offset + 120);
+        fs::write(index::path(&segment), doctored).unwrap();
+        let (_snd, mut subs) = build();
+        assert_eq!(Some(710), subs[0].try_recv().unwrap());
+    }
+
+    #[test]
+    fn groups_start_at_the_oldest_item_kept() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        {
+            let (mut snd, mut subs) = ChannelBuilder::new("groups_new", dir.path())
+                .build_groups::<u64, _>(&["a"])
+                .unwrap();
+            for i in 0..10u64 {
+                snd.send(i).unwrap();
+            }
+            assert_eq!(10, subs[0].try_iter().count());
+        }
+        let (_snd, mut subs) = ChannelBuilder::new("groups_new", dir.path())
+            .build_groups::<u64, _>(&["a", "b"])
+            .unwrap();
+        assert_eq!(0, subs[0].try_iter().count());
+        let b: Vec<u64> = subs[1].try_iter().collect();
+        assert_eq!((0..10).collect::<Vec<u64>>(), b);
+    }
+
+    #[test]
+    fn groups_remove_files_every_group_has_committed() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let root = dir.path().join("groups_reclaim");
+        let (mut snd, mut subs) = ChannelBuilder::new("groups_reclaim", dir.path())
+            .max_bytes(128)
+            .build_groups::<u64, _>(&["a", "b"])
+            .unwrap();
+        for i in 0..1000u64 {
+            snd.send(i).unwrap();
+        }
+        assert_eq!(1000, subs[0].try_iter().count());
+        subs[0].commit().unwrap();
+        let files = private::segment_ids(&root, None).len();
+        assert!(files > 1);
+        assert_eq!(1000, subs[1].try_iter().count());
+        assert_eq!(files, private::segment_ids(&root, None).len());
+        subs[1].commit().unwrap();
+        assert_eq!(1, private::segment_ids(&root, None).len());
+    }
+}
@@ -0,0 +1,132 @@
+//! A memory budget shared by many channels
+//!
+//! `ChannelBuilder::memory_budget` bounds each channel on its own, so a
+//! process with many channels must either give each a small budget or risk
+//! them all filling at once. A `Budget` is instead joined by any number of
+//! channels through `ChannelBuilder::shared_budget`. Each channel counts the
+//! encoded size of the items it holds in memory, in its in-memory tier and
+//! its disk buffer alike, against the budget as they are sent and gives it
+//! back as they are received or paged out. A channel sending an item that
+//! would take the channels together over the budget closes its in-memory
+//! tier, and while they are over it pages its disk buffer out to disk with
+//! each send, so a burst on one channel goes to disk rather than growing the
+//! process. A memory-only channel counts its items against the budget but,
+//! having nowhere to page them out to, is not held to it.
+use std::cmp;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A memory budget channels may share, see the module documentation
+///
+/// Clones refer to the same budget.
+#[derive(Clone)]
+pub struct Budget {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+impl fmt::Debug for Budget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Budget")
+            .field("limit", &self.limit())
+            .field("used", &self.used())
+            .finish()
+    }
+}
+
+impl Budget {
+    /// A budget of `bytes` of encoded items
+    pub fn new(bytes: usize) -> Budget {
+        Budget {
+            shared: Arc::new(Shared {
+                limit: bytes,
+                used: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Return the bytes the budget allows
+    pub fn limit(&self) -> usize {
+        self.shared.limit
+    }
+
+    /// Return the bytes the channels sharing the budget hold in memory
+    pub fn used(&self) -> usize {
+        self.shared.used.load(Ordering::Relaxed)
+    }
+
+    /// Whether `bytes` more would fit in the budget
+    pub(crate) fn has_room(&self, bytes: usize) -> bool {
+        self.used().saturating_add(bytes) <= self.shared.limit
+    }
+}
+
+/// One channel's share of a `Budget`, given back as it is dropped
+#[derive(Debug)]
+pub struct Charge {
+    budget: Budget,
+    bytes: usize,
+}
+
+impl Charge {
+    pub fn new(budget: Budget) -> Charge {
+        Charge {
+            budget: budget,
+            bytes: 0,
+        }
+    }
+
+    pub fn budget(&self) -> &Budget {
+        &self.budget
+    }
+
+    /// Count `bytes` more against the budget
+    pub fn add(&mut self, bytes: usize) {
+        self.bytes += bytes;
+        self.budget.shared.used.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Give `bytes` back to the budget
+    pub fn release(&mut self, bytes: usize) {
+        let bytes = cmp::min(bytes, self.bytes);
+        self.bytes -= bytes;
+        self.budget.shared.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        let bytes = self.bytes;
+        self.release(bytes);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn charges_are_given_back() {
+        let budget = Budget::new(100);
+        let mut a = Charge::new(budget.clone());
+        let mut b = Charge::new(budget.clone());
+        a.add(60);
+        assert!(budget.has_room(40));
+        assert!(!budget.has_room(41));
+        b.add(40);
+        assert!(budget.has_room(0));
+        b.add(1);
+        assert!(!budget.has_room(0));
+        a.release(10);
+        assert_eq!(91, budget.used());
+        drop(b);
+        assert_eq!(50, budget.used());
+        a.release(500);
+        assert_eq!(0, budget.used());
+    }
+}
@@ -0,0 +1,1530 @@
+use super::{private, Error, QueueEvent, Receiver, Sender};
+use archive::Archiver;
+use broadcast::{self, BroadcastSender, Subscriber};
+use budget::{Budget, Charge};
+use clock::Clock;
+use config::QueueConfig;
+use dead_letter;
+use delay;
+#[cfg(feature = "dictionary")]
+use dictionary;
+#[cfg(feature = "cgroup")]
+use cgroup::{self, CgroupMemory};
+#[cfg(feature = "encryption")]
+use crypt;
+#[cfg(feature = "dynamic")]
+use dynamic::{self, DynamicReceiver, DynamicSender, TypeRegistry};
+use index;
+use layout;
+use metrics::Metrics;
+use mux::{self, Mux};
+use paged::Paged;
+use rate::{RateLimit, RatePolicy};
+use raw::{self, RawReceiver, RawSender};
+use reserve::{Reservations, Share};
+use repair;
+use retention::{self, Retention};
+use retry::{Retries, RetryPolicy};
+use runtime::Runtime;
+#[cfg(feature = "sim")]
+use sim::Simulation;
+use scrub::{self, Scrubber};
+use shard::{self, ShardedReceiver, ShardedSender};
+use snapshot;
+use supervise::Supervisor;
+use sync::Mutex;
+use topology::{self, ChannelDescription};
+use verify::{self, VerifyLevel};
+use watermark::Watermarks;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::cmp;
+use std::fmt;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+#[cfg(feature = "cgroup")]
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+// A channel's Sender and Receiver, as `build` returns them
+type Pair<T> = (Sender<T>, Receiver<T>);
+
+/// The ordering guarantee a channel gives its Receiver
+///
+/// See the crate documentation, "What ordering does hopper guarantee?", for
+/// the details.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OrderMode {
+    /// Items are received in exactly the order their `send` calls completed,
+    /// across every Sender of the channel and across the in-memory and disk
+    /// tiers. This is the default.
+    #[default]
+    Global,
+    /// Items from any one Sender are received in the order that Sender sent
+    /// them but items from different Senders may interleave arbitrarily. Each
+    /// Sender stages a small batch of items locally and takes the channel lock
+    /// once per batch, reducing contention between many Senders. Staged items
+    /// become visible to the Receiver when the batch fills, when
+    /// `Sender::flush` is called or when the Sender is dropped.
+    PerSender,
+}
+
+/// What a send to a full memory-only channel, or one held at its disk quota,
+/// does
+///
+/// See `ChannelBuilder::memory_only` and `ChannelBuilder::max_disk_bytes`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait until the Receiver has made room. Sends to a channel whose
+    /// Receiver has been dropped are discarded rather than waiting for ever.
+    /// `Sender::send_timeout` and `Sender::send_deadline` bound the wait.
+    Block,
+    /// Refuse the item: `Sender::try_send` returns `SendError::Full`, or
+    /// `SendError::DiskFull` at a disk quota, and `Sender::send` drops it,
+    /// counting it in `QueueMetrics::total_overflowed` or
+    /// `QueueMetrics::total_disk_full`. This is the default.
+    #[default]
+    Fail,
+}
+
+/// Changes to the limits of a running channel, applied with
+/// `Sender::reconfigure`
+///
+/// A field left `None` leaves that limit as it is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConfigDelta {
+    /// The number of items the in-memory tier, and then the disk buffer,
+    /// holds before paging out
+    pub in_memory_capacity: Option<usize>,
+    /// The channel's `ChannelBuilder::memory_budget`
+    pub memory_budget: Option<usize>,
+    /// The channel's `ChannelBuilder::max_disk_bytes`
+    pub max_disk_bytes: Option<usize>,
+    /// The (high, low) marks of `ChannelBuilder::watermarks`
+    pub watermarks: Option<(usize, usize)>,
+    /// The (high, low) marks of `ChannelBuilder::byte_watermarks`
+    pub byte_watermarks: Option<(u64, u64)>,
+}
+
+/// What the Receiver does with a queue file record it cannot decrypt or
+/// decode
+///
+/// See `ChannelBuilder::corruption_policy`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum CorruptionPolicy {
+    /// Panic, as the record's item cannot be handed back, or return
+    /// `RecvError::Decode` from `Receiver::try_recv`. This is the default.
+    #[default]
+    Abort,
+    /// Drop the record and carry on with the next one
+    Skip,
+    /// Move the record under the given directory and carry on with the next
+    /// one. See `dead_letter`.
+    DeadLetter(PathBuf),
+}
+
+/// Configure and create a hopper channel
+///
+/// `ChannelBuilder` collects the options that shape a channel before calling
+/// `build` to create the (Sender, Receiver) pair. The free functions
+/// [`channel`](fn.channel.html) and
+/// [`channel_with_max_bytes`](fn.channel_with_max_bytes.html) are shorthands
+/// for the common cases.
+///
+/// # Example
+/// ```
+/// extern crate tempdir;
+/// extern crate hopper;
+///
+/// let dir = tempdir::TempDir::new("hopper").unwrap();
+/// let (mut snd, mut rcv) = hopper::ChannelBuilder::new("example", dir.path())
+///     .max_bytes(4096)
+///     .build()
+///     .unwrap();
+///
+/// snd.send(9);
+/// assert_eq!(Some(9), rcv.try_iter().next());
+/// ```
+#[derive(Clone)]
+pub struct ChannelBuilder {
+    name: String,
+    data_dir: PathBuf,
+    max_bytes: usize,
+    adaptive_max_bytes: Option<(usize, usize)>,
+    order: OrderMode,
+    priority_lanes: bool,
+    priority_aging: Option<Duration>,
+    flush_on_drop: bool,
+    stage_writes: bool,
+    coalesce_wakes: Option<(usize, Duration)>,
+    memory_budget: Option<usize>,
+    #[cfg(feature = "cgroup")]
+    cgroup_budget: Option<(f64, Duration)>,
+    shared_budget: Option<Budget>,
+    reservations: Vec<Share>,
+    runtime: Option<Runtime>,
+    clock: Option<Arc<dyn Clock>>,
+    read_ahead: usize,
+    block_bytes: usize,
+    #[cfg(feature = "compression")]
+    compress_blocks: bool,
+    #[cfg(feature = "dictionary")]
+    dictionary: Option<(usize, usize)>,
+    #[cfg(feature = "preallocate")]
+    preallocate: bool,
+    memory_only: bool,
+    overflow: OverflowPolicy,
+    max_disk_bytes: Option<usize>,
+    evict_oldest: bool,
+    retry: RetryPolicy,
+    disk_primary: bool,
+    #[cfg(feature = "encryption")]
+    encryption_key: Option<[u8; 32]>,
+    ttl: Option<Duration>,
+    stamp_sends: bool,
+    provenance: bool,
+    sequenced: bool,
+    archive_dir: Option<PathBuf>,
+    corruption: CorruptionPolicy,
+    retention: Retention,
+    scrub: Option<(Duration, bool)>,
+    verify: VerifyLevel,
+    repair_on_open: bool,
+    restore_from: Option<PathBuf>,
+    watermarks: Watermarks,
+    sampling_floor: Option<f64>,
+    rate_limit: Option<RateLimit>,
+    ephemeral: Option<Arc<private::EphemeralDir>>,
+    observer: Option<private::Observer>,
+    // The supervisor of the channel a lane belongs to, for a lane's builder
+    supervisor: Option<Supervisor>,
+}
+
+impl fmt::Debug for ChannelBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ChannelBuilder")
+            .field("name", &self.name)
+            .field("data_dir", &self.data_dir)
+            .field("max_bytes", &self.max_bytes)
+            .field("adaptive_max_bytes", &self.adaptive_max_bytes)
+            .field("order", &self.order)
+            .field("priority_lanes", &self.priority_lanes)
+            .field("priority_aging", &self.priority_aging)
+            .field("flush_on_drop", &self.flush_on_drop)
+            .field("stage_writes", &self.stage_writes)
+            .field("coalesce_wakes", &self.coalesce_wakes)
+            .field("memory_budget", &self.memory_budget)
+            .field("shared_budget", &self.shared_budget)
+            .field("reservations", &self.reservations)
+            .field("read_ahead", &self.read_ahead)
+            .field("block_bytes", &self.block_bytes)
+            .field("runtime", &self.runtime)
+            .field("clock", &self.clock)
+            .field("memory_only", &self.memory_only)
+            .field("overflow", &self.overflow)
+            .field("max_disk_bytes", &self.max_disk_bytes)
+            .field("evict_oldest", &self.evict_oldest)
+            .field("retry", &self.retry)
+            .field("disk_primary", &self.disk_primary)
+            .field("ttl", &self.ttl)
+            .field("stamp_sends", &self.stamp_sends)
+            .field("provenance", &self.provenance)
+            .field("sequenced", &self.sequenced)
+            .field("archive_dir", &self.archive_dir)
+            .field("corruption", &self.corruption)
+            .field("retention", &self.retention)
+            .field("scrub", &self.scrub)
+            .field("verify", &self.verify)
+            .field("repair_on_open", &self.repair_on_open)
+            .field("restore_from", &self.restore_from)
+            .field("watermarks", &self.watermarks)
+            .field("sampling_floor", &self.sampling_floor)
+            .field("rate_limit", &self.rate_limit)
+            .field("ephemeral", &self.ephemeral.is_some())
+            .field("observer", &self.observer.is_some())
+            .finish()
+    }
+}
+
+impl ChannelBuilder {
+    /// Begin configuring a channel named `name` whose queue files are stored
+    /// under `data_dir`
+    pub fn new<S>(name: S, data_dir: &Path) -> ChannelBuilder
+    where
+        S: Into<String>,
+    {
+        ChannelBuilder {
+            name: name.into(),
+            data_dir: data_dir.to_path_buf(),
+            max_bytes: 1_048_576 * 100,
+            adaptive_max_bytes: None,
+            order: OrderMode::default(),
+            priority_lanes: false,
+            priority_aging: None,
+            flush_on_drop: true,
+            stage_writes: false,
+            coalesce_wakes: None,
+            memory_budget: None,
+            #[cfg(feature = "cgroup")]
+            cgroup_budget: None,
+            shared_budget: None,
+            reservations: Vec::new(),
+            runtime: None,
+            clock: None,
+            read_ahead: 0,
+            block_bytes: 0,
+            #[cfg(feature = "compression")]
+            compress_blocks: false,
+            #[cfg(feature = "dictionary")]
+            dictionary: None,
+            #[cfg(feature = "preallocate")]
+            preallocate: false,
+            memory_only: false,
+            overflow: OverflowPolicy::default(),
+            max_disk_bytes: None,
+            evict_oldest: false,
+            retry: RetryPolicy::default(),
+            disk_primary: false,
+            #[cfg(feature = "encryption")]
+            encryption_key: None,
+            ttl: None,
+            stamp_sends: false,
+            provenance: false,
+            sequenced: false,
+            archive_dir: None,
+            corruption: CorruptionPolicy::default(),
+            retention: Retention::default(),
+            scrub: None,
+            verify: VerifyLevel::None,
+            repair_on_open: false,
+            restore_from: None,
+            watermarks: Watermarks::default(),
+            sampling_floor: None,
+            rate_limit: None,
+            ephemeral: None,
+            observer: None,
+            supervisor: None,
+        }
+    }
+
+    /// Begin configuring a channel as `config` describes
+    ///
+    /// Every option of `config` is set as its builder method would set it,
+    /// in the order `QueueConfig` lists them; options of a feature this build
+    /// lacks are passed over. Those a configuration cannot hold--the clock,
+    /// runtime, shared budget, event callback and encryption key--may be set
+    /// on the builder returned. See the `config` module.
+    pub fn from_config(config: &QueueConfig) -> ChannelBuilder {
+        let mut builder = ChannelBuilder::new(config.name.clone(), &config.data_dir);
+        if config.ephemeral {
+            builder = builder.ephemeral();
+        }
+        builder = builder
+            .max_bytes(config.max_bytes)
+            .order(config.order)
+            .flush_on_drop(config.flush_on_drop)
+            .stage_writes(config.stage_writes)
+            .read_ahead(config.read_ahead)
+            .pack_records(config.pack_records)
+            .memory_only(config.memory_only)
+            .disk_primary(config.disk_primary)
+            .overflow_policy(config.overflow_policy)
+            .evict_oldest(config.evict_oldest)
+            .retry_writes(config.retry_writes.0, config.retry_writes.1)
+            .stamp_send_times(config.stamp_send_times)
+            .record_provenance(config.record_provenance)
+            .sequence_senders(config.sequence_senders)
+            .verify_on_open(config.verify_on_open)
+            .repair_on_open(config.repair_on_open)
+            .corruption_policy(config.corruption_policy.clone());
+        if let Some((min, max)) = config.adaptive_max_bytes {
+            builder = builder.adaptive_max_bytes(min, max);
+        }
+        if config.priority_lanes {
+            builder = builder.priority_lanes();
+        }
+        if let Some(after) = config.priority_aging {
+            builder = builder.priority_aging(after);
+        }
+        if let Some((records, interval)) = config.coalesce_wakes {
+            builder = builder.coalesce_wakes(records, interval);
+        }
+        if let Some(bytes) = config.memory_budget {
+            builder = builder.memory_budget(bytes);
+        }
+        #[cfg(feature = "cgroup")]
+        {
+            if let Some((percent, interval)) = config.cgroup_memory_budget {
+                builder = builder.cgroup_memory_budget(percent, interval);
+            }
+        }
+        for &(ref group, memory_bytes, disk_bytes) in &config.reserve {
+            builder = builder.reserve(group, memory_bytes, disk_bytes);
+        }
+        #[cfg(feature = "compression")]
+        {
+            builder = builder.compress_blocks(config.compress_blocks);
+        }
+        #[cfg(feature = "dictionary")]
+        {
+            if let Some((samples, max_bytes)) = config.train_dictionary {
+                builder = builder.train_dictionary(samples, max_bytes);
+            }
+        }
+        #[cfg(feature = "preallocate")]
+        {
+            builder = builder.preallocate(config.preallocate);
+        }
+        if let Some(bytes) = config.max_disk_bytes {
+            builder = builder.max_disk_bytes(bytes);
+        }
+        if let Some(ttl) = config.ttl {
+            builder = builder.ttl(ttl);
+        }
+        if let Some(ref archive_dir) = config.archive_dir {
+            builder = builder.archive_dir(archive_dir);
+        }
+        if let Some(keep_for) = config.keep_for {
+            builder = builder.keep_for(keep_for);
+        }
+        if let Some(bytes) = config.keep_bytes {
+            builder = builder.keep_bytes(bytes);
+        }
+        if let Some((interval, quarantine)) = config.scrub_retained {
+            builder = builder.scrub_retained(interval, quarantine);
+        }
+        if let Some(ref path) = config.restore_from {
+            builder = builder.restore_from(path);
+        }
+        if let Some((high, low)) = config.watermarks {
+            builder = builder.watermarks(high, low);
+        }
+        if let Some((high, low)) = config.byte_watermarks {
+            builder = builder.byte_watermarks(high, low);
+        }
+        if let Some(min_rate) = config.adaptive_sampling {
+            builder = builder.adaptive_sampling(min_rate);
+        }
+        if let Some((per_second, burst, policy)) = config.rate_limit {
+            builder = builder.rate_limit(per_second, burst, policy);
+        }
+        if let Some((per_second, burst, policy)) = config.sender_rate_limit {
+            builder = builder.sender_rate_limit(per_second, burst, policy);
+        }
+        builder
+    }
+
+    /// Return the name of the channel being configured
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Change the directory the channel's queue files are stored under
+    pub fn data_dir(mut self, data_dir: &Path) -> ChannelBuilder {
+        self.data_dir = data_dir.to_path_buf();
+        self.ephemeral = None;
+        self
+    }
+
+    /// Store the channel's queue files in a fresh temporary directory,
+    /// removed once the channel is dropped
+    ///
+    /// For tests and short-lived pipelines. The directory is made under the
+    /// system's temporary directory in place of `data_dir`, and setting
+    /// `data_dir` afterwards turns this off. Once the last Sender and the
+    /// Receiver are dropped the directory is removed with its queue files and
+    /// lock file, whether or not the channel spilled. Queue files moved to an
+    /// `archive_dir` are not removed, nor is anything if the process dies.
+    pub fn ephemeral(mut self) -> ChannelBuilder {
+        let dir = private::EphemeralDir::new();
+        self.data_dir = dir.path.clone();
+        self.ephemeral = Some(Arc::new(dir));
+        self
+    }
+
+    /// Set the maximum size of hopper's queue files
+    ///
+    /// This is not the total disk allocation that may be made. Defaults to
+    /// 100MB.
+    pub fn max_bytes(mut self, max_bytes: usize) -> ChannelBuilder {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Let hopper adapt the queue file size between `min` and `max` bytes
+    ///
+    /// When enabled hopper observes how quickly each queue file fills. Files
+    /// that fill in under a second double the size of the next file, up to
+    /// `max`, so fast channels rotate less often. Files that take more than
+    /// thirty seconds to fill halve the size of the next file, down to `min`,
+    /// so slow channels reclaim disk space sooner. The size hopper has settled
+    /// on is reported by `Sender::segment_max_bytes`. The value given to
+    /// `max_bytes` is used as the starting size, clamped into the bounds.
+    pub fn adaptive_max_bytes(mut self, min: usize, max: usize) -> ChannelBuilder {
+        self.adaptive_max_bytes = Some((min, max));
+        self
+    }
+
+    /// Set the ordering guarantee of the channel, `OrderMode::Global` by
+    /// default
+    pub fn order(mut self, order: OrderMode) -> ChannelBuilder {
+        self.order = order;
+        self
+    }
+
+    /// Give the channel high and low priority lanes alongside the normal one
+    ///
+    /// Items are put on a lane with `Sender::send_with_priority`; see
+    /// `Priority` for how the Receiver drains them. Each lane is a queue in
+    /// its own right, stored in a subdirectory of the channel's directory and
+    /// paged to disk independently under the same configuration, save that
+    /// the lanes share `max_disk_bytes` and `keep_bytes` and give up the room
+    /// of lower lanes first. Metrics reported by the Sender and Receiver
+    /// cover the normal lane only.
+    pub fn priority_lanes(mut self) -> ChannelBuilder {
+        self.priority_lanes = true;
+        self
+    }
+
+    /// Give the channel priority lanes, as `priority_lanes` does, and promote
+    /// items that have waited `after` on a lower lane
+    ///
+    /// An item is taken as a lane higher than the one it was sent on once it
+    /// has waited `after`, and as two lanes higher once it has waited twice
+    /// that, so a low priority item waits no longer than twice `after` behind
+    /// a busy high lane. Among items of the same effective priority the one
+    /// that has waited longest is received first. Waits are measured from the
+    /// send on the channel's clock, so every item is stamped, adding eight
+    /// bytes to each paged out to disk. The Receiver reads the head of each
+    /// lower lane ahead of its turn to learn its age, and holds it until it
+    /// is received.
+    pub fn priority_aging(mut self, after: Duration) -> ChannelBuilder {
+        self.priority_lanes = true;
+        self.priority_aging = Some(after);
+        self
+    }
+
+    /// Whether a dropped Sender hands the items it has staged to the channel,
+    /// true by default
+    ///
+    /// Only `OrderMode::PerSender` Senders stage items. Handing them over
+    /// takes the channel lock, so a channel whose Senders are created and
+    /// dropped at a high rate may prefer to turn this off and lose whatever
+    /// was staged at the drop. `Sender::flush` still hands items over.
+    pub fn flush_on_drop(mut self, flush: bool) -> ChannelBuilder {
+        self.flush_on_drop = flush;
+        self
+    }
+
+    /// Have each Sender encode the items it sends while the channel pages out,
+    /// false by default
+    ///
+    /// Items bound for disk are otherwise encoded as they are paged out, under
+    /// the channel lock, and Senders spilling from many threads wait on one
+    /// another to encode. With staged writes a Sender that found the channel
+    /// paging out at its last send encodes its items before taking the lock,
+    /// each Sender clone into buffers of its own, and the disk buffer is
+    /// written out from those encodings in the order the items reached the
+    /// channel. An item bound for disk is held both whole and encoded until
+    /// it is written, and one encoded as the channel stops paging out is
+    /// encoded for nothing.
+    pub fn stage_writes(mut self, stage: bool) -> ChannelBuilder {
+        self.stage_writes = stage;
+        self
+    }
+
+    /// Wake a draining Receiver at most once per `records` items or per
+    /// `interval`
+    ///
+    /// A Sender that moves the queue from empty to non-empty wakes whoever
+    /// waits on the Receiver, a condvar signal at least. A consumer that
+    /// keeps pace with a burst finds the queue empty after nearly every item
+    /// and is woken for nearly every one. With wakes coalesced, while the
+    /// Receiver is draining--it has taken items within the last `interval`--a
+    /// send that would wake it holds the wake back until `records` items have
+    /// arrived or `interval` has passed since the first of them, and the
+    /// consumer takes them in one go. A wait through `Select`, and so through
+    /// the Receiver's own blocking calls, sees items within `interval` of
+    /// their send however few follow. A waker registered with
+    /// `Receiver::register_waker` is woken by the send that completes the
+    /// count or comes after the interval. A Receiver idle for longer than
+    /// `interval`, and `readiness_fd`, are woken as without coalescing.
+    pub fn coalesce_wakes(mut self, records: usize, interval: Duration) -> ChannelBuilder {
+        self.coalesce_wakes = Some((records, interval));
+        self
+    }
+
+    /// Bound the channel's memory by bytes rather than by item count
+    ///
+    /// By default the in-memory tier holds 1024 items and items bound for
+    /// disk are paged out 1024 at a time, whatever their size. With a budget
+    /// the in-memory tier holds items until their encoded size would pass
+    /// `bytes`, and the disk buffer is paged out once its items' encoded size
+    /// reaches `bytes`, so the channel holds about twice `bytes` in memory. An
+    /// item is sized by measuring its encoding as it is sent. Each lane of a
+    /// channel with `priority_lanes` has a budget of its own.
+    pub fn memory_budget(mut self, bytes: usize) -> ChannelBuilder {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Set the channel's `memory_budget` to `percent` of its cgroup's memory
+    /// limit
+    ///
+    /// In a container the memory the process may use is bounded by its
+    /// cgroup v2 `memory.max` rather than by the host's RAM. The budget is
+    /// taken from the limit as the channel is built and re-read every
+    /// `interval`, so a channel whose container is resized follows suit with
+    /// its next send. A process whose cgroup has no limit, or that is not in
+    /// a cgroup v2 hierarchy, keeps the `memory_budget` set on the builder,
+    /// if any. Remember the channel holds about twice its budget in memory.
+    #[cfg(feature = "cgroup")]
+    pub fn cgroup_memory_budget(mut self, percent: f64, interval: Duration) -> ChannelBuilder {
+        self.cgroup_budget = Some((percent, interval));
+        self
+    }
+
+    /// Hold the channel to a memory budget it shares with other channels
+    ///
+    /// Items are sized as for `memory_budget`, which still bounds the channel
+    /// on its own if set too. The channel counts the items it holds in memory
+    /// against `budget` and pages them out to disk while the channels sharing
+    /// it hold more than it allows; see `Budget` for how. Each lane of a
+    /// channel with `priority_lanes` joins the same budget.
+    pub fn shared_budget(mut self, budget: &Budget) -> ChannelBuilder {
+        self.shared_budget = Some(budget.clone());
+        self
+    }
+
+    /// Reserve a share of the channel's room for the Senders of `group`
+    ///
+    /// The group's Senders, those that have called `Sender::join_group`, may
+    /// always send within `memory_bytes` of encoded items on a memory-only
+    /// channel, or `memory_bytes` and `disk_bytes` together on one that
+    /// pages out, so long as the channel has room; see the `reserve` module
+    /// for how. Other Senders are refused, as `OverflowPolicy` says, rather
+    /// than take room a group has reserved and left unused. Shares are of the
+    /// channel's `memory_budget` when it is memory-only and of its
+    /// `max_disk_bytes` otherwise, and have no effect on a channel with
+    /// neither. Items are sized as they are sent, and every item records its
+    /// Sender, as with `record_provenance`. Call once for each group; a group
+    /// reserved twice keeps its first shares. Each lane of a channel with
+    /// `priority_lanes` reserves the shares of its own.
+    pub fn reserve(
+        mut self,
+        group: &str,
+        memory_bytes: usize,
+        disk_bytes: usize,
+    ) -> ChannelBuilder {
+        if self.reservations.iter().all(|share| share.name != group) {
+            self.reservations.push(Share {
+                name: group.to_string(),
+                memory: memory_bytes,
+                disk: disk_bytes,
+            });
+        }
+        self
+    }
+
+    /// Read up to `bytes` of queue file ahead of the Receiver
+    ///
+    /// By default the Receiver reads each record from disk as it comes to it,
+    /// so draining a large backlog waits on a read for every record. With
+    /// read-ahead the Receiver starts a thread that reads the records of the
+    /// queue file being drained into memory, keeping up to `bytes` of them
+    /// staged, and takes records from there. Records are decrypted and
+    /// decoded as the Receiver takes them. Zero, the default, turns
+    /// read-ahead off. Each lane of a channel with `priority_lanes` reads
+    /// ahead on its own.
+    pub fn read_ahead(mut self, bytes: usize) -> ChannelBuilder {
+        self.read_ahead = bytes;
+        self
+    }
+
+    /// Page items out in blocks of up to `bytes` of records each
+    ///
+    /// By default each item paged out is a queue file record of its own,
+    /// with a header of its own. Packed, the records of a spill are gathered
+    /// into blocks of about `bytes`, each written as one record with one
+    /// header and a checksum over the block, and the Receiver takes the
+    /// block's records one at a time as ever. A backlog of many small items
+    /// then replays in fewer reads, and with `compress_blocks` takes less
+    /// disk. Zero, the default, turns packing off. A block failing its
+    /// checksum is damage to the queue file, as a truncated record is,
+    /// rather than a record to pass over by the `CorruptionPolicy`.
+    pub fn pack_records(mut self, bytes: usize) -> ChannelBuilder {
+        self.block_bytes = bytes;
+        self
+    }
+
+    /// Compress the blocks of a channel with `pack_records`
+    ///
+    /// Requires the `compression` feature. Each block is compressed with LZ4
+    /// where that makes it smaller. Records sealed with an `encryption_key`
+    /// do not compress, and `Sender::rekey` leaves compressed blocks as they
+    /// are.
+    #[cfg(feature = "compression")]
+    pub fn compress_blocks(mut self, compress: bool) -> ChannelBuilder {
+        self.compress_blocks = compress;
+        self
+    }
+
+    /// Compress the blocks of a channel with `pack_records` with a zstd
+    /// dictionary trained on its own records
+    ///
+    /// Requires the `dictionary` feature. The first `samples` records paged
+    /// out are sampled and a dictionary of up to `max_bytes` trained on them,
+    /// a hundredth of the bytes sampled being a good size, and every block
+    /// after is compressed with it where that makes it smaller. Blocks
+    /// written before then are compressed as they would be without.
+    /// Dictionaries are stored in the channel's directory by version, and a
+    /// channel opened on a directory holding one uses the newest from the
+    /// start; `Sender::retrain_dictionary` trains the next. See the
+    /// `dictionary` module for the layout. Records sealed with an
+    /// `encryption_key` do not compress.
+    #[cfg(feature = "dictionary")]
+    pub fn train_dictionary(mut self, samples: usize, max_bytes: usize) -> ChannelBuilder {
+        self.dictionary = Some((samples, max_bytes));
+        self
+    }
+
+    /// Reserve disk for each new queue file up to the size it rotates at
+    ///
+    /// Requires the `preallocate` feature. By default a queue file grows a
+    /// page at a time as records are appended, which on ext4 and xfs means
+    /// allocating as the Sender writes and a file scattered about the disk.
+    /// Preallocated, each file has its blocks reserved with `fallocate` when
+    /// it is created, `max_bytes` of them or the size chosen by
+    /// `adaptive_max_bytes`, keeping its length as it was so the Receiver
+    /// reads it as ever. The reservation is released when the file is
+    /// removed. Filesystems without `fallocate`, and platforms other than
+    /// Linux, go on growing files as they are written.
+    #[cfg(feature = "preallocate")]
+    pub fn preallocate(mut self, preallocate: bool) -> ChannelBuilder {
+        self.preallocate = preallocate;
+        self
+    }
+
+    /// Run the channel's background work on `runtime` rather than on threads
+    /// of its own
+    ///
+    /// Covers the Receiver's `read_ahead`, the collector of a `keep_for`
+    /// retention and any `Sender::spawn_flusher`. See `Runtime` for how the
+    /// work is shared out. Each lane of a channel with `priority_lanes` runs
+    /// on the same runtime.
+    pub fn runtime(mut self, runtime: &Runtime) -> ChannelBuilder {
+        self.runtime = Some(runtime.clone());
+        self
+    }
+
+    /// Run the channel's background work on `simulation` and read the time
+    /// from its clock
+    ///
+    /// Requires the `sim` feature. As `runtime(simulation.runtime())` and
+    /// `clock(simulation.clock())` together, see the `sim` module.
+    #[cfg(feature = "sim")]
+    pub fn simulation(self, simulation: &Simulation) -> ChannelBuilder {
+        self.runtime(simulation.runtime()).clock(simulation.clock())
+    }
+
+    /// Read the time from `clock` rather than the system clock
+    ///
+    /// Meant for tests: with a `testing::ManualClock` a test can expire items
+    /// past their `ttl`, bring items sent with `Sender::send_after` due and
+    /// age retained files past `keep_for` by moving the clock on, rather than
+    /// by sleeping. See the `clock` module for what the clock does not cover.
+    /// Each lane of a channel with `priority_lanes` reads the same clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> ChannelBuilder {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Keep the channel's items in memory, never paging them out to disk
+    ///
+    /// A memory-only channel fills as a regular one does, but where a regular
+    /// channel would page out its full disk buffer--1024 items, or
+    /// `memory_budget` bytes of them--the memory-only channel is full and a
+    /// send is handled as its `overflow_policy` says. Sends and receives never
+    /// wait on disk. The channel's directory is still claimed, with its lock
+    /// file and an empty queue file, and items are lost with the process. The
+    /// lanes of a channel with `priority_lanes` are each memory-only.
+    pub fn memory_only(mut self, memory_only: bool) -> ChannelBuilder {
+        self.memory_only = memory_only;
+        if memory_only {
+            self.disk_primary = false;
+        }
+        self
+    }
+
+    /// Page every item out to disk as it is sent
+    ///
+    /// For channels of large items. A disk-primary channel has no in-memory
+    /// tier and no disk buffer: each send writes its item to the current
+    /// queue file, and the Receiver reads it back from there, so the memory
+    /// the channel holds does not grow with the size or number of its items.
+    /// Each send makes a write call. Turns `memory_only` off, as
+    /// `memory_only` turns this off.
+    pub fn disk_primary(mut self, disk_primary: bool) -> ChannelBuilder {
+        self.disk_primary = disk_primary;
+        if disk_primary {
+            self.memory_only = false;
+        }
+        self
+    }
+
+    /// Set what a send to a full `memory_only` channel, or to one held at its
+    /// `max_disk_bytes`, does
+    ///
+    /// `OverflowPolicy::Fail` by default. Has no effect on other channels.
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> ChannelBuilder {
+        self.overflow = policy;
+        self
+    }
+
+    /// Cap the bytes the channel's queue files may take on disk
+    ///
+    /// Where paging out the disk buffer would take the queue files past
+    /// `max_disk_bytes`, the buffer is held in memory instead, raising
+    /// `QueueEvent::DiskFull`, and paged out once the Receiver has freed
+    /// room. Until then a send that finds the held buffer full is handled as
+    /// the channel's `overflow_policy` says: `Sender::try_send` returns
+    /// `SendError::DiskFull` and `Sender::send` drops the item, counting it
+    /// in `QueueMetrics::total_disk_full`, or the send waits. Nothing is
+    /// written past the cap, which counts every queue file the channel has on
+    /// disk, read or not. A queue file is only removed once the Receiver has
+    /// read past it and it was sealed, so allow several times `max_bytes`.
+    /// Items are sized as they are sent, as with `memory_budget`. The
+    /// `priority_lanes` of a channel count against the cap together. Has no
+    /// effect on a `memory_only` channel.
+    pub fn max_disk_bytes(mut self, max_disk_bytes: usize) -> ChannelBuilder {
+        self.max_disk_bytes = Some(max_disk_bytes);
+        self
+    }
+
+    /// Make room at `max_disk_bytes` by deleting the oldest queue files the
+    /// Receiver has yet to reach, rather than holding the disk buffer
+    ///
+    /// For data better lost than waited on, such as telemetry. The items in
+    /// the files are never received: they are counted in
+    /// `QueueMetrics::total_evicted` and raised with `QueueEvent::Evicted`.
+    /// The queue file the Receiver is reading and the one being written are
+    /// kept, so once nothing else is left to evict the disk buffer is held
+    /// and the `overflow_policy` applies as before. A priority lane evicts
+    /// the queue files of the lanes below it, lowest first, before its own,
+    /// and never those of a lane above it. Off by default, and has no effect
+    /// without `max_disk_bytes`.
+    pub fn evict_oldest(mut self, evict_oldest: bool) -> ChannelBuilder {
+        self.evict_oldest = evict_oldest;
+        self
+    }
+
+    /// Retry a failed write to the queue files up to `retries` times in a
+    /// row, pausing `backoff` after the first failure and twice as long after
+    /// each one after, up to 5 seconds
+    ///
+    /// Only errors that may pass on their own are retried: a full disk or
+    /// disk quota, a timeout, an interrupted call or one that would block.
+    /// The write is undone, the disk buffer is held in memory, past its size,
+    /// and `QueueEvent::WriteFailed` is raised. The buffer is paged out again
+    /// by the first send to find it full once the pause has passed, or by a
+    /// `Sender::spawn_flusher`. The Receiver meanwhile reads the queue files
+    /// and then the held buffer, in order.
+    ///
+    /// Any other error, or one that fails every retry, stops the channel
+    /// paging out for good. It is raised as `QueueEvent::WriteFailed` and in
+    /// `Health::last_write_error`, and the channel holds items in memory only
+    /// from then on: once the disk buffer fills, a send is handled as the
+    /// channel's `overflow_policy` says, `Sender::try_send` returning
+    /// `SendError::WriteFailed`. The default is 5 retries from 10
+    /// milliseconds.
+    pub fn retry_writes(mut self, retries: u32, backoff: Duration) -> ChannelBuilder {
+        self.retry = RetryPolicy {
+            retries: retries,
+            backoff: backoff,
+        };
+        self
+    }
+
+    /// Encrypt items paged out to disk with the AES-256-GCM key `key`
+    ///
+    /// Each record is sealed before it is written, so a disk shared with
+    /// others only ever holds ciphertext. Items held in memory are not
+    /// encrypted. Sealing adds 32 bytes to every record on disk. As a regular
+    /// channel discards its queue files when it is next opened, the key need
+    /// only outlive the channel. The key has id 0; see
+    /// `Sender::rotate_encryption_key` to move to another.
+    #[cfg(feature = "encryption")]
+    pub fn encryption_key(mut self, key: [u8; 32]) -> ChannelBuilder {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Drop items that have waited longer than `ttl` rather than deliver them
+    ///
+    /// Each item is stamped with the wall-clock time it was sent. An item
+    /// older than `ttl` when the Receiver reaches it, in memory or on disk, is
+    /// discarded and counted in `QueueMetrics::total_expired`. Stamping adds
+    /// eight bytes to every item paged out to disk, and is recorded in the
+    /// channel's metadata: building the channel again with stamping turned on
+    /// or off returns `Error::MetadataMismatch`.
+    pub fn ttl(mut self, ttl: Duration) -> ChannelBuilder {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Stamp each item with the wall-clock time it was sent
+    ///
+    /// The stamp fills `QueueMetrics::time_in_queue` and `RecordMeta::enqueued`
+    /// for a channel that is not stamped already by a TTL, priority aging or
+    /// provenance. As with `ttl`, stamping adds eight bytes to every item paged
+    /// out to disk and is recorded in the channel's metadata: building the
+    /// channel again with stamping turned on or off returns
+    /// `Error::MetadataMismatch`.
+    pub fn stamp_send_times(mut self, stamp: bool) -> ChannelBuilder {
+        self.stamp_sends = stamp;
+        self
+    }
+
+    /// Record which Sender sent each item, and when
+    ///
+    /// Every Sender of the channel, clones included, is given a small id, see
+    /// `Sender::id`. Each item is stamped with its Sender's id and the
+    /// wall-clock time it was handed to the channel, both returned by
+    /// `Receiver::recv_with_meta`. Items paged out to disk carry them at the
+    /// head of their queue file record, the send time as eight big-endian
+    /// bytes and then the id as four, twelve bytes an item in all; see
+    /// `hopper-inspect --meta`. Items sent with `Sender::send_after` carry
+    /// neither.
+    pub fn record_provenance(mut self, provenance: bool) -> ChannelBuilder {
+        self.provenance = provenance;
+        self
+    }
+
+    /// Number each Sender's items, for `Receiver::recv_ordered_by_sender`
+    ///
+    /// Records provenance, as `record_provenance` does, and with it stamps
+    /// each item with its place among the items its Sender has handed to the
+    /// channel, counted from 0 for each Sender and returned as
+    /// `RecordMeta::sender_seq`. The Sender id and the count make a merge key
+    /// that orders the items of interleaved Senders the same way however
+    /// their sends interleaved. Items paged out to disk carry the count as
+    /// eight big-endian bytes after the Sender id, twenty bytes an item in
+    /// all.
+    pub fn sequence_senders(mut self, sequenced: bool) -> ChannelBuilder {
+        self.sequenced = sequenced;
+        self
+    }
+
+    /// Move sealed queue files under `archive_dir` once they are full
+    ///
+    /// The queue file being written stays under `data_dir`, so a small fast
+    /// device there takes the latency-sensitive writes while a large slow
+    /// device under `archive_dir` holds the backlog built up during a long
+    /// outage. Sealed files are moved in the background as the Sender rotates
+    /// off them, and the Receiver reads them from wherever they are. A file
+    /// that cannot be moved stays under `data_dir`. Like `data_dir`, the
+    /// channel's files are kept in a subdirectory named for the channel.
+    pub fn archive_dir(mut self, archive_dir: &Path) -> ChannelBuilder {
+        self.archive_dir = Some(archive_dir.to_path_buf());
+        self
+    }
+
+    /// Set what the Receiver does with a queue file record it cannot decrypt
+    /// or decode
+    ///
+    /// A record may fail to decode because it was corrupted on disk, say, or
+    /// written by an incompatible version of the item type.
+    /// `CorruptionPolicy::Abort`, the default, panics.
+    /// `CorruptionPolicy::Skip` drops the record and
+    /// `CorruptionPolicy::DeadLetter` moves it aside; either way the Receiver
+    /// carries on with the next record, counting the failure in
+    /// `QueueMetrics::deserialize_failures` and raising
+    /// `QueueEvent::CorruptRecord`.
+    pub fn corruption_policy(mut self, policy: CorruptionPolicy) -> ChannelBuilder {
+        self.corruption = policy;
+        self
+    }
+
+    /// Move queue file records the Receiver cannot decode under
+    /// `dead_letter_dir` rather than panic
+    ///
+    /// The same as `corruption_policy(CorruptionPolicy::DeadLetter(dir))`. A
+    /// record that fails to decrypt or decode is appended with its bytes, its
+    /// place in the channel and the error to the `dead-letters` file in a
+    /// subdirectory named for the channel, and the Receiver carries on with
+    /// the next record. See `dead_letter` for the format and for reading the
+    /// records back.
+    pub fn dead_letter_dir(self, dead_letter_dir: &Path) -> ChannelBuilder {
+        self.corruption_policy(CorruptionPolicy::DeadLetter(dead_letter_dir.to_path_buf()))
+    }
+
+    /// Keep queue files the Receiver has read past for `keep_for` rather
+    /// than delete them
+    ///
+    /// Consumed files are moved into a `retained` subdirectory of the
+    /// directory they were read from, for replay--see `Receiver::seek`--or
+    /// audit, and deleted once `keep_for` has passed since they were last
+    /// written to. A background thread deletes them for as long as the
+    /// channel is open. With `keep_bytes` too, a file is deleted as soon as
+    /// either limit is passed. Retained files are not counted in the
+    /// channel's metrics.
+    pub fn keep_for(mut self, keep_for: Duration) -> ChannelBuilder {
+        self.retention.keep_for = Some(keep_for);
+        self
+    }
+
+    /// Keep up to `bytes` of queue files the Receiver has read past rather
+    /// than delete them
+    ///
+    /// Like `keep_for`, but the oldest retained files are deleted once the
+    /// retained files together pass `bytes`. The `priority_lanes` of a
+    /// channel share the bytes, higher lanes first, so a lower lane's files
+    /// are deleted before a higher lane's.
+    pub fn keep_bytes(mut self, bytes: u64) -> ChannelBuilder {
+        self.retention.keep_bytes = Some(bytes);
+        self
+    }
+
+    /// Read the channel's retained files back in the background, one every
+    /// `interval`, to find damage before a replay needs them
+    ///
+    /// The scrubber cycles through the retained files for as long as the
+    /// channel is open. A file is damaged if its header or a record is not
+    /// whole or, with `pack_records`, a block fails its checksum. Each
+    /// damaged file found is
+    /// counted in `QueueMetrics::total_corrupt_segments` and raised with
+    /// `QueueEvent::CorruptSegment` and, with `quarantine`, moved into a
+    /// `corrupt` subdirectory beside the retained one, as `repair` does. Has
+    /// no effect without `keep_for` or `keep_bytes`.
+    pub fn scrub_retained(mut self, interval: Duration, quarantine: bool) -> ChannelBuilder {
+        self.scrub = Some((interval, quarantine));
+        self
+    }
+
+    /// Check the channel directory to `level` as the channel is built
+    ///
+    /// Reports the lock, metadata and queue files found wrong in an
+    /// `IntegrityReport`, read from `Receiver::integrity_report`; see the
+    /// `verify` module for what each level checks. The checks only report:
+    /// the build goes ahead, or fails, as it would have without them. The
+    /// default is `VerifyLevel::None`.
+    pub fn verify_on_open(mut self, level: VerifyLevel) -> ChannelBuilder {
+        self.verify = level;
+        self
+    }
+
+    /// Repair damage `verify_on_open` finds before the channel opens it
+    ///
+    /// Queue files that stop reading whole, or whose packed blocks fail their
+    /// checksums, are cut back to their last good record and quarantined if
+    /// nothing good is left, as `repair` does, and what was done is kept in
+    /// `IntegrityReport::repair`. Nothing is repaired while
+    /// `process::backup` is copying the queue files. Has no effect without
+    /// `verify_on_open`.
+    pub fn repair_on_open(mut self, repair: bool) -> ChannelBuilder {
+        self.repair_on_open = repair;
+        self
+    }
+
+    /// Seed the channel with the items of the snapshot at `path`
+    ///
+    /// `build` checks the snapshot's format version and checksum and that it
+    /// holds items of the channel's type, then sends its items in order
+    /// before handing out the channel, as if by `Sender::send`. A snapshot
+    /// that fails the checks fails the build with `Error::InvalidSnapshot`.
+    /// Restored items are stamped afresh for `ttl`. See
+    /// `Receiver::export_snapshot` to take a snapshot. A backlog left by
+    /// `Receiver::close_after` is restored first.
+    pub fn restore_from(mut self, path: &Path) -> ChannelBuilder {
+        self.restore_from = Some(path.to_path_buf());
+        self
+    }
+
+    /// Raise `QueueEvent::HighWatermark` once `high` items are queued and
+    /// `QueueEvent::LowWatermark` once no more than `low` are again
+    ///
+    /// Items are counted from their send until their receive, whether in
+    /// memory or on disk. Events go to the `on_event` callback, raised by the
+    /// send or receive that crossed the mark. With `byte_watermarks` too the
+    /// high event is raised when either mark is reached and the low event only
+    /// once the channel is under both. A `low` above `high` is taken as `high`.
+    /// Each priority lane is measured on its own.
+    pub fn watermarks(mut self, high: usize, low: usize) -> ChannelBuilder {
+        self.watermarks.items = Some((high, cmp::min(low, high)));
+        self
+    }
+
+    /// Raise watermark events on the bytes the channel holds in queue files
+    ///
+    /// Like `watermarks`, measured by the channel's `disk_bytes` metric.
+    pub fn byte_watermarks(mut self, high: u64, low: u64) -> ChannelBuilder {
+        self.watermarks.bytes = Some((high, cmp::min(low, high)));
+        self
+    }
+
+    /// Sample more sparsely as the channel runs deep
+    ///
+    /// `Sender::send_sampled` keeps items at the rate it is given while the
+    /// channel is at or under its low watermarks. As the depth climbs towards
+    /// the high marks the rate is scaled down in step, to `min_rate` of it at
+    /// a high mark and over. A `min_rate` outside 0 to 1 is taken as the
+    /// nearer end. The channel's watermarks, set by `watermarks` or
+    /// `byte_watermarks`, give the depths; without them sampling is never
+    /// scaled. Other sends are not sampled.
+    pub fn adaptive_sampling(mut self, min_rate: f64) -> ChannelBuilder {
+        self.sampling_floor = Some(min_rate.clamp(0.0, 1.0));
+        self
+    }
+
+    /// Limit the channel to `per_second` sends a second, in bursts of up to
+    /// `burst`
+    ///
+    /// Every Sender of the channel takes from one token bucket holding up to
+    /// `burst` tokens and refilled at `per_second` tokens a second. A send
+    /// finding it empty waits or is refused as `policy` says. Sends through
+    /// `Sender::send_with_priority` are limited alike, whatever the lane;
+    /// `Sender::send_after` is not limited. Panics at build if `per_second`
+    /// is zero.
+    pub fn rate_limit(mut self, per_second: u32, burst: u32, policy: RatePolicy) -> ChannelBuilder {
+        self.rate_limit = Some(RateLimit {
+            per_second: per_second,
+            burst: burst,
+            policy: policy,
+            per_sender: false,
+        });
+        self
+    }
+
+    /// Like `rate_limit`, but each Sender clone has a token bucket of its own
+    ///
+    /// Useful to stop one misbehaving producer from starving the others.
+    pub fn sender_rate_limit(
+        mut self,
+        per_second: u32,
+        burst: u32,
+        policy: RatePolicy,
+    ) -> ChannelBuilder {
+        self.rate_limit = Some(RateLimit {
+            per_second: per_second,
+            burst: burst,
+            policy: policy,
+            per_sender: true,
+        });
+        self
+    }
+
+    /// Call `callback` with each `QueueEvent` the channel raises
+    ///
+    /// Useful for alerting when a channel starts paging to disk. See
+    /// `QueueEvent` for the events delivered and the context the callback is
+    /// called in.
+    pub fn on_event<F>(mut self, callback: F) -> ChannelBuilder
+    where
+        F: Fn(QueueEvent) + Send + Sync + 'static,
+    {
+        self.observer = Some(Arc::new(callback));
+        self
+    }
+
+    /// Create a broadcast channel with `subscribers` Subscribers
+    ///
+    /// The subscribers share one log of the items sent, stored in the
+    /// channel's directory, and are named for their index. Of this builder's
+    /// settings only the channel's name, directory and `max_bytes` apply. See
+    /// the `broadcast` module documentation.
+    pub fn build_broadcast<T>(
+        self,
+        subscribers: usize,
+    ) -> Result<(BroadcastSender<T>, Vec<Subscriber<T>>), Error>
+    where
+        T: Serialize + DeserializeOwned + Clone,
+    {
+        let names: Vec<String> = (0..subscribers).map(|idx| format!("{}", idx)).collect();
+        self.build_subscribers(names, false)
+    }
+
+    /// Create a broadcast channel with one Subscriber per named consumer
+    /// group
+    ///
+    /// Like `build_broadcast` but each subscriber is named for its group. The
+    /// Subscribers are returned in the order of `groups` and report their
+    /// group from `Subscriber::name`. Each group reads at its own pace and
+    /// the channel's files are reclaimed once every group has committed past
+    /// them.
+    ///
+    /// Unlike `build_broadcast` the channel is durable: each item is written
+    /// to the channel's queue files as it is sent and each group's committed
+    /// place is kept beside them, so building the channel again resumes every group
+    /// from there. See `Subscriber::commit`.
+    pub fn build_groups<T, S>(
+        self,
+        groups: &[S],
+    ) -> Result<(BroadcastSender<T>, Vec<Subscriber<T>>), Error>
+    where
+        T: Serialize + DeserializeOwned + Clone,
+        S: AsRef<str>,
+    {
+        let names: Vec<String> = groups.iter().map(|g| g.as_ref().to_string()).collect();
+        self.build_subscribers(names, true)
+    }
+
+    /// Create a dynamic channel carrying the types registered in `types`
+    ///
+    /// Requires the `dynamic` feature. See the `dynamic` module.
+    #[cfg(feature = "dynamic")]
+    pub fn build_dynamic(
+        self,
+        types: TypeRegistry,
+    ) -> Result<(DynamicSender, DynamicReceiver), Error> {
+        Ok(dynamic::assemble(self.build::<dynamic::Record>()?, types))
+    }
+
+    /// Create a channel carrying many typed streams
+    ///
+    /// See the `mux` module.
+    pub fn build_mux(self) -> Result<Mux, Error> {
+        Ok(Mux::new(self.build::<mux::Record>()?))
+    }
+
+    /// Create a channel carrying byte strings encoded elsewhere
+    ///
+    /// See the `raw` module.
+    pub fn build_raw(self) -> Result<(RawSender, RawReceiver), Error> {
+        Ok(raw::assemble(self.build::<raw::Bytes>()?))
+    }
+
+    /// Create a channel split into `shards` shards, for many Senders
+    ///
+    /// Each shard is a queue of its own configured by this builder, stored
+    /// in a subdirectory of the channel's directory named for the shard's
+    /// index, and taken at least 1. See `ShardedSender`.
+    pub fn build_sharded<T>(
+        self,
+        shards: usize,
+    ) -> Result<(ShardedSender<T>, ShardedReceiver<T>), Error>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let names: Vec<String> = (0..cmp::max(1, shards)).map(|idx| format!("{}", idx)).collect();
+        Ok(shard::assemble(self.build_parts(&names)?))
+    }
+
+    fn build_subscribers<T>(
+        self,
+        names: Vec<String>,
+        durable: bool,
+    ) -> Result<(BroadcastSender<T>, Vec<Subscriber<T>>), Error>
+    where
+        T: Serialize + DeserializeOwned + Clone,
+    {
+        let root = self.data_dir.join(&self.name);
+        broadcast::open(&root, names, self.max_bytes, durable)
+    }
+
+    // Build a channel of this builder's for each of `names`, stored in
+    // subdirectories of this builder's channel directory.
+    fn build_parts<T>(self, names: &[String]) -> Result<Vec<Pair<T>>, Error>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let root = self.data_dir.join(&self.name);
+        let archive_dir = self.archive_dir.as_ref().map(|a| a.join(&self.name));
+        let mut pairs = Vec::with_capacity(names.len());
+        for name in names {
+            pairs.push(
+                ChannelBuilder {
+                    name: name.clone(),
+                    data_dir: root.clone(),
+                    archive_dir: archive_dir.clone(),
+                    ..self.clone()
+                }.build()?,
+            );
+        }
+        Ok(pairs)
+    }
+
+    /// Create the (Sender, Receiver) pair
+    ///
+    /// Fails with `Error::Unsupported` if the channel needs what the platform
+    /// does not have, as under WASI: see the crate documentation.
+    pub fn build<T>(self) -> Result<(Sender<T>, Receiver<T>), Error>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let background = self.retention.is_enabled() || self.read_ahead > 0;
+        if (background && !private::HAS_THREADS)
+            || (self.ephemeral.is_some() && !private::HAS_TEMP_DIR)
+        {
+            return Err(Error::Unsupported);
+        }
+        let restore = match self.restore_from {
+            Some(ref path) => Some(snapshot::Reader::<T>::open(
+                path,
+                ::std::any::type_name::<T>(),
+            )?),
+            None => None,
+        };
+        let root = self.data_dir.join(&self.name);
+        if !root.is_dir() {
+            fs::create_dir_all(&root).expect("could not create directory");
+        }
+        let dir_lock = layout::lock(&root)?;
+        // Checked before the metadata is claimed, which would upgrade it.
+        let type_name = ::std::any::type_name::<T>();
+        let packed = self.block_bytes > 0;
+        let integrity = if self.verify == VerifyLevel::None {
+            None
+        } else {
+            let mut report = verify::check(&root, self.verify, type_name, packed);
+            let pinned = report.findings.contains(&verify::Finding::Pinned);
+            if self.repair_on_open && report.is_damaged() && !pinned {
+                let repaired = repair::repair_held(&root, packed, |_| true);
+                report.repair = Some(repaired);
+            }
+            Some(report)
+        };
+        // The backlog a Receiver closed with `close_after` left for this build
+        let drained_path = root.join(layout::DRAINED_FILE);
+        let drained = if drained_path.is_file() {
+            Some(snapshot::Reader::<T>::open(
+                &drained_path,
+                ::std::any::type_name::<T>(),
+            )?)
+        } else {
+            None
+        };
+        let supervisor = match self.supervisor {
+            Some(ref supervisor) => supervisor.clone(),
+            None => Supervisor::new(&self.name, self.observer.clone()),
+        };
+        let lane_builder = if self.priority_lanes {
+            let mut lane = self.clone();
+            lane.supervisor = Some(supervisor.clone());
+            lane.data_dir = root.clone();
+            lane.archive_dir = self.archive_dir.as_ref().map(|a| a.join(&self.name));
+            if let CorruptionPolicy::DeadLetter(ref dir) = self.corruption {
+                lane.corruption = CorruptionPolicy::DeadLetter(dir.join(&self.name));
+            }
+            lane.priority_lanes = false;
+            lane.restore_from = None;
+            // The Sender takes tokens before handing items to a lane.
+            lane.rate_limit = None;
+            Some(lane)
+        } else {
+            None
+        };
+        let cap: usize = 1024;
+        let sz = mem::size_of::<T>();
+        let max_bytes = if self.max_bytes < sz {
+            sz
+        } else {
+            self.max_bytes
+        };
+        let mut fs_sync = private::FsSync::new(cap);
+        fs_sync.segment_max_bytes = max_bytes;
+        fs_sync.order = self.order;
+        fs_sync.flush_on_drop = self.flush_on_drop;
+        fs_sync.stage_writes = self.stage_writes;
+        fs_sync.coalesce = self.coalesce_wakes;
+        fs_sync.read_ahead = self.read_ahead;
+        fs_sync.block_bytes = self.block_bytes;
+        #[cfg(feature = "compression")]
+        {
+            fs_sync.compress_blocks = self.compress_blocks;
+        }
+        #[cfg(feature = "dictionary")]
+        {
+            fs_sync.trainer = self
+                .dictionary
+                .map(|(samples, max_bytes)| dictionary::Trainer::new(&root, samples, max_bytes));
+        }
+        #[cfg(feature = "preallocate")]
+        {
+            fs_sync.preallocate = self.preallocate;
+        }
+        fs_sync.runtime = self.runtime.clone();
+        if let Some(ref clock) = self.clock {
+            fs_sync.clock = Arc::clone(clock);
+        }
+        fs_sync.memory_only = self.memory_only;
+        // Items delayed by the channel's last build, and not yet delivered,
+        // wait on in its delayed file.
+        if !self.memory_only {
+            fs_sync.delayed = delay::Store::open(&root.join(layout::DELAYED_FILE))?;
+            fs_sync.paged = Some(Paged::open(&root.join(layout::PAGED_FILE))?);
+        }
+        fs_sync.overflow = self.overflow;
+        fs_sync.retries = Retries::new(self.retry);
+        #[cfg(feature = "encryption")]
+        {
+            fs_sync.cipher = self.encryption_key.as_ref().map(|key| crypt::Cipher::new(0, key));
+        }
+        if let Some(budget) = self.memory_budget {
+            fs_sync.memory_budget = Some(budget);
+            fs_sync.in_memory_idx = usize::MAX;
+        }
+        #[cfg(feature = "cgroup")]
+        {
+            if let Some((percent, interval)) = self.cgroup_budget {
+                if let Some(budget) = CgroupMemory::read().and_then(|m| m.share(percent)) {
+                    let budget = Arc::new(AtomicUsize::new(budget as usize));
+                    let weak = Arc::downgrade(&budget);
+                    cgroup::supervise_resizer(&supervisor, weak, percent, interval);
+                    fs_sync.memory_budget = Some(budget.load(Ordering::Relaxed));
+                    fs_sync.cgroup_budget = Some(budget);
+                    fs_sync.in_memory_idx = usize::MAX;
+                }
+            }
+        }
+        fs_sync.shared_budget = self.shared_budget.clone().map(Charge::new);
+        if self.disk_primary {
+            fs_sync.disk_primary = true;
+            fs_sync.in_memory_idx = 0;
+        }
+        fs_sync.ttl = self.ttl;
+        fs_sync.stamp_sends = self.stamp_sends;
+        fs_sync.aging = self.priority_aging;
+        fs_sync.provenance = self.provenance || self.sequenced || !self.reservations.is_empty();
+        if !self.reservations.is_empty() {
+            fs_sync.reservations = Some(Reservations::new(&self.reservations, self.memory_only));
+        }
+        fs_sync.sequenced = self.sequenced;
+        // Claimed once the fields the records lead with are settled
+        layout::claim(&root, type_name, fs_sync.framing())?;
+        fs_sync.retention = self.retention;
+        fs_sync.watermarks = self.watermarks;
+        fs_sync.sampling_floor = self.sampling_floor;
+        fs_sync.rate = self.rate_limit.map(|limit| (limit, limit.bucket()));
+        fs_sync.dir_lock = dir_lock;
+        fs_sync.ephemeral = self.ephemeral.clone();
+        if let Some(ref archive_dir) = self.archive_dir {
+            let archive = archive_dir.join(&self.name);
+            if !archive.is_dir() {
+                fs::create_dir_all(&archive).expect("could not create archive directory");
+            }
+            fs_sync.archiver = Some(Archiver::start(
+                archive.clone(),
+                self.runtime.as_ref(),
+                &supervisor,
+            ));
+            fs_sync.archive = Some(archive);
+        }
+        // The Receiver is handed the dead-letter file itself.
+        fs_sync.corruption = match self.corruption {
+            CorruptionPolicy::DeadLetter(ref dead_letter_dir) => {
+                let dead_letters = dead_letter_dir.join(&self.name);
+                if !dead_letters.is_dir() {
+                    fs::create_dir_all(&dead_letters)
+                        .expect("could not create dead-letter directory");
+                }
+                CorruptionPolicy::DeadLetter(dead_letters.join(dead_letter::FILE_NAME))
+            }
+            ref policy => policy.clone(),
+        };
+        let archive = fs_sync.archive.clone();
+        let observer = self.observer.clone();
+        fs_sync.observer = self.observer;
+        fs_sync.supervisor = supervisor.clone();
+        if let Some((min, max)) = self.adaptive_max_bytes {
+            let min = if min < sz { sz } else { min };
+            let max = if max < min { min } else { max };
+            fs_sync.segment_bounds = Some((min, max));
+            fs_sync.segment_max_bytes = if max_bytes < min {
+                min
+            } else if max_bytes > max {
+                max
+            } else {
+                max_bytes
+            };
+        }
+        let segment_max_bytes = fs_sync.segment_max_bytes;
+        let clock = Arc::clone(&fs_sync.clock);
+        // The Receiver clears out stale queue files as it starts, so our
+        // view of the disk can only be taken once both sides exist.
+        let metrics = Arc::new(Metrics::default());
+        metrics.in_memory_capacity.store(fs_sync.capacity, Ordering::Relaxed);
+        fs_sync.disk_quota = self.max_disk_bytes.map(|quota| (quota, Arc::clone(&metrics)));
+        fs_sync.evict_oldest = self.evict_oldest;
+        // An encrypted channel seals each item's send time with it.
+        #[cfg(feature = "encryption")]
+        let clear_stamps = fs_sync.stamped() && fs_sync.cipher.is_none();
+        #[cfg(not(feature = "encryption"))]
+        let clear_stamps = fs_sync.stamped();
+        let fs_lock = Arc::new(Mutex::new(fs_sync));
+        let mut sender = Sender::new(
+            self.name,
+            &root,
+            max_bytes,
+            Arc::clone(&fs_lock),
+            Arc::clone(&metrics),
+        )?;
+        let mut receiver = Receiver::new(&root, fs_lock, Arc::clone(&metrics))?;
+        if let Some(report) = integrity {
+            receiver.set_integrity(report);
+        }
+        if let Some(lane) = lane_builder {
+            let (high_snd, high_rcv) = ChannelBuilder {
+                name: "high".to_string(),
+                ..lane.clone()
+            }.build()?;
+            let (low_snd, low_rcv) = ChannelBuilder {
+                name: "low".to_string(),
+                ..lane
+            }.build()?;
+            sender.set_lanes(high_snd, low_snd);
+            receiver.set_lanes(high_rcv, low_rcv);
+        }
+        metrics.seed_from_dir(&root, archive.as_deref(), segment_max_bytes);
+        if self.retention.is_enabled() {
+            let now = clock.wall();
+            retention::collect_lanes(&sender.retained_dirs(), &self.retention, now);
+            for dir in Some(&root).into_iter().chain(archive.as_ref()) {
+                index::fill_in(&dir.join(retention::RETAINED_DIR), None, clear_stamps, packed);
+            }
+            retention::spawn_collector(
+                root.clone(),
+                archive.clone(),
+                self.retention,
+                Arc::downgrade(&metrics),
+                clock,
+                self.runtime.as_ref(),
+                &supervisor,
+            );
+            if let Some((interval, quarantine)) = self.scrub {
+                scrub::spawn_scrubber(
+                    Scrubber::new(root.clone(), archive.clone(), quarantine, packed),
+                    interval,
+                    Arc::downgrade(&metrics),
+                    observer,
+                    self.runtime.as_ref(),
+                    &supervisor,
+                );
+            }
+        }
+        topology::register(
+            ChannelDescription {
+                name: sender.name().to_string(),
+                data_dir: self.data_dir,
+                in_memory_capacity: cap,
+                max_bytes: max_bytes,
+                adaptive_max_bytes: self.adaptive_max_bytes,
+                order: self.order,
+            },
+            Arc::downgrade(&metrics),
+        );
+        let restoring = drained.is_some() || restore.is_some();
+        for mut restore in drained.into_iter().chain(restore) {
+            while let Some(item) = restore.next()? {
+                sender.send(item);
+            }
+        }
+        if restoring {
+            sender.flush()?;
+            let _ = fs::remove_file(&drained_path);
+        }
+        Ok((sender, receiver))
+    }
+}
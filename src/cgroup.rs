@@ -0,0 +1,135 @@
+//! cgroup v2 memory limits
+//!
+//! In a container the memory a process may use is bounded by its cgroup's
+//! `memory.max` rather than by the host's RAM. With the `cgroup` feature
+//! hopper reads that limit, and the cgroup's `memory.current` use, from the
+//! unified hierarchy under `/sys/fs/cgroup`, so that
+//! `ChannelBuilder::cgroup_memory_budget` and `pressure::adapt_to_cgroup` can
+//! size a channel by the container's budget. Only cgroup v2 is read, so only
+//! Linux hosts report anything.
+use std::fs;
+use std::path::Path;
+use std::sync::Weak;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use supervise::{Supervisor, Task};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const PROC_CGROUP: &str = "/proc/self/cgroup";
+
+/// The memory use and limit of a cgroup, in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CgroupMemory {
+    /// Memory in use by the cgroup, from `memory.current`
+    pub current: u64,
+    /// The cgroup's limit, from `memory.max`, or None if it has none
+    pub max: Option<u64>,
+}
+
+impl CgroupMemory {
+    /// Read the memory use and limit of this process's cgroup
+    ///
+    /// Returns None if the process is not in a cgroup v2 hierarchy.
+    pub fn read() -> Option<CgroupMemory> {
+        let proc_cgroup = fs::read_to_string(PROC_CGROUP).ok()?;
+        let path = unified_path(&proc_cgroup)?;
+        CgroupMemory::read_dir(&Path::new(CGROUP_ROOT).join(path.trim_start_matches('/')))
+    }
+
+    /// Read the memory use and limit of the cgroup whose directory is `dir`
+    pub fn read_dir(dir: &Path) -> Option<CgroupMemory> {
+        let current = fs::read_to_string(dir.join("memory.current")).ok()?;
+        let max = fs::read_to_string(dir.join("memory.max")).ok()?;
+        Some(CgroupMemory {
+            current: current.trim().parse().ok()?,
+            max: parse_max(&max)?,
+        })
+    }
+
+    /// `percent` of the cgroup's limit, if it has one
+    pub fn share(&self, percent: f64) -> Option<u64> {
+        self.max.map(|max| (max as f64 * percent / 100.0) as u64)
+    }
+}
+
+/// Find this process's cgroup in the cgroup v2 hierarchy, from the contents
+/// of `/proc/self/cgroup`
+pub fn unified_path(proc_cgroup: &str) -> Option<&str> {
+    proc_cgroup
+        .lines()
+        .find(|line| line.starts_with("0::"))
+        .map(|line| &line["0::".len()..])
+}
+
+/// Parse the contents of a `memory.max` file: a byte count or "max" for no
+/// limit
+pub fn parse_max(max: &str) -> Option<Option<u64>> {
+    match max.trim() {
+        "max" => Some(None),
+        bytes => bytes.parse().ok().map(Some),
+    }
+}
+
+/// Keep `budget` at `percent` of this process's cgroup limit, re-reading the
+/// limit every `interval` until `budget` is dropped
+///
+/// A budget whose cgroup has no limit, or cannot be read, is left as it was.
+pub fn spawn_resizer(budget: Weak<AtomicUsize>, percent: f64, interval: Duration) {
+    supervise_resizer(&Supervisor::new("cgroup", None), budget, percent, interval)
+}
+
+// As `spawn_resizer`, running the resizer as a task of a channel's
+// `supervisor`.
+pub(crate) fn supervise_resizer(
+    supervisor: &Supervisor,
+    budget: Weak<AtomicUsize>,
+    percent: f64,
+    interval: Duration,
+) {
+    supervisor
+        .spawn(Task::Resizer, move || loop {
+            thread::sleep(interval);
+            let budget = match budget.upgrade() {
+                Some(budget) => budget,
+                None => return,
+            };
+            if let Some(bytes) = CgroupMemory::read().and_then(|m| m.share(percent)) {
+                budget.store(bytes as usize, Ordering::Relaxed);
+            }
+        })
+        .expect("could not start cgroup budget resizer");
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::*;
+
+    #[test]
+    fn limits_are_parsed() {
+        let proc_cgroup = "0::/system.slice/hopper.service\n";
+        assert_eq!(Some("/system.slice/hopper.service"), unified_path(proc_cgroup));
+        assert_eq!(None, unified_path("1:memory:/docker/abc\n"));
+        assert_eq!(Some(None), parse_max("max\n"));
+        assert_eq!(Some(Some(1 << 30)), parse_max("1073741824\n"));
+        assert_eq!(None, parse_max("lots"));
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        assert_eq!(None, CgroupMemory::read_dir(dir.path()));
+        fs::write(dir.path().join("memory.current"), "1000\n").unwrap();
+        fs::write(dir.path().join("memory.max"), "4000\n").unwrap();
+        let memory = CgroupMemory::read_dir(dir.path()).unwrap();
+        assert_eq!(
+            CgroupMemory {
+                current: 1000,
+                max: Some(4000),
+            },
+            memory
+        );
+        assert_eq!(Some(1000), memory.share(25.0));
+        fs::write(dir.path().join("memory.max"), "max\n").unwrap();
+        assert_eq!(None, CgroupMemory::read_dir(dir.path()).unwrap().share(25.0));
+    }
+}
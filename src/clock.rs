@@ -0,0 +1,42 @@
+//! The time as a channel sees it
+//!
+//! A channel reads the time to stamp items for a TTL, to tell when an item
+//! sent with `Sender::send_after` comes due, to collect retained queue files
+//! under `keep_for` and to measure how long items wait. It reads it from the
+//! `Clock` set with `ChannelBuilder::clock`, a `SystemClock` by default, so a
+//! test can put a `testing::ManualClock` in its place and move time on by
+//! hand rather than sleeping. Timeouts given to receives, and the pace of
+//! background work, follow the real clock whatever a channel is given, but
+//! for a channel built on a `sim::Simulation`, which go by its clock.
+use std::fmt;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// A source of the current time
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// The current monotonic time, which delays are measured against
+    fn now(&self) -> Instant;
+
+    /// The current wall-clock time, which items are stamped with and files
+    /// are aged against
+    fn wall(&self) -> SystemTime;
+}
+
+/// The clock of the operating system
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// `clock`'s wall-clock time in milliseconds since the UNIX epoch
+pub fn millis(clock: &dyn Clock) -> u64 {
+    let since = clock.wall().duration_since(UNIX_EPOCH).unwrap_or_default();
+    since.as_secs() * 1000 + u64::from(since.subsec_millis())
+}
@@ -0,0 +1,675 @@
+//! A channel's configuration as data
+//!
+//! `QueueConfig` holds the options of a `ChannelBuilder` in a form that
+//! serde can read from and write to a configuration file, in whatever format
+//! the program already uses. `ChannelBuilder::from_config` starts a builder
+//! from one, and `Sender::reload` applies those of a changed file that a
+//! running channel can take: its `memory_budget`, `max_disk_bytes` and
+//! watermarks. The rest take effect the next time the channel is built.
+//!
+//! Each option is keyed by the name of the builder method that sets it.
+//! Durations are whole milliseconds, the policies their names in snake case,
+//! as `"per_sender"` or `{ "dead_letter": "/var/lib/app/dead" }`, and
+//! options taking several values a sequence of them, in the method's order.
+//! Only `name` is required; an option left out keeps the builder's default.
+//! Options that hold a live value--`clock`, `runtime`, `simulation`,
+//! `shared_budget`, `on_event`--and the `encryption_key`, which has no place
+//! in a configuration file, are set on the builder `from_config` returns.
+use builder::{ConfigDelta, CorruptionPolicy, OrderMode, OverflowPolicy};
+use rate::RatePolicy;
+use verify::VerifyLevel;
+use serde::de::{self, Deserialize, DeserializeSeed, Deserializer, EnumAccess, MapAccess,
+                SeqAccess, VariantAccess, Visitor};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// The options of a `ChannelBuilder`, for a configuration file
+///
+/// See the `config` module for how it is written out and read back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueConfig {
+    /// The channel's name
+    pub name: String,
+    /// The directory the channel's queue files are stored under
+    pub data_dir: PathBuf,
+    /// `ChannelBuilder::ephemeral`
+    pub ephemeral: bool,
+    /// `ChannelBuilder::max_bytes`
+    pub max_bytes: usize,
+    /// The (min, max) of `ChannelBuilder::adaptive_max_bytes`
+    pub adaptive_max_bytes: Option<(usize, usize)>,
+    /// `ChannelBuilder::order`
+    pub order: OrderMode,
+    /// `ChannelBuilder::priority_lanes`
+    pub priority_lanes: bool,
+    /// `ChannelBuilder::priority_aging`
+    pub priority_aging: Option<Duration>,
+    /// `ChannelBuilder::flush_on_drop`
+    pub flush_on_drop: bool,
+    /// `ChannelBuilder::stage_writes`
+    pub stage_writes: bool,
+    /// The (records, interval) of `ChannelBuilder::coalesce_wakes`
+    pub coalesce_wakes: Option<(usize, Duration)>,
+    /// `ChannelBuilder::memory_budget`
+    pub memory_budget: Option<usize>,
+    /// The (percent, interval) of `ChannelBuilder::cgroup_memory_budget`.
+    /// Ignored without the `cgroup` feature.
+    pub cgroup_memory_budget: Option<(f64, Duration)>,
+    /// The (group, memory bytes, disk bytes) of each `ChannelBuilder::reserve`
+    pub reserve: Vec<(String, usize, usize)>,
+    /// `ChannelBuilder::read_ahead`
+    pub read_ahead: usize,
+    /// `ChannelBuilder::pack_records`
+    pub pack_records: usize,
+    /// `ChannelBuilder::compress_blocks`. Ignored without the `compression`
+    /// feature.
+    pub compress_blocks: bool,
+    /// The (samples, max bytes) of `ChannelBuilder::train_dictionary`.
+    /// Ignored without the `dictionary` feature.
+    pub train_dictionary: Option<(usize, usize)>,
+    /// `ChannelBuilder::preallocate`. Ignored without the `preallocate`
+    /// feature.
+    pub preallocate: bool,
+    /// `ChannelBuilder::memory_only`
+    pub memory_only: bool,
+    /// `ChannelBuilder::disk_primary`
+    pub disk_primary: bool,
+    /// `ChannelBuilder::overflow_policy`
+    pub overflow_policy: OverflowPolicy,
+    /// `ChannelBuilder::max_disk_bytes`
+    pub max_disk_bytes: Option<usize>,
+    /// `ChannelBuilder::evict_oldest`
+    pub evict_oldest: bool,
+    /// The (retries, backoff) of `ChannelBuilder::retry_writes`
+    pub retry_writes: (u32, Duration),
+    /// `ChannelBuilder::ttl`
+    pub ttl: Option<Duration>,
+    /// `ChannelBuilder::stamp_send_times`
+    pub stamp_send_times: bool,
+    /// `ChannelBuilder::record_provenance`
+    pub record_provenance: bool,
+    /// `ChannelBuilder::sequence_senders`
+    pub sequence_senders: bool,
+    /// `ChannelBuilder::archive_dir`
+    pub archive_dir: Option<PathBuf>,
+    /// `ChannelBuilder::corruption_policy`
+    pub corruption_policy: CorruptionPolicy,
+    /// `ChannelBuilder::keep_for`
+    pub keep_for: Option<Duration>,
+    /// `ChannelBuilder::keep_bytes`
+    pub keep_bytes: Option<u64>,
+    /// The (interval, quarantine) of `ChannelBuilder::scrub_retained`
+    pub scrub_retained: Option<(Duration, bool)>,
+    /// `ChannelBuilder::verify_on_open`
+    pub verify_on_open: VerifyLevel,
+    /// `ChannelBuilder::repair_on_open`
+    pub repair_on_open: bool,
+    /// `ChannelBuilder::restore_from`
+    pub restore_from: Option<PathBuf>,
+    /// The (high, low) of `ChannelBuilder::watermarks`
+    pub watermarks: Option<(usize, usize)>,
+    /// The (high, low) of `ChannelBuilder::byte_watermarks`
+    pub byte_watermarks: Option<(u64, u64)>,
+    /// `ChannelBuilder::adaptive_sampling`
+    pub adaptive_sampling: Option<f64>,
+    /// The (per second, burst, policy) of `ChannelBuilder::rate_limit`
+    pub rate_limit: Option<(u32, u32, RatePolicy)>,
+    /// The (per second, burst, policy) of `ChannelBuilder::sender_rate_limit`,
+    /// which takes the place of any `rate_limit`
+    pub sender_rate_limit: Option<(u32, u32, RatePolicy)>,
+}
+
+impl QueueConfig {
+    /// The configuration of a channel named `name` whose queue files are
+    /// stored under `data_dir`, every other option at the builder's default
+    pub fn new<S, P>(name: S, data_dir: P) -> QueueConfig
+    where
+        S: Into<String>,
+        P: Into<PathBuf>,
+    {
+        QueueConfig {
+            name: name.into(),
+            data_dir: data_dir.into(),
+            ephemeral: false,
+            max_bytes: 1_048_576 * 100,
+            adaptive_max_bytes: None,
+            order: OrderMode::default(),
+            priority_lanes: false,
+            priority_aging: None,
+            flush_on_drop: true,
+            stage_writes: false,
+            coalesce_wakes: None,
+            memory_budget: None,
+            cgroup_memory_budget: None,
+            reserve: Vec::new(),
+            read_ahead: 0,
+            pack_records: 0,
+            compress_blocks: false,
+            train_dictionary: None,
+            preallocate: false,
+            memory_only: false,
+            disk_primary: false,
+            overflow_policy: OverflowPolicy::default(),
+            max_disk_bytes: None,
+            evict_oldest: false,
+            retry_writes: (5, Duration::from_millis(10)),
+            ttl: None,
+            stamp_send_times: false,
+            record_provenance: false,
+            sequence_senders: false,
+            archive_dir: None,
+            corruption_policy: CorruptionPolicy::default(),
+            keep_for: None,
+            keep_bytes: None,
+            scrub_retained: None,
+            verify_on_open: VerifyLevel::None,
+            repair_on_open: false,
+            restore_from: None,
+            watermarks: None,
+            byte_watermarks: None,
+            adaptive_sampling: None,
+            rate_limit: None,
+            sender_rate_limit: None,
+        }
+    }
+
+    /// The changes `Sender::reload` makes to a running channel: the options
+    /// of this configuration a channel can take without being rebuilt
+    ///
+    /// Options left unset here leave the channel's as they are.
+    pub fn delta(&self) -> ConfigDelta {
+        ConfigDelta {
+            in_memory_capacity: None,
+            memory_budget: self.memory_budget,
+            max_disk_bytes: self.max_disk_bytes,
+            watermarks: self.watermarks,
+            byte_watermarks: self.byte_watermarks,
+        }
+    }
+}
+
+const FIELDS: &[&str] = &[
+    "name",
+    "data_dir",
+    "ephemeral",
+    "max_bytes",
+    "adaptive_max_bytes",
+    "order",
+    "priority_lanes",
+    "priority_aging",
+    "flush_on_drop",
+    "stage_writes",
+    "coalesce_wakes",
+    "memory_budget",
+    "cgroup_memory_budget",
+    "reserve",
+    "read_ahead",
+    "pack_records",
+    "compress_blocks",
+    "train_dictionary",
+    "preallocate",
+    "memory_only",
+    "disk_primary",
+    "overflow_policy",
+    "max_disk_bytes",
+    "evict_oldest",
+    "retry_writes",
+    "ttl",
+    "stamp_send_times",
+    "record_provenance",
+    "sequence_senders",
+    "archive_dir",
+    "corruption_policy",
+    "keep_for",
+    "keep_bytes",
+    "scrub_retained",
+    "verify_on_open",
+    "repair_on_open",
+    "restore_from",
+    "watermarks",
+    "byte_watermarks",
+    "adaptive_sampling",
+    "rate_limit",
+    "sender_rate_limit",
+];
+
+// A duration as whole milliseconds
+#[derive(Debug, Clone, Copy)]
+struct Millis(Duration);
+
+impl Serialize for Millis {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        (self.0.as_millis() as u64).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Millis {
+    fn deserialize<D>(deserializer: D) -> Result<Millis, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        u64::deserialize(deserializer).map(|ms| Millis(Duration::from_millis(ms)))
+    }
+}
+
+fn millis(duration: Option<Duration>) -> Option<Millis> {
+    duration.map(Millis)
+}
+
+fn duration(millis: Option<Millis>) -> Option<Duration> {
+    millis.map(|m| m.0)
+}
+
+impl Serialize for QueueConfig {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_struct("QueueConfig", FIELDS.len())?;
+        s.serialize_field("name", &self.name)?;
+        s.serialize_field("data_dir", &self.data_dir)?;
+        s.serialize_field("ephemeral", &self.ephemeral)?;
+        s.serialize_field("max_bytes", &self.max_bytes)?;
+        s.serialize_field("adaptive_max_bytes", &self.adaptive_max_bytes)?;
+        s.serialize_field("order", &self.order)?;
+        s.serialize_field("priority_lanes", &self.priority_lanes)?;
+        s.serialize_field("priority_aging", &millis(self.priority_aging))?;
+        s.serialize_field("flush_on_drop", &self.flush_on_drop)?;
+        s.serialize_field("stage_writes", &self.stage_writes)?;
+        s.serialize_field(
+            "coalesce_wakes",
+            &self.coalesce_wakes.map(|(r, i)| (r, Millis(i))),
+        )?;
+        s.serialize_field("memory_budget", &self.memory_budget)?;
+        s.serialize_field(
+            "cgroup_memory_budget",
+            &self.cgroup_memory_budget.map(|(p, i)| (p, Millis(i))),
+        )?;
+        s.serialize_field("reserve", &self.reserve)?;
+        s.serialize_field("read_ahead", &self.read_ahead)?;
+        s.serialize_field("pack_records", &self.pack_records)?;
+        s.serialize_field("compress_blocks", &self.compress_blocks)?;
+        s.serialize_field("train_dictionary", &self.train_dictionary)?;
+        s.serialize_field("preallocate", &self.preallocate)?;
+        s.serialize_field("memory_only", &self.memory_only)?;
+        s.serialize_field("disk_primary", &self.disk_primary)?;
+        s.serialize_field("overflow_policy", &self.overflow_policy)?;
+        s.serialize_field("max_disk_bytes", &self.max_disk_bytes)?;
+        s.serialize_field("evict_oldest", &self.evict_oldest)?;
+        s.serialize_field(
+            "retry_writes",
+            &(self.retry_writes.0, Millis(self.retry_writes.1)),
+        )?;
+        s.serialize_field("ttl", &millis(self.ttl))?;
+        s.serialize_field("stamp_send_times", &self.stamp_send_times)?;
+        s.serialize_field("record_provenance", &self.record_provenance)?;
+        s.serialize_field("sequence_senders", &self.sequence_senders)?;
+        s.serialize_field("archive_dir", &self.archive_dir)?;
+        s.serialize_field("corruption_policy", &self.corruption_policy)?;
+        s.serialize_field("keep_for", &millis(self.keep_for))?;
+        s.serialize_field("keep_bytes", &self.keep_bytes)?;
+        s.serialize_field(
+            "scrub_retained",
+            &self.scrub_retained.map(|(i, q)| (Millis(i), q)),
+        )?;
+        s.serialize_field("verify_on_open", &self.verify_on_open)?;
+        s.serialize_field("repair_on_open", &self.repair_on_open)?;
+        s.serialize_field("restore_from", &self.restore_from)?;
+        s.serialize_field("watermarks", &self.watermarks)?;
+        s.serialize_field("byte_watermarks", &self.byte_watermarks)?;
+        s.serialize_field("adaptive_sampling", &self.adaptive_sampling)?;
+        s.serialize_field("rate_limit", &self.rate_limit)?;
+        s.serialize_field("sender_rate_limit", &self.sender_rate_limit)?;
+        s.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for QueueConfig {
+    fn deserialize<D>(deserializer: D) -> Result<QueueConfig, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_struct("QueueConfig", FIELDS, ConfigVisitor)
+    }
+}
+
+struct ConfigVisitor;
+
+impl<'de> Visitor<'de> for ConfigVisitor {
+    type Value = QueueConfig;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a channel configuration")
+    }
+
+    // Formats without field names, bincode among them, write every option
+    // in order.
+    fn visit_seq<A>(self, mut seq: A) -> Result<QueueConfig, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut read = 0;
+        macro_rules! element {
+            () => {
+                element!(_)
+            };
+            ($ty:ty) => {{
+                read += 1;
+                seq.next_element::<$ty>()?
+                    .ok_or_else(|| de::Error::invalid_length(read - 1, &self))?
+            }};
+        }
+        let name: String = element!();
+        let data_dir: PathBuf = element!();
+        let mut config = QueueConfig::new(name, data_dir);
+        config.ephemeral = element!();
+        config.max_bytes = element!();
+        config.adaptive_max_bytes = element!();
+        config.order = element!();
+        config.priority_lanes = element!();
+        config.priority_aging = duration(element!());
+        config.flush_on_drop = element!();
+        config.stage_writes = element!();
+        config.coalesce_wakes = element!(Option<(usize, Millis)>).map(|(r, i)| (r, i.0));
+        config.memory_budget = element!();
+        config.cgroup_memory_budget = element!(Option<(f64, Millis)>).map(|(p, i)| (p, i.0));
+        config.reserve = element!();
+        config.read_ahead = element!();
+        config.pack_records = element!();
+        config.compress_blocks = element!();
+        config.train_dictionary = element!();
+        config.preallocate = element!();
+        config.memory_only = element!();
+        config.disk_primary = element!();
+        config.overflow_policy = element!();
+        config.max_disk_bytes = element!();
+        config.evict_oldest = element!();
+        let (retries, backoff) = element!((u32, Millis));
+        config.retry_writes = (retries, backoff.0);
+        config.ttl = duration(element!());
+        config.stamp_send_times = element!();
+        config.record_provenance = element!();
+        config.sequence_senders = element!();
+        config.archive_dir = element!();
+        config.corruption_policy = element!();
+        config.keep_for = duration(element!());
+        config.keep_bytes = element!();
+        config.scrub_retained = element!(Option<(Millis, bool)>).map(|(i, q)| (i.0, q));
+        config.verify_on_open = element!();
+        config.repair_on_open = element!();
+        config.restore_from = element!();
+        config.watermarks = element!();
+        config.byte_watermarks = element!();
+        config.adaptive_sampling = element!();
+        config.rate_limit = element!();
+        config.sender_rate_limit = element!();
+        Ok(config)
+    }
+
+    // Formats with field names may leave options out, or give them in any
+    // order.
+    fn visit_map<A>(self, mut map: A) -> Result<QueueConfig, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut config = QueueConfig::new(String::new(), PathBuf::new());
+        let mut named = false;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "name" => {
+                    config.name = map.next_value()?;
+                    named = true;
+                }
+                "data_dir" => config.data_dir = map.next_value()?,
+                "ephemeral" => config.ephemeral = map.next_value()?,
+                "max_bytes" => config.max_bytes = map.next_value()?,
+                "adaptive_max_bytes" => config.adaptive_max_bytes = map.next_value()?,
+                "order" => config.order = map.next_value()?,
+                "priority_lanes" => config.priority_lanes = map.next_value()?,
+                "priority_aging" => config.priority_aging = duration(map.next_value()?),
+                "flush_on_drop" => config.flush_on_drop = map.next_value()?,
+                "stage_writes" => config.stage_writes = map.next_value()?,
+                "coalesce_wakes" => {
+                    config.coalesce_wakes = map.next_value::<Option<(usize, Millis)>>()?
+                        .map(|(r, i)| (r, i.0))
+                }
+                "memory_budget" => config.memory_budget = map.next_value()?,
+                "cgroup_memory_budget" => {
+                    config.cgroup_memory_budget = map.next_value::<Option<(f64, Millis)>>()?
+                        .map(|(p, i)| (p, i.0))
+                }
+                "reserve" => config.reserve = map.next_value()?,
+                "read_ahead" => config.read_ahead = map.next_value()?,
+                "pack_records" => config.pack_records = map.next_value()?,
+                "compress_blocks" => config.compress_blocks = map.next_value()?,
+                "train_dictionary" => config.train_dictionary = map.next_value()?,
+                "preallocate" => config.preallocate = map.next_value()?,
+                "memory_only" => config.memory_only = map.next_value()?,
+                "disk_primary" => config.disk_primary = map.next_value()?,
+                "overflow_policy" => config.overflow_policy = map.next_value()?,
+                "max_disk_bytes" => config.max_disk_bytes = map.next_value()?,
+                "evict_oldest" => config.evict_oldest = map.next_value()?,
+                "retry_writes" => {
+                    let (retries, backoff): (u32, Millis) = map.next_value()?;
+                    config.retry_writes = (retries, backoff.0);
+                }
+                "ttl" => config.ttl = duration(map.next_value()?),
+                "stamp_send_times" => config.stamp_send_times = map.next_value()?,
+                "record_provenance" => config.record_provenance = map.next_value()?,
+                "sequence_senders" => config.sequence_senders = map.next_value()?,
+                "archive_dir" => config.archive_dir = map.next_value()?,
+                "corruption_policy" => config.corruption_policy = map.next_value()?,
+                "keep_for" => config.keep_for = duration(map.next_value()?),
+                "keep_bytes" => config.keep_bytes = map.next_value()?,
+                "scrub_retained" => {
+                    config.scrub_retained = map.next_value::<Option<(Millis, bool)>>()?
+                        .map(|(i, q)| (i.0, q))
+                }
+                "verify_on_open" => config.verify_on_open = map.next_value()?,
+                "repair_on_open" => config.repair_on_open = map.next_value()?,
+                "restore_from" => config.restore_from = map.next_value()?,
+                "watermarks" => config.watermarks = map.next_value()?,
+                "byte_watermarks" => config.byte_watermarks = map.next_value()?,
+                "adaptive_sampling" => config.adaptive_sampling = map.next_value()?,
+                "rate_limit" => config.rate_limit = map.next_value()?,
+                "sender_rate_limit" => config.sender_rate_limit = map.next_value()?,
+                // A misspelt option would otherwise be dropped without a word.
+                other => return Err(de::Error::unknown_field(other, FIELDS)),
+            }
+        }
+        if !named {
+            return Err(de::Error::missing_field("name"));
+        }
+        Ok(config)
+    }
+}
+
+// The index of a variant among `names`, named or numbered as the format has
+// it
+struct Variant(&'static [&'static str]);
+
+impl<'de> DeserializeSeed<'de> for Variant {
+    type Value = usize;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<usize, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_identifier(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Variant {
+    type Value = usize;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "one of {:?}", self.0)
+    }
+
+    fn visit_u64<E>(self, idx: u64) -> Result<usize, E>
+    where
+        E: de::Error,
+    {
+        if (idx as usize) < self.0.len() {
+            Ok(idx as usize)
+        } else {
+            Err(de::Error::invalid_value(de::Unexpected::Unsigned(idx), &self))
+        }
+    }
+
+    fn visit_str<E>(self, name: &str) -> Result<usize, E>
+    where
+        E: de::Error,
+    {
+        self.0
+            .iter()
+            .position(|n| *n == name)
+            .ok_or_else(|| de::Error::unknown_variant(name, self.0))
+    }
+}
+
+// The enums of a configuration with only unit variants, each by its name
+macro_rules! unit_enum {
+    ($ty:ident, $names:ident, $variants:ident, $($variant:ident => $name:expr),+) => {
+        const $names: &[&str] = &[$($name),+];
+        const $variants: &[$ty] = &[$($ty::$variant),+];
+
+        impl Serialize for $ty {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: Serializer,
+            {
+                let idx = $variants.iter().position(|v| v == self).unwrap_or(0);
+                serializer.serialize_unit_variant(stringify!($ty), idx as u32, $names[idx])
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $ty {
+            fn deserialize<D>(deserializer: D) -> Result<$ty, D::Error>
+            where
+                D: Deserializer<'de>,
+            {
+                struct EnumVisitor;
+
+                impl<'de> Visitor<'de> for EnumVisitor {
+                    type Value = $ty;
+
+                    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                        write!(f, "one of {:?}", $names)
+                    }
+
+                    fn visit_enum<A>(self, data: A) -> Result<$ty, A::Error>
+                    where
+                        A: EnumAccess<'de>,
+                    {
+                        let (idx, variant) = data.variant_seed(Variant($names))?;
+                        variant.unit_variant().map(|()| $variants[idx])
+                    }
+                }
+
+                deserializer.deserialize_enum(stringify!($ty), $names, EnumVisitor)
+            }
+        }
+    };
+}
+
+unit_enum!(OrderMode, ORDER_NAMES, ORDER_MODES, Global => "global", PerSender => "per_sender");
+unit_enum!(OverflowPolicy, OVERFLOW_NAMES, OVERFLOW_POLICIES, Block => "block", Fail => "fail");
+unit_enum!(RatePolicy, RATE_NAMES, RATE_POLICIES, Block => "block", Fail => "fail");
+unit_enum!(VerifyLevel, VERIFY_NAMES, VERIFY_LEVELS, None => "none", Fast => "fast", Full => "full");
+
+const CORRUPTION_POLICIES: &[&str] = &["abort", "skip", "dead_letter"];
+
+impl Serialize for CorruptionPolicy {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match *self {
+            CorruptionPolicy::Abort => {
+                serializer.serialize_unit_variant("CorruptionPolicy", 0, "abort")
+            }
+            CorruptionPolicy::Skip => {
+                serializer.serialize_unit_variant("CorruptionPolicy", 1, "skip")
+            }
+            CorruptionPolicy::DeadLetter(ref dir) => {
+                serializer.serialize_newtype_variant("CorruptionPolicy", 2, "dead_letter", dir)
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for CorruptionPolicy {
+    fn deserialize<D>(deserializer: D) -> Result<CorruptionPolicy, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_enum("CorruptionPolicy", CORRUPTION_POLICIES, CorruptionVisitor)
+    }
+}
+
+struct CorruptionVisitor;
+
+impl<'de> Visitor<'de> for CorruptionVisitor {
+    type Value = CorruptionPolicy;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("\"abort\", \"skip\" or a \"dead_letter\" directory")
+    }
+
+    fn visit_enum<A>(self, data: A) -> Result<CorruptionPolicy, A::Error>
+    where
+        A: EnumAccess<'de>,
+    {
+        let (idx, variant) = data.variant_seed(Variant(CORRUPTION_POLICIES))?;
+        match idx {
+            0 => variant.unit_variant().map(|()| CorruptionPolicy::Abort),
+            1 => variant.unit_variant().map(|()| CorruptionPolicy::Skip),
+            _ => variant.newtype_variant().map(CorruptionPolicy::DeadLetter),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bincode;
+
+    #[test]
+    fn config_round_trips() {
+        let mut config = QueueConfig::new("orders", "/var/lib/app");
+        config.order = OrderMode::PerSender;
+        config.priority_aging = Some(Duration::from_millis(250));
+        config.cgroup_memory_budget = Some((0.25, Duration::from_secs(5)));
+        config.reserve = vec![("billing".to_string(), 1024, 4096)];
+        config.overflow_policy = OverflowPolicy::Block;
+        config.corruption_policy = CorruptionPolicy::DeadLetter(PathBuf::from("/dead"));
+        config.scrub_retained = Some((Duration::from_secs(60), true));
+        config.verify_on_open = VerifyLevel::Full;
+        config.repair_on_open = true;
+        config.sender_rate_limit = Some((100, 10, RatePolicy::Fail));
+        config.adaptive_sampling = Some(0.25);
+
+        let bytes = bincode::serialize(&config, bincode::Infinite).unwrap();
+        let back: QueueConfig = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(config, back);
+    }
+
+    #[test]
+    fn delta_carries_the_runtime_options() {
+        let mut config = QueueConfig::new("orders", "/var/lib/app");
+        config.memory_budget = Some(1 << 20);
+        config.watermarks = Some((100, 10));
+        config.ttl = Some(Duration::from_secs(1));
+        let delta = config.delta();
+        assert_eq!(Some(1 << 20), delta.memory_budget);
+        assert_eq!(Some((100, 10)), delta.watermarks);
+        assert_eq!(None, delta.max_disk_bytes);
+        assert_eq!(None, delta.in_memory_capacity);
+    }
+}
@@ -0,0 +1,203 @@
+//! Encryption of items paged out to disk
+//!
+//! A channel built with `ChannelBuilder::encryption_key` seals every record
+//! it pages out with AES-256-GCM. The record's length header is left in the
+//! clear so queue files can still be walked record by record; its payload
+//! becomes the id of the key it was sealed under, the nonce, the ciphertext
+//! and the authentication tag. Nonces are a random base chosen as the channel
+//! is built, combined with a count of the records sealed since, so none
+//! repeats under one key.
+//!
+//! A channel holds every key it has been given, so records sealed before a
+//! rotation still open after it. Resealing a record under the current key
+//! leaves its length unchanged.
+use aes_gcm::aead::{AeadCore, AeadInPlace, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce, Tag};
+use std::fmt;
+
+/// The bytes of key id leading each sealed payload, big-endian
+pub const KEY_ID_LEN: usize = 4;
+/// The bytes of nonce following the key id
+pub const NONCE_LEN: usize = 12;
+/// The bytes of authentication tag ending each sealed payload
+pub const TAG_LEN: usize = 16;
+
+const HEADER_LEN: usize = KEY_ID_LEN + NONCE_LEN;
+
+pub struct Cipher {
+    // Every key the channel has been given, by id. The last is current.
+    keys: Vec<(u32, Aes256Gcm)>,
+    base: [u8; NONCE_LEN],
+    sealed: u64,
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ids: Vec<u32> = self.keys.iter().map(|k| k.0).collect();
+        f.debug_struct("Cipher")
+            .field("key_ids", &ids)
+            .field("sealed", &self.sealed)
+            .finish()
+    }
+}
+
+impl Cipher {
+    /// A cipher sealing under `key`, known by `id`
+    pub fn new(id: u32, key: &[u8; 32]) -> Cipher {
+        let mut base = [0; NONCE_LEN];
+        base.copy_from_slice(&Aes256Gcm::generate_nonce(&mut OsRng));
+        Cipher {
+            keys: vec![(id, aead(key))],
+            base: base,
+            sealed: 0,
+        }
+    }
+
+    /// Seal from now on under `key`, known by `id`, keeping the keys before
+    /// it to open what they sealed
+    ///
+    /// Panics if `id` is already in use.
+    pub fn rotate(&mut self, id: u32, key: &[u8; 32]) {
+        assert!(
+            self.keys.iter().all(|k| k.0 != id),
+            "encryption key id {} is already in use",
+            id
+        );
+        self.keys.push((id, aead(key)));
+    }
+
+    /// The id of the key records are sealed under
+    pub fn current_id(&self) -> u32 {
+        self.keys.last().expect("cipher has no keys").0
+    }
+
+    /// Reserve room for a key id and nonce at the end of `buf`, returning
+    /// where the plaintext that follows them starts
+    pub fn begin(&self, buf: &mut Vec<u8>) -> usize {
+        buf.extend_from_slice(&[0; HEADER_LEN]);
+        buf.len()
+    }
+
+    /// Seal the plaintext `buf[start..]` in place, filling in the key id and
+    /// nonce reserved before it and appending the tag
+    pub fn seal(&mut self, buf: &mut Vec<u8>, start: usize) {
+        let tag = self.seal_in_place(&mut buf[start - HEADER_LEN..]);
+        buf.extend_from_slice(&tag);
+    }
+
+    /// Open the sealed payload `record` in place, returning its plaintext, or
+    /// None if it was sealed under a key this cipher does not hold or has
+    /// been altered since
+    pub fn open<'a>(&self, record: &'a mut [u8]) -> Option<&'a [u8]> {
+        if record.len() < HEADER_LEN + TAG_LEN {
+            return None;
+        }
+        let (header, rest) = record.split_at_mut(HEADER_LEN);
+        let mut id = [0; KEY_ID_LEN];
+        id.copy_from_slice(&header[..KEY_ID_LEN]);
+        let id = u32::from_be_bytes(id);
+        let aead = &self.keys.iter().find(|k| k.0 == id)?.1;
+        let split = rest.len() - TAG_LEN;
+        let (body, tag) = rest.split_at_mut(split);
+        let nonce = Nonce::from_slice(&header[KEY_ID_LEN..]);
+        aead.decrypt_in_place_detached(nonce, b"", body, Tag::from_slice(tag))
+            .ok()?;
+        Some(body)
+    }
+
+    /// Seal the sealed payload `record` afresh under the current key, in
+    /// place, returning false if it could not be opened
+    pub fn reseal(&mut self, record: &mut [u8]) -> bool {
+        if self.open(record).is_none() {
+            return false;
+        }
+        let split = record.len() - TAG_LEN;
+        let (sealed, tag) = record.split_at_mut(split);
+        tag.copy_from_slice(&self.seal_in_place(sealed));
+        true
+    }
+
+    // Seal `buf[HEADER_LEN..]` under the current key, writing the key id and
+    // a fresh nonce over `buf[..HEADER_LEN]`, and return the tag.
+    fn seal_in_place(&mut self, buf: &mut [u8]) -> Tag {
+        let mut nonce = self.base;
+        let count = self.sealed.to_be_bytes();
+        for (n, c) in nonce[NONCE_LEN - count.len()..].iter_mut().zip(count.iter()) {
+            *n ^= *c;
+        }
+        self.sealed += 1;
+        let &(id, ref aead) = self.keys.last().expect("cipher has no keys");
+        let (header, body) = buf.split_at_mut(HEADER_LEN);
+        header[..KEY_ID_LEN].copy_from_slice(&id.to_be_bytes());
+        header[KEY_ID_LEN..].copy_from_slice(&nonce);
+        aead.encrypt_in_place_detached(Nonce::from_slice(&nonce), b"", body)
+            .expect("could not encrypt record")
+    }
+}
+
+fn aead(key: &[u8; 32]) -> Aes256Gcm {
+    Aes256Gcm::new_from_slice(key).expect("AES-256 keys are 32 bytes")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sealed(cipher: &mut Cipher, plain: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let start = cipher.begin(&mut buf);
+        buf.extend_from_slice(plain);
+        cipher.seal(&mut buf, start);
+        buf
+    }
+
+    #[test]
+    fn sealed_records_open_to_their_plaintext() {
+        let mut cipher = Cipher::new(0, &[7; 32]);
+        let mut first = sealed(&mut cipher, b"hopper");
+        let second = sealed(&mut cipher, b"hopper");
+        assert_eq!(first.len(), HEADER_LEN + 6 + TAG_LEN);
+        assert!(first[HEADER_LEN..HEADER_LEN + 6] != b"hopper"[..]);
+        assert!(first[..HEADER_LEN] != second[..HEADER_LEN]);
+        assert_eq!(Some(&b"hopper"[..]), cipher.open(&mut first));
+    }
+
+    #[test]
+    fn altered_or_foreign_records_do_not_open() {
+        let mut cipher = Cipher::new(0, &[7; 32]);
+        let mut altered = sealed(&mut cipher, b"hopper");
+        altered[HEADER_LEN] ^= 1;
+        assert_eq!(None, cipher.open(&mut altered));
+
+        let mut foreign = sealed(&mut Cipher::new(0, &[8; 32]), b"hopper");
+        assert_eq!(None, cipher.open(&mut foreign));
+        let mut unknown = sealed(&mut Cipher::new(1, &[7; 32]), b"hopper");
+        assert_eq!(None, cipher.open(&mut unknown));
+        assert_eq!(None, cipher.open(&mut [0; 4]));
+    }
+
+    #[test]
+    fn rotation_keeps_old_records_open_until_resealed() {
+        let mut cipher = Cipher::new(0, &[7; 32]);
+        let mut old = sealed(&mut cipher, b"hopper");
+        cipher.rotate(1, &[8; 32]);
+        assert_eq!(1, cipher.current_id());
+        let mut new = sealed(&mut cipher, b"hopper");
+        assert_eq!(&[0, 0, 0, 1], &new[..KEY_ID_LEN]);
+        assert_eq!(Some(&b"hopper"[..]), cipher.open(&mut new));
+
+        let len = old.len();
+        assert!(cipher.reseal(&mut old));
+        assert_eq!(len, old.len());
+        assert_eq!(&[0, 0, 0, 1], &old[..KEY_ID_LEN]);
+        let only_new = Cipher::new(1, &[8; 32]);
+        assert_eq!(Some(&b"hopper"[..]), only_new.open(&mut old));
+    }
+
+    #[test]
+    #[should_panic]
+    fn key_ids_are_not_reused() {
+        let mut cipher = Cipher::new(0, &[7; 32]);
+        cipher.rotate(0, &[8; 32]);
+    }
+}
@@ -0,0 +1,126 @@
+//! Queue file records that could not be decoded
+//!
+//! By default a Receiver that reads a record it cannot decrypt or decode from
+//! a queue file panics, as there is no item to hand back. A channel built
+//! with `CorruptionPolicy::DeadLetter`, or `ChannelBuilder::dead_letter_dir`
+//! for short, instead moves the record aside and carries on with the next
+//! one, so one corrupt or schema-incompatible record does not hold up the
+//! rest. Each is counted in `QueueMetrics::total_dead_lettered` and appended
+//! to the `dead-letters` file in the channel's subdirectory of the
+//! dead-letter directory. Integers are big-endian.
+//!
+//! ```text
+//! records    each:
+//!   seq          u64   the record's place in the channel's send order
+//!   error_len    u32
+//!   error        error_len bytes, why the record failed, UTF-8
+//!   payload_len  u32
+//!   payload      payload_len bytes, the record as read, decrypted if it
+//!                could be
+//! ```
+//!
+//! `read` reads the records of a dead-letter file back.
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::Path;
+
+/// The name of the file dead letters are appended to
+pub const FILE_NAME: &str = "dead-letters";
+
+/// A record moved aside for failing to decode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadLetter {
+    /// The record's place in the channel's send order
+    pub seq: u64,
+    /// Why the record could not be decoded
+    pub error: String,
+    /// The record's bytes, decrypted if they could be
+    pub payload: Vec<u8>,
+}
+
+/// Append `letter` to the dead-letter file at `path`
+pub(crate) fn append(path: &Path, letter: &DeadLetter) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(16 + letter.error.len() + letter.payload.len());
+    buf.extend_from_slice(&letter.seq.to_be_bytes());
+    buf.extend_from_slice(&(letter.error.len() as u32).to_be_bytes());
+    buf.extend_from_slice(letter.error.as_bytes());
+    buf.extend_from_slice(&(letter.payload.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&letter.payload);
+    let mut fp = OpenOptions::new().create(true).append(true).open(path)?;
+    fp.write_all(&buf)
+}
+
+/// Read every record of the dead-letter file at `path`, oldest first
+pub fn read(path: &Path) -> io::Result<Vec<DeadLetter>> {
+    let buf = fs::read(path)?;
+    let mut letters = Vec::new();
+    let mut at = &buf[..];
+    while !at.is_empty() {
+        let seq = take(&mut at, 8)?;
+        let error_len = take(&mut at, 4)?;
+        let error = take(&mut at, be32(error_len) as usize)?;
+        let payload_len = take(&mut at, 4)?;
+        let payload = take(&mut at, be32(payload_len) as usize)?;
+        let mut seq_bytes = [0; 8];
+        seq_bytes.copy_from_slice(seq);
+        letters.push(DeadLetter {
+            seq: u64::from_be_bytes(seq_bytes),
+            error: String::from_utf8_lossy(error).into_owned(),
+            payload: payload.to_vec(),
+        });
+    }
+    Ok(letters)
+}
+
+fn be32(bytes: &[u8]) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(bytes);
+    u32::from_be_bytes(buf)
+}
+
+// Take the next `len` bytes from `buf`.
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if buf.len() < len {
+        return Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "dead-letter file ends part way through a record",
+        ));
+    }
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+    Ok(head)
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::*;
+
+    #[test]
+    fn letters_round_trip() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let path = dir.path().join(FILE_NAME);
+        let letters = vec![
+            DeadLetter {
+                seq: 7,
+                error: "invalid utf-8".to_string(),
+                payload: vec![0xff, 0x00],
+            },
+            DeadLetter {
+                seq: 1 << 40,
+                error: String::new(),
+                payload: Vec::new(),
+            },
+        ];
+        for letter in &letters {
+            append(&path, letter).unwrap();
+        }
+        assert_eq!(letters, read(&path).unwrap());
+
+        let mut torn = fs::read(&path).unwrap();
+        torn.pop();
+        fs::write(&path, torn).unwrap();
+        assert_eq!(ErrorKind::UnexpectedEof, read(&path).unwrap_err().kind());
+    }
+}
@@ -0,0 +1,256 @@
+//! Items sent with `Sender::send_after`, waiting to come due
+//!
+//! A delayed item is not held in memory. Its encoding is appended to the
+//! channel's `hopper.delayed` file, stamped with the time it comes due, and
+//! only its place in the file is kept, in order of when it comes due. Once
+//! due the item is read back for the Receiver and its stamp overwritten, so
+//! that it is not delivered again. The file is emptied whenever no delayed
+//! item is left waiting in it, and read back by the channel's next build, so
+//! delayed items outlive the process. Integers are big-endian.
+//!
+//! ```text
+//! records    each:
+//!   len      u32   the bytes of due and payload
+//!   due      u64   when the item comes due, in milliseconds since the UNIX
+//!                  epoch, or u64::MAX once delivered
+//!   payload  len - 8 bytes, the item's encoding, sealed if the channel
+//!            encrypts
+//! ```
+//!
+//! A memory-only channel keeps its delayed items' encodings in memory
+//! instead.
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// The stamp of a record already delivered
+const TAKEN: u64 = u64::MAX;
+
+// The bytes of a record's length and stamp
+const RECORD_HEADER: u64 = 12;
+
+// Where a delayed item's encoding is held
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Place {
+    // The offset and length of its record in the file
+    File(u64, u32),
+    Memory(Vec<u8>),
+}
+
+/// The delayed items of a channel, see the module documentation
+pub struct Store {
+    // The file, if the channel keeps its delayed items in one, and where its
+    // records end
+    path: Option<PathBuf>,
+    fp: Option<fs::File>,
+    end: u64,
+    // When each item comes due, and the order they were delayed in to break
+    // ties, earliest first
+    due: BinaryHeap<Reverse<(u64, u64, Place)>>,
+    next_seq: u64,
+}
+
+impl fmt::Debug for Store {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Store")
+            .field("path", &self.path)
+            .field("waiting", &self.len())
+            .field("next_due", &self.next_due())
+            .finish()
+    }
+}
+
+impl Store {
+    /// A store holding its items in memory
+    pub fn in_memory() -> Store {
+        Store {
+            path: None,
+            fp: None,
+            end: 0,
+            due: BinaryHeap::new(),
+            next_seq: 0,
+        }
+    }
+
+    /// The store in the file at `path`, holding the items left waiting in it
+    /// by an earlier build of the channel
+    ///
+    /// A record cut short by a crash mid-write is truncated away.
+    pub fn open(path: &Path) -> io::Result<Store> {
+        let mut store = Store::in_memory();
+        store.path = Some(path.to_path_buf());
+        let buf = match fs::read(path) {
+            Ok(buf) => buf,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(store),
+            Err(e) => return Err(e),
+        };
+        let mut at = 0;
+        while let Some((due, len)) = record_at(&buf, at) {
+            if due != TAKEN {
+                store.push(due, Place::File(at as u64, len));
+            }
+            at += RECORD_HEADER as usize + len as usize - 8;
+        }
+        store.end = at as u64;
+        if store.due.is_empty() {
+            store.end = 0;
+        }
+        let end = store.end;
+        if end < buf.len() as u64 {
+            store.file()?.set_len(end)?;
+        }
+        Ok(store)
+    }
+
+    /// Return the number of items waiting
+    pub fn len(&self) -> usize {
+        self.due.len()
+    }
+
+    /// Whether no item is waiting
+    pub fn is_empty(&self) -> bool {
+        self.due.is_empty()
+    }
+
+    /// When the earliest item waiting comes due, in milliseconds since the
+    /// UNIX epoch
+    pub fn next_due(&self) -> Option<u64> {
+        self.due.peek().map(|&Reverse((due, _, _))| due)
+    }
+
+    /// Hold the item encoded as `payload` until `due`, in milliseconds since
+    /// the UNIX epoch
+    ///
+    /// An item the file cannot take is held in memory instead, any part of
+    /// its record written truncated away again, and the error returned.
+    pub fn delay(&mut self, payload: Vec<u8>, due: u64) -> io::Result<()> {
+        if self.path.is_none() {
+            self.push(due, Place::Memory(payload));
+            return Ok(());
+        }
+        let len = payload.len() as u32 + 8;
+        let mut record = Vec::with_capacity(RECORD_HEADER as usize + payload.len());
+        record.extend_from_slice(&len.to_be_bytes());
+        record.extend_from_slice(&due.to_be_bytes());
+        record.extend_from_slice(&payload);
+        let at = self.end;
+        let written = self.file().and_then(|fp| {
+            fp.seek(SeekFrom::Start(at))?;
+            fp.write_all(&record)
+        });
+        if let Err(e) = written {
+            // Held in memory instead, so as not to be lost
+            let _ = self.file().and_then(|fp| fp.set_len(at));
+            self.push(due, Place::Memory(payload));
+            return Err(e);
+        }
+        self.end += record.len() as u64;
+        self.push(due, Place::File(at, len));
+        Ok(())
+    }
+
+    /// Take the encoding of the earliest item waiting, if it is due by `now`,
+    /// in milliseconds since the UNIX epoch
+    ///
+    /// The item is taken even if its record cannot be read back, and the
+    /// error returned in its place.
+    pub fn take_due(&mut self, now: u64) -> Option<io::Result<Vec<u8>>> {
+        match self.next_due() {
+            Some(due) if due <= now => {}
+            _ => return None,
+        }
+        let Reverse((_, _, place)) = self.due.pop().expect("due item");
+        let taken = match place {
+            Place::Memory(payload) => Ok(payload),
+            Place::File(at, len) => self.read_back(at, len),
+        };
+        if self.due.is_empty() && self.end > 0 && self.file().and_then(|fp| fp.set_len(0)).is_ok() {
+            self.end = 0;
+        }
+        Some(taken)
+    }
+
+    fn push(&mut self, due: u64, place: Place) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.due.push(Reverse((due, seq, place)));
+    }
+
+    // Read the payload of the record at `at` and mark it delivered.
+    fn read_back(&mut self, at: u64, len: u32) -> io::Result<Vec<u8>> {
+        let fp = self.file()?;
+        let mut payload = vec![0; len as usize - 8];
+        fp.seek(SeekFrom::Start(at + RECORD_HEADER))?;
+        fp.read_exact(&mut payload)?;
+        fp.seek(SeekFrom::Start(at + 4))?;
+        fp.write_all(&TAKEN.to_be_bytes())?;
+        Ok(payload)
+    }
+
+    fn file(&mut self) -> io::Result<&mut fs::File> {
+        if self.fp.is_none() {
+            let path = self.path.as_ref().expect("store kept in a file");
+            let fp = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            self.fp = Some(fp);
+        }
+        Ok(self.fp.as_mut().expect("file just opened"))
+    }
+}
+
+// The stamp and length of the whole record at `at` in `buf`, if there is one
+fn record_at(buf: &[u8], at: usize) -> Option<(u64, u32)> {
+    let rest = buf.get(at..)?;
+    if rest.len() < RECORD_HEADER as usize {
+        return None;
+    }
+    let mut len = [0; 4];
+    len.copy_from_slice(&rest[..4]);
+    let len = u32::from_be_bytes(len);
+    if len < 8 || rest.len() - 4 < len as usize {
+        return None;
+    }
+    let mut due = [0; 8];
+    due.copy_from_slice(&rest[4..12]);
+    Some((u64::from_be_bytes(due), len))
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::*;
+
+    #[test]
+    fn items_come_due_in_order_and_outlive_the_store() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let path = dir.path().join("hopper.delayed");
+        {
+            let mut store = Store::open(&path).unwrap();
+            store.delay(b"late".to_vec(), 300).unwrap();
+            store.delay(b"early".to_vec(), 100).unwrap();
+            store.delay(b"middle".to_vec(), 200).unwrap();
+            assert_eq!(Some(100), store.next_due());
+            assert!(store.take_due(99).is_none());
+            assert_eq!(b"early".to_vec(), store.take_due(150).unwrap().unwrap());
+        }
+        // A record cut short is dropped
+        let mut fp = OpenOptions::new().append(true).open(&path).unwrap();
+        fp.write_all(&[0, 0, 0, 20, 0, 0]).unwrap();
+        drop(fp);
+
+        let mut store = Store::open(&path).unwrap();
+        assert_eq!(2, store.len());
+        assert_eq!(b"middle".to_vec(), store.take_due(400).unwrap().unwrap());
+        assert_eq!(b"late".to_vec(), store.take_due(400).unwrap().unwrap());
+        assert!(store.is_empty());
+        assert_eq!(0, fs::metadata(&path).unwrap().len());
+    }
+}
@@ -0,0 +1,289 @@
+//! Compression dictionaries trained on a channel's own records
+//!
+//! Small records have little in common with the others of their block for a
+//! compressor to find, and compress poorly alone. A channel built with
+//! `ChannelBuilder::train_dictionary` samples the records it pages out,
+//! trains a zstd dictionary on them and from then on compresses the blocks of
+//! `ChannelBuilder::pack_records` with it. Each dictionary is stored in the
+//! channel's directory, beside its metadata file, as `hopper.dict.<version>`,
+//! versions counting up from 1, and each block compressed with one names it.
+//! `Sender::retrain_dictionary` samples afresh for the next version, so
+//! blocks written under an older dictionary go on decoding with theirs.
+//!
+//! A block compressed with a dictionary has the `block::DICTIONARY` flag set
+//! and a body of, big-endian:
+//!
+//! ```text
+//! version    u32        the version of the dictionary
+//! length     u32        the length of the body uncompressed
+//! frame      the body compressed with the dictionary as a zstd frame
+//! ```
+#[cfg(feature = "dictionary")]
+use private;
+#[cfg(feature = "dictionary")]
+use std::collections::HashMap;
+use std::fmt;
+#[cfg(feature = "dictionary")]
+use std::fs;
+use std::io::{self, ErrorKind};
+#[cfg(feature = "dictionary")]
+use std::mem;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "dictionary")]
+use zstd::bulk::{Compressor, Decompressor};
+
+/// The prefix of the name of a dictionary file, the version following
+///
+/// Queue files are named for their sequence number, so a non-numeric name
+/// can never collide with one.
+#[cfg(feature = "dictionary")]
+pub const FILE_PREFIX: &str = "hopper.dict.";
+
+// The bytes of a block body ahead of its zstd frame
+#[cfg(feature = "dictionary")]
+const HEADER_LEN: usize = 8;
+
+/// The path of the dictionary of `version` in the channel directory `root`
+#[cfg(feature = "dictionary")]
+pub fn path(root: &Path, version: u32) -> PathBuf {
+    root.join(format!("{}{}", FILE_PREFIX, version))
+}
+
+/// The version of the newest dictionary stored in `root`, if any
+#[cfg(feature = "dictionary")]
+pub fn newest(root: &Path) -> Option<u32> {
+    fs::read_dir(root)
+        .ok()?
+        .filter_map(|de| {
+            de.ok()?
+                .file_name()
+                .to_str()?
+                .strip_prefix(FILE_PREFIX)?
+                .parse::<u32>()
+                .ok()
+        })
+        .max()
+}
+
+#[cfg(feature = "dictionary")]
+fn damaged(what: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("queue file block {}", what))
+}
+
+/// Samples the records a Sender pages out, trains dictionaries on them and
+/// compresses blocks with the newest
+#[cfg(feature = "dictionary")]
+pub struct Trainer {
+    root: PathBuf,
+    // The records to sample for each dictionary and the most bytes it may
+    // take
+    samples: usize,
+    max_bytes: usize,
+    // The records sampled so far, back to back, and their lengths. Sampling
+    // stops once a dictionary is trained, or fails to be.
+    sampled: Vec<u8>,
+    sizes: Vec<usize>,
+    sampling: bool,
+    // The newest dictionary's version, 0 for none, and its compressor
+    version: u32,
+    compressor: Option<Compressor<'static>>,
+}
+
+#[cfg(feature = "dictionary")]
+impl fmt::Debug for Trainer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Trainer")
+            .field("root", &self.root)
+            .field("samples", &self.samples)
+            .field("max_bytes", &self.max_bytes)
+            .field("sampled", &self.sizes.len())
+            .field("sampling", &self.sampling)
+            .field("version", &self.version)
+            .finish()
+    }
+}
+
+#[cfg(feature = "dictionary")]
+impl Trainer {
+    /// A trainer for the channel directory `root`, sampling `samples` records
+    /// for a dictionary of up to `max_bytes`
+    ///
+    /// If `root` already holds a dictionary the newest is used from the
+    /// start, with no sampling until `retrain`.
+    pub fn new(root: &Path, samples: usize, max_bytes: usize) -> Trainer {
+        let mut trainer = Trainer {
+            root: root.to_path_buf(),
+            samples: samples,
+            max_bytes: max_bytes,
+            sampled: Vec::new(),
+            sizes: Vec::new(),
+            sampling: true,
+            version: 0,
+            compressor: None,
+        };
+        if let Some(version) = newest(root) {
+            trainer.version = version;
+            let compressor = fs::read(path(root, version))
+                .and_then(|dictionary| Compressor::with_dictionary(LEVEL, &dictionary));
+            if let Ok(compressor) = compressor {
+                trainer.compressor = Some(compressor);
+                trainer.sampling = false;
+            }
+        }
+        trainer
+    }
+
+    /// Sample the records of `batch`, framed back to back, training the next
+    /// dictionary once enough have been
+    pub fn sample(&mut self, batch: &[u8]) {
+        if !self.sampling {
+            return;
+        }
+        let mut rest = batch;
+        while let Some((record, after)) = private::next_record(rest) {
+            if self.sizes.len() >= self.samples {
+                break;
+            }
+            self.sampled.extend_from_slice(record);
+            self.sizes.push(record.len());
+            rest = after;
+        }
+        if self.sizes.len() >= self.samples {
+            self.train();
+        }
+    }
+
+    // Train a dictionary on the records sampled and store it as the next
+    // version. A dictionary that cannot be trained or stored is done without,
+    // the blocks compressed as they were.
+    fn train(&mut self) {
+        self.sampling = false;
+        let sampled = mem::take(&mut self.sampled);
+        let sizes = mem::take(&mut self.sizes);
+        let version = self.version + 1;
+        let stored = ::zstd::dict::from_continuous(&sampled, &sizes, self.max_bytes)
+            .and_then(|dictionary| {
+                let compressor = Compressor::with_dictionary(LEVEL, &dictionary)?;
+                // Stored before any block names it, and whole or not at all
+                let path = path(&self.root, version);
+                let partial = self.root.join(format!("{}{}.partial", FILE_PREFIX, version));
+                fs::write(&partial, &dictionary)?;
+                fs::rename(&partial, &path)?;
+                Ok(compressor)
+            });
+        match stored {
+            Ok(compressor) => {
+                self.version = version;
+                self.compressor = Some(compressor);
+            }
+            Err(_e) => {
+                trace_event!(
+                    root = %self.root.display(),
+                    version = version,
+                    error = %_e,
+                    "could not train compression dictionary"
+                );
+            }
+        }
+    }
+
+    /// Sample afresh for the next version of the dictionary
+    ///
+    /// Blocks go on being compressed with the current dictionary, if there is
+    /// one, until the next is trained.
+    pub fn retrain(&mut self) {
+        self.sampled.clear();
+        self.sizes.clear();
+        self.sampling = true;
+    }
+
+    /// The block body `body` compressed with the newest dictionary as a
+    /// `block::DICTIONARY` body, if there is one and that makes it smaller
+    pub fn compress(&mut self, body: &[u8]) -> Option<Vec<u8>> {
+        let version = self.version;
+        let frame = self.compressor.as_mut()?.compress(body).ok()?;
+        if HEADER_LEN + frame.len() >= body.len() {
+            return None;
+        }
+        let mut out = Vec::with_capacity(HEADER_LEN + frame.len());
+        out.extend_from_slice(&version.to_be_bytes());
+        out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        out.extend_from_slice(&frame);
+        Some(out)
+    }
+}
+
+#[cfg(feature = "dictionary")]
+const LEVEL: i32 = ::zstd::DEFAULT_COMPRESSION_LEVEL;
+
+/// The dictionaries of a channel directory a Receiver has loaded to
+/// decompress blocks with
+pub struct Dictionaries {
+    root: PathBuf,
+    #[cfg(feature = "dictionary")]
+    loaded: HashMap<u32, Decompressor<'static>>,
+}
+
+impl fmt::Debug for Dictionaries {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut debug = f.debug_struct("Dictionaries");
+        debug.field("root", &self.root);
+        #[cfg(feature = "dictionary")]
+        {
+            let loaded: Vec<&u32> = self.loaded.keys().collect();
+            debug.field("loaded", &loaded);
+        }
+        debug.finish()
+    }
+}
+
+impl Dictionaries {
+    /// The dictionaries of the channel directory `root`, loaded as blocks
+    /// need them
+    pub fn new(root: &Path) -> Dictionaries {
+        Dictionaries {
+            root: root.to_path_buf(),
+            #[cfg(feature = "dictionary")]
+            loaded: HashMap::new(),
+        }
+    }
+
+    /// Decompress `body`, the body of a `block::DICTIONARY` block
+    ///
+    /// A body naming a dictionary the directory does not hold, or that does
+    /// not decompress to the length it claims, is an error of kind
+    /// `InvalidData`.
+    #[cfg(feature = "dictionary")]
+    pub fn decompress(&mut self, body: &[u8]) -> io::Result<Vec<u8>> {
+        if body.len() < HEADER_LEN {
+            return Err(damaged("is too short for its dictionary header"));
+        }
+        let version = u32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+        let len = u32::from_be_bytes([body[4], body[5], body[6], body[7]]) as usize;
+        if !self.loaded.contains_key(&version) {
+            let dictionary = fs::read(path(&self.root, version))
+                .map_err(|_| damaged(&format!("names missing dictionary {}", version)))?;
+            let decompressor = Decompressor::with_dictionary(&dictionary)?;
+            self.loaded.insert(version, decompressor);
+        }
+        let decompressor = self.loaded.get_mut(&version).expect("dictionary just loaded");
+        let inflated = decompressor
+            .decompress(&body[HEADER_LEN..], len)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        if inflated.len() != len {
+            return Err(damaged("does not decompress to the length it claims"));
+        }
+        Ok(inflated)
+    }
+
+    /// Decompress `body`, the body of a `block::DICTIONARY` block
+    #[cfg(not(feature = "dictionary"))]
+    pub fn decompress(&mut self, _: &[u8]) -> io::Result<Vec<u8>> {
+        let msg = format!(
+            "queue file block in {} is compressed with a dictionary and the dictionary \
+             feature is off",
+            self.root.display()
+        );
+        Err(io::Error::new(ErrorKind::InvalidData, msg))
+    }
+}
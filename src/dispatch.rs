@@ -0,0 +1,164 @@
+use super::Receiver;
+use serde::de::DeserializeOwned;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+/// The outcome of a `Receiver::for_each_concurrent` or `Receiver::serve` run
+///
+/// Items whose handler returned `Ok` are acknowledged and gone from the
+/// channel. Items whose handler returned an error or panicked are handed back
+/// in `unacked`, in no particular order, so that the caller may retry, resend
+/// or discard them.
+pub struct DispatchReport<T, E> {
+    /// Number of items whose handler returned `Ok`
+    pub acked: usize,
+    /// Items whose handler did not succeed, with the reason
+    pub unacked: Vec<(T, DispatchFailure<E>)>,
+}
+
+impl<T, E> fmt::Debug for DispatchReport<T, E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DispatchReport")
+            .field("acked", &self.acked)
+            .field("unacked", &self.unacked.len())
+            .finish()
+    }
+}
+
+/// Why a handler did not acknowledge an item
+#[derive(Debug)]
+pub enum DispatchFailure<E> {
+    /// The handler returned this error
+    Failed(E),
+    /// The handler panicked. The worker that ran it carried on with the next
+    /// item.
+    Panicked,
+}
+
+impl<T> Receiver<T>
+where
+    T: DeserializeOwned + Send,
+{
+    /// Consume every available item with a pool of `workers` threads
+    ///
+    /// Items are pulled from the channel on the calling thread and handed to
+    /// at most `workers` concurrent invocations of `handler`. This call does
+    /// not return until the channel is empty--as `try_iter().next()` returning
+    /// `None` would report--and every dispatched item has been handled.
+    ///
+    /// A handler acknowledges an item by returning `Ok`. Panics are caught and
+    /// isolated to the item that caused them; the worker keeps running. Items
+    /// that were not acknowledged are returned in the report.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `workers` is zero.
+    pub fn for_each_concurrent<F, E>(&mut self, workers: usize, handler: F) -> DispatchReport<T, E>
+    where
+        F: Fn(&T) -> Result<(), E> + Sync,
+        E: Send,
+    {
+        assert!(workers > 0, "for_each_concurrent needs at least one worker");
+        let (snd, rcv) = mpsc::sync_channel::<T>(workers);
+        let rcv = Mutex::new(rcv);
+        let handler = &handler;
+        let rcv = &rcv;
+        thread::scope(|scope| {
+            let mut joins = Vec::with_capacity(workers);
+            for _ in 0..workers {
+                joins.push(scope.spawn(move || {
+                    let mut acked = 0;
+                    let mut unacked = Vec::new();
+                    loop {
+                        let item = match rcv.lock().expect("dispatch poisoned").recv() {
+                            Ok(item) => item,
+                            Err(_) => break,
+                        };
+                        match panic::catch_unwind(AssertUnwindSafe(|| handler(&item))) {
+                            Ok(Ok(())) => acked += 1,
+                            Ok(Err(e)) => unacked.push((item, DispatchFailure::Failed(e))),
+                            Err(_) => unacked.push((item, DispatchFailure::Panicked)),
+                        }
+                    }
+                    (acked, unacked)
+                }));
+            }
+            while let Some(item) = self.try_iter().next() {
+                if snd.send(item).is_err() {
+                    break;
+                }
+            }
+            drop(snd);
+
+            let mut report = DispatchReport {
+                acked: 0,
+                unacked: Vec::new(),
+            };
+            for jh in joins {
+                let (acked, mut unacked) = jh.join().expect("dispatch worker died");
+                report.acked += acked;
+                report.unacked.append(&mut unacked);
+            }
+            report
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::DispatchFailure;
+    use super::super::channel;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn for_each_concurrent_handles_every_item() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel("for_each_concurrent", dir.path()).unwrap();
+        for i in 0..4096u64 {
+            snd.send(i);
+        }
+
+        let sum = AtomicUsize::new(0);
+        let report = rcv.for_each_concurrent(4, |i: &u64| -> Result<(), ()> {
+            sum.fetch_add(*i as usize, Ordering::SeqCst);
+            Ok(())
+        });
+        assert_eq!(4096, report.acked);
+        assert!(report.unacked.is_empty());
+        assert_eq!((0..4096).sum::<usize>(), sum.load(Ordering::SeqCst));
+        assert_eq!(None, rcv.try_iter().next());
+    }
+
+    #[test]
+    fn for_each_concurrent_isolates_failures_and_panics() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel("for_each_concurrent_fail", dir.path()).unwrap();
+        for i in 0..100u64 {
+            snd.send(i);
+        }
+
+        let report = rcv.for_each_concurrent(3, |i: &u64| {
+            if *i == 13 {
+                panic!("unlucky");
+            } else if i.is_multiple_of(10) {
+                Err(*i)
+            } else {
+                Ok(())
+            }
+        });
+        assert_eq!(100 - 11, report.acked);
+        let mut unacked: Vec<u64> = report.unacked.iter().map(|&(i, _)| i).collect();
+        unacked.sort();
+        assert_eq!(vec![0, 10, 13, 20, 30, 40, 50, 60, 70, 80, 90], unacked);
+        for &(i, ref why) in &report.unacked {
+            match *why {
+                DispatchFailure::Panicked => assert_eq!(13, i),
+                DispatchFailure::Failed(e) => assert_eq!(i, e),
+            }
+        }
+    }
+}
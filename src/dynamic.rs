@@ -0,0 +1,323 @@
+//! Channels carrying items of several types
+//!
+//! A dynamic channel, built with `ChannelBuilder::build_dynamic` and the
+//! `dynamic` feature, carries boxed `Message` trait objects rather than a
+//! single item type, so producers owned by different components can share one
+//! channel and one spill directory. Every type sent must first be registered
+//! in the channel's `TypeRegistry` under a tag unique to it. Each item is
+//! stored as its tag followed by its bincode encoding, and the Receiver looks
+//! the tag up again to decode it as the type it was sent as.
+//!
+//! # Example
+//! ```
+//! extern crate tempdir;
+//! extern crate hopper;
+//!
+//! use hopper::dynamic::{Message, TypeRegistry};
+//!
+//! let dir = tempdir::TempDir::new("hopper").unwrap();
+//! let mut types = TypeRegistry::new();
+//! types.register::<u64>("count").unwrap();
+//! types.register::<String>("label").unwrap();
+//! let (mut snd, mut rcv) = hopper::ChannelBuilder::new("plugins", dir.path())
+//!     .build_dynamic(types)
+//!     .unwrap();
+//!
+//! snd.send(Box::new(9u64)).unwrap();
+//! snd.send(Box::new("nine".to_string())).unwrap();
+//! let first: Box<dyn Message> = rcv.try_recv().unwrap().unwrap();
+//! assert_eq!(Some(&9), first.downcast_ref::<u64>());
+//! let second = rcv.try_recv().unwrap().unwrap();
+//! assert_eq!(Some(&"nine".to_string()), second.downcast_ref::<String>());
+//! ```
+use super::{Error, QueueMetrics, Receiver, Sender};
+use bincode::{self, deserialize, serialize, Infinite};
+use erased_serde;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+/// An item that may be sent through a dynamic channel
+///
+/// Implemented for every `Serialize` type that is `Send`, `Debug` and
+/// `'static`. A type must also be registered in the channel's `TypeRegistry`
+/// before it is sent.
+pub trait Message: erased_serde::Serialize + Any + Send + fmt::Debug {
+    /// Return the `Message` as `Any`, to inspect its concrete type
+    fn as_any(&self) -> &dyn Any;
+
+    /// Convert the boxed `Message` into a boxed `Any`
+    fn into_any(self: Box<Self>) -> Box<dyn Any + Send>;
+}
+
+impl<T> Message for T
+where
+    T: Serialize + Any + Send + fmt::Debug,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any + Send> {
+        self
+    }
+}
+
+serialize_trait_object!(Message);
+
+impl dyn Message {
+    /// Whether the `Message` is a `T`
+    pub fn is<T: Any>(&self) -> bool {
+        self.as_any().is::<T>()
+    }
+
+    /// Return the `Message` as a `T`, if it is one
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.as_any().downcast_ref::<T>()
+    }
+
+    /// Convert the boxed `Message` into a boxed `T`, handing it back if it is
+    /// not one
+    pub fn downcast<T: Any>(self: Box<Self>) -> Result<Box<T>, Box<dyn Message>> {
+        if self.is::<T>() {
+            Ok(self.into_any().downcast::<T>().expect("type checked above"))
+        } else {
+            Err(self)
+        }
+    }
+}
+
+type Decode = fn(&[u8]) -> bincode::Result<Box<dyn Message>>;
+
+fn decode<T>(bytes: &[u8]) -> bincode::Result<Box<dyn Message>>
+where
+    T: Message + DeserializeOwned,
+{
+    deserialize::<T>(bytes).map(|item| Box::new(item) as Box<dyn Message>)
+}
+
+/// The types a dynamic channel carries, each bound to a tag
+///
+/// A tag names its type on disk, so it must be bound to one type only and
+/// should not change for as long as a channel holds items of that type.
+#[derive(Default)]
+pub struct TypeRegistry {
+    tags: HashMap<TypeId, String>,
+    decoders: HashMap<String, Decode>,
+}
+
+impl fmt::Debug for TypeRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TypeRegistry")
+            .field("tags", &self.tags())
+            .finish()
+    }
+}
+
+impl TypeRegistry {
+    /// Create a registry with no types in it
+    pub fn new() -> TypeRegistry {
+        TypeRegistry::default()
+    }
+
+    /// Bind `T` to `tag`
+    ///
+    /// Registering a type again under the tag it already has does nothing.
+    /// Binding a tag that names another type, or a type that has another tag,
+    /// is an `Error::TypeMismatch`.
+    pub fn register<T>(&mut self, tag: &str) -> Result<(), Error>
+    where
+        T: Message + DeserializeOwned,
+    {
+        let type_id = TypeId::of::<T>();
+        match (self.tags.get(&type_id), self.decoders.contains_key(tag)) {
+            (Some(known), _) if known == tag => return Ok(()),
+            (None, false) => {}
+            _ => return Err(Error::TypeMismatch),
+        }
+        self.tags.insert(type_id, tag.to_string());
+        self.decoders.insert(tag.to_string(), decode::<T>);
+        Ok(())
+    }
+
+    /// Return the tag `message`'s type is registered under, if it is
+    pub fn tag_of(&self, message: &dyn Message) -> Option<&str> {
+        self.tags.get(&message.as_any().type_id()).map(|tag| tag.as_str())
+    }
+
+    /// Return the registered tags, sorted
+    pub fn tags(&self) -> Vec<&str> {
+        let mut tags: Vec<&str> = self.decoders.keys().map(|tag| tag.as_str()).collect();
+        tags.sort();
+        tags
+    }
+}
+
+/// The on-disk form of a `Message`: its tag and its bincode encoding
+pub(crate) type Record = (String, Vec<u8>);
+
+/// The 'send' side of a dynamic channel
+///
+/// Created by `ChannelBuilder::build_dynamic`. Clones share the channel and
+/// its `TypeRegistry`.
+#[derive(Clone)]
+pub struct DynamicSender {
+    inner: Sender<Record>,
+    types: Arc<TypeRegistry>,
+}
+
+impl fmt::Debug for DynamicSender {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DynamicSender")
+            .field("name", &self.inner.name())
+            .field("types", &self.types)
+            .finish()
+    }
+}
+
+impl DynamicSender {
+    /// Send `message`
+    ///
+    /// Returns `Error::UnregisteredType` without sending if `message`'s type
+    /// is not in the channel's `TypeRegistry`.
+    pub fn send(&mut self, message: Box<dyn Message>) -> Result<(), Error> {
+        let tag = match self.types.tag_of(&*message) {
+            Some(tag) => tag.to_string(),
+            None => return Err(Error::UnregisteredType),
+        };
+        let bytes = serialize(&*message, Infinite).expect("Failed encoding");
+        self.inner.send((tag, bytes));
+        Ok(())
+    }
+
+    /// Hand any items staged by this DynamicSender to the Receiver
+    ///
+    /// See `Sender::flush`.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    /// Return the channel's `TypeRegistry`
+    pub fn types(&self) -> &TypeRegistry {
+        &self.types
+    }
+}
+
+/// The 'receive' side of a dynamic channel
+///
+/// Created by `ChannelBuilder::build_dynamic`.
+pub struct DynamicReceiver {
+    inner: Receiver<Record>,
+    types: Arc<TypeRegistry>,
+}
+
+impl fmt::Debug for DynamicReceiver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DynamicReceiver")
+            .field("name", &self.inner.name())
+            .field("types", &self.types)
+            .finish()
+    }
+}
+
+impl DynamicReceiver {
+    /// Receive the next item, if one is waiting
+    ///
+    /// An item whose tag is not in the channel's `TypeRegistry` is consumed
+    /// and reported as `Error::UnregisteredType`.
+    pub fn try_recv(&mut self) -> Option<Result<Box<dyn Message>, Error>> {
+        let (tag, bytes) = self.inner.try_iter().next()?;
+        Some(match self.types.decoders.get(&tag) {
+            Some(decode) => Ok(decode(&bytes).expect("Failed decoding")),
+            None => Err(Error::UnregisteredType),
+        })
+    }
+
+    /// Return the channel's `TypeRegistry`
+    pub fn types(&self) -> &TypeRegistry {
+        &self.types
+    }
+
+    /// Return the channel's metrics
+    ///
+    /// See `Receiver::metrics`.
+    pub fn metrics(&self) -> QueueMetrics {
+        self.inner.metrics()
+    }
+}
+
+/// Wrap a (Sender, Receiver) pair of `Record`s as a dynamic channel
+pub(crate) fn assemble(
+    pair: (Sender<Record>, Receiver<Record>),
+    types: TypeRegistry,
+) -> (DynamicSender, DynamicReceiver) {
+    let types = Arc::new(types);
+    (
+        DynamicSender {
+            inner: pair.0,
+            types: Arc::clone(&types),
+        },
+        DynamicReceiver {
+            inner: pair.1,
+            types: types,
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::*;
+    use super::super::{ChannelBuilder, Error};
+
+    fn types() -> TypeRegistry {
+        let mut types = TypeRegistry::new();
+        types.register::<u64>("count").unwrap();
+        types.register::<(String, u32)>("labelled").unwrap();
+        types
+    }
+
+    #[test]
+    fn tags_are_bound_to_one_type() {
+        let mut types = types();
+        assert_eq!(Ok(()), types.register::<u64>("count"));
+        assert_eq!(Err(Error::TypeMismatch), types.register::<u64>("other"));
+        assert_eq!(Err(Error::TypeMismatch), types.register::<i8>("count"));
+        assert_eq!(vec!["count", "labelled"], types.tags());
+        assert_eq!(Some("count"), types.tag_of(&7u64));
+        assert_eq!(None, types.tag_of(&7i8));
+    }
+
+    #[test]
+    fn mixed_types_share_one_channel() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("dynamic", dir.path())
+            .max_bytes(64)
+            .build_dynamic(types())
+            .unwrap();
+        let mut other = snd.clone();
+        for i in 0..1024u64 {
+            if i % 2 == 0 {
+                snd.send(Box::new(i)).unwrap();
+            } else {
+                other.send(Box::new((format!("{}", i), i as u32))).unwrap();
+            }
+        }
+        assert_eq!(Err(Error::UnregisteredType), snd.send(Box::new(1i8)));
+        for i in 0..1024u64 {
+            let message = rcv.try_recv().unwrap().unwrap();
+            if i % 2 == 0 {
+                assert_eq!(Some(&i), message.downcast_ref::<u64>());
+            } else {
+                let labelled = message.downcast::<(String, u32)>().unwrap();
+                assert_eq!((format!("{}", i), i as u32), *labelled);
+            }
+        }
+        assert!(rcv.try_recv().is_none());
+    }
+}
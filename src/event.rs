@@ -0,0 +1,119 @@
+use std::io;
+use std::time::Duration;
+use supervise::Task;
+
+/// A notable change in a channel's state, delivered to the callback given to
+/// `ChannelBuilder::on_event`
+///
+/// Events are delivered on the thread whose send or receive caused them,
+/// after hopper has released its internal lock. The callback may therefore
+/// use the channel's handles, but should be quick: it runs inline with the
+/// send or receive. `CorruptSegment` and `TaskPanicked` are the exceptions,
+/// delivered from the channel's scrubber and from the task that panicked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum QueueEvent {
+    /// The in-memory tier has filled and items are now being buffered for
+    /// disk.
+    MemoryFull,
+    /// Paging out the disk buffer would take the channel's queue files past
+    /// `ChannelBuilder::max_disk_bytes`, so it is held in memory. Once it
+    /// fills, sends are refused or wait, as the channel's `OverflowPolicy`
+    /// says, until the Receiver frees space on disk.
+    DiskFull {
+        /// Bytes held in queue files
+        disk_bytes: u64,
+    },
+    /// Queue files the Receiver had yet to reach were deleted to make room
+    /// at `ChannelBuilder::max_disk_bytes`, as `ChannelBuilder::evict_oldest`
+    /// allows.
+    Evicted {
+        /// Items lost with the files
+        records: u64,
+        /// Bytes the files held
+        bytes: u64,
+    },
+    /// Writing to the channel's queue files failed. The items are kept in
+    /// memory. After a retryable error, such as a full disk, they are paged
+    /// out again once a pause has passed. After any other error, or once
+    /// `ChannelBuilder::retry_writes` runs out, the channel holds items in
+    /// memory only from then on.
+    WriteFailed {
+        /// The kind of error
+        kind: io::ErrorKind,
+        /// Whether paging out will be tried again
+        retrying: bool,
+    },
+    /// A batch of items was paged out to disk, taking `bytes` bytes.
+    SpilledToDisk {
+        /// Bytes written to queue files by this spill
+        bytes: u64,
+    },
+    /// The Receiver started up and found `count` queue files already present
+    /// in the channel's directory.
+    RecoveredSegments {
+        /// Number of queue files found
+        count: usize,
+    },
+    /// The Receiver was dropped while items were still queued. Those items
+    /// stay on disk only as far as they had been paged out; a fresh Receiver
+    /// will not see items that were held in memory.
+    ReceiverDroppedWithBacklog {
+        /// Items sent but not yet received
+        remaining: usize,
+        /// Bytes held in queue files
+        disk_bytes: u64,
+        /// Age of the oldest queue file, `None` if nothing has been paged out
+        oldest_segment_age: Option<Duration>,
+    },
+    /// The Receiver was paused with `Receiver::pause`.
+    Paused,
+    /// The Receiver was resumed with `Receiver::resume` and is delivering
+    /// again.
+    Resumed {
+        /// Items waiting to be received
+        items: usize,
+    },
+    /// The channel's depth reached a high watermark set with
+    /// `ChannelBuilder::watermarks` or `ChannelBuilder::byte_watermarks`.
+    HighWatermark {
+        /// Items sent but not yet received
+        items: usize,
+        /// Bytes held in queue files
+        disk_bytes: u64,
+    },
+    /// The channel's depth fell back to its low watermarks after a
+    /// `HighWatermark`.
+    LowWatermark {
+        /// Items sent but not yet received
+        items: usize,
+        /// Bytes held in queue files
+        disk_bytes: u64,
+    },
+    /// The Receiver passed over a queue file record it could not decode, as
+    /// the channel's `CorruptionPolicy` allows.
+    CorruptRecord {
+        /// The record's place in the channel's send order
+        seq: u64,
+        /// Whether the record was moved to the dead-letter file
+        dead_lettered: bool,
+    },
+    /// The scrubber started with `ChannelBuilder::scrub_retained` found a
+    /// retained queue file that does not read whole.
+    CorruptSegment {
+        /// The queue file's sequence number
+        segment: usize,
+        /// Where in the file reading stopped: the start of the first record
+        /// that is not whole, or 0 if its header could not be read
+        offset: u64,
+        /// Whether the file was moved into the `corrupt` subdirectory
+        quarantined: bool,
+    },
+    /// One of the channel's background tasks panicked. See `Sender::health`.
+    TaskPanicked {
+        /// The task
+        task: Task,
+        /// Whether it will be started again, or has panicked too often
+        restarting: bool,
+    },
+}
@@ -0,0 +1,206 @@
+//! Prometheus integration, enabled with the `prometheus` feature
+//!
+//! `register` adds a collector to a user-supplied `prometheus::Registry` that
+//! reports a channel's `QueueMetrics` at scrape time. Every series carries a
+//! `channel` label holding the channel's name, so many hopper channels can
+//! share one registry. Send and receive rates are best derived in Prometheus
+//! from the `hopper_enqueued_total` and `hopper_dequeued_total` counters.
+use super::Sender;
+use metrics::Metrics;
+use prometheus::{IntCounter, IntGauge, Opts, Registry};
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use std::fmt;
+use std::sync::Arc;
+
+struct ChannelCollector {
+    metrics: Arc<Metrics>,
+    in_memory_depth: IntGauge,
+    disk_bytes: IntGauge,
+    segments_on_disk: IntGauge,
+    enqueued: IntCounter,
+    dequeued: IntCounter,
+    spills: IntCounter,
+    deserialize_failures: IntCounter,
+    expired: IntCounter,
+    records_written: IntCounter,
+    write_calls: IntCounter,
+    descs: Vec<Desc>,
+}
+
+impl fmt::Debug for ChannelCollector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ChannelCollector")
+            .field("metrics", &self.metrics)
+            .finish()
+    }
+}
+
+// Counters only ever move forward, so bring each up to the value the channel
+// reports rather than setting it outright.
+fn catch_up(counter: &IntCounter, value: u64) {
+    let current = counter.get();
+    if value > current {
+        counter.inc_by(value - current);
+    }
+}
+
+impl ChannelCollector {
+    fn new(name: &str, metrics: Arc<Metrics>) -> Result<ChannelCollector, prometheus::Error> {
+        let opts = |metric: &str, help: &str| Opts::new(metric, help).const_label("channel", name);
+        let in_memory_depth = IntGauge::with_opts(opts(
+            "hopper_in_memory_depth",
+            "Items held in memory, including those staged for disk",
+        ))?;
+        let disk_bytes =
+            IntGauge::with_opts(opts("hopper_disk_bytes", "Bytes held in queue files on disk"))?;
+        let segments_on_disk =
+            IntGauge::with_opts(opts("hopper_segments_on_disk", "Queue files on disk"))?;
+        let enqueued = IntCounter::with_opts(opts(
+            "hopper_enqueued_total",
+            "Items sent into the channel",
+        ))?;
+        let dequeued = IntCounter::with_opts(opts(
+            "hopper_dequeued_total",
+            "Items received out of the channel",
+        ))?;
+        let spills = IntCounter::with_opts(opts(
+            "hopper_spill_events_total",
+            "Batches of items paged out to disk",
+        ))?;
+        let deserialize_failures = IntCounter::with_opts(opts(
+            "hopper_deserialize_failures_total",
+            "On-disk items that could not be deserialized",
+        ))?;
+        let expired = IntCounter::with_opts(opts(
+            "hopper_expired_total",
+            "Items dropped unreceived after outliving the channel's TTL",
+        ))?;
+        let records_written = IntCounter::with_opts(opts(
+            "hopper_records_written_total",
+            "Items written to queue files",
+        ))?;
+        let write_calls = IntCounter::with_opts(opts(
+            "hopper_write_calls_total",
+            "Write calls made to queue files",
+        ))?;
+        let mut descs = Vec::new();
+        for desc in in_memory_depth
+            .desc()
+            .into_iter()
+            .chain(disk_bytes.desc())
+            .chain(segments_on_disk.desc())
+            .chain(enqueued.desc())
+            .chain(dequeued.desc())
+            .chain(spills.desc())
+            .chain(deserialize_failures.desc())
+            .chain(expired.desc())
+            .chain(records_written.desc())
+            .chain(write_calls.desc())
+        {
+            descs.push(desc.clone());
+        }
+        Ok(ChannelCollector {
+            metrics: metrics,
+            in_memory_depth: in_memory_depth,
+            disk_bytes: disk_bytes,
+            segments_on_disk: segments_on_disk,
+            enqueued: enqueued,
+            dequeued: dequeued,
+            spills: spills,
+            deserialize_failures: deserialize_failures,
+            expired: expired,
+            records_written: records_written,
+            write_calls: write_calls,
+            descs: descs,
+        })
+    }
+}
+
+impl Collector for ChannelCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let m = self.metrics.snapshot();
+        self.in_memory_depth.set(m.in_memory_depth as i64);
+        self.disk_bytes.set(m.disk_bytes as i64);
+        self.segments_on_disk.set(m.segments_on_disk as i64);
+        catch_up(&self.enqueued, m.total_enqueued);
+        catch_up(&self.dequeued, m.total_dequeued);
+        catch_up(&self.spills, m.spill_events);
+        catch_up(&self.deserialize_failures, m.deserialize_failures);
+        catch_up(&self.expired, m.total_expired);
+        catch_up(&self.records_written, m.records_written);
+        catch_up(&self.write_calls, m.write_calls);
+
+        let mut families = Vec::new();
+        families.extend(self.in_memory_depth.collect());
+        families.extend(self.disk_bytes.collect());
+        families.extend(self.segments_on_disk.collect());
+        families.extend(self.enqueued.collect());
+        families.extend(self.dequeued.collect());
+        families.extend(self.spills.collect());
+        families.extend(self.deserialize_failures.collect());
+        families.extend(self.expired.collect());
+        families.extend(self.records_written.collect());
+        families.extend(self.write_calls.collect());
+        families
+    }
+}
+
+/// Register `sender`'s channel with `registry`
+///
+/// The channel is labelled with `Sender::name`. Registering two channels of
+/// the same name into one registry is an error, as Prometheus would be unable
+/// to tell their series apart.
+pub fn register<T>(registry: &Registry, sender: &Sender<T>) -> Result<(), prometheus::Error> {
+    let collector = ChannelCollector::new(sender.name(), Arc::clone(sender.shared_metrics()))?;
+    registry.register(Box::new(collector))
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::register;
+    use super::super::channel;
+    use prometheus::Registry;
+    use prometheus::proto::MetricType;
+
+    #[test]
+    fn registered_channels_report_their_metrics() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel::<u64>("exported", dir.path()).unwrap();
+        for i in 0..3 {
+            snd.send(i);
+        }
+        assert_eq!(Some(0), rcv.try_iter().next());
+
+        let registry = Registry::new();
+        register(&registry, &snd).unwrap();
+        assert!(register(&registry, &snd).is_err());
+
+        let families = registry.gather();
+        let value = |name: &str| {
+            let family = families
+                .iter()
+                .find(|family| family.get_name() == name)
+                .expect("metric family not gathered");
+            let metric = &family.get_metric()[0];
+            let label = &metric.get_label()[0];
+            assert_eq!(("channel", "exported"), (label.get_name(), label.get_value()));
+            if family.get_field_type() == MetricType::GAUGE {
+                metric.get_gauge().get_value()
+            } else {
+                metric.get_counter().get_value()
+            }
+        };
+        assert_eq!(10, families.len());
+        assert_eq!(2.0, value("hopper_in_memory_depth"));
+        assert_eq!(3.0, value("hopper_enqueued_total"));
+        assert_eq!(1.0, value("hopper_dequeued_total"));
+        assert_eq!(0.0, value("hopper_expired_total"));
+    }
+}
@@ -0,0 +1,187 @@
+//! Latency histograms
+//!
+//! Every channel that stamps its items with their send time keeps a
+//! histogram of how long items wait in the queue, and with the `histograms`
+//! feature histograms of how long its sends, receives and spill flushes take,
+//! all reported through `QueueMetrics`. Values are recorded in
+//! microseconds into log-linear buckets in the manner of an HDR histogram:
+//! values below 16 are counted exactly and every larger value lands in a
+//! bucket no wider than a sixteenth of the values in it, so quantiles are
+//! reported to within about 6%. Values beyond about twelve days are counted
+//! as twelve days.
+//!
+//! To measure how long items wait, a channel with the feature on stamps every
+//! item with its send time, as it does for a TTL or when recording
+//! provenance, so queue file records carry an extra eight bytes.
+use std::cmp;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+// Each power of two from 16 up is split into 2^SUB_BITS buckets.
+const SUB_BITS: u32 = 4;
+const SUB_BUCKETS: usize = 1 << SUB_BITS;
+// Values are clamped below 2^MAX_BITS microseconds, about twelve days.
+const MAX_BITS: u32 = 40;
+const BUCKETS: usize = SUB_BUCKETS + (MAX_BITS - SUB_BITS) as usize * SUB_BUCKETS;
+
+fn bucket_of(value: u64) -> usize {
+    let value = cmp::min(value, (1 << MAX_BITS) - 1);
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    let exp = 63 - value.leading_zeros();
+    let sub = (value >> (exp - SUB_BITS)) as usize - SUB_BUCKETS;
+    SUB_BUCKETS + (exp - SUB_BITS) as usize * SUB_BUCKETS + sub
+}
+
+// The largest value counted in `bucket`.
+fn highest_in(bucket: usize) -> u64 {
+    if bucket < SUB_BUCKETS {
+        return bucket as u64;
+    }
+    let shift = ((bucket - SUB_BUCKETS) / SUB_BUCKETS) as u32;
+    let sub = ((bucket - SUB_BUCKETS) % SUB_BUCKETS) as u64;
+    ((SUB_BUCKETS as u64 + sub + 1) << shift) - 1
+}
+
+/// A point-in-time view of a latency histogram, in microseconds
+///
+/// Part of `QueueMetrics`. Each quantile is the largest value its bucket could hold, so is never
+/// under the true figure, and no larger than `max`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// Number of values recorded
+    pub count: u64,
+    /// Mean of the values recorded
+    pub mean: u64,
+    /// Largest value recorded
+    pub max: u64,
+    /// Median
+    pub p50: u64,
+    /// 90th percentile
+    pub p90: u64,
+    /// 99th percentile
+    pub p99: u64,
+    /// 99.9th percentile
+    pub p999: u64,
+}
+
+pub struct Histogram {
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+}
+
+impl fmt::Debug for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Histogram").field(&self.snapshot()).finish()
+    }
+}
+
+impl Histogram {
+    /// Count `micros`
+    pub fn record(&self, micros: u64) {
+        self.buckets[bucket_of(micros)].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(micros, Ordering::Relaxed);
+        self.max.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    /// Count the time since `started`
+    pub fn record_since(&self, started: Instant) {
+        let micros = started.elapsed().as_micros();
+        self.record(cmp::min(micros, u128::from(u64::MAX)) as u64);
+    }
+
+    pub fn snapshot(&self) -> LatencyHistogram {
+        let counts: Vec<u64> = self.buckets
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect();
+        let count: u64 = counts.iter().sum();
+        if count == 0 {
+            return LatencyHistogram::default();
+        }
+        let max = self.max.load(Ordering::Relaxed);
+        // The value at or below which `per_mille` thousandths of the values
+        // fall.
+        let quantile = |per_mille: u64| {
+            let rank = cmp::max(1, (count * per_mille).div_ceil(1000));
+            let mut seen = 0;
+            for (bucket, n) in counts.iter().enumerate() {
+                seen += n;
+                if seen >= rank {
+                    return cmp::min(highest_in(bucket), max);
+                }
+            }
+            max
+        };
+        LatencyHistogram {
+            count: count,
+            mean: self.sum.load(Ordering::Relaxed) / count,
+            max: max,
+            p50: quantile(500),
+            p90: quantile(900),
+            p99: quantile(990),
+            p999: quantile(999),
+        }
+    }
+}
+
+/// The histograms a channel keeps with the `histograms` feature
+#[cfg(feature = "histograms")]
+#[derive(Debug, Default)]
+pub struct Latencies {
+    pub send: Histogram,
+    pub recv: Histogram,
+    pub flush: Histogram,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn buckets_cover_their_values() {
+        let mut last = None;
+        for value in (0..100_000).chain(vec![1 << 39, (1 << 40) - 1]) {
+            let bucket = bucket_of(value);
+            assert!(value <= highest_in(bucket));
+            if bucket > 0 {
+                assert!(value > highest_in(bucket - 1));
+            }
+            if let Some(last) = last {
+                assert!(bucket >= last);
+            }
+            last = Some(bucket);
+        }
+        assert_eq!(BUCKETS - 1, bucket_of(u64::MAX));
+    }
+
+    #[test]
+    fn reports_quantiles_within_a_bucket() {
+        let histogram = Histogram::default();
+        assert_eq!(LatencyHistogram::default(), histogram.snapshot());
+        for micros in 1..1001 {
+            histogram.record(micros);
+        }
+        let snap = histogram.snapshot();
+        assert_eq!(1000, snap.count);
+        assert_eq!(500, snap.mean);
+        assert_eq!(1000, snap.max);
+        for &(got, want) in &[(snap.p50, 500), (snap.p90, 900), (snap.p99, 990)] {
+            assert!(got >= want && got <= want + want / 16, "{} for {}", got, want);
+        }
+        assert!(snap.p999 >= 999 && snap.p999 <= 1000);
+    }
+}
@@ -0,0 +1,359 @@
+//! Sparse indexes of sealed queue files
+//!
+//! Alongside the summary of a queue file it seals, a channel's Sender writes
+//! an index of the file, named for the queue file with an `.index` extension.
+//! The index has an entry for the file's first record and every
+//! `INTERVAL`th after it: the number of the file's items before the record,
+//! the newest send time of the file's items up to and including the
+//! record's, and the record's offset in the file. A file that outgrows
+//! `MAX_ENTRIES` entries keeps every other one and spaces those after twice
+//! as far apart, so an index is never longer. The entries are in file
+//! order with both counts and send times never falling, so a reader after an
+//! item or a send time finds the record to read from with a binary search
+//! rather than by reading the records before it. Each entry is a line of
+//! whitespace separated decimal fields in that order, a send time `-` where
+//! the channel does not stamp its items or the time could not be read back.
+//!
+//! An index goes wherever its queue file does, as a summary does. A file of
+//! no more than `INTERVAL` records, whose index would only point at its first
+//! record, is not given one. A file without one is read from its first
+//! record, and opening a channel writes the indexes missing from the files it
+//! keeps, see `fill_in`.
+use block;
+use layout;
+use private;
+use summary;
+use std::cmp;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The extension of the index written alongside a sealed queue file
+pub const EXTENSION: &str = "index";
+
+/// The fewest records between entries of an index
+pub const INTERVAL: u64 = 128;
+
+/// The most entries an index holds
+pub const MAX_ENTRIES: usize = 1024;
+
+/// Where a record of an indexed queue file is
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// The number of the file's items before the record
+    pub items: u64,
+    /// The newest send time of the file's items up to and including the
+    /// record's, in milliseconds since the UNIX epoch, if known
+    pub stamp: Option<u64>,
+    /// The offset of the record in the file
+    pub offset: u64,
+}
+
+/// The index of a queue file, see the module documentation
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SegmentIndex {
+    entries: Vec<IndexEntry>,
+    // The records between entries, INTERVAL until the file outgrows
+    // MAX_ENTRIES of them
+    interval: u64,
+    // The records and items counted so far, the newest send time among them
+    // and whether any could not be known
+    records: u64,
+    items: u64,
+    newest: Option<u64>,
+    blind: bool,
+}
+
+impl SegmentIndex {
+    /// The entries of the index, in file order
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    /// Whether the index points anywhere past the file's first record, and
+    /// so is worth writing
+    pub fn is_useful(&self) -> bool {
+        self.entries.len() > 1
+    }
+
+    /// Count the record at `offset`, holding `items` items sent at `stamps`,
+    /// None if their send times are not known
+    ///
+    /// Room for every entry is made with the first, so the index does not
+    /// allocate as a file fills.
+    pub fn add<I>(&mut self, offset: u64, items: u64, stamps: Option<I>)
+    where
+        I: IntoIterator<Item = u64>,
+    {
+        match stamps {
+            Some(stamps) => {
+                for stamp in stamps {
+                    self.newest = Some(self.newest.map_or(stamp, |s| cmp::max(s, stamp)));
+                }
+            }
+            None => self.blind = true,
+        }
+        if self.records == 0 {
+            self.entries.reserve_exact(MAX_ENTRIES);
+            self.interval = INTERVAL;
+        }
+        if self.entries.len() == MAX_ENTRIES && self.records.is_multiple_of(self.interval) {
+            let mut at = 0;
+            self.entries.retain(|_| {
+                at += 1;
+                at % 2 == 1
+            });
+            self.interval *= 2;
+        }
+        if self.records.is_multiple_of(self.interval) {
+            self.entries.push(IndexEntry {
+                items: self.items,
+                stamp: if self.blind { None } else { self.newest },
+                offset: offset,
+            });
+        }
+        self.records += 1;
+        self.items += items;
+    }
+
+    /// The last entry at or before the record holding the file's item
+    /// `items` places from its first, if any
+    pub fn by_items(&self, items: u64) -> Option<IndexEntry> {
+        let after = self.entries.partition_point(|entry| entry.items <= items);
+        after.checked_sub(1).map(|at| self.entries[at])
+    }
+
+    /// The last entry whose record and every record before it hold only
+    /// items sent before `stamp`, in milliseconds since the UNIX epoch, if
+    /// any
+    pub fn before_stamp(&self, stamp: u64) -> Option<IndexEntry> {
+        let after = self.entries
+            .partition_point(|entry| entry.stamp.is_some_and(|s| s < stamp));
+        after.checked_sub(1).map(|at| self.entries[at])
+    }
+
+    /// Empty the index for the next file, keeping its room
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.records = 0;
+        self.items = 0;
+        self.newest = None;
+        self.blind = false;
+    }
+
+    fn encode(&self) -> String {
+        let mut out = String::new();
+        for entry in &self.entries {
+            let stamp = entry.stamp.map_or("-".to_string(), |s| s.to_string());
+            out.push_str(&format!("{} {} {}\n", entry.items, stamp, entry.offset));
+        }
+        out
+    }
+
+    fn decode(text: &str) -> Option<SegmentIndex> {
+        let mut entries = Vec::new();
+        for line in text.lines() {
+            let mut fields = line.split_whitespace();
+            let mut next = || fields.next();
+            let items = next()?.parse().ok()?;
+            let stamp = match next()? {
+                "-" => None,
+                field => Some(field.parse().ok()?),
+            };
+            entries.push(IndexEntry {
+                items: items,
+                stamp: stamp,
+                offset: next()?.parse().ok()?,
+            });
+        }
+        Some(SegmentIndex {
+            entries: entries,
+            ..SegmentIndex::default()
+        })
+    }
+}
+
+/// The path of the index of the queue file at `segment`
+pub fn path(segment: &Path) -> PathBuf {
+    let mut name = segment
+        .file_name()
+        .map(|n| n.to_os_string())
+        .unwrap_or_default();
+    name.push(".");
+    name.push(EXTENSION);
+    segment.with_file_name(name)
+}
+
+/// Read the index of the queue file at `segment`, if it has one
+pub fn read(segment: &Path) -> Option<SegmentIndex> {
+    fs::read_to_string(path(segment))
+        .ok()
+        .and_then(|text| SegmentIndex::decode(&text))
+}
+
+/// Write `index` alongside the queue file at `segment`
+///
+/// The index is written aside and moved into place, so a reader finds
+/// either all of it or none.
+pub fn write(segment: &Path, index: &SegmentIndex) -> io::Result<()> {
+    let dest = path(segment);
+    let tmp = dest.with_extension(format!("{}.tmp", EXTENSION));
+    fs::write(&tmp, index.encode())?;
+    fs::rename(&tmp, dest)
+}
+
+/// Move the index of the queue file at `from`, if it has one, to go with the
+/// file at `to`
+pub fn rename(from: &Path, to: &Path) {
+    let _ = fs::rename(path(from), path(to));
+}
+
+/// Remove the index of the queue file at `segment`, if it has one
+pub fn remove(segment: &Path) {
+    let _ = fs::remove_file(path(segment));
+}
+
+/// Index the queue file at `segment` by reading it
+///
+/// `stamped` says whether each record leads with its item's send time in the
+/// clear, and `packed` whether the records are blocks; the send times packed
+/// in a compressed block are not read, nor any after them. Returns None if
+/// the file cannot be read.
+pub fn build(segment: &Path, stamped: bool, packed: bool) -> Option<SegmentIndex> {
+    let buf = fs::read(segment).ok()?;
+    let start = layout::records_start(&buf)?;
+    let mut index = SegmentIndex::default();
+    let mut rest = &buf[start..];
+    while let Some((record, tail)) = private::next_record(rest) {
+        let offset = (buf.len() - rest.len()) as u64;
+        let stamps = if !stamped {
+            None
+        } else if packed {
+            block::plain(record).and_then(|records| records.into_iter().map(stamp_of).collect())
+        } else {
+            stamp_of(record).map(|stamp| vec![stamp])
+        };
+        let items = if packed { block::count(record) } else { 1 };
+        index.add(offset, items, stamps);
+        rest = tail;
+    }
+    Some(index)
+}
+
+/// Write an index for each queue file in `dir` without one, bar the one
+/// with the id `open`, still being written to
+///
+/// `stamped` and `packed` are as for `build`. A file its summary shows too
+/// short to index is not read, and a file that cannot be read or an index
+/// that cannot be written is left out.
+pub fn fill_in(dir: &Path, open: Option<usize>, stamped: bool, packed: bool) {
+    if !dir.is_dir() {
+        return;
+    }
+    for id in private::segment_ids(dir, None) {
+        let segment = dir.join(format!("{}", id));
+        let short = summary::read(&segment).is_some_and(|s| s.records <= INTERVAL);
+        if Some(id) == open || short || path(&segment).exists() {
+            continue;
+        }
+        if let Some(index) = build(&segment, stamped, packed).filter(SegmentIndex::is_useful) {
+            let _ = write(&segment, &index);
+        }
+    }
+}
+
+// The send time a record payload leads with
+fn stamp_of(payload: &[u8]) -> Option<u64> {
+    let mut stamp = [0; 8];
+    stamp.copy_from_slice(payload.get(..8)?);
+    Some(u64::from_be_bytes(stamp))
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::*;
+
+    #[test]
+    fn indexes_find_records_and_round_trip() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let segment = dir.path().join("3");
+        assert_eq!(dir.path().join("3.index"), path(&segment));
+        assert_eq!(None, read(&segment));
+
+        let mut index = SegmentIndex::default();
+        for record in 0..(INTERVAL * 3) {
+            // Two items a record, the second sent before the first
+            let stamp = 1000 + record * 10;
+            index.add(record * 20, 2, Some(vec![stamp, stamp - 15]));
+        }
+        let offsets: Vec<u64> = index.entries().iter().map(|e| e.offset).collect();
+        assert_eq!(vec![0, INTERVAL * 20, INTERVAL * 40], offsets);
+        assert_eq!(Some(1000), index.entries()[0].stamp);
+
+        assert_eq!(0, index.by_items(0).unwrap().offset);
+        assert_eq!(0, index.by_items(INTERVAL * 2 - 1).unwrap().offset);
+        assert_eq!(INTERVAL * 20, index.by_items(INTERVAL * 2).unwrap().offset);
+        assert_eq!(INTERVAL * 40, index.by_items(u64::MAX).unwrap().offset);
+        assert_eq!(None, index.before_stamp(1000));
+        assert_eq!(0, index.before_stamp(1001).unwrap().offset);
+        let second = 1000 + INTERVAL * 10;
+        assert_eq!(0, index.before_stamp(second).unwrap().offset);
+        assert_eq!(INTERVAL * 20, index.before_stamp(second + 1).unwrap().offset);
+
+        write(&segment, &index).unwrap();
+        assert_eq!(index.entries(), read(&segment).unwrap().entries());
+        remove(&segment);
+        assert_eq!(None, read(&segment));
+    }
+
+    #[test]
+    fn indexes_built_by_reading_match_those_written() {
+        use super::super::ChannelBuilder;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, _rcv) = ChannelBuilder::new("packed", dir.path())
+            .max_bytes(32 * 1024)
+            .pack_records(64)
+            .stamp_send_times(true)
+            .build()
+            .unwrap();
+        for i in 0..16_384u64 {
+            snd.send(i);
+        }
+        let segment = dir.path().join("packed").join("0");
+        let written = read(&segment).unwrap();
+        assert!(written.entries().len() > 1);
+        assert!(written.entries()[1].stamp.is_some());
+        assert_eq!(written.entries(), build(&segment, true, true).unwrap().entries());
+    }
+
+    #[test]
+    fn long_files_keep_every_other_entry() {
+        let mut index = SegmentIndex::default();
+        let records = INTERVAL * MAX_ENTRIES as u64 * 3;
+        for record in 0..records {
+            index.add(record * 12, 1, None::<Vec<u64>>);
+        }
+        assert_eq!(MAX_ENTRIES * 3 / 4, index.entries().len());
+        let items: Vec<u64> = index.entries().iter().map(|e| e.items).collect();
+        let expected: Vec<u64> = (0..records).step_by(INTERVAL as usize * 4).collect();
+        assert_eq!(expected, items);
+        let last = *expected.last().unwrap();
+        assert_eq!(last, index.by_items(records - 1).unwrap().items);
+    }
+
+    #[test]
+    fn send_times_stop_where_they_cannot_be_known() {
+        let mut index = SegmentIndex::default();
+        index.add(0, 1, Some(vec![10]));
+        for record in 1..(INTERVAL * 2) {
+            let stamps = if record == 5 { None } else { Some(vec![10 + record]) };
+            index.add(record * 12, 1, stamps);
+        }
+        assert_eq!(Some(10), index.entries()[0].stamp);
+        assert_eq!(None, index.entries()[1].stamp);
+        assert_eq!(0, index.before_stamp(u64::MAX).unwrap().offset);
+    }
+}
@@ -0,0 +1,299 @@
+//! Offline inspection of channel directories
+//!
+//! Used by the `hopper-inspect` binary, built with the `cli` feature, to
+//! report on a channel's directory without opening the channel: its queue
+//! files, how many records each holds, the Receiver's committed position if
+//! the directory is used by a `process` channel, and the records themselves.
+//! A sealed queue file with a summary is reported from its summary, without
+//! reading its records.
+//!
+//! Records are reported as the raw bytes stored on disk. Channels built with
+//! a TTL, or with `ChannelBuilder::stamp_send_times`, prefix each record
+//! with an eight byte send time, and process channels with a four byte schema
+//! version, which are included. Channels that record provenance follow the
+//! send time with the four byte id of the record's Sender; `split_meta`
+//! separates the two from the item. Channels that sequence their Senders
+//! follow the id with the record's eight byte place among its Sender's
+//! records, which `split_meta` leaves at the head of the item.
+use layout;
+use private;
+use process;
+use summary::{self, SegmentSummary};
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// A queue file in a channel directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentInfo {
+    /// The queue file's sequence number, which is also its name
+    pub id: usize,
+    /// The size of the queue file in bytes
+    pub bytes: u64,
+    /// The number of complete records in the queue file
+    pub records: u64,
+    /// The position in the directory of the queue file's first record,
+    /// counting from the first record of the oldest queue file
+    pub first_record: u64,
+    /// Whether the Sender has finished with the queue file
+    pub sealed: bool,
+    /// The number of bytes after the last complete record, left by a write
+    /// that was cut short or still in progress
+    pub trailing_bytes: u64,
+    /// The summary the Sender wrote as it sealed the queue file, if it did
+    pub summary: Option<SegmentSummary>,
+}
+
+/// The state of a channel directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelInfo {
+    /// The contents of the channel's metadata file, if it has one
+    pub metadata: Option<String>,
+    /// The queue file and byte offset last committed by a `ProcessReceiver`
+    pub committed: Option<(usize, u64)>,
+    /// The queue files, oldest first
+    pub segments: Vec<SegmentInfo>,
+}
+
+/// Split the contents of a queue file into its records
+///
+/// Returns the records along with the number of bytes left over after the
+/// last complete one. The queue file's header, if it has one, is skipped.
+pub fn records(buf: &[u8]) -> (Vec<&[u8]>, u64) {
+    let mut records = Vec::new();
+    let mut rest = &buf[layout::records_start(buf).unwrap_or(0)..];
+    while let Some((record, tail)) = private::next_record(rest) {
+        records.push(record);
+        rest = tail;
+    }
+    (records, rest.len() as u64)
+}
+
+/// Read the records of queue file `id` in channel directory `root`
+pub fn read_segment(root: &Path, id: usize) -> io::Result<Vec<Vec<u8>>> {
+    let buf = fs::read(root.join(format!("{}", id)))?;
+    Ok(records(&buf).0.into_iter().map(|r| r.to_vec()).collect())
+}
+
+/// Report on the channel directory `root`
+pub fn inspect(root: &Path) -> io::Result<ChannelInfo> {
+    if !root.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "not a channel directory",
+        ));
+    }
+    let mut ids = private::segment_ids(root, None);
+    ids.sort();
+    let mut segments = Vec::with_capacity(ids.len());
+    let mut first_record = 0;
+    for id in ids {
+        let path = root.join(format!("{}", id));
+        let sealed = process::is_sealed(&path);
+        let summary = if sealed { summary::read(&path) } else { None };
+        let (bytes, count, trailing_bytes) = match summary {
+            Some(ref summary) => {
+                let mut fp = fs::File::open(&path)?;
+                let bytes = fp.metadata()?.len();
+                let start = layout::read_format(&mut fp)?.0.map_or(0, |(_, start)| start);
+                let trailing = bytes.saturating_sub(start as u64 + summary.stored_bytes);
+                (bytes, summary.records, trailing)
+            }
+            None => {
+                let buf = fs::read(&path)?;
+                let (recs, trailing_bytes) = records(&buf);
+                (buf.len() as u64, recs.len() as u64, trailing_bytes)
+            }
+        };
+        segments.push(SegmentInfo {
+            id: id,
+            bytes: bytes,
+            records: count,
+            first_record: first_record,
+            sealed: sealed,
+            trailing_bytes: trailing_bytes,
+            summary: summary,
+        });
+        first_record += count;
+    }
+    Ok(ChannelInfo {
+        metadata: fs::read_to_string(root.join(layout::METADATA_FILE)).ok(),
+        committed: process::read_control(root),
+        segments: segments,
+    })
+}
+
+/// The number of bytes a channel built with
+/// `ChannelBuilder::record_provenance` leads each record with
+pub const META_LEN: usize = 12;
+
+/// Split a record of a channel that records provenance into its send time, in
+/// milliseconds since the UNIX epoch, its Sender's id and the item
+///
+/// Returns `None` if the record is too short to carry them.
+pub fn split_meta(record: &[u8]) -> Option<(u64, u32, &[u8])> {
+    if record.len() < META_LEN {
+        return None;
+    }
+    let mut stamp = [0; 8];
+    stamp.copy_from_slice(&record[..8]);
+    let mut sender = [0; 4];
+    sender.copy_from_slice(&record[8..META_LEN]);
+    Some((u64::from_be_bytes(stamp), u32::from_be_bytes(sender), &record[META_LEN..]))
+}
+
+/// How `render` displays a record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// Space separated hex bytes
+    Hex,
+    /// The bytes as UTF-8, with invalid sequences replaced
+    Text,
+    /// The record as a bincode-encoded `String`, falling back to hex if it is
+    /// not one
+    BincodeString,
+}
+
+impl Codec {
+    /// Look up a codec by its command line name: `hex`, `text` or `string`
+    pub fn from_name(name: &str) -> Option<Codec> {
+        match name {
+            "hex" => Some(Codec::Hex),
+            "text" => Some(Codec::Text),
+            "string" => Some(Codec::BincodeString),
+            _ => None,
+        }
+    }
+}
+
+/// Render a record for display
+pub fn render(record: &[u8], codec: Codec) -> String {
+    match codec {
+        Codec::Hex => {
+            let mut out = String::with_capacity(record.len() * 3);
+            for (i, byte) in record.iter().enumerate() {
+                if i > 0 {
+                    out.push(' ');
+                }
+                let _ = write!(out, "{:02x}", byte);
+            }
+            out
+        }
+        Codec::Text => String::from_utf8_lossy(record).into_owned(),
+        Codec::BincodeString => match ::bincode::deserialize::<String>(record) {
+            Ok(s) => format!("{:?}", s),
+            Err(_) => render(record, Codec::Hex),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::*;
+    use super::super::{channel_with_max_bytes, ChannelBuilder};
+    use process;
+
+    #[test]
+    fn splits_records_and_reports_trailing_bytes() {
+        let mut buf = Vec::new();
+        for payload in &[&b"ab"[..], &b""[..], &b"xyz"[..]] {
+            buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            buf.extend_from_slice(payload);
+        }
+        buf.extend_from_slice(&10u32.to_be_bytes());
+        buf.extend_from_slice(b"torn");
+        let (recs, trailing) = records(&buf);
+        assert_eq!(recs, vec![&b"ab"[..], &b""[..], &b"xyz"[..]]);
+        assert_eq!(trailing, 8);
+    }
+
+    #[test]
+    fn reports_segments_of_a_channel() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, _rcv) =
+            channel_with_max_bytes::<u64>("inspect", dir.path(), 64).unwrap();
+        for i in 0..2048 {
+            snd.send(i);
+        }
+        snd.flush().unwrap();
+        let info = inspect(&dir.path().join("inspect")).unwrap();
+        assert!(info.metadata.unwrap().contains("u64"));
+        assert_eq!(info.committed, None);
+        assert!(info.segments.len() > 1);
+        let total: u64 = info.segments.iter().map(|s| s.records).sum();
+        assert_eq!(total, 1024);
+        let mut next = 0;
+        for seg in &info.segments {
+            assert_eq!(seg.first_record, next);
+            assert_eq!(seg.trailing_bytes, 0);
+            next += seg.records;
+        }
+        let (last, sealed) = info.segments.split_last().unwrap();
+        assert!(sealed.iter().all(|s| s.sealed));
+        assert_eq!(None, last.summary);
+        for seg in sealed {
+            let summary = seg.summary.expect("sealed file has no summary");
+            assert_eq!(summary.records, seg.records);
+            assert_eq!(summary.items, seg.records);
+            assert_eq!(summary.first_seq, seg.first_record);
+            assert_eq!(summary.last_seq, seg.first_record + seg.records - 1);
+        }
+    }
+
+    #[test]
+    fn reports_committed_offset() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let mut snd = process::sender::<String>("inspect", dir.path(), 1024).unwrap();
+        snd.send("hello".to_string());
+        {
+            let mut rcv = process::receiver::<String>("inspect", dir.path()).unwrap();
+            assert_eq!(rcv.try_recv().unwrap(), Some("hello".to_string()));
+            rcv.commit();
+        }
+        let root = dir.path().join("inspect");
+        let info = inspect(&root).unwrap();
+        let committed = info.committed.unwrap();
+        assert_eq!(committed.0, info.segments[0].id);
+        assert_eq!(committed.1, info.segments[0].bytes);
+        let recs = read_segment(&root, info.segments[0].id).unwrap();
+        // Process channel records lead with the Sender's schema version.
+        assert_eq!(&recs[0][..4], &[0, 0, 0, 0]);
+        assert_eq!(render(&recs[0][4..], Codec::BincodeString), "\"hello\"");
+    }
+
+    #[test]
+    fn splits_provenance_from_records() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, _rcv) = ChannelBuilder::new("inspect", dir.path())
+            .record_provenance(true)
+            .build::<String>()
+            .unwrap();
+        let mut other = snd.clone();
+        for _ in 0..1024 {
+            snd.send("first".to_string());
+        }
+        for _ in 0..1024 {
+            other.send("second".to_string());
+        }
+        other.flush().unwrap();
+        let recs = read_segment(&dir.path().join("inspect"), 0).unwrap();
+        assert_eq!(recs.len(), 1024);
+        let (stamp, sender, item) = split_meta(&recs[0]).unwrap();
+        assert!(stamp <= ::clock::millis(&::SystemClock));
+        assert_eq!(sender, other.id());
+        assert_eq!(render(item, Codec::BincodeString), "\"second\"");
+        assert_eq!(split_meta(&[0; 11]), None);
+    }
+
+    #[test]
+    fn renders_records() {
+        assert_eq!(render(&[0x00, 0xff, 0x10], Codec::Hex), "00 ff 10");
+        assert_eq!(render(b"hi", Codec::Text), "hi");
+        assert_eq!(render(&[0x01], Codec::BincodeString), "01");
+        assert_eq!(Codec::from_name("string"), Some(Codec::BincodeString));
+        assert_eq!(Codec::from_name("yaml"), None);
+    }
+}
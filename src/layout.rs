@@ -0,0 +1,446 @@
+//! The on-disk identity of a channel's directory
+//!
+//! Every channel keeps its queue files in its own subdirectory of `data_dir`,
+//! named for the channel. Alongside the queue files sits a small metadata file
+//! recording the item type the channel was created with and the version of the
+//! queue file format. Opening a channel checks the metadata first so that two
+//! channels of different types configured with the same name, or a newer
+//! hopper's directory, are refused rather than read as garbage.
+//!
+//! Each queue file starts with a header of its own: a magic number and the
+//! format version it was written in. Version 1 queue files, from before the
+//! header, start directly with their first record. Version 3 is version 2
+//! with the sender's schema version leading every record's payload. Process
+//! channels, whose records outlive the deploy that wrote them, write version
+//! 3; regular channels, which discard their queue files when reopened, go on
+//! writing version 2 and save the four bytes a record. Records are framed the
+//! same way in every version, so a directory claimed from an older hopper has
+//! its metadata moved on to the current version and its old queue files read
+//! where they are. A writer never appends to a queue file of another layout
+//! than its own but starts the next one instead.
+//!
+//! What a record leads with ahead of its item--a send time, a Sender's id, a
+//! place among that Sender's items--depends on how the channel was built, as
+//! does whether its records are packed into checksummed blocks, so the
+//! metadata records that too, see `RecordFraming`. A channel built again with
+//! other fields is refused: its retained queue files would otherwise have one
+//! field read as another.
+//!
+//! An open channel also holds an exclusive lock on a lock file in its
+//! directory, so a second process opening the same directory is refused
+//! rather than left to interleave its writes with the first.
+use super::Error;
+use std::fs;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process;
+
+/// The name of the metadata file in each channel directory
+///
+/// Queue files are named for their sequence number, so a non-numeric name can
+/// never collide with one.
+pub const METADATA_FILE: &str = "hopper.meta";
+
+/// The name of the lock file in each channel directory
+pub const LOCK_FILE: &str = "hopper.lock";
+
+/// The name of the lock file `process::backup` holds to pin a channel's queue
+/// files while it copies them
+pub const PIN_FILE: &str = "hopper.pin";
+
+/// The name of the file written and removed again to check that a channel
+/// directory takes writes, see `Health::disk_writable`
+pub const PROBE_FILE: &str = "hopper.probe";
+
+/// The name of the snapshot a Receiver closed with `Receiver::close_after`
+/// leaves its backlog in, restored by the channel's next build
+pub const DRAINED_FILE: &str = "hopper.drained";
+
+/// The name of the file items sent with `Sender::send_after` wait in until
+/// they come due
+pub const DELAYED_FILE: &str = "hopper.delayed";
+
+/// The name of the file `Sender::relieve_memory_pressure` pages the
+/// in-memory tier out to
+pub const PAGED_FILE: &str = "hopper.paged";
+
+/// The newest version of the queue file format, written by process channels
+pub const FORMAT_VERSION: u32 = 3;
+
+/// The newest version of the queue file format whose records carry no schema
+/// version, written by regular channels
+pub const PLAIN_FORMAT_VERSION: u32 = 2;
+
+/// The oldest version of the queue file format this hopper reads
+pub const OLDEST_FORMAT_VERSION: u32 = 1;
+
+/// The bytes each queue file starts with from format version 2, followed by
+/// the version as a big-endian u32
+///
+/// Read as the length of a version 1 record these bytes would claim over a
+/// gigabyte, more than any queue file holds, so the two are never confused.
+pub const SEGMENT_MAGIC: &[u8; 4] = b"HOPQ";
+
+/// The length of a queue file's header
+pub const SEGMENT_HEADER_LEN: usize = 8;
+
+/// What each record of a channel's queue files leads with ahead of its item,
+/// and whether the records are packed into blocks
+///
+/// The fields come in this order, each present as the channel was built.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RecordFraming {
+    /// The send time, in milliseconds since the UNIX epoch, eight bytes
+    pub stamped: bool,
+    /// The id of the Sender that sent the item, four bytes
+    pub traced: bool,
+    /// The item's place among that Sender's items, eight bytes
+    pub sequenced: bool,
+    /// Whether each queue file record is a checksummed block of records, see
+    /// `block`
+    pub packed: bool,
+}
+
+impl RecordFraming {
+    // The metadata lines naming the fields and marking packed records, or
+    // nothing if there are neither, as for directories from before the lines
+    // existed
+    fn render(self) -> String {
+        let fields: Vec<&str> = [
+            (self.stamped, "stamp"),
+            (self.traced, "origin"),
+            (self.sequenced, "seq"),
+        ].iter()
+            .filter(|&&(present, _)| present)
+            .map(|&(_, name)| name)
+            .collect();
+        let mut lines = String::new();
+        if !fields.is_empty() {
+            lines.push_str(&format!("record_meta {}\n", fields.join(" ")));
+        }
+        if self.packed {
+            lines.push_str("packed_records\n");
+        }
+        lines
+    }
+
+    /// The framing recorded in the metadata of the channel directory `root`
+    ///
+    /// Returns `None` if the directory has no metadata.
+    pub fn read(root: &Path) -> Option<RecordFraming> {
+        let meta = fs::read_to_string(root.join(METADATA_FILE)).ok()?;
+        let mut framing = RecordFraming::default();
+        for line in meta.lines() {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("record_meta") => for field in words {
+                    match field {
+                        "stamp" => framing.stamped = true,
+                        "origin" => framing.traced = true,
+                        "seq" => framing.sequenced = true,
+                        _ => {}
+                    }
+                },
+                Some("packed_records") => framing.packed = true,
+                _ => {}
+            }
+        }
+        Some(framing)
+    }
+}
+
+fn render(version: u32, type_name: &str, framing: RecordFraming) -> String {
+    format!("format_version {}\ntype {}\n{}", version, type_name, framing.render())
+}
+
+/// The header a new queue file of format `version` starts with
+pub fn segment_header(version: u32) -> [u8; SEGMENT_HEADER_LEN] {
+    let mut header = [0; SEGMENT_HEADER_LEN];
+    header[..4].copy_from_slice(SEGMENT_MAGIC);
+    header[4..].copy_from_slice(&version.to_be_bytes());
+    header
+}
+
+/// Whether the records of a queue file of format `version` lead with a
+/// schema version
+pub fn is_tagged(version: u32) -> bool {
+    version >= 3
+}
+
+/// The format version of the queue file whose contents start with `buf`, and
+/// where its first record begins
+///
+/// A version 1 queue file's records begin straight away; a later one's follow
+/// its header. Returns None if `buf` is too short to tell, as when the header
+/// is still being written, or if the queue file is of a version this hopper
+/// does not read.
+pub fn segment_format(buf: &[u8]) -> Option<(u32, usize)> {
+    let magic = &SEGMENT_MAGIC[..];
+    if buf.len() < SEGMENT_HEADER_LEN {
+        if magic.starts_with(&buf[..buf.len().min(magic.len())]) {
+            return None;
+        }
+        return if buf.len() < magic.len() { None } else { Some((1, 0)) };
+    }
+    if &buf[..4] != magic {
+        return Some((1, 0));
+    }
+    let version = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+    if version > OLDEST_FORMAT_VERSION && version <= FORMAT_VERSION {
+        Some((version, SEGMENT_HEADER_LEN))
+    } else {
+        None
+    }
+}
+
+/// Where the first record of the queue file whose contents start with `buf`
+/// begins, as `segment_format`
+pub fn records_start(buf: &[u8]) -> Option<usize> {
+    segment_format(buf).map(|(_, start)| start)
+}
+
+/// Read the header of the queue file `fp` is at the start of, returning what
+/// `segment_format` makes of it
+///
+/// `fp` is left wherever reading the header took it.
+pub fn read_format<R: Read>(fp: &mut R) -> io::Result<(Option<(u32, usize)>, usize)> {
+    let mut header = [0; SEGMENT_HEADER_LEN];
+    let mut filled = 0;
+    while filled < header.len() {
+        match fp.read(&mut header[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((segment_format(&header[..filled]), filled))
+}
+
+/// The format version of the queue file at `path`, if it has enough in it to
+/// tell
+pub fn file_format(path: &Path) -> Option<u32> {
+    let mut fp = fs::File::open(path).ok()?;
+    read_format(&mut fp).ok()?.0.map(|(version, _)| version)
+}
+
+/// Move `fp`, at the start of a queue file, past the file's header if it has
+/// one
+///
+/// The header must be complete: call this only for files the Sender has
+/// moved on from.
+pub fn skip_header<R: Read + Seek>(fp: &mut R) -> io::Result<()> {
+    let start = read_format(fp)?.0.map_or(0, |(_, start)| start);
+    fp.seek(SeekFrom::Start(start as u64)).map(|_| ())
+}
+
+/// Claim `root` for a channel of items of type `type_name` whose records are
+/// framed as `framing`
+///
+/// Writes the metadata file if `root` does not yet have one. Directories from
+/// before metadata files existed are adopted as they are, and directories of
+/// an older format version this hopper reads are moved on to the current
+/// one. Returns `Error::MetadataMismatch` if the directory belongs to a
+/// channel of another type, another record framing or a format version this
+/// hopper does not read, and `Error::Io` if the metadata file cannot be read
+/// or written.
+pub fn claim(root: &Path, type_name: &str, framing: RecordFraming) -> Result<(), Error> {
+    let path = root.join(METADATA_FILE);
+    let expected = render(FORMAT_VERSION, type_name, framing);
+    match fs::read_to_string(&path) {
+        Ok(found) => {
+            if found == expected {
+                Ok(())
+            } else if (OLDEST_FORMAT_VERSION..FORMAT_VERSION)
+                .any(|version| found == render(version, type_name, framing))
+            {
+                fs::write(&path, expected.as_bytes())?;
+                Ok(())
+            } else {
+                Err(Error::MetadataMismatch)
+            }
+        }
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {
+            let mut fp = fs::File::create(&path)?;
+            fp.write_all(expected.as_bytes())?;
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Take the exclusive lock on `root`
+///
+/// The lock is held for as long as the returned file is open and released
+/// when it is dropped, or by the OS if the process dies. Returns
+/// `Error::AlreadyLocked` if another open channel holds it. Filesystems that
+/// do not support locking are used unlocked.
+pub fn lock(root: &Path) -> Result<Option<fs::File>, Error> {
+    lock_file(root, LOCK_FILE)
+}
+
+/// Take the exclusive lock on the lock file `name` in `root`, as `lock`
+pub fn lock_file(root: &Path, name: &str) -> Result<Option<fs::File>, Error> {
+    let fp = open_lock_file(root, name);
+    match fp.try_lock() {
+        Ok(()) => Ok(Some(fp)),
+        Err(fs::TryLockError::WouldBlock) => Err(Error::AlreadyLocked),
+        Err(fs::TryLockError::Error(_)) => Ok(None),
+    }
+}
+
+/// Take a shared lock on the lock file `name` in `root`, returning
+/// `Error::AlreadyLocked` only while it is held exclusively
+pub fn share_lock_file(root: &Path, name: &str) -> Result<Option<fs::File>, Error> {
+    let fp = open_lock_file(root, name);
+    match fp.try_lock_shared() {
+        Ok(()) => Ok(Some(fp)),
+        Err(fs::TryLockError::WouldBlock) => Err(Error::AlreadyLocked),
+        Err(fs::TryLockError::Error(_)) => Ok(None),
+    }
+}
+
+/// Ask the holder of `root`'s lock to hand it over, by writing the id of
+/// this process into the lock file
+///
+/// The lock itself is advisory, so the file can be written while another
+/// process holds it. The request stands until `clear_handover`.
+pub fn request_handover(root: &Path) -> io::Result<()> {
+    let mut fp = open_lock_file(root, LOCK_FILE);
+    fp.set_len(0)?;
+    write!(fp, "{}", process::id())
+}
+
+/// Whether a process has asked for `root`'s lock with `request_handover`
+pub fn handover_requested(root: &Path) -> bool {
+    fs::metadata(root.join(LOCK_FILE)).is_ok_and(|md| md.len() > 0)
+}
+
+/// Withdraw, or mark as met, a request for `root`'s lock
+pub fn clear_handover(root: &Path) -> io::Result<()> {
+    open_lock_file(root, LOCK_FILE).set_len(0)
+}
+
+fn open_lock_file(root: &Path, name: &str) -> fs::File {
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(root.join(name))
+        .expect("could not open lock file")
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::{claim, records_start, segment_format, segment_header, RecordFraming,
+                METADATA_FILE, PLAIN_FORMAT_VERSION, SEGMENT_HEADER_LEN};
+    use super::super::{channel, channel_with_max_bytes, Error};
+    use std::fs;
+
+    #[test]
+    fn reopening_with_another_type_is_refused() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        {
+            let (mut snd, _rcv) = channel::<u32>("typed", dir.path()).unwrap();
+            snd.send(1);
+        }
+        assert!(channel::<u32>("typed", dir.path()).is_ok());
+        assert_eq!(
+            Err(Error::MetadataMismatch),
+            channel::<String>("typed", dir.path()).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn open_channel_locks_its_directory() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (snd, rcv) = channel::<u32>("locked", dir.path()).unwrap();
+        assert_eq!(
+            Err(Error::AlreadyLocked),
+            channel_with_max_bytes::<u32>("locked", dir.path(), 1024).map(|_| ())
+        );
+
+        // The lock is released only once every handle has gone.
+        drop(rcv);
+        assert_eq!(
+            Err(Error::AlreadyLocked),
+            channel::<u32>("locked", dir.path()).map(|_| ())
+        );
+        drop(snd);
+        assert!(channel::<u32>("locked", dir.path()).is_ok());
+    }
+
+    #[test]
+    fn other_format_versions_are_refused() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        fs::write(dir.path().join(METADATA_FILE), "format_version 0\ntype u32\n").unwrap();
+        assert_eq!(Err(Error::MetadataMismatch), claim(dir.path(), "u32", RecordFraming::default()));
+    }
+
+    #[test]
+    fn unreadable_metadata_is_an_io_error() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        fs::create_dir(dir.path().join(METADATA_FILE)).unwrap();
+        match claim(dir.path(), "u32", RecordFraming::default()) {
+            Err(Error::Io(_)) => {}
+            other => panic!("expected an io error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn directories_without_metadata_are_adopted() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        fs::write(dir.path().join("0"), b"").unwrap();
+        assert_eq!(Ok(()), claim(dir.path(), "u32", RecordFraming::default()));
+        assert_eq!(Ok(()), claim(dir.path(), "u32", RecordFraming::default()));
+        assert!(dir.path().join(METADATA_FILE).is_file());
+    }
+
+    #[test]
+    fn older_format_versions_are_moved_on() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let path = dir.path().join(METADATA_FILE);
+        fs::write(&path, "format_version 1\ntype u32\n").unwrap();
+        assert_eq!(Ok(()), claim(dir.path(), "u32", RecordFraming::default()));
+        assert_eq!("format_version 3\ntype u32\n", fs::read_to_string(&path).unwrap());
+        fs::write(&path, "format_version 1\ntype u32\n").unwrap();
+        assert_eq!(Err(Error::MetadataMismatch), claim(dir.path(), "u64", RecordFraming::default()));
+    }
+
+    #[test]
+    fn framing_is_read_back_from_the_metadata() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        assert_eq!(None, RecordFraming::read(dir.path()));
+        let framing = RecordFraming {
+            stamped: true,
+            traced: false,
+            sequenced: true,
+            packed: true,
+        };
+        assert_eq!(Ok(()), claim(dir.path(), "u32", framing));
+        assert_eq!(
+            "format_version 3\ntype u32\nrecord_meta stamp seq\npacked_records\n",
+            fs::read_to_string(dir.path().join(METADATA_FILE)).unwrap()
+        );
+        assert_eq!(Some(framing), RecordFraming::read(dir.path()));
+        let unpacked = RecordFraming { packed: false, ..framing };
+        assert_eq!(Err(Error::MetadataMismatch), claim(dir.path(), "u32", unpacked));
+    }
+
+    #[test]
+    fn records_start_after_any_header() {
+        let header = segment_header(PLAIN_FORMAT_VERSION);
+        assert_eq!(Some(SEGMENT_HEADER_LEN), records_start(&header));
+        assert_eq!(Some((3, SEGMENT_HEADER_LEN)), segment_format(&segment_header(3)));
+        assert_eq!(None, records_start(&header[..5]));
+        assert_eq!(None, records_start(b""));
+        // A version 1 file starts with the length of its first record.
+        assert_eq!(Some(0), records_start(&[0, 0, 0, 8, 1, 0, 0, 0, 0, 0, 0, 0]));
+        assert_eq!(Some(0), records_start(&[0, 0, 0, 0]));
+        let mut newer = segment_header(3);
+        newer[7] += 1;
+        assert_eq!(None, records_start(&newer));
+    }
+}
@@ -21,7 +21,9 @@
 //! are retrieved. Above index 1024 items are paged out to disk. Items stored
 //! between index 1024 and 2048 are temporarily buffered in memory to allow a
 //! single page to disk once this buffer is full. This scheme fixes the memory
-//! burden of the system at the expense of disk IO.
+//! burden of the system at the expense of disk IO. Where item sizes vary
+//! widely, `ChannelBuilder::memory_budget` draws both boundaries by encoded
+//! size in bytes instead of by count.
 //!
 //! Hopper is intended to be used in situtations where your system cannot
 //! load-shed inputs and _must_ eventually process them. Hopper does page to
@@ -43,12 +45,30 @@
 //! ```text
 //! data-dir/
 //!    sink-name0/
+//!       hopper.lock
+//!       hopper.meta
 //!       0
 //!       1
 //!    sink-name1/
+//!       hopper.lock
+//!       hopper.meta
 //!       0
 //! ```
 //!
+//! `hopper.meta` records the item type the channel was created with and the
+//! queue file format version. A channel refuses to open a directory whose
+//! metadata does not match, returning `Error::MetadataMismatch`, so two
+//! channels accidentally given the same name cannot interleave their files.
+//! The type is recorded by `std::any::type_name`, which is not guaranteed
+//! stable across compiler versions. So are the fields each record leads with
+//! ahead of its item, such as the send time a channel built with
+//! `ChannelBuilder::ttl` stamps: a channel built again with other fields is
+//! refused too.
+//!
+//! `hopper.lock` is held exclusively by an open channel. Opening a directory
+//! another channel holds open, from this process or another, returns
+//! `Error::AlreadyLocked`.
+//!
 //! You'll notice exports of Sender and Receiver in this module's
 //! namespace. These are the structures that back the send and receive side of
 //! the named channel. The Senders--there may be multiples of them--are
@@ -79,22 +99,196 @@
 //! hopper limits itself to one exclusive Sender or one exclusive Receiver at a
 //! time. This potentially limits the concurrency of mpsc but maintains data
 //! integrity. We are open to improvements in this area.
+//!
+//! ## What ordering does hopper guarantee?
+//!
+//! By default--`OrderMode::Global`--a channel is strictly FIFO. Every `send`
+//! takes the channel's single lock and is assigned its place in the queue
+//! there, whichever Sender clone it came through. Items are handed to the
+//! Receiver in that order whether they were held in memory, staged for disk
+//! or paged out to a queue file: the Receiver only reads from disk once every
+//! earlier in-memory item has been delivered and only reads the disk staging
+//! buffer once every earlier queue file entry has been delivered. A Sender
+//! kept from the lock for more than a millisecond has the others queue
+//! behind it, so a Sender sending in a tight loop cannot starve the rest.
+//!
+//! `OrderMode::PerSender`, set through `ChannelBuilder::order`, relaxes this
+//! to per-Sender FIFO. Each Sender batches items locally and publishes them a
+//! batch at a time, so items from one Sender stay in order relative to each
+//! other but may be delivered out of order relative to items sent through
+//! other Senders, even ones sent earlier. Call `Sender::flush` to publish a
+//! partial batch; dropping a Sender publishes whatever it has staged.
+//!
+//! Where the interleaving of Senders has to come out the same on every
+//! replay, build the channel with `ChannelBuilder::sequence_senders`. Each
+//! item then carries its Sender's id and its place among that Sender's items,
+//! and `Receiver::recv_ordered_by_sender` merges the Senders' items by them
+//! rather than by the order they reached the channel.
+//!
+//! ## Does hopper run under WASI?
+//!
+//! Yes, on `wasm32-wasi` and its successors, with the disk tier on the
+//! filesystem WASI gives the module. A WASI module reaches only directories
+//! its host preopened for it: give hopper a `data_dir` inside one and std
+//! resolves it against that directory's handle, so a channel can touch
+//! nothing the host did not grant. There are no file locks, so the channel's
+//! directory is used unlocked, as on any filesystem without them.
+//!
+//! Without threads--every WASI target but the `-threads` ones--there is
+//! nothing to run hopper's background work on. Building a channel that needs
+//! it, one with retention, scrubbing or read-ahead, fails with
+//! `Error::Unsupported`, as do `pressure::adapt` and `pressure::watch`, and
+//! `Sender::spawn_flusher` starts no flusher. WASI has no temporary
+//! directory either, so an `ephemeral` channel fails to build the same way.
+//! A `Runtime` cannot be made at all. The `bridge` module, which needs Unix
+//! domain sockets, is not built.
 extern crate serde;
 extern crate bincode;
+#[cfg(feature = "prometheus")]
+extern crate prometheus;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "encryption")]
+extern crate aes_gcm;
+#[cfg(feature = "dynamic")]
+#[macro_use]
+extern crate erased_serde;
+#[cfg(feature = "parking_lot")]
+extern crate parking_lot;
+#[cfg(feature = "compression")]
+extern crate lz4_flex;
+#[cfg(feature = "derive")]
+extern crate hopper_derive;
+#[cfg(feature = "dictionary")]
+extern crate zstd;
+#[cfg(feature = "log")]
+extern crate log;
+#[cfg(feature = "slog")]
+extern crate slog;
+#[cfg(all(unix, any(feature = "preallocate", feature = "watch")))]
+extern crate rustix;
+#[cfg(all(windows, feature = "watch"))]
+extern crate windows_sys;
+#[cfg(all(unix, feature = "mio"))]
+extern crate mio;
+
+// Emit a `tracing` event at debug level when the `tracing` feature is enabled.
+// Without the feature the arguments are never evaluated.
+#[cfg(feature = "tracing")]
+macro_rules! trace_event {
+    ($($arg:tt)*) => { ::tracing::debug!($($arg)*) };
+}
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace_event {
+    ($($arg:tt)*) => {};
+}
 
+mod admission;
+mod archive;
+mod block;
+#[cfg(all(feature = "bridge", unix))]
+pub mod bridge;
+mod broadcast;
+mod budget;
+mod builder;
+mod clock;
+pub mod config;
+#[cfg(feature = "cgroup")]
+pub mod cgroup;
+#[cfg(feature = "encryption")]
+mod crypt;
+pub mod dead_letter;
+mod delay;
+mod dictionary;
+mod dispatch;
+#[cfg(feature = "dynamic")]
+pub mod dynamic;
+mod event;
+pub mod fixed;
+mod histogram;
+#[cfg(feature = "prometheus")]
+pub mod exporter;
+mod index;
+#[cfg(feature = "cli")]
+pub mod inspect;
+mod layout;
+pub mod local;
+#[cfg(any(feature = "log", feature = "slog"))]
+pub mod logging;
+mod merge;
+mod metrics;
+mod paged;
+pub mod mux;
+mod prefetch;
+mod priority;
+mod rate;
+pub mod raw;
 mod receiver;
+mod registry;
+mod retention;
+pub use self::retention::PurgeReport;
+mod retry;
+mod runtime;
+mod scrub;
+mod seek;
 mod sender;
+mod serve;
+mod shard;
+#[cfg(feature = "sim")]
+pub mod sim;
 mod private;
+pub mod pressure;
+pub mod process;
+pub mod repair;
+mod reserve;
+pub mod segment;
+mod select;
+pub mod snapshot;
+mod storage;
+pub mod summary;
+mod supervise;
+mod sync;
+pub mod testing;
+mod topology;
+pub mod verify;
+mod watch;
+mod watermark;
+mod work;
 
-pub use self::receiver::Receiver;
-pub use self::sender::Sender;
+pub use self::broadcast::{BroadcastSender, Subscriber, SubscriberIter};
+pub use self::budget::Budget;
+pub use self::builder::{ChannelBuilder, ConfigDelta, CorruptionPolicy, OrderMode,
+                        OverflowPolicy};
+pub use self::clock::{Clock, SystemClock};
+pub use self::config::QueueConfig;
+pub use self::dispatch::{DispatchFailure, DispatchReport};
+pub use self::event::QueueEvent;
+pub use self::histogram::LatencyHistogram;
+pub use self::merge::{Merge, MergeOrder};
+pub use self::metrics::QueueMetrics;
+pub use self::priority::Priority;
+pub use self::rate::RatePolicy;
+pub use self::receiver::{Filtered, Mapped, MappedIter, Peeked, Receiver, RecordMeta,
+                         RecvRef, Recycled};
+pub use self::registry::Registry;
+pub use self::reserve::GroupMetrics;
+pub use self::runtime::Runtime;
+pub use self::seek::SeekTarget;
+pub use self::select::Select;
+pub use self::topology::{topology, ChannelDescription, Topology};
+pub use self::verify::{IntegrityReport, VerifyLevel};
+pub use self::sender::{Sender, WeakSender};
+pub use self::serve::{ConsumeError, Consumer, ServeOptions};
+pub use self::shard::{ShardedIter, ShardedReceiver, ShardedSender};
+pub use self::supervise::{Health, Task, TaskHealth};
+pub use self::work::{Lease, Worker};
 
 use serde::Serialize;
 use serde::de::DeserializeOwned;
-use std::fs;
+use std::error;
+use std::fmt;
+use std::io;
 use std::path::Path;
-use std::sync;
-use std::mem;
 
 /// Defines the errors that hopper will bubble up
 ///
@@ -104,10 +298,215 @@ use std::mem;
 /// detriment of your program--where we might be able to recover but assume that
 /// if an unkonwn condition _is_ hit it's a result of something foreign tainting
 /// hopper's directory.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Error {
     /// The directory given for use does not exist
     NoSuchDirectory,
+    /// A `Registry` channel or `Mux` stream was requested with a different
+    /// item type than the one it was created with, or a `TypeRegistry` tag
+    /// was bound to a second type
+    TypeMismatch,
+    /// The Receiver of a `Registry` channel or a `Mux` stream has already
+    /// been handed out
+    ReceiverTaken,
+    /// The channel's directory was created for a different item type or by a
+    /// hopper with a different queue file format
+    MetadataMismatch,
+    /// The host does not report memory pressure, or the probe given to
+    /// `pressure::adapt` cannot tell how much memory is in use
+    PressureUnavailable,
+    /// The channel's directory is already open, in this process or another
+    AlreadyLocked,
+    /// The snapshot given to `ChannelBuilder::restore_from` could not be
+    /// read, is damaged or holds items of a different type or format version
+    InvalidSnapshot,
+    /// A dynamic channel was sent an item of a type, or read an item with a
+    /// tag, that is not in its `TypeRegistry`
+    UnregisteredType,
+    /// The checkpoint given to `process::ProcessReceiver::resume_from` lies
+    /// in a queue file that has since been removed
+    StaleCheckpoint,
+    /// The channel needs a thread or a temporary directory and the platform
+    /// has neither, as under WASI. See the crate documentation, "Does hopper
+    /// run under WASI?"
+    Unsupported,
+    /// The channel's directory could not be read or written, for the reason
+    /// given
+    Io(io::ErrorKind),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e.kind())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match *self {
+            Error::NoSuchDirectory => "no such directory",
+            Error::TypeMismatch => "channel was created with a different item type",
+            Error::ReceiverTaken => "channel's receiver has already been taken",
+            Error::MetadataMismatch => {
+                "channel directory belongs to a different item type or format version"
+            }
+            Error::PressureUnavailable => "host does not report memory pressure",
+            Error::AlreadyLocked => "channel directory is already open",
+            Error::InvalidSnapshot => "snapshot is unreadable, damaged or of another type",
+            Error::UnregisteredType => "item type is not registered with the channel",
+            Error::StaleCheckpoint => "checkpoint's queue file has been removed",
+            Error::Unsupported => "channel needs threads or a temporary directory",
+            Error::Io(kind) => {
+                return write!(f, "channel directory could not be read or written: {:?}", kind)
+            }
+        };
+        f.write_str(msg)
+    }
+}
+
+impl error::Error for Error {}
+
+/// Why a Sender refused an item, which it hands back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SendError<T> {
+    /// The send was over the channel's rate limit and its `RatePolicy` is
+    /// `RatePolicy::Fail`
+    RateLimited(T),
+    /// The channel is `memory_only`, has no room for the item and its
+    /// `OverflowPolicy` is `OverflowPolicy::Fail`
+    Full(T),
+    /// Paging the item out would take the channel's queue files past its
+    /// `ChannelBuilder::max_disk_bytes` and its `OverflowPolicy` is
+    /// `OverflowPolicy::Fail`
+    DiskFull(T),
+    /// Writing the channel's queue files failed with an error retrying would
+    /// not mend, so it holds items in memory only, has no room for the item
+    /// and its `OverflowPolicy` is `OverflowPolicy::Fail`. See
+    /// `ChannelBuilder::retry_writes`.
+    WriteFailed(T),
+    /// The Receiver has closed the channel, see `Receiver::close`
+    Closed(T),
+}
+
+impl<T> SendError<T> {
+    /// Take back the item that was refused
+    pub fn into_inner(self) -> T {
+        match self {
+            SendError::RateLimited(item)
+            | SendError::Full(item)
+            | SendError::DiskFull(item)
+            | SendError::WriteFailed(item)
+            | SendError::Closed(item) => item,
+        }
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SendError::RateLimited(_) => f.write_str("send is over the channel's rate limit"),
+            SendError::Full(_) => f.write_str("memory-only channel is full"),
+            SendError::DiskFull(_) => f.write_str("channel is at its disk quota"),
+            SendError::WriteFailed(_) => f.write_str("channel can no longer write its queue files"),
+            SendError::Closed(_) => f.write_str("channel is closed"),
+        }
+    }
+}
+
+impl<T: fmt::Debug> error::Error for SendError<T> {}
+
+/// Why a Sender refused an item sent with a deadline, which it hands back
+///
+/// Returned by `Sender::send_deadline` and `Sender::send_timeout`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SendTimeoutError<T> {
+    /// The channel was full, its `OverflowPolicy` is `OverflowPolicy::Block`
+    /// and it had no room for the item by the deadline
+    Timeout(T),
+    /// The channel refused the item without waiting for room
+    Refused(SendError<T>),
+}
+
+impl<T> SendTimeoutError<T> {
+    /// Take back the item that was refused
+    pub fn into_inner(self) -> T {
+        match self {
+            SendTimeoutError::Timeout(item) => item,
+            SendTimeoutError::Refused(err) => err.into_inner(),
+        }
+    }
+}
+
+impl<T> fmt::Display for SendTimeoutError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SendTimeoutError::Timeout(_) => f.write_str("channel had no room by the deadline"),
+            SendTimeoutError::Refused(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl<T: fmt::Debug> error::Error for SendTimeoutError<T> {}
+
+/// Why a Receiver could not hand back the next item
+///
+/// Returned by `Receiver::try_recv`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum RecvError {
+    /// A queue file record could not be decrypted or decoded
+    Decode(DecodeError),
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RecvError::Decode(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for RecvError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            RecvError::Decode(ref e) => Some(e),
+        }
+    }
+}
+
+/// Where a queue file record that could not be decoded lies, and why it
+/// failed
+#[derive(Debug)]
+pub struct DecodeError {
+    /// The name of the channel
+    pub channel: String,
+    /// The id of the queue file holding the record, its file name in the
+    /// channel's directory
+    pub segment: usize,
+    /// The offset of the record's length prefix in the queue file
+    pub offset: u64,
+    /// The record's place in the channel's send order
+    pub seq: u64,
+    /// The codec's error, or why the record could not be decrypted
+    pub cause: Box<dyn error::Error + Send + Sync>,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "could not decode record {} of channel {} at offset {} of queue file {}: {}",
+            self.seq, self.channel, self.offset, self.segment, self.cause
+        )
+    }
+}
+
+impl error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&*self.cause)
+    }
 }
 
 /// Create a (Sender, Reciever) pair in a like fashion to
@@ -125,7 +524,7 @@ pub enum Error {
 /// let (mut snd, mut rcv) = hopper::channel("example", dir.path()).unwrap();
 ///
 /// snd.send(9);
-/// assert_eq!(Some(9), rcv.iter().next());
+/// assert_eq!(Some(9), rcv.try_iter().next());
 /// ```
 pub fn channel<T>(name: &str, data_dir: &Path) -> Result<(Sender<T>, Receiver<T>), Error>
 where
@@ -134,6 +533,35 @@ where
     channel_with_max_bytes(name, data_dir, 1_048_576 * 100)
 }
 
+/// Create a broadcast channel with `subscribers` Subscribers
+///
+/// Every item sent through the returned `BroadcastSender` is delivered to each
+/// of the Subscribers, which share one log of the items stored under
+/// `data_dir/name`.
+///
+/// # Example
+/// ```
+/// extern crate tempdir;
+/// extern crate hopper;
+///
+/// let dir = tempdir::TempDir::new("hopper").unwrap();
+/// let (mut snd, mut rcvs) = hopper::broadcast("example", dir.path(), 2).unwrap();
+///
+/// snd.send(9).unwrap();
+/// assert_eq!(Some(9), rcvs[0].try_iter().next());
+/// assert_eq!(Some(9), rcvs[1].try_iter().next());
+/// ```
+pub fn broadcast<T>(
+    name: &str,
+    data_dir: &Path,
+    subscribers: usize,
+) -> Result<(BroadcastSender<T>, Vec<Subscriber<T>>), Error>
+where
+    T: Serialize + DeserializeOwned + Clone,
+{
+    ChannelBuilder::new(name, data_dir).build_broadcast(subscribers)
+}
+
 /// Create a (Sender, Reciever) pair in a like fashion to
 /// [`std::sync::mpsc::channel`](https://doc.rust-lang.org/std/sync/mpsc/fn.channel.html)
 ///
@@ -151,21 +579,9 @@ pub fn channel_with_max_bytes<T>(
 where
     T: Serialize + DeserializeOwned,
 {
-    use std::sync::Arc; 
-
-    let root = data_dir.join(name);
-    let snd_root = root.clone();
-    let rcv_root = root.clone();
-    if !root.is_dir() {
-        fs::create_dir_all(root).expect("could not create directory");
-    }
-    let cap: usize = 1024;
-    let sz = mem::size_of::<T>();
-    let max_bytes = if max_bytes < sz { sz } else { max_bytes };
-    let fs_lock = sync::Arc::new(sync::Mutex::new(private::FsSync::new(cap)));
-    let sender = try!(Sender::new(name, &snd_root, max_bytes, Arc::clone(&fs_lock)));
-    let receiver = try!(Receiver::new(&rcv_root, fs_lock));
-    Ok((sender, receiver))
+    ChannelBuilder::new(name, data_dir)
+        .max_bytes(max_bytes)
+        .build()
 }
 
 #[cfg(test)]
@@ -173,18 +589,21 @@ mod test {
     extern crate quickcheck;
     extern crate tempdir;
 
+    use layout;
     use std::thread;
-    use super::{channel, channel_with_max_bytes};
+    use super::{channel, channel_with_max_bytes, dead_letter, Budget, ChannelBuilder,
+                CorruptionPolicy, OrderMode, Priority, QueueEvent, RecordMeta, Runtime};
     use self::quickcheck::{QuickCheck, TestResult};
 
     #[test]
     fn one_item_round_trip() {
         let dir = tempdir::TempDir::new("hopper").unwrap();
         let (mut snd, mut rcv) = channel("one_item_round_trip", dir.path()).unwrap();
+        assert_eq!("one_item_round_trip", rcv.name());
 
         snd.send(1);
 
-        assert_eq!(Some(1), rcv.iter().next());
+        assert_eq!(Some(1), rcv.try_iter().next());
     }
 
     #[test]
@@ -192,10 +611,10 @@ mod test {
         let dir = tempdir::TempDir::new("hopper").unwrap();
         let (mut snd, mut rcv) = channel("zero_item_round_trip", dir.path()).unwrap();
 
-        assert_eq!(None, rcv.iter().next());
+        assert_eq!(None, rcv.try_iter().next());
 
         snd.send(1);
-        assert_eq!(Some(1), rcv.iter().next());
+        assert_eq!(Some(1), rcv.try_iter().next());
     }
 
     #[test]
@@ -203,14 +622,14 @@ mod test {
         let dir = tempdir::TempDir::new("hopper").unwrap();
         let (mut snd, mut rcv) = channel("zero_item_round_trip", dir.path()).unwrap();
 
-        assert_eq!(None, rcv.iter().next());
+        assert_eq!(None, rcv.try_iter().next());
 
         let cap = 1022;
         for _ in 0..cap {
             snd.send(1);
         }
         for _ in 0..cap {
-            assert_eq!(Some(1), rcv.iter().next());
+            assert_eq!(Some(1), rcv.try_iter().next());
         }
     }
 
@@ -219,14 +638,14 @@ mod test {
         let dir = tempdir::TempDir::new("hopper").unwrap();
         let (mut snd, mut rcv) = channel("zero_item_round_trip", dir.path()).unwrap();
 
-        assert_eq!(None, rcv.iter().next());
+        assert_eq!(None, rcv.try_iter().next());
 
         let cap = 1024;
         for _ in 0..cap {
             snd.send(1);
         }
         for _ in 0..cap {
-            assert_eq!(Some(1), rcv.iter().next());
+            assert_eq!(Some(1), rcv.try_iter().next());
         }
     }
 
@@ -235,14 +654,14 @@ mod test {
         let dir = tempdir::TempDir::new("hopper").unwrap();
         let (mut snd, mut rcv) = channel("zero_item_round_trip", dir.path()).unwrap();
 
-        assert_eq!(None, rcv.iter().next());
+        assert_eq!(None, rcv.try_iter().next());
 
         let cap = 2048;
         for _ in 0..cap {
             snd.send(1);
         }
         for _ in 0..cap {
-            assert_eq!(Some(1), rcv.iter().next());
+            assert_eq!(Some(1), rcv.try_iter().next());
         }
     }
 
@@ -251,166 +670,3155 @@ mod test {
         let dir = tempdir::TempDir::new("hopper").unwrap();
         let (mut snd, mut rcv) = channel("zero_item_round_trip", dir.path()).unwrap();
 
-        assert_eq!(None, rcv.iter().next());
+        assert_eq!(None, rcv.try_iter().next());
 
         let cap = 4048;
         for _ in 0..cap {
             snd.send(1);
         }
         for _ in 0..cap {
-            assert_eq!(Some(1), rcv.iter().next());
+            assert_eq!(Some(1), rcv.try_iter().next());
         }
     }
 
     #[test]
-    fn round_trip() {
-        fn rnd_trip(max_bytes: usize, evs: Vec<Vec<u32>>) -> TestResult {
-            let dir = tempdir::TempDir::new("hopper").unwrap();
-            let (mut snd, mut rcv) =
-                channel_with_max_bytes("round_trip_order_preserved", dir.path(), max_bytes)
-                    .unwrap();
+    fn waker_fires_on_empty_to_non_empty() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::task::{Wake, Waker};
 
-            for ev in evs.clone() {
-                snd.send(ev);
+        struct Counter(AtomicUsize);
+        impl Wake for Counter {
+            fn wake(self: Arc<Self>) {
+                self.0.fetch_add(1, Ordering::SeqCst);
             }
+        }
 
-            for ev in evs {
-                assert_eq!(Some(ev), rcv.iter().next());
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel("waker_fires", dir.path()).unwrap();
+        let counter = Arc::new(Counter(AtomicUsize::new(0)));
+
+        rcv.register_waker(Waker::from(Arc::clone(&counter)));
+        assert_eq!(0, counter.0.load(Ordering::SeqCst));
+        snd.send(1);
+        assert_eq!(1, counter.0.load(Ordering::SeqCst));
+        // wakers are one-shot
+        snd.send(2);
+        assert_eq!(1, counter.0.load(Ordering::SeqCst));
+        // registering against a non-empty queue wakes immediately
+        rcv.register_waker(Waker::from(Arc::clone(&counter)));
+        assert_eq!(2, counter.0.load(Ordering::SeqCst));
+
+        assert_eq!(Some(1), rcv.try_iter().next());
+        assert_eq!(Some(2), rcv.try_iter().next());
+        rcv.register_waker(Waker::from(Arc::clone(&counter)));
+        assert_eq!(2, counter.0.load(Ordering::SeqCst));
+        snd.send(3);
+        assert_eq!(3, counter.0.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn coalesced_wakes_wait_for_the_count_or_the_interval() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::task::{Wake, Waker};
+        use std::time::{Duration, Instant};
+        use super::Select;
+
+        struct Counter(AtomicUsize);
+        impl Wake for Counter {
+            fn wake(self: Arc<Self>) {
+                self.0.fetch_add(1, Ordering::SeqCst);
             }
-            TestResult::passed()
         }
-        QuickCheck::new()
-            .tests(100)
-            .max_tests(1000)
-            .quickcheck(rnd_trip as fn(usize, Vec<Vec<u32>>) -> TestResult);
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let interval = Duration::from_millis(200);
+        let (mut snd, mut rcv) = ChannelBuilder::new("coalesced_wakes", dir.path())
+            .coalesce_wakes(4, interval)
+            .build()
+            .unwrap();
+        let counter = Arc::new(Counter(AtomicUsize::new(0)));
+
+        // Draining, the wake waits for the fourth item
+        snd.send(0);
+        assert_eq!(Some(0), rcv.try_iter().next());
+        rcv.register_waker(Waker::from(Arc::clone(&counter)));
+        for i in 1..4 {
+            snd.send(i);
+        }
+        assert_eq!(0, counter.0.load(Ordering::SeqCst));
+        snd.send(4);
+        assert_eq!(1, counter.0.load(Ordering::SeqCst));
+        assert_eq!(vec![1, 2, 3, 4], rcv.drain());
+
+        // A wait sees a lone item within the interval all the same
+        let mut sel = Select::new();
+        let idx = sel.add(&rcv);
+        let waiter = thread::spawn(move || {
+            let started = Instant::now();
+            (sel.ready_timeout(Duration::from_secs(10)), started.elapsed())
+        });
+        thread::sleep(Duration::from_millis(20));
+        snd.send(5);
+        let (ready, waited) = waiter.join().unwrap();
+        assert_eq!(Some(idx), ready);
+        assert!(waited < Duration::from_secs(5));
+        assert_eq!(Some(5), rcv.try_iter().next());
+
+        // Idle for longer than the interval, the Receiver is woken at once
+        thread::sleep(interval + Duration::from_millis(50));
+        rcv.register_waker(Waker::from(Arc::clone(&counter)));
+        snd.send(6);
+        assert_eq!(2, counter.0.load(Ordering::SeqCst));
     }
 
     #[test]
-    fn round_trip_small_max_bytes() {
-        fn rnd_trip(evs: Vec<Vec<u32>>) -> TestResult {
-            println!("START {:?}", evs);
-            let max_bytes: usize = 128;
-            let dir = tempdir::TempDir::new("hopper").unwrap();
-            let (mut snd, mut rcv) =
-                channel_with_max_bytes("small_max_bytes", dir.path(), max_bytes).unwrap();
+    fn fixed_segment_size_is_reported() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, _rcv) =
+            channel_with_max_bytes("fixed_segment_size", dir.path(), 128).unwrap();
 
-            for ev in evs.clone() {
-                snd.send(ev);
-            }
+        for i in 0..4096 {
+            snd.send(i);
+        }
+        assert_eq!(128, snd.segment_max_bytes());
+    }
 
-            let mut total = evs.len();
-            for ev in evs {
-                println!("REMAINING: {}", total);
-                assert_eq!(Some(ev), rcv.iter().next());
-                total -= 1;
-            }
-            TestResult::passed()
+    #[test]
+    fn adaptive_segment_size_grows_when_fast() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("adaptive_segment_size", dir.path())
+            .max_bytes(128)
+            .adaptive_max_bytes(128, 4096)
+            .build()
+            .unwrap();
+
+        for i in 0..8192 {
+            snd.send(i);
+        }
+        assert_eq!(4096, snd.segment_max_bytes());
+        for i in 0..8192 {
+            assert_eq!(Some(i), rcv.try_iter().next());
         }
-        QuickCheck::new()
-            .tests(100)
-            .max_tests(1000)
-            .quickcheck(rnd_trip as fn(Vec<Vec<u32>>) -> TestResult);
     }
 
     #[test]
-    fn concurrent_snd_and_rcv_round_trip() {
-        let max_bytes: usize = 512;
+    fn next_segment_size_stays_in_bounds() {
+        use std::time::Duration;
+        use super::private::next_segment_size;
+
+        let bounds = (64, 1024);
+        assert_eq!(1024, next_segment_size(1024, bounds, Duration::from_millis(1)));
+        assert_eq!(512, next_segment_size(256, bounds, Duration::from_millis(1)));
+        assert_eq!(256, next_segment_size(256, bounds, Duration::from_secs(10)));
+        assert_eq!(128, next_segment_size(256, bounds, Duration::from_secs(60)));
+        assert_eq!(64, next_segment_size(64, bounds, Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn metrics_track_depth_and_spill() {
         let dir = tempdir::TempDir::new("hopper").unwrap();
-        println!("CONCURRENT SND_RECV TESTDIR: {:?}", dir);
-        let (snd, mut rcv) = channel_with_max_bytes(
-            "concurrent_snd_and_rcv_small_max_bytes",
-            dir.path(),
-            max_bytes,
-        ).unwrap();
-        let max_thrs = 32;
-        let max_sz = 1000;
+        let (mut snd, mut rcv) = channel_with_max_bytes("metrics", dir.path(), 1024).unwrap();
 
-        // construct the payload to send repeatedly 'pyld' and the final payload
-        // the sender should receive
-        let mut tst_pylds = Vec::new();
-        for i in 0..(max_sz * max_thrs) {
-            tst_pylds.push(i);
+        let m = snd.metrics();
+        assert_eq!(0, m.in_memory_depth);
+        assert_eq!(layout::SEGMENT_HEADER_LEN as u64, m.disk_bytes);
+        assert_eq!(1, m.segments_on_disk);
+        assert_eq!(1024, m.segment_max_bytes);
+
+        for i in 0..10 {
+            snd.send(i);
         }
+        let m = rcv.metrics();
+        assert_eq!(10, m.in_memory_depth);
+        assert_eq!(10, m.total_enqueued);
+        assert_eq!(0, m.spill_events);
 
-        let mut joins = Vec::new();
+        for i in 10..4096 {
+            snd.send(i);
+        }
+        let m = snd.metrics();
+        assert_eq!(4096, m.total_enqueued);
+        assert_eq!(3, m.spill_events);
+        assert_eq!(1024, m.in_memory_depth);
+        assert!(m.disk_bytes > 0);
+        assert!(m.segments_on_disk > 1);
 
-        // start our receiver thread
-        joins.push(thread::spawn(move || {
-            // assert that we receive every element in tst_pylds by pulling a new
-            // value from the rcv, then marking it out of tst_pylds
-            for _ in 0..(max_sz * max_thrs) {
-                loop {
-                    if let Some(nxt) = rcv.iter().next() {
-                        let idx = tst_pylds.binary_search(&nxt).expect("DID NOT FIND ELEMENT");
-                        tst_pylds.remove(idx);
-                        break;
-                    }
-                }
-            }
-            assert!(tst_pylds.is_empty());
-        }));
+        for i in 0..4096 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        let m = rcv.metrics();
+        assert_eq!(4096, m.total_dequeued);
+        assert_eq!(0, m.in_memory_depth);
+        assert_eq!(0, m.deserialize_failures);
+        assert_eq!(1, m.segments_on_disk);
+    }
 
-        // start all our sender threads and blast away
-        for i in 0..max_thrs {
-            let mut thr_snd = snd.clone();
-            joins.push(thread::spawn(move || {
-                let base = i * max_sz;
-                for p in 0..max_sz {
-                    thr_snd.send(base + p);
-                }
-            }));
+    #[test]
+    fn handles_format_their_state_without_item_debug() {
+        // Compiles only if neither handle asks for `T: Debug`
+        fn debug<T>(snd: &super::Sender<T>, rcv: &super::Receiver<T>) -> (String, String) {
+            (format!("{:?}", snd), format!("{:?}", rcv))
         }
 
-        // wait until the senders are for sure done
-        for jh in joins {
-            jh.join().expect("Uh oh, child thread paniced!");
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel_with_max_bytes("formatted", dir.path(), 1024).unwrap();
+        for i in 0..2000u64 {
+            snd.send(i);
         }
+        assert_eq!(Some(0), rcv.try_iter().next());
+
+        let (debug, rcv_debug) = debug(&snd, &rcv);
+        assert!(debug.starts_with("Sender { name: \"formatted\""));
+        assert!(debug.contains("in_memory_capacity: 1024"));
+        assert!(debug.contains("channel: FsSync {"));
+        let debug = rcv_debug;
+        assert!(debug.contains("offset: Some("));
+        assert!(debug.contains("writes_to_read: 1999"));
+
+        let m = rcv.metrics();
+        let line = format!(
+            "formatted: {} in memory, {} files of {} bytes on disk, 2000 sent, 1 received, 0 dropped",
+            m.in_memory_depth, m.segments_on_disk, m.disk_bytes
+        );
+        assert_eq!(line, format!("{}", rcv));
+        assert!(format!("{}", snd).starts_with("formatted (sender 0): "));
+        assert!(!format!("{}", snd).contains('\n'));
     }
 
     #[test]
-    fn qc_concurrent_snd_and_rcv_round_trip() {
-        fn snd_rcv(evs: Vec<Vec<u32>>) -> TestResult {
-            let max_bytes: usize = 512;
-            let dir = tempdir::TempDir::new("hopper").unwrap();
-            println!("CONCURRENT SND_RECV TESTDIR: {:?}", dir);
-            let (snd, mut rcv) = channel_with_max_bytes(
-                "concurrent_snd_and_rcv_small_max_bytes",
-                dir.path(),
-                max_bytes,
-            ).unwrap();
+    fn spills_batch_records_into_few_writes() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, _rcv) = channel("batched_writes", dir.path()).unwrap();
 
-            let max_thrs = 32;
+        for i in 0..2048u64 {
+            snd.send(i);
+        }
+        let m = snd.metrics();
+        assert_eq!(1, m.spill_events);
+        assert_eq!(1024, m.records_written);
+        assert!(m.write_calls > 0);
+        assert!(m.records_written / m.write_calls >= 100);
+        assert_eq!(
+            layout::SEGMENT_HEADER_LEN as u64 + 1024 * (4 + 8),
+            m.disk_bytes
+        );
+    }
 
-            let mut joins = Vec::new();
+    #[test]
+    fn flusher_pages_out_in_background() {
+        use std::thread;
+        use std::time::{Duration, Instant};
 
-            // start our receiver thread
-            let total_pylds = evs.len() * max_thrs;
-            joins.push(thread::spawn(move || for _ in 0..total_pylds {
-                loop {
-                    if let Some(_) = rcv.iter().next() {
-                        break;
-                    }
-                }
-            }));
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel("flusher", dir.path()).unwrap();
+        snd.spawn_flusher(Duration::from_millis(10), 100);
+        // The channel keeps the one flusher
+        snd.clone().spawn_flusher(Duration::from_millis(1), 1);
+        assert_eq!(1, snd.supervisor().health().tasks.len());
 
-            // start all our sender threads and blast away
-            for _ in 0..max_thrs {
-                let mut thr_snd = snd.clone();
-                let thr_evs = evs.clone();
-                joins.push(thread::spawn(move || for e in thr_evs {
-                    thr_snd.send(e);
-                }));
+        for i in 0..1200u64 {
+            snd.send(i);
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let expected = layout::SEGMENT_HEADER_LEN as u64 + 176 * (4 + 8);
+        while snd.metrics().disk_bytes < expected && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(expected, snd.metrics().disk_bytes);
+        for i in 0..1200u64 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        assert_eq!(None, rcv.try_iter().next());
+    }
+
+    #[test]
+    fn channels_share_a_runtime() {
+        use std::time::{Duration, Instant};
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let runtime = Runtime::new(2);
+        let mut channels = Vec::new();
+        for c in 0..20 {
+            let (snd, rcv) = ChannelBuilder::new(format!("runtime_{}", c), dir.path())
+                .max_bytes(4096)
+                .read_ahead(1024)
+                .runtime(&runtime)
+                .build()
+                .unwrap();
+            snd.spawn_flusher(Duration::from_millis(10), 100);
+            channels.push((snd, rcv));
+        }
+        // A read-ahead and a flusher a channel, on two threads.
+        assert_eq!(40, runtime.jobs());
+        for &mut (ref mut snd, _) in &mut channels {
+            for i in 0..1200u64 {
+                snd.send(i);
+            }
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while channels.iter().any(|c| c.0.metrics().records_written < 176)
+            && Instant::now() < deadline
+        {
+            thread::sleep(Duration::from_millis(5));
+        }
+        for &mut (ref snd, ref mut rcv) in &mut channels {
+            assert_eq!(176, snd.metrics().records_written);
+            for i in 0..1200u64 {
+                assert_eq!(Some(i), rcv.try_iter().next());
             }
+        }
 
-            // wait until the senders are for sure done
-            for jh in joins {
-                jh.join().expect("Uh oh, child thread paniced!");
+        drop(channels);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while runtime.jobs() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(0, runtime.jobs());
+    }
+
+    #[test]
+    fn global_order_is_fifo_across_senders_and_tiers() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd_a, mut rcv) =
+            channel_with_max_bytes("global_order", dir.path(), 256).unwrap();
+        let mut snd_b = snd_a.clone();
+
+        for i in 0..4096 {
+            if i % 3 == 0 {
+                snd_b.send(i);
+            } else {
+                snd_a.send(i);
             }
-            TestResult::passed()
         }
-        QuickCheck::new()
-            .tests(100)
-            .max_tests(1000)
-            .quickcheck(snd_rcv as fn(Vec<Vec<u32>>) -> TestResult);
+        for i in 0..4096 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        assert_eq!(None, rcv.try_iter().next());
+    }
+
+    #[test]
+    fn per_sender_order_is_fifo_per_sender() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (snd, mut rcv) = ChannelBuilder::new("per_sender_order", dir.path())
+            .max_bytes(256)
+            .order(OrderMode::PerSender)
+            .build()
+            .unwrap();
+        let max_thrs = 8;
+        let max_sz = 1000;
+
+        let mut joins = Vec::new();
+        for thr in 0..max_thrs {
+            let mut thr_snd = snd.clone();
+            joins.push(thread::spawn(move || for i in 0..max_sz {
+                thr_snd.send((thr, i));
+            }));
+        }
+        drop(snd);
+        for jh in joins {
+            jh.join().expect("Uh oh, child thread paniced!");
+        }
+
+        let mut nxt = vec![0; max_thrs];
+        for _ in 0..(max_thrs * max_sz) {
+            let (thr, i) = rcv.try_iter().next().expect("missing item");
+            assert_eq!(nxt[thr], i);
+            nxt[thr] += 1;
+        }
+        assert_eq!(None, rcv.try_iter().next());
+    }
+
+    #[test]
+    fn staged_writes_page_out_every_senders_items_in_order() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (snd, mut rcv) = ChannelBuilder::new("staged_writes", dir.path())
+            .max_bytes(4096)
+            .memory_budget(1024)
+            .order(OrderMode::PerSender)
+            .stage_writes(true)
+            .build()
+            .unwrap();
+        let max_thrs = 4;
+        let max_sz = 2000;
+
+        let mut joins = Vec::new();
+        for thr in 0..max_thrs {
+            let mut thr_snd = snd.clone();
+            joins.push(thread::spawn(move || for i in 0..max_sz {
+                thr_snd.send((thr, i, format!("{}:{}", thr, i)));
+            }));
+        }
+        drop(snd);
+        for jh in joins {
+            jh.join().expect("Uh oh, child thread paniced!");
+        }
+        assert!(rcv.metrics().records_written > 0);
+
+        let mut nxt = vec![0; max_thrs];
+        for _ in 0..(max_thrs * max_sz) {
+            let (thr, i, label) = rcv.try_iter().next().expect("missing item");
+            assert_eq!(nxt[thr], i);
+            assert_eq!(format!("{}:{}", thr, i), label);
+            nxt[thr] += 1;
+        }
+        assert_eq!(None, rcv.try_iter().next());
+    }
+
+    #[test]
+    fn per_sender_flush_publishes_partial_batch() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("per_sender_flush", dir.path())
+            .order(OrderMode::PerSender)
+            .build()
+            .unwrap();
+
+        snd.send(1);
+        assert_eq!(None, rcv.try_iter().next());
+        snd.flush().unwrap();
+        assert_eq!(Some(1), rcv.try_iter().next());
+    }
+
+    #[test]
+    fn dropped_sender_hands_over_staged_items() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (snd, mut rcv) = ChannelBuilder::new("drop_flush", dir.path())
+            .order(OrderMode::PerSender)
+            .build()
+            .unwrap();
+
+        let mut thr_snd = snd.clone();
+        thr_snd.send(1);
+        drop(thr_snd);
+        assert_eq!(Some(1), rcv.try_iter().next());
+    }
+
+    #[test]
+    fn read_ahead_drains_backlog_across_segments() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("read_ahead", dir.path())
+            .max_bytes(4096)
+            .read_ahead(512)
+            .build()
+            .unwrap();
+        for i in 0..20_000u64 {
+            snd.send(i);
+        }
+        assert!(rcv.metrics().segments_on_disk > 1);
+        for i in 0..20_000u64 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        assert_eq!(None, rcv.try_iter().next());
+        // Items sent while the Receiver reads the same queue file still
+        // arrive in order.
+        for i in 0..5_000u64 {
+            snd.send(i);
+            if i % 3 == 0 {
+                assert_eq!(Some(i / 3), rcv.try_iter().next());
+            }
+        }
+        for i in 1667..5_000u64 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        assert_eq!(None, rcv.try_iter().next());
+    }
+
+    #[test]
+    fn ephemeral_channel_removes_its_directory() {
+        use std::path::Path;
+        use super::Priority;
+
+        let (mut snd, mut rcv) = ChannelBuilder::new("ephemeral", Path::new("unused"))
+            .ephemeral()
+            .max_bytes(1024)
+            .priority_lanes()
+            .build()
+            .unwrap();
+        let dir = snd.root().parent().unwrap().to_path_buf();
+        assert!(dir.starts_with(::std::env::temp_dir()));
+        for i in 0..4096u64 {
+            snd.send(i);
+            snd.send_with_priority(i, Priority::Low).unwrap();
+        }
+        assert!(rcv.metrics().spill_events > 0);
+        assert_eq!(Some(0), rcv.try_iter().next());
+        let other = snd.clone();
+        drop(snd);
+        drop(rcv);
+        assert!(dir.is_dir());
+        drop(other);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn groups_are_sent_whole_or_not_at_all() {
+        use super::SendError;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("group_memory", dir.path())
+            .max_bytes(64)
+            .memory_only(true)
+            .build()
+            .unwrap();
+        for i in 0..2040u64 {
+            snd.send(i);
+        }
+        let group: Vec<u64> = (2040..2050).collect();
+        assert_eq!(Err(SendError::Full(())), snd.send_all_or_nothing(&group));
+        assert_eq!(Ok(()), snd.send_all_or_nothing(&group[..8]));
+        assert_eq!(10, rcv.metrics().total_overflowed);
+        assert_eq!((0..2048).collect::<Vec<u64>>(), rcv.try_iter().collect::<Vec<u64>>());
+
+        // Groups from several Senders, most of them paged out, arrive unbroken.
+        let (snd, mut rcv) = ChannelBuilder::new("group_disk", dir.path())
+            .max_bytes(256)
+            .build()
+            .unwrap();
+        let senders: Vec<_> = (0..3u64)
+            .map(|thr| {
+                let mut snd = snd.clone();
+                thread::spawn(move || for g in 0..100u64 {
+                    let group: Vec<(u64, u64)> = (0..10).map(|i| (thr, g * 10 + i)).collect();
+                    snd.send_all_or_nothing(&group).unwrap();
+                })
+            })
+            .collect();
+        for jh in senders {
+            jh.join().unwrap();
+        }
+        let got: Vec<(u64, u64)> = rcv.try_iter().collect();
+        assert_eq!(3000, got.len());
+        assert!(rcv.metrics().records_written > 0);
+        for group in got.chunks(10) {
+            assert_eq!(0, group[0].1 % 10);
+            for (i, &(thr, seq)) in group.iter().enumerate() {
+                assert_eq!((group[0].0, group[0].1 + i as u64), (thr, seq));
+            }
+        }
+    }
+
+    #[test]
+    fn batches_are_sent_as_far_as_they_fit() {
+        use super::OverflowPolicy;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("many_memory", dir.path())
+            .memory_only(true)
+            .overflow_policy(OverflowPolicy::Block)
+            .build()
+            .unwrap();
+        assert_eq!((2040, Vec::new()), snd.try_send_many((0..2040u64).collect()));
+        let (sent, tail) = snd.try_send_many((2040..2050).collect());
+        assert_eq!((8, vec![2048, 2049]), (sent, tail));
+        assert_eq!((0, vec![2050]), snd.try_send_many(vec![2050]));
+        assert_eq!((0..2048).collect::<Vec<u64>>(), rcv.try_iter().collect::<Vec<u64>>());
+
+        let (mut snd, mut rcv) = channel_with_max_bytes("many_disk", dir.path(), 256).unwrap();
+        assert_eq!((5000, Vec::new()), snd.try_send_many((0..5000u64).collect()));
+        assert!(rcv.metrics().spill_events > 0);
+        assert_eq!((0..5000).collect::<Vec<u64>>(), rcv.try_iter().collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn deadline_sends_give_up_on_a_full_channel() {
+        use super::{OverflowPolicy, SendError, SendTimeoutError};
+        use std::time::{Duration, Instant};
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("deadline", dir.path())
+            .memory_only(true)
+            .overflow_policy(OverflowPolicy::Block)
+            .build()
+            .unwrap();
+        assert_eq!((2048, Vec::new()), snd.try_send_many((0..2048u64).collect()));
+        let started = Instant::now();
+        let timeout = Duration::from_millis(20);
+        assert_eq!(Err(SendTimeoutError::Timeout(2048)), snd.send_timeout(2048, timeout));
+        assert!(started.elapsed() >= timeout);
+        assert_eq!(1, snd.metrics().total_overflowed);
+
+        assert_eq!((0..2048).collect::<Vec<u64>>(), rcv.try_iter().collect::<Vec<u64>>());
+        assert_eq!(Ok(()), snd.send_deadline(2048, Instant::now() + timeout));
+        rcv.close();
+        assert_eq!(
+            Err(SendTimeoutError::Refused(SendError::Closed(2049))),
+            snd.send_timeout(2049, timeout)
+        );
+        assert_eq!(vec![2048], rcv.try_iter().collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn disk_quota_holds_items_in_memory_and_refuses_by_policy() {
+        use super::{OverflowPolicy, SendError};
+        use std::sync::{Arc, Mutex};
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let thr_seen = Arc::clone(&seen);
+        let (mut snd, mut rcv) = ChannelBuilder::new("disk_quota", dir.path())
+            .max_bytes(1024)
+            .memory_budget(800)
+            .max_disk_bytes(4096)
+            .on_event(move |ev| thr_seen.lock().unwrap().push(ev))
+            .build()
+            .unwrap();
+        let mut sent = 0;
+        let refused = loop {
+            match snd.try_send(sent) {
+                Ok(()) => sent += 1,
+                Err(err) => break err,
+            }
+            assert!(snd.metrics().disk_bytes <= 4096);
+        };
+        assert_eq!(SendError::DiskFull(sent), refused);
+        snd.send(sent + 1);
+        let metrics = snd.metrics();
+        assert!(metrics.spill_events > 0);
+        assert_eq!(2, metrics.total_disk_full);
+        assert_eq!(0, metrics.total_overflowed);
+        let reached = seen.lock().unwrap().iter().any(|ev| match *ev {
+            QueueEvent::DiskFull { disk_bytes } => disk_bytes <= 4096,
+            _ => false,
+        });
+        assert!(reached);
+
+        for i in 0..sent {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        assert_eq!(None, rcv.try_iter().next());
+        assert_eq!(Ok(()), snd.try_send(sent));
+        assert_eq!(Some(sent), rcv.try_iter().next());
+
+        let (mut snd, mut rcv) = ChannelBuilder::new("disk_quota_block", dir.path())
+            .max_bytes(1024)
+            .memory_budget(800)
+            .max_disk_bytes(4096)
+            .overflow_policy(OverflowPolicy::Block)
+            .build()
+            .unwrap();
+        let producer = thread::spawn(move || {
+            for i in 0..8192u64 {
+                snd.send(i);
+            }
+            snd
+        });
+        let mut received = 0;
+        while received < 8192 {
+            if let Some(i) = rcv.try_iter().next() {
+                assert_eq!(received, i);
+                received += 1;
+            }
+            assert!(rcv.metrics().disk_bytes <= 4096);
+        }
+        producer.join().unwrap();
+        assert_eq!(0, rcv.metrics().total_disk_full);
+    }
+
+    #[test]
+    fn reserved_shares_are_kept_for_their_groups() {
+        use super::SendError;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("reserve_memory", dir.path())
+            .memory_only(true)
+            .memory_budget(800)
+            .reserve("a", 400, 0)
+            .reserve("b", 400, 0)
+            .build()
+            .unwrap();
+        let mut a = snd.clone();
+        let mut b = snd.clone();
+        assert!(a.join_group("a"));
+        assert!(b.join_group("b"));
+        assert!(!b.join_group("a"));
+        assert!(!snd.join_group("c"));
+
+        // Ungrouped items have only the room no group reserved, and each
+        // group its own share once that is gone.
+        for i in 0..100u64 {
+            assert_eq!(Ok(()), snd.try_send(i));
+        }
+        assert_eq!(Err(SendError::Full(100)), snd.try_send(100));
+        for i in 0..50u64 {
+            assert_eq!(Ok(()), b.try_send(i));
+        }
+        assert_eq!(Err(SendError::Full(50)), b.try_send(50));
+        for i in 0..50u64 {
+            assert_eq!(Ok(()), a.try_send(i));
+        }
+        assert_eq!(Err(SendError::Full(50)), a.try_send(50));
+
+        let held = rcv.group_metrics("a").unwrap();
+        assert_eq!(400, held.reserved_bytes);
+        assert_eq!(400, held.held_bytes);
+        assert_eq!(50, held.total_enqueued);
+        assert_eq!(1, held.total_overflowed);
+        assert_eq!(Some(1), snd.group_metrics("b").map(|m| m.total_overflowed));
+        assert_eq!(None, rcv.group_metrics("c"));
+        assert_eq!(3, rcv.metrics().total_overflowed);
+
+        // With the in-memory tier filled once, only the disk buffer is left,
+        // and the groups have reserved all of it.
+        assert_eq!(200, rcv.try_iter().count());
+        assert_eq!(0, rcv.group_metrics("a").unwrap().held_bytes);
+        assert_eq!(0, rcv.group_metrics("b").unwrap().held_bytes);
+        assert_eq!(Err(SendError::Full(0)), snd.try_send(0));
+        assert_eq!(Ok(()), a.try_send(0));
+    }
+
+    #[test]
+    fn reserved_disk_shares_refuse_other_senders() {
+        use super::SendError;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("reserve_disk", dir.path())
+            .max_bytes(1024)
+            .memory_budget(800)
+            .max_disk_bytes(8192)
+            .reserve("a", 0, 2048)
+            .build()
+            .unwrap();
+        let mut a = snd.clone();
+        assert!(a.join_group("a"));
+
+        let mut sent = 0;
+        let refused = loop {
+            match snd.try_send(sent) {
+                Ok(()) => sent += 1,
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(SendError::DiskFull(sent), refused);
+        let mut reserved = 0;
+        while a.try_send(reserved).is_ok() {
+            reserved += 1;
+        }
+        assert!(reserved > 0);
+        let metrics = a.group_metrics("a").unwrap();
+        assert_eq!(reserved, metrics.total_enqueued);
+        assert_eq!(1, metrics.total_disk_full);
+        assert!(rcv.metrics().disk_bytes <= 8192);
+
+        assert_eq!((sent + reserved) as usize, rcv.try_iter().count());
+        assert_eq!(0, rcv.group_metrics("a").unwrap().held_bytes);
+    }
+
+    #[test]
+    fn disk_quota_evicts_the_oldest_unread_files() {
+        use std::sync::{Arc, Mutex};
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let thr_seen = Arc::clone(&seen);
+        let (mut snd, mut rcv) = ChannelBuilder::new("disk_quota_evict", dir.path())
+            .max_bytes(1024)
+            .memory_budget(800)
+            .max_disk_bytes(4096)
+            .evict_oldest(true)
+            .on_event(move |ev| thr_seen.lock().unwrap().push(ev))
+            .build()
+            .unwrap();
+        for i in 0..20_000u64 {
+            assert_eq!(Ok(()), snd.try_send(i));
+            assert!(snd.metrics().disk_bytes <= 4096);
+        }
+        let metrics = snd.metrics();
+        assert!(metrics.total_evicted > 0);
+        assert_eq!(0, metrics.total_disk_full);
+        let evicted: u64 = seen.lock()
+            .unwrap()
+            .iter()
+            .map(|ev| match *ev {
+                QueueEvent::Evicted { records, .. } => records,
+                _ => 0,
+            })
+            .sum();
+        assert_eq!(metrics.total_evicted, evicted);
+
+        // What is left arrives in order, from the first item sent to the
+        // last, with the evicted files' items missing
+        let received: Vec<u64> = rcv.try_iter().collect();
+        assert_eq!(Some(&0), received.first());
+        assert_eq!(Some(&19_999), received.last());
+        assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(20_000, received.len() as u64 + metrics.total_evicted);
+    }
+
+    #[test]
+    fn disk_quota_evicts_lower_lanes_first() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("disk_quota_lanes", dir.path())
+            .priority_lanes()
+            .max_bytes(1024)
+            .memory_budget(800)
+            .max_disk_bytes(64 * 1024)
+            .evict_oldest(true)
+            .build()
+            .unwrap();
+        // Each lane alone fits within the quota, both together do not
+        for i in 0..4_000u64 {
+            snd.send_with_priority(i, Priority::Low).unwrap();
+        }
+        for i in 0..4_000u64 {
+            snd.send_with_priority(100_000 + i, Priority::High).unwrap();
+        }
+
+        let received: Vec<u64> = rcv.try_iter().collect();
+        let (high, low): (Vec<u64>, Vec<u64>) = received.into_iter().partition(|i| *i >= 100_000);
+        assert_eq!((100_000..104_000).collect::<Vec<u64>>(), high);
+        assert!(low.len() < 4_000);
+        assert_eq!(Some(&0), low.first());
+        assert_eq!(Some(&3_999), low.last());
+        assert!(low.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn purge_before_deletes_what_was_sent_before_the_cutoff() {
+        use clock::Clock;
+        use std::sync::Arc;
+        use std::time::Duration;
+        use testing::ManualClock;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let (mut snd, mut rcv) = ChannelBuilder::new("purge_before", dir.path())
+            .max_bytes(1024)
+            .record_provenance(true)
+            .clock(clock.clone())
+            .build::<u64>()
+            .unwrap();
+        for i in 0..5000 {
+            snd.send(i);
+        }
+        clock.advance(Duration::from_secs(10));
+        let cutoff = clock.wall();
+        clock.advance(Duration::from_secs(10));
+        for i in 5000..6000 {
+            snd.send(i);
+        }
+        let on_disk = rcv.metrics().segments_on_disk;
+
+        let report = rcv.purge_before(cutoff);
+        assert!(report.segments > 0);
+        assert!(report.records > 0 && report.bytes > 0);
+        assert_eq!(on_disk - report.segments, rcv.metrics().segments_on_disk);
+
+        // Whatever was sent before the cutoff and not deleted is dropped as
+        // the Receiver reaches it
+        assert_eq!((5000..6000).collect::<Vec<u64>>(), rcv.drain());
+        let expired = rcv.metrics().total_expired;
+        assert_eq!(5000, report.records + expired);
+        assert!(report.pending <= expired);
+        assert_eq!(super::PurgeReport::default(), rcv.purge_before(cutoff));
+    }
+
+    #[test]
+    fn purge_before_cuts_the_front_of_a_file_by_its_index() {
+        use clock::Clock;
+        use index;
+        use std::sync::Arc;
+        use std::time::Duration;
+        use testing::ManualClock;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let (mut snd, mut rcv) = ChannelBuilder::new("purge_cut", dir.path())
+            .max_bytes(64 * 1024)
+            .memory_budget(1024)
+            .record_provenance(true)
+            .clock(clock.clone())
+            .build::<u64>()
+            .unwrap();
+        // The first file is being read, the second holds items sent both
+        // before and after the cutoff
+        for i in 0..3000 {
+            snd.send(i);
+        }
+        clock.advance(Duration::from_secs(10));
+        let cutoff = clock.wall();
+        clock.advance(Duration::from_secs(10));
+        for i in 3000..6000 {
+            snd.send(i);
+        }
+
+        let report = rcv.purge_before(cutoff);
+        assert_eq!(0, report.segments);
+        assert!(report.records >= index::INTERVAL);
+        assert_eq!(0, report.records % index::INTERVAL);
+
+        assert_eq!((3000..6000).collect::<Vec<u64>>(), rcv.drain());
+        let expired = rcv.metrics().total_expired;
+        assert_eq!(3000, report.records + expired);
+        assert_eq!(None, rcv.try_recv().unwrap());
+        snd.send(6000);
+        assert_eq!(Some(6000), rcv.try_recv().unwrap());
+    }
+
+    #[test]
+    fn reconfigure_changes_limits_of_a_running_channel() {
+        use super::{topology, ConfigDelta, SendError};
+        use std::sync::{Arc, Mutex};
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let thr_seen = Arc::clone(&seen);
+        let (mut snd, mut rcv) = ChannelBuilder::new("reconfigure", dir.path())
+            .max_bytes(1024)
+            .memory_budget(800)
+            .max_disk_bytes(4096)
+            .on_event(move |ev| thr_seen.lock().unwrap().push(ev))
+            .build()
+            .unwrap();
+        let mut sent = 0;
+        while snd.try_send(sent).is_ok() {
+            sent += 1;
+        }
+        assert_eq!(Err(SendError::DiskFull(sent)), snd.try_send(sent));
+
+        // The held disk buffer goes out under the raised quota
+        let delta = ConfigDelta {
+            max_disk_bytes: Some(1 << 20),
+            watermarks: Some((sent as usize + 2, 0)),
+            ..ConfigDelta::default()
+        };
+        assert!(snd.reconfigure(delta) > 0);
+        assert_eq!(Ok(()), snd.try_send(sent));
+        assert!(snd.metrics().disk_bytes > 4096);
+        assert_eq!(Ok(()), snd.try_send(sent + 1));
+        let high = seen.lock().unwrap().iter().any(|ev| match *ev {
+            QueueEvent::HighWatermark { items, .. } => items == sent as usize + 2,
+            _ => false,
+        });
+        assert!(high);
+        assert_eq!((0..sent + 2).collect::<Vec<u64>>(), rcv.try_iter().collect::<Vec<u64>>());
+
+        let (mut snd, mut rcv) = channel::<u64>("reconfigure_capacity", dir.path()).unwrap();
+        let delta = ConfigDelta {
+            in_memory_capacity: Some(8),
+            ..ConfigDelta::default()
+        };
+        assert_eq!(0, snd.reconfigure(delta));
+        let capacity = topology()
+            .channels
+            .iter()
+            .find(|chan| chan.name == "reconfigure_capacity")
+            .map(|chan| chan.in_memory_capacity);
+        assert_eq!(Some(8), capacity);
+        for i in 0..16 {
+            snd.send(i);
+        }
+        assert_eq!(1, snd.metrics().spill_events);
+        assert_eq!((0..16).collect::<Vec<u64>>(), rcv.try_iter().collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn config_builds_and_reloads_a_channel() {
+        use super::{QueueConfig, SendError};
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let mut config = QueueConfig::new("from_config", dir.path());
+        config.max_bytes = 1024;
+        config.memory_budget = Some(800);
+        config.max_disk_bytes = Some(4096);
+        let (mut snd, mut rcv) = ChannelBuilder::from_config(&config).build().unwrap();
+        let mut sent = 0;
+        while snd.try_send(sent).is_ok() {
+            sent += 1;
+        }
+        assert_eq!(Err(SendError::DiskFull(sent)), snd.try_send(sent));
+
+        // Only the quota is taken from the reloaded file; the rest waits for
+        // a rebuild.
+        config.max_disk_bytes = Some(1 << 20);
+        config.max_bytes = 1 << 20;
+        assert!(snd.reload(&config) > 0);
+        assert_eq!(Ok(()), snd.try_send(sent));
+        assert_eq!(1024, snd.segment_max_bytes());
+        assert_eq!((0..sent + 1).collect::<Vec<u64>>(), rcv.try_iter().collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn iterating_the_receiver_blocks_until_the_channel_hangs_up() {
+        use std::thread;
+        use std::time::Duration;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel_with_max_bytes::<u64>("blocking_iter", dir.path(), 1024)
+            .unwrap();
+        snd.send(0);
+        assert_eq!(vec![0], rcv.try_iter().collect::<Vec<u64>>());
+        assert!(!rcv.is_hung_up());
+
+        // A flusher's Sender does not keep the channel open, and the last
+        // Sender wakes the iteration as it goes
+        snd.spawn_flusher(Duration::from_millis(5), 512);
+        let jh = thread::spawn(move || for i in 1..1500 {
+            snd.send(i);
+            if i % 500 == 0 {
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+        assert_eq!((1..1500).collect::<Vec<u64>>(), rcv.iter().collect::<Vec<u64>>());
+        jh.join().unwrap();
+        assert!(rcv.is_hung_up());
+        assert_eq!(None, rcv.into_iter().next());
+    }
+
+    #[test]
+    fn weak_senders_do_not_keep_the_channel_open() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (snd, mut rcv) = channel::<u64>("weak_sender", dir.path()).unwrap();
+        assert_eq!(1, rcv.sender_count());
+        let weak = snd.downgrade();
+        assert_eq!(1, rcv.sender_count());
+
+        let mut upgraded = weak.upgrade().unwrap();
+        assert_eq!(2, rcv.sender_count());
+        upgraded.send(7);
+        drop(upgraded);
+        drop(snd);
+        assert_eq!(0, rcv.sender_count());
+        assert!(rcv.is_hung_up());
+        assert!(weak.upgrade().is_none());
+        assert_eq!(vec![7], rcv.try_iter().collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn sender_reports_occupancy_without_locking() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel::<u64>("occupancy", dir.path()).unwrap();
+        for i in 0..512 {
+            snd.send(i);
+        }
+        assert_eq!(0.5, snd.occupancy());
+        assert!(!snd.is_full());
+        for i in 512..1024 {
+            snd.send(i);
+        }
+        assert!(snd.is_full());
+        // Just the queue file header
+        let empty = snd.disk_backlog_bytes();
+
+        // The disk buffer fills and is paged out
+        for i in 1024..2048 {
+            snd.send(i);
+        }
+        assert_eq!(1.0, snd.occupancy());
+        assert_eq!(snd.metrics().disk_bytes, snd.disk_backlog_bytes());
+        assert!(snd.disk_backlog_bytes() > empty + 1024);
+        assert_eq!(2048, rcv.try_iter().count());
+        assert_eq!(0.0, snd.occupancy());
+    }
+
+    #[test]
+    fn memory_only_channel_overflows_by_policy() {
+        use super::{OverflowPolicy, SendError};
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("memory_only", dir.path())
+            .max_bytes(64)
+            .memory_only(true)
+            .build()
+            .unwrap();
+        for i in 0..2048u64 {
+            assert_eq!(Ok(()), snd.try_send(i));
+        }
+        assert_eq!(Err(SendError::Full(2048)), snd.try_send(2048));
+        snd.send(2049);
+        let metrics = rcv.metrics();
+        assert_eq!(2, metrics.total_overflowed);
+        assert_eq!(0, metrics.records_written);
+        assert_eq!(0, metrics.spill_events);
+        for i in 0..1025u64 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        assert_eq!(Ok(()), snd.try_send(2050));
+        assert_eq!(Err(SendError::Full(2051)), snd.try_send(2051));
+        for i in (1025..2048u64).chain(Some(2050)) {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        assert_eq!(None, rcv.try_iter().next());
+
+        let (mut snd, mut rcv) = ChannelBuilder::new("memory_only_block", dir.path())
+            .memory_only(true)
+            .overflow_policy(OverflowPolicy::Block)
+            .build()
+            .unwrap();
+        let producer = thread::spawn(move || {
+            for i in 0..8192u64 {
+                snd.send(i);
+            }
+            snd
+        });
+        let mut received = 0;
+        while received < 8192 {
+            if let Some(i) = rcv.try_iter().next() {
+                assert_eq!(received, i);
+                received += 1;
+            }
+        }
+        let mut snd = producer.join().unwrap();
+        assert_eq!(0, rcv.metrics().total_overflowed);
+        assert_eq!(0, rcv.metrics().records_written);
+
+        // Dropping the Receiver releases a Sender waiting for room.
+        for i in 0..1024u64 {
+            snd.send(i);
+        }
+        let waiting = thread::spawn(move || snd.send(1024));
+        thread::sleep(::std::time::Duration::from_millis(20));
+        drop(rcv);
+        waiting.join().unwrap();
+    }
+
+    #[test]
+    fn blocked_sender_is_woken_as_the_receiver_takes_items() {
+        use super::OverflowPolicy;
+        use std::time::{Duration, Instant};
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("room_wake", dir.path())
+            .memory_only(true)
+            .overflow_policy(OverflowPolicy::Block)
+            .build()
+            .unwrap();
+        // Each send waits until the Receiver empties the full channel. A
+        // Sender that only looked again now and then would lag the receive
+        // by milliseconds each time.
+        let mut lag = Duration::from_secs(0);
+        for _ in 0..10 {
+            while snd.try_send_many(vec![0; 256]).1.is_empty() {}
+            let mut snd = snd.clone();
+            let waiting = thread::spawn(move || {
+                snd.send(2048);
+                Instant::now()
+            });
+            thread::sleep(Duration::from_millis(25));
+            assert!(rcv.try_iter().count() > 0);
+            let drained = Instant::now();
+            lag += waiting.join().unwrap().saturating_duration_since(drained);
+        }
+        assert!(lag < Duration::from_millis(25), "{:?}", lag);
+    }
+
+    #[test]
+    fn disk_primary_channel_writes_every_send() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("disk_primary", dir.path())
+            .max_bytes(1 << 20)
+            .disk_primary(true)
+            .build()
+            .unwrap();
+        for i in 0..16u8 {
+            snd.send(vec![i; 256 * 1024]);
+            assert_eq!(u64::from(i) + 1, rcv.metrics().records_written);
+        }
+        let metrics = rcv.metrics();
+        assert_eq!(16, metrics.write_calls);
+        assert!(metrics.segments_on_disk > 1);
+        for i in 0..16u8 {
+            assert_eq!(Some(vec![i; 256 * 1024]), rcv.try_iter().next());
+        }
+        assert_eq!(None, rcv.try_iter().next());
+        snd.send(vec![16]);
+        assert_eq!(Some(vec![16]), rcv.try_iter().next());
+    }
+
+    #[test]
+    fn flush_on_drop_can_be_turned_off() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("drop_discard", dir.path())
+            .order(OrderMode::PerSender)
+            .flush_on_drop(false)
+            .build()
+            .unwrap();
+
+        let mut thr_snd = snd.clone();
+        thr_snd.send(1);
+        drop(thr_snd);
+        assert_eq!(None, rcv.try_iter().next());
+        snd.send(2);
+        snd.flush().unwrap();
+        assert_eq!(Some(2), rcv.try_iter().next());
+    }
+
+    #[test]
+    fn memory_budget_bounds_tiers_by_bytes() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("budget", dir.path())
+            .memory_budget(10_000)
+            .build()
+            .unwrap();
+        // Each encodes to 1008 bytes, so nine fit in memory and the disk
+        // buffer pages out every ten.
+        let items: Vec<String> = (0..30).map(|i| format!("{:01000}", i)).collect();
+        for item in &items {
+            snd.send(item.clone());
+        }
+        let m = snd.metrics();
+        assert_eq!(2, m.spill_events);
+        assert_eq!(20, m.records_written);
+
+        let got: Vec<String> = rcv.try_iter().collect();
+        assert_eq!(items, got);
+    }
+
+    #[test]
+    fn memory_budget_holds_many_small_items() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("budget_small", dir.path())
+            .memory_budget(1 << 20)
+            .build()
+            .unwrap();
+        for i in 0..5000u64 {
+            snd.send(i);
+        }
+        assert_eq!(0, snd.metrics().spill_events);
+        for i in 0..5000u64 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        assert_eq!(None, rcv.try_iter().next());
+    }
+
+    #[test]
+    fn shared_budget_bounds_channels_together() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let budget = Budget::new(800);
+        let (mut quiet_snd, quiet_rcv) = ChannelBuilder::new("quiet", dir.path())
+            .shared_budget(&budget)
+            .build()
+            .unwrap();
+        let (mut busy_snd, mut busy_rcv) = ChannelBuilder::new("busy", dir.path())
+            .shared_budget(&budget)
+            .build()
+            .unwrap();
+        // Each encodes to 8 bytes, so the quiet channel holds half the budget
+        // and the burst on the busy one goes to disk once it has the rest.
+        for i in 0..50u64 {
+            quiet_snd.send(i);
+        }
+        assert_eq!(400, budget.used());
+        for i in 0..1000u64 {
+            busy_snd.send(i);
+            assert!(budget.used() <= 808);
+        }
+        assert_eq!(0, quiet_snd.metrics().spill_events);
+        assert_eq!(950, busy_snd.metrics().records_written);
+
+        for i in 0..1000u64 {
+            assert_eq!(Some(i), busy_rcv.try_iter().next());
+        }
+        assert_eq!(400, budget.used());
+        drop((quiet_snd, quiet_rcv));
+        assert_eq!(0, budget.used());
+    }
+
+    #[cfg(feature = "cgroup")]
+    #[test]
+    fn cgroup_budget_falls_back_without_a_limit() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("cgroup_budget", dir.path())
+            .memory_budget(10_000)
+            .cgroup_memory_budget(50.0, ::std::time::Duration::from_millis(10))
+            .build()
+            .unwrap();
+        let items: Vec<String> = (0..30).map(|i| format!("{:01000}", i)).collect();
+        for item in &items {
+            snd.send(item.clone());
+        }
+        // Outside a limited cgroup the builder's budget stands.
+        let limited = ::cgroup::CgroupMemory::read().is_some_and(|m| m.max.is_some());
+        if !limited {
+            assert_eq!(20, snd.metrics().records_written);
+        }
+        let got: Vec<String> = rcv.try_iter().collect();
+        assert_eq!(items, got);
+    }
+
+    #[cfg(feature = "histograms")]
+    #[test]
+    fn metrics_report_latency_histograms() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("latency", dir.path())
+            .max_bytes(4096)
+            .stamp_send_times(true)
+            .build()
+            .unwrap();
+        for i in 0..4096u64 {
+            snd.send(i);
+        }
+        thread::sleep(::std::time::Duration::from_millis(20));
+        for i in 0..4096u64 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        assert_eq!(None, rcv.try_iter().next());
+
+        let m = rcv.metrics();
+        assert_eq!(4096, m.send_latency.count);
+        assert_eq!(4096, m.recv_latency.count);
+        assert_eq!(4096, m.time_in_queue.count);
+        assert!(m.flush_latency.count > 0);
+        assert!(m.time_in_queue.p50 >= 20_000);
+        assert!(m.send_latency.p50 <= m.send_latency.p99);
+        assert!(m.send_latency.p99 <= m.send_latency.max);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn encrypted_channel_keeps_plaintext_off_disk() {
+        use std::fs;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("encrypted", dir.path())
+            .encryption_key([3; 32])
+            .build()
+            .unwrap();
+        let items: Vec<String> = (0..3000).map(|i| format!("secret-{}", i)).collect();
+        for item in &items {
+            snd.send(item.clone());
+        }
+        let on_disk = fs::read(dir.path().join("encrypted").join("0")).unwrap();
+        assert!(!on_disk.is_empty());
+        assert!(!on_disk.windows(6).any(|w| w == b"secret"));
+
+        let got: Vec<String> = rcv.try_iter().collect();
+        assert_eq!(items, got);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn rekeyed_channel_seals_backlog_under_new_key() {
+        use std::fs;
+        use super::private;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("rekey", dir.path())
+            .max_bytes(4096)
+            .encryption_key([3; 32])
+            .build()
+            .unwrap();
+        for i in 0..3000u64 {
+            snd.send(i);
+        }
+        snd.rotate_encryption_key(1, [4; 32]);
+        for i in 3000..4000u64 {
+            snd.send(i);
+        }
+        assert!(snd.rekey().unwrap() > 0);
+
+        let root = dir.path().join("rekey");
+        let current = private::segment_ids(&root, None).into_iter().max().unwrap();
+        for id in private::segment_ids(&root, None) {
+            if id == current {
+                continue;
+            }
+            let buf = fs::read(root.join(format!("{}", id))).unwrap();
+            let mut rest = &buf[layout::SEGMENT_HEADER_LEN..];
+            while let Some((record, tail)) = private::next_record(rest) {
+                assert_eq!(&[0, 0, 0, 1], &record[..4]);
+                rest = tail;
+            }
+        }
+        let got: Vec<u64> = rcv.try_iter().collect();
+        assert_eq!((0..4000).collect::<Vec<u64>>(), got);
+    }
+
+    #[test]
+    fn events_report_memory_full_and_spills() {
+        use std::sync::{Arc, Mutex};
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let thr_seen = Arc::clone(&seen);
+        let (mut snd, _rcv) = ChannelBuilder::new("events", dir.path())
+            .on_event(move |ev| thr_seen.lock().unwrap().push(ev))
+            .build()
+            .unwrap();
+        assert_eq!(
+            vec![QueueEvent::RecoveredSegments { count: 1 }],
+            *seen.lock().unwrap()
+        );
+
+        for i in 0..2048u64 {
+            snd.send(i);
+        }
+        let seen = seen.lock().unwrap();
+        assert_eq!(3, seen.len());
+        assert_eq!(QueueEvent::MemoryFull, seen[1]);
+        match seen[2] {
+            QueueEvent::SpilledToDisk { bytes } => {
+                assert_eq!(1024 * (4 + 8), bytes)
+            }
+            ref other => panic!("unexpected event {:?}", other),
+        }
+    }
+
+    #[test]
+    fn watermark_crossings_are_reported() {
+        use std::sync::{Arc, Mutex};
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let thr_seen = Arc::clone(&seen);
+        let (mut snd, mut rcv) = ChannelBuilder::new("watermarks", dir.path())
+            .watermarks(1500, 100)
+            .on_event(move |ev| match ev {
+                QueueEvent::HighWatermark { .. } | QueueEvent::LowWatermark { .. } => {
+                    thr_seen.lock().unwrap().push(ev)
+                }
+                _ => {}
+            })
+            .build()
+            .unwrap();
+
+        for round in 0..2 {
+            for i in 0..2000u64 {
+                snd.send(i);
+            }
+            for i in 0..2000u64 {
+                assert_eq!(Some(i), rcv.try_iter().next());
+            }
+            let seen = seen.lock().unwrap();
+            assert_eq!(2 * (round + 1), seen.len());
+            match seen[2 * round] {
+                QueueEvent::HighWatermark { items, disk_bytes } => {
+                    assert_eq!(1500, items);
+                    assert!(disk_bytes > 0);
+                }
+                ref other => panic!("unexpected event {:?}", other),
+            }
+            match seen[2 * round + 1] {
+                QueueEvent::LowWatermark { items, .. } => assert_eq!(100, items),
+                ref other => panic!("unexpected event {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn sampled_sends_thin_out_as_the_channel_runs_deep() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("sampled", dir.path())
+            .watermarks(100, 20)
+            .adaptive_sampling(0.5)
+            .build()
+            .unwrap();
+
+        // Drained as it goes, the channel stays under its low mark.
+        for i in 0..100u64 {
+            if snd.send_sampled(i, 0.5) {
+                assert_eq!(Some(i), rcv.try_iter().next());
+                assert_eq!(1, i % 2);
+            }
+        }
+        assert_eq!(500_000, snd.metrics().sample_rate_ppm);
+
+        // At the high mark the rate is halved again.
+        for i in 0..100u64 {
+            snd.send(i);
+        }
+        let kept = (0..100u64).filter(|&i| snd.send_sampled(i, 0.5)).count();
+        assert_eq!(25, kept);
+        let metrics = snd.metrics();
+        assert_eq!(250_000, metrics.sample_rate_ppm);
+        assert_eq!(200, metrics.total_sampled);
+        assert_eq!(125, metrics.total_sampled_out);
+        assert_eq!(175, metrics.total_enqueued);
+    }
+
+    #[test]
+    fn paused_receiver_buffers_until_resumed() {
+        use std::sync::{Arc, Mutex};
+        use super::Select;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let thr_seen = Arc::clone(&seen);
+        let (mut snd, mut rcv) = ChannelBuilder::new("paused", dir.path())
+            .on_event(move |ev| match ev {
+                QueueEvent::Paused | QueueEvent::Resumed { .. } => {
+                    thr_seen.lock().unwrap().push(ev)
+                }
+                _ => {}
+            })
+            .build()
+            .unwrap();
+
+        rcv.pause();
+        assert!(rcv.is_paused());
+        for i in 0..4096u64 {
+            snd.send(i);
+        }
+        assert_eq!(None, rcv.try_iter().next());
+        assert!(rcv.metrics().spill_events > 0);
+
+        let mut sel = Select::new();
+        let idx = sel.add(&rcv);
+        assert_eq!(None, sel.try_ready());
+        let waiter = thread::spawn(move || sel.ready());
+        thread::sleep(::std::time::Duration::from_millis(20));
+        rcv.resume();
+        assert_eq!(idx, waiter.join().unwrap());
+        assert!(!rcv.is_paused());
+        assert_eq!(
+            vec![QueueEvent::Paused, QueueEvent::Resumed { items: 4096 }],
+            *seen.lock().unwrap()
+        );
+        for i in 0..4096u64 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        assert_eq!(None, rcv.try_iter().next());
+    }
+
+    #[test]
+    fn rate_limited_sends_fail_or_wait_by_policy() {
+        use std::time::{Duration, Instant};
+        use super::{RatePolicy, SendError};
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("rate_fail", dir.path())
+            .rate_limit(1, 10, RatePolicy::Fail)
+            .build()
+            .unwrap();
+        let mut other = snd.clone();
+        for i in 0..5u64 {
+            assert_eq!(Ok(()), snd.try_send(i));
+            assert_eq!(Ok(()), other.try_send(i));
+        }
+        assert_eq!(Err(SendError::RateLimited(10)), snd.try_send(10));
+        other.send(11);
+        assert_eq!(2, rcv.metrics().total_rate_limited);
+        assert_eq!(10, rcv.try_iter().count());
+
+        let (mut snd, mut rcv) = ChannelBuilder::new("rate_block", dir.path())
+            .sender_rate_limit(100, 1, RatePolicy::Block)
+            .build()
+            .unwrap();
+        let mut other = snd.clone();
+        let started = Instant::now();
+        for i in 0..11u64 {
+            snd.send(i);
+            other.send(i);
+        }
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(0, rcv.metrics().total_rate_limited);
+        assert_eq!(22, rcv.try_iter().count());
+    }
+
+    #[test]
+    fn batches_fill_to_max_or_wait_out_timeout() {
+        use std::time::{Duration, Instant};
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("batch", dir.path())
+            .max_bytes(1024)
+            .build()
+            .unwrap();
+        for i in 0..1200u64 {
+            snd.send(i);
+        }
+        let wait = Duration::from_millis(50);
+        assert_eq!((0..500).collect::<Vec<u64>>(), rcv.recv_batch(500, wait));
+        assert_eq!((500..1000).collect::<Vec<u64>>(), rcv.recv_batch(500, wait));
+        let started = Instant::now();
+        assert_eq!((1000..1200).collect::<Vec<u64>>(), rcv.recv_batch(500, wait));
+        assert!(started.elapsed() >= wait);
+        assert_eq!(1200, rcv.metrics().total_dequeued);
+
+        let sender = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            for i in 0..3u64 {
+                snd.send(i);
+            }
+        });
+        assert_eq!(vec![0, 1, 2], rcv.recv_batch(3, Duration::from_secs(10)));
+        sender.join().unwrap();
+        assert!(rcv.recv_batch(3, Duration::from_millis(0)).is_empty());
+    }
+
+    #[test]
+    fn recv_many_into_refills_a_buffer_in_place() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel_with_max_bytes::<u64>("many", dir.path(), 1024)
+            .unwrap();
+        for i in 0..3000 {
+            snd.send(i);
+        }
+        let mut items = Vec::with_capacity(256);
+        let buf = items.as_ptr();
+        let mut received = Vec::new();
+        loop {
+            items.clear();
+            let n = rcv.recv_many_into(&mut items, 256);
+            assert_eq!(n, items.len());
+            assert!(n <= 256);
+            assert_eq!(buf, items.as_ptr());
+            if n == 0 {
+                break;
+            }
+            received.extend_from_slice(&items);
+        }
+        assert_eq!((0..3000).collect::<Vec<u64>>(), received);
+
+        // Items are appended after those already in the buffer
+        let mut items = vec![42];
+        snd.send(3000);
+        snd.send(3001);
+        assert_eq!(1, rcv.recv_many_into(&mut items, 1));
+        assert_eq!(1, rcv.recv_many_into(&mut items, 5));
+        assert_eq!(0, rcv.recv_many_into(&mut items, 5));
+        assert_eq!(vec![42, 3000, 3001], items);
+    }
+
+    #[test]
+    fn errors_travel_in_line_with_data() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) =
+            channel_with_max_bytes::<Result<u64, String>>("errors", dir.path(), 1024).unwrap();
+        for i in 0..3000u64 {
+            if i % 1000 == 999 {
+                snd.send_err(format!("upstream failed after {}", i));
+            } else {
+                snd.send(Ok(i));
+            }
+        }
+        assert!(snd.try_send_err("done".to_string()).is_ok());
+
+        let (mut data, mut errors) = (Vec::new(), Vec::new());
+        assert_eq!(10, rcv.recv_split_into(&mut data, &mut errors, 10));
+        while rcv.recv_split_into(&mut data, &mut errors, 256) > 0 {}
+        let expected: Vec<u64> = (0..3000).filter(|i| i % 1000 != 999).collect();
+        assert_eq!(expected, data);
+        assert_eq!(
+            vec![
+                (999, "upstream failed after 999".to_string()),
+                (1998, "upstream failed after 1999".to_string()),
+                (2997, "upstream failed after 2999".to_string()),
+                (2997, "done".to_string()),
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn drain_takes_what_is_waiting_and_leaves_the_channel_open() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel_with_max_bytes::<u64>("drain", dir.path(), 1024)
+            .unwrap();
+        for i in 0..3000 {
+            snd.send(i);
+        }
+        assert_eq!((0..3000).collect::<Vec<u64>>(), rcv.drain());
+        assert!(rcv.drain().is_empty());
+
+        let mut items = vec![42];
+        snd.send(3000);
+        assert_eq!(1, rcv.drain_to(&mut items));
+        assert_eq!(vec![42, 3000], items);
+
+        // A drain ends while a Sender keeps sending
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let jh = thread::spawn(move || {
+            let mut i = 0u64;
+            while !stopped.load(Ordering::Relaxed) {
+                snd.send(i);
+                i += 1;
+            }
+            i
+        });
+        let mut drained = Vec::new();
+        while drained.is_empty() {
+            drained = rcv.drain();
+        }
+        stop.store(true, Ordering::Relaxed);
+        let sent = jh.join().unwrap();
+        drained.extend(rcv.drain());
+        assert_eq!((0..sent).collect::<Vec<u64>>(), drained);
+    }
+
+    #[test]
+    fn packed_records_come_back_in_order() {
+        use segment;
+        use snapshot::Reader;
+        use std::fs;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("packed", dir.path())
+            .max_bytes(1024)
+            .pack_records(256)
+            .build()
+            .unwrap();
+        for i in 0..6000u64 {
+            snd.send(i);
+        }
+        snd.flush().unwrap();
+        // Queue files hold blocks of many records, not one record per item
+        let file = fs::read(dir.path().join("packed").join("1")).unwrap();
+        let blocks = segment::parse(&file).unwrap().map(Result::unwrap).count();
+        assert!(blocks > 0 && blocks * 12 * 8 < file.len());
+        for i in 0..1500u64 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+
+        // The Receiver is partway through a block
+        let path = dir.path().join("packed.snap");
+        assert_eq!(4500, rcv.export_snapshot(&path).unwrap());
+        let mut snap = Reader::<u64>::open(&path, "u64").unwrap();
+        for i in 1500..6000u64 {
+            assert_eq!(Some(i), snap.next().unwrap());
+        }
+        assert_eq!(None, snap.next().unwrap());
+        for i in 1500..6000u64 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        assert_eq!(None, rcv.try_iter().next());
+    }
+
+    #[cfg(feature = "dictionary")]
+    #[test]
+    fn blocks_decode_with_the_dictionary_they_were_compressed_with() {
+        use block;
+        use segment;
+        use std::collections::BTreeSet;
+        use std::fs;
+        use std::path::Path;
+
+        // The dictionary versions the blocks of the channel's first queue
+        // file name
+        fn versions(root: &Path) -> BTreeSet<u32> {
+            let file = fs::read(root.join("0")).unwrap();
+            segment::parse(&file)
+                .unwrap()
+                .map(Result::unwrap)
+                .filter(|record| record.payload[0] == block::DICTIONARY)
+                .map(|record| {
+                    let p = record.payload;
+                    u32::from_be_bytes([p[5], p[6], p[7], p[8]])
+                })
+                .collect()
+        }
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let root = dir.path().join("dict");
+        let build = || {
+            ChannelBuilder::new("dict", dir.path())
+                .pack_records(256)
+                .train_dictionary(64, 1024)
+                .build::<u64>()
+                .unwrap()
+        };
+        let (mut snd, mut rcv) = build();
+        for i in 0..6000u64 {
+            snd.send(i);
+        }
+        snd.flush().unwrap();
+        assert!(root.join("hopper.dict.1").is_file());
+        assert_eq!(vec![1], versions(&root).into_iter().collect::<Vec<u32>>());
+
+        // Retrained, new blocks name the new dictionary and old ones still
+        // decode with the old
+        snd.retrain_dictionary();
+        for i in 6000..12000u64 {
+            snd.send(i);
+        }
+        snd.flush().unwrap();
+        assert!(root.join("hopper.dict.2").is_file());
+        assert_eq!(vec![1, 2], versions(&root).into_iter().collect::<Vec<u32>>());
+        for i in 0..12000u64 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        assert_eq!(None, rcv.try_iter().next());
+
+        // Reopened, the channel takes up the newest dictionary stored
+        drop(snd);
+        drop(rcv);
+        let (mut snd, mut rcv) = build();
+        for i in 0..3000u64 {
+            snd.send(i);
+        }
+        snd.flush().unwrap();
+        for i in 0..3000u64 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        assert!(!root.join("hopper.dict.3").exists());
+    }
+
+    #[cfg(all(feature = "preallocate", target_os = "linux"))]
+    #[test]
+    fn preallocated_queue_files_keep_their_length() {
+        use std::fs;
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("prealloc", dir.path())
+            .max_bytes(1 << 20)
+            .preallocate(true)
+            .build()
+            .unwrap();
+        for i in 0..2000u64 {
+            snd.send(i);
+        }
+        snd.flush().unwrap();
+        let meta = fs::metadata(dir.path().join("prealloc").join("0")).unwrap();
+        assert!(meta.len() < 1 << 16);
+        assert!(meta.blocks() * 512 >= 1 << 20);
+        assert_eq!((0..2000).collect::<Vec<u64>>(), rcv.drain());
+    }
+
+    #[test]
+    fn health_reports_disk_quota_and_tasks() {
+        use super::{Health, Task};
+        use std::time::Duration;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, rcv) = ChannelBuilder::new("health", dir.path())
+            .read_ahead(4096)
+            .max_disk_bytes(1 << 20)
+            .build::<u64>()
+            .unwrap();
+        assert_eq!(vec![Task::ReadAhead], tasks(&rcv.health()));
+        for i in 0..2000 {
+            snd.send(i);
+        }
+        snd.flush().unwrap();
+        let health = snd.health();
+        assert!(health.disk_writable);
+        assert_eq!(None, health.last_write_error);
+        let disk_bytes = snd.metrics().disk_bytes;
+        assert!(disk_bytes > 0);
+        assert_eq!(Some((1 << 20) - disk_bytes), health.quota_headroom);
+        snd.spawn_flusher(Duration::from_millis(10), 16);
+        let health = snd.health();
+        assert_eq!("health", health.channel);
+        assert_eq!(vec![Task::ReadAhead, Task::Flusher], tasks(&health));
+        assert!(health.is_healthy());
+        assert_eq!(health, rcv.health());
+
+        fn tasks(health: &Health) -> Vec<Task> {
+            health.tasks.iter().map(|t| t.task).collect()
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failed_writes_are_retried_then_hold_items_in_memory() {
+        use super::SendError;
+        use std::fs;
+        use std::io::ErrorKind;
+        use std::os::unix::fs::symlink;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+        use testing::ManualClock;
+
+        // Every write to /dev/full fails for want of space, so the queue file
+        // after the first cannot be created until the link is removed.
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let thr_seen = Arc::clone(&seen);
+        let (mut snd, mut rcv) = ChannelBuilder::new("write_retry", dir.path())
+            .max_bytes(1024)
+            .clock(clock.clone())
+            .on_event(move |ev| thr_seen.lock().unwrap().push(ev))
+            .build::<u64>()
+            .unwrap();
+        let next_file = dir.path().join("write_retry").join("1");
+        symlink("/dev/full", &next_file).unwrap();
+        for i in 0..4096 {
+            assert_eq!(Ok(()), snd.try_send(i));
+        }
+        let failed = QueueEvent::WriteFailed {
+            kind: ErrorKind::StorageFull,
+            retrying: true,
+        };
+        assert_eq!(1, seen.lock().unwrap().iter().filter(|&&ev| ev == failed).count());
+        assert!(snd.health().last_write_error.is_some());
+        assert!(!snd.health().is_healthy());
+
+        fs::remove_file(&next_file).unwrap();
+        clock.advance(Duration::from_secs(1));
+        snd.send(4096);
+        assert_eq!(None, snd.health().last_write_error);
+        assert!(snd.metrics().segments_on_disk > 1);
+        assert_eq!((0..4097).collect::<Vec<u64>>(), rcv.drain());
+
+        // An error retrying will not mend leaves the channel memory-only
+        let (mut snd, mut rcv) = ChannelBuilder::new("write_failed", dir.path())
+            .max_bytes(1024)
+            .on_event({
+                let seen = Arc::clone(&seen);
+                move |ev| seen.lock().unwrap().push(ev)
+            })
+            .build::<u64>()
+            .unwrap();
+        fs::create_dir(dir.path().join("write_failed").join("1")).unwrap();
+        let mut sent = 0;
+        let refused = loop {
+            match snd.try_send(sent) {
+                Ok(()) => sent += 1,
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(SendError::WriteFailed(sent), refused);
+        assert_eq!(1, snd.metrics().total_overflowed);
+        assert!(seen.lock().unwrap().contains(&QueueEvent::WriteFailed {
+            kind: ErrorKind::IsADirectory,
+            retrying: false,
+        }));
+        assert_eq!((0..sent).collect::<Vec<u64>>(), rcv.drain());
+        assert_eq!(Ok(()), snd.try_send(sent));
+        assert_eq!(vec![sent], rcv.drain());
+    }
+
+    #[test]
+    fn filtered_receiver_drops_and_counts_rejects() {
+        use std::time::Duration;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, rcv) = ChannelBuilder::new("filtered", dir.path())
+            .max_bytes(1024)
+            .build()
+            .unwrap();
+        let mut rcv = rcv.filtered(|i: &u64| i.is_multiple_of(2));
+        // Through the in-memory tier, the queue files and the disk buffer.
+        for i in 0..3000u64 {
+            snd.send(i);
+        }
+        let evens: Vec<u64> = (0..3000).filter(|i| i % 2 == 0).collect();
+        assert_eq!(evens[..1000].to_vec(), rcv.recv_batch(1000, Duration::from_millis(0)));
+        assert_eq!(evens[1000..].to_vec(), rcv.try_iter().collect::<Vec<u64>>());
+        let metrics = rcv.metrics();
+        assert_eq!(1500, metrics.total_filtered);
+        assert_eq!(1500, metrics.total_dequeued);
+
+        let mut rcv = rcv.into_inner();
+        snd.send(1);
+        assert_eq!(Some(1), rcv.try_iter().next());
+    }
+
+    #[test]
+    fn undecodable_records_are_dead_lettered() {
+        use std::fs;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let dead = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("dead_letter", dir.path())
+            .dead_letter_dir(dead.path())
+            .build()
+            .unwrap();
+        // The second 1024 are paged out to queue file 0.
+        let items: Vec<String> = (0..2048).map(|i| format!("item{:04}", i)).collect();
+        for item in &items {
+            snd.send(item.clone());
+        }
+        let segment = dir.path().join("dead_letter").join("0");
+        let mut bytes = fs::read(&segment).unwrap();
+        let at = bytes.windows(8).position(|w| w == b"item1500").unwrap();
+        bytes[at] = 0xff;
+        fs::write(&segment, bytes).unwrap();
+
+        let got: Vec<String> = rcv.try_iter().collect();
+        let mut want = items.clone();
+        want.remove(1500);
+        assert_eq!(want, got);
+        assert_eq!(1, rcv.metrics().deserialize_failures);
+        assert_eq!(1, rcv.metrics().total_dead_lettered);
+
+        let letters = dead_letter::read(&dead.path().join("dead_letter").join("dead-letters"));
+        let letters = letters.unwrap();
+        assert_eq!(1, letters.len());
+        assert_eq!(1500, letters[0].seq);
+        assert!(!letters[0].error.is_empty());
+        assert!(letters[0].payload.ends_with(b"\xfftem1500"));
+    }
+
+    #[test]
+    fn decode_errors_locate_the_record() {
+        use std::error::Error;
+        use std::fs;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel("decode_error", dir.path()).unwrap();
+        for i in 0..2048 {
+            snd.send(format!("item{:04}", i));
+        }
+        let segment = dir.path().join("decode_error").join("0");
+        let mut bytes = fs::read(&segment).unwrap();
+        let at = bytes.windows(8).position(|w| w == b"item1500").unwrap();
+        bytes[at] = 0xff;
+        fs::write(&segment, &bytes).unwrap();
+
+        for i in 0..1500 {
+            assert_eq!(Some(format!("item{:04}", i)), rcv.try_recv().unwrap());
+        }
+        let err = match rcv.try_recv() {
+            Err(super::RecvError::Decode(e)) => e,
+            other => panic!("unexpected receive {:?}", other),
+        };
+        assert_eq!("decode_error", err.channel);
+        assert_eq!(0, err.segment);
+        assert_eq!(1500, err.seq);
+        let offset = err.offset as usize;
+        let len = ::receiver::u8tou32abe(&bytes[offset..offset + 4]) as usize;
+        assert!(bytes[offset + 4..offset + 4 + len].ends_with(b"\xfftem1500"));
+        assert!(err.source().is_some());
+        assert!(format!("{}", err).contains("record 1500"));
+
+        assert_eq!(Some("item1501".to_string()), rcv.try_recv().unwrap());
+        assert_eq!(1, rcv.metrics().deserialize_failures);
+    }
+
+    #[test]
+    fn skipped_records_are_counted_and_reported() {
+        use std::fs;
+        use std::sync::{Arc, Mutex};
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let thr_seen = Arc::clone(&seen);
+        let (mut snd, mut rcv) = ChannelBuilder::new("skip_corrupt", dir.path())
+            .corruption_policy(CorruptionPolicy::Skip)
+            .on_event(move |ev| thr_seen.lock().unwrap().push(ev))
+            .build()
+            .unwrap();
+        let items: Vec<String> = (0..2048).map(|i| format!("item{:04}", i)).collect();
+        for item in &items {
+            snd.send(item.clone());
+        }
+        let segment = dir.path().join("skip_corrupt").join("0");
+        let mut bytes = fs::read(&segment).unwrap();
+        for name in &[&b"item1100"[..], &b"item1900"[..]] {
+            let at = bytes.windows(8).position(|w| w == *name).unwrap();
+            bytes[at] = 0xff;
+        }
+        fs::write(&segment, bytes).unwrap();
+
+        let got: Vec<String> = rcv.try_iter().collect();
+        let want: Vec<String> = items
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != 1100 && i != 1900)
+            .map(|(_, item)| item.clone())
+            .collect();
+        assert_eq!(want, got);
+        let metrics = rcv.metrics();
+        assert_eq!(2, metrics.deserialize_failures);
+        assert_eq!(0, metrics.total_dead_lettered);
+
+        let corrupt: Vec<QueueEvent> = seen
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .filter(|ev| matches!(*ev, QueueEvent::CorruptRecord { .. }))
+            .collect();
+        assert_eq!(
+            vec![
+                QueueEvent::CorruptRecord {
+                    seq: 1100,
+                    dead_lettered: false,
+                },
+                QueueEvent::CorruptRecord {
+                    seq: 1900,
+                    dead_lettered: false,
+                },
+            ],
+            corrupt
+        );
+    }
+
+    #[test]
+    fn peeked_items_stay_until_committed() {
+        use std::time::Duration;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel("peek", dir.path()).unwrap();
+        for i in 0..3u64 {
+            snd.send(i);
+        }
+        assert_eq!(0, *rcv.peek().unwrap());
+        rcv.peek().unwrap().abort();
+        assert_eq!(0, rcv.peek().unwrap().commit());
+        assert_eq!(1, *rcv.peek().unwrap());
+        assert_eq!(vec![1, 2], rcv.recv_batch(8, Duration::from_millis(0)));
+        assert!(rcv.peek().is_none());
+        assert_eq!(3, rcv.metrics().total_dequeued);
+    }
+
+    #[test]
+    fn items_carry_their_sender_and_send_time() {
+        use std::time::{Duration, SystemTime};
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("meta", dir.path())
+            .max_bytes(1024)
+            .record_provenance(true)
+            .build()
+            .unwrap();
+        let mut other = snd.clone();
+        assert_eq!((0, 1), (snd.id(), other.id()));
+        let before = SystemTime::now() - Duration::from_millis(1);
+        for i in 0..3000u64 {
+            if i % 2 == 0 {
+                snd.send(i);
+            } else {
+                other.send(i);
+            }
+        }
+        let after = SystemTime::now();
+        drop(rcv.peek());
+        for i in 0..3000u64 {
+            let (item, meta) = rcv.recv_with_meta().unwrap();
+            assert_eq!(i, item);
+            assert_eq!(Some((i % 2) as u32), meta.sender_id);
+            let enqueued = meta.enqueued.unwrap();
+            assert!(before <= enqueued && enqueued <= after);
+        }
+
+        let (mut snd, mut rcv) = channel("plain", dir.path()).unwrap();
+        snd.send(0u64);
+        let (_, meta) = rcv.recv_with_meta().unwrap();
+        assert_eq!(None, meta.sender_id);
+        assert_eq!(RecordMeta::default(), meta);
+    }
+
+    #[test]
+    fn sequenced_senders_merge_the_same_however_they_interleave() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let mut merges = Vec::new();
+        // The same two streams sent interleaved two ways, once through memory
+        // and once through queue files
+        for &(name, disk_primary) in &[("memory", false), ("disk", true)] {
+            let (mut a, mut rcv) = ChannelBuilder::new(name, dir.path())
+                .sequence_senders(true)
+                .disk_primary(disk_primary)
+                .build()
+                .unwrap();
+            let mut b = a.clone();
+            // One Sender sends all of its items while the other sends every
+            // third, then the other sends the rest
+            {
+                let (eager, lagging) = if disk_primary {
+                    ((&mut a, 0), (&mut b, 100))
+                } else {
+                    ((&mut b, 100), (&mut a, 0))
+                };
+                let mut lagged = 0..10u64;
+                for i in 0..10u64 {
+                    eager.0.send(eager.1 + i);
+                    if i % 3 == 0 {
+                        lagging.0.send(lagging.1 + lagged.next().unwrap());
+                    }
+                }
+                for i in lagged {
+                    lagging.0.send(lagging.1 + i);
+                }
+            }
+            drop((a, b));
+
+            let (first, meta) = rcv.recv_ordered_by_sender().unwrap();
+            assert_eq!(0, first);
+            assert_eq!((Some(0), Some(0)), (meta.sender_id, meta.sender_seq));
+            // A plain receive hands over the items already drawn, in merge
+            // order
+            assert_eq!(Some(100), rcv.try_recv().unwrap());
+            let mut merge = vec![0, 100];
+            while let Some((item, meta)) = rcv.recv_ordered_by_sender() {
+                assert_eq!(Some(item % 100), meta.sender_seq);
+                merge.push(item);
+            }
+            merges.push(merge);
+        }
+        let expected: Vec<u64> = (0..10u64).flat_map(|i| vec![i, 100 + i]).collect();
+        assert_eq!(vec![expected.clone(), expected], merges);
+    }
+
+    #[test]
+    fn receivers_report_how_stale_items_are() {
+        use std::time::Duration;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("stale", dir.path())
+            .ttl(Duration::from_secs(3600))
+            .build()
+            .unwrap();
+        assert_eq!(None, rcv.last_record_age());
+        for i in 0..3u64 {
+            snd.send(i);
+        }
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(Some(0), rcv.try_iter().next());
+        assert!(rcv.last_record_age().unwrap() >= Duration::from_millis(20));
+        let m = rcv.metrics();
+        assert_eq!(1, m.time_in_queue.count);
+        assert!(m.time_in_queue.p50 >= 20_000);
+        assert!(m.time_in_queue.p50 <= m.time_in_queue.p99);
+    }
+
+    #[test]
+    fn mapped_receiver_converts_every_path() {
+        use std::time::Duration;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, rcv) = ChannelBuilder::new("mapped", dir.path())
+            .max_bytes(1024)
+            .build()
+            .unwrap();
+        let mut rcv = rcv.map(|i: u64| format!("#{}", i));
+        for i in 0..3000u64 {
+            snd.send(i);
+        }
+        let all: Vec<String> = (0..3000).map(|i| format!("#{}", i)).collect();
+        assert_eq!(all[..1000].to_vec(), rcv.recv_batch(1000, Duration::from_millis(0)));
+        assert_eq!(Some("#1000".to_string()), rcv.try_iter().next());
+        drop(snd);
+        assert_eq!(all[1001..].to_vec(), rcv.into_iter().collect::<Vec<String>>());
+    }
+
+    #[test]
+    fn dropping_receiver_with_backlog_is_reported() {
+        use std::sync::{Arc, Mutex};
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let thr_seen = Arc::clone(&seen);
+        let (mut snd, mut rcv) = ChannelBuilder::new("drop_report", dir.path())
+            .max_bytes(128)
+            .on_event(move |ev| thr_seen.lock().unwrap().push(ev))
+            .build()
+            .unwrap();
+
+        for i in 0..4096u64 {
+            snd.send(i);
+        }
+        for _ in 0..96 {
+            rcv.try_iter().next();
+        }
+        drop(rcv);
+        let last = seen.lock().unwrap().last().cloned();
+        match last {
+            Some(QueueEvent::ReceiverDroppedWithBacklog {
+                remaining,
+                disk_bytes,
+                oldest_segment_age,
+            }) => {
+                assert_eq!(4000, remaining);
+                assert!(disk_bytes > 0);
+                assert!(oldest_segment_age.is_some());
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+
+    #[test]
+    fn dropping_drained_receiver_is_quiet() {
+        use std::sync::{Arc, Mutex};
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let thr_seen = Arc::clone(&seen);
+        let (mut snd, mut rcv) = ChannelBuilder::new("drop_quiet", dir.path())
+            .on_event(move |ev| thr_seen.lock().unwrap().push(ev))
+            .build()
+            .unwrap();
+
+        snd.send(1u64);
+        assert_eq!(Some(1), rcv.try_iter().next());
+        drop(rcv);
+        assert_eq!(
+            vec![QueueEvent::RecoveredSegments { count: 1 }],
+            *seen.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn received_items_are_recycled_into_sends() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel("recycle", dir.path()).unwrap();
+
+        snd.send_with(|buf: &mut Vec<u8>| {
+            assert_eq!(0, buf.capacity());
+            buf.extend_from_slice(&[1; 4096]);
+        });
+        {
+            let mut item = rcv.recv_recycled().unwrap();
+            assert_eq!(4096, item.len());
+            item.clear();
+        }
+        snd.send_with(|buf: &mut Vec<u8>| {
+            assert!(buf.is_empty());
+            assert!(buf.capacity() >= 4096);
+            buf.push(2);
+        });
+        snd.send_with(|buf: &mut Vec<u8>| {
+            assert_eq!(0, buf.capacity());
+            buf.push(3);
+        });
+        assert_eq!(vec![2], rcv.recv_recycled().unwrap().into_inner());
+        assert_eq!(Some(vec![3]), rcv.try_iter().next());
+        assert!(rcv.recv_recycled().is_none());
+    }
+
+    #[test]
+    fn receivers_lend_items_out() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel_with_max_bytes("recv_ref", dir.path(), 4096).unwrap();
+        for i in 0..3000u64 {
+            snd.send(vec![i; 8]);
+        }
+        for i in 0..3000u64 {
+            let item = rcv.recv_ref().unwrap();
+            assert_eq!(8, item.len());
+            assert_eq!(i, item[0]);
+        }
+        assert!(rcv.recv_ref().is_none());
+        assert_eq!(3000, rcv.metrics().total_dequeued);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn readiness_fd_is_readable_while_items_wait() {
+        use std::fs::File;
+        use std::io::Read;
+        use std::os::unix::io::RawFd;
+        use std::sync::mpsc;
+        use std::thread;
+        use std::time::Duration;
+
+        // Whether the pipe holds a byte, read out of it through a second
+        // handle. The Receiver expects to read that byte itself as it next
+        // empties the channel, so this comes last.
+        fn readable(fd: RawFd) -> bool {
+            let mut pipe = File::open(format!("/proc/self/fd/{}", fd)).unwrap();
+            let (tx, rx) = mpsc::channel();
+            thread::spawn(move || {
+                let _ = tx.send(pipe.read(&mut [0]).unwrap_or(0) == 1);
+            });
+            rx.recv_timeout(Duration::from_millis(250)).unwrap_or(false)
+        }
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) =
+            channel_with_max_bytes("readiness_fd", dir.path(), 4096).unwrap();
+        let fd = rcv.readiness_fd().unwrap();
+        assert_eq!(fd, rcv.readiness_fd().unwrap());
+        for i in 0..2000u64 {
+            snd.send(i);
+        }
+        // Taking the last item, the Receiver reads the byte back. Were it
+        // not there this would block.
+        assert_eq!(2000, rcv.recv_batch(2000, Duration::from_secs(1)).len());
+        assert_eq!(None, rcv.try_recv().unwrap());
+        snd.send(5);
+        assert_eq!(Some(5), rcv.try_recv().unwrap());
+        rcv.pause();
+        snd.send(7);
+        assert_eq!(None, rcv.try_recv().unwrap());
+        rcv.resume();
+        assert!(readable(fd));
+    }
+
+    #[cfg(all(unix, feature = "mio"))]
+    #[test]
+    fn receiver_is_a_mio_source() {
+        use mio::{Events, Interest, Poll, Token};
+        use std::time::Duration;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel("mio_source", dir.path()).unwrap();
+        let mut poll = Poll::new().unwrap();
+        let mut events = Events::with_capacity(4);
+        poll.registry().register(&mut rcv, Token(7), Interest::READABLE).unwrap();
+        poll.poll(&mut events, Some(Duration::from_millis(10))).unwrap();
+        assert!(events.is_empty());
+
+        snd.send(1u64);
+        snd.send(2u64);
+        poll.poll(&mut events, Some(Duration::from_secs(1))).unwrap();
+        let tokens: Vec<Token> = events.iter().map(|ev| ev.token()).collect();
+        assert_eq!(vec![Token(7)], tokens);
+        let received: Vec<u64> = rcv.try_iter().collect();
+        assert_eq!(vec![1, 2], received);
+
+        snd.send(3u64);
+        poll.poll(&mut events, Some(Duration::from_secs(1))).unwrap();
+        assert!(events.iter().any(|ev| ev.token() == Token(7) && ev.is_readable()));
+        assert_eq!(Some(3), rcv.try_recv().unwrap());
+        poll.registry().deregister(&mut rcv).unwrap();
+        snd.send(4u64);
+        poll.poll(&mut events, Some(Duration::from_millis(10))).unwrap();
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn send_after_holds_item_until_due() {
+        use std::thread;
+        use std::time::Duration;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel("send_after", dir.path()).unwrap();
+
+        snd.send_after(3u64, Duration::from_millis(100));
+        snd.send_after(2u64, Duration::from_millis(50));
+        snd.send(1u64);
+        assert_eq!(Some(1), rcv.try_iter().next());
+        assert_eq!(None, rcv.try_iter().next());
+
+        thread::sleep(Duration::from_millis(150));
+        snd.send(4u64);
+        assert_eq!(Some(2), rcv.try_iter().next());
+        assert_eq!(Some(3), rcv.try_iter().next());
+        assert_eq!(Some(4), rcv.try_iter().next());
+        assert_eq!(None, rcv.try_iter().next());
+        assert_eq!(0, rcv.metrics().in_memory_depth);
+    }
+
+    #[test]
+    fn delayed_items_wake_waiters_and_outlive_the_channel() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::task::{Wake, Waker};
+        use std::fs;
+        use std::thread;
+        use std::time::{Duration, Instant};
+        use testing::ManualClock;
+        use Select;
+
+        struct Count(AtomicUsize);
+
+        impl Wake for Count {
+            fn wake(self: Arc<Self>) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let delayed = dir.path().join("delayed").join(layout::DELAYED_FILE);
+        {
+            let (mut snd, mut rcv) = channel::<u64>("delayed", dir.path()).unwrap();
+            snd.send_after(1, Duration::from_millis(20));
+            snd.send_after(2, Duration::from_millis(40));
+            snd.send_after(3, Duration::from_secs(3600));
+            assert!(delayed.is_file());
+            assert_eq!(0, rcv.metrics().in_memory_depth);
+
+            // The delay timer wakes a registered waker
+            let count = Arc::new(Count(AtomicUsize::new(0)));
+            rcv.register_waker(Waker::from(Arc::clone(&count)));
+            assert_eq!(0, count.0.load(Ordering::SeqCst));
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while count.0.load(Ordering::SeqCst) == 0 && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(1));
+            }
+            assert_eq!(1, count.0.load(Ordering::SeqCst));
+            assert_eq!(Some(1), rcv.try_iter().next());
+
+            // A Select waits no longer than the next item's due time
+            let mut sel = Select::new();
+            let idx = sel.add(&rcv);
+            assert_eq!(Some(idx), sel.ready_timeout(Duration::from_secs(5)));
+            assert_eq!(Some(2), rcv.try_iter().next());
+            assert_eq!(None, rcv.try_iter().next());
+        }
+
+        let clock = Arc::new(ManualClock::new());
+        // The delay timer may hold the channel a moment longer as it looks
+        // at it, keeping the directory locked
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let (_snd, mut rcv) = loop {
+            match ChannelBuilder::new("delayed", dir.path())
+                .clock(clock.clone())
+                .build::<u64>()
+            {
+                Err(super::Error::AlreadyLocked) if Instant::now() < deadline => {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                built => break built.unwrap(),
+            }
+        };
+        assert_eq!(None, rcv.try_iter().next());
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(Some(3), rcv.try_iter().next());
+        assert_eq!(0, fs::metadata(&delayed).unwrap().len());
+    }
+
+    #[test]
+    fn expired_items_are_dropped_and_counted() {
+        use std::sync::Arc;
+        use std::time::Duration;
+        use testing::ManualClock;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let (mut snd, mut rcv) = ChannelBuilder::new("ttl", dir.path())
+            .max_bytes(4096)
+            .ttl(Duration::from_millis(500))
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+
+        for i in 0..4096u64 {
+            snd.send(i);
+        }
+        clock.advance(Duration::from_millis(750));
+        for i in 4096..8192u64 {
+            snd.send(i);
+        }
+        for i in 4096..8192u64 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        assert_eq!(None, rcv.try_iter().next());
+        let m = rcv.metrics();
+        assert_eq!(4096, m.total_expired);
+        assert_eq!(4096, m.total_dequeued);
+        assert_eq!(0, m.in_memory_depth);
+    }
+
+    #[test]
+    fn reopening_with_other_record_fields_is_refused() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        drop(ChannelBuilder::new("framed", dir.path()).build::<u64>().unwrap());
+        let traced = ChannelBuilder::new("framed", dir.path())
+            .record_provenance(true)
+            .build::<u64>();
+        assert_eq!(Err(super::Error::MetadataMismatch), traced.map(|_| ()));
+        assert!(ChannelBuilder::new("framed", dir.path()).build::<u64>().is_ok());
+    }
+
+    #[test]
+    fn send_times_are_stamped_when_asked() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("stamped", dir.path())
+            .max_bytes(4096)
+            .stamp_send_times(true)
+            .build()
+            .unwrap();
+        for i in 0..2048u64 {
+            snd.send(i);
+        }
+        // Each record paged out is a length, the send time and the item
+        assert_eq!(0, (snd.metrics().disk_bytes - layout::SEGMENT_HEADER_LEN as u64) % (4 + 8 + 8));
+        let (item, meta) = rcv.recv_with_meta().unwrap();
+        assert_eq!(0, item);
+        assert!(meta.enqueued.is_some());
+        drop((snd, rcv));
+
+        let plain = ChannelBuilder::new("stamped", dir.path()).build::<u64>();
+        assert_eq!(Err(super::Error::MetadataMismatch), plain.map(|_| ()));
+    }
+
+    #[test]
+    fn manual_clock_drives_ttl_and_delays() {
+        use std::sync::Arc;
+        use std::time::Duration;
+        use testing::ManualClock;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let (mut snd, mut rcv) = ChannelBuilder::new("manual_clock", dir.path())
+            .max_bytes(4096)
+            .ttl(Duration::from_secs(60))
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+
+        for i in 0..2048u64 {
+            snd.send(i);
+        }
+        snd.send_after(9999u64, Duration::from_secs(90));
+        clock.advance(Duration::from_secs(61));
+        for i in 2048..4096u64 {
+            snd.send(i);
+        }
+        for i in 2048..4096u64 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        assert_eq!(None, rcv.try_iter().next());
+        assert!(rcv.last_record_age().unwrap() < Duration::from_secs(1));
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(Some(9999), rcv.try_iter().next());
+        let m = rcv.metrics();
+        assert_eq!(2048, m.total_expired);
+        assert_eq!(2049, m.total_dequeued);
+    }
+
+    #[test]
+    fn sealed_segments_move_to_archive() {
+        use std::fs;
+        use std::time::Duration;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let archive = tempdir::TempDir::new("hopper-archive").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("archive", dir.path())
+            .max_bytes(128)
+            .archive_dir(archive.path())
+            .build()
+            .unwrap();
+
+        for i in 0..4096u64 {
+            snd.send(i);
+        }
+        // Sealed files are moved in the background, and into place by a flush
+        let mut staged = 0;
+        for _ in 0..5000 {
+            snd.flush().unwrap();
+            staged = super::private::segment_ids(&dir.path().join("archive"), None).len();
+            if staged == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        let archived = super::private::segment_ids(&archive.path().join("archive"), None).len();
+        assert!(archived > 0);
+        assert_eq!(1, staged);
+        assert_eq!(archived + 1, snd.metrics().segments_on_disk);
+
+        for i in 0..4096u64 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        assert_eq!(None, rcv.try_iter().next());
+        assert_eq!(0, fs::read_dir(archive.path().join("archive")).unwrap().count());
+    }
+
+    #[test]
+    fn consumed_segments_are_retained_by_policy() {
+        use std::fs;
+        use std::time::Duration;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("retained", dir.path())
+            .max_bytes(128)
+            .keep_bytes(1024)
+            .build()
+            .unwrap();
+        for i in 0..4096u64 {
+            snd.send(i);
+        }
+        for i in 0..4096u64 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        let retained = dir.path().join("retained").join("retained");
+        let bytes: u64 = super::private::segment_ids(&retained, None)
+            .into_iter()
+            .map(|id| fs::metadata(retained.join(format!("{}", id))).unwrap().len())
+            .sum();
+        assert!(bytes > 0);
+        assert!(bytes <= 1024);
+        assert_eq!(1, rcv.metrics().segments_on_disk);
+
+        let (mut snd, mut rcv) = ChannelBuilder::new("expiring", dir.path())
+            .max_bytes(128)
+            .keep_for(Duration::from_millis(500))
+            .build()
+            .unwrap();
+        for i in 0..2048u64 {
+            snd.send(i);
+        }
+        for i in 0..2048u64 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        let retained = dir.path().join("expiring").join("retained");
+        assert!(fs::read_dir(&retained).unwrap().count() > 0);
+        thread::sleep(Duration::from_millis(1500));
+        assert_eq!(0, fs::read_dir(&retained).unwrap().count());
+    }
+
+    #[test]
+    fn seek_replays_retained_segments() {
+        use std::io::ErrorKind;
+        use std::sync::Arc;
+        use std::time::Duration;
+        use clock::Clock;
+        use testing::ManualClock;
+        use {Receiver, SeekTarget};
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let (mut snd, mut rcv) = ChannelBuilder::new("seek", dir.path())
+            .max_bytes(128)
+            .keep_for(Duration::from_secs(3600))
+            .record_provenance(true)
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+        for i in 0..2048u64 {
+            snd.send(i);
+        }
+        clock.advance(Duration::from_secs(10));
+        let later = clock.wall();
+        for i in 2048..4096u64 {
+            snd.send(i);
+        }
+        for i in 0..3000u64 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        // The first 1024 items were delivered from memory, so only those
+        // paged out after them are replayed, and then the Receiver carries
+        // on from where it was.
+        let replay = |rcv: &mut Receiver<u64>, target| {
+            rcv.seek(target).unwrap();
+            let mut replayed = Vec::new();
+            loop {
+                match rcv.try_iter().next() {
+                    Some(3000) => return replayed,
+                    Some(i) => replayed.push(i),
+                    None => panic!("replay ended early"),
+                }
+            }
+        };
+        assert_eq!((1024..3000).collect::<Vec<u64>>(), replay(&mut rcv, SeekTarget::Beginning));
+        rcv.seek(SeekTarget::Sequence(500)).unwrap();
+        let replayed: Vec<u64> = rcv.try_iter().take(2).collect();
+        assert_eq!(vec![1524, 1525], replayed);
+        let replayed = replay(&mut rcv, SeekTarget::Timestamp(later));
+        assert_eq!((2048..3000).collect::<Vec<u64>>(), replayed);
+
+        let (_snd, mut rcv) = channel::<u64>("unretained", dir.path()).unwrap();
+        let refused = rcv.seek(SeekTarget::Beginning).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, refused.kind());
+    }
+
+    #[test]
+    fn seek_reads_from_the_indexed_record() {
+        use std::fs;
+        use std::sync::Arc;
+        use std::time::Duration;
+        use clock::Clock;
+        use index;
+        use testing::ManualClock;
+        use SeekTarget;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let (mut snd, mut rcv) = ChannelBuilder::new("indexed", dir.path())
+            .max_bytes(64 * 1024)
+            .keep_for(Duration::from_secs(3600))
+            .stamp_send_times(true)
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+        for i in 0..2048u64 {
+            snd.send(i);
+        }
+        clock.advance(Duration::from_secs(10));
+        let later = clock.wall();
+        for i in 2048..8192u64 {
+            snd.send(i);
+        }
+        for i in 0..7000u64 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+
+        // Spoil the records ahead of the entries the seeks below land on, so
+        // that only a seek that goes by the index reads past them.
+        let retained = dir.path().join("indexed").join("retained").join("0");
+        let entries = index::read(&retained).unwrap().entries().to_vec();
+        assert!(entries.len() > 8);
+        let mut buf = fs::read(&retained).unwrap();
+        for byte in &mut buf[entries[1].offset as usize..entries[7].offset as usize] {
+            *byte = 0xff;
+        }
+        fs::remove_file(&retained).unwrap();
+        fs::write(&retained, &buf).unwrap();
+
+        // Items were paged out from the 1025th on, so 2000 places on is 3024.
+        rcv.seek(SeekTarget::Sequence(2000)).unwrap();
+        assert_eq!(Some(3024), rcv.try_iter().next());
+        rcv.seek(SeekTarget::Timestamp(later)).unwrap();
+        assert_eq!(Some(2048), rcv.try_iter().next());
+    }
+
+    #[test]
+    fn exported_snapshot_holds_backlog_in_order() {
+        use bincode;
+        use snapshot::{crc32, MAGIC, VERSION};
+        use std::fs;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("export", dir.path())
+            .max_bytes(128)
+            .build()
+            .unwrap();
+        for i in 0..4096u64 {
+            snd.send(i);
+        }
+        for i in 0..1500u64 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+
+        let path = dir.path().join("export.snap");
+        assert_eq!(2596, rcv.export_snapshot(&path).unwrap());
+        let buf = fs::read(&path).unwrap();
+        let (body, crc) = buf.split_at(buf.len() - 4);
+        assert_eq!(&crc32(0, body).to_be_bytes(), crc);
+        assert_eq!(&MAGIC[..], &body[..8]);
+        assert_eq!(&VERSION.to_be_bytes(), &body[8..12]);
+        let name_len = u32::from_be_bytes([body[12], body[13], body[14], body[15]]) as usize;
+        assert_eq!(b"u64", &body[16..16 + name_len]);
+        let (mut records, count) = body[16 + name_len..].split_at(body.len() - 24 - name_len);
+        assert_eq!(&2596u64.to_be_bytes(), count);
+        for i in 1500..4096u64 {
+            assert_eq!(&[0, 0, 0, 8], &records[..4]);
+            let item: u64 = bincode::deserialize(&records[4..12]).unwrap();
+            assert_eq!(i, item);
+            records = &records[12..];
+        }
+        assert!(records.is_empty());
+
+        for i in 1500..4096u64 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        assert_eq!(None, rcv.try_iter().next());
+    }
+
+    #[test]
+    fn restored_channel_replays_exported_backlog() {
+        use super::Error;
+        use std::fs;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let path = dir.path().join("backlog.snap");
+        {
+            let (mut snd, mut rcv) = ChannelBuilder::new("origin", dir.path())
+                .max_bytes(128)
+                .build()
+                .unwrap();
+            for i in 0..3000u64 {
+                snd.send(i);
+            }
+            assert_eq!(Some(0), rcv.try_iter().next());
+            assert_eq!(2999, rcv.export_snapshot(&path).unwrap());
+        }
+
+        let (_snd, mut rcv) = ChannelBuilder::new("staging", dir.path())
+            .restore_from(&path)
+            .build::<u64>()
+            .unwrap();
+        for i in 1..3000u64 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        assert_eq!(None, rcv.try_iter().next());
+
+        let wrong_type = ChannelBuilder::new("wrong_type", dir.path())
+            .restore_from(&path)
+            .build::<u32>();
+        assert_eq!(Some(Error::InvalidSnapshot), wrong_type.err());
+        let mut damaged = fs::read(&path).unwrap();
+        let at = damaged.len() / 2;
+        damaged[at] ^= 1;
+        fs::write(&path, damaged).unwrap();
+        let damaged = ChannelBuilder::new("damaged", dir.path())
+            .restore_from(&path)
+            .build::<u64>();
+        assert_eq!(Some(Error::InvalidSnapshot), damaged.err());
+    }
+
+    #[test]
+    fn round_trip() {
+        fn rnd_trip(max_bytes: usize, evs: Vec<Vec<u32>>) -> TestResult {
+            let dir = tempdir::TempDir::new("hopper").unwrap();
+            let (mut snd, mut rcv) =
+                channel_with_max_bytes("round_trip_order_preserved", dir.path(), max_bytes)
+                    .unwrap();
+
+            for ev in evs.clone() {
+                snd.send(ev);
+            }
+
+            for ev in evs {
+                assert_eq!(Some(ev), rcv.try_iter().next());
+            }
+            TestResult::passed()
+        }
+        QuickCheck::new()
+            .tests(100)
+            .max_tests(1000)
+            .quickcheck(rnd_trip as fn(usize, Vec<Vec<u32>>) -> TestResult);
+    }
+
+    #[test]
+    fn round_trip_small_max_bytes() {
+        fn rnd_trip(evs: Vec<Vec<u32>>) -> TestResult {
+            println!("START {:?}", evs);
+            let max_bytes: usize = 128;
+            let dir = tempdir::TempDir::new("hopper").unwrap();
+            let (mut snd, mut rcv) =
+                channel_with_max_bytes("small_max_bytes", dir.path(), max_bytes).unwrap();
+
+            for ev in evs.clone() {
+                snd.send(ev);
+            }
+
+            let mut total = evs.len();
+            for ev in evs {
+                println!("REMAINING: {}", total);
+                assert_eq!(Some(ev), rcv.try_iter().next());
+                total -= 1;
+            }
+            TestResult::passed()
+        }
+        QuickCheck::new()
+            .tests(100)
+            .max_tests(1000)
+            .quickcheck(rnd_trip as fn(Vec<Vec<u32>>) -> TestResult);
+    }
+
+    #[test]
+    fn concurrent_snd_and_rcv_round_trip() {
+        let max_bytes: usize = 512;
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        println!("CONCURRENT SND_RECV TESTDIR: {:?}", dir);
+        let (snd, mut rcv) = channel_with_max_bytes(
+            "concurrent_snd_and_rcv_small_max_bytes",
+            dir.path(),
+            max_bytes,
+        ).unwrap();
+        let max_thrs = 32;
+        let max_sz = 1000;
+
+        // construct the payload to send repeatedly 'pyld' and the final payload
+        // the sender should receive
+        let mut tst_pylds = Vec::new();
+        for i in 0..(max_sz * max_thrs) {
+            tst_pylds.push(i);
+        }
+
+        let mut joins = Vec::new();
+
+        // start our receiver thread
+        joins.push(thread::spawn(move || {
+            // assert that we receive every element in tst_pylds by pulling a new
+            // value from the rcv, then marking it out of tst_pylds
+            for _ in 0..(max_sz * max_thrs) {
+                loop {
+                    if let Some(nxt) = rcv.try_iter().next() {
+                        let idx = tst_pylds.binary_search(&nxt).expect("DID NOT FIND ELEMENT");
+                        tst_pylds.remove(idx);
+                        break;
+                    }
+                }
+            }
+            assert!(tst_pylds.is_empty());
+        }));
+
+        // start all our sender threads and blast away
+        for i in 0..max_thrs {
+            let mut thr_snd = snd.clone();
+            joins.push(thread::spawn(move || {
+                let base = i * max_sz;
+                for p in 0..max_sz {
+                    thr_snd.send(base + p);
+                }
+            }));
+        }
+
+        // wait until the senders are for sure done
+        for jh in joins {
+            jh.join().expect("Uh oh, child thread paniced!");
+        }
+    }
+
+    #[test]
+    fn qc_concurrent_snd_and_rcv_round_trip() {
+        fn snd_rcv(evs: Vec<Vec<u32>>) -> TestResult {
+            let max_bytes: usize = 512;
+            let dir = tempdir::TempDir::new("hopper").unwrap();
+            println!("CONCURRENT SND_RECV TESTDIR: {:?}", dir);
+            let (snd, mut rcv) = channel_with_max_bytes(
+                "concurrent_snd_and_rcv_small_max_bytes",
+                dir.path(),
+                max_bytes,
+            ).unwrap();
+
+            let max_thrs = 32;
+
+            let mut joins = Vec::new();
+
+            // start our receiver thread
+            let total_pylds = evs.len() * max_thrs;
+            joins.push(thread::spawn(move || for _ in 0..total_pylds {
+                loop {
+                    if let Some(_) = rcv.try_iter().next() {
+                        break;
+                    }
+                }
+            }));
+
+            // start all our sender threads and blast away
+            for _ in 0..max_thrs {
+                let mut thr_snd = snd.clone();
+                let thr_evs = evs.clone();
+                joins.push(thread::spawn(move || for e in thr_evs {
+                    thr_snd.send(e);
+                }));
+            }
+
+            // wait until the senders are for sure done
+            for jh in joins {
+                jh.join().expect("Uh oh, child thread paniced!");
+            }
+            TestResult::passed()
+        }
+        QuickCheck::new()
+            .tests(100)
+            .max_tests(1000)
+            .quickcheck(snd_rcv as fn(Vec<Vec<u32>>) -> TestResult);
+    }
+
+    #[test]
+    fn closed_channel_drains_backlog_and_refuses_sends() {
+        use std::time::Duration;
+        use SendError;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel("close", dir.path()).unwrap();
+        // Enough to put items in memory, in the disk buffer and on disk
+        for i in 0..3000u64 {
+            snd.send(i);
+        }
+        assert!(!snd.is_closed());
+        rcv.close();
+        assert!(snd.is_closed());
+        snd.send(3000);
+        snd.send_after(3001, Duration::from_millis(0));
+        assert_eq!(Err(SendError::Closed(3002)), snd.try_send(3002));
+        assert_eq!((0, vec![3003]), snd.try_send_many(vec![3003]));
+
+        let got: Vec<u64> = rcv.try_iter().collect();
+        assert_eq!((0..3000).collect::<Vec<u64>>(), got);
+        assert_eq!(3000, snd.metrics().total_enqueued);
+        assert_eq!(0, snd.metrics().total_overflowed);
+    }
+
+    #[test]
+    fn flush_writes_out_the_disk_buffer() {
+        use std::fs;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel("flush", dir.path()).unwrap();
+        let on_disk = || -> u64 {
+            fs::read_dir(dir.path().join("flush"))
+                .unwrap()
+                .map(|entry| entry.unwrap())
+                .filter(|entry| entry.file_name().to_string_lossy().parse::<u64>().is_ok())
+                .map(|entry| entry.metadata().unwrap().len())
+                .sum()
+        };
+        // Past the in-memory tier but short of a full disk buffer
+        for i in 0..1500u64 {
+            snd.send(i);
+        }
+        let buffered = on_disk();
+        snd.flush().unwrap();
+        assert!(on_disk() > buffered);
+
+        let got: Vec<u64> = rcv.try_iter().collect();
+        assert_eq!((0..1500).collect::<Vec<u64>>(), got);
+    }
+
+    #[test]
+    fn soft_close_leaves_the_backlog_for_the_next_build() {
+        use std::sync::Arc;
+        use std::time::Duration;
+        use testing::ManualClock;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let build = || {
+            ChannelBuilder::new("close_after", dir.path())
+                .clock(clock.clone())
+                .build::<u64>()
+                .unwrap()
+        };
+        let (mut snd, mut rcv) = build();
+        for i in 0..3000u64 {
+            snd.send(i);
+        }
+        rcv.close_after(Duration::from_secs(5));
+        assert!(snd.is_closed());
+        snd.send(3000);
+        let first: Vec<u64> = rcv.try_iter().take(100).collect();
+        assert_eq!((0..100).collect::<Vec<u64>>(), first);
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(None, rcv.try_iter().next());
+        drop((snd, rcv));
+
+        let (snd, mut rcv) = build();
+        let rest: Vec<u64> = rcv.try_iter().collect();
+        assert_eq!((100..3000).collect::<Vec<u64>>(), rest);
+        assert!(!dir.path().join("close_after").join(layout::DRAINED_FILE).exists());
+        drop((snd, rcv));
+
+        // Dropped before its deadline, the Receiver leaves its backlog too
+        let (mut snd, mut rcv) = build();
+        snd.send(7);
+        rcv.close_after(Duration::from_secs(5));
+        drop((snd, rcv));
+        let (_snd, mut rcv) = build();
+        assert_eq!(vec![7], rcv.try_iter().collect::<Vec<u64>>());
+    }
+
+    // Panics while being paged out to disk, which it is if it arrives once the
+    // in-memory tier is full.
+    struct Bomb(u64);
+
+    impl ::serde::Serialize for Bomb {
+        fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+        where
+            S: ::serde::Serializer,
+        {
+            if self.0 == u64::MAX {
+                panic!("bomb went off");
+            }
+            s.serialize_u64(self.0)
+        }
+    }
+
+    impl<'de> ::serde::Deserialize<'de> for Bomb {
+        fn deserialize<D>(d: D) -> Result<Self, D::Error>
+        where
+            D: ::serde::Deserializer<'de>,
+        {
+            <u64 as ::serde::Deserialize>::deserialize(d).map(Bomb)
+        }
+    }
+
+    #[test]
+    fn panic_under_lock_does_not_poison_channel() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel("panic", dir.path()).unwrap();
+        for i in 0..1024 {
+            snd.send(Bomb(i));
+        }
+        let mut thr_snd = snd.clone();
+        let joined = thread::spawn(move || {
+            thr_snd.send(Bomb(u64::MAX));
+            for i in 0..1024 {
+                thr_snd.send(Bomb(5000 + i));
+            }
+        }).join();
+        assert!(joined.is_err());
+
+        // Only the item being paged out when the panic struck is lost.
+        snd.send(Bomb(9999));
+        let mut expected: Vec<u64> = (0..1024).chain(5000..6023).collect();
+        expected.push(9999);
+        let got: Vec<u64> = rcv.try_iter().map(|b| b.0).collect();
+        assert_eq!(expected, got);
+    }
+
+    #[test]
+    fn errors_are_std_errors() {
+        use std::error;
+        use super::Error;
+
+        let err: Box<dyn error::Error> = Box::new(Error::AlreadyLocked);
+        assert_eq!("channel directory is already open", err.to_string());
+        assert!(err.source().is_none());
     }
 }
@@ -0,0 +1,400 @@
+//! A queue for a single thread
+//!
+//! A regular channel shares its buffers between Senders and a Receiver behind
+//! a mutex, wakes the Receiver through a condvar and counts its metrics with
+//! atomics. A pipeline that sends and receives on one thread pays for all of
+//! that and uses none of it. A `local::Queue` is both ends of a channel in
+//! one handle, kept in a `RefCell`: it can be shared through an `Rc` between
+//! the stages of a pipeline but not sent to or shared with another thread.
+//!
+//! The queue pages to disk as a regular channel does. Its first 1024 items
+//! are held in memory and the rest written to queue files in the queue's
+//! directory, rotated once they reach `max_bytes`, until the queue has
+//! drained back to nothing on disk. Items are received in the order they
+//! were sent. Like a regular channel the queue keeps nothing across
+//! restarts: the queue files of a directory are cleared as it is opened.
+//!
+//! The items held in memory live in a `VecDeque` that is never grown. A
+//! queue opened with `queue_with_buffer` uses the one it is given, holding
+//! as many items in memory as it has capacity for, so an application can
+//! allocate the ring up front, from wherever its global allocator places
+//! it, and have it back with `Queue::into_buffer`.
+use bincode::{deserialize, serialize_into, Infinite};
+use layout::{self, RecordFraming};
+use private;
+use receiver::u8tou32abe;
+use sender::u32tou8abe;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use super::Error;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+// The number of items held in memory before the queue pages to disk.
+const IN_MEMORY_CAPACITY: usize = 1024;
+
+/// A single-threaded queue, see the module documentation
+pub struct Queue<T> {
+    name: String,
+    root: PathBuf,
+    inner: RefCell<Inner<T>>,
+    _lock: Option<fs::File>,
+}
+
+struct Inner<T> {
+    memory: VecDeque<T>,
+    capacity: usize,
+    max_bytes: usize,
+    writer: BufWriter<fs::File>,
+    write_seq: usize,
+    bytes_written: usize,
+    reader: Option<BufReader<fs::File>>,
+    read_seq: usize,
+    // Records written to queue files and not yet received
+    on_disk: usize,
+    encode_buf: Vec<u8>,
+}
+
+impl<T> fmt::Debug for Queue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("Queue")
+            .field("name", &self.name)
+            .field("root", &self.root)
+            .field("in_memory", &inner.memory.len())
+            .field("capacity", &inner.capacity)
+            .field("on_disk", &inner.on_disk)
+            .field("write_seq", &inner.write_seq)
+            .field("read_seq", &inner.read_seq)
+            .field("max_bytes", &inner.max_bytes)
+            .finish()
+    }
+}
+
+/// One line, for periodic logging: the items in memory and on disk and the
+/// queue files being written and read
+impl<T> fmt::Display for Queue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        write!(
+            f,
+            "{}: {} of {} in memory, {} on disk, writing file {}, reading file {}",
+            self.name,
+            inner.memory.len(),
+            inner.capacity,
+            inner.on_disk,
+            inner.write_seq,
+            inner.read_seq
+        )
+    }
+}
+
+/// Open the single-threaded queue `name` in `data_dir`
+///
+/// Queue files are rotated once they reach `max_bytes`. Returns
+/// `Error::AlreadyLocked` if the directory is open elsewhere, and
+/// `Error::MetadataMismatch` if it belongs to a channel of another type.
+pub fn queue<T>(name: &str, data_dir: &Path, max_bytes: usize) -> Result<Queue<T>, Error>
+where
+    T: Serialize + DeserializeOwned,
+{
+    let buffer = VecDeque::with_capacity(IN_MEMORY_CAPACITY);
+    open(name, data_dir, max_bytes, buffer, IN_MEMORY_CAPACITY)
+}
+
+/// Open the single-threaded queue `name` in `data_dir`, holding items in
+/// memory in `buffer`
+///
+/// As `queue`, but the queue holds up to `buffer.capacity()` items in memory,
+/// in `buffer` itself, before paging to disk. Items already in `buffer` are
+/// at the front of the queue.
+pub fn queue_with_buffer<T>(
+    name: &str,
+    data_dir: &Path,
+    max_bytes: usize,
+    buffer: VecDeque<T>,
+) -> Result<Queue<T>, Error>
+where
+    T: Serialize + DeserializeOwned,
+{
+    let capacity = buffer.capacity();
+    open(name, data_dir, max_bytes, buffer, capacity)
+}
+
+fn open<T>(
+    name: &str,
+    data_dir: &Path,
+    max_bytes: usize,
+    buffer: VecDeque<T>,
+    capacity: usize,
+) -> Result<Queue<T>, Error>
+where
+    T: Serialize + DeserializeOwned,
+{
+    let root = data_dir.join(name);
+    if !root.is_dir() {
+        fs::create_dir_all(&root).expect("could not create directory");
+    }
+    let lock = layout::lock(&root)?;
+    layout::claim(&root, ::std::any::type_name::<T>(), RecordFraming::default())?;
+    let ids = private::segment_ids(&root, None);
+    let seq_num = ids.iter().max().map_or(0, |max| max + 1);
+    for id in ids {
+        private::remove_segment(&root.join(format!("{}", id)))
+            .expect("could not remove queue file");
+    }
+    let (fp, header) = private::open_segment(&root.join(format!("{}", seq_num)))
+        .expect("could not open queue file");
+    Ok(Queue {
+        name: name.to_string(),
+        root: root,
+        inner: RefCell::new(Inner {
+            memory: buffer,
+            capacity: capacity,
+            max_bytes: max_bytes,
+            writer: BufWriter::new(fp),
+            write_seq: seq_num,
+            bytes_written: header as usize,
+            reader: None,
+            read_seq: seq_num,
+            on_disk: 0,
+            encode_buf: Vec::with_capacity(64),
+        }),
+        _lock: lock,
+    })
+}
+
+impl<T> Queue<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    /// Add `event` to the back of the queue
+    pub fn send(&self, event: T) {
+        let mut inner = self.inner.borrow_mut();
+        // Once an item has gone to disk every later item follows it there,
+        // until the disk is drained, so the memory tier is always the front.
+        if inner.on_disk == 0 && inner.memory.len() < inner.capacity {
+            inner.memory.push_back(event);
+            return;
+        }
+        inner.write(&self.root, &event);
+    }
+
+    /// Take the item at the front of the queue, if there is one
+    pub fn recv(&self) -> Option<T> {
+        let mut inner = self.inner.borrow_mut();
+        if let Some(event) = inner.memory.pop_front() {
+            return Some(event);
+        }
+        if inner.on_disk == 0 {
+            return None;
+        }
+        let record = inner.read(&self.root);
+        match deserialize(&record) {
+            Ok(event) => Some(event),
+            Err(e) => panic!("[local::Queue] failed to deserialize {:?}", e),
+        }
+    }
+
+    /// Return an iterator that takes items until the queue is empty
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter { queue: self }
+    }
+
+    /// Return the number of items waiting in the queue
+    pub fn len(&self) -> usize {
+        let inner = self.inner.borrow();
+        inner.memory.len() + inner.on_disk
+    }
+
+    /// Whether the queue is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return the name of the queue
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Close the queue, handing back the buffer its in-memory items are held
+    /// in
+    ///
+    /// The buffer holds the items still in memory. Items paged to disk are
+    /// left in the queue files, to be cleared when the directory is next
+    /// opened.
+    pub fn into_buffer(self) -> VecDeque<T> {
+        self.inner.into_inner().memory
+    }
+}
+
+impl<T> Inner<T>
+where
+    T: Serialize,
+{
+    // Page `event` out to the current queue file, moving on to the next once
+    // the file is full.
+    fn write(&mut self, root: &Path, event: &T) {
+        self.encode_buf.clear();
+        serialize_into(&mut self.encode_buf, event, Infinite).expect("could not serialize");
+        let pyld_sz_bytes: [u8; 4] = u32tou8abe(self.encode_buf.len() as u32);
+        let header = [
+            pyld_sz_bytes[3],
+            pyld_sz_bytes[2],
+            pyld_sz_bytes[1],
+            pyld_sz_bytes[0],
+        ];
+        let len = header.len() + self.encode_buf.len();
+        if self.bytes_written > layout::SEGMENT_HEADER_LEN
+            && self.bytes_written + len > self.max_bytes
+        {
+            self.writer.flush().expect("could not flush queue file");
+            self.write_seq += 1;
+            let path = root.join(format!("{}", self.write_seq));
+            let (fp, header) = private::open_segment(&path).expect("could not open queue file");
+            self.writer = BufWriter::new(fp);
+            self.bytes_written = header as usize;
+        }
+        self.writer.write_all(&header).expect("could not write queue file");
+        self.writer.write_all(&self.encode_buf).expect("could not write queue file");
+        self.bytes_written += len;
+        self.on_disk += 1;
+    }
+
+    // Read the next record from the queue files. There must be one: the
+    // caller has checked `on_disk`.
+    fn read(&mut self, root: &Path) -> Vec<u8> {
+        loop {
+            if self.read_seq == self.write_seq {
+                self.writer.flush().expect("could not flush queue file");
+            }
+            if self.reader.is_none() {
+                let path = root.join(format!("{}", self.read_seq));
+                let fp = fs::File::open(&path).expect("could not open queue file");
+                let mut fp = BufReader::new(fp);
+                layout::skip_header(&mut fp).expect("could not read queue file header");
+                self.reader = Some(fp);
+            }
+            let mut sz_buf = [0; 4];
+            let read = self.reader
+                .as_mut()
+                .expect("reader is open")
+                .read_exact(&mut sz_buf);
+            match read {
+                Ok(()) => {}
+                // Only a queue file the writer has moved on from runs out.
+                Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    assert!(self.read_seq < self.write_seq, "queue file ended early");
+                    self.reader = None;
+                    private::remove_segment(&root.join(format!("{}", self.read_seq)))
+                        .expect("could not remove queue file");
+                    self.read_seq += 1;
+                    continue;
+                }
+                Err(e) => panic!("[local::Queue] could not read queue file {:?}", e),
+            }
+            let mut record = vec![0; u8tou32abe(&sz_buf) as usize];
+            self.reader
+                .as_mut()
+                .expect("reader is open")
+                .read_exact(&mut record)
+                .expect("could not read queue file");
+            self.on_disk -= 1;
+            return record;
+        }
+    }
+}
+
+/// An iterator over the items of a `Queue`, see `Queue::iter`
+#[derive(Debug)]
+pub struct Iter<'a, T: 'a> {
+    queue: &'a Queue<T>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.queue.recv()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn items_page_to_disk_and_back_in_order() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let q = queue::<u64>("local", dir.path(), 256).unwrap();
+        for i in 0..5000u64 {
+            q.send(i);
+        }
+        assert_eq!(5000, q.len());
+        assert!(private::segment_ids(&dir.path().join("local"), None).len() > 1);
+        for i in 0..3000u64 {
+            assert_eq!(Some(i), q.recv());
+        }
+        // Sent while the disk holds items, so behind them.
+        for i in 5000..6000u64 {
+            q.send(i);
+        }
+        let rest: Vec<u64> = q.iter().collect();
+        assert_eq!((3000..6000).collect::<Vec<u64>>(), rest);
+        assert!(q.is_empty());
+        assert_eq!(1, private::segment_ids(&dir.path().join("local"), None).len());
+
+        q.send(7);
+        assert_eq!(Some(7), q.recv());
+        assert_eq!(None, q.recv());
+    }
+
+    #[test]
+    fn given_buffers_hold_the_memory_tier() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let mut buffer = VecDeque::with_capacity(16);
+        buffer.push_back(100u64);
+        let capacity = buffer.capacity();
+        let q = queue_with_buffer("local_buffer", dir.path(), 256, buffer).unwrap();
+        for i in 0..200u64 {
+            q.send(i);
+        }
+        assert_eq!(Some(100), q.recv());
+        for i in 0..100u64 {
+            assert_eq!(Some(i), q.recv());
+        }
+        let buffer = q.into_buffer();
+        assert!(buffer.is_empty());
+        assert_eq!(capacity, buffer.capacity());
+    }
+
+    #[test]
+    fn stages_share_a_queue() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let q = Rc::new(queue::<String>("local_rc", dir.path(), 1024).unwrap());
+        let produce = Rc::clone(&q);
+        for i in 0..2000 {
+            produce.send(format!("#{}", i));
+        }
+        assert_eq!(Some("#0".to_string()), q.recv());
+        assert_eq!(1999, q.iter().count());
+        match queue::<String>("local_rc", dir.path(), 1024) {
+            Err(Error::AlreadyLocked) => {}
+            other => panic!("expected AlreadyLocked, got {:?}", other.map(|_| ())),
+        }
+        drop(produce);
+        drop(q);
+        let q = queue::<String>("local_rc", dir.path(), 1024).unwrap();
+        assert!(q.is_empty());
+    }
+}
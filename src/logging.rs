@@ -0,0 +1,441 @@
+//! A channel as the durable buffer behind a logger
+//!
+//! With the `log` feature `LogSink` implements `log::Log`, and with the
+//! `slog` feature `SlogDrain` implements `slog::Drain`. Either turns each
+//! record it is handed into a `LogRecord` and sends it over a
+//! `Sender<LogRecord>`, to be paged out to disk with the channel's backlog
+//! while whatever ships the logs drains the Receiver at its own pace. A slow
+//! or unreachable destination then leaves the records on disk rather than
+//! holding up the threads that log.
+//!
+//! Sends to most channels never wait: a full in-memory tier pages out. A
+//! channel bounded with `ChannelBuilder::memory_only` or
+//! `ChannelBuilder::max_disk_bytes` does what its `OverflowPolicy` says once
+//! full. Leave it at `OverflowPolicy::Fail`, the default, for a logging path
+//! that never blocks: the records a full channel refuses are dropped and
+//! counted by `dropped`. `OverflowPolicy::Block` has the logging threads wait
+//! for the shipper instead, losing nothing.
+//!
+//! # Example
+//! ```
+//! # #[cfg(feature = "log")]
+//! extern crate log;
+//! extern crate tempdir;
+//! extern crate hopper;
+//!
+//! # #[cfg(feature = "log")]
+//! # fn main() {
+//! use hopper::logging::{LogLevel, LogRecord, LogSink};
+//! use log::Log;
+//!
+//! let dir = tempdir::TempDir::new("hopper").unwrap();
+//! let (snd, mut rcv) = hopper::ChannelBuilder::new("logs", dir.path())
+//!     .max_disk_bytes(1 << 30)
+//!     .build::<LogRecord>()
+//!     .unwrap();
+//! let sink = LogSink::new(snd, log::LevelFilter::Info);
+//! sink.log(
+//!     &log::Record::builder()
+//!         .level(log::Level::Warn)
+//!         .target("app")
+//!         .args(format_args!("disk {}% full", 91))
+//!         .build(),
+//! );
+//! sink.flush();
+//!
+//! let record = rcv.try_iter().next().unwrap();
+//! assert_eq!(LogLevel::Warn, record.level);
+//! assert_eq!("disk 91% full", record.message);
+//! # }
+//! # #[cfg(not(feature = "log"))]
+//! # fn main() {}
+//! ```
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+#[cfg(feature = "slog")]
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use super::Sender;
+use sync::{self, Mutex};
+
+/// The severity of a `LogRecord`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LogLevel {
+    /// slog's `Critical`, above `Error`
+    Critical,
+    /// `Error`
+    Error,
+    /// `Warn`, slog's `Warning`
+    Warn,
+    /// `Info`
+    Info,
+    /// `Debug`
+    Debug,
+    /// `Trace`
+    Trace,
+}
+
+impl LogLevel {
+    fn code(self) -> u8 {
+        match self {
+            LogLevel::Critical => 0,
+            LogLevel::Error => 1,
+            LogLevel::Warn => 2,
+            LogLevel::Info => 3,
+            LogLevel::Debug => 4,
+            LogLevel::Trace => 5,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<LogLevel> {
+        Some(match code {
+            0 => LogLevel::Critical,
+            1 => LogLevel::Error,
+            2 => LogLevel::Warn,
+            3 => LogLevel::Info,
+            4 => LogLevel::Debug,
+            5 => LogLevel::Trace,
+            _ => return None,
+        })
+    }
+}
+
+/// A logger's record, as carried over the channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    /// The record's severity
+    pub level: LogLevel,
+    /// log's target, or slog's tag where it has one and its module otherwise
+    pub target: String,
+    /// The message, formatted
+    pub message: String,
+    /// The module the record was logged from, if known
+    pub module_path: Option<String>,
+    /// The source file the record was logged from, if known
+    pub file: Option<String>,
+    /// The line the record was logged from, if known
+    pub line: Option<u32>,
+    /// When the record was logged, in milliseconds since the UNIX epoch
+    pub logged_at_ms: u64,
+    /// The record's key-value pairs, values formatted: slog's, the record's
+    /// own and then the logger's. Empty from `log`.
+    pub fields: Vec<(String, String)>,
+}
+
+// The fields of a LogRecord in the order they are encoded, level as its code
+type Encoded = (
+    u8,
+    String,
+    String,
+    Option<String>,
+    Option<String>,
+    Option<u32>,
+    u64,
+    Vec<(String, String)>,
+);
+
+impl Serialize for LogRecord {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        (
+            self.level.code(),
+            &self.target,
+            &self.message,
+            &self.module_path,
+            &self.file,
+            self.line,
+            self.logged_at_ms,
+            &self.fields,
+        ).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for LogRecord {
+    fn deserialize<D>(deserializer: D) -> Result<LogRecord, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let (level, target, message, module_path, file, line, logged_at_ms, fields) =
+            Encoded::deserialize(deserializer)?;
+        let level = LogLevel::from_code(level).ok_or_else(|| {
+            de::Error::invalid_value(de::Unexpected::Unsigned(level.into()), &"a log level")
+        })?;
+        Ok(LogRecord {
+            level: level,
+            target: target,
+            message: message,
+            module_path: module_path,
+            file: file,
+            line: line,
+            logged_at_ms: logged_at_ms,
+            fields: fields,
+        })
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// The Sender the adapters share between the threads that log, and the count
+// of records the channel refused.
+#[derive(Debug)]
+struct Shared {
+    sender: Mutex<Sender<LogRecord>>,
+    dropped: AtomicU64,
+}
+
+impl Shared {
+    fn new(sender: Sender<LogRecord>) -> Shared {
+        Shared {
+            sender: Mutex::new(sender),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    fn send(&self, record: LogRecord) {
+        if sync::lock(&self.sender).try_send(record).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A `log::Log` sending each record over a channel
+///
+/// Requires the `log` feature. See the module documentation.
+#[cfg(feature = "log")]
+#[derive(Debug)]
+pub struct LogSink {
+    shared: Shared,
+    level: ::log::LevelFilter,
+}
+
+#[cfg(feature = "log")]
+impl LogSink {
+    /// A sink sending the records of `level` and more severe over `sender`
+    pub fn new(sender: Sender<LogRecord>, level: ::log::LevelFilter) -> LogSink {
+        LogSink {
+            shared: Shared::new(sender),
+            level: level,
+        }
+    }
+
+    /// Install the sink as the process's logger, setting log's maximum level
+    /// to the sink's
+    ///
+    /// Fails if a logger has already been installed.
+    pub fn install(self) -> Result<(), ::log::SetLoggerError> {
+        let level = self.level;
+        ::log::set_boxed_logger(Box::new(self))?;
+        ::log::set_max_level(level);
+        Ok(())
+    }
+
+    /// The number of records the channel refused, see the module
+    /// documentation
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(feature = "log")]
+impl From<::log::Level> for LogLevel {
+    fn from(level: ::log::Level) -> LogLevel {
+        match level {
+            ::log::Level::Error => LogLevel::Error,
+            ::log::Level::Warn => LogLevel::Warn,
+            ::log::Level::Info => LogLevel::Info,
+            ::log::Level::Debug => LogLevel::Debug,
+            ::log::Level::Trace => LogLevel::Trace,
+        }
+    }
+}
+
+#[cfg(feature = "log")]
+impl ::log::Log for LogSink {
+    fn enabled(&self, metadata: &::log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &::log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        self.shared.send(LogRecord {
+            level: record.level().into(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+            module_path: record.module_path().map(str::to_string),
+            file: record.file().map(str::to_string),
+            line: record.line(),
+            logged_at_ms: now_ms(),
+            fields: Vec::new(),
+        });
+    }
+
+    /// Hand any records the Sender has staged to the channel
+    fn flush(&self) {
+        let _ = sync::lock(&self.shared.sender).flush();
+    }
+}
+
+/// A `slog::Drain` sending each record over a channel
+///
+/// Requires the `slog` feature. See the module documentation. The drain
+/// never fails: records the channel refuses are counted by `dropped`.
+#[cfg(feature = "slog")]
+#[derive(Debug)]
+pub struct SlogDrain {
+    shared: Shared,
+}
+
+#[cfg(feature = "slog")]
+impl SlogDrain {
+    /// A drain sending every record over `sender`
+    ///
+    /// Filter by level with slog's own `Drain::filter_level`.
+    pub fn new(sender: Sender<LogRecord>) -> SlogDrain {
+        SlogDrain {
+            shared: Shared::new(sender),
+        }
+    }
+
+    /// The number of records the channel refused, see the module
+    /// documentation
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Hand any records the Sender has staged to the channel
+    pub fn flush(&self) {
+        let _ = sync::lock(&self.shared.sender).flush();
+    }
+}
+
+#[cfg(feature = "slog")]
+impl From<::slog::Level> for LogLevel {
+    fn from(level: ::slog::Level) -> LogLevel {
+        match level {
+            ::slog::Level::Critical => LogLevel::Critical,
+            ::slog::Level::Error => LogLevel::Error,
+            ::slog::Level::Warning => LogLevel::Warn,
+            ::slog::Level::Info => LogLevel::Info,
+            ::slog::Level::Debug => LogLevel::Debug,
+            ::slog::Level::Trace => LogLevel::Trace,
+        }
+    }
+}
+
+// Gathers a slog record's key-value pairs, values formatted
+#[cfg(feature = "slog")]
+struct Fields(Vec<(String, String)>);
+
+#[cfg(feature = "slog")]
+impl ::slog::Serializer for Fields {
+    fn emit_arguments(&mut self, key: ::slog::Key, val: &fmt::Arguments) -> ::slog::Result {
+        self.0.push((key.to_string(), val.to_string()));
+        Ok(())
+    }
+}
+
+#[cfg(feature = "slog")]
+impl ::slog::Drain for SlogDrain {
+    type Ok = ();
+    type Err = ::slog::Never;
+
+    fn log(
+        &self,
+        record: &::slog::Record,
+        values: &::slog::OwnedKVList,
+    ) -> Result<(), ::slog::Never> {
+        use slog::KV;
+
+        let mut fields = Fields(Vec::new());
+        // A value that fails to format is left out rather than the record
+        let _ = record.kv().serialize(record, &mut fields);
+        let _ = values.serialize(record, &mut fields);
+        let target = if record.tag().is_empty() {
+            record.module()
+        } else {
+            record.tag()
+        };
+        self.shared.send(LogRecord {
+            level: record.level().into(),
+            target: target.to_string(),
+            message: record.msg().to_string(),
+            module_path: Some(record.module().to_string()),
+            file: Some(record.file().to_string()),
+            line: Some(record.line()),
+            logged_at_ms: now_ms(),
+            fields: fields.0,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::*;
+    use super::super::channel;
+
+    #[test]
+    fn log_records_round_trip_through_a_channel() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel("log_records", dir.path()).unwrap();
+        let record = LogRecord {
+            level: LogLevel::Critical,
+            target: "db".to_string(),
+            message: "replica lost".to_string(),
+            module_path: Some("app::db".to_string()),
+            file: None,
+            line: Some(12),
+            logged_at_ms: 1_700_000_000_000,
+            fields: vec![("replica".to_string(), "3".to_string())],
+        };
+        snd.send(record.clone());
+        assert_eq!(Some(record), rcv.try_iter().next());
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn log_sink_filters_by_level_and_counts_refusals() {
+        use log::{self, Log};
+        use super::super::ChannelBuilder;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (snd, mut rcv) = ChannelBuilder::new("log_sink", dir.path())
+            .memory_only(true)
+            .build()
+            .unwrap();
+        let sink = LogSink::new(snd, log::LevelFilter::Info);
+        let log = |level, i: usize| {
+            sink.log(
+                &log::Record::builder()
+                    .level(level)
+                    .target("app")
+                    .args(format_args!("record {}", i))
+                    .line(Some(7))
+                    .build(),
+            )
+        };
+        log(log::Level::Debug, 0);
+        // The memory-only channel holds 2048 records and refuses the next
+        for i in 1..2050 {
+            log(log::Level::Info, i);
+        }
+        assert_eq!(1, sink.dropped());
+        let logged = rcv.drain();
+        assert_eq!(2048, logged.len());
+        assert_eq!(LogLevel::Info, logged[0].level);
+        assert_eq!("record 1", logged[0].message);
+        assert_eq!(("app", Some(7)), (&logged[0].target[..], logged[0].line));
+    }
+}
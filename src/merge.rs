@@ -0,0 +1,316 @@
+use super::{Receiver, RecordMeta};
+use select::Select;
+use serde::de::DeserializeOwned;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How a `Merge` chooses the channel to take its next item from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MergeOrder {
+    /// Take an item from each channel with one waiting in turn, so that a
+    /// busy channel cannot starve the rest. This is the default.
+    #[default]
+    RoundRobin,
+    /// Take the item sent earliest among the items waiting at the head of
+    /// each channel, by `RecordMeta::enqueued`
+    ///
+    /// The order is approximate. Only the channels with an item waiting are
+    /// compared, so an item sent earlier over a channel that has yet to
+    /// deliver it comes later, and send times are to the millisecond, ties
+    /// going to the channel added first. Items of a channel that does not
+    /// stamp its items carry no send time and are taken as soon as seen.
+    Timestamp,
+}
+
+// One channel of a Merge
+struct Input<T> {
+    rx: Receiver<T>,
+    // The item drawn from the channel and not yet handed over, for a
+    // timestamp merge to compare
+    head: Option<(T, RecordMeta)>,
+    // Whether the channel has hung up and every item of it is handed over
+    done: bool,
+}
+
+impl<T> Input<T>
+where
+    T: DeserializeOwned,
+{
+    // Draw the channel's next item into `head`, if one is waiting, marking
+    // the channel done if it has hung up without one
+    fn fill(&mut self) {
+        if self.head.is_some() || self.done {
+            return;
+        }
+        // Checked first, so the last Sender's items are in by the receive
+        let hung_up = self.rx.is_hung_up();
+        self.head = self.rx.recv_with_meta();
+        self.done = self.head.is_none() && hung_up;
+    }
+}
+
+/// Receive from several channels as one
+///
+/// A `Merge` takes items from the Receivers it is given in the
+/// `MergeOrder` it is given, for a single consumer loop over channels that
+/// producers are spread across. The channels need not be alike, save in
+/// their item type. A channel whose Senders have all hung up is passed over
+/// once its last item is received, and the Merge itself hangs up once every
+/// channel has: `recv` then returns `None`, as does iterating the Merge.
+///
+/// # Example
+/// ```
+/// extern crate tempdir;
+/// extern crate hopper;
+///
+/// use hopper::{Merge, MergeOrder};
+///
+/// let dir = tempdir::TempDir::new("hopper").unwrap();
+/// let (mut snd_a, rcv_a) = hopper::channel::<u64>("a", dir.path()).unwrap();
+/// let (mut snd_b, rcv_b) = hopper::channel::<u64>("b", dir.path()).unwrap();
+/// snd_a.send(1);
+/// snd_a.send(3);
+/// snd_b.send(2);
+/// drop((snd_a, snd_b));
+///
+/// let merged = Merge::new(vec![rcv_a, rcv_b], MergeOrder::RoundRobin);
+/// assert_eq!(vec![1, 2, 3], merged.collect::<Vec<u64>>());
+/// ```
+pub struct Merge<T> {
+    inputs: Vec<Input<T>>,
+    order: MergeOrder,
+    // The channel to look at first on the next round-robin receive
+    turn: usize,
+    // Made on the first wait
+    sel: Option<Select>,
+}
+
+impl<T> fmt::Debug for Merge<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Merge")
+            .field("receivers", &self.inputs.len())
+            .field("hung_up", &self.inputs.iter().filter(|i| i.done).count())
+            .field("order", &self.order)
+            .field("turn", &self.turn)
+            .finish()
+    }
+}
+
+impl<T> Merge<T>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    /// Merge the channels of `receivers`, taking items from them in `order`
+    ///
+    /// Each channel is known by its index in `receivers`, as
+    /// `try_recv_from` reports it.
+    pub fn new(receivers: Vec<Receiver<T>>, order: MergeOrder) -> Merge<T> {
+        Merge {
+            inputs: receivers
+                .into_iter()
+                .map(|rx| Input {
+                    rx: rx,
+                    head: None,
+                    done: false,
+                })
+                .collect(),
+            order: order,
+            turn: 0,
+            sel: None,
+        }
+    }
+
+    /// Receive the next item, if one is waiting on any channel
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.try_recv_from().map(|(_, item)| item)
+    }
+
+    /// Receive the next item, if one is waiting on any channel, with the
+    /// index of the channel it came over
+    pub fn try_recv_from(&mut self) -> Option<(usize, T)> {
+        let idx = match self.order {
+            MergeOrder::RoundRobin => self.next_in_turn(),
+            MergeOrder::Timestamp => self.earliest(),
+        }?;
+        let (item, _) = self.inputs[idx].head.take()?;
+        Some((idx, item))
+    }
+
+    /// Receive the next item, waiting up to `timeout` for one to arrive
+    ///
+    /// Returns `None` if the timeout elapses first or once every channel has
+    /// hung up and been drained.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Option<T> {
+        self.wait(Some(Instant::now() + timeout))
+    }
+
+    /// Receive the next item, waiting for one to arrive
+    ///
+    /// Returns `None` once every channel has hung up and been drained.
+    pub fn recv(&mut self) -> Option<T> {
+        self.wait(None)
+    }
+
+    /// Whether every channel has hung up and had its last item received
+    pub fn is_hung_up(&self) -> bool {
+        self.inputs.iter().all(|input| input.done)
+    }
+
+    /// The number of channels not yet hung up and drained
+    pub fn live_receivers(&self) -> usize {
+        self.inputs.iter().filter(|input| !input.done).count()
+    }
+
+    // The index of the next channel in turn with an item drawn, if any
+    fn next_in_turn(&mut self) -> Option<usize> {
+        let total = self.inputs.len();
+        for turn in self.turn..self.turn + total {
+            let idx = turn % total;
+            self.inputs[idx].fill();
+            if self.inputs[idx].head.is_some() {
+                self.turn = (idx + 1) % total;
+                return Some(idx);
+            }
+        }
+        None
+    }
+
+    // The index of the channel whose drawn item was sent earliest, if any
+    fn earliest(&mut self) -> Option<usize> {
+        for input in &mut self.inputs {
+            input.fill();
+        }
+        self.inputs
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, input)| {
+                input
+                    .head
+                    .as_ref()
+                    .map(|(_, meta)| (meta.enqueued, idx))
+            })
+            .min()
+            .map(|(_, idx)| idx)
+    }
+
+    fn wait(&mut self, deadline: Option<Instant>) -> Option<T> {
+        loop {
+            if let Some((_, item)) = self.try_recv_from() {
+                return Some(item);
+            }
+            if self.is_hung_up() {
+                return None;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return None;
+            }
+            let inputs = &self.inputs;
+            let sel = self.sel.get_or_insert_with(|| {
+                let mut sel = Select::new();
+                for input in inputs {
+                    sel.add(&input.rx);
+                }
+                sel
+            });
+            sel.ready_or_hang_up(deadline);
+        }
+    }
+}
+
+impl<T> Iterator for Merge<T>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    type Item = T;
+
+    /// Wait for the next item, ending once every channel has hung up and
+    /// been drained
+    fn next(&mut self) -> Option<T> {
+        self.recv()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::{Merge, MergeOrder};
+    use super::super::{channel, ChannelBuilder};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use testing::ManualClock;
+
+    #[test]
+    fn round_robin_takes_each_channel_in_turn() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd_a, rcv_a) = channel::<u64>("merge_rr_a", dir.path()).unwrap();
+        let (mut snd_b, rcv_b) = channel::<u64>("merge_rr_b", dir.path()).unwrap();
+        for i in 0..4 {
+            snd_a.send(i);
+        }
+        snd_b.send(10);
+        snd_b.send(11);
+
+        let mut merge = Merge::new(vec![rcv_a, rcv_b], MergeOrder::RoundRobin);
+        let mut got = Vec::new();
+        while let Some(from) = merge.try_recv_from() {
+            got.push(from);
+        }
+        assert_eq!(
+            vec![(0, 0), (1, 10), (0, 1), (1, 11), (0, 2), (0, 3)],
+            got
+        );
+        assert!(!merge.is_hung_up());
+    }
+
+    #[test]
+    fn timestamp_merge_orders_by_send_time() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let build = |name: &str| {
+            ChannelBuilder::new(name, dir.path())
+                .record_provenance(true)
+                .clock(clock.clone())
+                .build::<u64>()
+                .unwrap()
+        };
+        let (mut snd_a, rcv_a) = build("merge_ts_a");
+        let (mut snd_b, rcv_b) = build("merge_ts_b");
+        for i in 0..6 {
+            if i % 3 == 0 {
+                snd_b.send(i);
+            } else {
+                snd_a.send(i);
+            }
+            clock.advance(Duration::from_millis(5));
+        }
+
+        let mut merge = Merge::new(vec![rcv_a, rcv_b], MergeOrder::Timestamp);
+        let got: Vec<u64> = (0..6).filter_map(|_| merge.try_recv()).collect();
+        assert_eq!(vec![0, 1, 2, 3, 4, 5], got);
+        assert_eq!(None, merge.try_recv());
+    }
+
+    #[test]
+    fn hung_up_channels_are_passed_over() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd_a, rcv_a) = channel::<u64>("merge_hang_a", dir.path()).unwrap();
+        let (mut snd_b, rcv_b) = channel::<u64>("merge_hang_b", dir.path()).unwrap();
+        snd_a.send(1);
+        drop(snd_a);
+
+        let mut merge = Merge::new(vec![rcv_a, rcv_b], MergeOrder::RoundRobin);
+        assert_eq!(Some(1), merge.recv());
+        assert_eq!(None, merge.recv_timeout(Duration::from_millis(10)));
+        assert_eq!(1, merge.live_receivers());
+
+        let jh = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            snd_b.send(2);
+        });
+        assert_eq!(vec![2], merge.by_ref().collect::<Vec<u64>>());
+        assert!(merge.is_hung_up());
+        jh.join().unwrap();
+    }
+}
@@ -0,0 +1,216 @@
+#[cfg(feature = "histograms")]
+use histogram::Latencies;
+use histogram::{Histogram, LatencyHistogram};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+/// A point-in-time view of a channel's depth and spill behaviour
+///
+/// Obtained from `Sender::metrics` or `Receiver::metrics`. Both sides of a
+/// channel share the same underlying counters so either will do. The counters
+/// are maintained with relaxed atomics and are not read as a single consistent
+/// unit: under concurrent load the fields may be very slightly out of step
+/// with one another.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueMetrics {
+    /// Items currently held in memory, including those staged for the next
+    /// write to disk
+    pub in_memory_depth: usize,
+    /// Bytes held in queue files on disk
+    pub disk_bytes: u64,
+    /// Number of queue files on disk
+    pub segments_on_disk: usize,
+    /// The size at which the current queue file will be rotated
+    pub segment_max_bytes: usize,
+    /// Items sent into the channel since it was created
+    pub total_enqueued: u64,
+    /// Items received out of the channel since it was created
+    pub total_dequeued: u64,
+    /// Number of times a batch of items was paged out to disk
+    pub spill_events: u64,
+    /// Number of on-disk items that could not be deserialized
+    pub deserialize_failures: u64,
+    /// Of those, the number moved to the channel's dead-letter file
+    pub total_dead_lettered: u64,
+    /// Items dropped unreceived because they outlived the channel's TTL
+    pub total_expired: u64,
+    /// Items written to queue files
+    pub records_written: u64,
+    /// Write calls made to queue files. `records_written / write_calls` is the
+    /// number of items batched into each write.
+    pub write_calls: u64,
+    /// Items refused or dropped for being over the channel's rate limit
+    pub total_rate_limited: u64,
+    /// Items refused or dropped because a memory-only channel was full
+    pub total_overflowed: u64,
+    /// Items refused or dropped because a channel was held at its disk quota
+    pub total_disk_full: u64,
+    /// Items deleted unreceived from queue files evicted at a disk quota, see
+    /// `ChannelBuilder::evict_oldest`
+    pub total_evicted: u64,
+    /// Damaged retained files found by the scrubber, see
+    /// `ChannelBuilder::scrub_retained`
+    pub total_corrupt_segments: u64,
+    /// Items dropped unreceived by the predicate of a `Receiver::filtered`
+    pub total_filtered: u64,
+    /// Items offered to `Sender::send_sampled`
+    pub total_sampled: u64,
+    /// Of those, the number left out of the sample and not sent
+    pub total_sampled_out: u64,
+    /// The sample rate `Sender::send_sampled` last applied, in parts per
+    /// million, adaptive sampling included: 1,000,000 keeps every item.
+    /// Each item kept stands for `1_000_000 / sample_rate_ppm` items offered.
+    /// 0 until an item is offered.
+    pub sample_rate_ppm: u32,
+    /// Time taken by each `Sender::send`
+    #[cfg(feature = "histograms")]
+    pub send_latency: LatencyHistogram,
+    /// Time taken by each receive that returned an item
+    #[cfg(feature = "histograms")]
+    pub recv_latency: LatencyHistogram,
+    /// Time taken to flush each batch of items paged out to a queue file.
+    /// Regular channels do not sync their queue files, so this is the time
+    /// taken to hand the batch to the operating system.
+    #[cfg(feature = "histograms")]
+    pub flush_latency: LatencyHistogram,
+    /// Time from each item's send to its receive, to the millisecond. Empty
+    /// unless the channel stamps its items, see `RecordMeta::enqueued`.
+    pub time_in_queue: LatencyHistogram,
+}
+
+impl QueueMetrics {
+    /// Items sent into the channel and dropped unreceived, for whatever
+    /// reason: refused at a limit, expired, evicted, filtered or dead
+    /// lettered
+    pub fn total_dropped(&self) -> u64 {
+        self.total_rate_limited
+            + self.total_overflowed
+            + self.total_disk_full
+            + self.total_expired
+            + self.total_evicted
+            + self.total_filtered
+            + self.total_dead_lettered
+    }
+}
+
+/// One line, for periodic logging: the items in memory, the queue files on
+/// disk and the items sent, received and dropped
+impl fmt::Display for QueueMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} in memory, {} files of {} bytes on disk, {} sent, {} received, {} dropped",
+            self.in_memory_depth,
+            self.segments_on_disk,
+            self.disk_bytes,
+            self.total_enqueued,
+            self.total_dequeued,
+            self.total_dropped()
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub in_memory_depth: AtomicUsize,
+    // The in-memory capacity, as scaled under memory pressure, read by
+    // Sender::occupancy
+    pub in_memory_capacity: AtomicUsize,
+    pub disk_bytes: AtomicU64,
+    pub segments_on_disk: AtomicUsize,
+    pub segment_max_bytes: AtomicUsize,
+    pub total_enqueued: AtomicU64,
+    pub total_dequeued: AtomicU64,
+    pub spill_events: AtomicU64,
+    pub deserialize_failures: AtomicU64,
+    pub total_dead_lettered: AtomicU64,
+    pub total_expired: AtomicU64,
+    pub records_written: AtomicU64,
+    pub write_calls: AtomicU64,
+    pub total_rate_limited: AtomicU64,
+    pub total_overflowed: AtomicU64,
+    pub total_disk_full: AtomicU64,
+    pub total_evicted: AtomicU64,
+    pub total_corrupt_segments: AtomicU64,
+    pub total_filtered: AtomicU64,
+    pub total_sampled: AtomicU64,
+    pub total_sampled_out: AtomicU64,
+    pub sample_rate_ppm: AtomicU32,
+    pub time_in_queue: Histogram,
+    #[cfg(feature = "histograms")]
+    pub latency: Latencies,
+}
+
+impl Metrics {
+    /// Seed the on-disk gauges from whatever queue files already exist in
+    /// `root`.
+    pub fn seed_from_dir(&self, root: &Path, archive: Option<&Path>, segment_max_bytes: usize) {
+        self.segment_max_bytes
+            .store(segment_max_bytes, Ordering::Relaxed);
+        for root in Some(root).into_iter().chain(archive) {
+            self.seed_segments(root);
+        }
+    }
+
+    fn seed_segments(&self, root: &Path) {
+        if let Ok(entries) = fs::read_dir(root) {
+            for entry in entries.filter_map(|e| e.ok()) {
+                let is_segment = entry
+                    .file_name()
+                    .to_str()
+                    .is_some_and(|n| n.parse::<usize>().is_ok());
+                if !is_segment {
+                    continue;
+                }
+                if let Ok(md) = entry.metadata() {
+                    self.segments_on_disk.fetch_add(1, Ordering::Relaxed);
+                    self.disk_bytes.fetch_add(md.len(), Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> QueueMetrics {
+        QueueMetrics {
+            in_memory_depth: self.in_memory_depth.load(Ordering::Relaxed),
+            disk_bytes: self.disk_bytes.load(Ordering::Relaxed),
+            segments_on_disk: self.segments_on_disk.load(Ordering::Relaxed),
+            segment_max_bytes: self.segment_max_bytes.load(Ordering::Relaxed),
+            total_enqueued: self.total_enqueued.load(Ordering::Relaxed),
+            total_dequeued: self.total_dequeued.load(Ordering::Relaxed),
+            spill_events: self.spill_events.load(Ordering::Relaxed),
+            deserialize_failures: self.deserialize_failures.load(Ordering::Relaxed),
+            total_dead_lettered: self.total_dead_lettered.load(Ordering::Relaxed),
+            total_expired: self.total_expired.load(Ordering::Relaxed),
+            records_written: self.records_written.load(Ordering::Relaxed),
+            write_calls: self.write_calls.load(Ordering::Relaxed),
+            total_rate_limited: self.total_rate_limited.load(Ordering::Relaxed),
+            total_overflowed: self.total_overflowed.load(Ordering::Relaxed),
+            total_disk_full: self.total_disk_full.load(Ordering::Relaxed),
+            total_evicted: self.total_evicted.load(Ordering::Relaxed),
+            total_corrupt_segments: self.total_corrupt_segments.load(Ordering::Relaxed),
+            total_filtered: self.total_filtered.load(Ordering::Relaxed),
+            total_sampled: self.total_sampled.load(Ordering::Relaxed),
+            total_sampled_out: self.total_sampled_out.load(Ordering::Relaxed),
+            sample_rate_ppm: self.sample_rate_ppm.load(Ordering::Relaxed),
+            #[cfg(feature = "histograms")]
+            send_latency: self.latency.send.snapshot(),
+            #[cfg(feature = "histograms")]
+            recv_latency: self.latency.recv.snapshot(),
+            #[cfg(feature = "histograms")]
+            flush_latency: self.latency.flush.snapshot(),
+            time_in_queue: self.time_in_queue.snapshot(),
+        }
+    }
+
+    /// Count an item handed to the Receiver's caller, having waited `waited`
+    /// milliseconds if the channel stamps its items
+    pub fn dequeued(&self, waited: Option<u64>) {
+        self.total_dequeued.fetch_add(1, Ordering::Relaxed);
+        if let Some(millis) = waited {
+            self.time_in_queue.record(millis.saturating_mul(1000));
+        }
+    }
+}
@@ -0,0 +1,379 @@
+//! Many typed streams over one channel
+//!
+//! A `Mux`, built with `ChannelBuilder::build_mux`, carries any number of
+//! logical streams, each of its own item type, over one channel: one
+//! directory, one sequence of queue files and one set of file handles,
+//! however many streams there are. Each item is stored as the id of its
+//! stream followed by its bincode encoding. A stream's receiver takes the
+//! channel's items in order, keeping those of other streams aside in memory
+//! for their own receivers, so a stream left unread holds its items in memory
+//! once they have been read past.
+//!
+//! # Example
+//! ```
+//! extern crate tempdir;
+//! extern crate hopper;
+//!
+//! let dir = tempdir::TempDir::new("hopper").unwrap();
+//! let mux = hopper::ChannelBuilder::new("events", dir.path()).build_mux().unwrap();
+//!
+//! let mut clicks = mux.sender::<u64>(0).unwrap();
+//! let mut labels = mux.sender::<String>(1).unwrap();
+//! clicks.send(9);
+//! labels.send("nine".to_string());
+//!
+//! let mut rcv = mux.receiver::<String>(1).unwrap();
+//! assert_eq!(Some("nine".to_string()), rcv.try_recv());
+//! assert!(mux.sender::<String>(0).is_err());
+//! ```
+use super::{Error, QueueMetrics, Receiver, Sender};
+use bincode::{deserialize, serialize, Infinite};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::any::TypeId;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use sync::{self, Mutex};
+
+// How long a waiting stream receiver holds the channel before giving the
+// receivers of other streams a turn at it.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The on-disk form of a stream's item: its stream id and bincode encoding
+pub(crate) type Record = (u32, Vec<u8>);
+
+struct Stream {
+    type_id: TypeId,
+    type_name: &'static str,
+    receiver_taken: bool,
+}
+
+struct Demux {
+    rcv: Receiver<Record>,
+    // Items read past on the way to another stream's, by stream id
+    pending: HashMap<u32, VecDeque<Vec<u8>>>,
+}
+
+impl Demux {
+    // Take the next item of `stream`, reading the channel past the items of
+    // other streams as far as need be.
+    fn next(&mut self, stream: u32) -> Option<Vec<u8>> {
+        if let Some(bytes) = self.pending.get_mut(&stream).and_then(VecDeque::pop_front) {
+            return Some(bytes);
+        }
+        self.read_to(stream, Duration::from_millis(0))
+    }
+
+    fn read_to(&mut self, stream: u32, timeout: Duration) -> Option<Vec<u8>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            let (id, bytes) = self.rcv.recv_batch(1, wait).pop()?;
+            if id == stream {
+                return Some(bytes);
+            }
+            self.pending.entry(id).or_default().push_back(bytes);
+        }
+    }
+}
+
+struct Shared {
+    snd: Mutex<Sender<Record>>,
+    demux: Mutex<Demux>,
+    streams: Mutex<HashMap<u32, Stream>>,
+}
+
+/// A channel carrying many logical streams, see the `mux` module
+///
+/// Each stream is named by a `u32` id and bound to the item type it was first
+/// opened with. Opening it with any other type is an `Error::TypeMismatch`.
+/// The id names the stream on disk, so it should not be reused for another
+/// type for as long as the channel holds items of the stream.
+pub struct Mux {
+    shared: Arc<Shared>,
+}
+
+impl fmt::Debug for Mux {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let streams = sync::lock(&self.shared.streams);
+        let mut ids: Vec<(&u32, &'static str)> =
+            streams.iter().map(|(id, s)| (id, s.type_name)).collect();
+        ids.sort();
+        f.debug_struct("Mux")
+            .field("name", &sync::lock(&self.shared.snd).name())
+            .field("streams", &ids)
+            .finish()
+    }
+}
+
+impl Mux {
+    pub(crate) fn new(pair: (Sender<Record>, Receiver<Record>)) -> Mux {
+        Mux {
+            shared: Arc::new(Shared {
+                snd: Mutex::new(pair.0),
+                demux: Mutex::new(Demux {
+                    rcv: pair.1,
+                    pending: HashMap::new(),
+                }),
+                streams: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    // Bind `stream` to `T` if it is not yet bound, taking its receiver if
+    // `receiver` is set.
+    fn open<T: 'static>(&self, stream: u32, receiver: bool) -> Result<(), Error> {
+        let mut streams = sync::lock(&self.shared.streams);
+        let entry = streams.entry(stream).or_insert_with(|| Stream {
+            type_id: TypeId::of::<T>(),
+            type_name: ::std::any::type_name::<T>(),
+            receiver_taken: false,
+        });
+        if entry.type_id != TypeId::of::<T>() {
+            return Err(Error::TypeMismatch);
+        }
+        if receiver {
+            if entry.receiver_taken {
+                return Err(Error::ReceiverTaken);
+            }
+            entry.receiver_taken = true;
+        }
+        Ok(())
+    }
+
+    /// Return a Sender for the stream `stream`
+    pub fn sender<T>(&self, stream: u32) -> Result<StreamSender<T>, Error>
+    where
+        T: Serialize + 'static,
+    {
+        self.open::<T>(stream, false)?;
+        Ok(StreamSender {
+            inner: sync::lock(&self.shared.snd).clone(),
+            stream: stream,
+            resource_type: PhantomData,
+        })
+    }
+
+    /// Take the Receiver for the stream `stream`
+    ///
+    /// Streams have a single Receiver, so this succeeds once per stream.
+    /// Later calls return `Error::ReceiverTaken`. Items sent on the stream
+    /// before its Receiver is taken wait for it.
+    pub fn receiver<T>(&self, stream: u32) -> Result<StreamReceiver<T>, Error>
+    where
+        T: DeserializeOwned + 'static,
+    {
+        self.open::<T>(stream, true)?;
+        Ok(StreamReceiver {
+            shared: Arc::clone(&self.shared),
+            stream: stream,
+            resource_type: PhantomData,
+        })
+    }
+
+    /// Return the ids of the opened streams, sorted
+    pub fn streams(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = sync::lock(&self.shared.streams).keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    /// Return the metrics of the channel the streams share
+    pub fn metrics(&self) -> QueueMetrics {
+        sync::lock(&self.shared.snd).metrics()
+    }
+}
+
+/// The 'send' side of one of a `Mux`'s streams
+///
+/// Created by `Mux::sender`. Clones send on the same stream.
+pub struct StreamSender<T> {
+    inner: Sender<Record>,
+    stream: u32,
+    resource_type: PhantomData<T>,
+}
+
+impl<T> fmt::Debug for StreamSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StreamSender")
+            .field("name", &self.inner.name())
+            .field("stream", &self.stream)
+            .finish()
+    }
+}
+
+impl<T> Clone for StreamSender<T> {
+    fn clone(&self) -> StreamSender<T> {
+        StreamSender {
+            inner: self.inner.clone(),
+            stream: self.stream,
+            resource_type: PhantomData,
+        }
+    }
+}
+
+impl<T> StreamSender<T>
+where
+    T: Serialize,
+{
+    /// Send `event` on the stream
+    ///
+    /// See `Sender::send`.
+    pub fn send(&mut self, event: T) {
+        let bytes = serialize(&event, Infinite).expect("Failed encoding");
+        self.inner.send((self.stream, bytes))
+    }
+
+    /// Hand any items staged by this StreamSender to the channel
+    ///
+    /// See `Sender::flush`.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    /// Return the id of the stream this Sender sends on
+    pub fn stream(&self) -> u32 {
+        self.stream
+    }
+}
+
+/// The 'receive' side of one of a `Mux`'s streams
+///
+/// Created by `Mux::receiver`.
+pub struct StreamReceiver<T> {
+    shared: Arc<Shared>,
+    stream: u32,
+    resource_type: PhantomData<T>,
+}
+
+impl<T> fmt::Debug for StreamReceiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StreamReceiver")
+            .field("stream", &self.stream)
+            .field("pending", &self.pending())
+            .finish()
+    }
+}
+
+impl<T> StreamReceiver<T> {
+    /// Return the id of the stream this Receiver reads
+    pub fn stream(&self) -> u32 {
+        self.stream
+    }
+
+    /// The number of the stream's items read past by other streams'
+    /// Receivers and held in memory for this one
+    pub fn pending(&self) -> usize {
+        sync::lock(&self.shared.demux)
+            .pending
+            .get(&self.stream)
+            .map_or(0, VecDeque::len)
+    }
+}
+
+impl<T> StreamReceiver<T>
+where
+    T: DeserializeOwned,
+{
+    /// Receive the stream's next item, if one is waiting
+    pub fn try_recv(&mut self) -> Option<T> {
+        let bytes = sync::lock(&self.shared.demux).next(self.stream)?;
+        Some(deserialize(&bytes).expect("Failed decoding"))
+    }
+
+    /// Receive the stream's next item, waiting up to `timeout` for one
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Option<T> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(item) = self.try_recv() {
+                return Some(item);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            let wait = cmp::min(deadline - now, POLL_INTERVAL);
+            let bytes = sync::lock(&self.shared.demux).read_to(self.stream, wait);
+            if let Some(bytes) = bytes {
+                return Some(deserialize(&bytes).expect("Failed decoding"));
+            }
+            // Give the Receivers of other streams a turn at the channel.
+            thread::yield_now();
+        }
+    }
+
+    /// An iterator over the stream's waiting items, ending once none is
+    pub fn iter(&mut self) -> StreamIter<'_, T> {
+        StreamIter { rx: self }
+    }
+}
+
+/// An iterator over the items of a `StreamReceiver`, see
+/// `StreamReceiver::iter`
+#[derive(Debug)]
+pub struct StreamIter<'a, T: 'a> {
+    rx: &'a mut StreamReceiver<T>,
+}
+
+impl<'a, T> Iterator for StreamIter<'a, T>
+where
+    T: DeserializeOwned,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.try_recv()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::super::{ChannelBuilder, Error};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn streams_share_one_channel_and_keep_apart() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let mux = ChannelBuilder::new("mux", dir.path())
+            .max_bytes(1024)
+            .build_mux()
+            .unwrap();
+        let mut counts = mux.sender::<u64>(0).unwrap();
+        let mut names = mux.sender::<String>(7).unwrap();
+        // Through the in-memory tier and the queue files.
+        for i in 0..2000u64 {
+            counts.send(i);
+            names.send(format!("{}", i));
+        }
+        assert!(mux.metrics().disk_bytes > 0);
+        assert_eq!(Error::TypeMismatch, mux.sender::<String>(0).unwrap_err());
+        assert_eq!(vec![0, 7], mux.streams());
+
+        let mut rcv_names = mux.receiver::<String>(7).unwrap();
+        assert_eq!(Error::ReceiverTaken, mux.receiver::<String>(7).unwrap_err());
+        let got: Vec<String> = rcv_names.iter().collect();
+        assert_eq!((0..2000).map(|i| format!("{}", i)).collect::<Vec<_>>(), got);
+
+        let mut rcv_counts = mux.receiver::<u64>(0).unwrap();
+        assert_eq!(2000, rcv_counts.pending());
+        assert_eq!((0..2000).collect::<Vec<u64>>(), rcv_counts.iter().collect::<Vec<u64>>());
+
+        let jh = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            names.send("late".to_string());
+            counts.send(2000);
+        });
+        assert_eq!(Some(2000), rcv_counts.recv_timeout(Duration::from_secs(10)));
+        assert_eq!(Some("late".to_string()), rcv_names.try_recv());
+        jh.join().unwrap();
+    }
+}
@@ -0,0 +1,190 @@
+//! The in-memory tier of a channel, paged out under memory pressure
+//!
+//! `Sender::relieve_memory_pressure` moves the items held in a channel's
+//! in-memory tier out to its `hopper.paged` file. They are the oldest items
+//! in the channel, ahead of any in its queue files, so cannot be appended to
+//! those. The Receiver reads them back from this file, in order, before it
+//! takes from the in-memory tier again. A record's payload is framed as a
+//! queue file record's is: send time, Sender id and place as the channel
+//! stamps them, then the item's encoding, sealed if the channel encrypts. The
+//! file is emptied whenever the Receiver has read everything in it, and by
+//! the channel's next build, which discards the items of the last as it does
+//! those of its queue files. Integers are big-endian.
+//!
+//! ```text
+//! records    each:
+//!   len      u32   the bytes of payload
+//!   payload  len bytes
+//! ```
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// The items paged out of a channel's in-memory tier, see the module
+/// documentation
+pub struct Paged {
+    path: PathBuf,
+    fp: Option<fs::File>,
+    // Where the next record to read back starts and where the records end
+    next: u64,
+    end: u64,
+    // The length of each payload waiting, so that one which cannot be read
+    // back is passed over without losing the place of those after it
+    lens: VecDeque<u32>,
+}
+
+impl fmt::Debug for Paged {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Paged")
+            .field("path", &self.path)
+            .field("waiting", &self.lens.len())
+            .finish()
+    }
+}
+
+impl Paged {
+    /// The paged out items in the file at `path`, none to begin with
+    ///
+    /// Items left in the file by an earlier build of the channel are
+    /// discarded.
+    pub fn open(path: &Path) -> io::Result<Paged> {
+        match fs::remove_file(path) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(Paged {
+            path: path.to_path_buf(),
+            fp: None,
+            next: 0,
+            end: 0,
+            lens: VecDeque::new(),
+        })
+    }
+
+    /// Return the number of items waiting to be read back
+    pub fn len(&self) -> usize {
+        self.lens.len()
+    }
+
+    /// Whether no item is waiting to be read back
+    pub fn is_empty(&self) -> bool {
+        self.lens.is_empty()
+    }
+
+    /// Append `records`, each a length and a payload
+    ///
+    /// Records the file cannot take are truncated away again and the error
+    /// returned, so that none of them is taken as paged out.
+    pub fn page_out(&mut self, records: &[u8]) -> io::Result<()> {
+        let at = self.end;
+        let written = self.file().and_then(|fp| {
+            fp.seek(SeekFrom::Start(at))?;
+            fp.write_all(records)
+        });
+        if let Err(e) = written {
+            let _ = self.file().and_then(|fp| fp.set_len(at));
+            return Err(e);
+        }
+        self.end += records.len() as u64;
+        let mut rest = records;
+        while rest.len() >= 4 {
+            let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]);
+            self.lens.push_back(len);
+            rest = &rest[(4 + len as usize).min(rest.len())..];
+        }
+        Ok(())
+    }
+
+    /// Take the payload of the earliest item waiting, if there is one
+    ///
+    /// The item is taken even if its record cannot be read back, and the
+    /// error returned in its place.
+    pub fn take(&mut self) -> Option<io::Result<Vec<u8>>> {
+        let len = self.lens.pop_front()?;
+        let at = self.next;
+        let taken = self.read_at(at, len);
+        self.next += 4 + u64::from(len);
+        if self.lens.is_empty() && self.file().and_then(|fp| fp.set_len(0)).is_ok() {
+            self.next = 0;
+            self.end = 0;
+        }
+        Some(taken)
+    }
+
+    /// The payloads of every item waiting, earliest first, leaving them
+    /// waiting
+    pub fn waiting(&mut self) -> io::Result<Vec<Vec<u8>>> {
+        let lens: Vec<u32> = self.lens.iter().cloned().collect();
+        let mut payloads = Vec::with_capacity(lens.len());
+        let mut at = self.next;
+        for len in lens {
+            payloads.push(self.read_at(at, len)?);
+            at += 4 + u64::from(len);
+        }
+        Ok(payloads)
+    }
+
+    fn read_at(&mut self, at: u64, len: u32) -> io::Result<Vec<u8>> {
+        let fp = self.file()?;
+        let mut payload = vec![0; len as usize];
+        fp.seek(SeekFrom::Start(at + 4))?;
+        fp.read_exact(&mut payload)?;
+        Ok(payload)
+    }
+
+    fn file(&mut self) -> io::Result<&mut fs::File> {
+        if self.fp.is_none() {
+            let fp = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&self.path)?;
+            self.fp = Some(fp);
+        }
+        Ok(self.fp.as_mut().expect("paged file open"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::Paged;
+
+    fn framed(payloads: &[&[u8]]) -> Vec<u8> {
+        let mut records = Vec::new();
+        for payload in payloads {
+            records.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            records.extend_from_slice(payload);
+        }
+        records
+    }
+
+    #[test]
+    fn items_come_back_in_order_and_the_file_empties() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let path = dir.path().join("hopper.paged");
+        let mut paged = Paged::open(&path).unwrap();
+        assert_eq!(None, paged.take().map(|taken| taken.unwrap()));
+
+        paged.page_out(&framed(&[b"one", b"two"])).unwrap();
+        assert_eq!(Some(b"one".to_vec()), paged.take().map(|taken| taken.unwrap()));
+        paged.page_out(&framed(&[b"three"])).unwrap();
+        assert_eq!(vec![b"two".to_vec(), b"three".to_vec()], paged.waiting().unwrap());
+        assert_eq!(2, paged.len());
+        assert_eq!(Some(b"two".to_vec()), paged.take().map(|taken| taken.unwrap()));
+        assert_eq!(Some(b"three".to_vec()), paged.take().map(|taken| taken.unwrap()));
+        assert!(paged.is_empty());
+        assert_eq!(0, path.metadata().unwrap().len());
+
+        // The next build starts afresh
+        paged.page_out(&framed(&[b"four"])).unwrap();
+        let mut reopened = Paged::open(&path).unwrap();
+        assert!(reopened.is_empty());
+        assert_eq!(None, reopened.take().map(|taken| taken.unwrap()));
+    }
+}
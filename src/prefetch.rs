@@ -0,0 +1,500 @@
+//! Read-ahead of queue files
+//!
+//! A Receiver draining a backlog from disk reads each record as it comes to
+//! it. A channel built with `ChannelBuilder::read_ahead` instead gives the
+//! Receiver a thread that reads the records of the queue file being drained
+//! into a staging ring of up to the configured number of bytes, so the
+//! Receiver takes records from memory while the thread reads on. Records are
+//! staged as they are on disk and decrypted and decoded as they are taken.
+//! The Receiver of a channel built with `ChannelBuilder::runtime` has its
+//! records read ahead by a job of the runtime instead. A read-ahead that
+//! panics is started again from the last record it staged, and should it be
+//! given up on the Receiver reads on from the file itself. On a
+//! `sim::Simulation` the job runs on the Receiver's thread as the Receiver
+//! waits for it.
+use runtime::{JobHandle, Next, Runtime};
+use supervise::{Stopped, Supervisor, Task};
+use sync::{self, Condvar, Mutex, MutexGuard};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufReader, ErrorKind, Seek, SeekFrom};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// The reader of the queue file a Receiver is draining
+pub enum SegmentReader {
+    /// Records are read as they are asked for
+    Direct(BufReader<fs::File>),
+    /// Records are read ahead by a thread of their own
+    Staged(Staged),
+}
+
+impl fmt::Debug for SegmentReader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SegmentReader::Direct(ref fp) => f.debug_tuple("Direct").field(fp).finish(),
+            SegmentReader::Staged(ref staged) => f.debug_struct("Staged")
+                .field("position", &staged.position)
+                .field("read_ahead", &staged.ring.limit)
+                .finish(),
+        }
+    }
+}
+
+impl SegmentReader {
+    /// Read the records of `fp` from where it stands, reading ahead up to
+    /// `read_ahead` bytes if that is not zero, on `runtime` if given, as a
+    /// task of `supervisor`
+    pub fn new(
+        fp: BufReader<fs::File>,
+        read_ahead: usize,
+        runtime: Option<&Runtime>,
+        supervisor: &Supervisor,
+    ) -> io::Result<SegmentReader> {
+        if read_ahead == 0 {
+            return Ok(SegmentReader::Direct(fp));
+        }
+        Staged::spawn(fp, read_ahead, runtime, supervisor).map(SegmentReader::Staged)
+    }
+
+    /// Return the next record's payload, or None at the end of the file
+    ///
+    /// The end of the file is where the senders had written to when asked:
+    /// records written later are returned by later calls.
+    pub fn next_record(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match *self {
+            SegmentReader::Direct(ref mut fp) => match read_record(fp)? {
+                Fetched::Record(payload) => Ok(Some(payload)),
+                Fetched::End => Ok(None),
+                Fetched::Partial => Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "on-disk payload of advertised size not available",
+                )),
+            },
+            SegmentReader::Staged(ref mut staged) => staged.next_record(),
+        }
+    }
+
+    /// Return the offset in the file of the next record to be returned
+    pub fn position(&mut self) -> io::Result<u64> {
+        match *self {
+            SegmentReader::Direct(ref mut fp) => fp.stream_position(),
+            SegmentReader::Staged(ref staged) => Ok(staged.position),
+        }
+    }
+
+    /// Return the offset in the file of the next record to be returned, as
+    /// `position` but without moving the reader, or None if it is not known
+    pub fn offset(&self) -> Option<u64> {
+        match *self {
+            SegmentReader::Direct(ref fp) => {
+                let mut file = fp.get_ref();
+                let at = file.stream_position().ok()?;
+                Some(at - fp.buffer().len() as u64)
+            }
+            SegmentReader::Staged(ref staged) => Some(staged.position),
+        }
+    }
+
+    /// Return the metadata of the file being read
+    pub fn metadata(&self) -> io::Result<fs::Metadata> {
+        match *self {
+            SegmentReader::Direct(ref fp) => fp.get_ref().metadata(),
+            SegmentReader::Staged(ref staged) => staged.file.metadata(),
+        }
+    }
+}
+
+enum Fetched {
+    Record(Vec<u8>),
+    // Nothing more has been written
+    End,
+    // A record has only been partly written
+    Partial,
+}
+
+// Read the record at `fp`'s position.
+fn read_record<R: io::Read>(fp: &mut R) -> io::Result<Fetched> {
+    let mut sz_buf = [0; 4];
+    if !read_full(fp, &mut sz_buf)? {
+        return Ok(Fetched::End);
+    }
+    let mut payload = vec![0; ::receiver::u8tou32abe(&sz_buf) as usize];
+    if !read_full(fp, &mut payload)? {
+        return Ok(Fetched::Partial);
+    }
+    Ok(Fetched::Record(payload))
+}
+
+// Fill `buf`, returning false if the reader ran out first.
+fn read_full<R: io::Read>(fp: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    match fp.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// A queue file being read ahead into a staging ring
+pub struct Staged {
+    ring: Arc<Ring>,
+    // Kept to check whether the senders have sealed the file
+    file: fs::File,
+    position: u64,
+    reader: Reader,
+    // Reads the file once the read-ahead has been given up on
+    direct: Option<BufReader<fs::File>>,
+    // The simulation the read-ahead job runs on, if it does
+    driven: Option<Runtime>,
+}
+
+// What reads the records ahead
+enum Reader {
+    Thread(Option<thread::JoinHandle<()>>),
+    // The job and the file it reads from, taken to close it
+    Job(JobHandle, Arc<Mutex<Option<Cursor>>>),
+}
+
+// A queue file and the position in it of the next record to stage
+type Cursor = (BufReader<fs::File>, u64);
+
+// The records a read-ahead job stages before making way for other jobs
+const RECORDS_PER_TURN: usize = 64;
+
+struct Ring {
+    state: Mutex<State>,
+    cond: Condvar,
+    limit: usize,
+}
+
+#[derive(Default)]
+struct State {
+    records: VecDeque<Vec<u8>>,
+    // The bytes on disk of the staged records
+    bytes: usize,
+    // Set by the thread once it has read all that has been written, cleared
+    // by the Receiver to have it look again
+    at_end: bool,
+    failed: Option<io::Error>,
+    // Set once the reader has panicked too often to be started again
+    abandoned: bool,
+    stop: bool,
+}
+
+impl Ring {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        sync::lock(&self.state)
+    }
+
+    fn wait<'a>(&self, state: MutexGuard<'a, State>) -> MutexGuard<'a, State> {
+        sync::wait(&self.cond, state)
+    }
+
+    // Hand the Receiver an error the reader could not read past.
+    fn fail(&self, e: io::Error) {
+        let mut state = self.lock();
+        state.failed = Some(e);
+        state.at_end = true;
+        self.cond.notify_all();
+    }
+
+    // Leave the Receiver to read for itself.
+    fn abandon(&self) {
+        self.lock().abandoned = true;
+        self.cond.notify_all();
+    }
+}
+
+impl Staged {
+    fn spawn(
+        fp: BufReader<fs::File>,
+        limit: usize,
+        runtime: Option<&Runtime>,
+        supervisor: &Supervisor,
+    ) -> io::Result<Staged> {
+        let mut fp = fp;
+        let position = fp.stream_position()?;
+        let file = fp.get_ref().try_clone()?;
+        let ring = Arc::new(Ring {
+            state: Mutex::new(State::default()),
+            cond: Condvar::new(),
+            limit: limit,
+        });
+        let thr_ring = Arc::clone(&ring);
+        let registration = supervisor.register(Task::ReadAhead);
+        let reader = match runtime {
+            Some(runtime) => {
+                let cursor = Arc::new(Mutex::new(Some((fp, position))));
+                let job_cursor = Arc::clone(&cursor);
+                let mut restarted = false;
+                let job = runtime.spawn(move || {
+                    let turn = registration.attempt(|| {
+                        if restarted {
+                            rewind(&job_cursor)?;
+                        }
+                        Ok(read_ahead_turn(&job_cursor, &thr_ring))
+                    });
+                    restarted = false;
+                    match turn {
+                        Ok(Ok(next)) => next,
+                        Ok(Err(e)) => {
+                            thr_ring.fail(e);
+                            Next::Done
+                        }
+                        Err(Stopped::Restart(pause)) => {
+                            restarted = true;
+                            Next::After(pause)
+                        }
+                        Err(Stopped::GiveUp) => {
+                            thr_ring.abandon();
+                            Next::Done
+                        }
+                    }
+                });
+                Reader::Job(job, cursor)
+            }
+            None => {
+                let thread = thread::Builder::new()
+                    .name(supervisor.thread_name(Task::ReadAhead))
+                    .spawn(move || {
+                        let (mut fp, mut position) = (fp, position);
+                        loop {
+                            // A restart picks up after the last record staged.
+                            let run = registration.attempt(|| {
+                                fp.seek(SeekFrom::Start(position))?;
+                                read_ahead(&mut fp, &mut position, &thr_ring);
+                                Ok(())
+                            });
+                            match run {
+                                Ok(Ok(())) => return,
+                                Ok(Err(e)) => return thr_ring.fail(e),
+                                Err(Stopped::Restart(pause)) => thread::sleep(pause),
+                                Err(Stopped::GiveUp) => return thr_ring.abandon(),
+                            }
+                        }
+                    })?;
+                Reader::Thread(Some(thread))
+            }
+        };
+        Ok(Staged {
+            ring: ring,
+            file: file,
+            position: position,
+            reader: reader,
+            direct: None,
+            driven: runtime.filter(|runtime| runtime.is_driven()).cloned(),
+        })
+    }
+
+    // Have the reader look at the ring again.
+    fn prod(&self) {
+        self.ring.cond.notify_all();
+        if let Reader::Job(ref job, _) = self.reader {
+            job.wake();
+        }
+    }
+
+    fn next_record(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut state = self.ring.lock();
+        let mut looked_again = false;
+        loop {
+            if let Some(payload) = state.records.pop_front() {
+                let len = payload.len() + 4;
+                state.bytes -= len;
+                self.position += len as u64;
+                self.prod();
+                return Ok(Some(payload));
+            }
+            if let Some(e) = state.failed.take() {
+                return Err(e);
+            }
+            if state.abandoned {
+                drop(state);
+                return self.read_directly();
+            }
+            if state.at_end {
+                // The thread may have found the end before the record asked
+                // for was written, so look once more before giving up.
+                if looked_again {
+                    return Ok(None);
+                }
+                looked_again = true;
+                state.at_end = false;
+                self.prod();
+            }
+            // A simulation runs nothing of itself, so its read-ahead job is
+            // run here, and only that job: the Receiver may hold the
+            // channel's lock, which other jobs take.
+            state = match (&self.driven, &self.reader) {
+                (Some(runtime), Reader::Job(job, _)) => {
+                    drop(state);
+                    runtime.run_job(job);
+                    self.ring.lock()
+                }
+                _ => self.ring.wait(state),
+            };
+        }
+    }
+}
+
+impl Staged {
+    // Read the next record from the file, reading ahead having been given up
+    // on, as `stage` would have.
+    fn read_directly(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.direct.is_none() {
+            let mut fp = BufReader::new(self.file.try_clone()?);
+            fp.seek(SeekFrom::Start(self.position))?;
+            self.direct = Some(fp);
+        }
+        let fp = self.direct.as_mut().expect("no direct reader");
+        match read_record(fp)? {
+            Fetched::Record(payload) => {
+                self.position += payload.len() as u64 + 4;
+                Ok(Some(payload))
+            }
+            _ => {
+                fp.seek(SeekFrom::Start(self.position))?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl Drop for Staged {
+    fn drop(&mut self) {
+        self.ring.lock().stop = true;
+        self.prod();
+        // Close the file before the Receiver removes it.
+        match self.reader {
+            Reader::Thread(ref mut thread) => {
+                if let Some(thread) = thread.take() {
+                    let _ = thread.join();
+                }
+            }
+            Reader::Job(_, ref cursor) => {
+                sync::lock(cursor).take();
+            }
+        }
+    }
+}
+
+// The read-ahead thread: stage records from `fp`, which stands at `position`,
+// until the ring is full, then wait for the Receiver to take some.
+fn read_ahead(fp: &mut BufReader<fs::File>, position: &mut u64, ring: &Ring) {
+    loop {
+        {
+            let mut state = ring.lock();
+            while !state.stop && (state.at_end || state.bytes >= ring.limit) {
+                state = ring.wait(state);
+            }
+            if state.stop {
+                return;
+            }
+        }
+        stage(fp, position, ring);
+    }
+}
+
+// Put a read-ahead job's file back at the start of the next record to stage,
+// after a turn that panicked partway through a read.
+fn rewind(cursor: &Mutex<Option<Cursor>>) -> io::Result<()> {
+    if let Some((ref mut fp, position)) = *sync::lock(cursor) {
+        fp.seek(SeekFrom::Start(position))?;
+    }
+    Ok(())
+}
+
+// A turn of a read-ahead job: stage records as the thread would, until the
+// ring is full or the turn is up.
+fn read_ahead_turn(cursor: &Mutex<Option<Cursor>>, ring: &Ring) -> Next {
+    let mut cursor = sync::lock(cursor);
+    let (fp, position) = match *cursor {
+        Some((ref mut fp, ref mut position)) => (fp, position),
+        None => return Next::Done,
+    };
+    for _ in 0..RECORDS_PER_TURN {
+        {
+            let state = ring.lock();
+            if state.stop {
+                return Next::Done;
+            }
+            if state.at_end || state.bytes >= ring.limit {
+                return Next::Woken;
+            }
+        }
+        stage(fp, position, ring);
+    }
+    Next::After(Duration::from_millis(0))
+}
+
+// Stage the record at `position` of `fp`, or mark the ring at its end if the
+// senders have written no more.
+fn stage(fp: &mut BufReader<fs::File>, position: &mut u64, ring: &Ring) {
+    let read = read_record(fp);
+    // Reading past what the senders have written may have consumed part of a
+    // record: step back to its start to read it whole later.
+    let read = match read {
+        Ok(Fetched::Record(payload)) => Ok(Fetched::Record(payload)),
+        Ok(_) => fp.seek(SeekFrom::Start(*position)).map(|_| Fetched::End),
+        Err(e) => Err(e),
+    };
+    let mut state = ring.lock();
+    match read {
+        Ok(Fetched::Record(payload)) => {
+            *position += payload.len() as u64 + 4;
+            state.bytes += payload.len() + 4;
+            state.records.push_back(payload);
+        }
+        Ok(_) => state.at_end = true,
+        Err(e) => {
+            state.failed = Some(e);
+            state.at_end = true;
+        }
+    }
+    ring.cond.notify_all();
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::*;
+    use std::io::Write;
+
+    fn record(payload: &[u8]) -> Vec<u8> {
+        let len = payload.len();
+        // Queue files store lengths in the byte order `u8tou32abe` reads.
+        let mut buf = vec![(len >> 16) as u8, (len >> 24) as u8, (len >> 8) as u8, len as u8];
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    #[test]
+    fn staged_records_follow_the_writer() {
+        let runtime = Runtime::new(1);
+        for (i, runtime) in [None, Some(&runtime)].iter().enumerate() {
+            let dir = tempdir::TempDir::new("hopper").unwrap();
+            let path = dir.path().join(format!("{}", i));
+            let mut out = fs::File::create(&path).unwrap();
+            out.write_all(&record(b"one")).unwrap();
+            let partial = record(b"two");
+            out.write_all(&partial[..5]).unwrap();
+
+            let fp = BufReader::new(fs::File::open(&path).unwrap());
+            let supervisor = Supervisor::new("staged", None);
+            let mut reader = SegmentReader::new(fp, 2, *runtime, &supervisor).unwrap();
+            assert_eq!(Some(b"one".to_vec()), reader.next_record().unwrap());
+            assert_eq!(None, reader.next_record().unwrap());
+            assert_eq!(7, reader.position().unwrap());
+
+            out.write_all(&partial[5..]).unwrap();
+            out.write_all(&record(b"three")).unwrap();
+            assert_eq!(Some(b"two".to_vec()), reader.next_record().unwrap());
+            assert_eq!(Some(b"three".to_vec()), reader.next_record().unwrap());
+            assert_eq!(None, reader.next_record().unwrap());
+            assert_eq!(23, reader.position().unwrap());
+        }
+    }
+}
@@ -0,0 +1,355 @@
+//! Spill channels to disk when the host comes under memory pressure
+//!
+//! On Linux kernels with pressure stall information, `/proc/pressure/memory`
+//! reports the share of recent time tasks spent stalled waiting on memory.
+//! `watch` polls that figure and, whenever it crosses a threshold, calls
+//! `Sender::relieve_memory_pressure` so a channel gives up what memory it can
+//! before the host reaches for the OOM killer.
+//!
+//! `adapt` instead watches how much memory the process uses, or whatever a
+//! probe of the caller's reports, and shrinks the channel's in-memory
+//! thresholds as use nears a limit, so the channel spills earlier the less
+//! memory there is to spare. With the `cgroup` feature, `adapt_to_cgroup`
+//! does the same against the memory limit of the process's cgroup.
+use private;
+use super::{Error, Sender};
+use supervise::Task;
+#[cfg(feature = "cgroup")]
+use cgroup::CgroupMemory;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const PSI_MEMORY: &str = "/proc/pressure/memory";
+const PROC_STATUS: &str = "/proc/self/status";
+
+/// Read the ten second average of the "some" line of a PSI file
+///
+/// The "some" line gives the percentage of time at least one task was stalled.
+pub fn some_avg10(psi: &str) -> Option<f32> {
+    psi.lines()
+        .find(|line| line.starts_with("some "))
+        .and_then(|line| {
+            line.split_whitespace()
+                .find(|field| field.starts_with("avg10="))
+                .and_then(|field| field["avg10=".len()..].parse::<f32>().ok())
+        })
+}
+
+/// Read the resident set size, in bytes, from a `/proc/<pid>/status` file
+pub fn vm_rss(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find(|line| line.starts_with("VmRSS:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+}
+
+/// How `adapt` measures memory use
+#[derive(Clone)]
+pub enum MemoryProbe {
+    /// The resident set size of this process, read from `/proc/self/status`
+    /// on Linux
+    ProcessRss,
+    /// A function of the caller's returning the bytes in use, or None if it
+    /// cannot tell
+    Custom(Arc<dyn Fn() -> Option<u64> + Send + Sync>),
+}
+
+impl fmt::Debug for MemoryProbe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MemoryProbe::ProcessRss => f.write_str("ProcessRss"),
+            MemoryProbe::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+impl MemoryProbe {
+    /// Return the bytes in use, if the probe can tell
+    pub fn read(&self) -> Option<u64> {
+        match *self {
+            MemoryProbe::ProcessRss => fs::read_to_string(PROC_STATUS)
+                .ok()
+                .and_then(|status| vm_rss(&status)),
+            MemoryProbe::Custom(ref probe) => probe(),
+        }
+    }
+}
+
+/// The share of its configured memory a channel may use when `used` of
+/// `limit` bytes are in use
+///
+/// A channel keeps all of it until half of `limit` is in use, then gives it
+/// up steadily, keeping none once `limit` is reached.
+pub fn memory_scale(used: u64, limit: u64) -> f64 {
+    let half = limit as f64 / 2.0;
+    if used as f64 <= half {
+        1.0
+    } else if used >= limit {
+        0.0
+    } else {
+        (limit - used) as f64 / half
+    }
+}
+
+/// Shrink `sender`'s channel as the memory `probe` reports in use nears
+/// `limit` bytes
+///
+/// A background thread reads `probe` every `interval` and calls
+/// `Sender::scale_memory` on a clone of `sender` with `memory_scale` of the
+/// reading. The in-memory tier and disk buffer keep their full size while
+/// less than half of `limit` is in use and shrink towards a single item as
+/// use reaches it, growing back as memory is freed. The thread holds a Sender
+/// of its own, which does not keep the channel from hanging up, and exits
+/// once every other Sender and the Receiver of the channel have been dropped.
+///
+/// Returns `Error::PressureUnavailable` if `probe` cannot tell how much
+/// memory is in use, as `MemoryProbe::ProcessRss` cannot on non-Linux
+/// systems, and `Error::Unsupported` where there are no threads.
+pub fn adapt<'de, T>(
+    sender: &Sender<T>,
+    probe: MemoryProbe,
+    limit: u64,
+    interval: Duration,
+) -> Result<(), Error>
+where
+    T: Serialize + Deserialize<'de> + Send + 'static,
+{
+    if !private::HAS_THREADS {
+        return Err(Error::Unsupported);
+    }
+    if probe.read().is_none() {
+        return Err(Error::PressureUnavailable);
+    }
+    spawn_scaler(sender, interval, move || {
+        probe.read().map(|used| memory_scale(used, limit))
+    });
+    Ok(())
+}
+
+/// Shrink `sender`'s channel as its cgroup's memory use nears `percent` of
+/// the cgroup's limit
+///
+/// As `adapt`, with the cgroup v2 `memory.current` of this process as the
+/// reading and `percent` of its `memory.max` as the limit. Both are re-read
+/// every `interval`, so a container that is resized moves the limit with it.
+/// A channel whose cgroup has no limit keeps its full size.
+///
+/// Returns `Error::PressureUnavailable` if this process is not in a cgroup
+/// v2 hierarchy.
+#[cfg(feature = "cgroup")]
+pub fn adapt_to_cgroup<'de, T>(
+    sender: &Sender<T>,
+    percent: f64,
+    interval: Duration,
+) -> Result<(), Error>
+where
+    T: Serialize + Deserialize<'de> + Send + 'static,
+{
+    if CgroupMemory::read().is_none() {
+        return Err(Error::PressureUnavailable);
+    }
+    spawn_scaler(sender, interval, move || {
+        CgroupMemory::read().map(|memory| match memory.share(percent) {
+            Some(limit) => memory_scale(memory.current, limit),
+            None => 1.0,
+        })
+    });
+    Ok(())
+}
+
+// Call `Sender::scale_memory` on a clone of `sender` whenever the scale
+// `measure` gives every `interval` changes, until the channel is orphaned.
+fn spawn_scaler<'de, T, F>(sender: &Sender<T>, interval: Duration, measure: F)
+where
+    T: Serialize + Deserialize<'de> + Send + 'static,
+    F: Fn() -> Option<f64> + Send + 'static,
+{
+    let mut sender = sender.internal_clone();
+    let supervisor = sender.supervisor();
+    let mut scale = 1.0;
+    supervisor
+        .spawn(Task::Pressure, move || while !sender.is_orphaned() {
+            if let Some(next) = measure() {
+                if next != scale {
+                    scale = next;
+                    sender.scale_memory(scale);
+                }
+            }
+            thread::sleep(interval);
+        })
+        .expect("could not start memory scaler");
+}
+
+/// Relieve `sender`'s channel whenever memory pressure exceeds `threshold`
+///
+/// A background thread checks `/proc/pressure/memory` every `interval` and
+/// calls `Sender::relieve_memory_pressure` on a clone of `sender` while the
+/// ten second stall average is above `threshold` percent. The thread holds a
+/// Sender of its own, which does not keep the channel from hanging up, and
+/// exits once every other Sender and the Receiver of the channel have been
+/// dropped.
+///
+/// Returns `Error::PressureUnavailable` if the host does not report memory
+/// pressure, as on non-Linux systems or kernels built without PSI, and
+/// `Error::Unsupported` where there are no threads.
+pub fn watch<'de, T>(sender: &Sender<T>, threshold: f32, interval: Duration) -> Result<(), Error>
+where
+    T: Serialize + Deserialize<'de> + Send + 'static,
+{
+    if !private::HAS_THREADS {
+        return Err(Error::Unsupported);
+    }
+    if fs::read_to_string(PSI_MEMORY).ok().and_then(|p| some_avg10(&p)).is_none() {
+        return Err(Error::PressureUnavailable);
+    }
+    let mut sender = sender.internal_clone();
+    let supervisor = sender.supervisor();
+    supervisor
+        .spawn(Task::Pressure, move || while !sender.is_orphaned() {
+            let pressure = fs::read_to_string(PSI_MEMORY)
+                .ok()
+                .and_then(|p| some_avg10(&p));
+            if pressure.is_some_and(|p| p > threshold) {
+                sender.relieve_memory_pressure();
+            }
+            thread::sleep(interval);
+        })
+        .expect("could not start memory pressure watcher");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use layout;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use super::{adapt, memory_scale, some_avg10, vm_rss, MemoryProbe};
+    use super::super::{channel, Error};
+
+    #[test]
+    fn psi_some_average_is_parsed() {
+        let psi = "some avg10=1.53 avg60=0.87 avg300=0.20 total=1234\n\
+                   full avg10=0.50 avg60=0.10 avg300=0.00 total=99\n";
+        assert_eq!(Some(1.53), some_avg10(psi));
+        assert_eq!(None, some_avg10("full avg10=0.50\n"));
+        assert_eq!(None, some_avg10(""));
+    }
+
+    #[test]
+    fn relieving_pressure_pages_out_memory() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel("relieve", dir.path()).unwrap();
+
+        for i in 0..1500u64 {
+            snd.send(i);
+        }
+        let header = layout::SEGMENT_HEADER_LEN as u64;
+        let record = 4 + 8;
+        assert_eq!(header, snd.metrics().disk_bytes);
+        // The in-memory tier goes to the paged file, the disk buffer to the
+        // queue file.
+        assert_eq!(1500 * record, snd.relieve_memory_pressure());
+        assert_eq!(header + 476 * record, snd.metrics().disk_bytes);
+        assert_eq!(0, snd.metrics().in_memory_depth);
+        assert_eq!(0, snd.relieve_memory_pressure());
+
+        // The in-memory tier stays closed.
+        for i in 1500..2600u64 {
+            snd.send(i);
+        }
+        assert_eq!(76, snd.metrics().in_memory_depth);
+        for i in 0..2600u64 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        assert_eq!(None, rcv.try_iter().next());
+        let paged = dir.path().join("relieve").join(layout::PAGED_FILE);
+        assert_eq!(0, paged.metadata().unwrap().len());
+    }
+
+    #[test]
+    fn rss_is_parsed_and_scaled() {
+        let status = "Name:\thopper\nVmPeak:\t  9000 kB\nVmRSS:\t  2048 kB\n";
+        assert_eq!(Some(2048 * 1024), vm_rss(status));
+        assert_eq!(None, vm_rss("Name:\thopper\n"));
+        assert_eq!(1.0, memory_scale(0, 1000));
+        assert_eq!(1.0, memory_scale(500, 1000));
+        assert_eq!(0.5, memory_scale(750, 1000));
+        assert_eq!(0.0, memory_scale(1000, 1000));
+        assert_eq!(0.0, memory_scale(2000, 1000));
+    }
+
+    #[test]
+    fn scaled_channel_pages_out_early() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel("scaled", dir.path()).unwrap();
+
+        assert_eq!(0, snd.scale_memory(0.25));
+        for i in 0..600u64 {
+            snd.send(i);
+        }
+        let header = layout::SEGMENT_HEADER_LEN as u64;
+        let record = 4 + 8;
+        assert_eq!(256, snd.metrics().records_written);
+        assert_eq!(88 * record, snd.scale_memory(0.01));
+        assert_eq!(header + 344 * record, snd.metrics().disk_bytes);
+        assert_eq!(0, snd.scale_memory(1.0));
+        for i in 0..600u64 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        assert_eq!(None, rcv.try_iter().next());
+    }
+
+    #[test]
+    fn adapt_scales_by_probe() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel("adapt", dir.path()).unwrap();
+
+        let blind = MemoryProbe::Custom(Arc::new(|| None));
+        assert_eq!(
+            Err(Error::PressureUnavailable),
+            adapt(&snd, blind, 1000, Duration::from_millis(1))
+        );
+        let full = MemoryProbe::Custom(Arc::new(|| Some(1000)));
+        adapt(&snd, full, 1000, Duration::from_millis(1)).unwrap();
+        assert_eq!(1, rcv.sender_count());
+        thread::sleep(Duration::from_millis(100));
+        // The in-memory tier is closed after one item and every item since
+        // is paged out as it is sent.
+        for i in 0..10u64 {
+            snd.send(i);
+        }
+        assert_eq!(9, snd.metrics().records_written);
+        drop(snd);
+        assert_eq!((0..10).collect::<Vec<u64>>(), rcv.iter().collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn background_senders_exit_with_the_channel() {
+        use std::time::Instant;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (snd, rcv) = channel::<u64>("background", dir.path()).unwrap();
+        let supervisor = snd.supervisor();
+        snd.spawn_flusher(Duration::from_millis(1), 16);
+        let idle = MemoryProbe::Custom(Arc::new(|| Some(0)));
+        adapt(&snd, idle, 1000, Duration::from_millis(1)).unwrap();
+        assert_eq!(2, supervisor.health().tasks.len());
+
+        // Neither keeps the other running once the channel is dropped
+        drop((snd, rcv));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !supervisor.health().tasks.is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(supervisor.health().tasks.is_empty());
+    }
+}
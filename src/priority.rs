@@ -0,0 +1,223 @@
+/// The lane an item is sent on with `Sender::send_with_priority`
+///
+/// Channels built with `ChannelBuilder::priority_lanes` keep one queue per
+/// lane. The Receiver drains higher lanes first, except that after
+/// `LANE_BURST` items in a row it starts once from a lower lane, so a steady
+/// stream of high priority items cannot starve the others completely. On a
+/// channel built with `ChannelBuilder::priority_aging` an item that has
+/// waited long enough is also taken as if sent on a higher lane.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Drained before every other lane
+    High,
+    /// The lane `Sender::send` uses. This is the default.
+    #[default]
+    Normal,
+    /// Drained once the other lanes are empty
+    Low,
+}
+
+/// The number of items the Receiver takes in priority order before giving a
+/// lower lane the first look
+pub const LANE_BURST: usize = 16;
+
+/// The order in which the Receiver tries its lanes on the `turn`th receive
+///
+/// Lanes are numbered by priority: 0 is high, 1 normal and 2 low. Every
+/// `LANE_BURST`th turn starts from normal or low, alternating between them.
+pub fn lane_order(turn: usize) -> [usize; 3] {
+    if turn % LANE_BURST != LANE_BURST - 1 {
+        [0, 1, 2]
+    } else if (turn / LANE_BURST).is_multiple_of(2) {
+        [1, 2, 0]
+    } else {
+        [2, 0, 1]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::{lane_order, Priority, LANE_BURST};
+    use super::super::ChannelBuilder;
+
+    #[test]
+    fn lanes_favour_high_priority_every_turn_but_one() {
+        let favoured = (0..LANE_BURST).filter(|t| lane_order(*t)[0] == 0).count();
+        assert_eq!(LANE_BURST - 1, favoured);
+        assert_eq!(1, lane_order(LANE_BURST - 1)[0]);
+        assert_eq!(2, lane_order(2 * LANE_BURST - 1)[0]);
+    }
+
+    #[test]
+    fn receiver_drains_higher_lanes_first() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("lanes_order", dir.path())
+            .priority_lanes()
+            .build()
+            .unwrap();
+
+        snd.send_with_priority(3u64, Priority::Low).unwrap();
+        snd.send(2u64);
+        snd.send_with_priority(1u64, Priority::High).unwrap();
+
+        let received: Vec<u64> = rcv.try_iter().collect();
+        assert_eq!(vec![1, 2, 3], received);
+    }
+
+    #[test]
+    fn low_priority_is_not_starved() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("lanes_starve", dir.path())
+            .priority_lanes()
+            .build()
+            .unwrap();
+
+        for i in 0..(4 * LANE_BURST as u64) {
+            snd.send_with_priority(i, Priority::High).unwrap();
+        }
+        snd.send_with_priority(u64::MAX, Priority::Low).unwrap();
+
+        let position = rcv.try_iter()
+            .position(|v| v == u64::MAX)
+            .expect("low priority item never delivered");
+        assert!(position < 2 * LANE_BURST);
+    }
+
+    #[test]
+    fn aged_items_are_promoted_a_lane_at_a_time() {
+        use std::sync::Arc;
+        use std::time::Duration;
+        use testing::ManualClock;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let (mut snd, mut rcv) = ChannelBuilder::new("lanes_aging", dir.path())
+            .priority_aging(Duration::from_secs(10))
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+
+        snd.send_with_priority(3u64, Priority::Low).unwrap();
+        snd.send(2u64);
+        clock.advance(Duration::from_secs(10));
+        snd.send_with_priority(1u64, Priority::High).unwrap();
+
+        // Both have waited one step: normal now ranks with high and goes
+        // first for having waited longer, low ranks with normal.
+        let received: Vec<u64> = rcv.try_iter().collect();
+        assert_eq!(vec![2, 1, 3], received);
+    }
+
+    #[test]
+    fn aging_bounds_the_wait_behind_a_busy_high_lane() {
+        use std::sync::Arc;
+        use std::time::Duration;
+        use testing::ManualClock;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let (mut snd, mut rcv) = ChannelBuilder::new("lanes_aging_fair", dir.path())
+            .priority_aging(Duration::from_secs(1))
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+
+        snd.send_with_priority(u64::MAX, Priority::Low).unwrap();
+        let mut position = None;
+        for i in 0..(4 * LANE_BURST as u64) {
+            snd.send_with_priority(i, Priority::High).unwrap();
+            snd.send_with_priority(i, Priority::High).unwrap();
+            match rcv.try_iter().next() {
+                Some(v) if v == u64::MAX => {
+                    position = Some(i);
+                    break;
+                }
+                Some(_) => {}
+                None => panic!("channel ran dry"),
+            }
+            clock.advance(Duration::from_millis(500));
+        }
+        // Two seconds, four receives, for the low item to rank with high.
+        assert_eq!(Some(4), position);
+    }
+
+    #[test]
+    fn aging_keeps_priority_order_for_fresh_items() {
+        use std::sync::Arc;
+        use std::time::Duration;
+        use testing::ManualClock;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let (mut snd, mut rcv) = ChannelBuilder::new("lanes_aging_fresh", dir.path())
+            .priority_aging(Duration::from_secs(60))
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+
+        snd.send_with_priority(3u64, Priority::Low).unwrap();
+        snd.send(2u64);
+        snd.send_with_priority(1u64, Priority::High).unwrap();
+        clock.advance(Duration::from_secs(1));
+
+        let received: Vec<u64> = rcv.try_iter().collect();
+        assert_eq!(vec![1, 2, 3], received);
+        assert_eq!(None, rcv.try_iter().next());
+    }
+
+    #[test]
+    fn priority_without_lanes_is_plain_send() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("lanes_absent", dir.path())
+            .build()
+            .unwrap();
+
+        snd.send_with_priority(1u64, Priority::Low).unwrap();
+        snd.send_with_priority(2u64, Priority::High).unwrap();
+        let received: Vec<u64> = rcv.try_iter().collect();
+        assert_eq!(vec![1, 2], received);
+    }
+
+    #[test]
+    fn lanes_take_one_token_and_hand_refusals_back() {
+        use super::super::{RatePolicy, SendError};
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("lanes_rate", dir.path())
+            .priority_lanes()
+            .rate_limit(1, 2, RatePolicy::Fail)
+            .build()
+            .unwrap();
+
+        assert_eq!(Ok(()), snd.send_with_priority(1u64, Priority::High));
+        assert_eq!(Ok(()), snd.send_with_priority(2u64, Priority::Low));
+        assert_eq!(Err(SendError::RateLimited(3)), snd.send_with_priority(3u64, Priority::High));
+        assert_eq!(1, snd.metrics().total_rate_limited);
+        let received: Vec<u64> = rcv.try_iter().collect();
+        assert_eq!(vec![1, 2], received);
+    }
+
+    #[test]
+    fn full_lane_hands_the_item_back() {
+        use super::super::SendError;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, _rcv) = ChannelBuilder::new("lanes_full", dir.path())
+            .priority_lanes()
+            .memory_only(true)
+            .build()
+            .unwrap();
+
+        let mut sent = 0u64;
+        let refused = loop {
+            match snd.send_with_priority(sent, Priority::Low) {
+                Ok(()) => sent += 1,
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(SendError::Full(sent), refused);
+        assert_eq!(Ok(()), snd.send_with_priority(sent, Priority::High));
+    }
+}
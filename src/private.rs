@@ -1,9 +1,49 @@
+use std::cmp;
 use std::collections::VecDeque;
-use std::sync::{Arc, Mutex};
-use std::io::BufWriter;
+use std::sync::{Arc, Weak};
+use std::io::{self, BufWriter, Read, Write};
+use std::env;
+use std::fmt;
 use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{self, AtomicUsize};
+use std::task::Waker;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use admission::Admission;
+use archive::Archiver;
+use budget::Charge;
+use clock::{self, Clock, SystemClock};
+use delay;
+use index::{self, SegmentIndex};
+use layout::{self, RecordFraming};
+use metrics::Metrics;
+use paged::Paged;
+use receiver::u8tou32abe;
+use rate::{RateLimit, TokenBucket};
+use reserve::Reservations;
+use retention::Retention;
+use retry::{Retries, RetryPolicy};
+use segment;
+use summary::{self, SegmentSummary};
+use supervise::{Health, Supervisor};
+use runtime::{JobHandle, Runtime};
+use sync::{self, Condvar, Mutex, MutexGuard};
+use watermark::Watermarks;
+#[cfg(feature = "encryption")]
+use crypt;
+#[cfg(feature = "dictionary")]
+use dictionary;
+use super::{ConfigDelta, CorruptionPolicy, OrderMode, OverflowPolicy, QueueEvent};
+
+pub type Observer = Arc<dyn Fn(QueueEvent) + Send + Sync>;
+
+// Queue files that fill faster than this grow the next file when adaptive
+// sizing is enabled, files that fill slower than SLOW_SEGMENT shrink it.
+const FAST_SEGMENT: Duration = Duration::from_secs(1);
+const SLOW_SEGMENT: Duration = Duration::from_secs(30);
 
-#[derive(Default, Debug)]
 pub struct FsSync<T> {
     pub receiver_read_id: u64,
     pub receiver_idx: Option<usize>,
@@ -17,11 +57,386 @@ pub struct FsSync<T> {
     pub sender_fp: Option<BufWriter<fs::File>>,
 
     pub in_memory_idx: usize,
+    // The items the in-memory tier and the disk buffer each hold, without a
+    // memory budget, and the fraction of that and of a budget they may use
+    // while `pressure::adapt` finds memory short
+    pub capacity: usize,
+    pub spill_scale: f64,
     pub bytes_written: usize,
+    // What the records written to the current queue file add up to, and
+    // where they are, for its summary and index once sealed
+    pub segment_summary: SegmentSummary,
+    pub segment_index: SegmentIndex,
     pub disk_writes_to_read: usize,
     pub sender_seq_num: usize,
     pub mem_buffer: VecDeque<T>,
     pub disk_buffer: VecDeque<T>,
+    // Scratch space the disk buffer is encoded into when spilled, kept
+    // between spills so they need not allocate
+    pub encode_buf: Vec<u8>,
+    // With staged writes, the encoding of each item in the disk buffer its
+    // Sender made before handing it over, if it did, running parallel to the
+    // disk buffer
+    pub stage_writes: bool,
+    pub disk_encodings: VecDeque<Option<Vec<u8>>>,
+    // Items the Receiver has finished with, for `Sender::send_with` to fill
+    // in again, no more than `capacity` of them
+    pub recycled: Vec<T>,
+
+    // When the channel stamps its items--with a TTL set, provenance recorded,
+    // priority aging or send times asked for--the send time of each buffered
+    // item in milliseconds since the UNIX epoch, running parallel to the
+    // buffers above
+    pub ttl: Option<Duration>,
+    pub stamp_sends: bool,
+    // Items sent before this, in milliseconds since the UNIX epoch, were
+    // purged and are dropped as the Receiver reaches them. The unread queue
+    // file a purge cut the front from, the offset of the record the Receiver
+    // is to start it at and the items before that, no longer counted.
+    pub purge_floor: u64,
+    pub purge_skip: Option<(PathBuf, u64, u64)>,
+    pub mem_stamps: VecDeque<u64>,
+    pub disk_stamps: VecDeque<u64>,
+    // The time the stamps, expiry, delays and retention go by
+    pub clock: Arc<dyn Clock>,
+    // With priority aging, the wait after which an item on a priority lane is
+    // taken as a lane higher, and whether the Receiver has drawn this lane's
+    // head ahead of its turn to learn its age
+    pub aging: Option<Duration>,
+    pub head_drawn: bool,
+
+    // With provenance recorded, the id of the Sender of each buffered item,
+    // running parallel to the buffers above, and the id the next Sender
+    // created is given
+    pub provenance: bool,
+    pub mem_origins: VecDeque<u32>,
+    pub disk_origins: VecDeque<u32>,
+    pub next_sender_id: u32,
+    // With Senders sequenced, the place of each buffered item among its
+    // Sender's items, running parallel to the buffers above
+    pub sequenced: bool,
+    pub mem_seqs: VecDeque<u64>,
+    pub disk_seqs: VecDeque<u64>,
+
+    // With a memory budget set, the in-memory tier and the disk buffer are
+    // bounded by the encoded size of their items rather than by count. The
+    // encoded size of each item in the disk buffer runs parallel to it.
+    pub memory_budget: Option<usize>,
+    // A budget kept at a share of the cgroup's memory limit, which takes the
+    // place of `memory_budget` while it is set
+    #[cfg(feature = "cgroup")]
+    pub cgroup_budget: Option<Arc<AtomicUsize>>,
+    pub mem_bytes: usize,
+    pub disk_buffer_bytes: usize,
+    pub disk_sizes: VecDeque<usize>,
+    // With a `Budget` shared with other channels, the bytes this channel
+    // holds against it, and the encoded size of each item in the in-memory
+    // tier, running parallel to it
+    pub shared_budget: Option<Charge>,
+    pub mem_sizes: VecDeque<usize>,
+    // With groups reserving shares of the channel, the groups and the Senders
+    // in each. The in-memory tier keeps its items' sizes, as for a shared
+    // `Budget`, to give them back to their groups as they are received.
+    pub reservations: Option<Reservations>,
+
+    // Seals records as they are paged out, if the channel encrypts
+    #[cfg(feature = "encryption")]
+    pub cipher: Option<crypt::Cipher>,
+
+    // Where sealed queue files are moved to, if anywhere, and what moves
+    // them
+    pub archive: Option<PathBuf>,
+    pub archiver: Option<Archiver>,
+    // What the Receiver does with records it cannot decode, holding the
+    // dead-letter file itself rather than its directory
+    pub corruption: CorruptionPolicy,
+
+    // When queue files the Receiver has read past are deleted
+    pub retention: Retention,
+
+    // The channel's rate limit, if it has one, and the bucket its Senders
+    // share unless each has its own
+    pub rate: Option<(RateLimit, Arc<Mutex<TokenBucket>>)>,
+
+    // The depths at which the observer is told the channel is running deep
+    // or has drained, and with adaptive sampling the rate sampled sends fall
+    // to as the depth climbs between them
+    pub watermarks: Watermarks,
+    pub sampling_floor: Option<f64>,
+
+    // The channel directory's lock file, held until the last handle drops
+    pub dir_lock: Option<fs::File>,
+
+    // The bytes of queue file the Receiver reads ahead of itself, if any
+    pub read_ahead: usize,
+    // The bytes of records packed into each block paged out, if they are,
+    // and whether blocks are compressed
+    pub block_bytes: usize,
+    pub compress_blocks: bool,
+    // Samples records for, and compresses blocks with, a trained dictionary
+    #[cfg(feature = "dictionary")]
+    pub trainer: Option<dictionary::Trainer>,
+    // Whether new queue files have disk reserved up to their rotation size
+    pub preallocate: bool,
+    // Runs the channel's background tasks
+    pub supervisor: Supervisor,
+    // The error of the last write to a queue file that failed, until paging
+    // out next succeeds, and how paging out has fared under the channel's
+    // retry policy
+    pub last_write_error: Option<String>,
+    pub retries: Retries,
+    // The pool background work is run on, if not threads of its own
+    pub runtime: Option<Runtime>,
+
+    // Whether the disk buffer is held rather than paged out, and what a send
+    // finding it full does
+    pub memory_only: bool,
+    pub overflow: OverflowPolicy,
+    // The bytes the channel's queue files may take, if capped, and the
+    // metrics that count the bytes they do take. Whether the disk buffer is
+    // being held in memory at the cap, so that reaching it is raised once.
+    pub disk_quota: Option<(usize, Arc<Metrics>)>,
+    pub at_disk_quota: bool,
+    // Whether unread queue files are deleted to make room at the cap
+    pub evict_oldest: bool,
+    // What this lane shares with the others, on a channel with priority lanes
+    pub lanes: Option<LaneShare<T>>,
+    // Whether every item is paged out as it is sent
+    pub disk_primary: bool,
+
+    // The background flusher, if one is running, and how many items may wait
+    // in the disk buffer before it is woken early
+    pub flusher: Option<(Arc<FlushSignal>, usize)>,
+
+    pub wakers: Vec<Waker>,
+    // With wakes coalesced, the records and the time a wake may be held back
+    // for; when the Receiver last took items; and, for a wake held back, when
+    // it was first due and the records it covers
+    pub coalesce: Option<(usize, Duration)>,
+    pub drained_at: Option<Instant>,
+    pub deferred_wake: Option<(Instant, usize)>,
+    // The write end of the pipe an event loop polls for the Receiver, if it
+    // has handed one out, and whether the channel's byte is in it
+    pub ready_pipe: Option<Arc<io::PipeWriter>>,
+    pub ready_signalled: bool,
+
+    // The queue Senders take their turn at this lock through
+    pub admission: Arc<Admission>,
+    // Woken as room is made for Senders waiting on a full channel that
+    // blocks, and how many of them wait on it
+    pub room: Arc<Condvar>,
+    pub room_waiters: usize,
+
+    // Set by `Receiver::close`, after which sends are discarded
+    pub closed: bool,
+    // The channel's live Senders, counting those hopper holds itself, and
+    // how many of them it does: a flusher's or memory pressure watcher's
+    pub senders: usize,
+    pub internal_senders: usize,
+    // Set by `Receiver::pause`, while which nothing is delivered
+    pub paused: bool,
+
+    // Items sent with `Sender::send_after`, and the timer waking the
+    // Receiver as each comes due, if one is running
+    pub delayed: delay::Store,
+    // Items of the in-memory tier paged out under memory pressure, unless the
+    // channel is memory-only
+    pub paged: Option<Paged>,
+    pub delay_timer: Option<Arc<FlushSignal>>,
+
+    pub segment_max_bytes: usize,
+    pub segment_bounds: Option<(usize, usize)>,
+    pub segment_opened: Option<Instant>,
+
+    pub order: OrderMode,
+    // Whether a dropped Sender hands over the items it has staged
+    pub flush_on_drop: bool,
+    pub observer: Option<Observer>,
+
+    // The temporary directory of an ephemeral channel, shared with its lanes.
+    // Last so that the files above are closed before it is removed.
+    pub ephemeral: Option<Arc<EphemeralDir>>,
+}
+
+/// What the lanes of a channel with priority lanes share, as each lane's
+/// state holds it
+///
+/// The lanes take their disk quota together. A lane at the quota evicts the
+/// unread queue files of the lanes below it, lowest first, before its own,
+/// and never those of a lane above it. Only a lane locks those below it, so
+/// the lanes' locks are always taken from high to low.
+pub struct LaneShare<T> {
+    /// The metrics of every lane, counting the disk bytes that the quota
+    /// holds them to together
+    pub disk: Vec<Arc<Metrics>>,
+    /// The directory and state of each lane below this one, lowest first
+    pub below: Vec<(PathBuf, Weak<Mutex<FsSync<T>>>)>,
+    /// The directory and archive directory of every lane, highest first,
+    /// whose retained files spend `keep_bytes` in that order
+    pub retained: Vec<(PathBuf, Option<PathBuf>)>,
+}
+
+impl<T> fmt::Debug for LaneShare<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LaneShare")
+            .field("below", &self.below.iter().map(|lane| &lane.0).collect::<Vec<_>>())
+            .field("retained", &self.retained)
+            .finish()
+    }
+}
+
+/// Wakes a channel's background flusher ahead of its interval
+#[derive(Debug, Default)]
+pub struct FlushSignal {
+    requested: Mutex<bool>,
+    cond: Condvar,
+    // The flusher's job, if it runs on a `Runtime` rather than waiting here
+    job: Option<JobHandle>,
+}
+
+impl FlushSignal {
+    /// A signal waking the flusher `job`
+    pub fn waking(job: JobHandle) -> FlushSignal {
+        FlushSignal {
+            job: Some(job),
+            ..FlushSignal::default()
+        }
+    }
+
+    /// Wake the flusher now
+    pub fn notify(&self) {
+        if let Some(ref job) = self.job {
+            job.wake();
+            return;
+        }
+        *sync::lock(&self.requested) = true;
+        self.cond.notify_one();
+    }
+
+    /// Wait until notified or `timeout` passes
+    pub fn wait(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let mut requested = sync::lock(&self.requested);
+        while !*requested {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            requested = sync::wait_timeout(&self.cond, requested, deadline - now);
+        }
+        *requested = false;
+    }
+}
+
+/// Whether the target can run threads, which hopper's background work needs
+///
+/// Not on WebAssembly, but for builds with its threads proposal.
+pub const HAS_THREADS: bool = !cfg!(all(target_family = "wasm", not(target_feature = "atomics")));
+
+/// Whether the target has a temporary directory for an ephemeral channel
+pub const HAS_TEMP_DIR: bool = !cfg!(target_family = "wasm");
+
+/// The temporary directory of a channel built with
+/// `ChannelBuilder::ephemeral`
+///
+/// Removed, with everything in it, when dropped.
+#[derive(Debug)]
+pub struct EphemeralDir {
+    pub path: PathBuf,
+}
+
+impl EphemeralDir {
+    /// Choose a directory under the system's temporary directory that no
+    /// other channel uses
+    ///
+    /// Where there is no temporary directory the path is empty, and the
+    /// channel fails to build.
+    pub fn new() -> EphemeralDir {
+        if !HAS_TEMP_DIR {
+            return EphemeralDir {
+                path: PathBuf::new(),
+            };
+        }
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        let name = format!(
+            "hopper-{}-{}-{}",
+            process::id(),
+            NEXT.fetch_add(1, atomic::Ordering::Relaxed),
+            nanos
+        );
+        EphemeralDir {
+            path: env::temp_dir().join(name),
+        }
+    }
+}
+
+impl Drop for EphemeralDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+impl<T> fmt::Debug for FsSync<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FsSync")
+            .field("receiver_read_id", &self.receiver_read_id)
+            .field("receiver_idx", &self.receiver_idx)
+            .field("sender_idx", &self.sender_idx)
+            .field("sender_seq_num", &self.sender_seq_num)
+            .field("writes_to_read", &self.writes_to_read)
+            .field("disk_writes_to_read", &self.disk_writes_to_read)
+            .field("mem_buffer", &self.mem_buffer.len())
+            .field("recycled", &self.recycled.len())
+            .field("disk_buffer", &self.disk_buffer.len())
+            .field("stage_writes", &self.stage_writes)
+            .field("delayed", &self.delayed.len())
+            .field("closed", &self.closed)
+            .field("paused", &self.paused)
+            .field("ttl", &self.ttl)
+            .field("stamp_sends", &self.stamp_sends)
+            .field("clock", &self.clock)
+            .field("provenance", &self.provenance)
+            .field("sequenced", &self.sequenced)
+            .field("memory_budget", &self.budget())
+            .field("spill_scale", &self.spill_scale)
+            .field("disk_buffer_bytes", &self.disk_buffer_bytes)
+            .field("shared_budget", &self.shared_budget.as_ref().map(Charge::budget))
+            .field("reservations", &self.reservations)
+            .field("archive", &self.archive)
+            .field("corruption", &self.corruption)
+            .field("read_ahead", &self.read_ahead)
+            .field("runtime", &self.runtime)
+            .field("memory_only", &self.memory_only)
+            .field("overflow", &self.overflow)
+            .field("disk_quota", &self.disk_quota.as_ref().map(|quota| quota.0))
+            .field("evict_oldest", &self.evict_oldest)
+            .field("lanes", &self.lanes)
+            .field("disk_primary", &self.disk_primary)
+            .field("flusher", &self.flusher.as_ref().map(|f| f.1))
+            .field("coalesce", &self.coalesce)
+            .field("segment_max_bytes", &self.segment_max_bytes)
+            .field("order", &self.order)
+            .field("flush_on_drop", &self.flush_on_drop)
+            .field("ephemeral", &self.ephemeral)
+            .finish()
+    }
+}
+
+/// The state of a channel for the `Debug` of its Senders and Receiver,
+/// `<locked>` if the channel is locked, so that formatting never waits
+pub struct DebugState<'a, T: 'a>(pub &'a FSLock<T>);
+
+impl<'a, T> fmt::Debug for DebugState<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match sync::try_lock(self.0) {
+            Some(syn) => syn.fmt(f),
+            None => f.write_str("<locked>"),
+        }
+    }
 }
 
 impl<T> FsSync<T> {
@@ -39,13 +454,1172 @@ impl<T> FsSync<T> {
             sender_fp: None,
 
             in_memory_idx: cap,
+            capacity: cap,
+            spill_scale: 1.0,
             bytes_written: 0,
+            segment_summary: SegmentSummary::default(),
+            segment_index: SegmentIndex::default(),
             disk_writes_to_read: 0,
             sender_seq_num: 0,
             mem_buffer: VecDeque::with_capacity(cap),
             disk_buffer: VecDeque::with_capacity(cap),
+            encode_buf: Vec::new(),
+            stage_writes: false,
+            disk_encodings: VecDeque::new(),
+            recycled: Vec::new(),
+
+            ttl: None,
+            stamp_sends: false,
+            purge_floor: 0,
+            purge_skip: None,
+            clock: Arc::new(SystemClock),
+            mem_stamps: VecDeque::new(),
+            disk_stamps: VecDeque::new(),
+            aging: None,
+            head_drawn: false,
+
+            provenance: false,
+            mem_origins: VecDeque::new(),
+            disk_origins: VecDeque::new(),
+            next_sender_id: 0,
+            sequenced: false,
+            mem_seqs: VecDeque::new(),
+            disk_seqs: VecDeque::new(),
+
+            memory_budget: None,
+            #[cfg(feature = "cgroup")]
+            cgroup_budget: None,
+            mem_bytes: 0,
+            disk_buffer_bytes: 0,
+            disk_sizes: VecDeque::new(),
+            shared_budget: None,
+            reservations: None,
+            mem_sizes: VecDeque::new(),
+
+            #[cfg(feature = "encryption")]
+            cipher: None,
+
+            archive: None,
+            archiver: None,
+            corruption: CorruptionPolicy::Abort,
+
+            retention: Retention::default(),
+            rate: None,
+            watermarks: Watermarks::default(),
+            sampling_floor: None,
+
+            dir_lock: None,
+
+            read_ahead: 0,
+            block_bytes: 0,
+            compress_blocks: false,
+            #[cfg(feature = "dictionary")]
+            trainer: None,
+            preallocate: false,
+            supervisor: Supervisor::new("", None),
+            last_write_error: None,
+            retries: Retries::new(RetryPolicy::default()),
+            runtime: None,
+
+            memory_only: false,
+            overflow: OverflowPolicy::Fail,
+            disk_quota: None,
+            at_disk_quota: false,
+            evict_oldest: false,
+            lanes: None,
+            disk_primary: false,
+
+            flusher: None,
+
+            wakers: Vec::new(),
+            coalesce: None,
+            drained_at: None,
+            deferred_wake: None,
+            ready_pipe: None,
+            ready_signalled: false,
+
+            admission: Arc::new(Admission::default()),
+            room: Arc::new(Condvar::new()),
+            room_waiters: 0,
+
+            closed: false,
+            senders: 0,
+            internal_senders: 0,
+            paused: false,
+
+            delayed: delay::Store::in_memory(),
+            paged: None,
+            delay_timer: None,
+
+            segment_max_bytes: 0,
+            segment_bounds: None,
+            segment_opened: None,
+
+            order: OrderMode::Global,
+            flush_on_drop: true,
+            observer: None,
+
+            ephemeral: None,
+        }
+    }
+
+    /// Whether each item carries its send time, in memory and in its queue
+    /// file record
+    pub fn stamped(&self) -> bool {
+        self.ttl.is_some() || self.aging.is_some() || self.provenance || self.stamp_sends
+    }
+
+    /// What each of the channel's queue file records leads with ahead of its
+    /// item, and whether they are packed into blocks
+    pub fn framing(&self) -> RecordFraming {
+        RecordFraming {
+            stamped: self.stamped(),
+            traced: self.provenance,
+            sequenced: self.sequenced,
+            packed: self.block_bytes > 0,
+        }
+    }
+
+    /// The channel clock's time in milliseconds since the UNIX epoch
+    pub fn now_millis(&self) -> u64 {
+        clock::millis(&*self.clock)
+    }
+
+    /// Whether an item sent at `stamp` has outlived the channel's TTL, or
+    /// was sent before the channel's last purge
+    pub fn expired(&self, stamp: Option<u64>) -> bool {
+        is_expired(self.ttl, stamp, self.now_millis()) || self.purged(stamp)
+    }
+
+    /// Whether an item sent at `stamp` was sent before the channel's last
+    /// purge, see `Receiver::purge_before`
+    pub fn purged(&self, stamp: Option<u64>) -> bool {
+        stamp.is_some_and(|stamp| stamp < self.purge_floor)
+    }
+
+    /// How long, in milliseconds, an item sent at `stamp` has waited
+    pub fn waited(&self, stamp: Option<u64>) -> Option<u64> {
+        stamp.map(|stamp| self.now_millis().saturating_sub(stamp))
+    }
+
+    /// Accept an item into the channel's buffers. Returns true if the disk
+    /// buffer has filled and must now be paged out to disk.
+    ///
+    /// `size` is the item's encoded size, given when the channel has a memory
+    /// budget, and `encoding` its encoding if its Sender staged the write.
+    /// `sender` is the id of the item's Sender and `seq` the item's place
+    /// among that Sender's items.
+    pub fn admit(
+        &mut self,
+        event: T,
+        size: Option<usize>,
+        encoding: Option<Vec<u8>>,
+        sender: u32,
+        seq: u64,
+    ) -> bool {
+        let stamp = if self.stamped() {
+            Some(self.now_millis())
+        } else {
+            None
+        };
+        let origin = if self.provenance { Some(sender) } else { None };
+        let seq = if self.sequenced { Some(seq) } else { None };
+        if self.sender_idx < self.in_memory_idx {
+            // The in-memory tier ends with the last item to fit the budget,
+            // or to fit a capacity scaled down under memory pressure.
+            let full = match (self.budget(), size) {
+                (Some(budget), Some(size)) => self.mem_bytes + size > self.scaled(budget),
+                _ => self.mem_buffer.len() >= self.scaled(self.capacity),
+            };
+            if full || !self.shared_room_for(size.unwrap_or(0)) {
+                self.in_memory_idx = self.sender_idx;
+            }
+        }
+        if let (Some(charge), Some(size)) = (self.shared_budget.as_mut(), size) {
+            charge.add(size);
+        }
+        if let (Some(reservations), Some(size)) = (self.reservations.as_ref(), size) {
+            reservations.hold(sender, size);
+        }
+        let spill = if self.sender_idx < self.in_memory_idx {
+            self.mem_buffer.push_back(event);
+            self.mem_stamps.extend(stamp);
+            self.mem_origins.extend(origin);
+            self.mem_seqs.extend(seq);
+            if self.shared_budget.is_some() || self.reservations.is_some() {
+                self.mem_sizes.extend(size);
+            }
+            self.mem_bytes += size.unwrap_or(0);
+            false
+        } else {
+            self.disk_buffer.push_back(event);
+            self.disk_stamps.extend(stamp);
+            self.disk_origins.extend(origin);
+            self.disk_seqs.extend(seq);
+            self.disk_sizes.extend(size);
+            if self.stage_writes {
+                self.disk_encodings.push_back(encoding);
+            }
+            self.disk_buffer_bytes += size.unwrap_or(0);
+            self.disk_buffer_full() && !self.memory_only && self.within_disk_quota(0, 0)
+                && self.may_page_out()
+        };
+        self.writes_to_read += 1;
+        if (self.sender_captured_recv_id != self.receiver_read_id) || self.write_bound.is_none() {
+            self.sender_captured_recv_id = self.receiver_read_id;
+            self.write_bound = Some(self.sender_idx);
+        }
+        self.sender_idx += 1;
+        spill
+    }
+
+    // The channel's memory budget as it stands, if it has one.
+    fn budget(&self) -> Option<usize> {
+        #[cfg(feature = "cgroup")]
+        {
+            if let Some(ref budget) = self.cgroup_budget {
+                return Some(budget.load(atomic::Ordering::Relaxed));
+            }
+        }
+        self.memory_budget
+    }
+
+    // Whether the `Budget` the channel shares, if any, has room for `bytes`
+    // more. A memory-only channel has nowhere else to put items, so counts
+    // them against the budget without being held to it.
+    fn shared_room_for(&self, bytes: usize) -> bool {
+        match self.shared_budget {
+            Some(ref charge) if !self.memory_only => charge.budget().has_room(bytes),
+            _ => true,
+        }
+    }
+
+    // `limit` as scaled down under memory pressure, never below one.
+    fn scaled(&self, limit: usize) -> usize {
+        if self.spill_scale >= 1.0 {
+            return limit;
+        }
+        cmp::max(1, (limit as f64 * self.spill_scale) as usize)
+    }
+
+    /// The in-memory capacity, as scaled down under memory pressure
+    pub fn scaled_capacity(&self) -> usize {
+        self.scaled(self.capacity)
+    }
+
+    /// The channel's live Senders, not counting those hopper holds itself
+    pub fn live_senders(&self) -> usize {
+        self.senders - self.internal_senders
+    }
+
+    /// Whether every Sender of the channel but those hopper holds itself has
+    /// been dropped, or the Receiver has closed the channel
+    pub fn hung_up(&self) -> bool {
+        self.closed || self.live_senders() == 0
+    }
+
+    /// Whether the disk buffer holds as much as it may before being paged out
+    pub fn disk_buffer_full(&self) -> bool {
+        if self.disk_primary || !self.shared_room_for(0) {
+            return !self.disk_buffer.is_empty();
+        }
+        match self.budget() {
+            Some(budget) => self.disk_buffer_bytes >= self.scaled(budget),
+            None => self.disk_buffer.len() >= self.scaled(self.capacity),
+        }
+    }
+
+    /// Whether the channel is memory-only, held at its disk quota or no
+    /// longer able to write its queue files, and has no room for another item
+    pub fn is_full(&self) -> bool {
+        self.sender_idx >= self.in_memory_idx && self.disk_buffer_full()
+            && (self.memory_only || self.write_failed() || !self.within_disk_quota(0, 0))
+    }
+
+    /// Whether the disk buffer may be paged out now, rather than held in
+    /// memory after a failed write
+    pub fn may_page_out(&self) -> bool {
+        self.retries.may_write(self.clock.now())
+    }
+
+    /// Whether a write to the queue files has failed for good, leaving the
+    /// channel to hold items in memory only
+    pub fn write_failed(&self) -> bool {
+        self.retries.fatal().is_some()
+    }
+
+    /// Whether paging out the disk buffer, with `records` more items of
+    /// `bytes` encoded bytes, keeps the channel's queue files within its disk
+    /// quota. A channel without a quota always does.
+    ///
+    /// The bytes a spill writes are over-estimated, not under: every queue
+    /// file it could open is counted with its header.
+    pub fn within_disk_quota(&self, bytes: usize, records: usize) -> bool {
+        let (quota, metrics) = match self.disk_quota {
+            Some((quota, ref metrics)) => (quota, metrics),
+            None => return true,
+        };
+        let mut framing = 4;
+        if self.stamped() {
+            framing += 8;
+        }
+        if self.provenance {
+            framing += 4;
+        }
+        if self.sequenced {
+            framing += 8;
+        }
+        #[cfg(feature = "encryption")]
+        {
+            if self.cipher.is_some() {
+                framing += crypt::KEY_ID_LEN + crypt::NONCE_LEN + crypt::TAG_LEN;
+            }
+        }
+        let records = self.disk_buffer.len() + records;
+        let spilled = self.disk_buffer_bytes + bytes + framing * records;
+        let files = spilled / cmp::max(1, self.segment_max_bytes) + 1;
+        let on_disk = self.disk_bytes(metrics) as usize;
+        on_disk + spilled + files * layout::SEGMENT_HEADER_LEN <= quota
+    }
+
+    // The bytes the channel's queue files take, `metrics` counting this
+    // lane's, all the lanes' together on a channel with priority lanes.
+    fn disk_bytes(&self, metrics: &Metrics) -> u64 {
+        match self.lanes {
+            Some(ref lanes) => lanes
+                .disk
+                .iter()
+                .map(|lane| lane.disk_bytes.load(atomic::Ordering::Relaxed))
+                .sum(),
+            None => metrics.disk_bytes.load(atomic::Ordering::Relaxed),
+        }
+    }
+
+    /// The directories whose retained files share the channel's
+    /// `keep_bytes`, with their archive directories, highest priority lane
+    /// first
+    pub fn retained_dirs(&self, root: &Path) -> Vec<(PathBuf, Option<PathBuf>)> {
+        match self.lanes {
+            Some(ref lanes) => lanes.retained.clone(),
+            None => vec![(root.to_path_buf(), self.archive.clone())],
+        }
+    }
+
+    /// Whether the disk buffer has just been held back in memory at the
+    /// channel's disk quota, for the first time since it was last paged out
+    pub fn reached_disk_quota(&mut self) -> bool {
+        let held = self.disk_quota.is_some() && !self.memory_only && self.disk_buffer_full()
+            && !self.within_disk_quota(0, 0);
+        let reached = held && !self.at_disk_quota;
+        self.at_disk_quota = held;
+        reached
+    }
+
+    /// Delete the oldest queue files the Receiver has yet to reach until the
+    /// disk buffer, held at the channel's disk quota, may be paged out
+    ///
+    /// Only a channel built to evict does so. On a channel with priority
+    /// lanes the files of the lanes below this one go first, lowest lane
+    /// first, then this lane's own; a higher lane's are never evicted. The
+    /// queue file a Receiver is reading and the one being written are never
+    /// evicted, so the quota may still hold the buffer once there is nothing
+    /// left to evict. Returns the number of records and bytes deleted.
+    pub fn evict_oldest(&mut self, root: &Path) -> (u64, u64) {
+        let metrics = match self.disk_quota {
+            Some((_, ref metrics)) if self.evict_oldest => Arc::clone(metrics),
+            _ => return (0, 0),
+        };
+        if self.memory_only || !self.disk_buffer_full() || self.within_disk_quota(0, 0) {
+            return (0, 0);
+        }
+        let (mut records, mut bytes) = (0, 0);
+        let below = self.lanes.as_ref().map_or_else(Vec::new, |lanes| lanes.below.clone());
+        for (lane_root, lane) in below {
+            let lane = match lane.upgrade() {
+                Some(lane) => lane,
+                None => continue,
+            };
+            let mut lane = lock(&lane);
+            let lane_metrics = match lane.disk_quota {
+                Some((_, ref metrics)) => Arc::clone(metrics),
+                None => continue,
+            };
+            for path in lane.unread_segments(&lane_root) {
+                if self.within_disk_quota(0, 0) {
+                    return (records, bytes);
+                }
+                let (count, len) = match lane.drop_unread(&path, &lane_metrics) {
+                    Some(dropped) => dropped,
+                    None => break,
+                };
+                lane_metrics.total_evicted.fetch_add(count, atomic::Ordering::Relaxed);
+                records += count;
+                bytes += len;
+            }
+        }
+        for path in self.unread_segments(root) {
+            if self.within_disk_quota(0, 0) {
+                break;
+            }
+            let (count, len) = match self.drop_unread(&path, &metrics) {
+                Some(dropped) => dropped,
+                None => break,
+            };
+            metrics.total_evicted.fetch_add(count, atomic::Ordering::Relaxed);
+            records += count;
+            bytes += len;
+        }
+        (records, bytes)
+    }
+
+    // The queue files under `root` the Receiver has yet to reach, oldest
+    // first, short of the one being written.
+    fn unread_segments(&self, root: &Path) -> Vec<PathBuf> {
+        let archive = self.archive.as_deref();
+        let mut ids = segment_ids(root, archive);
+        ids.sort();
+        // The oldest queue file is the one the Receiver is reading.
+        ids.into_iter()
+            .skip(1)
+            .take_while(|id| *id != self.sender_seq_num)
+            .map(|id| segment_path(root, archive, id))
+            .collect()
+    }
+
+    /// Delete the queue files the Receiver has yet to reach whose items were
+    /// all sent before `cutoff`, in milliseconds since the UNIX epoch, and
+    /// have items sent before it that are still to come dropped as they are
+    /// reached, if the channel stamps its items
+    ///
+    /// Files go oldest first, up to the first holding an item sent since,
+    /// judged by their summaries. Of that file the records its index shows
+    /// were sent before `cutoff` are cut from the front: the Receiver starts
+    /// it past them. The queue file the Receiver is reading and the one being
+    /// written are never deleted. Returns the number of files, records and
+    /// bytes deleted, the records cut from the front of a file included.
+    pub fn purge_before(&mut self, root: &Path, cutoff: u64, metrics: &Metrics) -> (usize, u64, u64) {
+        if self.stamped() {
+            self.purge_floor = cmp::max(self.purge_floor, cutoff);
+        }
+        let (mut segments, mut records, mut bytes) = (0, 0, 0);
+        for path in self.unread_segments(root) {
+            let sent_before = summary::newest_item(&path)
+                .and_then(|newest| newest.duration_since(UNIX_EPOCH).ok())
+                .is_some_and(|newest| (newest.as_millis() as u64) < cutoff);
+            if !sent_before {
+                records += self.cut_front(path, cutoff);
+                break;
+            }
+            let (count, len) = match self.drop_unread(&path, metrics) {
+                Some(dropped) => dropped,
+                None => break,
+            };
+            segments += 1;
+            records += count;
+            bytes += len;
+        }
+        (segments, records, bytes)
+    }
+
+    // Have the Receiver start the unread queue file at `path` past the
+    // records its index shows hold only items sent before `cutoff`, taking
+    // them out of the channel's reckoning. Returns the number of items cut.
+    fn cut_front(&mut self, path: PathBuf, cutoff: u64) -> u64 {
+        let entry = match index::read(&path).and_then(|index| index.before_stamp(cutoff)) {
+            Some(entry) => entry,
+            None => return 0,
+        };
+        // One file at a time is cut, the same one until it is reached.
+        let cut = match self.purge_skip {
+            Some((ref skip, _, items)) if *skip == path => entry.items.saturating_sub(items),
+            Some(_) => 0,
+            None => entry.items,
+        };
+        if cut == 0 {
+            return 0;
+        }
+        self.writes_to_read -= cut as usize;
+        self.disk_writes_to_read -= cut as usize;
+        self.receiver_idx = self.receiver_idx.map(|idx| idx + cut as usize);
+        self.purge_skip = Some((path, entry.offset, entry.items));
+        cut
+    }
+
+    /// The number of items held in memory sent before `cutoff`, in
+    /// milliseconds since the UNIX epoch, if the channel stamps its items
+    pub fn held_before(&self, cutoff: u64) -> u64 {
+        self.mem_stamps
+            .iter()
+            .chain(self.disk_stamps.iter())
+            .filter(|&&stamp| stamp < cutoff)
+            .count() as u64
+    }
+
+    // Delete the unread queue file at `path`, taking its records out of the
+    // channel's reckoning. Returns the records and bytes deleted, or None if
+    // the file could not be.
+    fn drop_unread(&mut self, path: &Path, metrics: &Metrics) -> Option<(u64, u64)> {
+        let (mut count, len) = segment_contents(path)?;
+        remove_segment(path).ok()?;
+        // Items cut from the front of the file are out of the reckoning
+        // already.
+        if self.purge_skip.as_ref().is_some_and(|skip| skip.0 == path) {
+            count = count.saturating_sub(self.purge_skip.take().map_or(0, |skip| skip.2));
+        }
+        self.writes_to_read -= count as usize;
+        self.disk_writes_to_read -= count as usize;
+        self.receiver_idx = self.receiver_idx.map(|idx| idx + count as usize);
+        metrics.segments_on_disk.fetch_sub(1, atomic::Ordering::Relaxed);
+        metrics.disk_bytes.fetch_sub(len, atomic::Ordering::Relaxed);
+        self.room_made();
+        Some((count, len))
+    }
+
+    /// Whether a memory-only channel has room for items of `sizes`, sent in
+    /// one step
+    ///
+    /// Items fill the in-memory tier, then the disk buffer, as `admit` places
+    /// them. A channel that is not memory-only has room unless it has a disk
+    /// quota, which the items must fit within along with the disk buffer.
+    pub fn has_room_for<I>(&self, sizes: I) -> bool
+    where
+        I: IntoIterator<Item = Option<usize>>,
+    {
+        if !self.memory_only {
+            let (mut bytes, mut records) = (0, 0);
+            for size in sizes {
+                bytes += size.unwrap_or(0);
+                records += 1;
+            }
+            // A channel that evicts makes room as the items are paged out.
+            return !self.is_full() && (self.evict_oldest || self.within_disk_quota(bytes, records));
+        }
+        let budget = self.budget();
+        let mut open = self.sender_idx < self.in_memory_idx;
+        let (mut mem_len, mut mem_bytes) = (self.mem_buffer.len(), self.mem_bytes);
+        let (mut disk_len, mut disk_bytes) = (self.disk_buffer.len(), self.disk_buffer_bytes);
+        for size in sizes {
+            if !open {
+                let disk_full = match budget {
+                    Some(budget) => disk_bytes >= self.scaled(budget),
+                    None => disk_len >= self.scaled(self.capacity),
+                };
+                if disk_full {
+                    return false;
+                }
+            } else {
+                open = match (budget, size) {
+                    (Some(budget), Some(size)) => mem_bytes + size <= self.scaled(budget),
+                    _ => mem_len < self.scaled(self.capacity),
+                };
+            }
+            if open {
+                mem_len += 1;
+                mem_bytes += size.unwrap_or(0);
+            } else {
+                disk_len += 1;
+                disk_bytes += size.unwrap_or(0);
+            }
+        }
+        true
+    }
+
+    /// Whether the next item sent must be refused for want of room
+    ///
+    /// Senders of a channel that blocks wait for room before sending, so a
+    /// full channel that blocks takes the item all the same.
+    pub fn refuses(&self) -> bool {
+        self.overflow == OverflowPolicy::Fail && self.is_full()
+    }
+
+    /// Whether the channel has room for `records` items of `bytes` sent by
+    /// `sender` without taking room another group has reserved
+    ///
+    /// Items within their group's share need only the channel's own room,
+    /// checked by `has_room_for`. Past it they must leave every other group
+    /// its unused share: in the room left under the `memory_budget` of a
+    /// memory-only channel, in its in-memory tier while open and its disk
+    /// buffer, or under the `max_disk_bytes` of one that pages out.
+    pub fn group_room_for(&self, sender: u32, bytes: usize, records: usize) -> bool {
+        let reservations = match self.reservations {
+            Some(ref reservations) => reservations,
+            None => return true,
+        };
+        if reservations.within_share(sender, bytes) {
+            return true;
+        }
+        let bytes = bytes + reservations.unused_elsewhere(sender);
+        if !self.memory_only {
+            return self.within_disk_quota(bytes, records);
+        }
+        match self.budget() {
+            Some(budget) => {
+                let budget = self.scaled(budget);
+                // The in-memory tier takes only the first items sent.
+                let mut room = budget.saturating_sub(self.disk_buffer_bytes);
+                if self.sender_idx < self.in_memory_idx {
+                    room += budget.saturating_sub(self.mem_bytes);
+                }
+                bytes <= room
+            }
+            None => true,
+        }
+    }
+
+    /// Whether the next item sent by `sender`, of `size`, must be refused for
+    /// want of room its group may take, as `refuses`
+    pub fn group_refuses(&self, sender: u32, size: Option<usize>) -> bool {
+        self.overflow == OverflowPolicy::Fail
+            && !self.group_room_for(sender, size.unwrap_or(0), 1)
+    }
+
+    /// Give back to its group an item of `size` sent by `sender`, no longer
+    /// held by the channel
+    ///
+    /// Once the channel is empty every group is given back what it holds,
+    /// so that items lost unreceived are not counted against it for good.
+    pub fn release_held(&self, sender: Option<u32>, size: Option<usize>) {
+        if let Some(ref reservations) = self.reservations {
+            if let (Some(sender), Some(size)) = (sender, size) {
+                reservations.release(sender, size);
+            }
+            if self.writes_to_read == 0 {
+                reservations.clear();
+            }
+        }
+    }
+
+    /// Forget the encoded size, and any staged encoding, of the item just
+    /// taken from the front of the disk buffer, returning the size
+    pub fn take_disk_size(&mut self) -> Option<usize> {
+        self.disk_encodings.pop_front();
+        let size = self.disk_sizes.pop_front();
+        if let Some(size) = size {
+            self.disk_buffer_bytes -= size;
+            if let Some(ref mut charge) = self.shared_budget {
+                charge.release(size);
+            }
+        }
+        size
+    }
+
+    /// Remove the item at `at` in the disk buffer, as lost
+    pub fn remove_disk_item(&mut self, at: usize) {
+        self.disk_buffer.remove(at);
+        self.disk_stamps.remove(at);
+        let origin = self.disk_origins.remove(at);
+        self.disk_seqs.remove(at);
+        self.disk_encodings.remove(at);
+        let size = self.disk_sizes.remove(at);
+        if let Some(size) = size {
+            self.disk_buffer_bytes -= size;
+            if let Some(ref mut charge) = self.shared_budget {
+                charge.release(size);
+            }
+        }
+        self.writes_to_read -= 1;
+        self.release_held(origin, size);
+    }
+
+    /// Give back to a shared `Budget` the encoded size of the item just taken
+    /// from the front of the in-memory tier, returning the size
+    pub fn take_mem_size(&mut self) -> Option<usize> {
+        let size = self.mem_sizes.pop_front();
+        if let (Some(size), Some(charge)) = (size, self.shared_budget.as_mut()) {
+            charge.release(size);
+        }
+        size
+    }
+
+    /// Start a record's payload at the end of `buf`, returning where the
+    /// item's encoding begins
+    #[cfg_attr(not(feature = "encryption"), allow(clippy::ptr_arg))]
+    pub fn begin_record(&self, buf: &mut Vec<u8>) -> usize {
+        #[cfg(feature = "encryption")]
+        {
+            if let Some(ref cipher) = self.cipher {
+                return cipher.begin(buf);
+            }
+        }
+        buf.len()
+    }
+
+    /// Write the fields a record payload leads with ahead of its item onto
+    /// the end of `buf`: the send time `stamp`, big-endian, if the channel
+    /// stamps its items, then the Sender id `origin` if it records provenance
+    /// and the place `seq` among that Sender's items if it sequences them
+    pub fn lead_record(
+        &self,
+        buf: &mut Vec<u8>,
+        stamp: Option<u64>,
+        origin: Option<u32>,
+        seq: Option<u64>,
+    ) {
+        if self.stamped() {
+            buf.extend_from_slice(&stamp.unwrap_or(0).to_be_bytes());
+        }
+        if self.provenance {
+            buf.extend_from_slice(&origin.unwrap_or(0).to_be_bytes());
+        }
+        if self.sequenced {
+            buf.extend_from_slice(&seq.unwrap_or(0).to_be_bytes());
+        }
+    }
+
+    /// Finish the record payload whose encoding is `buf[start..]`, sealing it
+    /// if the channel encrypts
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables, clippy::ptr_arg))]
+    pub fn end_record(&mut self, buf: &mut Vec<u8>, start: usize) {
+        #[cfg(feature = "encryption")]
+        {
+            if let Some(ref mut cipher) = self.cipher {
+                cipher.seal(buf, start);
+            }
+        }
+    }
+
+    /// The encoding held in a record payload read back from disk, or None if
+    /// it could not be decrypted
+    pub fn open_record<'a>(&self, payload: &'a mut [u8]) -> Option<&'a [u8]> {
+        #[cfg(feature = "encryption")]
+        {
+            if let Some(ref cipher) = self.cipher {
+                return cipher.open(payload);
+            }
+        }
+        Some(payload)
+    }
+
+    /// Hold the item encoded as `payload` back from the Receiver until
+    /// `due`, in milliseconds since the UNIX epoch
+    ///
+    /// An item the channel's delayed file cannot take is held in memory, and
+    /// the failure recorded as the channel's last write error.
+    pub fn delay(&mut self, payload: Vec<u8>, due: u64) {
+        if let Err(e) = self.delayed.delay(payload, due) {
+            self.last_write_error = Some(e.to_string());
+        }
+        if let Some(ref timer) = self.delay_timer {
+            timer.notify();
+        }
+    }
+
+    /// Take the encoding of the earliest delayed item if it has come due
+    pub fn take_due(&mut self) -> Option<io::Result<Vec<u8>>> {
+        let taken = self.delayed.take_due(self.now_millis())?;
+        // The next item may come due sooner than the timer is waiting for.
+        if let Some(ref timer) = self.delay_timer {
+            timer.notify();
+        }
+        Some(taken)
+    }
+
+    /// Whether a delayed item has come due
+    pub fn delay_due(&self) -> bool {
+        self.delayed
+            .next_due()
+            .is_some_and(|due| due <= self.now_millis())
+    }
+
+    /// How long until the earliest delayed item comes due, if one is waiting
+    /// and not yet due
+    pub fn until_delay_due(&self) -> Option<Duration> {
+        let due = self.delayed.next_due()?;
+        let now = self.now_millis();
+        if due <= now {
+            None
+        } else {
+            Some(Duration::from_millis(due - now))
+        }
+    }
+
+    /// Whether the Receiver has items it may deliver
+    pub fn is_ready(&self) -> bool {
+        (self.writes_to_read > 0 || self.head_drawn || self.delay_due()) && !self.paused
+    }
+
+    /// Write the channel's byte to the Receiver's readiness pipe, if it has
+    /// one and the byte is not there already
+    pub fn signal_ready(&mut self) {
+        if self.ready_signalled {
+            return;
+        }
+        if let Some(ref pipe) = self.ready_pipe {
+            // The pipe holds a byte per channel at most, so this never fills
+            // it and blocks
+            let mut pipe: &io::PipeWriter = pipe;
+            let _ = pipe.write_all(&[1]);
+            self.ready_signalled = true;
         }
     }
+
+    /// Read the channel's byte back out of the readiness pipe once the
+    /// Receiver has nothing it may deliver
+    pub fn clear_ready(&mut self, mut pipe: &io::PipeReader) {
+        if self.ready_signalled && !self.is_ready() {
+            // The byte is known to be there, so this does not block
+            let _ = pipe.read_exact(&mut [0]);
+            self.ready_signalled = false;
+        }
+    }
+
+    /// Whether a send of `records` items that found the queue `was_empty`
+    /// should wake the registered wakers
+    ///
+    /// Without coalescing that is whenever the queue was empty. With it a
+    /// wake is held back while the Receiver is draining, having taken items
+    /// within the coalescing interval, until the records it covers reach the
+    /// coalescing count or it has been held back for the interval.
+    pub fn wake_due(&mut self, was_empty: bool, records: usize) -> bool {
+        if !self.is_ready() {
+            return false;
+        }
+        // With no waker to wake there is nothing to hold back
+        if self.wakers.is_empty() {
+            return was_empty;
+        }
+        let (count, interval) = match self.coalesce {
+            Some(coalesce) => coalesce,
+            None => return was_empty,
+        };
+        let now = Instant::now();
+        let (since, held) = match self.deferred_wake {
+            Some(deferred) => deferred,
+            None if !was_empty => return false,
+            None => {
+                let draining = self.drained_at.is_some_and(|at| now < at + interval);
+                if !draining {
+                    return true;
+                }
+                (now, 0)
+            }
+        };
+        let held = held + records;
+        if held >= count || now >= since + interval {
+            self.deferred_wake = None;
+            true
+        } else {
+            self.deferred_wake = Some((since, held));
+            false
+        }
+    }
+
+    /// Note that the Receiver has taken items, for wake coalescing and for
+    /// Senders waiting for room
+    pub fn drained(&mut self) {
+        if self.coalesce.is_some() {
+            self.drained_at = Some(Instant::now());
+            self.deferred_wake = None;
+        }
+        self.room_made();
+    }
+
+    /// Wake the Senders waiting for room, if any, to look again
+    pub fn room_made(&self) {
+        if self.room_waiters > 0 {
+            self.room.notify_all();
+        }
+    }
+
+    /// The latest a wake held back by coalescing, or owed as a delayed item
+    /// comes due, may be owed to a waker registered now, if one may be owed
+    /// at all
+    ///
+    /// Sends hold a wake back only while the Receiver is draining, which ends
+    /// an interval after it last took items, and a wait bounded by that
+    /// keeps the interval's latency bound however few items follow. A wait
+    /// bounded by the next delayed item's due time sees it come due even
+    /// where the channel has no timer to wake it.
+    pub fn wake_deadline(&self) -> Option<Instant> {
+        let now = Instant::now();
+        let coalesced = match (self.coalesce, self.drained_at) {
+            (Some((_, interval)), Some(at)) if now < at + interval => Some(at + interval),
+            _ => None,
+        };
+        let delayed = self.until_delay_due().map(|until| now + until);
+        match (coalesced, delayed) {
+            (Some(a), Some(b)) => Some(cmp::min(a, b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// The simulation the channel runs on, if it is built on one, whose clock
+    /// its waits go by and whose jobs they run rather than block
+    pub fn simulation(&self) -> Option<Runtime> {
+        self.runtime.clone().filter(Runtime::is_driven)
+    }
+
+    /// Register a waker against the queue. If the queue already holds items
+    /// the Receiver may deliver the waker is handed back so the caller can
+    /// wake it once the lock has been released.
+    pub fn register_waker(&mut self, waker: Waker) -> Option<Waker> {
+        if self.is_ready() {
+            Some(waker)
+        } else {
+            if !self.wakers.iter().any(|w| w.will_wake(&waker)) {
+                self.wakers.push(waker);
+            }
+            None
+        }
+    }
+
+    /// Apply the changes of `delta` to the channel's limits
+    ///
+    /// A budget or quota the channel was not built with is left unset:
+    /// Senders size items only for a channel built with one.
+    pub fn reconfigure(&mut self, delta: &ConfigDelta) {
+        if let Some(capacity) = delta.in_memory_capacity {
+            self.capacity = cmp::max(1, capacity);
+            // An open in-memory tier of a channel without a budget ends at
+            // the capacity, or here if it already holds as much.
+            let unbudgeted = self.in_memory_idx != usize::MAX;
+            if unbudgeted && self.sender_idx < self.in_memory_idx {
+                self.in_memory_idx = cmp::max(self.capacity, self.sender_idx);
+            }
+        }
+        if let (Some(budget), true) = (delta.memory_budget, self.memory_budget.is_some()) {
+            self.memory_budget = Some(budget);
+        }
+        if let (Some(bytes), Some(quota)) = (delta.max_disk_bytes, self.disk_quota.as_mut()) {
+            quota.0 = bytes;
+        }
+        if let Some((high, low)) = delta.watermarks {
+            self.watermarks.items = Some((high, cmp::min(low, high)));
+        }
+        if let Some((high, low)) = delta.byte_watermarks {
+            self.watermarks.bytes = Some((high, cmp::min(low, high)));
+        }
+    }
+
+    /// Called by the leading Sender as it rotates off a full queue file. When
+    /// adaptive sizing is enabled this adjusts the size of the next file based
+    /// on how long the last one took to fill.
+    pub fn rotate_segment(&mut self) {
+        let now = Instant::now();
+        if let (Some(bounds), Some(opened)) = (self.segment_bounds, self.segment_opened) {
+            self.segment_max_bytes =
+                next_segment_size(self.segment_max_bytes, bounds, now.duration_since(opened));
+        }
+        self.segment_opened = Some(now);
+    }
+}
+
+/// Compute the next queue file size given the time it took to fill the last.
+pub fn next_segment_size(current: usize, bounds: (usize, usize), fill_time: Duration) -> usize {
+    let (min, max) = bounds;
+    let next = if fill_time < FAST_SEGMENT {
+        current.saturating_mul(2)
+    } else if fill_time > SLOW_SEGMENT {
+        current / 2
+    } else {
+        current
+    };
+    if next < min {
+        min
+    } else if next > max {
+        max
+    } else {
+        next
+    }
+}
+
+/// The ids of the queue files in `dir` and, if given, `archive`, in no
+/// particular order
+///
+/// Queue files are named for their sequence number. Anything else in the
+/// directories, such as the channel's metadata file, is skipped.
+pub fn segment_ids(dir: &Path, archive: Option<&Path>) -> Vec<usize> {
+    let mut ids = Vec::new();
+    for dir in Some(dir).into_iter().chain(archive) {
+        ids.extend(fs::read_dir(dir).unwrap().filter_map(|de| {
+            de.unwrap()
+                .file_name()
+                .to_str()
+                .and_then(|n| n.parse::<usize>().ok())
+        }));
+    }
+    ids
+}
+
+/// Split the first record off the contents of a queue file
+///
+/// Returns the record's payload and the bytes after it, or `None` if `buf`
+/// does not start with a complete record.
+pub fn next_record(buf: &[u8]) -> Option<(&[u8], &[u8])> {
+    if buf.len() < 4 {
+        return None;
+    }
+    let len = u8tou32abe(&buf[..4]) as usize;
+    if buf.len() - 4 < len {
+        return None;
+    }
+    Some((&buf[4..4 + len], &buf[4 + len..]))
+}
+
+/// Open the queue file at `path` for appending, creating it if need be
+///
+/// A queue file created here is given a version 2 header, written straight
+/// to the file so a reader opening it finds the header whole. A header that
+/// cannot be written whole is truncated away, so the file may be opened
+/// again. Returns the file and the number of header bytes written.
+pub fn open_segment(path: &Path) -> io::Result<(fs::File, u64)> {
+    let mut fp = fs::OpenOptions::new().append(true).create(true).open(path)?;
+    if fp.metadata()?.len() > 0 {
+        return Ok((fp, 0));
+    }
+    if let Err(e) = fp.write_all(&layout::segment_header(layout::PLAIN_FORMAT_VERSION)) {
+        let _ = fp.set_len(0);
+        return Err(e);
+    }
+    Ok((fp, layout::SEGMENT_HEADER_LEN as u64))
+}
+
+/// The health of the channel whose state is `fs_lock`, its queue files in
+/// `root`
+///
+/// The directory is probed once the channel's lock is released.
+pub fn health<T>(fs_lock: &Mutex<FsSync<T>>, root: &Path) -> Health {
+    let (mut health, headroom, error) = {
+        let syn = lock(fs_lock);
+        let headroom = syn.disk_quota.as_ref().map(|&(quota, ref metrics)| {
+            (quota as u64).saturating_sub(syn.disk_bytes(metrics))
+        });
+        (syn.supervisor.health(), headroom, syn.last_write_error.clone())
+    };
+    health.disk_writable = probe(root).is_ok();
+    health.quota_headroom = headroom;
+    health.last_write_error = error;
+    health
+}
+
+/// Wake the Receiver of the channel whose state is `fs_lock` if a delayed
+/// item has come due, returning how long until the next comes due if one is
+/// waiting and not yet due
+pub fn wake_delayed<T>(fs_lock: &Mutex<FsSync<T>>) -> Option<Duration> {
+    let mut syn = lock(fs_lock);
+    let wakers = if syn.delay_due() && syn.is_ready() {
+        syn.signal_ready();
+        mem::take(&mut syn.wakers)
+    } else {
+        Vec::new()
+    };
+    let until = syn.until_delay_due();
+    drop(syn);
+    for waker in wakers {
+        waker.wake();
+    }
+    until
+}
+
+// Write a byte to a file in `root` and remove it again.
+fn probe(root: &Path) -> io::Result<()> {
+    let path = root.join(layout::PROBE_FILE);
+    let written = fs::write(&path, b"ok");
+    // Another probe of the channel may have removed it already.
+    let _ = fs::remove_file(&path);
+    written
+}
+
+/// Reserve `len` bytes of disk for the queue file `fp` without changing its
+/// length
+///
+/// Appends then fill blocks already allocated rather than growing the file
+/// as they go, while readers still find the end of the file where the last
+/// record ends. Does nothing but on Linux with the `preallocate` feature.
+#[cfg(all(feature = "preallocate", target_os = "linux"))]
+pub fn preallocate(fp: &fs::File, len: u64) -> io::Result<()> {
+    use rustix::fs::{fallocate, FallocateFlags};
+
+    fallocate(fp, FallocateFlags::KEEP_SIZE, 0, len).map_err(io::Error::from)
+}
+
+/// Reserve `len` bytes of disk for the queue file `fp`, which does nothing
+/// here
+#[cfg(not(all(feature = "preallocate", target_os = "linux")))]
+pub fn preallocate(_: &fs::File, _: u64) -> io::Result<()> {
+    Ok(())
+}
+
+/// The path of queue file `id`, looking in `archive` before `dir`
+pub fn segment_path(dir: &Path, archive: Option<&Path>, id: usize) -> PathBuf {
+    let name = format!("{}", id);
+    match archive.map(|a| a.join(&name)) {
+        Some(ref path) if path.exists() => path.clone(),
+        _ => dir.join(name),
+    }
+}
+
+/// The number of items in the queue file at `path` and its size in bytes
+///
+/// A summarised file need not be read to count its items. Otherwise each
+/// whole record is counted. Returns None if the file cannot be read.
+pub fn segment_contents(path: &Path) -> Option<(u64, u64)> {
+    match summary::read(path) {
+        Some(summary) => fs::metadata(path).ok().map(|md| (summary.items, md.len())),
+        None => fs::read(path).ok().map(|buf| {
+            let count = segment::parse(&buf)
+                .map(|file| file.take_while(|record| record.is_ok()).count())
+                .unwrap_or(0);
+            (count as u64, buf.len() as u64)
+        }),
+    }
+}
+
+/// Delete a queue file, its summary and its index
+///
+/// Sealed queue files are read-only, which Windows will not delete, so the
+/// flag is cleared first there.
+pub fn remove_segment(path: &Path) -> io::Result<()> {
+    #[cfg(windows)]
+    {
+        if let Ok(md) = fs::metadata(path) {
+            let mut permissions = md.permissions();
+            permissions.set_readonly(false);
+            let _ = fs::set_permissions(path, permissions);
+        }
+    }
+    fs::remove_file(path)?;
+    summary::remove(path);
+    index::remove(path);
+    Ok(())
+}
+
+/// Move the rewritten queue file `tmp` into place over `path`
+///
+/// Windows will not rename over a read-only file, so there the original is
+/// removed first. The original's summary and index no longer hold, so go
+/// too.
+pub fn replace_segment(tmp: &Path, path: &Path) -> io::Result<()> {
+    if fs::rename(tmp, path).is_err() {
+        remove_segment(path)?;
+        fs::rename(tmp, path)?;
+    }
+    summary::remove(path);
+    index::remove(path);
+    Ok(())
+}
+
+/// Whether an item sent at `stamp` has outlived `ttl` at `now`, both in
+/// milliseconds since the UNIX epoch
+pub fn is_expired(ttl: Option<Duration>, stamp: Option<u64>, now: u64) -> bool {
+    match (ttl, stamp) {
+        (Some(ttl), Some(stamp)) => {
+            let age = now.saturating_sub(stamp);
+            u128::from(age) > ttl.as_millis()
+        }
+        _ => false,
+    }
 }
 
 pub type FSLock<T> = Arc<Mutex<FsSync<T>>>;
+
+/// Lock a channel's shared state, carrying on past poisoning
+///
+/// A thread that panics while holding the lock--an item whose `Serialize`
+/// impl panics, say, or a failed write--poisons it. Refusing every later
+/// lock would take every other Sender and the Receiver down with it, so the
+/// state is used as the panicking thread left it. At worst the items it was
+/// paging out at the time are lost.
+pub fn lock<T>(fs_lock: &Mutex<FsSync<T>>) -> MutexGuard<'_, FsSync<T>> {
+    sync::lock(fs_lock)
+}
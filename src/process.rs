@@ -0,0 +1,1333 @@
+//! Channels whose Sender and Receiver live in different processes
+//!
+//! A regular hopper channel keeps its in-memory tier and bookkeeping in state
+//! shared between the Sender and Receiver, so both must live in one process.
+//! The handles in this module instead coordinate purely through the channel's
+//! directory: a `ProcessSender` appends every item straight to the queue files
+//! and a `ProcessReceiver`, typically in another process, tails them.
+//!
+//! The Receiver's position is kept in a small control file in the channel
+//! directory, written by `ProcessReceiver::commit` and when the Receiver is
+//! dropped, so a restarted collector carries on from its last commit. Queue
+//! files are removed once a commit moves past them, and
+//! `ProcessReceiver::compact` reclaims the part of a partly read one that has
+//! already been consumed. An application that keeps the Receiver's place in
+//! its own store instead takes a `Checkpoint` and resumes from it. The Sender
+//! holds the directory's lock for as long as it is open, which is how the
+//! Receiver tells whether it is alive; a new process takes the channel over
+//! from a running Sender with `take_over`. A Receiver waiting for new data
+//! is woken by the operating system's file-change notification with the
+//! `watch` feature, and polls without it. `backup` copies a running channel's
+//! directory into one a Receiver can replay.
+//!
+//! Queue files use the same record framing as regular channels, with the
+//! Sender's schema version leading each payload so that a Receiver deployed
+//! after a change to the item type can tell old records from new; see
+//! `ProcessSender::set_schema_version` and `ProcessReceiver::recv_raw`. A
+//! directory may be used in one mode or the other but not both at once.
+use bincode::{deserialize, serialize_into, Infinite};
+use layout::{self, RecordFraming};
+use private;
+use receiver::u8tou32abe;
+use sender::u32tou8abe;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use storage::{self, Io};
+use watch::{self, Watcher};
+use super::{DecodeError, Error, RecvError};
+use std::fmt;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The name of the control file recording the Receiver's committed position
+pub const CONTROL_FILE: &str = "hopper.ctl";
+
+// Held by the ProcessReceiver so that a directory has one reader at a time.
+pub(crate) const READER_LOCK_FILE: &str = "hopper.reader.lock";
+
+// Holds the process id of the ProcessSender while it has the directory open,
+// see `ProcessReceiver::sender_alive`.
+const SENDER_FILE: &str = "hopper.sender";
+
+fn segment(root: &Path, id: usize) -> PathBuf {
+    root.join(format!("{}", id))
+}
+
+/// A `ProcessReceiver`'s place in its channel
+///
+/// Taken with `ProcessReceiver::checkpoint` and handed back to
+/// `ProcessReceiver::resume_from`, so an application can keep the Receiver's
+/// place in its own transactional store, alongside the effects of the items
+/// it has received, rather than in the channel's control file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Checkpoint {
+    /// The queue file the next record is read from
+    pub segment: usize,
+    /// The byte offset of the next record in that queue file
+    pub offset: u64,
+    /// The number of records the Receiver has read before this point
+    ///
+    /// Counted from the first record the channel's Receivers read, whatever
+    /// queue files have been removed since, so it identifies each record for
+    /// a sink that skips records it has seen.
+    pub seq: u64,
+}
+
+/// Where a channel's records begin and end on disk, and where its Receiver
+/// last committed, as returned by `ProcessReceiver::offsets`
+///
+/// Each is a record sequence number, counted as `Checkpoint::seq` is. The
+/// Receiver's lag behind its Sender, in records, is `latest_written -
+/// committed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Offsets {
+    /// The first record the channel's queue files still hold
+    pub earliest_retained: u64,
+    /// One past the last record written: the number the next is given
+    pub latest_written: u64,
+    /// The first record a restarted Receiver reads, as of the last commit
+    pub committed: u64,
+}
+
+/// The queue file and byte offset last committed in `root`'s control file
+pub(crate) fn read_control(root: &Path) -> Option<(usize, u64)> {
+    read_checkpoint(root).map(|checkpoint| (checkpoint.segment, checkpoint.offset))
+}
+
+// The checkpoint last committed in `root`'s control file. Control files
+// written before records were counted have no sequence number: their count
+// starts at 0.
+fn read_checkpoint(root: &Path) -> Option<Checkpoint> {
+    fs::read_to_string(root.join(CONTROL_FILE))
+        .ok()
+        .and_then(|ctl| {
+            let mut fields = ctl.split_whitespace();
+            let segment = fields.next()?.parse::<usize>().ok()?;
+            let offset = fields.next()?.parse::<u64>().ok()?;
+            let seq = match fields.next() {
+                Some(seq) => seq.parse::<u64>().ok()?,
+                None => 0,
+            };
+            Some(Checkpoint {
+                segment: segment,
+                offset: offset,
+                seq: seq,
+            })
+        })
+}
+
+pub(crate) fn is_sealed(path: &Path) -> bool {
+    fs::metadata(path)
+        .map(|md| md.permissions().readonly())
+        .unwrap_or(false)
+}
+
+/// The 'send' side of a cross-process channel
+///
+/// Every `send` writes its item to the current queue file and flushes it, so
+/// the item is visible to a `ProcessReceiver` as soon as `send` returns.
+pub struct ProcessSender<T> {
+    root: PathBuf,
+    fp: BufWriter<Box<dyn Io>>,
+    opener: storage::Opener,
+    seq_num: usize,
+    bytes_written: usize,
+    max_bytes: usize,
+    schema_version: u32,
+    _lock: Option<fs::File>,
+    resource_type: PhantomData<T>,
+}
+
+impl<T> Drop for ProcessSender<T> {
+    // The process id goes before the lock is released, so that it cannot
+    // remove a successor's.
+    fn drop(&mut self) {
+        let _ = fs::remove_file(self.root.join(SENDER_FILE));
+    }
+}
+
+impl<T> fmt::Debug for ProcessSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ProcessSender")
+            .field("root", &self.root)
+            .field("seq_num", &self.seq_num)
+            .field("bytes_written", &self.bytes_written)
+            .field("max_bytes", &self.max_bytes)
+            .field("schema_version", &self.schema_version)
+            .finish()
+    }
+}
+
+/// Open the sending side of the cross-process channel `name` in `data_dir`
+///
+/// Queue files are rotated once they reach `max_bytes`. Returns
+/// `Error::AlreadyLocked` if the directory is open elsewhere as a channel or
+/// by another `ProcessSender`.
+pub fn sender<T>(name: &str, data_dir: &Path, max_bytes: usize) -> Result<ProcessSender<T>, Error>
+where
+    T: Serialize,
+{
+    sender_with(name, data_dir, max_bytes, storage::files())
+}
+
+// As `sender`, writing queue files through `opener`.
+pub(crate) fn sender_with<T>(
+    name: &str,
+    data_dir: &Path,
+    max_bytes: usize,
+    opener: storage::Opener,
+) -> Result<ProcessSender<T>, Error>
+where
+    T: Serialize,
+{
+    let root = data_dir.join(name);
+    if !root.is_dir() {
+        fs::create_dir_all(&root).expect("could not create directory");
+    }
+    let lock = layout::lock(&root)?;
+    layout::claim(&root, ::std::any::type_name::<T>(), RecordFraming::default())?;
+    // A request to take the directory over is met, or was left by a process
+    // that gave up on it.
+    if lock.is_some() {
+        let _ = layout::clear_handover(&root);
+    }
+    fs::write(
+        root.join(SENDER_FILE),
+        format!("{}\n", ::std::process::id()),
+    ).expect("could not write sender file");
+    let mut seq_num = private::segment_ids(&root, None).into_iter().max().unwrap_or(0);
+    // Records without schema versions are left to a queue file of their own.
+    let current = segment(&root, seq_num);
+    if layout::file_format(&current).is_some_and(|version| !layout::is_tagged(version)) {
+        seal(&current);
+    }
+    if is_sealed(&current) {
+        seq_num += 1;
+    }
+    let path = segment(&root, seq_num);
+    let bytes_written = fs::metadata(&path).map(|md| md.len() as usize).unwrap_or(0);
+    let mut fp = BufWriter::new(opener(&path).expect("could not open queue file"));
+    if bytes_written == 0 {
+        write_header(&mut fp);
+    }
+    Ok(ProcessSender {
+        root: root,
+        fp: fp,
+        opener: opener,
+        seq_num: seq_num,
+        bytes_written: bytes_written,
+        max_bytes: max_bytes,
+        schema_version: 0,
+        _lock: lock,
+        resource_type: PhantomData,
+    })
+}
+
+/// Open the sending side of the cross-process channel `name` in `data_dir`,
+/// taking it over from the `ProcessSender` that has it open
+///
+/// For a deploy without downtime: the new process asks the Sender holding
+/// the directory to hand it over, by writing its process id into the
+/// directory's lock file, and waits up to `timeout` for that Sender to see
+/// the request with `ProcessSender::takeover_requested` and answer it with
+/// `ProcessSender::release`. Nothing is opened until the lock is free, so
+/// the two never write at once, and this Sender starts a queue file of its
+/// own after the one released. A directory no Sender has open is opened at
+/// once, as `sender` does. Returns `Error::AlreadyLocked`, withdrawing the
+/// request, if the directory is not released in time.
+pub fn take_over<T>(
+    name: &str,
+    data_dir: &Path,
+    max_bytes: usize,
+    timeout: Duration,
+) -> Result<ProcessSender<T>, Error>
+where
+    T: Serialize,
+{
+    let root = data_dir.join(name);
+    let deadline = Instant::now() + timeout;
+    let mut requested = false;
+    loop {
+        match sender(name, data_dir, max_bytes) {
+            Err(Error::AlreadyLocked) => {}
+            opened => return opened,
+        }
+        if !requested {
+            layout::request_handover(&root).expect("could not write lock file");
+            requested = true;
+        }
+        if Instant::now() >= deadline {
+            let _ = layout::clear_handover(&root);
+            return Err(Error::AlreadyLocked);
+        }
+        thread::sleep(watch::POLL_INTERVAL);
+    }
+}
+
+impl<T> ProcessSender<T>
+where
+    T: Serialize,
+{
+    /// Tag the items sent from now on with the schema version `version`
+    ///
+    /// The version is the caller's own, for the shape of `T`, and is handed
+    /// back with each record by `ProcessReceiver::recv_raw`. Items are tagged
+    /// 0 until this is called.
+    pub fn set_schema_version(&mut self, version: u32) {
+        self.schema_version = version;
+    }
+
+    /// Write `event` to the channel
+    pub fn send(&mut self, event: T) {
+        let mut pyld = Vec::with_capacity(64);
+        pyld.extend_from_slice(&self.schema_version.to_be_bytes());
+        serialize_into(&mut pyld, &event, Infinite).expect("could not serialize");
+        let pyld_sz_bytes: [u8; 4] = u32tou8abe(pyld.len() as u32);
+        let header = [
+            pyld_sz_bytes[3],
+            pyld_sz_bytes[2],
+            pyld_sz_bytes[1],
+            pyld_sz_bytes[0],
+        ];
+        if self.bytes_written > 0 && self.bytes_written + header.len() + pyld.len() > self.max_bytes
+        {
+            self.rotate();
+        }
+        self.fp.write_all(&header).expect("could not write queue file");
+        self.fp.write_all(&pyld).expect("could not write queue file");
+        self.fp.flush().expect("could not flush queue file");
+        self.bytes_written += header.len() + pyld.len();
+    }
+
+    /// Make every item sent so far durable
+    ///
+    /// `send` hands each item to the operating system, so it survives the
+    /// Sender's process crashing, but it may be lost to a power failure
+    /// until it is synced.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.fp.flush()?;
+        self.fp.get_mut().sync()
+    }
+
+    /// Whether another process is waiting in `take_over` to be handed the
+    /// channel
+    pub fn takeover_requested(&self) -> bool {
+        layout::handover_requested(&self.root)
+    }
+
+    /// Hand the channel over to the process waiting in `take_over`
+    ///
+    /// Every item sent is synced, as `sync` does, and the queue file being
+    /// written is sealed before the directory's lock is released, so the
+    /// successor finds whole records only and writes on in a file of its
+    /// own. A Receiver reads on from the one into the other. The Sender is
+    /// closed even if the sync fails: its items were handed to the operating
+    /// system as they were sent, but may not survive a power failure.
+    pub fn release(mut self) -> io::Result<()> {
+        let synced = self.sync();
+        seal(&segment(&self.root, self.seq_num));
+        synced
+    }
+
+    // Seal the current queue file and move on to the next. The file is
+    // complete before it is marked read-only, and the next exists before the
+    // Receiver could look for it.
+    fn rotate(&mut self) {
+        self.fp.flush().expect("could not flush queue file");
+        let path = segment(&self.root, self.seq_num);
+        let next = segment(&self.root, self.seq_num + 1);
+        let mut fp = BufWriter::new((self.opener)(&next).expect("could not open queue file"));
+        write_header(&mut fp);
+        seal(&path);
+        self.fp = fp;
+        self.seq_num += 1;
+        self.bytes_written = 0;
+    }
+}
+
+// Start a new queue file with its header. The header is flushed at once so a
+// Receiver never finds the file without it once the Sender has moved on.
+fn write_header(fp: &mut BufWriter<Box<dyn Io>>) {
+    fp.write_all(&layout::segment_header(layout::FORMAT_VERSION))
+        .expect("could not write queue file");
+    fp.flush().expect("could not flush queue file");
+}
+
+// Mark the queue file at `path` read-only, telling the Receiver nothing more
+// will be written to it.
+fn seal(path: &Path) {
+    if let Ok(md) = fs::metadata(path) {
+        let mut permissions = md.permissions();
+        permissions.set_readonly(true);
+        let _ = fs::set_permissions(path, permissions);
+    }
+}
+
+/// The 'receive' side of a cross-process channel
+pub struct ProcessReceiver<T> {
+    root: PathBuf,
+    fp: Option<BufReader<fs::File>>,
+    // Whether the records of the open queue file lead with a schema version
+    tagged: bool,
+    seq_num: usize,
+    offset: u64,
+    // The number of records read before `offset`
+    seq: u64,
+    // Wakes `recv_timeout` as the directory changes
+    watcher: Watcher,
+    _lock: Option<fs::File>,
+    resource_type: PhantomData<T>,
+}
+
+impl<T> fmt::Debug for ProcessReceiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ProcessReceiver")
+            .field("root", &self.root)
+            .field("seq_num", &self.seq_num)
+            .field("offset", &self.offset)
+            .field("seq", &self.seq)
+            .finish()
+    }
+}
+
+/// Open the receiving side of the cross-process channel `name` in `data_dir`
+///
+/// Reading resumes from the position last committed, or from the oldest queue
+/// file if nothing has been committed. Returns `Error::AlreadyLocked` if a
+/// `ProcessReceiver` for the channel is already open.
+pub fn receiver<T>(name: &str, data_dir: &Path) -> Result<ProcessReceiver<T>, Error>
+where
+    T: DeserializeOwned,
+{
+    let root = data_dir.join(name);
+    if !root.is_dir() {
+        fs::create_dir_all(&root).expect("could not create directory");
+    }
+    let lock = layout::lock_file(&root, READER_LOCK_FILE)?;
+    layout::claim(&root, ::std::any::type_name::<T>(), RecordFraming::default())?;
+    let oldest = private::segment_ids(&root, None).into_iter().min().unwrap_or(0);
+    let (seq_num, offset, seq) = match read_checkpoint(&root) {
+        Some(ctl) if segment(&root, ctl.segment).exists() => (ctl.segment, ctl.offset, ctl.seq),
+        _ => (oldest, 0, 0),
+    };
+    let watcher = Watcher::new(&root);
+    Ok(ProcessReceiver {
+        root: root,
+        fp: None,
+        tagged: false,
+        seq_num: seq_num,
+        offset: offset,
+        seq: seq,
+        watcher: watcher,
+        _lock: lock,
+        resource_type: PhantomData,
+    })
+}
+
+// Read as much of `buf` as is available, returning the number of bytes read.
+fn read_available<R: Read>(fp: &mut R, buf: &mut [u8]) -> usize {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match fp.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => panic!("could not read queue file: {:?}", e),
+        }
+    }
+    filled
+}
+
+impl<T> ProcessReceiver<T>
+where
+    T: DeserializeOwned,
+{
+    /// Return the next item if one has been written, without blocking
+    ///
+    /// A record the Sender is partway through writing is left until it is
+    /// complete. A record that cannot be decoded as a `T` is returned as
+    /// `RecvError::Decode`, with its queue file, offset and place in the
+    /// channel, and passed over: the next call carries on with the record
+    /// after it.
+    pub fn try_recv(&mut self) -> Result<Option<T>, RecvError> {
+        let payload = match self.recv_raw() {
+            Some((_, payload)) => payload,
+            None => return Ok(None),
+        };
+        match deserialize(&payload) {
+            Ok(event) => Ok(Some(event)),
+            Err(cause) => {
+                let version_len = if self.tagged { 4 } else { 0 };
+                let record_len = 4 + version_len + payload.len() as u64;
+                let channel = self.root.file_name().map(|name| name.to_string_lossy());
+                Err(RecvError::Decode(DecodeError {
+                    channel: channel.unwrap_or_default().into_owned(),
+                    segment: self.seq_num,
+                    offset: self.offset - record_len,
+                    seq: self.seq - 1,
+                    cause: cause,
+                }))
+            }
+        }
+    }
+
+    /// Return the next record if one has been written, without blocking, as
+    /// its schema version and the bincode encoding of its item
+    ///
+    /// For a Receiver deployed alongside a change to `T`: records tagged with
+    /// an older version by `ProcessSender::set_schema_version` can be decoded
+    /// as the type they were written as and migrated. Records from before
+    /// schema versions were kept report version 0. Otherwise as `try_recv`.
+    pub fn recv_raw(&mut self) -> Option<(u32, Vec<u8>)> {
+        loop {
+            if self.fp.is_none() && !self.open() {
+                if self.advance() {
+                    continue;
+                }
+                return None;
+            }
+            let offset = self.offset;
+            let fp = self.fp.as_mut().expect("no queue file to read");
+            fp.seek(SeekFrom::Start(offset))
+                .expect("could not seek queue file");
+            let mut sz_buf = [0; 4];
+            match read_available(fp, &mut sz_buf) {
+                4 => {
+                    let payload_size_in_bytes = u8tou32abe(&sz_buf) as usize;
+                    let mut payload_buf = vec![0; payload_size_in_bytes];
+                    if read_available(fp, &mut payload_buf) < payload_size_in_bytes {
+                        return None;
+                    }
+                    self.offset += 4 + payload_size_in_bytes as u64;
+                    self.seq += 1;
+                    if !self.tagged {
+                        return Some((0, payload_buf));
+                    }
+                    assert!(payload_buf.len() >= 4, "queue file record has no schema version");
+                    let version = u8tou32abe(&payload_buf[..4]);
+                    payload_buf.drain(..4);
+                    return Some((version, payload_buf));
+                }
+                0 => {
+                    if !self.advance() {
+                        return None;
+                    }
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    // Open the current queue file and learn its format, returning false if
+    // it does not yet exist or has too little in it to tell.
+    fn open(&mut self) -> bool {
+        let mut fp = match fs::File::open(segment(&self.root, self.seq_num)) {
+            Ok(fp) => BufReader::new(fp),
+            Err(_) => return false,
+        };
+        let (format, _) = layout::read_format(&mut fp).expect("could not read queue file");
+        match format {
+            Some((version, start)) => {
+                self.tagged = layout::is_tagged(version);
+                if self.offset < start as u64 {
+                    self.offset = start as u64;
+                }
+                self.fp = Some(fp);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Move on to the next queue file if the Sender has finished with the
+    // current one, returning whether it had.
+    fn advance(&mut self) -> bool {
+        let path = segment(&self.root, self.seq_num);
+        let next = segment(&self.root, self.seq_num + 1);
+        if !(is_sealed(&path) && next.exists()) {
+            return false;
+        }
+        // The finished file stays until a commit moves past it, so a
+        // Receiver restarted before then can replay it.
+        self.fp = None;
+        self.seq_num += 1;
+        self.offset = 0;
+        true
+    }
+
+    /// Wait up to `timeout` for the next item
+    ///
+    /// With the `watch` feature the wait ends as the Sender writes, on a
+    /// notification from the operating system; otherwise the queue files are
+    /// polled. See the `watch` module. A record that cannot be decoded is
+    /// returned as `try_recv` returns it.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<T>, RecvError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(event) = self.try_recv()? {
+                return Ok(Some(event));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            let current = segment(&self.root, self.seq_num);
+            self.watcher.wait(&current, deadline - now);
+        }
+    }
+
+    /// Record the current position so a restarted Receiver resumes from it
+    ///
+    /// Queue files wholly behind the committed position are removed, unless
+    /// `backup` is copying them, in which case the next commit removes them.
+    pub fn commit(&self) {
+        write_checkpoint(&self.root, &self.checkpoint()).expect("could not write control file");
+        collect(&self.root, self.seq_num);
+    }
+
+    /// The Receiver's current place in the channel: the next record it reads
+    ///
+    /// Store the checkpoint with whatever the application did with the
+    /// records before it--in one transaction--and resume from it with
+    /// `resume_from` after a restart, so that every record takes effect once
+    /// for an idempotent sink. Taking a checkpoint commits nothing. `commit`,
+    /// and dropping the Receiver, still remove the queue files behind the
+    /// Receiver's place, so commit no further than a checkpoint already
+    /// stored. `compact` moves the records of the queue file it rewrites:
+    /// checkpoints taken in that file before then no longer hold.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            segment: self.seq_num,
+            offset: self.offset,
+            seq: self.seq,
+        }
+    }
+
+    /// The channel's offsets, for gauging how far behind its Sender the
+    /// Receiver is
+    ///
+    /// Assembled from the control file and by counting the records of the
+    /// queue files on disk either side of the committed position, so this
+    /// reads every queue file. Records read but not yet committed count as
+    /// unread, and a record the Sender is partway through writing is not
+    /// counted. With nothing committed the count starts at the oldest queue
+    /// file.
+    pub fn offsets(&self) -> Offsets {
+        let ids = private::segment_ids(&self.root, None);
+        let committed = read_checkpoint(&self.root).unwrap_or(Checkpoint {
+            segment: ids.iter().cloned().min().unwrap_or(0),
+            offset: 0,
+            seq: 0,
+        });
+        let (mut behind, mut ahead) = (0, 0);
+        for id in ids {
+            let buf = match fs::read(segment(&self.root, id)) {
+                Ok(buf) => buf,
+                Err(_) => continue,
+            };
+            let records = match ::segment::parse(&buf) {
+                Ok(records) => records,
+                Err(_) => continue,
+            };
+            for record in records {
+                let record = match record {
+                    Ok(record) => record,
+                    Err(_) => break,
+                };
+                if (id, record.offset as u64) < (committed.segment, committed.offset) {
+                    behind += 1;
+                } else {
+                    ahead += 1;
+                }
+            }
+        }
+        Offsets {
+            earliest_retained: committed.seq.saturating_sub(behind),
+            latest_written: committed.seq + ahead,
+            committed: committed.seq,
+        }
+    }
+
+    /// Carry on from `checkpoint`, as taken by `checkpoint`
+    ///
+    /// The Receiver reads the record at the checkpoint next. Nothing is
+    /// committed. Returns `Error::StaleCheckpoint` if the checkpoint's queue
+    /// file has been removed, leaving the Receiver where it was.
+    pub fn resume_from(&mut self, checkpoint: Checkpoint) -> Result<(), Error> {
+        if !segment(&self.root, checkpoint.segment).exists() {
+            return Err(Error::StaleCheckpoint);
+        }
+        self.fp = None;
+        self.seq_num = checkpoint.segment;
+        self.offset = checkpoint.offset;
+        self.seq = checkpoint.seq;
+        Ok(())
+    }
+
+    /// Reclaim the space taken by records already read from the current queue
+    /// file, returning the number of bytes freed
+    ///
+    /// The file is rewritten without those records, committing the current
+    /// position as it goes. Only a sealed file is compacted, as the Sender may
+    /// still be appending to an open one, and none while `backup` runs. A
+    /// crash partway through replays the compacted records rather than losing
+    /// any.
+    pub fn compact(&mut self) -> u64 {
+        let path = segment(&self.root, self.seq_num);
+        if self.offset == 0 || !is_sealed(&path) {
+            return 0;
+        }
+        // A backup copying the file has it pinned.
+        let _pin = match layout::share_lock_file(&self.root, layout::PIN_FILE) {
+            Ok(pin) => pin,
+            Err(_) => return 0,
+        };
+        let buf = fs::read(&path).expect("could not read queue file");
+        let start = layout::records_start(&buf).unwrap_or(0) as u64;
+        let offset = ::std::cmp::min(self.offset, buf.len() as u64);
+        if offset <= start {
+            return 0;
+        }
+        let freed = offset - start;
+        let mut compacted = buf[..start as usize].to_vec();
+        compacted.extend_from_slice(&buf[offset as usize..]);
+        let tmp = self.root.join(format!("{}.compact", self.seq_num));
+        fs::write(&tmp, &compacted).expect("could not write compacted queue file");
+        let mut permissions = fs::metadata(&tmp)
+            .expect("could not read compacted queue file metadata")
+            .permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&tmp, permissions).expect("could not seal compacted queue file");
+
+        self.fp = None;
+        self.offset = 0;
+        write_checkpoint(&self.root, &self.checkpoint()).expect("could not write control file");
+        private::replace_segment(&tmp, &path).expect("could not replace queue file");
+        collect(&self.root, self.seq_num);
+        freed
+    }
+
+    /// Whether a `ProcessSender` currently has the channel open
+    ///
+    /// A Sender leaves its process id in the channel's directory while it has
+    /// the channel open, and the check looks that process up rather than
+    /// taking the Sender's lock. On Linux a Sender that died without closing
+    /// the channel is seen to be gone; elsewhere it is taken as alive until
+    /// the next Sender opens the channel.
+    pub fn sender_alive(&self) -> bool {
+        match fs::read_to_string(self.root.join(SENDER_FILE)) {
+            Ok(pid) => pid.trim().parse().map(process_running).unwrap_or(false),
+            Err(_) => false,
+        }
+    }
+}
+
+impl<T> Drop for ProcessReceiver<T> {
+    fn drop(&mut self) {
+        let checkpoint = Checkpoint {
+            segment: self.seq_num,
+            offset: self.offset,
+            seq: self.seq,
+        };
+        if write_checkpoint(&self.root, &checkpoint).is_ok() {
+            collect(&self.root, self.seq_num);
+        }
+    }
+}
+
+// Whether the process `pid` is running, see `ProcessReceiver::sender_alive`.
+#[cfg(target_os = "linux")]
+fn process_running(pid: u32) -> bool {
+    pid == ::std::process::id() || Path::new("/proc").join(pid.to_string()).exists()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_running(_pid: u32) -> bool {
+    true
+}
+
+// Remove the queue files before `seq_num`. One that cannot be removed now,
+// or while a backup has them pinned, is tried again at the next commit.
+fn collect(root: &Path, seq_num: usize) {
+    let _pin = match layout::share_lock_file(root, layout::PIN_FILE) {
+        Ok(pin) => pin,
+        Err(_) => return,
+    };
+    for id in private::segment_ids(root, None) {
+        if id < seq_num {
+            let _ = private::remove_segment(&segment(root, id));
+        }
+    }
+}
+
+/// What `backup` copied
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Backup {
+    /// The queue files in the backup
+    pub segments: usize,
+    /// How many of those were hard-linked rather than copied
+    pub linked: usize,
+    /// The bytes the queue files in the backup hold
+    pub bytes: u64,
+}
+
+/// Back up the cross-process channel `name` in `data_dir` into `dest`, while
+/// its Sender and Receiver run
+///
+/// The backup is a channel directory of its own, `name` under `dest`, and a
+/// `ProcessReceiver` opened on it replays the channel from the position last
+/// committed when the backup was taken. It holds the channel's metadata,
+/// control file and queue files. Queue files the Sender has sealed never
+/// change again, so they are hard-linked where `dest` is on the same
+/// filesystem and copied where it is not. The file the Sender is writing is
+/// copied up to its last whole record.
+///
+/// While the backup is taken the channel's queue files are pinned: the
+/// Receiver removes and compacts none of them, and `repair` refuses the
+/// directory. The Receiver removes what it held off at its next commit. Items
+/// it commits after the backup takes its position are replayed by the backup
+/// too. Returns an error of kind `AlreadyExists` if `dest` already holds
+/// anything under `name`.
+pub fn backup(name: &str, data_dir: &Path, dest: &Path) -> io::Result<Backup> {
+    let root = data_dir.join(name);
+    if !root.is_dir() {
+        return Err(io::Error::new(ErrorKind::NotFound, "no such channel directory"));
+    }
+    let target = dest.join(name);
+    if target.is_dir() && fs::read_dir(&target)?.next().is_some() {
+        let msg = "backup directory is not empty";
+        return Err(io::Error::new(ErrorKind::AlreadyExists, msg));
+    }
+    fs::create_dir_all(&target)?;
+    let pin = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(root.join(layout::PIN_FILE))?;
+    // A Receiver partway through removing or compacting files finishes first.
+    pin.lock()?;
+
+    // The position is taken first. Every queue file from it on stays until
+    // the pin is released.
+    for file in &[layout::METADATA_FILE, CONTROL_FILE] {
+        match fs::copy(root.join(file), target.join(file)) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let mut ids = private::segment_ids(&root, None);
+    ids.sort();
+    let mut backup = Backup::default();
+    for (i, &id) in ids.iter().enumerate() {
+        let (from, to) = (segment(&root, id), segment(&target, id));
+        if is_sealed(&from) {
+            if fs::hard_link(&from, &to).is_ok() {
+                backup.linked += 1;
+            } else {
+                fs::copy(&from, &to)?;
+            }
+        } else {
+            let buf = fs::read(&from)?;
+            let mut fp = fs::File::create(&to)?;
+            fp.write_all(&buf[..whole_records(&buf)])?;
+            fp.sync_all()?;
+            // The Sender had moved on from any file but the last, so the
+            // Receiver of the backup must find it sealed to move on too.
+            if i + 1 < ids.len() {
+                seal(&to);
+            }
+        }
+        backup.segments += 1;
+        backup.bytes += fs::metadata(&to)?.len();
+    }
+    drop(pin);
+    Ok(backup)
+}
+
+// The length of the queue file contents `buf` up to the end of its last whole
+// record, or 0 if its header is not yet whole.
+fn whole_records(buf: &[u8]) -> usize {
+    let start = match layout::records_start(buf) {
+        Some(start) => start,
+        None => return 0,
+    };
+    let mut rest = &buf[start..];
+    while let Some((_, after)) = private::next_record(rest) {
+        rest = after;
+    }
+    buf.len() - rest.len()
+}
+
+// Move the committed position in `root`'s control file to `offset` of the
+// queue file `seq_num`, keeping its count of records read.
+pub(crate) fn write_control(root: &Path, seq_num: usize, offset: u64) -> io::Result<()> {
+    let seq = read_checkpoint(root).map_or(0, |checkpoint| checkpoint.seq);
+    write_checkpoint(
+        root,
+        &Checkpoint {
+            segment: seq_num,
+            offset: offset,
+            seq: seq,
+        },
+    )
+}
+
+// Replace the control file in one step so a crash mid-write cannot leave a
+// torn position behind.
+fn write_checkpoint(root: &Path, checkpoint: &Checkpoint) -> io::Result<()> {
+    let tmp = root.join(format!("{}.tmp", CONTROL_FILE));
+    let ctl = format!("{} {} {}\n", checkpoint.segment, checkpoint.offset, checkpoint.seq);
+    fs::write(&tmp, ctl)?;
+    fs::rename(&tmp, root.join(CONTROL_FILE))
+}
+
+#[cfg(test)]
+mod test {
+    extern crate quickcheck;
+    extern crate tempdir;
+
+    use self::quickcheck::{QuickCheck, TestResult};
+    use super::{backup, receiver, segment, sender, sender_with, take_over, Offsets, SENDER_FILE};
+    use super::super::{Error, RecvError};
+    use layout;
+    use private;
+    use repair;
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+    use storage::faults::{self, Faults};
+
+    #[test]
+    fn items_cross_between_handles() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let mut snd = sender::<u64>("xproc", dir.path(), 128).unwrap();
+        let mut rcv = receiver::<u64>("xproc", dir.path()).unwrap();
+        assert!(rcv.sender_alive());
+
+        for i in 0..100u64 {
+            snd.send(i);
+        }
+        for i in 0..100u64 {
+            assert_eq!(Some(i), rcv.try_recv().unwrap());
+        }
+        assert_eq!(None, rcv.try_recv().unwrap());
+        assert_eq!(None, rcv.recv_timeout(Duration::from_millis(20)).unwrap());
+
+        drop(snd);
+        assert!(!rcv.sender_alive());
+    }
+
+    #[test]
+    fn records_carry_the_senders_schema_version() {
+        use bincode::{serialize, Infinite};
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let mut snd = sender::<(u32, String)>("xproc_schema", dir.path(), 128).unwrap();
+        let mut rcv = receiver::<(u32, String)>("xproc_schema", dir.path()).unwrap();
+        snd.send((1, "old".to_string()));
+        snd.set_schema_version(7);
+        snd.send((2, "new".to_string()));
+
+        let (version, old) = rcv.recv_raw().unwrap();
+        assert_eq!(0, version);
+        assert_eq!(serialize(&(1u32, "old"), Infinite).unwrap(), old);
+        assert_eq!((7, serialize(&(2u32, "new"), Infinite).unwrap()), rcv.recv_raw().unwrap());
+        assert_eq!(None, rcv.recv_raw());
+
+        snd.send((3, "typed".to_string()));
+        assert_eq!(Some((3, "typed".to_string())), rcv.try_recv().unwrap());
+    }
+
+    #[test]
+    fn undecodable_records_are_reported_and_passed_over() {
+        use std::io::Write;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let mut snd = sender::<u64>("xproc_decode", dir.path(), 1024).unwrap();
+        let mut rcv = receiver::<u64>("xproc_decode", dir.path()).unwrap();
+        snd.send(1);
+        // A record whose item is two bytes, too short for a u64
+        snd.fp.write_all(&[0, 0, 0, 6, 0, 0, 0, 0, 1, 2]).unwrap();
+        snd.bytes_written += 10;
+        snd.send(3);
+
+        assert_eq!(Some(1), rcv.try_recv().unwrap());
+        let start = rcv.offset;
+        match rcv.try_recv() {
+            Err(RecvError::Decode(e)) => {
+                assert_eq!("xproc_decode", e.channel);
+                assert_eq!(0, e.segment);
+                assert_eq!(start, e.offset);
+                assert_eq!(1, e.seq);
+            }
+            other => panic!("expected a decode error, got {:?}", other),
+        }
+        assert_eq!(Some(3), rcv.try_recv().unwrap());
+        assert_eq!(None, rcv.try_recv().unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn a_dead_senders_process_id_is_not_alive() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let rcv = receiver::<u64>("xproc_dead", dir.path()).unwrap();
+        assert!(!rcv.sender_alive());
+
+        // Left by a Sender that was killed, its process since gone
+        let root = dir.path().join("xproc_dead");
+        fs::write(root.join(SENDER_FILE), format!("{}\n", u32::MAX)).unwrap();
+        assert!(!rcv.sender_alive());
+
+        // The next Sender opens without waiting on the check
+        let snd = sender::<u64>("xproc_dead", dir.path(), 128).unwrap();
+        assert!(rcv.sender_alive());
+        drop(snd);
+        assert!(!root.join(SENDER_FILE).exists());
+        assert!(!rcv.sender_alive());
+    }
+
+    #[test]
+    fn receiver_resumes_from_commit() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let mut snd = sender::<u64>("xproc_resume", dir.path(), 128).unwrap();
+        for i in 0..50u64 {
+            snd.send(i);
+        }
+        {
+            let mut rcv = receiver::<u64>("xproc_resume", dir.path()).unwrap();
+            for i in 0..20u64 {
+                assert_eq!(Some(i), rcv.try_recv().unwrap());
+            }
+            rcv.commit();
+        }
+        let mut rcv = receiver::<u64>("xproc_resume", dir.path()).unwrap();
+        for i in 20..50u64 {
+            assert_eq!(Some(i), rcv.try_recv().unwrap());
+        }
+        assert_eq!(None, rcv.try_recv().unwrap());
+    }
+
+    #[test]
+    fn offsets_span_the_records_on_disk() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let mut snd = sender::<u64>("xproc_offsets", dir.path(), 128).unwrap();
+        let mut rcv = receiver::<u64>("xproc_offsets", dir.path()).unwrap();
+        for i in 0..50u64 {
+            snd.send(i);
+        }
+        let offsets = Offsets {
+            earliest_retained: 0,
+            latest_written: 50,
+            committed: 0,
+        };
+        assert_eq!(offsets, rcv.offsets());
+        for _ in 0..30 {
+            rcv.try_recv().unwrap();
+        }
+        assert_eq!(offsets, rcv.offsets());
+        rcv.commit();
+        snd.send(50);
+        let offsets = rcv.offsets();
+        assert_eq!(30, offsets.committed);
+        assert_eq!(51, offsets.latest_written);
+        // The commit removed the queue files wholly behind it, but not the
+        // records read from the one it is in
+        assert!(offsets.earliest_retained > 0 && offsets.earliest_retained < 30);
+    }
+
+    #[test]
+    fn receiver_resumes_from_checkpoint() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let mut snd = sender::<u64>("xproc_checkpoint", dir.path(), 128).unwrap();
+        for i in 0..50u64 {
+            snd.send(i);
+        }
+        let mut rcv = receiver::<u64>("xproc_checkpoint", dir.path()).unwrap();
+        for i in 0..20u64 {
+            assert_eq!(Some(i), rcv.try_recv().unwrap());
+        }
+        let checkpoint = rcv.checkpoint();
+        assert_eq!(20, checkpoint.seq);
+        for i in 20..45u64 {
+            assert_eq!(Some(i), rcv.try_recv().unwrap());
+        }
+        rcv.resume_from(checkpoint).unwrap();
+        for i in 20..50u64 {
+            assert_eq!(Some(i), rcv.try_recv().unwrap());
+        }
+        assert_eq!(None, rcv.try_recv().unwrap());
+
+        // A commit removes the queue files behind the Receiver.
+        rcv.commit();
+        assert!(checkpoint.segment < rcv.checkpoint().segment);
+        assert_eq!(Err(Error::StaleCheckpoint), rcv.resume_from(checkpoint));
+        assert_eq!(None, rcv.try_recv().unwrap());
+
+        // Records are counted on from the commit across a restart.
+        drop(rcv);
+        snd.send(50);
+        let mut rcv = receiver::<u64>("xproc_checkpoint", dir.path()).unwrap();
+        assert_eq!(50, rcv.checkpoint().seq);
+        assert_eq!(Some(50), rcv.try_recv().unwrap());
+        assert_eq!(51, rcv.checkpoint().seq);
+    }
+
+    #[test]
+    fn consumed_segments_are_removed_on_commit() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let root = dir.path().join("xproc_gc");
+        let mut snd = sender::<u64>("xproc_gc", dir.path(), 128).unwrap();
+        for i in 0..50u64 {
+            snd.send(i);
+        }
+        let mut rcv = receiver::<u64>("xproc_gc", dir.path()).unwrap();
+        for i in 0..30u64 {
+            assert_eq!(Some(i), rcv.try_recv().unwrap());
+        }
+        assert!(segment(&root, 0).exists());
+        rcv.commit();
+        let remaining = private::segment_ids(&root, None);
+        assert!(!remaining.is_empty());
+        assert!(remaining.iter().all(|&id| id >= rcv.seq_num));
+        assert!(!segment(&root, 0).exists());
+    }
+
+    #[test]
+    fn compaction_drops_consumed_records() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let root = dir.path().join("xproc_compact");
+        let mut snd = sender::<u64>("xproc_compact", dir.path(), 128).unwrap();
+        for i in 0..50u64 {
+            snd.send(i);
+        }
+        let before = fs::metadata(segment(&root, 0)).unwrap().len();
+        {
+            let mut rcv = receiver::<u64>("xproc_compact", dir.path()).unwrap();
+            for i in 0..5u64 {
+                assert_eq!(Some(i), rcv.try_recv().unwrap());
+            }
+            let freed = rcv.compact();
+            assert!(freed > 0);
+            assert_eq!(before - freed, fs::metadata(segment(&root, 0)).unwrap().len());
+            assert_eq!(Some(5), rcv.try_recv().unwrap());
+        }
+        let mut rcv = receiver::<u64>("xproc_compact", dir.path()).unwrap();
+        for i in 6..50u64 {
+            assert_eq!(Some(i), rcv.try_recv().unwrap());
+        }
+        assert_eq!(None, rcv.try_recv().unwrap());
+    }
+
+    #[test]
+    fn backup_replays_from_the_committed_position() {
+        use std::io::{ErrorKind, Write};
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let dest = tempdir::TempDir::new("hopper").unwrap();
+        let root = dir.path().join("xproc_backup");
+        let mut snd = sender::<u64>("xproc_backup", dir.path(), 128).unwrap();
+        let mut rcv = receiver::<u64>("xproc_backup", dir.path()).unwrap();
+        for i in 0..100u64 {
+            snd.send(i);
+        }
+        for i in 0..30u64 {
+            assert_eq!(Some(i), rcv.try_recv().unwrap());
+        }
+        rcv.commit();
+        // The Sender is partway through a record
+        let current = private::segment_ids(&root, None).into_iter().max().unwrap();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(segment(&root, current))
+            .unwrap()
+            .write_all(&[0, 0, 0, 20, 1, 2])
+            .unwrap();
+
+        // Nothing is removed while the files are pinned
+        let oldest = private::segment_ids(&root, None).into_iter().min().unwrap();
+        let pin = layout::lock_file(&root, layout::PIN_FILE).unwrap();
+        for _ in 30..60u64 {
+            rcv.try_recv().unwrap();
+        }
+        rcv.commit();
+        assert!(segment(&root, oldest).exists());
+        drop(pin);
+
+        let copied = backup("xproc_backup", dir.path(), dest.path()).unwrap();
+        assert!(copied.linked > 0 && copied.linked < copied.segments);
+        assert_eq!(private::segment_ids(&root, None).len(), copied.segments);
+        rcv.commit();
+        assert!(!segment(&root, oldest).exists());
+        drop(rcv);
+        drop(snd);
+
+        // The backup replays from the commit it was taken at and carries on
+        let mut restored = receiver::<u64>("xproc_backup", dest.path()).unwrap();
+        for i in 60..100u64 {
+            assert_eq!(Some(i), restored.try_recv().unwrap());
+        }
+        assert_eq!(None, restored.try_recv().unwrap());
+        let mut snd = sender::<u64>("xproc_backup", dest.path(), 128).unwrap();
+        snd.send(100);
+        assert_eq!(Some(100), restored.try_recv().unwrap());
+
+        let refused = backup("xproc_backup", dir.path(), dest.path()).unwrap_err();
+        assert_eq!(ErrorKind::AlreadyExists, refused.kind());
+    }
+
+    #[test]
+    fn sender_hands_the_channel_over() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let mut snd = sender::<u64>("xproc_takeover", dir.path(), 128).unwrap();
+        let mut rcv = receiver::<u64>("xproc_takeover", dir.path()).unwrap();
+        for i in 0..10u64 {
+            snd.send(i);
+        }
+        assert!(!snd.takeover_requested());
+
+        let data_dir = dir.path().to_path_buf();
+        let successor = thread::spawn(move || {
+            let mut snd =
+                take_over::<u64>("xproc_takeover", &data_dir, 128, Duration::from_secs(10))
+                    .unwrap();
+            for i in 10..20u64 {
+                snd.send(i);
+            }
+        });
+        while !snd.takeover_requested() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        snd.send(10_000);
+        snd.release().unwrap();
+        successor.join().unwrap();
+
+        let received: Vec<u64> = (0..21).map(|_| rcv.try_recv().unwrap().unwrap()).collect();
+        let expected: Vec<u64> = (0..10).chain(Some(10_000)).chain(10..20).collect();
+        assert_eq!(expected, received);
+        assert_eq!(None, rcv.try_recv().unwrap());
+        // The request was met, so the next Sender is not asked to release.
+        let snd = sender::<u64>("xproc_takeover", dir.path(), 128).unwrap();
+        assert!(!snd.takeover_requested());
+    }
+
+    #[test]
+    fn takeover_gives_up_after_its_timeout() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let snd = sender::<u64>("xproc_takeover_timeout", dir.path(), 128).unwrap();
+        let taken = take_over::<u64>(
+            "xproc_takeover_timeout",
+            dir.path(),
+            128,
+            Duration::from_millis(50),
+        );
+        assert_eq!(Err(Error::AlreadyLocked), taken.map(|_| ()));
+        assert!(!snd.takeover_requested());
+        drop(snd);
+
+        let free = take_over::<u64>("xproc_takeover_free", dir.path(), 128, Duration::new(0, 0));
+        assert!(free.is_ok());
+    }
+
+    #[test]
+    fn one_sender_and_one_receiver_at_a_time() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let _snd = sender::<u64>("xproc_single", dir.path(), 128).unwrap();
+        let _rcv = receiver::<u64>("xproc_single", dir.path()).unwrap();
+        assert_eq!(
+            Err(Error::AlreadyLocked),
+            sender::<u64>("xproc_single", dir.path(), 128).map(|_| ())
+        );
+        assert_eq!(
+            Err(Error::AlreadyLocked),
+            receiver::<u64>("xproc_single", dir.path()).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn short_writes_lose_nothing() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let mut short = Faults::default();
+        short.short_writes = Some(3);
+        let (opener, _) = faults::opener(short);
+        let mut snd = sender_with::<u64>("xproc_short", dir.path(), 128, opener).unwrap();
+        let mut rcv = receiver::<u64>("xproc_short", dir.path()).unwrap();
+        for i in 0..50u64 {
+            snd.send(i);
+        }
+        for i in 0..50u64 {
+            assert_eq!(Some(i), rcv.try_recv().unwrap());
+        }
+        assert_eq!(None, rcv.try_recv().unwrap());
+    }
+
+    #[test]
+    fn failed_sync_is_reported() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let mut failing = Faults::default();
+        failing.fail_sync = true;
+        let (opener, faults) = faults::opener(failing);
+        let mut snd = sender_with::<u64>("xproc_sync", dir.path(), 128, opener).unwrap();
+        snd.send(1);
+        assert!(snd.sync().is_err());
+        faults.lock().unwrap().fail_sync = false;
+        assert!(snd.sync().is_ok());
+    }
+
+    #[test]
+    fn power_loss_leaves_a_recoverable_prefix() {
+        fn recover(evs: Vec<Vec<u32>>, cut: u64) -> TestResult {
+            let dir = tempdir::TempDir::new("hopper").unwrap();
+            let root = dir.path().join("xproc_power");
+            // Where in the stream of bytes written each record ends, with a
+            // header starting each queue file
+            let header = layout::SEGMENT_HEADER_LEN as u64;
+            let mut ends = Vec::with_capacity(evs.len());
+            let (mut total, mut in_file) = (header, 0);
+            for ev in &evs {
+                let len = 4 + 4 + 8 + 4 * ev.len() as u64;
+                if in_file > 0 && in_file + len > 128 {
+                    total += header;
+                    in_file = 0;
+                }
+                total += len;
+                in_file += len;
+                ends.push(total);
+            }
+            let cut = total * (cut % 101) / 100;
+
+            let mut crashing = Faults::default();
+            crashing.power_loss_at = Some(cut);
+            let (opener, _) = faults::opener(crashing);
+            {
+                let mut snd =
+                    sender_with::<Vec<u32>>("xproc_power", dir.path(), 128, opener).unwrap();
+                for ev in evs.clone() {
+                    snd.send(ev);
+                }
+            }
+            repair::repair(&root).unwrap();
+
+            // Exactly the records that landed whole survive, and the channel
+            // carries on after them.
+            let survivors = ends.iter().take_while(|&&end| end <= cut).count();
+            let mut snd = sender::<Vec<u32>>("xproc_power", dir.path(), 128).unwrap();
+            snd.send(vec![7]);
+            let mut rcv = receiver::<Vec<u32>>("xproc_power", dir.path()).unwrap();
+            for ev in &evs[..survivors] {
+                assert_eq!(Some(ev), rcv.try_recv().unwrap().as_ref());
+            }
+            assert_eq!(Some(vec![7]), rcv.try_recv().unwrap());
+            assert_eq!(None, rcv.try_recv().unwrap());
+            TestResult::passed()
+        }
+        QuickCheck::new()
+            .tests(100)
+            .max_tests(1000)
+            .quickcheck(recover as fn(Vec<Vec<u32>>, u64) -> TestResult);
+    }
+}
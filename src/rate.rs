@@ -0,0 +1,119 @@
+//! Token-bucket rate limiting of sends
+//!
+//! A channel built with `ChannelBuilder::rate_limit` or
+//! `ChannelBuilder::sender_rate_limit` gives each send a token from a bucket
+//! that holds up to `burst` tokens and refills at `per_second` tokens a
+//! second. A send finding the bucket empty waits for the next token or is
+//! refused, as the channel's `RatePolicy` says.
+use std::cmp;
+use std::sync::Arc;
+use sync::{self, Mutex};
+use std::time::{Duration, Instant};
+
+/// What a send over a channel's rate limit does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RatePolicy {
+    /// Wait until a token is available
+    Block,
+    /// Refuse the item: `Sender::try_send` returns
+    /// `SendError::RateLimited` and `Sender::send` drops it, counting it in
+    /// `QueueMetrics::total_rate_limited`
+    Fail,
+}
+
+/// A rate limit as set on the builder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub per_second: u32,
+    pub burst: u32,
+    pub policy: RatePolicy,
+    // Whether each Sender clone has a bucket of its own rather than sharing
+    // the channel's
+    pub per_sender: bool,
+}
+
+impl RateLimit {
+    /// A full bucket for this limit
+    pub fn bucket(&self) -> Arc<Mutex<TokenBucket>> {
+        Arc::new(Mutex::new(TokenBucket::new(self.per_second, self.burst)))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TokenBucket {
+    per_second: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket of `burst` tokens, at least one, refilled at
+    /// `per_second`
+    pub fn new(per_second: u32, burst: u32) -> TokenBucket {
+        assert!(per_second > 0, "rate limit must allow at least one send a second");
+        let burst = f64::from(cmp::max(burst, 1));
+        TokenBucket {
+            per_second: f64::from(per_second),
+            burst: burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    /// Take a token as of `now`, or return how long until one is available
+    pub fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        if now > self.last {
+            let refill = now.duration_since(self.last).as_secs_f64() * self.per_second;
+            self.tokens = (self.tokens + refill).min(self.burst);
+            self.last = now;
+        }
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.per_second))
+        }
+    }
+}
+
+/// Take a token from `bucket`, waiting for one if `block` is set
+///
+/// Returns whether a token was taken. The bucket is not locked while waiting,
+/// so other Senders sharing it carry on.
+pub fn acquire(bucket: &Mutex<TokenBucket>, block: bool) -> bool {
+    loop {
+        let wait = match sync::lock(bucket).take(Instant::now()) {
+            Ok(()) => return true,
+            Err(wait) => wait,
+        };
+        if !block {
+            return false;
+        }
+        ::std::thread::sleep(wait);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_burst_then_refills() {
+        let mut bucket = TokenBucket::new(10, 3);
+        let start = bucket.last;
+        for _ in 0..3 {
+            assert_eq!(Ok(()), bucket.take(start));
+        }
+        let wait = bucket.take(start).unwrap_err();
+        assert!(wait > Duration::from_millis(90) && wait <= Duration::from_millis(100));
+        assert_eq!(Ok(()), bucket.take(start + Duration::from_millis(100)));
+        assert!(bucket.take(start + Duration::from_millis(100)).is_err());
+        // Refills stop at the burst size.
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(Ok(()), bucket.take(later));
+        }
+        assert!(bucket.take(later).is_err());
+    }
+}
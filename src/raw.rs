@@ -0,0 +1,221 @@
+//! Channels carrying bytes encoded elsewhere
+//!
+//! A raw channel, opened with `raw::channel` or `ChannelBuilder::build_raw`,
+//! carries byte strings as they are given to it, for pipelines whose items
+//! arrive already encoded. Each is paged out as the payload of a queue file
+//! record, exactly its bytes, with no bincode encoding around it: the
+//! record's length prefix says where it ends. Everything else is as for a
+//! regular channel, sequencing, rotation, encryption and all.
+//!
+//! # Example
+//! ```
+//! extern crate tempdir;
+//! extern crate hopper;
+//!
+//! let dir = tempdir::TempDir::new("hopper").unwrap();
+//! let (mut snd, mut rcv) = hopper::raw::channel("raw", dir.path()).unwrap();
+//!
+//! snd.send(b"already encoded");
+//! assert_eq!(Some(b"already encoded".to_vec()), rcv.try_recv().unwrap());
+//! ```
+use super::{ChannelBuilder, Error, QueueMetrics, Receiver, RecvError, Sender};
+use serde::de::{Deserialize, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeTuple, Serializer};
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// The item of a raw channel: bytes that encode as themselves
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Bytes(Vec<u8>);
+
+impl Serialize for Bytes {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        // bincode gives a tuple no length prefix, leaving the bytes bare.
+        let mut tuple = serializer.serialize_tuple(self.0.len())?;
+        for byte in &self.0 {
+            tuple.serialize_element(byte)?;
+        }
+        tuple.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Bytes {
+    fn deserialize<D>(deserializer: D) -> Result<Bytes, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_tuple(usize::MAX, BytesVisitor)
+    }
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Bytes;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("the bytes of a queue file record")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Bytes, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        // Decoding reads from the record's payload alone, so the bytes end
+        // where reading another fails.
+        let mut bytes = Vec::new();
+        while let Ok(Some(byte)) = seq.next_element() {
+            bytes.push(byte);
+        }
+        Ok(Bytes(bytes))
+    }
+}
+
+/// Create a raw (RawSender, RawReceiver) pair named `name` in `data_dir`
+///
+/// As `hopper::channel`. Use `ChannelBuilder::build_raw` to configure the
+/// channel.
+pub fn channel(name: &str, data_dir: &Path) -> Result<(RawSender, RawReceiver), Error> {
+    ChannelBuilder::new(name, data_dir).build_raw()
+}
+
+/// The 'send' side of a raw channel
+///
+/// Created by `raw::channel` or `ChannelBuilder::build_raw`. Clones share the
+/// channel as `Sender` clones do.
+#[derive(Clone)]
+pub struct RawSender {
+    inner: Sender<Bytes>,
+}
+
+impl fmt::Debug for RawSender {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RawSender")
+            .field("name", &self.inner.name())
+            .finish()
+    }
+}
+
+impl RawSender {
+    /// Send `bytes`
+    ///
+    /// See `Sender::send`.
+    pub fn send(&mut self, bytes: &[u8]) {
+        self.inner.send(Bytes(bytes.to_vec()))
+    }
+
+    /// Hand any items staged by this RawSender to the Receiver
+    ///
+    /// See `Sender::flush`.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    /// Return the channel's metrics
+    pub fn metrics(&self) -> QueueMetrics {
+        self.inner.metrics()
+    }
+}
+
+/// The 'receive' side of a raw channel
+///
+/// Created by `raw::channel` or `ChannelBuilder::build_raw`.
+pub struct RawReceiver {
+    inner: Receiver<Bytes>,
+}
+
+impl fmt::Debug for RawReceiver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RawReceiver")
+            .field("name", &self.inner.name())
+            .finish()
+    }
+}
+
+impl RawReceiver {
+    /// Receive the next item, waiting for one until the channel hangs up
+    ///
+    /// Returns `None` once every RawSender has been dropped and every item
+    /// received. See `Receiver::is_hung_up`.
+    pub fn recv(&mut self) -> Option<Vec<u8>> {
+        self.inner.iter().next().map(|bytes| bytes.0)
+    }
+
+    /// Receive the next item, if one is waiting
+    ///
+    /// See `Receiver::try_recv`.
+    pub fn try_recv(&mut self) -> Result<Option<Vec<u8>>, RecvError> {
+        Ok(self.inner.try_recv()?.map(|bytes| bytes.0))
+    }
+
+    /// Receive the next item, waiting up to `timeout` for one
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Option<Vec<u8>> {
+        self.inner.recv_batch(1, timeout).pop().map(|bytes| bytes.0)
+    }
+
+    /// Receive up to `max` items, waiting up to `timeout` for them to arrive
+    ///
+    /// See `Receiver::recv_batch`.
+    pub fn recv_batch(&mut self, max: usize, timeout: Duration) -> Vec<Vec<u8>> {
+        self.inner
+            .recv_batch(max, timeout)
+            .into_iter()
+            .map(|bytes| bytes.0)
+            .collect()
+    }
+
+    /// Return the channel's metrics
+    pub fn metrics(&self) -> QueueMetrics {
+        self.inner.metrics()
+    }
+}
+
+/// Wrap a (Sender, Receiver) pair of `Bytes` as a raw channel
+pub(crate) fn assemble(pair: (Sender<Bytes>, Receiver<Bytes>)) -> (RawSender, RawReceiver) {
+    (RawSender { inner: pair.0 }, RawReceiver { inner: pair.1 })
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::*;
+    use std::fs;
+    use std::thread;
+
+    #[test]
+    fn bytes_reach_the_queue_file_bare() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = ChannelBuilder::new("raw", dir.path())
+            .max_bytes(1 << 20)
+            .build_raw()
+            .unwrap();
+        let items: Vec<Vec<u8>> = (0..3000u32).map(|i| i.to_string().into_bytes()).collect();
+        for item in &items {
+            snd.send(item);
+        }
+        snd.send(b"");
+        assert!(snd.metrics().disk_bytes > 0);
+
+        // Past the in-memory tier each record is a length and the bytes
+        let file = fs::read(dir.path().join("raw").join("0")).unwrap();
+        let mut framed = (items[1024].len() as u32).to_be_bytes().to_vec();
+        framed.extend_from_slice(&items[1024]);
+        assert!(file.windows(framed.len()).any(|w| w == &framed[..]));
+
+        let jh = thread::spawn(move || drop(snd));
+        let mut received = rcv.recv_batch(1000, Duration::from_millis(0));
+        while let Some(bytes) = rcv.recv() {
+            received.push(bytes);
+        }
+        jh.join().unwrap();
+        assert_eq!(items.len() + 1, received.len());
+        assert_eq!(&items[..], &received[..items.len()]);
+        assert_eq!(Vec::<u8>::new(), received[items.len()]);
+    }
+}
@@ -1,89 +1,613 @@
-use bincode::deserialize;
+use bincode::{self, deserialize};
+use block;
+use dead_letter::{self, DeadLetter};
+use dictionary::Dictionaries;
+use metrics::{Metrics, QueueMetrics};
+use layout::{self, RecordFraming};
+use prefetch::SegmentReader;
+use priority;
 use private;
+use reserve::GroupMetrics;
+use retention::{self, PurgeReport};
+use runtime::Runtime;
+use super::{CorruptionPolicy, DecodeError, QueueEvent, RecvError};
+use serde::Serialize;
 use serde::de::DeserializeOwned;
+use seek::{Replay, SeekTarget};
+use select::Select;
+use snapshot;
+use supervise::Health;
+use verify::IntegrityReport;
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
 use std::fs;
-use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Seek, SeekFrom};
 use std::iter::IntoIterator;
 use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::task::Waker;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use work::Worker;
+
+// The most items `Receiver::recv_ordered_by_sender` draws from the channel
+// ahead of handing them over.
+const MERGE_WINDOW: usize = 1024;
 
 #[inline]
-fn u8tou32abe(v: &[u8]) -> u32 {
+pub(crate) fn u8tou32abe(v: &[u8]) -> u32 {
     u32::from(v[3]) + (u32::from(v[2]) << 8) + (u32::from(v[1]) << 24) + (u32::from(v[0]) << 16)
 }
 
-#[derive(Debug)]
+// Take the first `len` bytes of `rest` off it, if `present` and it has that
+// many.
+fn take_head<'a>(present: bool, len: usize, rest: &mut &'a [u8]) -> Option<&'a [u8]> {
+    if present && rest.len() >= len {
+        let (head, tail) = rest.split_at(len);
+        *rest = tail;
+        Some(head)
+    } else {
+        None
+    }
+}
+
+// Split an on-disk record into its send time, present when the channel stamps
+// its items, its meta--the send time again, the id of the Sender that sent it,
+// present when the channel records provenance, and its place among that
+// Sender's items, present when the channel sequences its Senders--and the
+// encoding of its item.
+fn split_record(framing: RecordFraming, payload: &[u8]) -> (Option<u64>, RecordMeta, &[u8]) {
+    let mut rest = payload;
+    let stamp = take_head(framing.stamped, 8, &mut rest).map(|bytes| {
+        let mut stamp = [0; 8];
+        stamp.copy_from_slice(bytes);
+        u64::from_be_bytes(stamp)
+    });
+    let origin = take_head(framing.traced, 4, &mut rest).map(|bytes| {
+        let mut origin = [0; 4];
+        origin.copy_from_slice(bytes);
+        u32::from_be_bytes(origin)
+    });
+    let seq = take_head(framing.sequenced, 8, &mut rest).map(|bytes| {
+        let mut seq = [0; 8];
+        seq.copy_from_slice(bytes);
+        u64::from_be_bytes(seq)
+    });
+    (stamp, RecordMeta::new(stamp, origin, seq), rest)
+}
+
+// Why a record failed to decode, for `DecodeError::cause`
+type DecodeCause = Box<dyn std::error::Error + Send + Sync>;
+
+// The deadline of a soft close and the writer of the backlog left at it, see
+// `Receiver::close_after`
+type Drain<T> = (Instant, fn(&mut Receiver<T>));
+
+// Unwrap a receive, panicking on a record that could not be decoded as a
+// channel with `CorruptionPolicy::Abort` does outside `try_recv`.
+fn decoded<T>(value: Result<Option<T>, DecodeError>) -> Option<T> {
+    value.unwrap_or_else(|e| panic!("Failed decoding. {}", e))
+}
+
+// Decode an on-disk record into its send time, its meta and its item.
+fn decode_record<T>(
+    framing: RecordFraming,
+    payload: &[u8],
+) -> bincode::Result<(Option<u64>, RecordMeta, T)>
+where
+    T: DeserializeOwned,
+{
+    let (stamp, meta, item) = split_record(framing, payload);
+    deserialize(item).map(|event| (stamp, meta, event))
+}
+
 /// The 'receive' side of hopper, similar to
 /// [`std::sync::mpsc::Receiver`](https://doc.rust-lang.
 /// org/std/sync/mpsc/struct.Receiver.html).
+///
+/// A Receiver formats with `{:?}` and `{}` as a `Sender` does, its `Debug`
+/// adding its place in the queue file it is reading.
 pub struct Receiver<T> {
+    name: String,
     root: PathBuf,           // directory we store our queues in
-    fp: BufReader<fs::File>, // active fp
+    fp: SegmentReader,       // active fp
+    // The records left of the block last read from `fp`, if packed, and the
+    // length of the block's file record
+    unpacked: VecDeque<Vec<u8>>,
+    block_len: u64,
+    // The dictionaries blocks compressed with one are decompressed with
+    dictionaries: Dictionaries,
     fs_lock: private::FSLock<T>,
+    metrics: Arc<Metrics>,
+    // The high and low priority lanes, if the channel has them, and the count
+    // of receives used to schedule between lanes
+    lanes: Vec<Receiver<T>>,
+    turn: usize,
+    // Whether receives must check for the channel falling below its low
+    // watermarks
+    watermarked: bool,
+    // The predicate items must pass to be received, if filtered
+    filter: Option<Predicate<T>>,
+    // Events raised under the lock, for the observer once it is released
+    notices: Vec<QueueEvent>,
+    // The item a `Peeked` left at the head of the channel, if any
+    held: Option<(T, RecordMeta)>,
+    // The head of this lane, drawn ahead of its turn to learn its age under
+    // priority aging
+    head: Option<(T, RecordMeta)>,
+    // The items `recv_ordered_by_sender` has drawn from the channel and not
+    // yet handed over, by merge key and then by the order they were drawn in,
+    // and the count of items drawn
+    merging: BTreeMap<((u64, u32), u64), (T, RecordMeta)>,
+    drawn: u64,
+    // The item a `RecvRef` lends out, until it is dropped
+    lent: Option<T>,
+    // The read end of the pipe handed out by `readiness_fd`, if it has been
+    ready: Option<io::PipeReader>,
+    // Where and when the last item received was sent
+    meta: RecordMeta,
+    // The soft close the Receiver is draining to, if any, and whether its
+    // deadline has passed
+    drain: Option<Drain<T>>,
+    drained: bool,
+    // What checking the channel directory found as the channel was built, if
+    // it was checked
+    integrity: Option<IntegrityReport>,
+    // The items delivered already that `seek` rewound over, left to replay
+    replay: Option<Replay>,
     resource_type: PhantomData<T>,
 }
 
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Receiver")
+            .field("name", &self.name)
+            .field("root", &self.root)
+            .field("offset", &self.fp.offset())
+            .field("unpacked", &self.unpacked.len())
+            .field("in_memory", &self.metrics.in_memory_depth.load(Ordering::Relaxed))
+            .field("in_memory_capacity", &self.metrics.in_memory_capacity.load(Ordering::Relaxed))
+            .field("segments_on_disk", &self.metrics.segments_on_disk.load(Ordering::Relaxed))
+            .field("disk_bytes", &self.metrics.disk_bytes.load(Ordering::Relaxed))
+            .field("held", &(self.held.is_some() as usize + self.merging.len()))
+            .field("filtered", &self.filter.is_some())
+            .field("draining", &self.drain.map(|(deadline, _)| deadline))
+            .field("drained", &self.drained)
+            .field("meta", &self.meta)
+            .field("integrity", &self.integrity)
+            .field("replaying", &self.replay.is_some())
+            .field("lanes", &self.lanes)
+            .field("channel", &private::DebugState(&self.fs_lock))
+            .finish()
+    }
+}
+
+impl<T> fmt::Display for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.name, self.metrics.snapshot())
+    }
+}
+
+/// Where and when an item was sent, as returned by `Receiver::recv_with_meta`
+///
+/// `sender_id` is `None` unless the channel was built with
+/// `ChannelBuilder::record_provenance`. `enqueued` is `None` unless the
+/// channel stamps its items with their send time: it records provenance, has
+/// a TTL or priority aging or was built with
+/// `ChannelBuilder::stamp_send_times`.
+/// `sender_seq` is `None` unless the channel was built with
+/// `ChannelBuilder::sequence_senders`.
+/// Items sent with `Sender::send_after` carry none of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct RecordMeta {
+    /// The id of the Sender the item came from, see `Sender::id`
+    pub sender_id: Option<u32>,
+    /// The wall-clock time the item was handed to the channel, to the
+    /// millisecond
+    pub enqueued: Option<SystemTime>,
+    /// The item's place among the items its Sender handed to the channel,
+    /// counted from 0
+    pub sender_seq: Option<u64>,
+}
+
+impl RecordMeta {
+    fn new(stamp: Option<u64>, origin: Option<u32>, seq: Option<u64>) -> RecordMeta {
+        RecordMeta {
+            sender_id: origin,
+            enqueued: stamp.map(|millis| UNIX_EPOCH + Duration::from_millis(millis)),
+            sender_seq: seq,
+        }
+    }
+
+    // Where the item falls in the order `Receiver::recv_ordered_by_sender`
+    // hands items over in.
+    fn merge_key(&self) -> (u64, u32) {
+        (self.sender_seq.unwrap_or(0), self.sender_id.unwrap_or(0))
+    }
+}
+
+// The predicate of a `Filtered` Receiver, shared with its lanes.
+struct Predicate<T>(Arc<dyn Fn(&T) -> bool + Send + Sync>);
+
+impl<T> Clone for Predicate<T> {
+    fn clone(&self) -> Predicate<T> {
+        Predicate(Arc::clone(&self.0))
+    }
+}
+
+impl<T> fmt::Debug for Predicate<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Predicate")
+    }
+}
+
 impl<T> Receiver<T>
 where
     T: DeserializeOwned,
 {
     #[doc(hidden)]
-    pub fn new(data_dir: &Path, fs_lock: private::FSLock<T>) -> Result<Receiver<T>, super::Error> {
-        let _ = fs_lock.lock().expect("Sender fs_lock poisoned");
+    pub fn new(
+        data_dir: &Path,
+        fs_lock: private::FSLock<T>,
+        metrics: Arc<Metrics>,
+    ) -> Result<Receiver<T>, super::Error> {
+        let (observer, archive, watermarked, read_ahead, runtime, supervisor) = {
+            let syn = private::lock(&fs_lock);
+            let watermarked = syn.observer.is_some() && syn.watermarks.is_set();
+            (
+                syn.observer.clone(),
+                syn.archive.clone(),
+                watermarked,
+                syn.read_ahead,
+                syn.runtime.clone(),
+                syn.supervisor.clone(),
+            )
+        };
+        let archive = archive.as_deref();
         if !data_dir.is_dir() {
             return Err(super::Error::NoSuchDirectory);
         }
-        let seq_num = fs::read_dir(data_dir)
-            .unwrap()
-            .map(|de| {
-                de.unwrap()
-                    .path()
-                    .file_name()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .parse::<usize>()
-                    .unwrap()
-            })
-            .max()
-            .unwrap();
+        let name = data_dir
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let seq_num = private::segment_ids(data_dir, archive).into_iter().max().unwrap();
         // Remove all index files we've fast-forwarded over
         //
         // As the senders will restart with writes_to_read at 0, we're going to
         // have to make sure that receiver is on the same page with regard to
         // place on disk.
-        for fname in fs::read_dir(data_dir).unwrap() {
-            let path = fname.unwrap().path();
-            let id = path.file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .parse::<usize>()
-                .unwrap();
-            let full_path = data_dir.join(path.file_name().unwrap().to_str().unwrap());
+        let mut found = 0;
+        for id in private::segment_ids(data_dir, archive) {
+            let full_path = private::segment_path(data_dir, archive, id);
+            found += 1;
             if id != seq_num {
-                fs::remove_file(full_path).expect("could not remove index file");
+                private::remove_segment(&full_path).expect("could not remove index file");
+                trace_event!(channel = %name, segment = id, "recovery removed stale segment");
             }
         }
-        let log = data_dir.join(format!("{}", seq_num));
+        trace_event!(channel = %name, segment = seq_num, "recovery scan complete");
+        if let Some(observer) = observer {
+            observer(QueueEvent::RecoveredSegments { count: found });
+        }
+        let log = private::segment_path(data_dir, archive, seq_num);
         let mut fp = fs::OpenOptions::new()
             .read(true)
             .open(log)
             .expect("RECEIVER could not open file");
         fp.seek(SeekFrom::End(0))
             .expect("could not get to end of file");
+        let fp = SegmentReader::new(BufReader::new(fp), read_ahead, runtime.as_ref(), &supervisor)
+            .expect("could not start queue file read-ahead");
 
         Ok(Receiver {
+            name: name,
             root: data_dir.to_path_buf(),
-            fp: BufReader::new(fp),
+            fp: fp,
+            unpacked: VecDeque::new(),
+            block_len: 0,
+            dictionaries: Dictionaries::new(data_dir),
             resource_type: PhantomData,
             fs_lock: fs_lock,
+            metrics: metrics,
+            lanes: Vec::new(),
+            turn: 0,
+            watermarked: watermarked,
+            filter: None,
+            notices: Vec::new(),
+            held: None,
+            head: None,
+            merging: BTreeMap::new(),
+            drawn: 0,
+            lent: None,
+            ready: None,
+            meta: RecordMeta::default(),
+            drain: None,
+            drained: false,
+            integrity: None,
+            replay: None,
         })
     }
 
+    // Whether a soft close has passed its deadline, writing the backlog out
+    // as it does
+    fn drain_over(&mut self) -> bool {
+        if let Some((deadline, persist)) = self.drain {
+            if private::lock(&self.fs_lock).clock.now() >= deadline {
+                self.drain = None;
+                persist(self);
+                self.drained = true;
+            }
+        }
+        self.drained
+    }
+
     fn next_value(&mut self) -> Option<T> {
-        let mut sz_buf = [0; 4];
-        let mut syn = self.fs_lock.lock().expect("Receiver fs_lock was poisoned!");
+        decoded(self.try_next_value())
+    }
+
+    fn try_next_value(&mut self) -> Result<Option<T>, DecodeError> {
+        if self.drain_over() {
+            return Ok(None);
+        }
+        if self.replay.is_some() {
+            let fs_lock = Arc::clone(&self.fs_lock);
+            let mut syn = private::lock(&fs_lock);
+            if let Some(item) = self.next_replayed(&mut syn) {
+                return Ok(Some(item));
+            }
+        }
+        if let Some((item, meta)) = self.held.take() {
+            self.meta = meta;
+            return Ok(Some(item));
+        }
+        if let Some((_, (item, meta))) = self.merging.pop_first() {
+            self.meta = meta;
+            return Ok(Some(item));
+        }
+        self.try_fresh_value()
+    }
+
+    // Receive the next item from the channel itself, passing over any the
+    // Receiver holds.
+    fn try_fresh_value(&mut self) -> Result<Option<T>, DecodeError> {
+        if self.drain_over() {
+            return Ok(None);
+        }
+        #[cfg(feature = "histograms")]
+        let started = Instant::now();
+        let value = self.next_lane_value();
+        let received = value.as_ref().ok().and_then(Option::as_ref).is_some();
+        #[cfg(feature = "histograms")]
+        {
+            if received {
+                self.metrics.latency.recv.record_since(started);
+            }
+        }
+        if received {
+            self.received();
+        }
+        self.clear_ready();
+        self.notify();
+        value
+    }
+
+    // Receive into `batch` until it holds `max` items or nothing more is
+    // waiting, taking the lock once rather than once an item where the channel
+    // has no priority lanes.
+    fn drain_into(&mut self, batch: &mut Vec<T>, max: usize) {
+        #[cfg(feature = "histograms")]
+        let started = Instant::now();
+        if self.replay.is_some() {
+            let fs_lock = Arc::clone(&self.fs_lock);
+            let mut syn = private::lock(&fs_lock);
+            while batch.len() < max {
+                match self.next_replayed(&mut syn) {
+                    Some(item) => batch.push(item),
+                    None => break,
+                }
+            }
+        }
+        if batch.len() < max {
+            batch.extend(self.held.take().map(|(item, _)| item));
+        }
+        while batch.len() < max {
+            match self.merging.pop_first() {
+                Some((_, (item, _))) => batch.push(item),
+                None => break,
+            }
+        }
+        let before = batch.len();
+        if self.lanes.is_empty() {
+            let fs_lock = Arc::clone(&self.fs_lock);
+            let mut syn = private::lock(&fs_lock);
+            while batch.len() < max {
+                match decoded(self.next_locked(&mut syn)) {
+                    Some(item) => batch.push(item),
+                    None => break,
+                }
+            }
+            if batch.len() > before {
+                syn.drained();
+            }
+        } else {
+            while batch.len() < max {
+                match decoded(self.next_lane_value()) {
+                    Some(item) => batch.push(item),
+                    None => break,
+                }
+            }
+        }
+        if batch.len() > before {
+            #[cfg(feature = "histograms")]
+            self.metrics.latency.recv.record_since(started);
+            self.received();
+        }
+        self.clear_ready();
+        self.notify();
+    }
+
+    // Take the byte back out of the `readiness_fd` pipe for each channel, the
+    // Receiver's own and its lanes', with nothing left to deliver.
+    fn clear_ready(&self) {
+        if let Some(ref pipe) = self.ready {
+            for rcv in Some(self).into_iter().chain(self.lanes.iter()) {
+                private::lock(&rcv.fs_lock).clear_ready(pipe);
+            }
+        }
+    }
+
+    // Hand the events raised during a receive, by this Receiver and its
+    // lanes, to the observer.
+    fn notify(&mut self) {
+        let mut notices = Vec::new();
+        for lane in &mut self.lanes {
+            notices.append(&mut lane.notices);
+        }
+        notices.append(&mut self.notices);
+        if notices.is_empty() {
+            return;
+        }
+        let observer = private::lock(&self.fs_lock).observer.clone();
+        if let Some(observer) = observer {
+            for notice in notices {
+                observer(notice);
+            }
+        }
+    }
+
+    // Bookkeeping once a receive has returned items.
+    fn received(&self) {
+        self.check_watermarks();
+        for lane in &self.lanes {
+            lane.check_watermarks();
+        }
+    }
+
+    // Raise `LowWatermark` if a receive has taken the channel back under its
+    // low watermarks.
+    fn check_watermarks(&self) {
+        if !self.watermarked {
+            return;
+        }
+        let (event, observer) = {
+            let mut syn = private::lock(&self.fs_lock);
+            let disk_bytes = self.metrics.disk_bytes.load(Ordering::Relaxed);
+            let items = syn.writes_to_read;
+            (syn.watermarks.cross(items, disk_bytes), syn.observer.clone())
+        };
+        if let (Some(event), Some(observer)) = (event, observer) {
+            observer(event);
+        }
+    }
+
+    fn next_lane_value(&mut self) -> Result<Option<T>, DecodeError> {
+        if self.lanes.is_empty() {
+            return self.next_local();
+        }
+        let mut order = priority::lane_order(self.turn);
+        let aging = private::lock(&self.fs_lock).aging;
+        if let Some(after) = aging {
+            // A turn that gives a lower lane the first look keeps to it.
+            if order[0] == 0 {
+                order = self.aged_order(after)?;
+            }
+        }
+        for lane in &order {
+            let value = match *lane {
+                0 => {
+                    let value = self.lanes[0].next_head()?;
+                    self.meta = self.lanes[0].meta;
+                    value
+                }
+                1 => self.next_head()?,
+                _ => {
+                    let value = self.lanes[1].next_head()?;
+                    self.meta = self.lanes[1].meta;
+                    value
+                }
+            };
+            if value.is_some() {
+                self.turn = self.turn.wrapping_add(1);
+                return Ok(value);
+            }
+        }
+        Ok(None)
+    }
+
+    // The order to try the lanes in with their heads aged: by effective
+    // priority, the head that has waited longest first among equals.
+    fn aged_order(&mut self, after: Duration) -> Result<[usize; 3], DecodeError> {
+        let now_millis = private::lock(&self.fs_lock).now_millis();
+        let now = UNIX_EPOCH + Duration::from_millis(now_millis);
+        let waits = [
+            self.lanes[0].head_wait(now)?,
+            self.head_wait(now)?,
+            self.lanes[1].head_wait(now)?,
+        ];
+        let mut order = [0, 1, 2];
+        order.sort_by_key(|&lane| {
+            let wait = waits[lane];
+            let promoted = cmp::min(wait.as_nanos() / cmp::max(after.as_nanos(), 1), 2);
+            (lane - cmp::min(promoted as usize, lane), cmp::Reverse(wait))
+        });
+        Ok(order)
+    }
+
+    // How long the head of this lane has waited, drawing it ahead of its turn
+    // if it has not been already. An empty lane, or a head sent without a
+    // stamp, has waited no time at all.
+    fn head_wait(&mut self, now: SystemTime) -> Result<Duration, DecodeError> {
+        if self.head.is_none() {
+            let fs_lock = Arc::clone(&self.fs_lock);
+            let mut syn = private::lock(&fs_lock);
+            if let Some(item) = self.next_locked(&mut syn)? {
+                syn.drained();
+                syn.head_drawn = true;
+                self.head = Some((item, self.meta));
+            }
+        }
+        let enqueued = self.head.as_ref().and_then(|(_, meta)| meta.enqueued);
+        Ok(enqueued
+            .and_then(|at| now.duration_since(at).ok())
+            .unwrap_or_default())
+    }
+
+    // Receive the next item of this lane, its head first if drawn ahead.
+    fn next_head(&mut self) -> Result<Option<T>, DecodeError> {
+        if self.head.is_none() {
+            return self.next_local();
+        }
+        let mut syn = private::lock(&self.fs_lock);
+        if syn.paused {
+            return Ok(None);
+        }
+        syn.head_drawn = false;
+        let (item, meta) = self.head.take().expect("lane head drawn");
+        self.meta = meta;
+        Ok(Some(item))
+    }
+
+    fn next_local(&mut self) -> Result<Option<T>, DecodeError> {
+        let fs_lock = Arc::clone(&self.fs_lock);
+        let mut syn = private::lock(&fs_lock);
+        let value = self.next_locked(&mut syn);
+        if let Ok(Some(_)) = value {
+            syn.drained();
+        }
+        value
+    }
+
+    // Receive the next item of this lane with its lock already held.
+    fn next_locked(
+        &mut self,
+        fslock: &mut private::FsSync<T>,
+    ) -> Result<Option<T>, DecodeError> {
         // The receive loop
         //
         // The receiver works by regularly attempting to read a payload from its
@@ -94,8 +618,33 @@ where
         // this is a signal from the senders that the file is no longer being
         // written to. It's safe for the Receiver to declare the log done by
         // deleting it and moving on to the next file.
-        let fslock = &mut (*syn);
-
+        if fslock.paused {
+            return Ok(None);
+        }
+        while let Some(taken) = fslock.take_due() {
+            // A delayed item that cannot be read back or decoded is passed
+            // over, counted as a failure to decode.
+            let event = taken.ok().and_then(|mut payload| {
+                fslock
+                    .open_record(&mut payload)
+                    .and_then(|plain| deserialize::<T>(plain).ok())
+            });
+            let event = match event {
+                Some(event) => event,
+                None => {
+                    self.metrics
+                        .deserialize_failures
+                        .fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            };
+            if !self.passes(&event) {
+                continue;
+            }
+            self.metrics.dequeued(None);
+            self.meta = RecordMeta::default();
+            return Ok(Some(event));
+        }
         while fslock.writes_to_read > 0 {
             fslock.receiver_read_id = fslock.receiver_read_id.wrapping_add(1);
 
@@ -103,13 +652,62 @@ where
                 fslock.receiver_idx = Some(fslock.write_bound.expect("NO BOUND"));
             }
             if fslock.receiver_idx.unwrap() < fslock.in_memory_idx {
+                // Items paged out under memory pressure come ahead of those
+                // still held, see `paged`. One that cannot be read back or
+                // decoded is passed over, counted as a failure to decode.
+                if let Some(taken) = fslock.paged.as_mut().and_then(|paged| paged.take()) {
+                    fslock.writes_to_read -= 1;
+                    fslock.receiver_idx = fslock.receiver_idx.map(|x| x + 1);
+                    let framing = fslock.framing();
+                    let decoded = taken.ok().and_then(|mut payload| {
+                        let plain = fslock.open_record(&mut payload)?;
+                        let (_, meta, item) = split_record(framing, plain);
+                        let held = (meta.sender_id, item.len());
+                        decode_record::<T>(framing, plain).ok().map(|decoded| (decoded, held))
+                    });
+                    let ((stamp, meta, event), (origin, size)) = match decoded {
+                        Some(decoded) => decoded,
+                        None => {
+                            self.metrics
+                                .deserialize_failures
+                                .fetch_add(1, Ordering::Relaxed);
+                            continue;
+                        }
+                    };
+                    fslock.release_held(origin, Some(size));
+                    if fslock.expired(stamp) {
+                        self.metrics.total_expired.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                    if !self.passes(&event) {
+                        continue;
+                    }
+                    self.metrics.dequeued(fslock.waited(stamp));
+                    self.meta = meta;
+                    return Ok(Some(event));
+                }
                 let event = fslock
                     .mem_buffer
                     .pop_front()
                     .expect("there was not an event in the in-memory");
+                let stamp = fslock.mem_stamps.pop_front();
+                let origin = fslock.mem_origins.pop_front();
+                let seq = fslock.mem_seqs.pop_front();
+                let size = fslock.take_mem_size();
                 fslock.writes_to_read -= 1;
+                fslock.release_held(origin, size);
                 fslock.receiver_idx = fslock.receiver_idx.map(|x| x + 1);
-                return Some(event);
+                self.metrics.in_memory_depth.fetch_sub(1, Ordering::Relaxed);
+                if fslock.expired(stamp) {
+                    self.metrics.total_expired.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                if !self.passes(&event) {
+                    continue;
+                }
+                self.metrics.dequeued(fslock.waited(stamp));
+                self.meta = RecordMeta::new(stamp, origin, seq);
+                return Ok(Some(event));
             } else if (fslock.disk_writes_to_read == 0)
                 && (fslock.receiver_idx.unwrap() >= fslock.in_memory_idx)
             {
@@ -117,115 +715,1247 @@ where
                     .disk_buffer
                     .pop_front()
                     .expect("there was not an event in the disk buffer!");
+                let stamp = fslock.disk_stamps.pop_front();
+                let origin = fslock.disk_origins.pop_front();
+                let seq = fslock.disk_seqs.pop_front();
+                let size = fslock.take_disk_size();
                 fslock.writes_to_read -= 1;
+                fslock.release_held(origin, size);
                 fslock.receiver_idx = fslock.receiver_idx.map(|x| x + 1);
-                return Some(event);
+                self.metrics.in_memory_depth.fetch_sub(1, Ordering::Relaxed);
+                if fslock.expired(stamp) {
+                    self.metrics.total_expired.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                if !self.passes(&event) {
+                    continue;
+                }
+                self.metrics.dequeued(fslock.waited(stamp));
+                self.meta = RecordMeta::new(stamp, origin, seq);
+                return Ok(Some(event));
             } else {
-                match self.fp.read_exact(&mut sz_buf) {
-                    Ok(()) => {
-                        let payload_size_in_bytes = u8tou32abe(&sz_buf);
-                        let mut payload_buf = vec![0; (payload_size_in_bytes as usize)];
-                        match self.fp.read_exact(&mut payload_buf) {
-                            Ok(()) => match deserialize(&payload_buf) {
-                                Ok(event) => {
-                                    fslock.receiver_idx = fslock.receiver_idx.map(|x| x + 1);
-                                    fslock.writes_to_read -= 1;
-                                    fslock.disk_writes_to_read -= 1;
-                                    return Some(event);
+                match self.next_record(fslock.block_bytes > 0) {
+                    Ok(Some((mut payload_buf, record_len))) => {
+                        let framing = fslock.framing();
+                        // The Sender and size of the item, to give back to
+                        // its reservation group
+                        let mut held = (None, None);
+                        let opened = match fslock.open_record(&mut payload_buf) {
+                            Some(payload) => {
+                                if fslock.reservations.is_some() {
+                                    let (_, meta, item) = split_record(framing, payload);
+                                    held = (meta.sender_id, Some(item.len()));
                                 }
-                                Err(e) => panic!("Failed decoding. Skipping {:?}", e),
-                            },
-                            Err(e) => {
-                                panic!(
-                                    "Error, on-disk payload of advertised size not available! \
-                                     Recv failed with error {:?}",
-                                    e
-                                );
+                                decode_record(framing, payload).map_err(|e| {
+                                    let cause: DecodeCause = e;
+                                    (cause, payload.to_vec())
+                                })
                             }
-                        }
-                    }
-                    Err(e) => {
-                        match e.kind() {
-                            ErrorKind::UnexpectedEof => {
-                                // Okay, we're pretty sure that no one snuck data in
-                                // on us. We check the metadata condition of the
-                                // file and, if we find it read-only, switch on over
-                                // to a new log file.
-                                let metadata = self.fp
-                                    .get_ref()
-                                    .metadata()
-                                    .expect("could not get metadata at UnexpectedEof");
-                                if metadata.permissions().readonly() {
-                                    // TODO all these unwraps are a silent death
-                                    let seq_num = fs::read_dir(&self.root)
-                                        .unwrap()
-                                        .map(|de| {
-                                            de.unwrap()
-                                                .path()
-                                                .file_name()
-                                                .unwrap()
-                                                .to_str()
-                                                .unwrap()
-                                                .parse::<usize>()
-                                                .unwrap()
-                                        })
-                                        .min()
-                                        .unwrap();
-                                    let old_log = self.root.join(format!("{}", seq_num));
-                                    fs::remove_file(old_log).expect("could not remove log");
-                                    let lg = self.root.join(format!("{}", seq_num.wrapping_add(1)));
-                                    match fs::OpenOptions::new().read(true).open(&lg) {
-                                        Ok(fp) => {
-                                            self.fp = BufReader::new(fp);
-                                            continue;
+                            None => Err((
+                                io::Error::new(
+                                    ErrorKind::InvalidData,
+                                    "could not decrypt queue file record",
+                                ).into(),
+                                payload_buf.clone(),
+                            )),
+                        };
+                        let seq = fslock.receiver_idx.unwrap_or(0) as u64;
+                        fslock.receiver_idx = fslock.receiver_idx.map(|x| x + 1);
+                        fslock.writes_to_read -= 1;
+                        fslock.disk_writes_to_read -= 1;
+                        fslock.release_held(held.0, held.1);
+                        match opened {
+                            Ok((stamp, meta, event)) => {
+                                if fslock.expired(stamp) {
+                                    self.metrics.total_expired.fetch_add(1, Ordering::Relaxed);
+                                    continue;
+                                }
+                                if !self.passes(&event) {
+                                    continue;
+                                }
+                                self.metrics.dequeued(fslock.waited(stamp));
+                                self.meta = meta;
+                                return Ok(Some(event));
+                            }
+                            Err((cause, payload)) => {
+                                self.metrics
+                                    .deserialize_failures
+                                    .fetch_add(1, Ordering::Relaxed);
+                                let dead_lettered = match fslock.corruption {
+                                    CorruptionPolicy::Abort => {
+                                        let end = self.fp.position().unwrap_or(record_len);
+                                        let archive = fslock.archive.as_deref();
+                                        return Err(DecodeError {
+                                            channel: self.name.clone(),
+                                            segment: private::segment_ids(&self.root, archive)
+                                                .into_iter()
+                                                .min()
+                                                .unwrap_or(0),
+                                            offset: end.saturating_sub(record_len),
+                                            seq: seq,
+                                            cause: cause,
+                                        });
+                                    }
+                                    CorruptionPolicy::Skip => false,
+                                    CorruptionPolicy::DeadLetter(ref path) => {
+                                        let letter = DeadLetter {
+                                            seq: seq,
+                                            error: cause.to_string(),
+                                            payload: payload,
+                                        };
+                                        if let Err(e) = dead_letter::append(path, &letter) {
+                                            panic!("could not write dead letter {:?}", e);
                                         }
-                                        Err(e) => panic!("[Receiver] could not open {:?}", e),
+                                        self.metrics
+                                            .total_dead_lettered
+                                            .fetch_add(1, Ordering::Relaxed);
+                                        true
                                     }
+                                };
+                                trace_event!(
+                                    channel = %self.name,
+                                    seq = seq,
+                                    dead_lettered = dead_lettered,
+                                    "corrupt record passed over"
+                                );
+                                if fslock.observer.is_some() {
+                                    self.notices.push(QueueEvent::CorruptRecord {
+                                        seq: seq,
+                                        dead_lettered: dead_lettered,
+                                    });
                                 }
+                                continue;
+                            }
+                        }
+                    }
+                    Ok(None) => {
+                        // Okay, we're pretty sure that no one snuck data in
+                        // on us. We check the metadata condition of the
+                        // file and, if we find it read-only, switch on over
+                        // to a new log file.
+                        let metadata = self.fp
+                            .metadata()
+                            .expect("could not get metadata at end of queue file");
+                        if metadata.permissions().readonly() {
+                            // TODO all these unwraps are a silent death
+                            if let Some(ref archiver) = fslock.archiver {
+                                archiver.finish();
+                            }
+                            let archive = fslock.archive.as_deref();
+                            let mut ids = private::segment_ids(&self.root, archive);
+                            ids.sort();
+                            let seq_num = ids[0];
+                            let old_log =
+                                private::segment_path(&self.root, archive, seq_num);
+                            let old_len =
+                                fs::metadata(&old_log).map(|m| m.len()).unwrap_or(0);
+                            // The files after this one may have been evicted
+                            // at the disk quota, so the next is whichever
+                            // comes first.
+                            let next_seq = ids.get(1).cloned().unwrap_or(seq_num.wrapping_add(1));
+                            let lg = private::segment_path(&self.root, archive, next_seq);
+                            // Move onto the next file before removing
+                            // this one: Windows will not delete a file
+                            // that is still open.
+                            let mut next = match fs::OpenOptions::new().read(true).open(&lg) {
+                                Ok(fp) => BufReader::new(fp),
+                                Err(e) => panic!("[Receiver] could not open {:?}", e),
+                            };
+                            layout::skip_header(&mut next)
+                                .expect("could not read queue file header");
+                            // A purge may have cut the front from the file.
+                            if fslock.purge_skip.as_ref().is_some_and(|skip| skip.0 == lg) {
+                                let offset = fslock.purge_skip.take().map_or(0, |skip| skip.1);
+                                next.seek(SeekFrom::Start(offset))
+                                    .expect("could not seek past purged records");
                             }
-                            _ => {
-                                panic!("unable to cope");
+                            let reader = SegmentReader::new(
+                                next,
+                                fslock.read_ahead,
+                                fslock.runtime.as_ref(),
+                                &fslock.supervisor,
+                            );
+                            self.fp = reader.expect("could not start queue file read-ahead");
+                            let retention = fslock.retention;
+                            if retention.is_enabled() {
+                                retention::retain(&old_log)
+                                    .expect("could not retain log");
+                                let now = fslock.clock.wall();
+                                let lanes = fslock.retained_dirs(&self.root);
+                                retention::collect_lanes(&lanes, &retention, now);
+                                trace_event!(
+                                    channel = %self.name,
+                                    segment = seq_num,
+                                    bytes = old_len,
+                                    "segment retained"
+                                );
+                            } else {
+                                private::remove_segment(&old_log)
+                                    .expect("could not remove log");
+                                trace_event!(
+                                    channel = %self.name,
+                                    segment = seq_num,
+                                    bytes = old_len,
+                                    "segment deleted"
+                                );
                             }
+                            self.metrics.segments_on_disk.fetch_sub(1, Ordering::Relaxed);
+                            self.metrics.disk_bytes.fetch_sub(old_len, Ordering::Relaxed);
+                            continue;
                         }
                     }
+                    Err(e) => {
+                        panic!(
+                            "Error, on-disk payload of advertised size not available! \
+                             Recv failed with error {:?}",
+                            e
+                        );
+                    }
                 }
             }
         }
-        None
+        Ok(None)
+    }
+
+    // The next item `seek` rewound over, if any are left to replay. Records
+    // that cannot be read back or decoded are passed over and counted as
+    // failures to decode.
+    fn next_replayed(&mut self, fslock: &mut private::FsSync<T>) -> Option<T> {
+        if fslock.paused {
+            return None;
+        }
+        let framing = fslock.framing();
+        let packed = fslock.block_bytes > 0;
+        loop {
+            let next = {
+                let replay = self.replay.as_mut()?;
+                replay.next_record(packed, &mut self.dictionaries)
+            };
+            let (mut payload, seq) = match next {
+                Ok(Some(record)) => record,
+                Ok(None) => {
+                    self.replay = None;
+                    return None;
+                }
+                Err(_) => {
+                    self.metrics.deserialize_failures.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            };
+            let decoded = fslock
+                .open_record(&mut payload)
+                .and_then(|plain| decode_record::<T>(framing, plain).ok());
+            let (stamp, meta, event) = match decoded {
+                Some(decoded) => decoded,
+                None => {
+                    self.metrics.deserialize_failures.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            };
+            let reached = self.replay.as_mut().is_some_and(|replay| replay.reached(seq, stamp));
+            if !reached || !self.passes(&event) {
+                continue;
+            }
+            self.meta = meta;
+            return Some(event);
+        }
+    }
+
+    // The next record's payload from the queue file, with the length of the
+    // file record it came from: a block's, if `packed`.
+    fn next_record(&mut self, packed: bool) -> io::Result<Option<(Vec<u8>, u64)>> {
+        if !packed {
+            return Ok(self.fp.next_record()?.map(|payload| {
+                let len = payload.len() as u64 + 4;
+                (payload, len)
+            }));
+        }
+        loop {
+            if let Some(payload) = self.unpacked.pop_front() {
+                return Ok(Some((payload, self.block_len)));
+            }
+            match self.fp.next_record()? {
+                Some(block) => {
+                    self.unpacked = block::unpack(&block, &mut self.dictionaries)?;
+                    self.block_len = block.len() as u64 + 4;
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+
+    // Whether `event` passes the Receiver's filter, counting it if not.
+    fn passes(&self, event: &T) -> bool {
+        match self.filter {
+            Some(ref filter) if !(filter.0)(event) => {
+                self.metrics.total_filtered.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Register a waker to be notified when the queue has data
+    ///
+    /// The waker is woken--once--the next time a Sender moves the queue from
+    /// empty to non-empty. If the queue already holds items when
+    /// `register_waker` is called the waker is woken immediately. Wakers are
+    /// not retained after they fire: re-register after each wake-up to keep
+    /// receiving notifications. This is the hook external executors and select
+    /// implementations should use to learn of new data.
+    ///
+    /// On a channel built with `ChannelBuilder::coalesce_wakes` a send may
+    /// hold the wake back while the Receiver is draining, see there.
+    pub fn register_waker(&mut self, waker: Waker) {
+        if self.held.is_some() || !self.merging.is_empty() {
+            waker.wake();
+            return;
+        }
+        for lane in &mut self.lanes {
+            lane.register_waker(waker.clone());
+        }
+        let ready = private::lock(&self.fs_lock).register_waker(waker);
+        if let Some(waker) = ready {
+            waker.wake();
+        }
+    }
+
+    /// A file descriptor that is readable while the Receiver has items
+    ///
+    /// For waiting on the Receiver with epoll, kqueue or an event loop built
+    /// on them, such as mio through its `SourceFd`, alongside sockets and
+    /// without a thread blocked in `recv`. The descriptor is the read end of a
+    /// pipe a byte is written to whenever the channel goes from empty to
+    /// non-empty, items paged to disk included, and on `resume` with items
+    /// waiting. The Receiver reads the byte back as a receive empties the
+    /// channel, or finds it empty, so the descriptor stays readable exactly
+    /// as long as items wait. Never read the descriptor directly. Items sent
+    /// with `Sender::send_after` make it readable as they come due. The pipe
+    /// is opened on the first call and closed with the Receiver. With the
+    /// `mio` feature the Receiver is itself a `mio::event::Source`.
+    #[cfg(unix)]
+    pub fn readiness_fd(&mut self) -> io::Result<RawFd> {
+        if let Some(ref pipe) = self.ready {
+            return Ok(pipe.as_raw_fd());
+        }
+        let (reader, writer) = io::pipe()?;
+        let writer = Arc::new(writer);
+        for rcv in Some(&*self).into_iter().chain(self.lanes.iter()) {
+            let mut syn = private::lock(&rcv.fs_lock);
+            syn.ready_pipe = Some(Arc::clone(&writer));
+            if syn.is_ready() {
+                syn.signal_ready();
+            }
+        }
+        let fd = reader.as_raw_fd();
+        self.ready = Some(reader);
+        Ok(fd)
+    }
+
+    /// Stop the channel accepting new items
+    ///
+    /// Everything sent before the close, in memory or on disk, is still
+    /// delivered, so a consumer shutting down can close the channel and then
+    /// drain it until it is empty. Items sent afterwards are discarded--
+    /// `Sender::try_send` refuses them with `SendError::Closed`--and
+    /// `Sender::is_closed` tells producers to stop. Items still staged inside
+    /// an `OrderMode::PerSender` Sender have not reached the channel and are
+    /// discarded too: flush Senders before closing to keep them.
+    pub fn close(&mut self) {
+        {
+            let mut syn = private::lock(&self.fs_lock);
+            syn.closed = true;
+            syn.room_made();
+        }
+        for lane in &mut self.lanes {
+            lane.close();
+        }
+    }
+
+    /// Stop delivering items until `resume` is called
+    ///
+    /// While paused the Receiver reports the channel empty, `Select` and
+    /// registered wakers see nothing ready and the channel goes on accepting
+    /// items, paging them to disk as its memory fills, so a backlog built up
+    /// during maintenance is held rather than consumed. Raises
+    /// `QueueEvent::Paused`.
+    pub fn pause(&mut self) {
+        self.set_paused(true);
+    }
+
+    /// Deliver items again after `pause`
+    ///
+    /// Every waker registered on the Receiver, and so any `Select` blocked on
+    /// it, is woken whether or not items are waiting, and
+    /// `QueueEvent::Resumed` is raised with the size of the backlog. Does
+    /// nothing if the Receiver is not paused.
+    pub fn resume(&mut self) {
+        self.set_paused(false);
+    }
+
+    /// Whether the Receiver has been paused
+    pub fn is_paused(&self) -> bool {
+        private::lock(&self.fs_lock).paused
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        let mut wakers = Vec::new();
+        let mut items = 0;
+        for rcv in Some(&*self).into_iter().chain(self.lanes.iter()) {
+            let mut syn = private::lock(&rcv.fs_lock);
+            if syn.paused == paused {
+                return;
+            }
+            syn.paused = paused;
+            if !paused {
+                wakers.append(&mut syn.wakers);
+                syn.deferred_wake = None;
+                if syn.is_ready() {
+                    syn.signal_ready();
+                }
+            }
+            items += syn.writes_to_read + rcv.head.iter().count();
+        }
+        for waker in wakers {
+            waker.wake();
+        }
+        let observer = private::lock(&self.fs_lock).observer.clone();
+        if let Some(observer) = observer {
+            observer(if paused {
+                QueueEvent::Paused
+            } else {
+                QueueEvent::Resumed { items: items }
+            });
+        }
+    }
+
+    /// Return the name of the channel this Receiver reads from
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return a snapshot of the channel's metrics
+    pub fn metrics(&self) -> QueueMetrics {
+        self.metrics.snapshot()
+    }
+
+    /// Return a snapshot of the metrics of the reservation group `group`, as
+    /// `Sender::group_metrics`
+    pub fn group_metrics(&self, group: &str) -> Option<GroupMetrics> {
+        Some(self).into_iter().chain(self.lanes.iter()).fold(None, |acc, rcv| {
+            let metrics = private::lock(&rcv.fs_lock)
+                .reservations
+                .as_ref()
+                .and_then(|reservations| reservations.metrics(group));
+            match (acc, metrics) {
+                (Some(acc), Some(lane)) => Some(acc.merge(lane)),
+                (acc, lane) => acc.or(lane),
+            }
+        })
+    }
+
+    /// What checking the channel directory found as the channel was built,
+    /// if it was built with `ChannelBuilder::verify_on_open`
+    ///
+    /// The directories of the channel's priority lanes are checked, and
+    /// repaired, alike but not reported on here.
+    pub fn integrity_report(&self) -> Option<&IntegrityReport> {
+        self.integrity.as_ref()
+    }
+
+    pub(crate) fn set_integrity(&mut self, report: IntegrityReport) {
+        self.integrity = Some(report);
+    }
+
+    /// Whether the channel is in working order, as `Sender::health`
+    pub fn health(&self) -> Health {
+        private::health(&self.fs_lock, &self.root)
+    }
+
+    pub(crate) fn set_lanes(&mut self, high: Receiver<T>, low: Receiver<T>) {
+        self.lanes = vec![high, low];
+    }
+
+    pub(crate) fn lanes(&self) -> &[Receiver<T>] {
+        &self.lanes
+    }
+
+    pub(crate) fn fs_lock(&self) -> &private::FSLock<T> {
+        &self.fs_lock
+    }
+
+    /// Receive up to `max` items, waiting up to `timeout` for them to arrive
+    ///
+    /// Returns once `max` items have been received or `timeout` has passed,
+    /// with whatever arrived by then, which may be nothing. Items already
+    /// waiting, in memory or in sequence on disk, are taken in one pass under
+    /// a single acquisition of the channel's lock. While the batch is short
+    /// the call blocks as `Select` does, waking as items are sent, or on a
+    /// `sim::Simulation` runs its jobs as `Select` does.
+    pub fn recv_batch(&mut self, max: usize, timeout: Duration) -> Vec<T>
+    where
+        T: Send + 'static,
+    {
+        // A channel on a simulation waits by the simulation's clock.
+        let simulation = private::lock(&self.fs_lock).simulation();
+        let now = || simulation.as_ref().map_or_else(Instant::now, Runtime::now);
+        let deadline = now() + timeout;
+        let mut batch = Vec::with_capacity(cmp::min(max, 1024));
+        let mut sel: Option<Select> = None;
+        loop {
+            self.drain_into(&mut batch, max);
+            let now = now();
+            if batch.len() >= max || now >= deadline {
+                return batch;
+            }
+            if sel.is_none() {
+                let mut watch = Select::new();
+                watch.add(self);
+                sel = Some(watch);
+            }
+            if let Some(ref mut sel) = sel {
+                sel.ready_timeout(deadline - now);
+            }
+        }
+    }
+
+    /// Take every item waiting on the channel, without waiting for more
+    ///
+    /// As `drain_to`, into a new Vec.
+    pub fn drain(&mut self) -> Vec<T> {
+        let mut items = Vec::new();
+        self.drain_to(&mut items);
+        items
+    }
+
+    /// Take every item waiting on the channel into `items`, without waiting
+    /// for more, returning how many were taken
+    ///
+    /// Takes the items waiting when called, in memory and in sequence on
+    /// disk, as one `recv_batch` pass does. Items sent meanwhile are left for
+    /// the next receive, so a drain ends even while Senders keep sending.
+    /// The channel is left open and usable. Items delayed and not yet due,
+    /// and any waiting on a paused channel, are not taken.
+    pub fn drain_to(&mut self, items: &mut Vec<T>) -> usize {
+        let before = items.len();
+        let waiting = self.held.iter().count() + self.merging.len()
+            + Some(&*self)
+                .into_iter()
+                .chain(self.lanes.iter())
+                .map(|rcv| {
+                    rcv.head.iter().count() + private::lock(&rcv.fs_lock).writes_to_read
+                })
+                .sum::<usize>();
+        self.drain_into(items, before + waiting);
+        items.len() - before
+    }
+
+    /// Append up to `max` of the items waiting on the channel to `items`,
+    /// without waiting for more, returning how many were appended
+    ///
+    /// For a consumer that reuses its own buffer from one pass to the next:
+    /// the items are taken as one `recv_batch` pass takes them, under a
+    /// single acquisition of the channel's lock where it has no priority
+    /// lanes, but nothing is allocated on the way but what reading from disk
+    /// needs. Items held in memory are moved into `items` as they are, and
+    /// `items` grows only if it is short of capacity for them, so clearing
+    /// and refilling a buffer of `max` capacity never allocates.
+    pub fn recv_many_into(&mut self, items: &mut Vec<T>, max: usize) -> usize {
+        let before = items.len();
+        self.drain_into(items, before.saturating_add(max));
+        items.len() - before
+    }
+
+    /// Receive only the items for which `predicate` returns true
+    ///
+    /// Items the predicate rejects are dropped as the Receiver comes to them
+    /// and counted in `QueueMetrics::total_filtered`, not
+    /// `QueueMetrics::total_dequeued`. They are dropped inside the receive,
+    /// with the channel locked, so a batch from `Filtered::recv_batch` is
+    /// made up of matching items only and no rejected item reaches the
+    /// caller. Items read from disk are decoded in full before the
+    /// predicate sees them: bincode cannot decode part of an item. The
+    /// predicate must not use the channel itself. The lanes of a channel with
+    /// `priority_lanes` are filtered alike.
+    pub fn filtered<F>(mut self, predicate: F) -> Filtered<T>
+    where
+        F: Fn(&T) -> bool + Send + Sync + 'static,
+    {
+        self.set_filter(Some(Predicate(Arc::new(predicate))));
+        Filtered { rx: self }
+    }
+
+    /// Convert every item received with `f`
+    ///
+    /// The returned adapter receives as this Receiver does--one at a time
+    /// through `Mapped::iter` or in batches through `Mapped::recv_batch`--and
+    /// hands back `f` of each item, so downstream code sees the target type
+    /// directly. `f` runs once the channel's lock has been released.
+    pub fn map<U, F>(self, f: F) -> Mapped<T, F>
+    where
+        F: FnMut(T) -> U,
+    {
+        Mapped { rx: self, f: f }
+    }
+
+    /// Share this Receiver among competing consumers
+    ///
+    /// Each clone of the returned `Worker` takes items for itself, in no
+    /// particular order, as `Lease`s to acknowledge once handled. See
+    /// `Worker`.
+    pub fn into_worker(self) -> Worker<T> {
+        Worker::new(self)
+    }
+
+    fn set_filter(&mut self, filter: Option<Predicate<T>>) {
+        for lane in &mut self.lanes {
+            lane.filter = filter.clone();
+        }
+        self.filter = filter;
+    }
+
+    /// An iterator over the items of the channel, waiting on each until the
+    /// channel hangs up
+    ///
+    /// As `std::sync::mpsc::Receiver::iter`, this blocks while the channel is
+    /// empty and ends once it has hung up and every item is received. See
+    /// `is_hung_up`. Use `try_iter` to take only the items already waiting.
+    pub fn iter(&mut self) -> Iter<'_, T>
+    where
+        T: Send + 'static,
+    {
+        Iter { rx: self, sel: None }
     }
 
+    /// An iterator over the items waiting on the channel, ending once none is
+    ///
+    /// Does not block: it takes the items held in memory and those already
+    /// readable on disk, and ends where `try_recv` would return `Ok(None)`.
+    pub fn try_iter(&mut self) -> TryIter<'_, T> {
+        TryIter { rx: self }
+    }
+
+    /// Whether every Sender of the channel has been dropped, or the channel
+    /// closed
+    ///
+    /// The Senders hopper holds itself, as a flusher's or a memory pressure
+    /// watcher's, do not count. Once the channel has hung up, no more items
+    /// arrive: those waiting are all there will be.
+    pub fn is_hung_up(&self) -> bool {
+        private::lock(&self.fs_lock).hung_up() && self.lanes.iter().all(Receiver::is_hung_up)
+    }
+
+    /// The number of the channel's Senders alive, clones included
+    ///
+    /// The Senders hopper holds itself, as a flusher's or a memory pressure
+    /// watcher's, and any `WeakSender`s are not counted.
+    pub fn sender_count(&self) -> usize {
+        private::lock(&self.fs_lock).live_senders()
+    }
+
+    /// Receive the next item, if one is waiting, reporting a record that
+    /// cannot be decoded rather than panicking
+    ///
+    /// Returns `Ok(None)` where `try_iter` would end. Where the channel's
+    /// `CorruptionPolicy` is `CorruptionPolicy::Abort` and the next queue
+    /// file record cannot be decrypted or decoded, returns
+    /// `RecvError::Decode` with the record's queue file, offset and place in
+    /// the channel, which `hopper-inspect` can be pointed at. The record is
+    /// counted in `QueueMetrics::deserialize_failures` and passed over: the
+    /// next call carries on with the record after it.
+    pub fn try_recv(&mut self) -> Result<Option<T>, RecvError> {
+        self.try_next_value().map_err(RecvError::Decode)
+    }
+
+    /// Receive the next item, if one is waiting, with the id of its Sender and
+    /// the time it was sent
+    ///
+    /// Returns `None` where `try_iter` would end. See `RecordMeta` for when
+    /// the channel has them to give.
+    pub fn recv_with_meta(&mut self) -> Option<(T, RecordMeta)> {
+        let item = self.next_value()?;
+        Some((item, self.meta))
+    }
+
+    /// Receive the next item, if one is waiting, merging the items of the
+    /// channel's Senders by their place among their own Sender's items
+    ///
+    /// Meant for a channel built with `ChannelBuilder::sequence_senders`. The
+    /// Receiver draws the items waiting, up to 1,024 at a time, and hands over
+    /// the first of them by `RecordMeta::sender_seq` and then by
+    /// `RecordMeta::sender_id`: the first item of each Sender in turn, then
+    /// the second of each and so on. Each Sender's items still arrive in the
+    /// order it sent them. The order so depends only on what each Sender sent,
+    /// not on how their sends interleaved, so long as the items merged were
+    /// all waiting when drawn: a channel replayed after its Senders have
+    /// finished is merged the same way each time. Items lacking a place are
+    /// taken as the first of their Sender's, those lacking a Sender as from
+    /// Sender 0, and ties go in channel order.
+    ///
+    /// Items drawn and not yet handed over are held by the Receiver, and any
+    /// other receive hands them over first, in merge order. Returns `None`
+    /// where `try_iter` would end.
+    pub fn recv_ordered_by_sender(&mut self) -> Option<(T, RecordMeta)> {
+        if self.drain_over() {
+            return None;
+        }
+        if let Some((item, meta)) = self.held.take() {
+            self.merge(item, meta);
+        }
+        while self.merging.len() < MERGE_WINDOW {
+            match decoded(self.try_fresh_value()) {
+                Some(item) => {
+                    let meta = self.meta;
+                    self.merge(item, meta);
+                }
+                None => break,
+            }
+        }
+        let (_, (item, meta)) = self.merging.pop_first()?;
+        self.meta = meta;
+        Some((item, meta))
+    }
+
+    // Hold `item` for `recv_ordered_by_sender` to hand over in merge order.
+    fn merge(&mut self, item: T, meta: RecordMeta) {
+        self.merging.insert((meta.merge_key(), self.drawn), (item, meta));
+        self.drawn = self.drawn.wrapping_add(1);
+    }
+
+    /// How long ago the last item received was sent
+    ///
+    /// A measure of how far behind its Senders the Receiver is running: of how
+    /// stale the data it is handing over is. `None` until an item is received,
+    /// and where the channel does not stamp its items, see
+    /// `RecordMeta::enqueued`. The percentiles of the same figure over every
+    /// item received are in `QueueMetrics::time_in_queue`.
+    pub fn last_record_age(&self) -> Option<Duration> {
+        let enqueued = self.meta.enqueued?;
+        let now = private::lock(&self.fs_lock).clock.wall();
+        Some(now.duration_since(enqueued).unwrap_or_default())
+    }
+
+    /// Receive the next item, lending it out rather than handing it over
+    ///
+    /// The item stays with the Receiver, which the returned `RecvRef`
+    /// borrows, and is dropped along with the guard. A consumer that only
+    /// reads its items so never moves them out, however large. Returns None
+    /// where `try_iter` would end.
+    pub fn recv_ref(&mut self) -> Option<RecvRef<'_, T>> {
+        let item = self.next_value()?;
+        self.lent = Some(item);
+        Some(RecvRef { rx: self })
+    }
 
-    /// An iterator over messages on a receiver, this iterator will block
-    /// whenever `next` is called, waiting for a new message, and `None` will be
-    /// returned when the corresponding channel has hung up.
-    pub fn iter(&mut self) -> Iter<T> {
-        Iter { rx: self }
+    /// Receive the next item, to be given back to the channel once finished
+    /// with
+    ///
+    /// Dropping the `Recycled` hands the item back as a slot for a later
+    /// `Sender::send_with` to fill in again. The channel keeps as many slots
+    /// as its in-memory tier holds items and drops any beyond that. Slots are
+    /// not counted against a memory budget. Returns None where `try_iter`
+    /// would end.
+    pub fn recv_recycled(&mut self) -> Option<Recycled<'_, T>> {
+        let item = self.next_value()?;
+        Some(Recycled {
+            rx: self,
+            item: Some(item),
+        })
+    }
+
+    /// Look at the next item, if one is waiting, without yet removing it
+    ///
+    /// `Peeked::commit` removes the item and hands it over. `Peeked::abort`,
+    /// or dropping the `Peeked`, leaves it at the head of the channel for the
+    /// next `peek` or receive. A consumer forwarding items elsewhere can so
+    /// commit only those it forwarded. An item left is held by the Receiver
+    /// rather than the channel--it wakes a waker registered with
+    /// `register_waker` but not a `Select`--and is counted in
+    /// `QueueMetrics::total_dequeued` once, when first peeked. Returns None
+    /// where `try_iter` would end.
+    pub fn peek(&mut self) -> Option<Peeked<'_, T>> {
+        let item = self.next_value()?;
+        Some(Peeked {
+            rx: self,
+            item: Some(item),
+        })
     }
 }
 
+impl<T, E> Receiver<Result<T, E>>
+where
+    T: DeserializeOwned,
+    E: DeserializeOwned,
+{
+    /// Receive up to `max` of the items waiting on the channel, without
+    /// waiting for more, appending the data to `data` and the errors sent by
+    /// `Sender::send_err` to `errors`, returning how many were received
+    ///
+    /// Each error is appended with the length `data` had when it arrived, so
+    /// its place among the data is kept: an error recorded at `n` was sent
+    /// after the item at `data[n - 1]` and before the one at `data[n]`.
+    pub fn recv_split_into(
+        &mut self,
+        data: &mut Vec<T>,
+        errors: &mut Vec<(usize, E)>,
+        max: usize,
+    ) -> usize {
+        let mut received = 0;
+        while received < max {
+            match self.next_value() {
+                Some(Ok(item)) => data.push(item),
+                Some(Err(err)) => errors.push((data.len(), err)),
+                None => break,
+            }
+            received += 1;
+        }
+        received
+    }
+}
+
+impl<T> Receiver<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    /// Stop the channel accepting new items and go on delivering those
+    /// already sent for up to `drain_for`, leaving the rest for the channel's
+    /// next build
+    ///
+    /// Sends are refused from now on, as with `close`. Receives carry on as
+    /// ever until `drain_for` has passed on the channel's clock. The first
+    /// receive after that writes the items still waiting, in memory or on
+    /// disk, to a snapshot in the channel's directory--see `export_snapshot`
+    /// for what it leaves out--and it and every receive after it reports the
+    /// channel empty. A Receiver dropped before the deadline writes its
+    /// backlog out as it drops. The next `ChannelBuilder::build` of the
+    /// channel sends the snapshot's items ahead of any `restore_from` and then
+    /// removes it. Each priority lane keeps a snapshot of its own.
+    pub fn close_after(&mut self, drain_for: Duration) {
+        let deadline = {
+            let mut syn = private::lock(&self.fs_lock);
+            syn.closed = true;
+            syn.clock.now() + drain_for
+        };
+        self.drain = Some((deadline, persist_backlog::<T>));
+        for lane in &mut self.lanes {
+            lane.close_after(drain_for);
+        }
+    }
+
+    /// Rewind to `target`, to deliver again items already delivered
+    ///
+    /// For a channel built with `ChannelBuilder::keep_for` or
+    /// `ChannelBuilder::keep_bytes`, which keeps the queue files the Receiver
+    /// has read past. The Receiver replays the items of those files still
+    /// retained, from the first at or past `target`, then those it has read
+    /// from the queue file it is partway through, and then carries on from
+    /// where it was before the seek. A later seek starts the replay over.
+    /// Only items paged out to disk are replayed: those delivered from memory
+    /// were never written to a queue file. The items of a priority lane are
+    /// not replayed, nor is an item that failed to decode. An item
+    /// left at the head by `peek`, or drawn ahead by `recv_ordered_by_sender`
+    /// or priority aging, is delivered after the replay, and may be in it too
+    /// if it came from disk. Nothing is read until the replay reaches it.
+    ///
+    /// Fails with an error of kind `InvalidInput` if the channel retains no
+    /// queue files, or `target` is a `SeekTarget::Timestamp` and the channel
+    /// does not stamp its items, and with the error of reading the queue file
+    /// the Receiver is in.
+    pub fn seek(&mut self, target: SeekTarget) -> io::Result<()> {
+        let (retains, stamped, archive) = {
+            let syn = private::lock(&self.fs_lock);
+            (syn.retention.is_enabled(), syn.stamped(), syn.archive.clone())
+        };
+        let invalid = |msg| Err(io::Error::new(ErrorKind::InvalidInput, msg));
+        if !retains {
+            return invalid("channel does not retain its queue files");
+        }
+        if let SeekTarget::Timestamp(_) = target {
+            if !stamped {
+                return invalid("channel does not stamp its items with their send time");
+            }
+        }
+        let archive = archive.as_deref();
+        let current = private::segment_ids(&self.root, archive)
+            .into_iter()
+            .min()
+            .map(|id| private::segment_path(&self.root, archive, id));
+        let current = match current {
+            Some(path) => Some((path, self.fp.position()?, self.unpacked.len())),
+            None => None,
+        };
+        self.replay = Some(Replay::new(target, &self.root, archive, current));
+        Ok(())
+    }
+
+    /// Delete the items sent before `cutoff` that are still to be received,
+    /// reporting what was deleted
+    ///
+    /// Queue files the Receiver has yet to reach go whole, oldest first, up
+    /// to the first holding an item sent since, aged by their summaries where
+    /// they have them and else by when they were last written to. That file
+    /// loses the records its index shows were sent before `cutoff`, which
+    /// the Receiver passes over without reading. Files kept by `keep_for` or
+    /// `keep_bytes` with nothing sent since go whole too. If the
+    /// channel stamps its items--see `RecordMeta::enqueued`--the items sent
+    /// before `cutoff` that are held in memory or in the file being read are
+    /// dropped as the Receiver reaches them, counted as expired. The report
+    /// counts them as pending. Otherwise they are delivered. The channel's
+    /// priority lanes are purged alike.
+    pub fn purge_before(&mut self, cutoff: SystemTime) -> PurgeReport {
+        let millis = cutoff
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        let mut report = {
+            let mut syn = private::lock(&self.fs_lock);
+            let (segments, records, bytes) = syn.purge_before(&self.root, millis, &self.metrics);
+            let pending = if syn.stamped() { syn.held_before(millis) } else { 0 };
+            let (kept_segments, kept_records, kept_bytes) =
+                retention::purge_before(&self.root, syn.archive.as_deref(), cutoff);
+            PurgeReport {
+                segments: segments + kept_segments,
+                records: records + kept_records,
+                bytes: bytes + kept_bytes,
+                pending: pending,
+            }
+        };
+        for lane in &mut self.lanes {
+            report.add(&lane.purge_before(cutoff));
+        }
+        report
+    }
+
+    /// Write the items waiting in the channel to a snapshot file at `path`,
+    /// returning the number written
+    ///
+    /// The snapshot holds the items this Receiver would deliver next, in
+    /// order, whether held in memory or on disk at the time of the call. See
+    /// `snapshot` for the format. Nothing is consumed: the Receiver carries on
+    /// from where it was. Senders wait only while the items held in memory
+    /// are encoded, not while queue files are read. The file is written
+    /// alongside `path` and moved into place once complete. Expired items are
+    /// left out, as are items sent with `Sender::send_after` and items on
+    /// priority lanes.
+    pub fn export_snapshot(&mut self, path: &Path) -> io::Result<u64> {
+        // The backlog runs through the in-memory tier, paged out first, the
+        // queue files from the Receiver's place onwards and then the disk
+        // buffer.
+        let (paged, memory, buffered, disk_records, ttl, now, floor, framing, archive) = {
+            let mut syn = private::lock(&self.fs_lock);
+            let paged = match syn.paged {
+                Some(ref mut paged) => paged.waiting()?,
+                None => Vec::new(),
+            };
+            let now = syn.now_millis();
+            let expired = |stamp| syn.expired(stamp);
+            let memory = encode_live(&expired, &syn.mem_buffer, &syn.mem_stamps);
+            let buffered = encode_live(&expired, &syn.disk_buffer, &syn.disk_stamps);
+            (
+                paged,
+                memory,
+                buffered,
+                syn.disk_writes_to_read,
+                syn.ttl,
+                now,
+                syn.purge_floor,
+                syn.framing(),
+                syn.archive.clone(),
+            )
+        };
+        let archive = archive.as_deref();
+        let mut offset = Some(self.fp.position()? as usize);
+
+        let tmp = path.with_extension("partial");
+        let out = BufWriter::new(fs::File::create(&tmp)?);
+        let mut writer = snapshot::Writer::new(out, ::std::any::type_name::<T>())?;
+        // An item left by a `Peeked` comes first, then those drawn ahead by
+        // `recv_ordered_by_sender` and the head drawn ahead by priority aging.
+        let held = self.held.iter().chain(self.merging.values()).chain(self.head.iter());
+        for (held, _) in held {
+            let item = bincode::serialize(held, bincode::Infinite).expect("could not serialize");
+            writer.record(&item)?;
+        }
+        // The records of a block the Receiver is partway through come next,
+        // then the blocks after it, if the channel packs its records.
+        let fs_lock = &self.fs_lock;
+        let packed = private::lock(fs_lock).block_bytes > 0;
+        let write_record = |writer: &mut snapshot::Writer<_>, mut record: Vec<u8>| {
+            let plain = match private::lock(fs_lock).open_record(&mut record) {
+                Some(plain) => plain,
+                None => {
+                    let msg = "could not decrypt queue file record";
+                    return Err(io::Error::new(ErrorKind::InvalidData, msg));
+                }
+            };
+            let (stamp, _, item) = split_record(framing, plain);
+            let purged = stamp.is_some_and(|stamp| stamp < floor);
+            if !private::is_expired(ttl, stamp, now) && !purged {
+                writer.record(item)?;
+            }
+            Ok(())
+        };
+        for record in paged {
+            write_record(&mut writer, record)?;
+        }
+        for item in &memory {
+            writer.record(item)?;
+        }
+        let mut remaining = disk_records;
+        for record in self.unpacked.iter().cloned() {
+            if remaining == 0 {
+                break;
+            }
+            write_record(&mut writer, record)?;
+            remaining -= 1;
+        }
+        let mut ids = private::segment_ids(&self.root, archive);
+        ids.sort();
+        for id in ids {
+            if remaining == 0 {
+                break;
+            }
+            let buf = fs::read(private::segment_path(&self.root, archive, id))?;
+            // The Receiver is already past the header of the file it is in.
+            let mut at = match offset.take() {
+                Some(offset) => offset,
+                None => layout::records_start(&buf).unwrap_or(buf.len()),
+            };
+            while remaining > 0 {
+                let len = match private::next_record(&buf[at..]) {
+                    Some((record, _)) => record.len(),
+                    None => break,
+                };
+                let record = buf[at + 4..at + 4 + len].to_vec();
+                if packed {
+                    for record in block::unpack(&record, &mut self.dictionaries)? {
+                        if remaining == 0 {
+                            break;
+                        }
+                        write_record(&mut writer, record)?;
+                        remaining -= 1;
+                    }
+                } else {
+                    write_record(&mut writer, record)?;
+                    remaining -= 1;
+                }
+                at += 4 + len;
+            }
+        }
+        for item in &buffered {
+            writer.record(item)?;
+        }
+        let (out, count) = writer.finish()?;
+        out.into_inner()?.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(count)
+    }
+}
+
+// The bincode encoding of each item of a buffer unexpired at `now`.
+fn encode_live<T, E>(expired: &E, items: &VecDeque<T>, stamps: &VecDeque<u64>) -> Vec<Vec<u8>>
+where
+    T: Serialize,
+    E: Fn(Option<u64>) -> bool,
+{
+    items
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| !expired(stamps.get(i).cloned()))
+        .map(|(_, item)| bincode::serialize(item, bincode::Infinite).expect("could not serialize"))
+        .collect()
+}
+
+// Write the backlog of a Receiver closed with `close_after` to the
+// channel's drained snapshot, removing a snapshot left empty
+fn persist_backlog<T>(rx: &mut Receiver<T>)
+where
+    T: Serialize + DeserializeOwned,
+{
+    let path = rx.root.join(layout::DRAINED_FILE);
+    match rx.export_snapshot(&path) {
+        Ok(0) => {
+            let _ = fs::remove_file(&path);
+        }
+        Ok(_items) => {
+            trace_event!(
+                channel = %rx.name,
+                items = _items,
+                "receiver left its backlog for the next build"
+            );
+        }
+        Err(_e) => {
+            trace_event!(
+                channel = %rx.name,
+                error = %_e,
+                "could not leave receiver backlog for the next build"
+            );
+        }
+    }
+}
+
+/// With the `mio` feature a Receiver registers with a `mio::Poll` as its
+/// `readiness_fd` does through `mio::unix::SourceFd`
+///
+/// The Receiver is readable while items wait. Registrations are edge
+/// triggered, as mio's are: once woken, receive until the channel is empty,
+/// as `try_recv` returning `None` says, before polling again.
+#[cfg(all(unix, feature = "mio"))]
+impl<T> ::mio::event::Source for Receiver<T>
+where
+    T: DeserializeOwned,
+{
+    fn register(
+        &mut self,
+        registry: &::mio::Registry,
+        token: ::mio::Token,
+        interests: ::mio::Interest,
+    ) -> io::Result<()> {
+        let fd = self.readiness_fd()?;
+        ::mio::unix::SourceFd(&fd).register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &::mio::Registry,
+        token: ::mio::Token,
+        interests: ::mio::Interest,
+    ) -> io::Result<()> {
+        let fd = self.readiness_fd()?;
+        ::mio::unix::SourceFd(&fd).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &::mio::Registry) -> io::Result<()> {
+        let fd = self.readiness_fd()?;
+        ::mio::unix::SourceFd(&fd).deregister(registry)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        if let Some((_, persist)) = self.drain.take() {
+            persist(self);
+        }
+        let (remaining, observer, archive) = {
+            let mut syn = private::lock(&self.fs_lock);
+            // Nothing sent to a memory-only channel can outlive its Receiver,
+            // so close it rather than leave Senders waiting for room.
+            if syn.memory_only {
+                syn.closed = true;
+                syn.room_made();
+            }
+            (
+                syn.writes_to_read + syn.delayed.len() + self.held.iter().count()
+                    + self.merging.len() + self.head.iter().count(),
+                syn.observer.clone(),
+                syn.archive.clone(),
+            )
+        };
+        let archive = archive.as_deref();
+        if remaining == 0 {
+            return;
+        }
+        let disk_bytes = self.metrics.disk_bytes.load(Ordering::Relaxed);
+        let oldest_segment_age = private::segment_ids(&self.root, archive)
+            .into_iter()
+            .filter(|_| disk_bytes > 0)
+            .min()
+            .and_then(|id| fs::metadata(private::segment_path(&self.root, archive, id)).ok())
+            .and_then(|md| md.created().or_else(|_| md.modified()).ok())
+            .and_then(|t| t.elapsed().ok());
+        trace_event!(
+            channel = %self.name,
+            remaining = remaining,
+            disk_bytes = disk_bytes,
+            "receiver dropped with backlog"
+        );
+        if let Some(observer) = observer {
+            observer(QueueEvent::ReceiverDroppedWithBacklog {
+                remaining: remaining,
+                disk_bytes: disk_bytes,
+                oldest_segment_age: oldest_segment_age,
+            });
+        }
+    }
+}
+
+/// A blocking iterator over the items of a Receiver, ending once the
+/// channel hangs up and every item is received
+///
+/// Returned by `Receiver::iter`. See `Receiver::is_hung_up`.
 #[derive(Debug)]
 pub struct Iter<'a, T: 'a + DeserializeOwned> {
     rx: &'a mut Receiver<T>,
+    // Made on the first wait
+    sel: Option<Select>,
 }
 
+/// An iterator over the items waiting on a Receiver, ending once none is
+///
+/// Returned by `Receiver::try_iter`.
+#[derive(Debug)]
+pub struct TryIter<'a, T: 'a + DeserializeOwned> {
+    rx: &'a mut Receiver<T>,
+}
+
+/// A blocking iterator over the items of a Receiver, ending once the
+/// channel hangs up and every item is received
+///
+/// Returned by the Receiver's `into_iter`. See `Receiver::is_hung_up`.
 #[derive(Debug)]
 pub struct IntoIter<T: DeserializeOwned> {
     rx: Receiver<T>,
+    // Made on the first wait
+    sel: Option<Select>,
 }
 
 impl<T> IntoIterator for Receiver<T>
 where
-    T: DeserializeOwned,
+    T: DeserializeOwned + Send + 'static,
 {
     type Item = T;
     type IntoIter = IntoIter<T>;
 
     fn into_iter(self) -> IntoIter<T> {
-        IntoIter { rx: self }
+        IntoIter { rx: self, sel: None }
+    }
+}
+
+// Take the next item of `rx`, waiting on it until the channel hangs up. The
+// last Sender wakes the Select as it goes.
+fn next_blocking<T>(rx: &mut Receiver<T>, sel: &mut Option<Select>) -> Option<T>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    loop {
+        // Checked first, so the last Sender's items are in by the receive
+        let hung_up = rx.is_hung_up();
+        if let Some(item) = rx.next_value() {
+            return Some(item);
+        }
+        if hung_up {
+            return None;
+        }
+        let sel = sel.get_or_insert_with(|| {
+            let mut sel = Select::new();
+            sel.add(rx);
+            sel
+        });
+        sel.ready_or_hang_up(None);
     }
 }
 
 impl<'a, T> Iterator for Iter<'a, T>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        next_blocking(self.rx, &mut self.sel)
+    }
+}
+
+impl<'a, T> Iterator for TryIter<'a, T>
 where
     T: DeserializeOwned,
 {
@@ -238,11 +1968,275 @@ where
 
 impl<T> Iterator for IntoIter<T>
 where
-    T: DeserializeOwned,
+    T: DeserializeOwned + Send + 'static,
 {
     type Item = T;
 
     fn next(&mut self) -> Option<T> {
-        self.rx.next_value()
+        next_blocking(&mut self.rx, &mut self.sel)
+    }
+}
+
+/// The next item of a channel, left at its head unless committed
+///
+/// Returned by `Receiver::peek`. Dereferences to the item.
+#[derive(Debug)]
+pub struct Peeked<'a, T: 'a> {
+    rx: &'a mut Receiver<T>,
+    // Taken by `commit`
+    item: Option<T>,
+}
+
+impl<'a, T> Peeked<'a, T> {
+    /// Remove the item from the channel, handing it over
+    pub fn commit(mut self) -> T {
+        self.item.take().expect("peeked item already committed")
+    }
+
+    /// Leave the item at the head of the channel, to be received again
+    pub fn abort(self) {}
+}
+
+impl<'a, T> Deref for Peeked<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.item.as_ref().expect("peeked item already committed")
+    }
+}
+
+impl<'a, T> Drop for Peeked<'a, T> {
+    fn drop(&mut self) {
+        if let Some(item) = self.item.take() {
+            self.rx.held = Some((item, self.rx.meta));
+        }
+    }
+}
+
+/// A received item lent out by its Receiver, consumed when dropped
+///
+/// Returned by `Receiver::recv_ref`. Dereferences to the item.
+#[derive(Debug)]
+pub struct RecvRef<'a, T: 'a> {
+    rx: &'a mut Receiver<T>,
+}
+
+impl<'a, T> Deref for RecvRef<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.rx.lent.as_ref().expect("lent item already dropped")
+    }
+}
+
+impl<'a, T> Drop for RecvRef<'a, T> {
+    fn drop(&mut self) {
+        self.rx.lent = None;
+    }
+}
+
+/// A received item, handed back to its channel for reuse when dropped
+///
+/// Returned by `Receiver::recv_recycled`. Dereferences to the item.
+#[derive(Debug)]
+pub struct Recycled<'a, T: 'a> {
+    rx: &'a Receiver<T>,
+    // Taken by `into_inner`
+    item: Option<T>,
+}
+
+impl<'a, T> Recycled<'a, T> {
+    /// Keep the item rather than handing it back
+    pub fn into_inner(mut self) -> T {
+        self.item.take().expect("recycled item already taken")
+    }
+}
+
+impl<'a, T> Deref for Recycled<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.item.as_ref().expect("recycled item already taken")
+    }
+}
+
+impl<'a, T> DerefMut for Recycled<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.item.as_mut().expect("recycled item already taken")
+    }
+}
+
+impl<'a, T> Drop for Recycled<'a, T> {
+    fn drop(&mut self) {
+        if let Some(item) = self.item.take() {
+            let mut syn = private::lock(&self.rx.fs_lock);
+            if syn.recycled.len() < syn.capacity {
+                syn.recycled.push(item);
+            }
+        }
+    }
+}
+
+/// A Receiver that drops the items its predicate rejects
+///
+/// Returned by `Receiver::filtered`.
+#[derive(Debug)]
+pub struct Filtered<T> {
+    rx: Receiver<T>,
+}
+
+impl<T> Filtered<T>
+where
+    T: DeserializeOwned,
+{
+    /// A blocking iterator over the matching items, as `Receiver::iter`
+    pub fn iter(&mut self) -> Iter<'_, T>
+    where
+        T: Send + 'static,
+    {
+        self.rx.iter()
+    }
+
+    /// An iterator over the matching items waiting, as `Receiver::try_iter`
+    pub fn try_iter(&mut self) -> TryIter<'_, T> {
+        self.rx.try_iter()
+    }
+
+    /// Receive up to `max` matching items, waiting up to `timeout` for them,
+    /// as `Receiver::recv_batch`
+    pub fn recv_batch(&mut self, max: usize, timeout: Duration) -> Vec<T>
+    where
+        T: Send + 'static,
+    {
+        self.rx.recv_batch(max, timeout)
+    }
+
+    /// Return a snapshot of the channel's metrics
+    pub fn metrics(&self) -> QueueMetrics {
+        self.rx.metrics()
+    }
+
+    /// Return the name of the channel this Receiver reads from
+    pub fn name(&self) -> &str {
+        self.rx.name()
+    }
+
+    /// Stop filtering, returning the Receiver to receive every item
+    pub fn into_inner(mut self) -> Receiver<T> {
+        self.rx.set_filter(None);
+        self.rx
+    }
+}
+
+impl<T> IntoIterator for Filtered<T>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        self.rx.into_iter()
+    }
+}
+
+/// A Receiver whose items are converted as they are received
+///
+/// Returned by `Receiver::map`.
+pub struct Mapped<T, F> {
+    rx: Receiver<T>,
+    f: F,
+}
+
+impl<T, F> fmt::Debug for Mapped<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Mapped").field("name", &self.rx.name).finish()
+    }
+}
+
+impl<T, U, F> Mapped<T, F>
+where
+    T: DeserializeOwned,
+    F: FnMut(T) -> U,
+{
+    /// A blocking iterator over the converted items, as `Receiver::iter`
+    pub fn iter(&mut self) -> ::std::iter::Map<Iter<'_, T>, &mut F>
+    where
+        T: Send + 'static,
+    {
+        self.rx.iter().map(&mut self.f)
+    }
+
+    /// An iterator over the converted items waiting, as `Receiver::try_iter`
+    pub fn try_iter(&mut self) -> MappedIter<'_, T, F> {
+        MappedIter { mapped: self }
+    }
+
+    /// Receive up to `max` items, waiting up to `timeout` for them, as
+    /// `Receiver::recv_batch`, and convert each of them
+    pub fn recv_batch(&mut self, max: usize, timeout: Duration) -> Vec<U>
+    where
+        T: Send + 'static,
+    {
+        let batch = self.rx.recv_batch(max, timeout);
+        batch.into_iter().map(&mut self.f).collect()
+    }
+
+    /// Register a waker to be notified when the queue has data, as
+    /// `Receiver::register_waker`
+    pub fn register_waker(&mut self, waker: Waker) {
+        self.rx.register_waker(waker)
+    }
+
+    /// Return a snapshot of the channel's metrics
+    pub fn metrics(&self) -> QueueMetrics {
+        self.rx.metrics()
+    }
+
+    /// Return the name of the channel this Receiver reads from
+    pub fn name(&self) -> &str {
+        self.rx.name()
+    }
+
+    /// Stop converting, returning the Receiver
+    pub fn into_inner(self) -> Receiver<T> {
+        self.rx
+    }
+}
+
+/// An iterator over the converted items waiting on a `Mapped` Receiver
+pub struct MappedIter<'a, T: 'a, F: 'a> {
+    mapped: &'a mut Mapped<T, F>,
+}
+
+impl<'a, T, F> fmt::Debug for MappedIter<'a, T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MappedIter").field("mapped", &self.mapped).finish()
+    }
+}
+
+impl<'a, T, U, F> Iterator for MappedIter<'a, T, F>
+where
+    T: DeserializeOwned,
+    F: FnMut(T) -> U,
+{
+    type Item = U;
+
+    fn next(&mut self) -> Option<U> {
+        let mapped = &mut *self.mapped;
+        mapped.rx.next_value().map(&mut mapped.f)
+    }
+}
+
+impl<T, U, F> IntoIterator for Mapped<T, F>
+where
+    T: DeserializeOwned + Send + 'static,
+    F: FnMut(T) -> U,
+{
+    type Item = U;
+    type IntoIter = ::std::iter::Map<IntoIter<T>, F>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.rx.into_iter().map(self.f)
     }
 }
@@ -0,0 +1,196 @@
+use super::{ChannelBuilder, Error, Receiver, Sender};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+struct Entry {
+    type_id: TypeId,
+    type_name: &'static str,
+    sender: Box<dyn Any + Send>,
+    receiver: Option<Box<dyn Any + Send>>,
+}
+
+/// A set of channels looked up by name
+///
+/// A `Registry` owns the channels created through it, keyed by name, so that
+/// one component may create a channel and another find it later. Every
+/// channel is stored under `data_dir`. A channel is created the first time it
+/// is asked for, or up front with `create` to control its configuration.
+///
+/// Each name is bound to the item type it was first created with. Asking for
+/// that name with any other type is an `Error::TypeMismatch`.
+///
+/// # Example
+/// ```
+/// extern crate tempdir;
+/// extern crate hopper;
+///
+/// let dir = tempdir::TempDir::new("hopper").unwrap();
+/// let registry = hopper::Registry::new(dir.path());
+///
+/// let mut snd = registry.sender::<u64>("metrics").unwrap();
+/// let mut rcv = registry.receiver::<u64>("metrics").unwrap();
+/// snd.send(9);
+/// assert_eq!(Some(9), rcv.try_iter().next());
+///
+/// assert!(registry.sender::<String>("metrics").is_err());
+/// ```
+pub struct Registry {
+    data_dir: PathBuf,
+    channels: Mutex<HashMap<String, Entry>>,
+}
+
+impl fmt::Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let channels = self.channels.lock().expect("Registry poisoned");
+        let mut names: Vec<(&String, &'static str)> =
+            channels.iter().map(|(n, e)| (n, e.type_name)).collect();
+        names.sort();
+        f.debug_struct("Registry")
+            .field("data_dir", &self.data_dir)
+            .field("channels", &names)
+            .finish()
+    }
+}
+
+impl Registry {
+    /// Create an empty registry whose channels live under `data_dir`
+    pub fn new(data_dir: &Path) -> Registry {
+        Registry {
+            data_dir: data_dir.to_path_buf(),
+            channels: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Create a channel from `builder` and add it to the registry
+    ///
+    /// The builder's name becomes the channel's registry key and its data
+    /// directory is ignored in favour of the registry's. If a channel of that
+    /// name already exists this does nothing, returning
+    /// `Error::TypeMismatch` if it holds a different item type.
+    pub fn create<T>(&self, builder: ChannelBuilder) -> Result<(), Error>
+    where
+        T: Serialize + DeserializeOwned + Send + 'static,
+    {
+        let mut channels = self.channels.lock().expect("Registry poisoned");
+        let name = builder.name().to_string();
+        if let Some(entry) = channels.get(&name) {
+            return if entry.type_id == TypeId::of::<T>() {
+                Ok(())
+            } else {
+                Err(Error::TypeMismatch)
+            };
+        }
+        let (snd, rcv) = builder.data_dir(&self.data_dir).build::<T>()?;
+        channels.insert(
+            name,
+            Entry {
+                type_id: TypeId::of::<T>(),
+                type_name: ::std::any::type_name::<T>(),
+                sender: Box::new(snd),
+                receiver: Some(Box::new(rcv)),
+            },
+        );
+        Ok(())
+    }
+
+    /// Return a Sender for the channel `name`, creating it if need be
+    pub fn sender<T>(&self, name: &str) -> Result<Sender<T>, Error>
+    where
+        T: Serialize + DeserializeOwned + Send + 'static,
+    {
+        self.create::<T>(ChannelBuilder::new(name, &self.data_dir))?;
+        let channels = self.channels.lock().expect("Registry poisoned");
+        match channels[name].sender.downcast_ref::<Sender<T>>() {
+            Some(snd) => Ok(snd.clone()),
+            None => Err(Error::TypeMismatch),
+        }
+    }
+
+    /// Take the Receiver for the channel `name`, creating it if need be
+    ///
+    /// Channels have a single Receiver, so this succeeds once per channel.
+    /// Later calls return `Error::ReceiverTaken`.
+    pub fn receiver<T>(&self, name: &str) -> Result<Receiver<T>, Error>
+    where
+        T: Serialize + DeserializeOwned + Send + 'static,
+    {
+        self.create::<T>(ChannelBuilder::new(name, &self.data_dir))?;
+        let mut channels = self.channels.lock().expect("Registry poisoned");
+        let entry = channels.get_mut(name).expect("channel vanished from registry");
+        match entry.receiver.take() {
+            Some(rcv) => match rcv.downcast::<Receiver<T>>() {
+                Ok(rcv) => Ok(*rcv),
+                Err(rcv) => {
+                    entry.receiver = Some(rcv);
+                    Err(Error::TypeMismatch)
+                }
+            },
+            None => Err(Error::ReceiverTaken),
+        }
+    }
+
+    /// Return the names of the registered channels, sorted
+    pub fn names(&self) -> Vec<String> {
+        let channels = self.channels.lock().expect("Registry poisoned");
+        let mut names: Vec<String> = channels.keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::Registry;
+    use super::super::{ChannelBuilder, Error};
+    use std::thread;
+
+    #[test]
+    fn registry_hands_out_connected_handles() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let registry = Registry::new(dir.path());
+
+        let mut rcv = registry.receiver::<u32>("events").unwrap();
+        let snd = registry.sender::<u32>("events").unwrap();
+        let jh = thread::spawn(move || {
+            let mut snd = snd;
+            for i in 0..10 {
+                snd.send(i);
+            }
+        });
+        jh.join().expect("sender thread panicked");
+        for i in 0..10 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        assert_eq!(vec!["events".to_string()], registry.names());
+    }
+
+    #[test]
+    fn registry_rejects_type_mismatch() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let registry = Registry::new(dir.path());
+
+        registry.sender::<u32>("typed").unwrap();
+        assert_eq!(Err(Error::TypeMismatch), registry.sender::<u64>("typed").map(|_| ()));
+        assert_eq!(Err(Error::TypeMismatch), registry.receiver::<u64>("typed").map(|_| ()));
+        assert!(registry.receiver::<u32>("typed").is_ok());
+    }
+
+    #[test]
+    fn registry_receiver_is_taken_once() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let registry = Registry::new(dir.path());
+        registry
+            .create::<u32>(ChannelBuilder::new("once", dir.path()).max_bytes(1024))
+            .unwrap();
+
+        assert!(registry.receiver::<u32>("once").is_ok());
+        assert_eq!(Err(Error::ReceiverTaken), registry.receiver::<u32>("once").map(|_| ()));
+    }
+}
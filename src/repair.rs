@@ -0,0 +1,348 @@
+//! Recovery of channel directories damaged by a crash
+//!
+//! A crash or power loss partway through a write can leave a queue file
+//! ending in a torn record. A `ProcessReceiver` resuming over such a file
+//! waits forever for the rest of the record, and a `ProcessSender` reopening
+//! it appends after the torn bytes, so everything written afterwards is
+//! misread too. `repair` walks a closed channel directory and cuts each queue
+//! file back to its last good record.
+//!
+//! A record is good if it is completely framed and, for `repair_as`, decodes
+//! as the channel's item type. Queue files carry no checksums of their own,
+//! but a channel built with `ChannelBuilder::pack_records` writes each record
+//! as a block with a checksum over it, so there a record is good only if its
+//! block passes its checksum too, and a file is cut back at its first bad
+//! block. The channel's metadata says which its queue files hold. A queue
+//! file with nothing readable in it is moved into a `corrupt` subdirectory
+//! rather than truncated to nothing, so its bytes are kept for inspection,
+//! and an empty file is left in its place.
+use bincode::deserialize;
+use block;
+use layout::{self, RecordFraming};
+use private;
+use process;
+use serde::de::DeserializeOwned;
+use super::Error;
+use std::fs;
+use std::path::Path;
+
+/// The subdirectory of a channel directory that unreadable queue files are
+/// moved into
+pub const QUARANTINE_DIR: &str = "corrupt";
+
+/// What `repair` did to one queue file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentRepair {
+    /// The queue file's sequence number
+    pub id: usize,
+    /// The number of good records left in the queue file
+    pub kept_records: u64,
+    /// The number of bytes cut from the queue file
+    pub lost_bytes: u64,
+    /// Whether the queue file was moved into the quarantine directory
+    pub quarantined: bool,
+}
+
+/// The outcome of `repair`
+///
+/// Only queue files that needed repair are listed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// The repaired queue files, oldest first
+    pub segments: Vec<SegmentRepair>,
+}
+
+impl RepairReport {
+    /// Whether the directory was undamaged
+    pub fn is_clean(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// The total number of bytes cut or quarantined
+    pub fn lost_bytes(&self) -> u64 {
+        self.segments.iter().map(|s| s.lost_bytes).sum()
+    }
+}
+
+/// Repair the channel directory `root`, checking record framing and the
+/// checksums of packed blocks
+///
+/// Returns `Error::AlreadyLocked` if the channel is open, by this process or
+/// another, or is being backed up by `process::backup`. A committed
+/// `ProcessReceiver` position past the end of a repaired queue file is moved
+/// back to the new end.
+pub fn repair(root: &Path) -> Result<RepairReport, Error> {
+    repair_with(root, |_| true)
+}
+
+/// Repair the channel directory `root`, also requiring each record to decode
+/// as a `T`
+///
+/// Only for directories of channels without a TTL, whose records hold
+/// nothing but the item and, for process channels, its schema version. The
+/// records of packed blocks are not decoded: a block is good if it passes
+/// its checksum. Otherwise as `repair`.
+pub fn repair_as<T>(root: &Path) -> Result<RepairReport, Error>
+where
+    T: DeserializeOwned,
+{
+    repair_with(root, |record| deserialize::<T>(record).is_ok())
+}
+
+fn repair_with<F>(root: &Path, valid: F) -> Result<RepairReport, Error>
+where
+    F: Fn(&[u8]) -> bool,
+{
+    if !root.is_dir() {
+        return Err(Error::NoSuchDirectory);
+    }
+    let _sender_lock = layout::lock(root)?;
+    let _reader_lock = layout::lock_file(root, process::READER_LOCK_FILE)?;
+    let _pin = layout::share_lock_file(root, layout::PIN_FILE)?;
+    let packed = RecordFraming::read(root).is_some_and(|framing| framing.packed);
+    Ok(repair_held(root, packed, valid))
+}
+
+/// Repair the channel directory `root`, whose locks the caller holds, keeping
+/// records while `valid` holds for them or, if `packed`, while their blocks
+/// pass their checksums
+pub(crate) fn repair_held<F>(root: &Path, packed: bool, valid: F) -> RepairReport
+where
+    F: Fn(&[u8]) -> bool,
+{
+    let mut ids = private::segment_ids(root, None);
+    ids.sort();
+    let mut report = RepairReport::default();
+    for id in ids {
+        let path = root.join(format!("{}", id));
+        let (kept_records, good_bytes, total_bytes) = match fs::read(&path) {
+            Ok(buf) => {
+                // A header cut short leaves nothing to keep.
+                let (version, start) = layout::segment_format(&buf).unwrap_or((1, 0));
+                let tagged = layout::is_tagged(version);
+                let (records, good) = good_prefix(&buf[start..], &|record: &[u8]| {
+                    if packed {
+                        block::intact(record)
+                    } else if tagged {
+                        record.len() >= 4 && valid(&record[4..])
+                    } else {
+                        valid(record)
+                    }
+                });
+                (records, start as u64 + good, buf.len() as u64)
+            }
+            Err(_) => (0, 0, fs::metadata(&path).map(|md| md.len()).unwrap_or(0)),
+        };
+        if good_bytes == total_bytes {
+            continue;
+        }
+        if kept_records == 0 {
+            quarantine(root, &path);
+        } else {
+            truncate(&path, good_bytes);
+        }
+        report.segments.push(SegmentRepair {
+            id: id,
+            kept_records: kept_records,
+            lost_bytes: total_bytes - good_bytes,
+            quarantined: kept_records == 0,
+        });
+    }
+
+    if let Some((seq, offset)) = process::read_control(root) {
+        let len = fs::metadata(root.join(format!("{}", seq)))
+            .map(|md| md.len())
+            .ok();
+        if let Some(len) = len {
+            if offset > len {
+                process::write_control(root, seq, len).expect("could not write control file");
+            }
+        }
+    }
+    report
+}
+
+// The number of good records at the start of `buf` and the bytes they span.
+fn good_prefix<F>(buf: &[u8], valid: &F) -> (u64, u64)
+where
+    F: Fn(&[u8]) -> bool,
+{
+    let mut records = 0;
+    let mut rest = buf;
+    while let Some((record, tail)) = private::next_record(rest) {
+        if !valid(record) {
+            break;
+        }
+        records += 1;
+        rest = tail;
+    }
+    (records, (buf.len() - rest.len()) as u64)
+}
+
+// Move the queue file at `path` into quarantine, leaving an empty file in its
+// place. Readers step from one queue file to the next by sequence number, so
+// a gap would strand them.
+fn quarantine(root: &Path, path: &Path) {
+    let sealed = process::is_sealed(path);
+    let dir = root.join(QUARANTINE_DIR);
+    fs::create_dir_all(&dir).expect("could not create quarantine directory");
+    let dest = dir.join(path.file_name().expect("queue file has no name"));
+    fs::rename(path, dest).expect("could not quarantine queue file");
+    let fp = fs::File::create(path).expect("could not replace quarantined queue file");
+    if sealed {
+        let mut permissions = fp.metadata()
+            .expect("could not read queue file metadata")
+            .permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(path, permissions).expect("could not seal queue file");
+    }
+}
+
+// Cut the queue file at `path` down to its first `len` bytes, keeping it
+// sealed if it was. The cut copy is written alongside and moved into place so
+// a crash partway leaves the original whole.
+fn truncate(path: &Path, len: u64) {
+    let sealed = process::is_sealed(path);
+    let buf = fs::read(path).expect("could not read queue file");
+    let tmp = path.with_extension("repair");
+    fs::write(&tmp, &buf[..len as usize]).expect("could not write repaired queue file");
+    if sealed {
+        let mut permissions = fs::metadata(&tmp)
+            .expect("could not read repaired queue file metadata")
+            .permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&tmp, permissions).expect("could not seal repaired queue file");
+    }
+    private::replace_segment(&tmp, path).expect("could not replace damaged queue file");
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::*;
+    use process;
+    use std::fs;
+    use std::io::Write;
+
+    fn append(path: &Path, bytes: &[u8]) {
+        let mut fp = fs::OpenOptions::new().append(true).open(path).unwrap();
+        fp.write_all(bytes).unwrap();
+    }
+
+    #[test]
+    fn torn_tail_is_cut_and_replay_resumes() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let root = dir.path().join("repair");
+        {
+            let mut snd = process::sender::<u64>("repair", dir.path(), 1 << 20).unwrap();
+            for i in 0..10u64 {
+                snd.send(i);
+            }
+        }
+        // A record header promising more than was written
+        append(&root.join("0"), &[0, 0, 0, 8, 1, 2]);
+        let expected = SegmentRepair {
+            id: 0,
+            kept_records: 10,
+            lost_bytes: 6,
+            quarantined: false,
+        };
+        assert_eq!(repair(&root).unwrap().segments, vec![expected]);
+        assert!(repair(&root).unwrap().is_clean());
+
+        let mut snd = process::sender::<u64>("repair", dir.path(), 1 << 20).unwrap();
+        snd.send(10);
+        let mut rcv = process::receiver::<u64>("repair", dir.path()).unwrap();
+        for i in 0..11u64 {
+            assert_eq!(Some(i), rcv.try_recv().unwrap());
+        }
+        assert_eq!(None, rcv.try_recv().unwrap());
+    }
+
+    #[test]
+    fn unreadable_segments_are_quarantined() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let root = dir.path().join("repair");
+        {
+            let mut snd = process::sender::<String>("repair", dir.path(), 1 << 20).unwrap();
+            snd.send("kept".to_string());
+        }
+        // Framed correctly but not a String
+        fs::write(root.join("1"), [0, 0, 0, 2, 0xff, 0xff]).unwrap();
+
+        let report = repair_as::<String>(&root).unwrap();
+        assert_eq!(report.segments.len(), 1);
+        assert!(report.segments[0].quarantined);
+        assert_eq!(report.lost_bytes(), 6);
+        assert_eq!(0, fs::metadata(root.join("1")).unwrap().len());
+        assert!(root.join(QUARANTINE_DIR).join("1").exists());
+
+        let mut rcv = process::receiver::<String>("repair", dir.path()).unwrap();
+        assert_eq!(Some("kept".to_string()), rcv.try_recv().unwrap());
+    }
+
+    #[test]
+    fn packed_segments_are_cut_at_the_first_bad_block() {
+        use segment;
+        use super::super::ChannelBuilder;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let root = dir.path().join("packed");
+        {
+            let (mut snd, _rcv) = ChannelBuilder::new("packed", dir.path())
+                .max_bytes(1 << 20)
+                .pack_records(256)
+                .build::<u64>()
+                .unwrap();
+            for i in 0..3000u64 {
+                snd.send(i);
+            }
+            snd.flush().unwrap();
+        }
+        let path = root.join("0");
+        let buf = fs::read(&path).unwrap();
+        let blocks: Vec<usize> = segment::parse(&buf)
+            .unwrap()
+            .map(|record| record.unwrap().offset)
+            .collect();
+        assert!(blocks.len() > 2);
+        // A flipped bit in the second block, framed as well as ever
+        let mut damaged = buf.clone();
+        damaged[blocks[1] + 12] ^= 1;
+        fs::write(&path, &damaged).unwrap();
+
+        let expected = SegmentRepair {
+            id: 0,
+            kept_records: 1,
+            lost_bytes: (buf.len() - blocks[1]) as u64,
+            quarantined: false,
+        };
+        assert_eq!(repair(&root).unwrap().segments, vec![expected]);
+        assert_eq!(blocks[1] as u64, fs::metadata(&path).unwrap().len());
+        assert!(repair(&root).unwrap().is_clean());
+    }
+
+    #[test]
+    fn committed_offset_is_pulled_back() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let root = dir.path().join("repair");
+        {
+            let mut snd = process::sender::<u64>("repair", dir.path(), 1 << 20).unwrap();
+            snd.send(1);
+        }
+        let good = fs::metadata(root.join("0")).unwrap().len();
+        append(&root.join("0"), &[0, 0]);
+        process::write_control(&root, 0, good + 2).unwrap();
+
+        repair(&root).unwrap();
+        assert_eq!(process::read_control(&root), Some((0, good)));
+    }
+
+    #[test]
+    fn open_channels_are_refused() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let _snd = process::sender::<u64>("repair", dir.path(), 1 << 20).unwrap();
+        assert_eq!(repair(&dir.path().join("repair")), Err(Error::AlreadyLocked));
+    }
+}
@@ -0,0 +1,252 @@
+//! Capacity reserved for groups of Senders
+//!
+//! A channel built with `ChannelBuilder::reserve` names groups of Senders,
+//! each with a share of the channel's room that the others may not take.
+//! Senders join a group with `Sender::join_group`. A memory-only channel's
+//! room is its `memory_budget`, and each group is held to its memory share;
+//! a channel that pages out holds a group's items first in memory and then
+//! on disk, so the group's share there is its memory and disk shares
+//! together, of the room under `max_disk_bytes`. A group is counted the
+//! encoded size of each of its items from its send to its receive. A Sender
+//! may always send within its group's share, room allowing, and past it only
+//! into room no other group has reserved and left unused; Senders outside
+//! every group have only that unreserved room. A send refused for room is
+//! handled as the channel's `OverflowPolicy` says, and counted against the
+//! group as well as the channel.
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// A point-in-time view of one reservation group of a channel
+///
+/// Obtained from `Sender::group_metrics` or `Receiver::group_metrics`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GroupMetrics {
+    /// Bytes reserved for the group's items
+    pub reserved_bytes: usize,
+    /// Encoded bytes of the group's items sent and not yet received
+    pub held_bytes: usize,
+    /// Items the group's Senders have sent into the channel
+    pub total_enqueued: u64,
+    /// Of the group's items, those refused or dropped because a memory-only
+    /// channel had no room for them
+    pub total_overflowed: u64,
+    /// Of the group's items, those refused or dropped because the channel
+    /// was held at its disk quota
+    pub total_disk_full: u64,
+}
+
+/// A group's shares as set on the builder
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Share {
+    pub name: String,
+    pub memory: usize,
+    pub disk: usize,
+}
+
+// One group of a channel. Its counts change under the channel's lock but are
+// atomics so that a Sender may count its refusals without taking it.
+#[derive(Debug)]
+pub struct Group {
+    name: String,
+    reserved: usize,
+    held: AtomicUsize,
+    enqueued: AtomicU64,
+    overflowed: AtomicU64,
+    disk_full: AtomicU64,
+}
+
+impl Group {
+    /// The group's name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Count `items` of the group's refused, as overflowed from memory or
+    /// held at the disk quota
+    pub fn refused(&self, disk_quota: bool, items: u64) {
+        let counter = if disk_quota {
+            &self.disk_full
+        } else {
+            &self.overflowed
+        };
+        counter.fetch_add(items, Ordering::Relaxed);
+    }
+
+    fn held(&self) -> usize {
+        self.held.load(Ordering::Relaxed)
+    }
+
+    fn unused(&self) -> usize {
+        self.reserved.saturating_sub(self.held())
+    }
+
+    fn metrics(&self) -> GroupMetrics {
+        GroupMetrics {
+            reserved_bytes: self.reserved,
+            held_bytes: self.held(),
+            total_enqueued: self.enqueued.load(Ordering::Relaxed),
+            total_overflowed: self.overflowed.load(Ordering::Relaxed),
+            total_disk_full: self.disk_full.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The groups of a channel and the Senders in each, by id
+#[derive(Debug, Default)]
+pub struct Reservations {
+    groups: Vec<Arc<Group>>,
+    members: HashMap<u32, Arc<Group>>,
+}
+
+impl Reservations {
+    /// The groups of `shares`, each reserving its memory share on a
+    /// `memory_only` channel and both its shares on one that pages out
+    pub fn new(shares: &[Share], memory_only: bool) -> Reservations {
+        let groups = shares
+            .iter()
+            .map(|share| {
+                let reserved = if memory_only {
+                    share.memory
+                } else {
+                    share.memory.saturating_add(share.disk)
+                };
+                Arc::new(Group {
+                    name: share.name.clone(),
+                    reserved: reserved,
+                    held: AtomicUsize::new(0),
+                    enqueued: AtomicU64::new(0),
+                    overflowed: AtomicU64::new(0),
+                    disk_full: AtomicU64::new(0),
+                })
+            })
+            .collect();
+        Reservations {
+            groups: groups,
+            members: HashMap::new(),
+        }
+    }
+
+    /// Put the Sender with id `sender` in the group `name`, returning the
+    /// group, or `None` if the channel has no such group
+    pub fn join(&mut self, sender: u32, name: &str) -> Option<Arc<Group>> {
+        let group = Arc::clone(self.groups.iter().find(|g| g.name == name)?);
+        self.members.insert(sender, Arc::clone(&group));
+        Some(group)
+    }
+
+    /// Count an item of `bytes` sent by `sender` against its group
+    pub fn hold(&self, sender: u32, bytes: usize) {
+        if let Some(group) = self.members.get(&sender) {
+            group.held.fetch_add(bytes, Ordering::Relaxed);
+            group.enqueued.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Give back to its group an item of `bytes` sent by `sender`, no
+    /// longer held by the channel
+    pub fn release(&self, sender: u32, bytes: usize) {
+        if let Some(group) = self.members.get(&sender) {
+            let held = group.held().saturating_sub(bytes);
+            group.held.store(held, Ordering::Relaxed);
+        }
+    }
+
+    /// Give back everything held, once the channel is empty
+    ///
+    /// Items lost without being received--evicted with their queue file or
+    /// passed over as corrupt--are never given back one by one.
+    pub fn clear(&self) {
+        for group in &self.groups {
+            group.held.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether `bytes` more from `sender` fit within its group's share
+    pub fn within_share(&self, sender: u32, bytes: usize) -> bool {
+        self.members
+            .get(&sender)
+            .is_some_and(|g| g.held().saturating_add(bytes) <= g.reserved)
+    }
+
+    /// The bytes reserved for, and not yet used by, every group but that of
+    /// `sender`
+    pub fn unused_elsewhere(&self, sender: u32) -> usize {
+        let own = self.members.get(&sender);
+        self.groups
+            .iter()
+            .filter(|g| own.is_none_or(|own| !Arc::ptr_eq(g, own)))
+            .map(|g| g.unused())
+            .sum()
+    }
+
+    /// The metrics of the group `name`, if the channel has one
+    pub fn metrics(&self, name: &str) -> Option<GroupMetrics> {
+        self.groups
+            .iter()
+            .find(|g| g.name == name)
+            .map(|g| g.metrics())
+    }
+}
+
+impl GroupMetrics {
+    /// The metrics of two lanes' groups of the same name, together
+    pub(crate) fn merge(self, other: GroupMetrics) -> GroupMetrics {
+        GroupMetrics {
+            reserved_bytes: self.reserved_bytes + other.reserved_bytes,
+            held_bytes: self.held_bytes + other.held_bytes,
+            total_enqueued: self.total_enqueued + other.total_enqueued,
+            total_overflowed: self.total_overflowed + other.total_overflowed,
+            total_disk_full: self.total_disk_full + other.total_disk_full,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn shares() -> Vec<Share> {
+        vec![
+            Share {
+                name: "a".to_string(),
+                memory: 100,
+                disk: 1000,
+            },
+            Share {
+                name: "b".to_string(),
+                memory: 50,
+                disk: 0,
+            },
+        ]
+    }
+
+    #[test]
+    fn shares_are_counted_by_channel_kind() {
+        let paging = Reservations::new(&shares(), false);
+        assert_eq!(1100, paging.metrics("a").unwrap().reserved_bytes);
+        let memory_only = Reservations::new(&shares(), true);
+        assert_eq!(100, memory_only.metrics("a").unwrap().reserved_bytes);
+        assert_eq!(None, memory_only.metrics("c"));
+    }
+
+    #[test]
+    fn unused_shares_elsewhere_are_set_aside() {
+        let mut reservations = Reservations::new(&shares(), true);
+        assert!(reservations.join(1, "a").is_some());
+        assert!(reservations.join(2, "b").is_some());
+        assert!(reservations.join(3, "c").is_none());
+
+        reservations.hold(1, 60);
+        assert!(reservations.within_share(1, 40));
+        assert!(!reservations.within_share(1, 41));
+        assert!(!reservations.within_share(3, 0));
+        assert_eq!(50, reservations.unused_elsewhere(1));
+        assert_eq!(40, reservations.unused_elsewhere(2));
+        assert_eq!(90, reservations.unused_elsewhere(3));
+
+        reservations.release(1, 100);
+        assert_eq!(0, reservations.metrics("a").unwrap().held_bytes);
+        assert_eq!(1, reservations.metrics("a").unwrap().total_enqueued);
+    }
+}
@@ -0,0 +1,338 @@
+//! Retention of consumed queue files
+//!
+//! By default the Receiver deletes a queue file as soon as it has read past
+//! it. A channel built with `ChannelBuilder::keep_for` or
+//! `ChannelBuilder::keep_bytes` instead moves the file into a `retained`
+//! subdirectory of the directory it was read from, where it stays until the
+//! policy no longer covers it. Retained files keep their queue file names and
+//! contents, so replay and audit tools can read them as they would any queue
+//! file. The name of the subdirectory is not a number and so never collides
+//! with a queue file.
+//!
+//! A retained file is aged by the send time of its newest item, where its
+//! summary records one, else by the time it was last written to. Retained
+//! files are collected each time the Receiver retains another and,
+//! for `keep_for`, by a background thread that lives as long as the channel,
+//! or by a job of its `Runtime`.
+use clock::Clock;
+use index;
+use metrics::Metrics;
+use private;
+use summary;
+use runtime::{Next, Runtime};
+use supervise::{Supervisor, Task};
+use std::cmp;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// The subdirectory consumed queue files are retained in
+pub const RETAINED_DIR: &str = "retained";
+
+/// When consumed queue files are finally deleted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    /// Delete a retained file this long after its newest item was sent, or
+    /// it was last written to
+    pub keep_for: Option<Duration>,
+    /// Delete the oldest retained files once together they pass this many
+    /// bytes
+    pub keep_bytes: Option<u64>,
+}
+
+impl Retention {
+    /// Whether consumed queue files are kept at all
+    pub fn is_enabled(&self) -> bool {
+        self.keep_for.is_some() || self.keep_bytes.is_some()
+    }
+}
+
+/// Move the consumed queue file at `path` into the retained directory
+/// alongside it
+pub fn retain(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(parent) => parent.join(RETAINED_DIR),
+        None => PathBuf::from(RETAINED_DIR),
+    };
+    fs::create_dir_all(&dir)?;
+    let name = path.file_name().expect("queue file has no name");
+    let dest = dir.join(name);
+    summary::rename(path, &dest);
+    index::rename(path, &dest);
+    fs::rename(path, dest)
+}
+
+/// Delete the files retained under `root` and `archive` that `retention` no
+/// longer covers as of `now`, returning the number of bytes freed
+pub fn collect(root: &Path, archive: Option<&Path>, retention: &Retention, now: SystemTime) -> u64 {
+    let lane = (root.to_path_buf(), archive.map(Path::to_path_buf));
+    collect_lanes(&[lane], retention, now)
+}
+
+/// Delete the files retained under the directories of `lanes`, each with its
+/// archive directory, that `retention` no longer covers as of `now`,
+/// returning the number of bytes freed
+///
+/// The lanes of a channel with priority lanes share `keep_bytes`, highest
+/// priority first: past it a lower lane's files go before a higher lane's.
+pub fn collect_lanes(
+    lanes: &[(PathBuf, Option<PathBuf>)],
+    retention: &Retention,
+    now: SystemTime,
+) -> u64 {
+    let mut retained = Vec::new();
+    for (rank, (root, archive)) in lanes.iter().enumerate() {
+        for dir in Some(root).into_iter().chain(archive) {
+            let dir = dir.join(RETAINED_DIR);
+            if !dir.is_dir() {
+                continue;
+            }
+            for id in private::segment_ids(&dir, None) {
+                let path = dir.join(format!("{}", id));
+                if let Ok(md) = fs::metadata(&path) {
+                    let newest = summary::newest_item(&path);
+                    let age = newest
+                        .and_then(|t| now.duration_since(t).ok())
+                        .unwrap_or_default();
+                    retained.push((rank, id, path, md.len(), age));
+                }
+            }
+        }
+    }
+    // Higher lanes first and newest first, so the byte budget is spent on
+    // the most important and most recent data.
+    retained.sort_by_key(|r| (r.0, cmp::Reverse(r.1)));
+    let mut kept_bytes: u64 = 0;
+    let mut freed = 0;
+    for (_, _, path, len, age) in retained {
+        kept_bytes += len;
+        let too_old = retention.keep_for.is_some_and(|keep_for| age > keep_for);
+        let too_big = retention.keep_bytes.is_some_and(|keep| kept_bytes > keep);
+        if (too_old || too_big) && private::remove_segment(&path).is_ok() {
+            freed += len;
+        }
+    }
+    freed
+}
+
+/// What `Receiver::purge_before` deleted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PurgeReport {
+    /// The number of queue files deleted, retained files included
+    pub segments: usize,
+    /// The number of items in the deleted files
+    pub records: u64,
+    /// The bytes of the deleted files
+    pub bytes: u64,
+    /// The number of items sent before the cutoff still held in memory or in
+    /// the queue file being read, to be dropped as the Receiver reaches them
+    pub pending: u64,
+}
+
+impl PurgeReport {
+    pub(crate) fn add(&mut self, other: &PurgeReport) {
+        self.segments += other.segments;
+        self.records += other.records;
+        self.bytes += other.bytes;
+        self.pending += other.pending;
+    }
+}
+
+/// Delete the files retained under `root` and `archive` whose items were all
+/// sent before `cutoff`, returning the number of files, items and bytes
+/// deleted
+pub fn purge_before(root: &Path, archive: Option<&Path>, cutoff: SystemTime) -> (usize, u64, u64) {
+    let (mut segments, mut records, mut bytes) = (0, 0, 0);
+    for dir in Some(root).into_iter().chain(archive) {
+        let dir = dir.join(RETAINED_DIR);
+        if !dir.is_dir() {
+            continue;
+        }
+        for id in private::segment_ids(&dir, None) {
+            let path = dir.join(format!("{}", id));
+            if summary::newest_item(&path).is_none_or(|newest| newest >= cutoff) {
+                continue;
+            }
+            if let Some((count, len)) = private::segment_contents(&path) {
+                if private::remove_segment(&path).is_ok() {
+                    segments += 1;
+                    records += count;
+                    bytes += len;
+                }
+            }
+        }
+    }
+    (segments, records, bytes)
+}
+
+/// Collect the files retained under `root` and `archive` from a background
+/// thread, or a job of `runtime` if given, until the channel whose metrics are
+/// `alive` is dropped, aging them by `clock`
+///
+/// Only `keep_for` needs the thread: retained bytes grow only as the Receiver
+/// retains files, and it collects as it does so.
+pub fn spawn_collector(
+    root: PathBuf,
+    archive: Option<PathBuf>,
+    retention: Retention,
+    alive: Weak<Metrics>,
+    clock: Arc<dyn Clock>,
+    runtime: Option<&Runtime>,
+    supervisor: &Supervisor,
+) {
+    let keep_for = match retention.keep_for {
+        Some(keep_for) => keep_for,
+        None => return,
+    };
+    let interval = cmp::max(
+        cmp::min(keep_for / 2, Duration::from_secs(1)),
+        Duration::from_millis(1),
+    );
+    if let Some(runtime) = runtime {
+        runtime.spawn(supervisor.job(Task::Collector, move || {
+            if alive.upgrade().is_none() {
+                return Next::Done;
+            }
+            collect(&root, archive.as_deref(), &retention, clock.wall());
+            Next::After(interval)
+        }));
+        return;
+    }
+    supervisor
+        .spawn(Task::Collector, move || {
+            while alive.upgrade().is_some() {
+                collect(&root, archive.as_deref(), &retention, clock.wall());
+                thread::sleep(interval);
+            }
+        })
+        .expect("could not start retention collector");
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::*;
+
+    fn queue_file(dir: &Path, id: usize, len: usize) -> PathBuf {
+        let path = dir.join(format!("{}", id));
+        fs::write(&path, vec![0; len]).unwrap();
+        path
+    }
+
+    #[test]
+    fn retained_files_leave_the_queue() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let path = queue_file(dir.path(), 0, 8);
+        retain(&path).unwrap();
+        assert!(!path.exists());
+        assert!(dir.path().join(RETAINED_DIR).join("0").exists());
+        assert!(private::segment_ids(dir.path(), None).is_empty());
+    }
+
+    #[test]
+    fn keep_bytes_drops_the_oldest_files() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        for id in 0..4 {
+            retain(&queue_file(dir.path(), id, 10)).unwrap();
+        }
+        let retention = Retention {
+            keep_for: None,
+            keep_bytes: Some(25),
+        };
+        let now = SystemTime::now();
+        assert_eq!(20, collect(dir.path(), None, &retention, now));
+        let mut left = private::segment_ids(&dir.path().join(RETAINED_DIR), None);
+        left.sort();
+        assert_eq!(vec![2, 3], left);
+        assert_eq!(0, collect(dir.path(), None, &retention, now));
+    }
+
+    #[test]
+    fn keep_bytes_drops_lower_lanes_first() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (high, low) = (dir.path().join("high"), dir.path().join("low"));
+        for lane in &[&high, &low] {
+            fs::create_dir(lane).unwrap();
+            for id in 0..2 {
+                retain(&queue_file(lane, id, 10)).unwrap();
+            }
+        }
+        let retention = Retention {
+            keep_for: None,
+            keep_bytes: Some(25),
+        };
+        let lanes = [(high.clone(), None), (low.clone(), None)];
+        assert_eq!(20, collect_lanes(&lanes, &retention, SystemTime::now()));
+        assert_eq!(2, private::segment_ids(&high.join(RETAINED_DIR), None).len());
+        assert!(private::segment_ids(&low.join(RETAINED_DIR), None).is_empty());
+    }
+
+    #[test]
+    fn keep_for_drops_files_once_they_age() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        retain(&queue_file(dir.path(), 0, 10)).unwrap();
+        let retention = Retention {
+            keep_for: Some(Duration::from_secs(3600)),
+            keep_bytes: None,
+        };
+        let now = SystemTime::now();
+        assert_eq!(0, collect(dir.path(), None, &retention, now));
+        let later = now + Duration::from_secs(3601);
+        assert_eq!(10, collect(dir.path(), None, &retention, later));
+        assert!(private::segment_ids(&dir.path().join(RETAINED_DIR), None).is_empty());
+    }
+
+    #[test]
+    fn keep_for_ages_summarised_files_by_their_newest_item() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let path = queue_file(dir.path(), 0, 10);
+        let sent = SystemTime::now() - Duration::from_secs(7200);
+        let stamp = sent.duration_since(::std::time::UNIX_EPOCH).unwrap();
+        let summary = summary::SegmentSummary {
+            records: 1,
+            items: 1,
+            first_stamp: Some(stamp.as_millis() as u64),
+            last_stamp: Some(stamp.as_millis() as u64),
+            ..Default::default()
+        };
+        summary::write(&path, &summary).unwrap();
+        retain(&path).unwrap();
+        let retained = dir.path().join(RETAINED_DIR).join("0");
+        assert_eq!(Some(summary), summary::read(&retained));
+
+        let retention = Retention {
+            keep_for: Some(Duration::from_secs(3600)),
+            keep_bytes: None,
+        };
+        assert_eq!(10, collect(dir.path(), None, &retention, SystemTime::now()));
+        assert!(!summary::path(&retained).exists());
+    }
+
+    #[test]
+    fn purge_before_deletes_retained_files_sent_before_the_cutoff() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let old = queue_file(dir.path(), 0, 10);
+        let sent = SystemTime::now() - Duration::from_secs(60);
+        let stamp = sent.duration_since(::std::time::UNIX_EPOCH).unwrap();
+        let summary = summary::SegmentSummary {
+            records: 1,
+            items: 1,
+            first_stamp: Some(stamp.as_millis() as u64),
+            last_stamp: Some(stamp.as_millis() as u64),
+            ..Default::default()
+        };
+        summary::write(&old, &summary).unwrap();
+        retain(&old).unwrap();
+        retain(&queue_file(dir.path(), 1, 20)).unwrap();
+
+        let cutoff = SystemTime::now() - Duration::from_secs(30);
+        assert_eq!((1, 1, 10), purge_before(dir.path(), None, cutoff));
+        let left = private::segment_ids(&dir.path().join(RETAINED_DIR), None);
+        assert_eq!(vec![1], left);
+        assert_eq!((0, 0, 0), purge_before(dir.path(), None, cutoff));
+    }
+}
@@ -0,0 +1,137 @@
+//! What a channel does when writing its queue files fails
+//!
+//! An error that may pass on its own--a full disk or quota, a timeout, an
+//! interrupted or refused call--is retried. The write is undone, the disk
+//! buffer left in memory and paging out tried again after a pause that
+//! doubles with each failure in a row, up to `MAX_BACKOFF`. Any other error,
+//! and a retryable one that outlasts the channel's retries, stops the channel
+//! paging out for good: from then on it holds items in memory only.
+use std::cmp;
+use std::io::{self, ErrorKind};
+use std::time::{Duration, Instant};
+
+/// The longest pause between attempts to page out
+pub const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Whether `err` may pass if the write is tried again
+pub fn is_retryable(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::StorageFull
+            | ErrorKind::QuotaExceeded
+            | ErrorKind::TimedOut
+            | ErrorKind::WouldBlock
+            | ErrorKind::Interrupted
+    )
+}
+
+/// How a channel retries failed writes, as set on the builder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    // The failures in a row that are retried before giving up
+    pub retries: u32,
+    // The pause after the first failure
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            retries: 5,
+            backoff: Duration::from_millis(10),
+        }
+    }
+}
+
+/// How paging out has fared under a `RetryPolicy`
+#[derive(Debug, Clone, Copy)]
+pub struct Retries {
+    policy: RetryPolicy,
+    failures: u32,
+    resume: Option<Instant>,
+    fatal: Option<ErrorKind>,
+}
+
+impl Retries {
+    /// Retries under `policy` that have yet to see a failure
+    pub fn new(policy: RetryPolicy) -> Retries {
+        Retries {
+            policy: policy,
+            failures: 0,
+            resume: None,
+            fatal: None,
+        }
+    }
+
+    /// Whether paging out may be tried at `now`
+    pub fn may_write(&self, now: Instant) -> bool {
+        self.fatal.is_none() && self.resume.is_none_or(|resume| now >= resume)
+    }
+
+    /// The kind of error that stopped paging out for good, if one has
+    pub fn fatal(&self) -> Option<ErrorKind> {
+        self.fatal
+    }
+
+    /// Record that paging out failed with `err` at `now`, returning whether
+    /// it will be tried again
+    pub fn failed(&mut self, err: &io::Error, now: Instant) -> bool {
+        self.failures = self.failures.saturating_add(1);
+        if is_retryable(err) && self.failures <= self.policy.retries {
+            let doublings = cmp::min(self.failures - 1, 16);
+            let pause = self
+                .policy
+                .backoff
+                .checked_mul(1 << doublings)
+                .map_or(MAX_BACKOFF, |pause| cmp::min(pause, MAX_BACKOFF));
+            self.resume = Some(now + pause);
+            true
+        } else {
+            self.resume = None;
+            self.fatal = Some(err.kind());
+            false
+        }
+    }
+
+    /// Record that paging out succeeded
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+        self.resume = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retryable_errors_back_off_then_turn_fatal() {
+        let policy = RetryPolicy {
+            retries: 2,
+            backoff: Duration::from_millis(10),
+        };
+        let mut retries = Retries::new(policy);
+        let now = Instant::now();
+        let full = io::Error::new(ErrorKind::StorageFull, "no space left on device");
+        assert!(retries.failed(&full, now));
+        assert!(!retries.may_write(now));
+        assert!(retries.may_write(now + Duration::from_millis(10)));
+        assert!(retries.failed(&full, now));
+        assert!(!retries.may_write(now + Duration::from_millis(10)));
+        assert!(retries.may_write(now + Duration::from_millis(20)));
+
+        // Success starts the count again
+        retries.succeeded();
+        assert!(retries.failed(&full, now));
+        assert!(retries.failed(&full, now));
+        assert!(!retries.failed(&full, now));
+        assert_eq!(Some(ErrorKind::StorageFull), retries.fatal());
+        assert!(!retries.may_write(now + MAX_BACKOFF));
+
+        // Other errors are fatal from the first
+        let mut retries = Retries::new(policy);
+        let denied = io::Error::new(ErrorKind::PermissionDenied, "permission denied");
+        assert!(!retries.failed(&denied, now));
+        assert_eq!(Some(ErrorKind::PermissionDenied), retries.fatal());
+    }
+}
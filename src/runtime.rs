@@ -0,0 +1,421 @@
+//! A pool of threads shared by many channels' background work
+//!
+//! Left to themselves channels start a thread for each piece of background
+//! work they are given: one for each `Sender::spawn_flusher`, one to collect
+//! retained files for a `keep_for` retention and one for a Receiver's
+//! `ChannelBuilder::read_ahead`. A process with hundreds of channels ends up
+//! with hundreds of threads, nearly all of them asleep. Channels built with
+//! `ChannelBuilder::runtime` instead hand that work to a `Runtime`, which runs
+//! it for all of them on a fixed pool of threads.
+//!
+//! Each piece of work is a job the pool runs whenever it is due: a flusher
+//! every interval and as soon as its threshold is reached, a collector every
+//! interval, a read-ahead whenever its Receiver has taken records from it. A
+//! job runs on one thread at a time, and a read-ahead stages a bounded number
+//! of records before making way for the next job. Writes made by the sends
+//! that fill a disk buffer, and the syncs of `process` channels, stay with
+//! the thread doing the sending.
+//!
+//! With the `sim` feature a `sim::Simulation` is a Runtime with no threads
+//! at all, whose jobs run on a virtual clock as a test drives it.
+use clock::{Clock, SystemClock};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use sync::{self, Condvar, Mutex, MutexGuard};
+#[cfg(feature = "sim")]
+use testing::ManualClock;
+
+/// When a job wants to run again, as returned from each run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Next {
+    /// Once `Duration` has passed, or sooner if woken
+    After(Duration),
+    /// Once woken
+    Woken,
+    /// Never: the job is finished with
+    Done,
+}
+
+type Job = Box<dyn FnMut() -> Next + Send>;
+
+/// A pool of threads running channels' background work, see the module
+/// documentation
+///
+/// Clones refer to the same pool. Its threads exit once every clone has been
+/// dropped and every channel built with it has finished its work.
+#[derive(Clone)]
+pub struct Runtime {
+    pool: Arc<Pool>,
+}
+
+// Stops the pool's threads as the last Runtime goes.
+struct Pool {
+    shared: Arc<Shared>,
+    threads: usize,
+    // The clock a simulation moves on as it is driven
+    #[cfg(feature = "sim")]
+    virtual_clock: Option<Arc<ManualClock>>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    cond: Condvar,
+    // The time jobs come due by: the real clock, or a simulation's
+    clock: Arc<dyn Clock>,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    // The jobs not running, with when each is next due, if it is
+    idle: HashMap<u64, (Job, Option<Instant>)>,
+    // When idle jobs are due, earliest first. An entry no longer matching its
+    // job's due time, or whose job is running, is stale and passed over.
+    due: BinaryHeap<Reverse<(Instant, u64)>>,
+    running: HashSet<u64>,
+    // Running jobs woken since they started, to run again once they return
+    rewoken: HashSet<u64>,
+    stop: bool,
+}
+
+/// Wakes a job of a `Runtime`
+pub struct JobHandle {
+    id: u64,
+    shared: Weak<Shared>,
+}
+
+impl fmt::Debug for Runtime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Runtime")
+            .field("threads", &self.threads())
+            .field("jobs", &self.jobs())
+            .finish()
+    }
+}
+
+impl fmt::Debug for JobHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JobHandle").field("id", &self.id).finish()
+    }
+}
+
+impl Runtime {
+    /// A pool of `threads` threads, at least one
+    ///
+    /// Panics where threads cannot be started, as under WASI.
+    pub fn new(threads: usize) -> Runtime {
+        let threads = if threads == 0 { 1 } else { threads };
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            cond: Condvar::new(),
+            clock: Arc::new(SystemClock),
+        });
+        for _ in 0..threads {
+            let shared = Arc::clone(&shared);
+            thread::Builder::new()
+                .name("hopper-runtime".to_string())
+                .spawn(move || work(&shared))
+                .expect("could not start runtime thread");
+        }
+        Runtime {
+            pool: Arc::new(Pool {
+                shared: shared,
+                threads: threads,
+                #[cfg(feature = "sim")]
+                virtual_clock: None,
+            }),
+        }
+    }
+
+    /// A Runtime with no threads, whose jobs run on `clock` as `step` and
+    /// `advance_to` are called
+    #[cfg(feature = "sim")]
+    pub(crate) fn driven(clock: Arc<ManualClock>) -> Runtime {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            cond: Condvar::new(),
+            clock: Arc::clone(&clock) as Arc<dyn Clock>,
+        });
+        Runtime {
+            pool: Arc::new(Pool {
+                shared: shared,
+                threads: 0,
+                virtual_clock: Some(clock),
+            }),
+        }
+    }
+
+    /// Return the number of threads in the pool, none for a simulation
+    pub fn threads(&self) -> usize {
+        self.pool.threads
+    }
+
+    /// Return the number of jobs the pool has, running or waiting
+    pub fn jobs(&self) -> usize {
+        let state = self.pool.shared.lock();
+        state.idle.len() + state.running.len()
+    }
+
+    /// Run `job` now and then again whenever it asks to be
+    pub(crate) fn spawn<F>(&self, job: F) -> JobHandle
+    where
+        F: FnMut() -> Next + Send + 'static,
+    {
+        let shared = &self.pool.shared;
+        let mut state = shared.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+        let now = shared.clock.now();
+        state.idle.insert(id, (Box::new(job), Some(now)));
+        state.due.push(Reverse((now, id)));
+        drop(state);
+        shared.cond.notify_one();
+        JobHandle {
+            id: id,
+            shared: Arc::downgrade(shared),
+        }
+    }
+
+    /// Whether the Runtime is a simulation, its jobs run only as it is driven
+    pub(crate) fn is_driven(&self) -> bool {
+        self.pool.threads == 0
+    }
+
+    /// The time by the clock the Runtime's jobs come due by
+    pub(crate) fn now(&self) -> Instant {
+        self.pool.shared.clock.now()
+    }
+
+    /// Run a job due now on the calling thread or, with none due, move the
+    /// clock on to the next and run that. Returns false, having run nothing,
+    /// if the Runtime has no job waiting to come due.
+    pub(crate) fn step(&self) -> bool {
+        if self.run_due() {
+            return true;
+        }
+        match self.next_due() {
+            Some(at) => {
+                self.advance_to(at);
+                self.run_due()
+            }
+            None => false,
+        }
+    }
+
+    /// Run a job due now on the calling thread, returning whether there was
+    /// one
+    pub(crate) fn run_due(&self) -> bool {
+        let shared = &self.pool.shared;
+        let taken = shared.lock().take_due(shared.clock.now());
+        match taken {
+            Ok((id, mut job)) => {
+                let next = job();
+                shared.lock().finish(id, job, next, shared.clock.now());
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Run `job` on the calling thread whether or not it is due, moving a
+    /// simulation's clock on to when it is if that is later. Returns false,
+    /// having run nothing, if the job is running or finished with.
+    pub(crate) fn run_job(&self, job: &JobHandle) -> bool {
+        let shared = &self.pool.shared;
+        let mut state = shared.lock();
+        let (mut work, due) = match state.idle.remove(&job.id) {
+            Some(idle) => idle,
+            None => return false,
+        };
+        state.running.insert(job.id);
+        drop(state);
+        if let Some(at) = due {
+            self.advance_to(at);
+        }
+        let next = work();
+        shared.lock().finish(job.id, work, next, shared.clock.now());
+        true
+    }
+
+    /// When the next job not running is due, if one is
+    pub(crate) fn next_due(&self) -> Option<Instant> {
+        self.pool.shared.lock().next_due()
+    }
+
+    /// Move a simulation's clock on to `at`, if it stands before then
+    pub(crate) fn advance_to(&self, at: Instant) {
+        #[cfg(feature = "sim")]
+        {
+            if let Some(ref clock) = self.pool.virtual_clock {
+                let now = clock.now();
+                if at > now {
+                    clock.advance(at - now);
+                }
+            }
+        }
+        #[cfg(not(feature = "sim"))]
+        let _ = at;
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        self.shared.lock().stop = true;
+        self.shared.cond.notify_all();
+    }
+}
+
+impl JobHandle {
+    /// Have the job run as soon as a thread is free, if it is not finished
+    pub fn wake(&self) {
+        let shared = match self.shared.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
+        let mut state = shared.lock();
+        if state.running.contains(&self.id) {
+            state.rewoken.insert(self.id);
+            return;
+        }
+        let now = shared.clock.now();
+        let woken = match state.idle.get_mut(&self.id) {
+            Some(&mut (_, ref mut due)) if due.is_none_or(|due| due > now) => {
+                *due = Some(now);
+                true
+            }
+            _ => false,
+        };
+        if woken {
+            state.due.push(Reverse((now, self.id)));
+            drop(state);
+            shared.cond.notify_one();
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        sync::lock(&self.state)
+    }
+}
+
+impl State {
+    // When the first idle job is due, passing over stale entries.
+    fn next_due(&mut self) -> Option<Instant> {
+        while let Some(&Reverse((at, id))) = self.due.peek() {
+            if self.idle.get(&id).is_some_and(|&(_, due)| due == Some(at)) {
+                return Some(at);
+            }
+            self.due.pop();
+        }
+        None
+    }
+
+    // Take the first job due by `now`, or say when the next will be.
+    fn take_due(&mut self, now: Instant) -> Result<(u64, Job), Option<Instant>> {
+        match self.next_due() {
+            Some(at) if at <= now => {
+                let Reverse((_, id)) = self.due.pop().expect("due job is queued");
+                let (job, _) = self.idle.remove(&id).expect("due job is idle");
+                self.running.insert(id);
+                Ok((id, job))
+            }
+            next => Err(next),
+        }
+    }
+
+    // Put back a job that has run at `now`, as `next` says.
+    fn finish(&mut self, id: u64, job: Job, next: Next, now: Instant) {
+        self.running.remove(&id);
+        let rewoken = self.rewoken.remove(&id);
+        let due = match next {
+            Next::Done => return,
+            _ if rewoken => Some(now),
+            Next::After(wait) => Some(now + wait),
+            Next::Woken => None,
+        };
+        if let Some(due) = due {
+            self.due.push(Reverse((due, id)));
+        }
+        self.idle.insert(id, (job, due));
+    }
+}
+
+// A pool thread: run jobs as they come due until the pool stops.
+fn work(shared: &Shared) {
+    let mut state = shared.lock();
+    loop {
+        if state.stop {
+            return;
+        }
+        match state.take_due(shared.clock.now()) {
+            Ok((id, mut job)) => {
+                drop(state);
+                let next = job();
+                state = shared.lock();
+                state.finish(id, job, next, shared.clock.now());
+                // Another thread may be asleep past the job's new due time.
+                shared.cond.notify_one();
+            }
+            Err(Some(at)) => {
+                let wait = at.saturating_duration_since(shared.clock.now());
+                state = sync::wait_timeout(&shared.cond, state, wait);
+            }
+            Err(None) => state = sync::wait(&shared.cond, state),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn jobs_run_when_due_and_when_woken() {
+        let runtime = Runtime::new(2);
+        let runs = Arc::new(AtomicUsize::new(0));
+        let job_runs = Arc::clone(&runs);
+        let job = runtime.spawn(move || {
+            if job_runs.fetch_add(1, Ordering::SeqCst) == 2 {
+                Next::Done
+            } else {
+                Next::Woken
+            }
+        });
+        let wait_for = |n: usize| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while runs.load(Ordering::SeqCst) < n && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(1));
+            }
+            runs.load(Ordering::SeqCst)
+        };
+        assert_eq!(1, wait_for(1));
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(1, runs.load(Ordering::SeqCst));
+        job.wake();
+        assert_eq!(2, wait_for(2));
+        job.wake();
+        assert_eq!(3, wait_for(3));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while runtime.jobs() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(0, runtime.jobs());
+        job.wake();
+
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let job_ticks = Arc::clone(&ticks);
+        runtime.spawn(move || {
+            job_ticks.fetch_add(1, Ordering::SeqCst);
+            Next::After(Duration::from_millis(1))
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(ticks.load(Ordering::SeqCst) > 5);
+        assert_eq!(1, runtime.jobs());
+    }
+}
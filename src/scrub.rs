@@ -0,0 +1,252 @@
+//! Scrubbing of retained queue files
+//!
+//! Retained files are kept for replay and audit, often long after they were
+//! read, so damage to them would otherwise go unnoticed until a replay needs
+//! them. A channel built with `ChannelBuilder::scrub_retained` reads its
+//! retained files back in the background, one file each interval, cycling
+//! through them for as long as the channel lives.
+//!
+//! A file is sound if `segment::parse` reads its header and every one of its
+//! records whole and, where the channel packs its records into blocks, every
+//! block passes its checksum: queue files carry no checksums otherwise. A
+//! damaged file is
+//! counted in `QueueMetrics::total_corrupt_segments`, raised with
+//! `QueueEvent::CorruptSegment` and, if the channel quarantines, moved into
+//! the `corrupt` subdirectory `repair` uses, beside the retained directory it
+//! was found in.
+use block;
+use metrics::Metrics;
+use private::{self, Observer};
+use repair::QUARANTINE_DIR;
+use retention::RETAINED_DIR;
+use runtime::{Next, Runtime};
+use segment::{self, ParseError};
+use supervise::{Supervisor, Task};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Weak;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+use super::QueueEvent;
+
+/// Damage found in a retained file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Damage {
+    /// The queue file's sequence number
+    pub segment: usize,
+    /// Where in the file reading stopped: the start of the first record that
+    /// is not whole or whose block fails its checksum, or 0 if the header is
+    /// not one this hopper reads
+    pub offset: u64,
+    /// Whether the file was moved into the quarantine directory
+    pub quarantined: bool,
+}
+
+/// Walks the files retained under a channel's directory and archive, see the
+/// module documentation
+#[derive(Debug)]
+pub struct Scrubber {
+    root: PathBuf,
+    archive: Option<PathBuf>,
+    quarantine: bool,
+    // Whether the files' records are checksummed blocks
+    packed: bool,
+    // The id of the file last scrubbed
+    last: Option<usize>,
+}
+
+impl Scrubber {
+    /// A scrubber of the files retained under `root` and `archive`, moving
+    /// damaged files into quarantine if `quarantine`, whose records are
+    /// blocks if `packed`
+    pub fn new(
+        root: PathBuf,
+        archive: Option<PathBuf>,
+        quarantine: bool,
+        packed: bool,
+    ) -> Scrubber {
+        Scrubber {
+            root: root,
+            archive: archive,
+            quarantine: quarantine,
+            packed: packed,
+            last: None,
+        }
+    }
+
+    /// Scrub the retained file after the last one scrubbed, starting over
+    /// from the oldest once past the newest, returning the damage found in it
+    ///
+    /// Returns `None` as well if nothing is retained.
+    pub fn scrub_next(&mut self) -> Option<Damage> {
+        let mut retained = Vec::new();
+        for dir in Some(&self.root).into_iter().chain(self.archive.as_ref()) {
+            let dir = dir.join(RETAINED_DIR);
+            if dir.is_dir() {
+                let ids = private::segment_ids(&dir, None);
+                retained.extend(ids.into_iter().map(|id| (id, dir.clone())));
+            }
+        }
+        retained.sort();
+        let last = self.last;
+        let next = retained
+            .iter()
+            .find(|&&(id, _)| last.is_none_or(|last| id > last))
+            .or_else(|| retained.first())
+            .cloned();
+        let (id, dir) = next?;
+        self.last = Some(id);
+        let path = dir.join(format!("{}", id));
+        let buf = fs::read(&path).ok()?;
+        let offset = damaged_at(&buf, self.packed)?;
+        let quarantined = self.quarantine && quarantine(&dir, &path);
+        Some(Damage {
+            segment: id,
+            offset: offset,
+            quarantined: quarantined,
+        })
+    }
+}
+
+// Where the queue file `buf`, of blocks if `packed`, stops being readable, if
+// it does.
+fn damaged_at(buf: &[u8], packed: bool) -> Option<u64> {
+    let records = match segment::parse(buf) {
+        Ok(records) => records,
+        Err(_) => return Some(0),
+    };
+    for record in records {
+        match record {
+            Ok(record) => if packed && !block::intact(record.payload) {
+                return Some(record.offset as u64);
+            },
+            Err(ParseError::TornRecord { offset, .. })
+            | Err(ParseError::MissingSchemaVersion { offset }) => return Some(offset as u64),
+            Err(ParseError::UnknownFormat) => return Some(0),
+        }
+    }
+    None
+}
+
+// Move the damaged file at `path`, retained in `dir`, into the quarantine
+// directory beside `dir`, returning whether it was moved.
+fn quarantine(dir: &Path, path: &Path) -> bool {
+    let quarantine = match dir.parent() {
+        Some(parent) => parent.join(QUARANTINE_DIR),
+        None => return false,
+    };
+    let name = match path.file_name() {
+        Some(name) => name,
+        None => return false,
+    };
+    fs::create_dir_all(&quarantine).is_ok() && fs::rename(path, quarantine.join(name)).is_ok()
+}
+
+/// Run `scrubber` on a background thread, or as a job of `runtime` if given,
+/// one file every `interval`, until the channel whose metrics are `alive` is
+/// dropped
+///
+/// Damage found is counted in the metrics and handed to `observer`.
+pub fn spawn_scrubber(
+    mut scrubber: Scrubber,
+    interval: Duration,
+    alive: Weak<Metrics>,
+    observer: Option<Observer>,
+    runtime: Option<&Runtime>,
+    supervisor: &Supervisor,
+) {
+    let mut scrub = move || -> bool {
+        let metrics = match alive.upgrade() {
+            Some(metrics) => metrics,
+            None => return false,
+        };
+        if let Some(damage) = scrubber.scrub_next() {
+            metrics.total_corrupt_segments.fetch_add(1, Ordering::Relaxed);
+            if let Some(ref observer) = observer {
+                observer(QueueEvent::CorruptSegment {
+                    segment: damage.segment,
+                    offset: damage.offset,
+                    quarantined: damage.quarantined,
+                });
+            }
+        }
+        true
+    };
+    if let Some(runtime) = runtime {
+        runtime.spawn(supervisor.job(Task::Scrubber, move || if scrub() {
+            Next::After(interval)
+        } else {
+            Next::Done
+        }));
+        return;
+    }
+    supervisor
+        .spawn(Task::Scrubber, move || while scrub() {
+            thread::sleep(interval);
+        })
+        .expect("could not start scrubber");
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::*;
+    use layout;
+
+    #[test]
+    fn damaged_files_are_found_in_turn_and_quarantined() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let retained = dir.path().join(RETAINED_DIR);
+        fs::create_dir_all(&retained).unwrap();
+        let mut sound = layout::segment_header(layout::PLAIN_FORMAT_VERSION).to_vec();
+        sound.extend_from_slice(&[0, 0, 0, 2, 0xAA, 0xBB]);
+        let mut torn = sound.clone();
+        torn.extend_from_slice(&[0, 0, 0, 9, 0xCC]);
+        fs::write(retained.join("0"), &sound).unwrap();
+        fs::write(retained.join("1"), &torn).unwrap();
+        fs::write(retained.join("2"), b"HOP").unwrap();
+
+        let mut scrubber = Scrubber::new(dir.path().to_path_buf(), None, false, false);
+        assert_eq!(None, scrubber.scrub_next());
+        let damage = Damage {
+            segment: 1,
+            offset: 14,
+            quarantined: false,
+        };
+        assert_eq!(Some(damage), scrubber.scrub_next());
+        assert_eq!(0, scrubber.scrub_next().unwrap().offset);
+        // Back around to the first
+        assert_eq!(None, scrubber.scrub_next());
+
+        let mut scrubber = Scrubber::new(dir.path().to_path_buf(), None, true, false);
+        scrubber.scrub_next();
+        assert!(scrubber.scrub_next().unwrap().quarantined);
+        assert!(!retained.join("1").exists());
+        assert!(dir.path().join(QUARANTINE_DIR).join("1").exists());
+        assert_eq!(Some(2), scrubber.scrub_next().map(|damage| damage.segment));
+        assert_eq!(vec![0], private::segment_ids(&retained, None));
+    }
+
+    #[test]
+    fn blocks_failing_their_checksums_are_damage() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let retained = dir.path().join(RETAINED_DIR);
+        fs::create_dir_all(&retained).unwrap();
+        // Framed whole, but no block
+        let mut file = layout::segment_header(layout::PLAIN_FORMAT_VERSION).to_vec();
+        file.extend_from_slice(&[0, 0, 0, 2, 0xAA, 0xBB]);
+        fs::write(retained.join("0"), &file).unwrap();
+
+        let mut scrubber = Scrubber::new(dir.path().to_path_buf(), None, false, false);
+        assert_eq!(None, scrubber.scrub_next());
+        let mut scrubber = Scrubber::new(dir.path().to_path_buf(), None, false, true);
+        let damage = Damage {
+            segment: 0,
+            offset: layout::SEGMENT_HEADER_LEN as u64,
+            quarantined: false,
+        };
+        assert_eq!(Some(damage), scrubber.scrub_next());
+    }
+}
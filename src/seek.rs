@@ -0,0 +1,233 @@
+//! Rewinding a Receiver over its channel's retained queue files
+//!
+//! `Receiver::seek` replays items the Receiver has already delivered, read
+//! back from the queue files retention kept after it read past them, and
+//! from the part of the queue file it is reading that it has read. Files are
+//! read one at a time, as the replay reaches them. Where a retained file has
+//! a summary it is passed over whole if it ends before the target, see
+//! `summary`. The file the target is in is read from the record its index
+//! puts nearest before the target, see `index`, or without an index from its
+//! first record.
+use block;
+use dictionary::Dictionaries;
+use index::{self, IndexEntry, SegmentIndex};
+use layout;
+use private;
+use retention::RETAINED_DIR;
+use summary::{self, SegmentSummary};
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where `Receiver::seek` rewinds a Receiver to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekTarget {
+    /// The oldest item still retained
+    Beginning,
+    /// The item at this place among those the channel has paged out since it
+    /// was built, counting from 0, as `summary::SegmentSummary::first_seq`
+    /// counts them
+    Sequence(u64),
+    /// The first item sent at or after this time. Only a channel that stamps
+    /// its items with their send time can seek to one, see `RecordMeta`.
+    Timestamp(SystemTime),
+}
+
+// A queue file to replay, the part of it to read and the place of its first
+// item, if its summary records one
+#[derive(Debug)]
+struct Source {
+    path: PathBuf,
+    // The offset the records to replay end at, and the number of packed
+    // records at the end of the last block to leave out, for the file the
+    // Receiver is partway through
+    end: Option<(u64, usize)>,
+    first_seq: Option<u64>,
+}
+
+/// The items a Receiver replays, see the module documentation
+#[derive(Debug)]
+pub struct Replay {
+    target: SeekTarget,
+    sources: VecDeque<Source>,
+    // The records read from the file being replayed, still sealed if the
+    // channel encrypts, with the place of each if known
+    records: VecDeque<(Vec<u8>, Option<u64>)>,
+    // Whether the target has been reached
+    reached: bool,
+}
+
+impl Replay {
+    /// A replay of the files retained under `root` and `archive` from
+    /// `target`, followed by the file at `current` up to `end`, the
+    /// Receiver's offset in it, less the `unread` packed records of the block
+    /// it is partway through
+    pub fn new(
+        target: SeekTarget,
+        root: &Path,
+        archive: Option<&Path>,
+        current: Option<(PathBuf, u64, usize)>,
+    ) -> Replay {
+        let mut retained = Vec::new();
+        for dir in Some(root).into_iter().chain(archive) {
+            let dir = dir.join(RETAINED_DIR);
+            if dir.is_dir() {
+                retained.extend(
+                    private::segment_ids(&dir, None)
+                        .into_iter()
+                        .map(|id| (id, dir.join(format!("{}", id)))),
+                );
+            }
+        }
+        retained.sort();
+        let mut sources: VecDeque<Source> = retained
+            .into_iter()
+            .map(|(_, path)| {
+                let summary = summary::read(&path);
+                (path, summary)
+            })
+            .skip_while(|(_, summary)| summary.is_some_and(|s| before(target, &s)))
+            .map(|(path, summary)| Source {
+                path: path,
+                end: None,
+                first_seq: summary.map(|s| s.first_seq),
+            })
+            .collect();
+        sources.extend(current.map(|(path, end, unread)| Source {
+            path: path,
+            end: Some((end, unread)),
+            first_seq: None,
+        }));
+        Replay {
+            target: target,
+            sources: sources,
+            records: VecDeque::new(),
+            reached: target == SeekTarget::Beginning,
+        }
+    }
+
+    /// The next record to replay, still sealed if the channel encrypts, and
+    /// the place of its item if known, reading the next file if need be
+    ///
+    /// `packed` says whether the channel packs its records into blocks,
+    /// decompressed with `dictionaries`. Returns None once every file has
+    /// been replayed.
+    pub fn next_record(
+        &mut self,
+        packed: bool,
+        dictionaries: &mut Dictionaries,
+    ) -> io::Result<Option<(Vec<u8>, Option<u64>)>> {
+        loop {
+            if let Some(record) = self.records.pop_front() {
+                return Ok(Some(record));
+            }
+            let source = match self.sources.pop_front() {
+                Some(source) => source,
+                None => return Ok(None),
+            };
+            let next_seq = self.records_of(source, packed, dictionaries)?;
+            // A file without a summary carries on from the one before it.
+            if let (Some(next_seq), Some(after)) = (next_seq, self.sources.front_mut()) {
+                after.first_seq = after.first_seq.or(Some(next_seq));
+            }
+        }
+    }
+
+    /// Whether the item of the record at `seq`, sent at `stamp` in
+    /// milliseconds since the UNIX epoch, is at or past the target
+    ///
+    /// Every item after the first that is is replayed too.
+    pub fn reached(&mut self, seq: Option<u64>, stamp: Option<u64>) -> bool {
+        if !self.reached {
+            self.reached = match self.target {
+                SeekTarget::Beginning => true,
+                SeekTarget::Sequence(target) => seq.is_some_and(|seq| seq >= target),
+                SeekTarget::Timestamp(target) => {
+                    stamp.is_some_and(|stamp| stamp >= millis(target))
+                }
+            };
+        }
+        self.reached
+    }
+
+    // Read the records of `source` to replay, returning the place of the
+    // item after its last, if known.
+    fn records_of(
+        &mut self,
+        source: Source,
+        packed: bool,
+        dictionaries: &mut Dictionaries,
+    ) -> io::Result<Option<u64>> {
+        let mut fp = match fs::File::open(&source.path) {
+            Ok(fp) => fp,
+            // Collected by retention since the seek
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let entry = if self.reached {
+            None
+        } else {
+            index::read(&source.path).and_then(|index| self.nearest(&index, source.first_seq))
+        };
+        let (end, unread) = source.end.unwrap_or((u64::MAX, 0));
+        let entry = entry.filter(|entry| entry.offset <= end);
+        let from = entry.map_or(0, |entry| entry.offset);
+        let mut buf = Vec::new();
+        fp.seek(SeekFrom::Start(from))?;
+        fp.take(end - from).read_to_end(&mut buf)?;
+        let start = match entry {
+            Some(_) => 0,
+            None => layout::records_start(&buf).unwrap_or(buf.len()),
+        };
+        let mut rest = &buf[start..];
+        let mut payloads = VecDeque::new();
+        while let Some((record, tail)) = private::next_record(rest) {
+            if packed {
+                payloads.extend(block::unpack(record, dictionaries)?);
+            } else {
+                payloads.push_back(record.to_vec());
+            }
+            rest = tail;
+        }
+        for _ in 0..unread {
+            payloads.pop_back();
+        }
+        let skipped = entry.map_or(0, |entry| entry.items);
+        let mut seq = source.first_seq.map(|seq| seq + skipped);
+        for payload in payloads {
+            self.records.push_back((payload, seq));
+            seq = seq.map(|seq| seq + 1);
+        }
+        Ok(seq)
+    }
+
+    // The entry of `index`, of the file whose first item is at `first_seq`,
+    // nearest before the target, if the index puts any there
+    fn nearest(&self, index: &SegmentIndex, first_seq: Option<u64>) -> Option<IndexEntry> {
+        match self.target {
+            SeekTarget::Beginning => None,
+            SeekTarget::Sequence(target) => {
+                first_seq.and_then(|first| index.by_items(target.saturating_sub(first)))
+            }
+            SeekTarget::Timestamp(target) => index.before_stamp(millis(target)),
+        }
+    }
+}
+
+// Whether every item of the file `summary` describes comes before `target`.
+fn before(target: SeekTarget, summary: &SegmentSummary) -> bool {
+    match target {
+        SeekTarget::Beginning => false,
+        SeekTarget::Sequence(target) => summary.items > 0 && summary.last_seq < target,
+        SeekTarget::Timestamp(target) => {
+            summary.last_stamp.is_some_and(|last| last < millis(target))
+        }
+    }
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
@@ -0,0 +1,261 @@
+//! Reading queue files
+//!
+//! A parser for the queue files channels page their items out to, for tools
+//! that read a channel's data without opening the channel. It touches
+//! nothing on disk: hand it the bytes of a queue file and it hands back the
+//! file's format version and its records, in order. The parser trusts none
+//! of its input. Whatever the bytes, it returns an error rather than
+//! panicking, and it never reads or allocates past the end of the buffer.
+//!
+//! A queue file is a header--the bytes `HOPQ` and the format version as a
+//! big-endian u32--followed by its records. Version 1 files, written before
+//! the header was, start straight away with their first record. Each record
+//! is a big-endian u32 length and that many bytes of payload. In version 3,
+//! written by `process` channels, each payload leads with a big-endian u32
+//! schema version, see `process::ProcessSender::set_schema_version`. What a
+//! payload holds otherwise depends on how its channel was built: see
+//! `inspect` for the send times and Sender ids some channels lead with.
+//!
+//! A record is valid here if it is completely framed. A file ending partway
+//! through a record, as a crash mid-write leaves it, ends with
+//! `ParseError::TornRecord`; `repair` cuts such files back to their last
+//! whole record. The records of a channel built with
+//! `ChannelBuilder::pack_records` are blocks, each with a checksum of its
+//! own, which the parser leaves to `verify` and `repair` to check.
+use layout;
+use std::error;
+use std::fmt;
+
+/// Why a queue file, or a record in it, could not be read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// The file starts with a header this hopper does not read, or with too
+    /// little of one to tell
+    UnknownFormat,
+    /// The file ends partway through the record starting at `offset`
+    TornRecord {
+        /// Where in the file the record starts
+        offset: usize,
+        /// The bytes of it the file holds
+        bytes: usize,
+    },
+    /// The record starting at `offset`, in a format whose payloads lead with
+    /// a schema version, is too short to hold one
+    MissingSchemaVersion {
+        /// Where in the file the record starts
+        offset: usize,
+    },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::UnknownFormat => f.write_str("queue file is of an unknown format"),
+            ParseError::TornRecord { offset, bytes } => write!(
+                f,
+                "queue file ends {} bytes into the record at offset {}",
+                bytes, offset
+            ),
+            ParseError::MissingSchemaVersion { offset } => write!(
+                f,
+                "record at offset {} is too short for its schema version",
+                offset
+            ),
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+/// A record of a queue file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record<'a> {
+    /// Where in the file the record starts, at its length
+    pub offset: usize,
+    /// The Sender's schema version, in formats that record one
+    pub schema_version: Option<u32>,
+    /// The record's payload, after its schema version if it has one
+    pub payload: &'a [u8],
+}
+
+/// The records of a queue file, see `parse`
+///
+/// Yields each record in turn. An error is the last thing yielded: past one
+/// the file cannot be read.
+#[derive(Debug, Clone)]
+pub struct Records<'a> {
+    buf: &'a [u8],
+    at: usize,
+    version: u32,
+    done: bool,
+}
+
+/// Parse the contents of a queue file, returning its records
+///
+/// An empty buffer is a queue file no record has been written to yet, of
+/// the current format. Returns `ParseError::UnknownFormat` if the header is
+/// of a format this hopper does not read.
+pub fn parse(buf: &[u8]) -> Result<Records<'_>, ParseError> {
+    let (version, start) = if buf.is_empty() {
+        (layout::FORMAT_VERSION, 0)
+    } else {
+        layout::segment_format(buf).ok_or(ParseError::UnknownFormat)?
+    };
+    Ok(Records {
+        buf: buf,
+        at: start,
+        version: version,
+        done: false,
+    })
+}
+
+impl<'a> Records<'a> {
+    /// The queue file's format version
+    pub fn format_version(&self) -> u32 {
+        self.version
+    }
+
+    /// Where in the file the next record starts
+    pub fn offset(&self) -> usize {
+        self.at
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<Record<'a>, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.at == self.buf.len() {
+            return None;
+        }
+        let offset = self.at;
+        let rest = &self.buf[offset..];
+        let torn = ParseError::TornRecord {
+            offset: offset,
+            bytes: rest.len(),
+        };
+        if rest.len() < 4 {
+            self.done = true;
+            return Some(Err(torn));
+        }
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        if rest.len() - 4 < len {
+            self.done = true;
+            return Some(Err(torn));
+        }
+        let mut payload = &rest[4..4 + len];
+        let schema_version = if layout::is_tagged(self.version) {
+            if payload.len() < 4 {
+                self.done = true;
+                return Some(Err(ParseError::MissingSchemaVersion { offset: offset }));
+            }
+            let version = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+            payload = &payload[4..];
+            Some(version)
+        } else {
+            None
+        };
+        self.at += 4 + len;
+        Some(Ok(Record {
+            offset: offset,
+            schema_version: schema_version,
+            payload: payload,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate quickcheck;
+    extern crate tempdir;
+
+    use self::quickcheck::{QuickCheck, TestResult};
+    use super::*;
+    use super::super::{channel_with_max_bytes, process};
+    use std::fs;
+
+    #[test]
+    fn reads_the_records_channels_write() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, _rcv) = channel_with_max_bytes("segment", dir.path(), 1 << 20).unwrap();
+        for i in 0..2048u32 {
+            snd.send(i);
+        }
+        snd.flush().unwrap();
+        let buf = fs::read(dir.path().join("segment").join("0")).unwrap();
+        let records = parse(&buf).unwrap();
+        assert_eq!(layout::PLAIN_FORMAT_VERSION, records.format_version());
+        let records: Vec<Record> = records.map(|r| r.unwrap()).collect();
+        assert_eq!(1024, records.len());
+        assert_eq!(None, records[0].schema_version);
+        assert_eq!(layout::SEGMENT_HEADER_LEN, records[0].offset);
+
+        let mut psnd = process::sender::<u32>("segment_process", dir.path(), 1 << 20).unwrap();
+        psnd.set_schema_version(3);
+        psnd.send(7);
+        let buf = fs::read(dir.path().join("segment_process").join("0")).unwrap();
+        let record = parse(&buf).unwrap().next().unwrap().unwrap();
+        assert_eq!(Some(3), record.schema_version);
+        assert_eq!(&[7, 0, 0, 0], record.payload);
+    }
+
+    #[test]
+    fn damaged_files_end_in_an_error() {
+        let mut buf = layout::segment_header(layout::PLAIN_FORMAT_VERSION).to_vec();
+        buf.extend_from_slice(&[0, 0, 0, 2, 0xAA, 0xBB]);
+        buf.extend_from_slice(&[0, 0, 0, 9, 0xCC]);
+        let mut records = parse(&buf).unwrap();
+        let first = records.next().unwrap().unwrap();
+        assert_eq!(&[0xAA, 0xBB], first.payload);
+        assert_eq!(
+            Some(Err(ParseError::TornRecord {
+                offset: 14,
+                bytes: 5,
+            })),
+            records.next()
+        );
+        assert_eq!(None, records.next());
+
+        let mut tagged = layout::segment_header(layout::FORMAT_VERSION).to_vec();
+        tagged.extend_from_slice(&[0, 0, 0, 1, 0]);
+        let err = parse(&tagged).unwrap().next().unwrap();
+        assert_eq!(Err(ParseError::MissingSchemaVersion { offset: 8 }), err);
+
+        assert_eq!(Some(ParseError::UnknownFormat), parse(b"HOPQ\0\0\0\x09").err());
+        assert_eq!(Some(ParseError::UnknownFormat), parse(b"HOP").err());
+        assert_eq!(0, parse(&[]).unwrap().count());
+    }
+
+    #[test]
+    fn any_bytes_parse_without_panicking() {
+        fn parses(header: Option<bool>, body: Vec<u8>) -> TestResult {
+            let mut buf = match header {
+                Some(tagged) => {
+                    let version = if tagged { 3 } else { 2 };
+                    layout::segment_header(version).to_vec()
+                }
+                None => Vec::new(),
+            };
+            buf.extend_from_slice(&body);
+            let records = match parse(&buf) {
+                Ok(records) => records,
+                Err(_) => return TestResult::passed(),
+            };
+            let mut end = records.offset();
+            for record in records {
+                let record = match record {
+                    Ok(record) => record,
+                    Err(_) => break,
+                };
+                assert_eq!(end, record.offset);
+                let tag = record.schema_version.map_or(0, |_| 4);
+                end = record.offset + 4 + tag + record.payload.len();
+                assert!(end <= buf.len());
+            }
+            TestResult::passed()
+        }
+        QuickCheck::new()
+            .tests(1000)
+            .quickcheck(parses as fn(Option<bool>, Vec<u8>) -> TestResult);
+    }
+}
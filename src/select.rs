@@ -0,0 +1,378 @@
+use super::Receiver;
+use private::{self, FsSync};
+use runtime::Runtime;
+use serde::de::DeserializeOwned;
+use sync::{self, Condvar, Mutex};
+use std::cmp;
+use std::fmt;
+use std::sync::Arc;
+use std::task::{Wake, Waker};
+use std::time::{Duration, Instant};
+
+// The type-erased view of a Receiver's shared state that Select needs: whether
+// any items are waiting and a way to be told when some arrive.
+trait Ready: Send + Sync {
+    fn is_ready(&self) -> bool;
+    // Register `waker`, returning the latest a wake the channel holds back
+    // may be owed to it, see `ChannelBuilder::coalesce_wakes`
+    fn register(&self, waker: Waker) -> Option<Instant>;
+    // The simulation the channel runs on, if any
+    fn simulation(&self) -> Option<Runtime>;
+    // Whether every Sender of the channel has gone or it has been closed
+    fn hung_up(&self) -> bool;
+}
+
+impl<T> Ready for Mutex<FsSync<T>>
+where
+    T: Send,
+{
+    fn is_ready(&self) -> bool {
+        private::lock(self).is_ready()
+    }
+
+    fn register(&self, waker: Waker) -> Option<Instant> {
+        let mut syn = private::lock(self);
+        let deadline = syn.wake_deadline();
+        let ready = syn.register_waker(waker);
+        drop(syn);
+        if let Some(waker) = ready {
+            waker.wake();
+        }
+        deadline
+    }
+
+    fn simulation(&self) -> Option<Runtime> {
+        private::lock(self).simulation()
+    }
+
+    fn hung_up(&self) -> bool {
+        private::lock(self).hung_up()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Signal {
+    woken: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl Wake for Signal {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        *sync::lock(&self.woken) = true;
+        // `ready` takes the Select mutably, so one thread at most waits here.
+        self.cond.notify_one();
+    }
+}
+
+/// Wait on several Receivers at once
+///
+/// A `Select` lets a single thread block until any one of a set of Receivers
+/// has an item available, whether that item is held in memory or has been
+/// paged out to disk. Receivers are added with `add`, which hands back the
+/// index that `ready` will return when that Receiver has data. The Receivers
+/// need not share an item type.
+///
+/// `Select` does not borrow the Receivers it watches. Once `ready` returns an
+/// index, pull from the matching Receiver as normal.
+///
+/// A `Select` watching a channel built on a `sim::Simulation` does not block.
+/// It runs the simulation's jobs in turn, moving its clock on from one to the
+/// next, until a Receiver has data. A timeout is measured on the
+/// simulation's clock. `ready` panics if the simulation runs out of jobs
+/// first, as nothing could ever arrive.
+///
+/// # Example
+/// ```
+/// extern crate tempdir;
+/// extern crate hopper;
+///
+/// let dir = tempdir::TempDir::new("hopper").unwrap();
+/// let (_, rcv_a) = hopper::channel::<u64>("a", dir.path()).unwrap();
+/// let (mut snd_b, mut rcv_b) = hopper::channel::<String>("b", dir.path()).unwrap();
+///
+/// let mut sel = hopper::Select::new();
+/// let a = sel.add(&rcv_a);
+/// let b = sel.add(&rcv_b);
+///
+/// snd_b.send("hello".to_string());
+/// assert_eq!(b, sel.ready());
+/// assert_ne!(a, b);
+/// assert_eq!(Some("hello".to_string()), rcv_b.try_iter().next());
+/// ```
+pub struct Select {
+    handles: Vec<Vec<Arc<dyn Ready>>>,
+    signal: Arc<Signal>,
+    next: usize,
+    // The simulation of the first channel added built on one
+    simulation: Option<Runtime>,
+}
+
+impl fmt::Debug for Select {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Select")
+            .field("receivers", &self.handles.len())
+            .finish()
+    }
+}
+
+impl Default for Select {
+    fn default() -> Select {
+        Select::new()
+    }
+}
+
+impl Select {
+    /// Create an empty `Select`
+    pub fn new() -> Select {
+        Select {
+            handles: Vec::new(),
+            signal: Arc::new(Signal::default()),
+            next: 0,
+            simulation: None,
+        }
+    }
+
+    /// Watch `receiver`, returning the index `ready` will report for it
+    pub fn add<T>(&mut self, receiver: &Receiver<T>) -> usize
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let mut lanes = vec![Arc::clone(receiver.fs_lock()) as Arc<dyn Ready>];
+        for lane in receiver.lanes() {
+            lanes.push(Arc::clone(lane.fs_lock()) as Arc<dyn Ready>);
+        }
+        if self.simulation.is_none() {
+            self.simulation = lanes[0].simulation();
+        }
+        self.handles.push(lanes);
+        self.handles.len() - 1
+    }
+
+    /// Return the index of a Receiver with data, if any, without blocking
+    ///
+    /// Receivers are polled starting just after the one most recently reported
+    /// ready so that a busy Receiver will not starve the others.
+    pub fn try_ready(&mut self) -> Option<usize> {
+        let total = self.handles.len();
+        for offset in 0..total {
+            let idx = (self.next + offset) % total;
+            if self.handles[idx].iter().any(|lane| lane.is_ready()) {
+                self.next = (idx + 1) % total;
+                return Some(idx);
+            }
+        }
+        None
+    }
+
+    /// Block until one of the Receivers has data and return its index
+    ///
+    /// # Panics
+    ///
+    /// Panics if no Receivers have been added, as the wait could never end.
+    pub fn ready(&mut self) -> usize {
+        assert!(!self.handles.is_empty(), "Select has no receivers");
+        loop {
+            if let Some(idx) = self.wait(None, false) {
+                return idx;
+            }
+        }
+    }
+
+    // Block until one of the Receivers has data, returning its index, or
+    // until the channel of every one has hung up or `deadline` comes,
+    // returning `None`. The last Sender of a channel wakes its wakers as it
+    // goes, so no wait outlasts it.
+    pub(crate) fn ready_or_hang_up(&mut self, deadline: Option<Instant>) -> Option<usize> {
+        loop {
+            if let Some(idx) = self.wait(deadline, true) {
+                return Some(idx);
+            }
+            if self.hung_up() || deadline.is_some_and(|deadline| self.now() >= deadline) {
+                return None;
+            }
+        }
+    }
+
+    fn hung_up(&self) -> bool {
+        self.handles.iter().all(|lanes| lanes.iter().all(|lane| lane.hung_up()))
+    }
+
+    /// Block for up to `timeout` until one of the Receivers has data
+    ///
+    /// Returns `None` if the timeout elapses with every Receiver empty.
+    pub fn ready_timeout(&mut self, timeout: Duration) -> Option<usize> {
+        let deadline = self.now() + timeout;
+        loop {
+            match self.wait(Some(deadline), false) {
+                Some(idx) => return Some(idx),
+                None => if self.now() >= deadline {
+                    return None;
+                },
+            }
+        }
+    }
+
+    // The time waits are measured by: the simulation's, if there is one
+    fn now(&self) -> Instant {
+        match self.simulation {
+            Some(ref simulation) => simulation.now(),
+            None => Instant::now(),
+        }
+    }
+
+    // Wait for one of the Receivers to have data until `deadline`, or, if
+    // `hang_up`, until every one's channel has hung up
+    fn wait(&mut self, deadline: Option<Instant>, hang_up: bool) -> Option<usize> {
+        if let Some(simulation) = self.simulation.clone() {
+            return self.run(&simulation, deadline, hang_up);
+        }
+        *sync::lock(&self.signal.woken) = false;
+        // Registration wakes the signal straight away if a Receiver already
+        // has data, so no send can slip between the check and the wait.
+        let waker = Waker::from(Arc::clone(&self.signal));
+        let mut deadline = deadline;
+        for lane in self.handles.iter().flat_map(|lanes| lanes.iter()) {
+            // A channel holding back a wake may owe it before the deadline,
+            // so look again by then
+            if let Some(owed) = lane.register(waker.clone()) {
+                deadline = Some(deadline.map_or(owed, |d| cmp::min(d, owed)));
+            }
+        }
+        if let Some(idx) = self.try_ready() {
+            return Some(idx);
+        }
+        // Looked at once registered, lest the last Sender go unseen between
+        if hang_up && self.hung_up() {
+            return None;
+        }
+        let mut woken = sync::lock(&self.signal.woken);
+        while !*woken {
+            match deadline {
+                None => {
+                    woken = sync::wait(&self.signal.cond, woken);
+                }
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    woken = sync::wait_timeout(&self.signal.cond, woken, deadline - now);
+                }
+            }
+        }
+        drop(woken);
+        self.try_ready()
+    }
+
+    // Wait as `wait` does on a simulation, which runs nothing of itself: run
+    // its jobs here, moving its clock on to each as it comes due, until a
+    // Receiver has data, the deadline comes or, if `hang_up`, every channel
+    // has hung up.
+    fn run(
+        &mut self,
+        simulation: &Runtime,
+        deadline: Option<Instant>,
+        hang_up: bool,
+    ) -> Option<usize> {
+        loop {
+            if let Some(idx) = self.try_ready() {
+                return Some(idx);
+            }
+            if hang_up && self.hung_up() {
+                return None;
+            }
+            if simulation.run_due() {
+                continue;
+            }
+            match (simulation.next_due(), deadline) {
+                (Some(at), Some(deadline)) if at > deadline => {
+                    simulation.advance_to(deadline);
+                    return self.try_ready();
+                }
+                (Some(at), _) => simulation.advance_to(at),
+                (None, Some(deadline)) => {
+                    simulation.advance_to(deadline);
+                    return self.try_ready();
+                }
+                (None, None) => panic!("simulation has no jobs left and no Receiver has data"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::Select;
+    use super::super::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn select_times_out_when_empty() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (_snd, rcv) = channel::<u32>("select_times_out", dir.path()).unwrap();
+
+        let mut sel = Select::new();
+        sel.add(&rcv);
+        assert_eq!(None, sel.try_ready());
+        assert_eq!(None, sel.ready_timeout(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn select_wakes_on_send_from_other_thread() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (_snd_a, rcv_a) = channel::<u32>("select_wakes_a", dir.path()).unwrap();
+        let (snd_b, mut rcv_b) = channel::<u32>("select_wakes_b", dir.path()).unwrap();
+
+        let mut sel = Select::new();
+        sel.add(&rcv_a);
+        let b = sel.add(&rcv_b);
+
+        let mut thr_snd = snd_b.clone();
+        let jh = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            thr_snd.send(7);
+        });
+        assert_eq!(b, sel.ready());
+        assert_eq!(Some(7), rcv_b.try_iter().next());
+        jh.join().expect("sender thread panicked");
+    }
+
+    #[test]
+    fn select_sees_disk_resident_items() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel::<u32>("select_disk", dir.path()).unwrap();
+        for i in 0..4096 {
+            snd.send(i);
+        }
+        for i in 0..2048 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+
+        let mut sel = Select::new();
+        let idx = sel.add(&rcv);
+        assert_eq!(Some(idx), sel.try_ready());
+    }
+
+    #[test]
+    fn select_rotates_between_ready_receivers() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd_a, rcv_a) = channel::<u32>("select_rotates_a", dir.path()).unwrap();
+        let (mut snd_b, rcv_b) = channel::<u32>("select_rotates_b", dir.path()).unwrap();
+        snd_a.send(1);
+        snd_b.send(2);
+
+        let mut sel = Select::new();
+        let a = sel.add(&rcv_a);
+        let b = sel.add(&rcv_b);
+        assert_eq!(a, sel.ready());
+        assert_eq!(b, sel.ready());
+        assert_eq!(a, sel.ready());
+    }
+}
@@ -1,21 +1,76 @@
-use bincode::{serialize_into, Infinite};
+use admission::Admission;
+use block;
+use bincode::{serialize_into, serialized_size, Infinite};
+#[cfg(feature = "encryption")]
+use crypt;
+use index;
+use layout;
+use metrics::{Metrics, QueueMetrics};
+use rate::{self, TokenBucket};
+use reserve::{Group, GroupMetrics};
+use runtime::Next;
+use supervise::{Health, Supervisor, Task};
+use summary;
+use sync::{self, Mutex};
+use super::{ConfigDelta, OrderMode, OverflowPolicy, Priority, QueueConfig, QueueEvent,
+            RatePolicy, SendError, SendTimeoutError};
 use private;
 use serde::{Deserialize, Serialize};
+use std::cmp;
 use std::fmt;
 use std::fs;
-use std::io::{BufWriter, Write};
+use std::io::{self, BufWriter, ErrorKind, Write};
 use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use topology;
+
+// The number of items a Sender in OrderMode::PerSender will hold before taking
+// the channel lock to publish them.
+const PER_SENDER_STAGE: usize = 32;
+
+// The longest the delay timer sleeps before looking whether its channel is
+// still open.
+const DELAY_TIMER_IDLE: Duration = Duration::from_secs(1);
+
+// The longest a Sender waits for room before looking again. The Receiver
+// wakes it as it takes items, but room may be made elsewhere too: in a
+// budget shared with other channels, or by `Sender::reconfigure`.
+const ROOM_RECHECK: Duration = Duration::from_millis(10);
+
+// An item on its way to the channel: the item, its encoded size if the
+// channel has a memory budget and its encoding if the Sender staged the write
+type Outgoing<T> = (T, Option<usize>, Option<Vec<u8>>);
+
+// How a wait for room in a full channel ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Room {
+    // There is room, the channel does not block or it has been closed
+    Made,
+    // The items would not fit the channel even empty
+    Never,
+    // The deadline passed first
+    TimedOut,
+}
 
 #[inline]
-fn u32tou8abe(v: u32) -> [u8; 4] {
+pub(crate) fn u32tou8abe(v: u32) -> [u8; 4] {
     [v as u8, (v >> 8) as u8, (v >> 24) as u8, (v >> 16) as u8]
 }
 
-#[derive(Debug)]
 /// The 'send' side of hopper, similar to
 /// [`std::sync::mpsc::Sender`](https://doc.rust-lang.org/std/sync/mpsc/struct.
 /// Sender.html).
+///
+/// A Sender formats with `{:?}` as the state of its channel--its occupancy,
+/// its place in the queue files and its configuration--and with `{}` as one
+/// line of the channel's metrics, whatever `T` is. Neither waits for the
+/// channel's lock: state the lock holds shows as `<locked>` while another
+/// thread has it.
 pub struct Sender<T> {
     name: String,
     root: PathBuf, // directory we store our queues in
@@ -23,21 +78,178 @@ pub struct Sender<T> {
     seq_num: usize,
     max_bytes: usize,
     fs_lock: private::FSLock<T>,
+    admission: Arc<Admission>,
+    metrics: Arc<Metrics>,
+    stage_limit: usize,
+    // Staged items, with their encoded size if the channel has a memory
+    // budget and their encoding if their write is staged
+    staged: Vec<Outgoing<T>>,
+    flush_on_drop: bool,
+    sized: bool,
+    // Whether the channel stages writes, and whether it was paging out as of
+    // this Sender's last hand over, so that items are encoded before it
+    stages_writes: bool,
+    paging: bool,
+    // The high and low priority lanes, if the channel has them
+    lanes: Vec<Sender<T>>,
+    // The token bucket sends take from, if the channel is rate limited
+    rate: Option<(RatePolicy, Arc<Mutex<TokenBucket>>)>,
+    // Whether sends wait for room in a full channel, memory-only, held at its
+    // disk quota or no longer able to write its queue files, and whether a
+    // full channel may be held at its quota
+    waits_for_room: bool,
+    disk_quota: bool,
+    // This Sender's id, stamped on its items where the channel records
+    // provenance, and the number of its items the channel has taken, the
+    // place of the next among them
+    id: u32,
+    sent: u64,
+    // The reservation group this Sender has joined, if any
+    group: Option<Arc<Group>>,
+    // Whether hopper holds this Sender itself to run background work, so
+    // that it does not keep the channel from hanging up
+    internal: bool,
+    // The share of an item `send_sampled` has offered and not yet kept
+    sample_credit: f64,
     resource_type: PhantomData<T>,
 }
 
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sender")
+            .field("name", &self.name)
+            .field("root", &self.root)
+            .field("path", &self.path)
+            .field("seq_num", &self.seq_num)
+            .field("max_bytes", &self.max_bytes)
+            .field("id", &self.id)
+            .field("sent", &self.sent)
+            .field("staged", &self.staged.len())
+            .field("stage_limit", &self.stage_limit)
+            .field("in_memory", &self.metrics.in_memory_depth.load(Ordering::Relaxed))
+            .field("in_memory_capacity", &self.metrics.in_memory_capacity.load(Ordering::Relaxed))
+            .field("segments_on_disk", &self.metrics.segments_on_disk.load(Ordering::Relaxed))
+            .field("disk_bytes", &self.metrics.disk_bytes.load(Ordering::Relaxed))
+            .field("rate", &self.rate.as_ref().map(|rate| rate.0))
+            .field("group", &self.group)
+            .field("lanes", &self.lanes)
+            .field("channel", &private::DebugState(&self.fs_lock))
+            .finish()
+    }
+}
+
+impl<T> fmt::Display for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (sender {}): {}", self.name, self.id, self.metrics.snapshot())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // Staged items are handed over without paging them to disk--doing so
+        // would need T: Serialize--and the next send to spill will write them
+        // out along with its own.
+        let mut syn = private::lock(&self.fs_lock);
+        syn.senders -= 1;
+        if self.internal {
+            syn.internal_senders -= 1;
+        }
+        // The last Sender wakes a Receiver waiting on items, which then finds
+        // the channel hung up
+        let mut wake = !self.internal && syn.live_senders() == 0;
+        if !self.staged.is_empty() && self.flush_on_drop {
+            if syn.closed {
+                self.staged.clear();
+            } else {
+                let was_empty = syn.writes_to_read == 0;
+                let sent = self.sent;
+                let mut refused = 0;
+                for (event, size, encoding) in self.staged.drain(..) {
+                    if syn.refuses() {
+                        refused += 1;
+                        continue;
+                    }
+                    self.metrics.total_enqueued.fetch_add(1, Ordering::Relaxed);
+                    self.metrics.in_memory_depth.fetch_add(1, Ordering::Relaxed);
+                    syn.admit(event, size, encoding, self.id, self.sent);
+                    self.sent += 1;
+                }
+                self.overflowed(refused);
+                if was_empty && syn.is_ready() {
+                    syn.signal_ready();
+                }
+                wake |= syn.wake_due(was_empty, (self.sent - sent) as usize);
+            }
+        }
+        let wakers = if wake {
+            mem::take(&mut syn.wakers)
+        } else {
+            Vec::new()
+        };
+        drop(syn);
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    /// Return the sender's name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[cfg(feature = "prometheus")]
+    pub(crate) fn shared_metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    // Count `items` refused by a full channel, as overflowed from memory or
+    // held at the disk quota.
+    fn overflowed(&self, items: u64) {
+        let counter = if self.disk_quota {
+            &self.metrics.total_disk_full
+        } else {
+            &self.metrics.total_overflowed
+        };
+        counter.fetch_add(items, Ordering::Relaxed);
+        if let Some(ref group) = self.group {
+            group.refused(self.disk_quota, items);
+        }
+    }
+
+    // The error refusing `item` from a full or closed channel.
+    fn refusal<I>(&self, item: I) -> SendError<I> {
+        let syn = private::lock(&self.fs_lock);
+        if syn.closed {
+            SendError::Closed(item)
+        } else if syn.write_failed() {
+            SendError::WriteFailed(item)
+        } else if self.disk_quota {
+            SendError::DiskFull(item)
+        } else {
+            SendError::Full(item)
+        }
+    }
+}
+
 impl<'de, T> Clone for Sender<T>
 where
     T: Serialize + Deserialize<'de>,
 {
     fn clone(&self) -> Sender<T> {
-        use std::sync::Arc;
-        Sender::new(
+        let mut sender = Sender::new(
             self.name.clone(),
             &self.root,
             self.max_bytes,
             Arc::clone(&self.fs_lock),
-        ).expect("COULD NOT CLONE")
+            Arc::clone(&self.metrics),
+        ).expect("COULD NOT CLONE");
+        sender.lanes = self.lanes.clone();
+        if let Some(ref group) = self.group {
+            sender.join_group(group.name());
+        }
+        sender
     }
 }
 
@@ -51,38 +263,56 @@ where
         data_dir: &Path,
         max_bytes: usize,
         fs_lock: private::FSLock<T>,
+        metrics: Arc<Metrics>,
     ) -> Result<Sender<T>, super::Error>
     where
         S: Into<String> + fmt::Display,
     {
-        use std::sync::Arc;
         let init_fs_lock = Arc::clone(&fs_lock);
-        let mut syn = init_fs_lock.lock().expect("Sender fs_lock poisoned");
+        let mut syn = private::lock(&init_fs_lock);
         if !data_dir.is_dir() {
             return Err(super::Error::NoSuchDirectory);
         }
-        let seq_num = match fs::read_dir(data_dir)
-            .unwrap()
-            .map(|de| {
-                de.unwrap()
-                    .path()
-                    .file_name()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .parse::<usize>()
-                    .unwrap()
-            })
+        let mut seq_num = match private::segment_ids(data_dir, syn.archive.as_deref())
+            .into_iter()
             .max()
         {
             Some(sn) => sn,
             None => 0,
         };
+        // A queue file left by a process channel has schema versions in its
+        // records, which ours do not.
+        let found = layout::file_format(&data_dir.join(format!("{}", seq_num)));
+        if found.is_some_and(layout::is_tagged) {
+            seq_num += 1;
+        }
         let log = data_dir.join(format!("{}", seq_num));
-        match fs::OpenOptions::new().append(true).create(true).open(&log) {
-            Ok(fp) => {
+        match private::open_segment(&log) {
+            Ok((fp, header_len)) => {
+                if syn.preallocate && header_len > 0 {
+                    let _ = private::preallocate(&fp, syn.segment_max_bytes as u64);
+                }
                 syn.sender_fp = Some(BufWriter::new(fp));
                 (*syn).sender_seq_num = seq_num;
+                let stage_limit = match syn.order {
+                    OrderMode::Global => 1,
+                    OrderMode::PerSender => PER_SENDER_STAGE,
+                };
+                if syn.segment_opened.is_none() {
+                    syn.segment_opened = Some(Instant::now());
+                }
+                let disk_quota = syn.disk_quota.is_some() && !syn.memory_only;
+                let id = syn.next_sender_id;
+                syn.next_sender_id = id.wrapping_add(1);
+                syn.senders += 1;
+                let rate = syn.rate.as_ref().map(|&(limit, ref shared)| {
+                    let bucket = if limit.per_sender {
+                        limit.bucket()
+                    } else {
+                        Arc::clone(shared)
+                    };
+                    (limit.policy, bucket)
+                });
                 Ok(Sender {
                     name: name.into(),
                     root: data_dir.to_path_buf(),
@@ -90,6 +320,24 @@ where
                     seq_num: seq_num,
                     max_bytes: max_bytes,
                     fs_lock: fs_lock,
+                    admission: Arc::clone(&syn.admission),
+                    metrics: metrics,
+                    stage_limit: stage_limit,
+                    staged: Vec::with_capacity(stage_limit),
+                    flush_on_drop: syn.flush_on_drop,
+                    sized: syn.memory_budget.is_some() || syn.shared_budget.is_some()
+                        || syn.reservations.is_some() || disk_quota,
+                    stages_writes: syn.stage_writes && !syn.memory_only,
+                    paging: false,
+                    lanes: Vec::new(),
+                    rate: rate,
+                    waits_for_room: syn.overflow == OverflowPolicy::Block,
+                    disk_quota: disk_quota,
+                    id: id,
+                    sent: 0,
+                    group: None,
+                    internal: false,
+                    sample_credit: 0.0,
                     resource_type: PhantomData,
                 })
             }
@@ -102,102 +350,1626 @@ where
     ///  u32: payload_size
     ///  [u8] payload
     ///
+    /// In `OrderMode::PerSender` the item may be staged inside this Sender
+    /// rather than handed to the channel immediately. See `flush`.
+    ///
+    /// On a rate limited channel the send may wait for a token or, with
+    /// `RatePolicy::Fail`, drop the item. A send to a full memory-only channel
+    /// likewise waits or drops the item as its `OverflowPolicy` says. Use
+    /// `try_send` to have a dropped item back.
     pub fn send(&mut self, event: T) {
-        let mut syn = self.fs_lock.lock().expect("Sender fs_lock poisoned");
+        let _ = self.try_send(event);
+    }
+
+    /// Send an item filled in place in a recycled slot
+    ///
+    /// `fill` is handed the slot to write the item into: an item the Receiver
+    /// has finished with through a `Recycled`, or `T::default()` if none is
+    /// waiting. A recycled slot still holds the item it last carried, so
+    /// `fill` overwrites or clears it. An item's allocations--the buffers of
+    /// its `Vec`s and `String`s--so carry over from one send to the next
+    /// rather than being made afresh. Otherwise as `send`.
+    pub fn send_with<F>(&mut self, fill: F)
+    where
+        T: Default,
+        F: FnOnce(&mut T),
+    {
+        let mut slot = private::lock(&self.fs_lock).recycled.pop().unwrap_or_default();
+        fill(&mut slot);
+        self.send(slot);
+    }
+
+    /// Send `event`, handing it back if it is refused
+    ///
+    /// An item over the channel's rate limit is refused with
+    /// `SendError::RateLimited` if the channel's `RatePolicy` is
+    /// `RatePolicy::Fail`. Otherwise this waits for a token, as `send` does.
+    /// An item sent to a full memory-only channel is refused with
+    /// `SendError::Full`, and one sent to a channel held at its disk quota
+    /// with `SendError::DiskFull`, if its `OverflowPolicy` is
+    /// `OverflowPolicy::Fail`. An item sent after the Receiver has closed the
+    /// channel is refused with `SendError::Closed`. Items staged by an
+    /// `OrderMode::PerSender` Sender are only checked for room as they are
+    /// handed over. Those refused are dropped and counted.
+    pub fn try_send(&mut self, event: T) -> Result<(), SendError<T>> {
+        if !self.take_token() {
+            return Err(SendError::RateLimited(event));
+        }
+        self.send_unlimited(event).map_err(|event| self.refusal(event))
+    }
+
+    /// Send `event`, waiting for room in a full channel no later than
+    /// `deadline`
+    ///
+    /// A channel whose `OverflowPolicy` is `OverflowPolicy::Block` waits for
+    /// room as `send` does, but only until `deadline`: an item the channel
+    /// still has no room for then is handed back in
+    /// `SendTimeoutError::Timeout` and counted as overflowed, so that a
+    /// caller can shed load within a bound on latency. Items staged by an
+    /// `OrderMode::PerSender` Sender are handed over first, and `event`
+    /// with them. An item the channel refuses without waiting, as `try_send`
+    /// would, is handed back in `SendTimeoutError::Refused`. The deadline
+    /// bounds the wait for room only, not a wait for a rate limit's token.
+    pub fn send_deadline(&mut self, event: T, deadline: Instant) -> Result<(), SendTimeoutError<T>> {
+        if !self.take_token() {
+            return Err(SendTimeoutError::Refused(SendError::RateLimited(event)));
+        }
+        self.hand_over();
+        let outgoing = self.outgoing(event);
+        match self.wait_for_room_until(&[outgoing.1], Some(deadline)) {
+            Room::Made | Room::Never => {}
+            Room::TimedOut => {
+                self.overflowed(1);
+                return Err(SendTimeoutError::Timeout(outgoing.0));
+            }
+        }
+        match self.publish(Some(outgoing)).pop() {
+            Some(event) => Err(SendTimeoutError::Refused(self.refusal(event))),
+            None => Ok(()),
+        }
+    }
+
+    /// Send `event`, waiting for room in a full channel for no longer than
+    /// `timeout`
+    ///
+    /// See `send_deadline`.
+    pub fn send_timeout(&mut self, event: T, timeout: Duration) -> Result<(), SendTimeoutError<T>> {
+        self.send_deadline(event, Instant::now() + timeout)
+    }
+
+    /// Send every item of `events`, or none of them
+    ///
+    /// The group is handed to the channel in one step, under a single
+    /// acquisition of its lock, so the Receiver finds none of it or all of
+    /// it, in order and with nothing from another Sender in between. A group
+    /// paged out is written to the queue files back to back. Items staged by
+    /// an `OrderMode::PerSender` Sender are handed over first. The group
+    /// counts as one send against the channel's rate limit.
+    ///
+    /// A memory-only channel without room for the whole group refuses it with
+    /// `SendError::Full`, and a channel whose disk quota the group would take
+    /// it past with `SendError::DiskFull`, or, if its `OverflowPolicy` is
+    /// `OverflowPolicy::Block`, waits until there is room. A group larger than
+    /// the channel holds when empty is refused whatever the policy. Nothing of
+    /// a refused group is sent. A regular channel replays nothing after a
+    /// crash, so no part of a group outlives one either.
+    pub fn send_all_or_nothing(&mut self, events: &[T]) -> Result<(), SendError<()>>
+    where
+        T: Clone,
+    {
+        if events.is_empty() {
+            return Ok(());
+        }
+        if !self.take_token() {
+            return Err(SendError::RateLimited(()));
+        }
+        self.hand_over();
+        let group: Vec<Outgoing<T>> = events.iter().cloned().map(|e| self.outgoing(e)).collect();
+        let sizes: Vec<Option<usize>> = group.iter().map(|&(_, size, _)| size).collect();
+        if !self.wait_for_room(&sizes) {
+            self.overflowed(events.len() as u64);
+            return Err(self.refusal(()));
+        }
+        // A channel that blocks takes the group all the same once it has
+        // waited for room, as it does single items.
+        let (id, bytes) = (self.id, sizes.iter().map(|size| size.unwrap_or(0)).sum());
+        let refused = self.publish_if(
+            group,
+            |syn| {
+                syn.overflow == OverflowPolicy::Block
+                    || (syn.has_room_for(sizes.iter().cloned())
+                        && syn.group_room_for(id, bytes, sizes.len()))
+            },
+            false,
+        );
+        if refused.is_empty() {
+            Ok(())
+        } else {
+            Err(self.refusal(()))
+        }
+    }
+
+    /// Send as many of `events` as the channel has room for, handing back
+    /// the rest
+    ///
+    /// The items are handed to the channel in order under a single
+    /// acquisition of its lock, until one finds the channel full: memory-only
+    /// and out of room, or held at its disk quota.
+    /// That item and every one after it are refused and handed back, in
+    /// order, along with the number of items sent. A full channel refuses
+    /// them whatever its `OverflowPolicy`: nothing here waits for room. A
+    /// regular channel pages out as it must and takes every item. Items
+    /// staged by an `OrderMode::PerSender` Sender are handed over first. The
+    /// batch counts as one send against the channel's rate limit, and a batch
+    /// over the rate with `RatePolicy::Fail` is handed back whole.
+    pub fn try_send_many(&mut self, events: Vec<T>) -> (usize, Vec<T>) {
+        if events.is_empty() {
+            return (0, events);
+        }
+        if !self.take_token() {
+            return (0, events);
+        }
+        self.hand_over();
+        let total = events.len();
+        // Sized before the lock is taken, not while it is held.
+        let group: Vec<Outgoing<T>> = events.into_iter().map(|e| self.outgoing(e)).collect();
+        let refused = self.publish_if(group, |_| true, true);
+        (total - refused.len(), refused)
+    }
+
+    // Take a token for a send, waiting for one unless the channel fails
+    // sends over its rate. Returns whether the send may go ahead.
+    fn take_token(&mut self) -> bool {
+        let allowed = match self.rate {
+            Some((policy, ref bucket)) => rate::acquire(bucket, policy == RatePolicy::Block),
+            None => true,
+        };
+        if !allowed {
+            self.metrics.total_rate_limited.fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+
+    // Send `event` without taking a token, handing it back if a full channel
+    // refuses it.
+    fn send_unlimited(&mut self, event: T) -> Result<(), T> {
+        #[cfg(feature = "histograms")]
+        let started = Instant::now();
+        let outgoing = self.outgoing(event);
+        let size = outgoing.1;
+        let mut refused = None;
+        if self.stage_limit <= 1 {
+            self.wait_for_room(&[size]);
+            refused = self.publish(Some(outgoing)).pop();
+        } else {
+            self.staged.push(outgoing);
+            if self.staged.len() >= self.stage_limit {
+                self.wait_for_room(&[size]);
+                self.hand_over();
+            }
+        }
+        #[cfg(feature = "histograms")]
+        self.metrics.latency.send.record_since(started);
+        match refused {
+            Some(event) => Err(event),
+            None => Ok(()),
+        }
+    }
+
+    // `event` ready to be handed to the channel: sized if the channel has a
+    // memory budget and, if the channel stages writes and was paging out as
+    // of the last hand over, encoded. Both happen before the lock is taken.
+    fn outgoing(&self, event: T) -> Outgoing<T> {
+        let encoding = if self.stages_writes && self.paging {
+            let mut buf = Vec::new();
+            serialize_into(&mut buf, &event, Infinite).expect("could not serialize");
+            Some(buf)
+        } else {
+            None
+        };
+        let size = match encoding {
+            _ if !self.sized => None,
+            Some(ref buf) => Some(buf.len()),
+            None => Some(serialized_size(&event) as usize),
+        };
+        (event, size, encoding)
+    }
+
+    // Wait until a full channel that blocks has room for items of `sizes`, or
+    // has been closed. Returns false, without waiting, if the items would not
+    // fit the channel even empty.
+    fn wait_for_room(&self, sizes: &[Option<usize>]) -> bool {
+        self.wait_for_room_until(sizes, None) != Room::Never
+    }
+
+    // As `wait_for_room`, giving up once `deadline`, if any, has passed.
+    fn wait_for_room_until(&self, sizes: &[Option<usize>], deadline: Option<Instant>) -> Room {
+        if !self.waits_for_room {
+            return Room::Made;
+        }
+        let bytes = sizes.iter().map(|size| size.unwrap_or(0)).sum();
+        let mut syn = private::lock(&self.fs_lock);
+        loop {
+            if syn.closed
+                || (syn.has_room_for(sizes.iter().cloned())
+                    && syn.group_room_for(self.id, bytes, sizes.len()))
+            {
+                return Room::Made;
+            }
+            if syn.mem_buffer.is_empty() && syn.disk_buffer.is_empty()
+                && syn.disk_writes_to_read == 0
+                && syn.paged.as_ref().is_none_or(|paged| paged.is_empty())
+            {
+                return Room::Never;
+            }
+            let now = Instant::now();
+            if deadline.is_some_and(|deadline| now >= deadline) {
+                return Room::TimedOut;
+            }
+            // A channel on a simulation has room made, if at all, by the
+            // simulation's jobs, which run only as it is driven.
+            if let Some(simulation) = syn.simulation() {
+                drop(syn);
+                assert!(
+                    simulation.step(),
+                    "simulation has no jobs left and the channel has no room"
+                );
+                syn = private::lock(&self.fs_lock);
+                continue;
+            }
+            let wait = deadline.map_or(ROOM_RECHECK, |deadline| {
+                cmp::min(ROOM_RECHECK, deadline - now)
+            });
+            let room = Arc::clone(&syn.room);
+            syn.room_waiters += 1;
+            syn = sync::wait_timeout(&room, syn, wait);
+            syn.room_waiters -= 1;
+        }
+    }
+
+    /// Hand any items staged by this Sender to the channel, and sync the
+    /// channel's current queue file
+    ///
+    /// Only Senders created with `OrderMode::PerSender` stage items. Staged
+    /// items are not visible to the Receiver until the stage fills, `flush` is
+    /// called or the Sender is dropped--and are lost with the Sender if the
+    /// channel was built with `ChannelBuilder::flush_on_drop(false)`. Call
+    /// `flush` before shutting down so the Receiver can drain everything sent.
+    ///
+    /// Items the channel has decided to page out are then written to the
+    /// current queue file rather than left to build up a full batch, and the
+    /// file is synced, so that they are on disk when this returns. Items held
+    /// in memory are not paged out. Fails with the error of a write or sync
+    /// that did not go through.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.hand_over();
+        for lane in &mut self.lanes {
+            lane.flush()?;
+        }
+        self.spill_buffered(false);
+        let mut syn = private::lock(&self.fs_lock);
+        if let Some(ref archiver) = syn.archiver {
+            archiver.finish();
+        }
+        if !syn.disk_buffer.is_empty() {
+            if let Some(ref err) = syn.last_write_error {
+                return Err(io::Error::other(err.clone()));
+            }
+        }
+        match syn.sender_fp {
+            Some(ref mut fp) => {
+                fp.flush()?;
+                fp.get_ref().sync_data()
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Hand any items staged by this Sender and its lanes to the channel.
+    fn hand_over(&mut self) {
+        if !self.staged.is_empty() {
+            let staged = mem::replace(&mut self.staged, Vec::with_capacity(self.stage_limit));
+            // Items refused for want of room have been counted.
+            let _ = self.publish(staged);
+        }
+        for lane in &mut self.lanes {
+            lane.hand_over();
+        }
+    }
+
+    /// Send `event` so that it becomes visible to the Receiver after `delay`
+    ///
+    /// Until it comes due the item is skipped over: the Receiver goes on
+    /// delivering items sent after it. Once due it is delivered ahead of the
+    /// rest of the queue, and a Receiver waiting on a waker, `Select` or its
+    /// readiness pipe is woken. Delayed items are kept on disk, in the
+    /// channel's `hopper.delayed` file, rather than in memory, and those not
+    /// yet delivered when the process stops are delivered by the channel's
+    /// next build. A memory-only channel holds them in memory. The channel's
+    /// clock measures the delay, to the millisecond.
+    pub fn send_after(&mut self, event: T, delay: Duration)
+    where
+        T: Send + 'static,
+    {
+        let supervisor = self.supervisor();
+        let mut syn = self.admission.lock(&self.fs_lock);
+        if syn.closed {
+            return;
+        }
+        self.metrics.total_enqueued.fetch_add(1, Ordering::Relaxed);
+        let mut payload = Vec::new();
+        let start = syn.begin_record(&mut payload);
+        serialize_into(&mut payload, &event, Infinite).expect("could not serialize");
+        syn.end_record(&mut payload, start);
+        let delay = cmp::min(delay.as_millis(), u128::from(u64::MAX)) as u64;
+        let due = syn.now_millis().saturating_add(delay);
+        if syn.delay_timer.is_none() {
+            syn.delay_timer = start_delay_timer(&self.fs_lock, &syn, &supervisor);
+        }
+        syn.delay(payload, due);
+    }
+
+    /// Send `event` if it falls in a sample taken at `rate`
+    ///
+    /// For shedding load statistically rather than blocking or dropping
+    /// blindly. Of the items a Sender offers at a rate of 0.25, one in four
+    /// is sent, as `send` would, and the rest are dropped unsent; a `rate`
+    /// outside 0 to 1 is taken as the nearer end. The sample is systematic,
+    /// not random: each offer adds `rate` to the Sender's credit and an item
+    /// is kept whenever the credit reaches a whole item. A channel built with
+    /// `ChannelBuilder::adaptive_sampling` scales `rate` down further as its
+    /// depth climbs between its watermarks.
+    ///
+    /// The rate applied is recorded in `QueueMetrics::sample_rate_ppm`, and
+    /// the items offered and left out in `QueueMetrics::total_sampled` and
+    /// `QueueMetrics::total_sampled_out`, for consumers to weight what they
+    /// receive by. Returns whether the item was kept.
+    pub fn send_sampled(&mut self, event: T, rate: f64) -> bool {
+        let rate = rate.clamp(0.0, 1.0) * self.sampling_scale();
+        self.metrics.total_sampled.fetch_add(1, Ordering::Relaxed);
+        self.metrics
+            .sample_rate_ppm
+            .store((rate * 1_000_000.0).round() as u32, Ordering::Relaxed);
+        self.sample_credit += rate;
+        if self.sample_credit < 1.0 {
+            self.metrics.total_sampled_out.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        self.sample_credit -= 1.0;
+        self.send(event);
+        true
+    }
+
+    // What adaptive sampling scales sampled sends by at the channel's
+    // present depth: 1 without it
+    fn sampling_scale(&self) -> f64 {
+        let syn = private::lock(&self.fs_lock);
+        match syn.sampling_floor {
+            Some(floor) => {
+                let disk_bytes = self.metrics.disk_bytes.load(Ordering::Relaxed);
+                let pressure = syn.watermarks.pressure(syn.writes_to_read, disk_bytes);
+                1.0 - (1.0 - floor) * pressure
+            }
+            None => 1.0,
+        }
+    }
+
+    /// Send `event` on the lane for `priority`, handing it back if it is
+    /// refused
+    ///
+    /// The Receiver drains high priority items before normal ones and normal
+    /// before low, see `Priority`. The item takes one token of the channel's
+    /// rate limit, whatever the lane, and is refused as `try_send` refuses
+    /// it; sending with `Priority::Normal` is the same as `try_send`. On a
+    /// channel built without `ChannelBuilder::priority_lanes` every priority
+    /// goes to the one lane.
+    pub fn send_with_priority(&mut self, event: T, priority: Priority) -> Result<(), SendError<T>> {
+        if !self.take_token() {
+            return Err(SendError::RateLimited(event));
+        }
+        // The lanes have no rate limit of their own: the token is this one.
+        let lane = match (priority, self.lanes.len()) {
+            (Priority::High, 2) => &mut self.lanes[0],
+            (Priority::Low, 2) => &mut self.lanes[1],
+            _ => self,
+        };
+        lane.send_unlimited(event).map_err(|event| lane.refusal(event))
+    }
+
+    /// Join the reservation group `group`
+    ///
+    /// From the next send this Sender's items are counted against the
+    /// group's share of the channel and may take room reserved for it, see
+    /// `ChannelBuilder::reserve`. A Sender stays in the group it joins, and
+    /// its clones join it too. Returns false, leaving the Sender as it was,
+    /// if the channel has no such group or the Sender is in one already.
+    pub fn join_group(&mut self, group: &str) -> bool {
+        if self.group.is_some() {
+            return false;
+        }
+        let joined = {
+            let mut syn = private::lock(&self.fs_lock);
+            let id = self.id;
+            syn.reservations.as_mut().and_then(|r| r.join(id, group))
+        };
+        if joined.is_none() {
+            return false;
+        }
+        self.hand_over();
+        self.group = joined;
+        for lane in &mut self.lanes {
+            lane.join_group(group);
+        }
+        true
+    }
+
+    // Hand `events` to the channel, returning any a full memory-only channel
+    // refused.
+    fn publish<I>(&mut self, events: I) -> Vec<T>
+    where
+        I: IntoIterator<Item = Outgoing<T>>,
+    {
+        self.publish_if(events, |_| true, false)
+    }
+
+    // As `publish`, refusing every one of `events` unless `admits` the
+    // channel as it stands once locked. With `take_what_fits` the first item
+    // to find a memory-only channel full is refused, whatever its overflow
+    // policy, and so is every item after it.
+    fn publish_if<I, F>(&mut self, events: I, admits: F, take_what_fits: bool) -> Vec<T>
+    where
+        I: IntoIterator<Item = Outgoing<T>>,
+        F: FnOnce(&private::FsSync<T>) -> bool,
+    {
+        let fs_lock = Arc::clone(&self.fs_lock);
+        let mut syn = self.admission.lock(&fs_lock);
         let fslock = &mut (*syn);
+        let mut refused = Vec::new();
+        if fslock.closed {
+            // Refused without counting as overflowed: the channel is not full.
+            refused.extend(events.into_iter().map(|(event, _, _)| event));
+            return refused;
+        }
+        if !admits(fslock) {
+            refused.extend(events.into_iter().map(|(event, _, _)| event));
+            self.overflowed(refused.len() as u64);
+            return refused;
+        }
+
+        let was_empty = fslock.writes_to_read == 0;
+        let sent = self.sent;
+        // Notices are only gathered for an observer, sparing unobserved
+        // channels the allocation.
+        let observed = fslock.observer.is_some();
+        let mut notices = Vec::new();
+        for (event, size, encoding) in events {
+            let full = take_what_fits
+                && (!refused.is_empty() || fslock.is_full()
+                    || !fslock.group_room_for(self.id, size.unwrap_or(0), 1));
+            if full || fslock.refuses() || fslock.group_refuses(self.id, size) {
+                self.overflowed(1);
+                refused.push(event);
+                continue;
+            }
+            self.metrics.total_enqueued.fetch_add(1, Ordering::Relaxed);
+            self.metrics.in_memory_depth.fetch_add(1, Ordering::Relaxed);
+            let in_memory = fslock.sender_idx < fslock.in_memory_idx;
+            let mut spill = fslock.admit(event, size, encoding, self.id, self.sent);
+            self.sent += 1;
+            if !spill {
+                let (records, bytes) = fslock.evict_oldest(&self.root);
+                if bytes > 0 {
+                    trace_event!(
+                        channel = %self.name,
+                        records = records,
+                        bytes = bytes,
+                        "segments evicted"
+                    );
+                    spill = fslock.within_disk_quota(0, 0) && fslock.may_page_out();
+                    if observed {
+                        notices.push(QueueEvent::Evicted {
+                            records: records,
+                            bytes: bytes,
+                        });
+                    }
+                }
+            }
+            if spill {
+                self.metrics.spill_events.fetch_add(1, Ordering::Relaxed);
+                let (bytes, failed) = self.spill(fslock);
+                if observed {
+                    if bytes > 0 || failed.is_none() {
+                        notices.push(QueueEvent::SpilledToDisk { bytes: bytes });
+                    }
+                    notices.extend(failed);
+                }
+            } else if observed && in_memory && fslock.sender_idx >= fslock.in_memory_idx {
+                notices.push(QueueEvent::MemoryFull);
+            }
+            if fslock.reached_disk_quota() && observed {
+                let disk_bytes = self.metrics.disk_bytes.load(Ordering::Relaxed);
+                notices.push(QueueEvent::DiskFull {
+                    disk_bytes: disk_bytes,
+                });
+            }
+        }
+        if observed && fslock.watermarks.is_set() {
+            let disk_bytes = self.metrics.disk_bytes.load(Ordering::Relaxed);
+            notices.extend(fslock.watermarks.cross(fslock.writes_to_read, disk_bytes));
+        }
+        self.paging = fslock.sender_idx >= fslock.in_memory_idx;
+        let observer = fslock.observer.clone();
+        let flusher = match fslock.flusher {
+            Some((ref signal, threshold)) if fslock.disk_buffer.len() >= threshold => {
+                Some(Arc::clone(signal))
+            }
+            _ => None,
+        };
+        // If this send moved the queue from empty to non-empty anyone waiting
+        // on the Receiver gets woken, unless it is paused and will wake them
+        // as it resumes or the channel coalesces wakes and holds this one
+        // back. We wake only after releasing the lock in case a waker
+        // turns around and polls the Receiver directly.
+        if was_empty && fslock.is_ready() {
+            fslock.signal_ready();
+        }
+        let wakers = if fslock.wake_due(was_empty, (self.sent - sent) as usize) {
+            mem::take(&mut fslock.wakers)
+        } else {
+            Vec::new()
+        };
+        drop(syn);
+        for waker in wakers {
+            waker.wake();
+        }
+        if let Some(flusher) = flusher {
+            flusher.notify();
+        }
+        if let Some(observer) = observer {
+            for notice in notices {
+                observer(notice);
+            }
+        }
+        refused
+    }
+
+    /// Scale the channel's in-memory tier and disk buffer to `scale` of their
+    /// configured size, between 0 and 1
+    ///
+    /// Below 1 the channel pages out earlier, holding less in memory: an open
+    /// in-memory tier closes once it holds `scale` of its capacity and the
+    /// disk buffer is paged out at `scale` of its size, never less than one
+    /// item. A disk buffer already over its scaled size is paged out now.
+    /// Returns the number of bytes written.
+    ///
+    /// See `pressure::adapt` to scale the channel by how much memory the
+    /// process uses.
+    pub fn scale_memory(&mut self, scale: f64) -> u64 {
+        let scale = scale.clamp(0.0, 1.0);
+        let over = {
+            let mut syn = private::lock(&self.fs_lock);
+            syn.spill_scale = scale;
+            self.metrics
+                .in_memory_capacity
+                .store(syn.scaled_capacity(), Ordering::Relaxed);
+            syn.disk_buffer_full()
+        };
+        let bytes = if over { self.spill_buffered(false) } else { 0 };
+        let mut lanes = mem::take(&mut self.lanes);
+        let bytes = lanes
+            .iter_mut()
+            .fold(bytes, |acc, lane| acc + lane.scale_memory(scale));
+        self.lanes = lanes;
+        bytes
+    }
+
+    /// Change the limits of the running channel, returning the number of
+    /// bytes paged out as a result
+    ///
+    /// The changes apply to every Sender of the channel and to its priority
+    /// lanes, from the next send. The in-memory tier takes only the first
+    /// items sent, so a new `in_memory_capacity` changes how many it holds
+    /// only while it has yet to fill; after that it sets the size of the disk
+    /// buffer. A disk buffer over a lowered capacity or budget, or held at a
+    /// raised `max_disk_bytes`, is paged out now. A `memory_budget` or
+    /// `max_disk_bytes` is only changed on a channel built with one, and a
+    /// budget kept by `ChannelBuilder::cgroup_memory_budget` is reset by its
+    /// next reading of the cgroup.
+    pub fn reconfigure(&mut self, delta: ConfigDelta) -> u64 {
+        let spill = {
+            let mut syn = private::lock(&self.fs_lock);
+            syn.reconfigure(&delta);
+            self.metrics
+                .in_memory_capacity
+                .store(syn.scaled_capacity(), Ordering::Relaxed);
+            syn.disk_buffer_full()
+        };
+        if let Some(capacity) = delta.in_memory_capacity {
+            topology::set_capacity(&self.metrics, capacity);
+        }
+        let bytes = if spill { self.spill_buffered(false) } else { 0 };
+        let mut lanes = mem::take(&mut self.lanes);
+        let bytes = lanes
+            .iter_mut()
+            .fold(bytes, |acc, lane| acc + lane.reconfigure(delta));
+        self.lanes = lanes;
+        bytes
+    }
+
+    /// Apply to the running channel the options of `config` it can take
+    /// without being rebuilt, returning the number of bytes paged out as a
+    /// result
+    ///
+    /// These are the options of `QueueConfig::delta`, applied as
+    /// `reconfigure` applies them. The rest of `config` is left for the next
+    /// time the channel is built with `ChannelBuilder::from_config`.
+    pub fn reload(&mut self, config: &QueueConfig) -> u64 {
+        self.reconfigure(config.delta())
+    }
 
+    /// Page out the items the channel holds in memory now
+    ///
+    /// Items the channel has already decided to page out are held in memory
+    /// until a full batch has built up, and the first items sent are held in
+    /// its in-memory tier until received. Under memory pressure this writes
+    /// out both and releases spare buffer capacity, trading early, smaller
+    /// writes and slower receives for a lower footprint. The in-memory tier
+    /// takes no more items after, so every item sent since is paged out too.
+    /// Items of a memory-only channel stay where they are. Returns the number
+    /// of bytes written.
+    ///
+    /// See `pressure::watch` to call this automatically when the host comes
+    /// under memory pressure.
+    pub fn relieve_memory_pressure(&mut self) -> u64 {
+        self.hand_over();
+        let bytes = self.page_out_memory() + self.spill_buffered(true);
+        let mut lanes = mem::take(&mut self.lanes);
+        let bytes = lanes
+            .iter_mut()
+            .fold(bytes, |acc, lane| acc + lane.relieve_memory_pressure());
+        self.lanes = lanes;
+        bytes
+    }
+
+    /// Page out items bound for disk from a background thread
+    ///
+    /// Without a flusher, items are paged out by the `send` that fills the
+    /// disk buffer, which waits on the write. With one, a background thread
+    /// writes out whatever has built up every `interval`, and sooner once
+    /// `threshold` items are waiting, so that sends rarely wait on disk. A
+    /// send that fills the disk buffer before the flusher gets to it still
+    /// pages it out itself. Items waiting on the flusher are held in memory
+    /// and lost if the process dies, as with any item not yet paged out.
+    ///
+    /// The thread holds a Sender of its own and exits once every other Sender
+    /// and the Receiver of the channel have been dropped. A channel has one
+    /// flusher: once it is started, later calls from any of the channel's
+    /// Senders leave it running as it is and start no other. The flusher of a channel built with
+    /// `ChannelBuilder::runtime` is a job of the runtime instead of a thread.
+    /// Where there are no threads, as under WASI, no flusher is started and
+    /// sends page out as they would without one.
+    pub fn spawn_flusher<'de>(&self, interval: Duration, threshold: usize)
+    where
+        T: Deserialize<'de> + Send + 'static,
+    {
+        let runtime = private::lock(&self.fs_lock).runtime.clone();
+        if runtime.is_none() && !private::HAS_THREADS {
+            return;
+        }
+        let mut sender = self.internal_clone();
+        // Each lane gets its own flusher below.
+        sender.lanes.clear();
+        let supervisor = sender.supervisor();
+        // The flusher is started under the lock, so that two calls at once
+        // cannot both find the channel without one.
+        let mut syn = private::lock(&self.fs_lock);
+        if syn.flusher.is_some() {
+            drop(syn);
+            return;
+        }
+        let signal = match runtime {
+            Some(runtime) => {
+                let job = runtime.spawn(supervisor.job(Task::Flusher, move || {
+                    if sender.is_orphaned() {
+                        return Next::Done;
+                    }
+                    sender.spill_buffered(false);
+                    Next::After(interval)
+                }));
+                Arc::new(private::FlushSignal::waking(job))
+            }
+            None => {
+                let signal = Arc::new(private::FlushSignal::default());
+                let thr_signal = Arc::clone(&signal);
+                supervisor
+                    .spawn(Task::Flusher, move || while !sender.is_orphaned() {
+                        thr_signal.wait(interval);
+                        sender.spill_buffered(false);
+                    })
+                    .expect("could not start flusher");
+                signal
+            }
+        };
+        syn.flusher = Some((signal, threshold));
+        drop(syn);
+        for lane in &self.lanes {
+            lane.spawn_flusher(interval, threshold);
+        }
+    }
+
+    // Page the in-memory tier out to the channel's paged file, see `paged`,
+    // returning the number of bytes written, and close the tier. A tier the
+    // file cannot take is left where it is and the failure recorded as the
+    // channel's last write error.
+    fn page_out_memory(&mut self) -> u64 {
+        let mut syn = private::lock(&self.fs_lock);
+        let fslock = &mut (*syn);
+        if fslock.mem_buffer.is_empty() || fslock.paged.is_none() {
+            return 0;
+        }
+        let mut records = Vec::new();
+        for at in 0..fslock.mem_buffer.len() {
+            let start = records.len();
+            // The length is filled in once the payload's is known.
+            records.extend_from_slice(&[0; 4]);
+            let encoded = fslock.begin_record(&mut records);
+            fslock.lead_record(
+                &mut records,
+                fslock.mem_stamps.get(at).cloned(),
+                fslock.mem_origins.get(at).cloned(),
+                fslock.mem_seqs.get(at).cloned(),
+            );
+            serialize_into(&mut records, &fslock.mem_buffer[at], Infinite)
+                .expect("could not serialize");
+            fslock.end_record(&mut records, encoded);
+            let len = (records.len() - start - 4) as u32;
+            records[start..start + 4].copy_from_slice(&len.to_be_bytes());
+        }
+        let paged = fslock.paged.as_mut().expect("paged file");
+        if let Err(e) = paged.page_out(&records) {
+            fslock.last_write_error = Some(e.to_string());
+            return 0;
+        }
+        let count = fslock.mem_buffer.len();
+        for _ in 0..count {
+            fslock.take_mem_size();
+        }
+        fslock.mem_buffer.clear();
+        fslock.mem_stamps.clear();
+        fslock.mem_origins.clear();
+        fslock.mem_seqs.clear();
         if fslock.sender_idx < fslock.in_memory_idx {
-            fslock.mem_buffer.push_back(event);
+            fslock.in_memory_idx = fslock.sender_idx;
+        }
+        self.metrics
+            .in_memory_depth
+            .fetch_sub(count, Ordering::Relaxed);
+        records.len() as u64
+    }
+
+    // Page out the disk buffer now rather than when it fills, returning the
+    // number of bytes written. With `shrink`, spare buffer capacity is
+    // released too.
+    fn spill_buffered(&mut self, shrink: bool) -> u64 {
+        let fs_lock = Arc::clone(&self.fs_lock);
+        let mut syn = private::lock(&fs_lock);
+        let fslock = &mut (*syn);
+        let (bytes, failed) = if fslock.disk_buffer.is_empty() || fslock.memory_only
+            || !fslock.within_disk_quota(0, 0) || !fslock.may_page_out()
+        {
+            (0, None)
         } else {
-            fslock.disk_buffer.push_back(event);
-            if fslock.disk_buffer.len() >= fslock.in_memory_idx {
-                while let Some(ev) = fslock.disk_buffer.pop_front() {
-                    let mut pyld = Vec::with_capacity(64);
-                    serialize_into(&mut pyld, &ev, Infinite).expect("could not serialize");
-                    // NOTE The conversion of t.len to u32 and usize is _only_
-                    // safe when u32 <= usize. That's very likely to hold true
-                    // for machines--for now?--that hopper will run on. However!
-                    let pyld_sz_bytes: [u8; 4] = u32tou8abe(pyld.len() as u32);
-                    let mut t = vec![0, 0, 0, 0];
-                    t[0] = pyld_sz_bytes[3];
-                    t[1] = pyld_sz_bytes[2];
-                    t[2] = pyld_sz_bytes[1];
-                    t[3] = pyld_sz_bytes[0];
-                    t.append(&mut pyld);
-                    // If the individual sender writes enough to go over the max
-                    // we mark the file read-only--which will help the receiver
-                    // to decide it has hit the end of its log file--and create
-                    // a new log file.
-                    let bytes_written = fslock.bytes_written + t.len();
-                    if (bytes_written > self.max_bytes) || (self.seq_num != fslock.sender_seq_num)
-                        || fslock.sender_fp.is_none()
-                    {
-                        // Once we've gone over the write limit for our current
-                        // file or find that we've gotten behind the current
-                        // queue file we need to seek forward to find our place
-                        // in the space of queue files. We mark our current file
-                        // read-only--there's some possibility that this will be
-                        // done redundantly, but that's okay--and then read the
-                        // current sender_seq_num to get up to date.
-                        let _ = fs::metadata(&self.path).map(|p| {
-                            let mut permissions = p.permissions();
-                            permissions.set_readonly(true);
-                            let _ = fs::set_permissions(&self.path, permissions);
-                        });
-                        if fslock.sender_fp.is_some() {
-                            if self.seq_num != fslock.sender_seq_num {
-                                // This thread is behind the leader. We've got to
-                                // set our current notion of seq_num forward and
-                                // then open the corresponding file.
-                                self.seq_num = fslock.sender_seq_num;
-                            } else {
-                                // This thread is the leader. We reset the
-                                // sender_seq_num and bytes written and open the
-                                // next queue file. All follower threads will hit
-                                // the branch above this one.
-                                fslock.sender_seq_num = self.seq_num.wrapping_add(1);
-                                self.seq_num = fslock.sender_seq_num;
-                                fslock.bytes_written = 0;
-                            }
+            self.metrics.spill_events.fetch_add(1, Ordering::Relaxed);
+            self.spill(fslock)
+        };
+        if shrink {
+            fslock.disk_buffer.shrink_to_fit();
+            fslock.disk_encodings.shrink_to_fit();
+            fslock.mem_buffer.shrink_to_fit();
+            fslock.encode_buf = Vec::new();
+        }
+        let observer = fslock.observer.clone();
+        drop(syn);
+        if let Some(observer) = observer {
+            if bytes > 0 {
+                observer(QueueEvent::SpilledToDisk { bytes: bytes });
+            }
+            if let Some(failed) = failed {
+                observer(failed);
+            }
+        }
+        bytes
+    }
+
+    // Write the disk buffer out to queue files, returning the number of bytes
+    // written and, should a write fail, the event raising it. Items leave the
+    // disk buffer only once their queue file holds them, so a failed write
+    // leaves those it did not finish in memory.
+    fn spill(&mut self, fslock: &mut private::FsSync<T>) -> (u64, Option<QueueEvent>) {
+        let mut spilled_bytes = 0;
+        // Files the archiver has staged are moved into place while the lock
+        // is held, see `archive`.
+        if let Some(ref archiver) = fslock.archiver {
+            archiver.finish();
+        }
+        #[cfg(feature = "tracing")]
+        let spilled = fslock.disk_buffer.len();
+        #[cfg(feature = "tracing")]
+        let started = Instant::now();
+        // Records bound for the current queue file, encoded back to back and
+        // written out together when the file fills or the disk buffer is
+        // empty. The buffer is the channel's, reused from spill to spill.
+        let mut batch = mem::take(&mut fslock.encode_buf);
+        let mut batched = 0;
+        while batched < fslock.disk_buffer.len() {
+            let start = batch.len();
+            // The header is filled in once the payload's length is known.
+            batch.extend_from_slice(&[0; 4]);
+            let encoded = fslock.begin_record(&mut batch);
+            fslock.lead_record(
+                &mut batch,
+                fslock.disk_stamps.get(batched).cloned(),
+                fslock.disk_origins.get(batched).cloned(),
+                fslock.disk_seqs.get(batched).cloned(),
+            );
+            // An item whose Sender staged its write was encoded before it was
+            // handed over, and is only copied in.
+            let encoding = match fslock.disk_encodings.get(batched) {
+                Some(Some(staged)) => {
+                    batch.extend_from_slice(staged);
+                    Ok(Ok(()))
+                }
+                _ => {
+                    let item = &fslock.disk_buffer[batched];
+                    panic::catch_unwind(AssertUnwindSafe(|| {
+                        serialize_into(&mut batch, item, Infinite)
+                    }))
+                }
+            };
+            match encoding {
+                Ok(encoded) => encoded.expect("could not serialize"),
+                Err(payload) => {
+                    // The item is lost rather than left to take down every
+                    // spill after this one.
+                    fslock.remove_disk_item(batched);
+                    self.metrics.in_memory_depth.fetch_sub(1, Ordering::Relaxed);
+                    panic::resume_unwind(payload)
+                }
+            }
+            fslock.end_record(&mut batch, encoded);
+            // NOTE The conversion of t.len to u32 and usize is _only_
+            // safe when u32 <= usize. That's very likely to hold true
+            // for machines--for now?--that hopper will run on. However!
+            let pyld_len = batch.len() - start - 4;
+            let pyld_sz_bytes: [u8; 4] = u32tou8abe(pyld_len as u32);
+            let header = [
+                pyld_sz_bytes[3],
+                pyld_sz_bytes[2],
+                pyld_sz_bytes[1],
+                pyld_sz_bytes[0],
+            ];
+            batch[start..start + 4].copy_from_slice(&header);
+            let record_len = batch.len() - start;
+            // If the individual sender writes enough to go over the max
+            // we mark the file read-only--which will help the receiver
+            // to decide it has hit the end of its log file--and create
+            // a new log file.
+            let bytes_written = fslock.bytes_written + record_len;
+            if (bytes_written > fslock.segment_max_bytes) || (self.seq_num != fslock.sender_seq_num)
+                || fslock.sender_fp.is_none()
+            {
+                // Records batched for the current file must reach it before
+                // it is sealed. This record goes to the next file.
+                match self.write_batch(fslock, &batch[..start], batched) {
+                    Ok(bytes) => spilled_bytes += bytes,
+                    Err(e) => return self.spill_failed(fslock, batch, spilled_bytes, e),
+                }
+                batch.drain(..start);
+                batched = 0;
+                let mut sealed = None;
+                if fslock.sender_fp.is_some() {
+                    // Once we've gone over the write limit for our current
+                    // file or find that we've gotten behind the current
+                    // queue file we need to seek forward to find our place
+                    // in the space of queue files. We mark our current file
+                    // read-only--there's some possibility that this will be
+                    // done redundantly, but that's okay--and then read the
+                    // current sender_seq_num to get up to date.
+                    let _ = fs::metadata(&self.path).map(|p| {
+                        let mut permissions = p.permissions();
+                        permissions.set_readonly(true);
+                        let _ = fs::set_permissions(&self.path, permissions);
+                    });
+                    trace_event!(channel = %self.name, segment = self.seq_num, "segment sealed");
+                    if self.seq_num != fslock.sender_seq_num {
+                        // This thread is behind the leader. We've got to
+                        // set our current notion of seq_num forward and
+                        // then open the corresponding file.
+                        self.seq_num = fslock.sender_seq_num;
+                    } else {
+                        // This thread is the leader. We reset the
+                        // sender_seq_num and bytes written and open the
+                        // next queue file. All follower threads will hit
+                        // the branch above this one.
+                        sealed = Some(self.path.clone());
+                        // A summary or index that cannot be written is left
+                        // out: readers of the file fall back to reading it.
+                        let tally = mem::take(&mut fslock.segment_summary);
+                        let _ = summary::write(&self.path, &tally);
+                        if fslock.segment_index.is_useful() {
+                            let _ = index::write(&self.path, &fslock.segment_index);
                         }
-                        self.path = self.root.join(format!("{}", self.seq_num));
-                        match fs::OpenOptions::new()
-                            .append(true)
-                            .create(true)
-                            .open(&self.path)
-                        {
-                            Ok(fp) => fslock.sender_fp = Some(BufWriter::new(fp)),
-                            Err(e) => panic!("FAILED TO OPEN {:?} WITH {:?}", &self.path, e),
+                        fslock.segment_index.clear();
+                        fslock.sender_seq_num = self.seq_num.wrapping_add(1);
+                        self.seq_num = fslock.sender_seq_num;
+                        fslock.bytes_written = 0;
+                        fslock.rotate_segment();
+                        trace_event!(
+                            channel = %self.name,
+                            segment = self.seq_num,
+                            max_bytes = fslock.segment_max_bytes,
+                            "segment created"
+                        );
+                        self.metrics.segments_on_disk.fetch_add(1, Ordering::Relaxed);
+                        self.metrics
+                            .segment_max_bytes
+                            .store(fslock.segment_max_bytes, Ordering::Relaxed);
+                    }
+                } else {
+                    // The next queue file could not be opened last time, so
+                    // is opened now, whichever Sender failed to.
+                    self.seq_num = fslock.sender_seq_num;
+                }
+                self.path = self.root.join(format!("{}", self.seq_num));
+                // The sealed file's writer was flushed as its batch was
+                // written, so is dropped here whole.
+                fslock.sender_fp = None;
+                match private::open_segment(&self.path) {
+                    Ok((fp, header_len)) => {
+                        if fslock.preallocate && header_len > 0 {
+                            // Preallocation is a hint: a filesystem without
+                            // it grows the file as ever.
+                            let _ = private::preallocate(&fp, fslock.segment_max_bytes as u64);
                         }
+                        fslock.sender_fp = Some(BufWriter::new(fp));
+                        self.metrics
+                            .disk_bytes
+                            .fetch_add(header_len, Ordering::Relaxed);
                     }
+                    Err(e) => return self.spill_failed(fslock, batch, spilled_bytes, e),
+                }
+                // The sealed file is complete, so may be moved off to the
+                // archive.
+                if let (Some(sealed), Some(archiver)) = (sealed, fslock.archiver.as_ref()) {
+                    archiver.archive(sealed);
+                    trace_event!(channel = %self.name, "segment handed to archiver");
+                }
+            }
 
-                    assert!(fslock.sender_fp.is_some());
-                    if let Some(ref mut fp) = fslock.sender_fp {
-                        match fp.write(&t[..]) {
-                            Ok(written) => fslock.bytes_written += written,
-                            Err(e) => panic!("Write error: {}", e),
-                        }
-                        fslock.disk_writes_to_read += 1;
+            assert!(fslock.sender_fp.is_some());
+            fslock.bytes_written += record_len;
+            batched += 1;
+        }
+        match self.write_batch(fslock, &batch, batched) {
+            Ok(bytes) => spilled_bytes += bytes,
+            Err(e) => return self.spill_failed(fslock, batch, spilled_bytes, e),
+        }
+        self.reuse_batch(fslock, batch);
+        fslock.retries.succeeded();
+        fslock.last_write_error = None;
+        trace_event!(
+            channel = %self.name,
+            segment = self.seq_num,
+            items = spilled,
+            spill_us = started.elapsed().as_micros() as u64,
+            "spilled to disk"
+        );
+        (spilled_bytes, None)
+    }
+
+    // Hand the spill's encoding buffer back to the channel, emptied.
+    fn reuse_batch(&self, fslock: &mut private::FsSync<T>, mut batch: Vec<u8>) {
+        batch.clear();
+        // A disk-primary channel holds on to nothing the size of its items.
+        if !fslock.disk_primary {
+            fslock.encode_buf = batch;
+        }
+    }
+
+    // Record that a spill failed with `err` having written `bytes`, leaving
+    // the rest of the disk buffer to be paged out once the channel's retry
+    // policy allows, if it does.
+    fn spill_failed(
+        &self,
+        fslock: &mut private::FsSync<T>,
+        batch: Vec<u8>,
+        bytes: u64,
+        err: io::Error,
+    ) -> (u64, Option<QueueEvent>) {
+        self.reuse_batch(fslock, batch);
+        let retrying = fslock.retries.failed(&err, fslock.clock.now());
+        fslock.last_write_error = Some(err.to_string());
+        trace_event!(
+            channel = %self.name,
+            segment = self.seq_num,
+            error = %err,
+            retrying = retrying,
+            "write to queue file failed"
+        );
+        let failed = QueueEvent::WriteFailed {
+            kind: err.kind(),
+            retrying: retrying,
+        };
+        (bytes, Some(failed))
+    }
+
+    // Write `records` encoded records, those at the front of the disk buffer,
+    // to the current queue file in as few writes as the file will take,
+    // packed into blocks if the channel packs them, and flush it. The records
+    // then leave the disk buffer for the Receiver to read from the file.
+    // Returns the number of bytes written.
+    //
+    // A write that fails is undone, truncating the file back to where it
+    // was, and the records stay in the disk buffer. A file that cannot be
+    // truncated fails with an error of kind `Other`, never retried.
+    fn write_batch(
+        &self,
+        fslock: &mut private::FsSync<T>,
+        batch: &[u8],
+        records: usize,
+    ) -> io::Result<u64> {
+        if batch.is_empty() {
+            return Ok(0);
+        }
+        let item_bytes = batch.len() as u64;
+        let packed;
+        let batch = if fslock.block_bytes > 0 {
+            packed = pack_blocks(fslock, batch);
+            &packed[..]
+        } else {
+            batch
+        };
+        let mut calls = 0;
+        let written = {
+            let fp = fslock.sender_fp.as_mut().expect("no queue file to write to");
+            // Each batch is flushed, so the file holds everything before it.
+            let mark = fp.get_ref().metadata()?.len();
+            let mut remaining = batch;
+            let mut written = Ok(());
+            while !remaining.is_empty() {
+                match fp.write(remaining) {
+                    Ok(0) => {
+                        let msg = "queue file accepted no bytes";
+                        written = Err(io::Error::new(ErrorKind::WriteZero, msg));
+                        break;
+                    }
+                    Ok(n) => {
+                        calls += 1;
+                        remaining = &remaining[n..];
+                    }
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        written = Err(e);
+                        break;
                     }
                 }
-                assert!(fslock.sender_fp.is_some());
-                if let Some(ref mut fp) = fslock.sender_fp {
-                    fp.flush().expect("unable to flush");
+            }
+            #[cfg(feature = "histograms")]
+            let flush_started = Instant::now();
+            let written = written.and_then(|()| fp.flush());
+            #[cfg(feature = "histograms")]
+            self.metrics.latency.flush.record_since(flush_started);
+            written.map(|()| mark).map_err(|e| (e, mark))
+        };
+        let mark = match written {
+            Ok(mark) => mark,
+            Err((e, mark)) => {
+                // Bytes the writer holds are dropped rather than flushed later.
+                let fp = fslock.sender_fp.take().expect("no queue file to write to");
+                let (file, _) = fp.into_parts();
+                return match file.set_len(mark) {
+                    Ok(()) => {
+                        fslock.sender_fp = Some(BufWriter::new(file));
+                        Err(e)
+                    }
+                    Err(undo) => {
+                        let msg = format!("{}, and could not be undone: {}", e, undo);
+                        Err(io::Error::other(msg))
+                    }
+                };
+            }
+        };
+        let first_seq = self.metrics.records_written.load(Ordering::Relaxed);
+        let stamped = if fslock.stamped() { records } else { 0 };
+        // Packed, the records written are the blocks the items went into.
+        let mut written_records = records as u64;
+        if fslock.block_bytes > 0 {
+            written_records = 0;
+            let mut rest = batch;
+            while let Some((_, tail)) = private::next_record(rest) {
+                written_records += 1;
+                rest = tail;
+            }
+        }
+        fslock.segment_summary.add(
+            first_seq,
+            records as u64,
+            written_records,
+            fslock.disk_stamps.iter().take(stamped).cloned(),
+            item_bytes,
+            batch.len() as u64,
+        );
+        let packed = fslock.block_bytes > 0;
+        let mut stamps = fslock.disk_stamps.iter().take(stamped).cloned();
+        let mut rest = batch;
+        while let Some((record, tail)) = private::next_record(rest) {
+            let offset = mark + (batch.len() - rest.len()) as u64;
+            let items = if packed { block::count(record) } else { 1 };
+            let stamps = stamps.by_ref().take(items as usize);
+            fslock.segment_index.add(offset, items, if stamped > 0 { Some(stamps) } else { None });
+            rest = tail;
+        }
+        for _ in 0..records {
+            fslock.disk_buffer.pop_front();
+            fslock.disk_stamps.pop_front();
+            fslock.disk_origins.pop_front();
+            fslock.disk_seqs.pop_front();
+            fslock.take_disk_size();
+        }
+        fslock.disk_writes_to_read += records;
+        self.metrics
+            .in_memory_depth
+            .fetch_sub(records, Ordering::Relaxed);
+        self.metrics
+            .disk_bytes
+            .fetch_add(batch.len() as u64, Ordering::Relaxed);
+        self.metrics
+            .records_written
+            .fetch_add(records as u64, Ordering::Relaxed);
+        self.metrics.write_calls.fetch_add(calls, Ordering::Relaxed);
+        Ok(batch.len() as u64)
+    }
+
+    /// Train the next version of the channel's compression dictionary
+    ///
+    /// The records paged out from now on are sampled as they were for the
+    /// first, see `ChannelBuilder::train_dictionary`, and blocks go on being
+    /// compressed with the current dictionary until the next is trained.
+    /// Blocks already written keep the dictionary they were compressed with.
+    /// The retraining applies to every Sender of the channel and to its
+    /// priority lanes. It has no effect on a channel built without
+    /// `train_dictionary`.
+    #[cfg(feature = "dictionary")]
+    pub fn retrain_dictionary(&self) {
+        if let Some(ref mut trainer) = private::lock(&self.fs_lock).trainer {
+            trainer.retrain();
+        }
+        for lane in &self.lanes {
+            lane.retrain_dictionary();
+        }
+    }
+
+    /// Seal records paged out from now on under `key`, known by `id`
+    ///
+    /// The channel keeps every key it has been given, so records already on
+    /// disk under earlier ones are still delivered; `rekey` reseals them under
+    /// this one. The rotation applies to every Sender of the channel and to
+    /// its priority lanes. It has no effect on a channel built without
+    /// `ChannelBuilder::encryption_key`. Panics if `id` has been used before.
+    #[cfg(feature = "encryption")]
+    pub fn rotate_encryption_key(&self, id: u32, key: [u8; 32]) {
+        {
+            let mut syn = private::lock(&self.fs_lock);
+            if let Some(ref mut cipher) = syn.cipher {
+                cipher.rotate(id, &key);
+            }
+        }
+        for lane in &self.lanes {
+            lane.rotate_encryption_key(id, key);
+        }
+    }
+
+    /// Reseal the records of the channel's sealed queue files under the
+    /// current encryption key, returning the number resealed
+    ///
+    /// Each file is rewritten alongside and moved into place. Records keep
+    /// their length, so the Receiver's place is undisturbed, though a file it
+    /// already has open goes on being read as it was. Records that cannot be
+    /// opened, and the queue file still being written to, are left alone.
+    /// The files are resealed one at a time, and sends wait only while the
+    /// file at hand is rewritten. Files the Receiver finishes with meanwhile
+    /// are passed over. Returns the error of the first file that cannot be
+    /// read or rewritten, those before it resealed and those after it not.
+    #[cfg(feature = "encryption")]
+    pub fn rekey(&self) -> io::Result<u64> {
+        let ids = {
+            let syn = private::lock(&self.fs_lock);
+            if syn.cipher.is_none() {
+                Vec::new()
+            } else {
+                private::segment_ids(&self.root, syn.archive.as_deref())
+            }
+        };
+        let mut resealed = 0;
+        for id in ids {
+            let mut syn = private::lock(&self.fs_lock);
+            let fslock = &mut *syn;
+            if id == fslock.sender_seq_num {
+                continue;
+            }
+            let path = private::segment_path(&self.root, fslock.archive.as_deref(), id);
+            let packed = fslock.block_bytes > 0;
+            if let Some(ref mut cipher) = fslock.cipher {
+                match reseal_segment(cipher, &path, packed) {
+                    Ok(records) => resealed += records,
+                    Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
                 }
             }
         }
-        fslock.writes_to_read += 1;
-        if (fslock.sender_captured_recv_id != fslock.receiver_read_id)
-            || fslock.write_bound.is_none()
+        for lane in &self.lanes {
+            resealed += lane.rekey()?;
+        }
+        Ok(resealed)
+    }
+
+    /// Whether the Receiver has closed the channel
+    ///
+    /// Items sent on a closed channel are discarded, and refused by `try_send`
+    /// with `SendError::Closed`. See `Receiver::close`.
+    pub fn is_closed(&self) -> bool {
+        private::lock(&self.fs_lock).closed
+    }
+
+    /// Whether the channel holds as many items in memory as its capacity
+    ///
+    /// Read without taking the channel's lock, see `Sender::occupancy`.
+    pub fn is_full(&self) -> bool {
+        self.occupancy() >= 1.0
+    }
+
+    /// The fraction of the channel's in-memory capacity its items fill
+    ///
+    /// Items waiting to be paged out count, so this may run past 1 while a
+    /// disk buffer builds up. The capacity is 1024 items unless changed by
+    /// `Sender::reconfigure`, as scaled by `Sender::scale_memory`, and counts
+    /// items however a memory budget sizes them. Read from relaxed
+    /// atomics, without taking the channel's lock, so cheap enough to check
+    /// on every send but possibly a little out of step with concurrent sends
+    /// and receives.
+    pub fn occupancy(&self) -> f32 {
+        let depth = self.metrics.in_memory_depth.load(Ordering::Relaxed);
+        let capacity = self.metrics.in_memory_capacity.load(Ordering::Relaxed);
+        depth as f32 / cmp::max(1, capacity) as f32
+    }
+
+    /// The bytes of queue files on disk, as `QueueMetrics::disk_bytes`
+    ///
+    /// Read from a relaxed atomic, without taking the channel's lock.
+    pub fn disk_backlog_bytes(&self) -> u64 {
+        self.metrics.disk_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Return the sender's id
+    ///
+    /// Each Sender of a channel, clones included, has its own, numbered from
+    /// 0 in the order they were created. A channel built with
+    /// `ChannelBuilder::record_provenance` stamps every item with the id of
+    /// the Sender it came from.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// A handle on the channel that does not keep it open, see `WeakSender`
+    pub fn downgrade(&self) -> WeakSender<T> {
+        WeakSender {
+            name: self.name.clone(),
+            root: self.root.clone(),
+            max_bytes: self.max_bytes,
+            fs_lock: Arc::downgrade(&self.fs_lock),
+            metrics: Arc::downgrade(&self.metrics),
+            lanes: self.lanes.iter().map(Sender::downgrade).collect(),
+        }
+    }
+
+    /// Return the size at which the current queue file will be rotated
+    ///
+    /// This is the configured `max_bytes` unless adaptive sizing was enabled
+    /// through `ChannelBuilder::adaptive_max_bytes`, in which case it is the
+    /// size hopper has most recently chosen.
+    pub fn segment_max_bytes(&self) -> usize {
+        private::lock(&self.fs_lock).segment_max_bytes
+    }
+
+    /// Return a snapshot of the channel's metrics
+    pub fn metrics(&self) -> QueueMetrics {
+        self.metrics.snapshot()
+    }
+
+    /// Return a snapshot of the metrics of the reservation group `group`, or
+    /// `None` if the channel has no such group
+    ///
+    /// The group's lanes, on a channel with priority lanes, are counted
+    /// together. See `ChannelBuilder::reserve`.
+    pub fn group_metrics(&self, group: &str) -> Option<GroupMetrics> {
+        let metrics = private::lock(&self.fs_lock)
+            .reservations
+            .as_ref()
+            .and_then(|reservations| reservations.metrics(group));
+        self.lanes.iter().fold(metrics, |acc, lane| {
+            match (acc, lane.group_metrics(group)) {
+                (Some(acc), Some(lane)) => Some(acc.merge(lane)),
+                (acc, lane) => acc.or(lane),
+            }
+        })
+    }
+
+    /// Whether the channel is in working order, for a readiness check
+    ///
+    /// Reports whether the channel's directory takes writes, the room left
+    /// under its disk quota, the last failed write to a queue file and how
+    /// its background tasks have fared. A task that panics is started again,
+    /// and given up on once it has panicked too often; each panic is also
+    /// raised with the channel's `ChannelBuilder::on_event` callback as
+    /// `QueueEvent::TaskPanicked`. See `Health`.
+    pub fn health(&self) -> Health {
+        private::health(&self.fs_lock, &self.root)
+    }
+
+    pub(crate) fn supervisor(&self) -> Supervisor {
+        private::lock(&self.fs_lock).supervisor.clone()
+    }
+
+    // Link this Sender's channel and its priority lanes so that they share
+    // the disk quota and `keep_bytes`, a lane evicting from those below it.
+    pub(crate) fn set_lanes(&mut self, high: Sender<T>, low: Sender<T>) {
+        {
+            let lanes = [&high, &*self, &low];
+            let disk: Vec<Arc<Metrics>> =
+                lanes.iter().map(|lane| Arc::clone(&lane.metrics)).collect();
+            let retained: Vec<(PathBuf, Option<PathBuf>)> = lanes
+                .iter()
+                .map(|lane| (lane.root.clone(), private::lock(&lane.fs_lock).archive.clone()))
+                .collect();
+            for (rank, lane) in lanes.iter().enumerate() {
+                let below = lanes[rank + 1..]
+                    .iter()
+                    .rev()
+                    .map(|lower| (lower.root.clone(), Arc::downgrade(&lower.fs_lock)))
+                    .collect();
+                private::lock(&lane.fs_lock).lanes = Some(private::LaneShare {
+                    disk: disk.clone(),
+                    below: below,
+                    retained: retained.clone(),
+                });
+            }
+        }
+        self.lanes = vec![high, low];
+    }
+
+    // The directories whose retained files share the channel's `keep_bytes`.
+    pub(crate) fn retained_dirs(&self) -> Vec<(PathBuf, Option<PathBuf>)> {
+        private::lock(&self.fs_lock).retained_dirs(&self.root)
+    }
+
+    // A clone of this Sender, and of its lanes, for hopper's own background
+    // work, not counted among the channel's live Senders
+    pub(crate) fn internal_clone<'de>(&self) -> Sender<T>
+    where
+        T: Serialize + Deserialize<'de>,
+    {
+        let mut sender = self.clone();
+        sender.internal = true;
+        private::lock(&sender.fs_lock).internal_senders += 1;
+        sender.lanes = self.lanes.iter().map(Sender::internal_clone).collect();
+        sender
+    }
+
+    // Whether every other handle on the channel--the Receiver and any Sender
+    // clones--has been dropped, the Senders hopper holds itself aside, so
+    // that a flusher and a memory pressure watcher do not keep each other
+    // running.
+    pub(crate) fn is_orphaned(&self) -> bool {
+        let internal = private::lock(&self.fs_lock).internal_senders;
+        Arc::strong_count(&self.fs_lock) <= internal + !self.internal as usize
+    }
+
+    pub(crate) fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub(crate) fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    // Throw away any bytes sitting in the shared write buffer without flushing
+    // them, as a process dying mid-write would.
+    pub(crate) fn abandon(&self) {
+        let mut syn = private::lock(&self.fs_lock);
+        if let Some(fp) = syn.sender_fp.take() {
+            let _ = fp.into_parts();
+        }
+    }
+}
+
+impl<T, E> Sender<Result<T, E>>
+where
+    T: Serialize,
+    E: Serialize,
+{
+    /// Send `err` in line with the items, to tell the Receiver that
+    /// something upstream failed
+    ///
+    /// A channel of `Result<T, E>` carries failures with its data, in order
+    /// and through the disk tier alike: the error reaches the Receiver after
+    /// every item sent before it and before every item sent after.
+    /// `Receiver::recv_split_into` separates the two again. Otherwise as
+    /// `send`.
+    pub fn send_err(&mut self, err: E) {
+        self.send(Err(err));
+    }
+
+    /// Send `err` in line with the items, handing it back if it is refused,
+    /// as `try_send` does
+    pub fn try_send_err(&mut self, err: E) -> Result<(), SendError<Result<T, E>>> {
+        self.try_send(Err(err))
+    }
+}
+
+/// A handle on a channel from which Senders can be had while any remain
+///
+/// Created by `Sender::downgrade`. A `WeakSender` is not counted among the
+/// channel's Senders: once every Sender has been dropped the channel hangs up,
+/// whatever WeakSenders are left, and `upgrade` gives `None` from then on.
+#[derive(Debug)]
+pub struct WeakSender<T> {
+    name: String,
+    root: PathBuf,
+    max_bytes: usize,
+    fs_lock: Weak<Mutex<private::FsSync<T>>>,
+    metrics: Weak<Metrics>,
+    lanes: Vec<WeakSender<T>>,
+}
+
+impl<T> Clone for WeakSender<T> {
+    fn clone(&self) -> WeakSender<T> {
+        WeakSender {
+            name: self.name.clone(),
+            root: self.root.clone(),
+            max_bytes: self.max_bytes,
+            fs_lock: Weak::clone(&self.fs_lock),
+            metrics: Weak::clone(&self.metrics),
+            lanes: self.lanes.clone(),
+        }
+    }
+}
+
+impl<T> WeakSender<T>
+where
+    T: Serialize,
+{
+    /// A new Sender on the channel, if any of its Senders remain
+    ///
+    /// A flusher's Sender does not count. See `Receiver::sender_count`.
+    pub fn upgrade(&self) -> Option<Sender<T>> {
+        let fs_lock = self.fs_lock.upgrade()?;
+        let metrics = self.metrics.upgrade()?;
         {
-            fslock.sender_captured_recv_id = fslock.receiver_read_id;
-            fslock.write_bound = Some(fslock.sender_idx);
+            // Hold the count up while the Sender is made, lest the last of
+            // the others be dropped meanwhile.
+            let mut syn = private::lock(&fs_lock);
+            if syn.live_senders() == 0 {
+                return None;
+            }
+            syn.senders += 1;
+        }
+        let sender = Sender::new(
+            self.name.clone(),
+            &self.root,
+            self.max_bytes,
+            Arc::clone(&fs_lock),
+            metrics,
+        );
+        private::lock(&fs_lock).senders -= 1;
+        let mut sender = sender.ok()?;
+        sender.lanes = self.lanes
+            .iter()
+            .map(WeakSender::upgrade)
+            .collect::<Option<Vec<_>>>()?;
+        Some(sender)
+    }
+}
+
+// Pack `batch` into blocks, compressing each with the channel's trained
+// dictionary where it has one, and otherwise with LZ4 if it compresses blocks.
+fn pack_blocks<T>(fslock: &mut private::FsSync<T>, batch: &[u8]) -> Vec<u8> {
+    let lz4 = fslock.compress_blocks;
+    #[cfg(feature = "dictionary")]
+    {
+        if let Some(ref mut trainer) = fslock.trainer {
+            trainer.sample(batch);
+            return block::pack(batch, fslock.block_bytes, |body| {
+                match trainer.compress(body) {
+                    Some(smaller) => Some((block::DICTIONARY, smaller)),
+                    None if lz4 => block::lz4(body),
+                    None => None,
+                }
+            });
         }
-        fslock.sender_idx += 1;
     }
+    block::pack(batch, fslock.block_bytes, |body| if lz4 { block::lz4(body) } else { None })
+}
 
-    /// Return the sender's name
-    pub fn name(&self) -> &str {
-        &self.name
+// Start the timer waking the Receiver of the channel whose state is `fs_lock`,
+// held as `syn`, as its delayed items come due. The timer holds the channel
+// only while it looks at it, and exits once the channel is gone.
+pub(crate) fn start_delay_timer<T>(
+    fs_lock: &Arc<Mutex<private::FsSync<T>>>,
+    syn: &private::FsSync<T>,
+    supervisor: &Supervisor,
+) -> Option<Arc<private::FlushSignal>>
+where
+    T: Send + 'static,
+{
+    let fs_lock = Arc::downgrade(fs_lock);
+    match syn.runtime.clone() {
+        Some(runtime) => {
+            let job = runtime.spawn(supervisor.job(Task::Timer, move || {
+                match fs_lock.upgrade().map(|fs_lock| private::wake_delayed(&fs_lock)) {
+                    None => Next::Done,
+                    Some(Some(until)) => Next::After(until),
+                    Some(None) => Next::Woken,
+                }
+            }));
+            Some(Arc::new(private::FlushSignal::waking(job)))
+        }
+        None if private::HAS_THREADS => {
+            let signal = Arc::new(private::FlushSignal::default());
+            let thr_signal = Arc::clone(&signal);
+            supervisor
+                .spawn(Task::Timer, move || {
+                    while let Some(until) = fs_lock.upgrade().map(|fs_lock| private::wake_delayed(&fs_lock)) {
+                        // Woken as items are delayed and taken, and now and
+                        // then to see whether the channel is gone
+                        thr_signal.wait(until.unwrap_or(DELAY_TIMER_IDLE));
+                    }
+                })
+                .expect("could not start delay timer");
+            Some(signal)
+        }
+        None => None,
+    }
+}
+
+// Reseal each record of the queue file at `path` under `cipher`'s current key,
+// returning the number resealed, those `packed` in blocks included. The file
+// is rewritten alongside and moved into place, read-only as it was.
+#[cfg(feature = "encryption")]
+fn reseal_segment(cipher: &mut crypt::Cipher, path: &Path, packed: bool) -> io::Result<u64> {
+    let mut buf = fs::read(path)?;
+    let mut resealed = 0;
+    let mut at = layout::records_start(&buf).unwrap_or(buf.len());
+    while let Some(len) = private::next_record(&buf[at..]).map(|(record, _)| record.len()) {
+        let record = &mut buf[at + 4..at + 4 + len];
+        if packed {
+            resealed += block::reseal(record, |inner| cipher.reseal(inner));
+        } else if cipher.reseal(record) {
+            resealed += 1;
+        }
+        at += 4 + len;
+    }
+    let tmp = path.with_extension("rekey");
+    let replaced = fs::write(&tmp, &buf)
+        .and_then(|()| fs::metadata(&tmp))
+        .and_then(|md| {
+            let mut permissions = md.permissions();
+            permissions.set_readonly(true);
+            fs::set_permissions(&tmp, permissions)
+        })
+        .and_then(|()| private::replace_segment(&tmp, path));
+    if let Err(e) = replaced {
+        let _ = private::remove_segment(&tmp);
+        return Err(e);
     }
+    Ok(resealed)
 }
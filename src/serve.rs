@@ -0,0 +1,285 @@
+use dispatch::{DispatchFailure, DispatchReport};
+use select::Select;
+use serde::de::DeserializeOwned;
+use std::error::Error;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use super::Receiver;
+use sync::{self, Mutex};
+
+/// Why a `Consumer` could not handle an item
+pub type ConsumeError = Box<dyn Error + Send + Sync>;
+
+/// What `Receiver::serve` hands a channel's items to
+///
+/// The trait is object safe: a `Box<dyn Consumer<T>>` picked at run time
+/// serves as well as a type known at compile time. Any closure taking `&T` and
+/// returning `Result<(), ConsumeError>` is a Consumer. `handle` may be called
+/// from several threads at once, see `ServeOptions::concurrency`.
+pub trait Consumer<T>: Sync {
+    /// Handle `item`, returning `Ok` once done with it
+    fn handle(&self, item: &T) -> Result<(), ConsumeError>;
+}
+
+impl<T, F> Consumer<T> for F
+where
+    F: Fn(&T) -> Result<(), ConsumeError> + Sync,
+{
+    fn handle(&self, item: &T) -> Result<(), ConsumeError> {
+        self(item)
+    }
+}
+
+impl<T> Consumer<T> for Box<dyn Consumer<T>> {
+    fn handle(&self, item: &T) -> Result<(), ConsumeError> {
+        (**self).handle(item)
+    }
+}
+
+/// How `Receiver::serve` delivers items to its `Consumer`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServeOptions {
+    /// The most items taken from the channel at a time, 64 by default
+    pub batch_size: usize,
+    /// The number of threads handling items at once, 1 by default
+    pub concurrency: usize,
+    /// The times an item the consumer failed on is handed to it again before
+    /// it is given up on, 3 by default
+    pub retries: u32,
+    /// The pause before an item's first retry, doubled for each after, 100
+    /// milliseconds by default
+    pub backoff: Duration,
+}
+
+impl Default for ServeOptions {
+    fn default() -> ServeOptions {
+        ServeOptions {
+            batch_size: 64,
+            concurrency: 1,
+            retries: 3,
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+// Hand `item` to `consumer`, retrying a failure as `opts` says.
+fn deliver<T>(
+    consumer: &dyn Consumer<T>,
+    item: &T,
+    opts: ServeOptions,
+) -> Result<(), DispatchFailure<ConsumeError>> {
+    let mut pause = opts.backoff;
+    let mut retries = 0;
+    loop {
+        match panic::catch_unwind(AssertUnwindSafe(|| consumer.handle(item))) {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(e)) => {
+                if retries >= opts.retries {
+                    return Err(DispatchFailure::Failed(e));
+                }
+                retries += 1;
+                thread::sleep(pause);
+                pause = pause.checked_mul(2).unwrap_or(pause);
+            }
+            Err(_) => return Err(DispatchFailure::Panicked),
+        }
+    }
+}
+
+impl<T> Receiver<T>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    /// Deliver the channel's items to `consumer` until the channel hangs up
+    ///
+    /// Hopper drives the consumer rather than the consumer the Receiver. Items
+    /// are taken from the channel on the calling thread, up to
+    /// `ServeOptions::batch_size` at a time, and handed to `Consumer::handle`
+    /// on `ServeOptions::concurrency` threads. With one thread items are
+    /// handled in the order the channel delivers them; with more, in no
+    /// particular order. Between batches the call waits on the channel as iterating the
+    /// Receiver does, and returns once every Sender is gone and every item
+    /// taken has been handled.
+    ///
+    /// An item is acknowledged when `handle` returns `Ok`, and is then gone
+    /// from the channel, as any item received is. An item `handle` fails on
+    /// is handed to it again after `ServeOptions::backoff`, the pause
+    /// doubling each time, up to `ServeOptions::retries` times. Items still
+    /// failing after that, and items `handle` panicked on, which are not
+    /// retried, are given back in the report. A panic is kept to the item
+    /// that raised it: its thread carries on with the next.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ServeOptions::batch_size` or `ServeOptions::concurrency` is
+    /// zero.
+    pub fn serve<C>(&mut self, consumer: C, opts: ServeOptions) -> DispatchReport<T, ConsumeError>
+    where
+        C: Consumer<T>,
+    {
+        assert!(opts.batch_size > 0, "serve needs a batch size of at least one");
+        assert!(opts.concurrency > 0, "serve needs at least one thread");
+        let consumer: &dyn Consumer<T> = &consumer;
+        let (snd, rcv) = mpsc::sync_channel::<T>(opts.batch_size);
+        let rcv = &Mutex::new(rcv);
+        thread::scope(|scope| {
+            let mut joins = Vec::with_capacity(opts.concurrency);
+            for _ in 0..opts.concurrency {
+                joins.push(scope.spawn(move || {
+                    let mut acked = 0;
+                    let mut unacked = Vec::new();
+                    loop {
+                        let item = match sync::lock(rcv).recv() {
+                            Ok(item) => item,
+                            Err(_) => break,
+                        };
+                        match deliver(consumer, &item, opts) {
+                            Ok(()) => acked += 1,
+                            Err(why) => unacked.push((item, why)),
+                        }
+                    }
+                    (acked, unacked)
+                }));
+            }
+
+            let mut batch = Vec::with_capacity(opts.batch_size);
+            let mut sel: Option<Select> = None;
+            'serve: loop {
+                // Checked first, so the last Sender's items are in by the
+                // receive
+                let hung_up = self.is_hung_up();
+                if self.recv_many_into(&mut batch, opts.batch_size) == 0 {
+                    if hung_up {
+                        break;
+                    }
+                    let rx = &*self;
+                    let sel = sel.get_or_insert_with(|| {
+                        let mut sel = Select::new();
+                        sel.add(rx);
+                        sel
+                    });
+                    sel.ready_or_hang_up(None);
+                    continue;
+                }
+                for item in batch.drain(..) {
+                    if snd.send(item).is_err() {
+                        break 'serve;
+                    }
+                }
+            }
+            drop(snd);
+
+            let mut report = DispatchReport {
+                acked: 0,
+                unacked: Vec::new(),
+            };
+            for jh in joins {
+                let (acked, mut unacked) = jh.join().expect("serve thread died");
+                report.acked += acked;
+                report.unacked.append(&mut unacked);
+            }
+            report
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::*;
+    use super::super::channel;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn serve_retries_failures_and_reports_what_never_succeeded() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel("serve", dir.path()).unwrap();
+        let sender = thread::spawn(move || {
+            for i in 0..500u64 {
+                snd.send(i);
+            }
+        });
+
+        // Item 7 fails twice before it goes through, 13 always fails and 21
+        // panics
+        let attempts = Mutex::new(HashMap::new());
+        let sum = AtomicUsize::new(0);
+        let consumer = |i: &u64| -> Result<(), ConsumeError> {
+            let tries = {
+                let mut attempts = sync::lock(&attempts);
+                let tries = attempts.entry(*i).or_insert(0);
+                *tries += 1;
+                *tries
+            };
+            match *i {
+                7 if tries <= 2 => Err("not yet".into()),
+                13 => Err(format!("failed {} times", tries).into()),
+                21 => panic!("unlucky"),
+                _ => {
+                    sum.fetch_add(*i as usize, Ordering::SeqCst);
+                    Ok(())
+                }
+            }
+        };
+        let opts = ServeOptions {
+            batch_size: 16,
+            concurrency: 4,
+            retries: 2,
+            backoff: Duration::from_millis(1),
+        };
+        let report = rcv.serve(consumer, opts);
+        sender.join().unwrap();
+
+        assert_eq!(498, report.acked);
+        assert_eq!((0..500).sum::<usize>() - 13 - 21, sum.load(Ordering::SeqCst));
+        let mut unacked: Vec<(u64, String)> = report
+            .unacked
+            .iter()
+            .map(|&(i, ref why)| match *why {
+                DispatchFailure::Failed(ref e) => (i, e.to_string()),
+                DispatchFailure::Panicked => (i, "panicked".to_string()),
+            })
+            .collect();
+        unacked.sort();
+        assert_eq!(
+            vec![
+                (13, "failed 3 times".to_string()),
+                (21, "panicked".to_string()),
+            ],
+            unacked
+        );
+        let attempts = sync::lock(&attempts);
+        assert_eq!((3, 3, 1), (attempts[&7], attempts[&13], attempts[&21]));
+        assert_eq!(None, rcv.try_iter().next());
+    }
+
+    #[test]
+    fn serve_takes_a_boxed_consumer_in_order() {
+        struct Collect(Arc<Mutex<Vec<u64>>>);
+
+        impl Consumer<u64> for Collect {
+            fn handle(&self, item: &u64) -> Result<(), ConsumeError> {
+                sync::lock(&self.0).push(*item);
+                Ok(())
+            }
+        }
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel("serve_boxed", dir.path()).unwrap();
+        for i in 0..100u64 {
+            snd.send(i);
+        }
+        drop(snd);
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let consumer: Box<dyn Consumer<u64>> = Box::new(Collect(Arc::clone(&handled)));
+        let report = rcv.serve(consumer, ServeOptions::default());
+        assert_eq!(100, report.acked);
+        assert!(report.unacked.is_empty());
+        assert_eq!((0..100).collect::<Vec<u64>>(), *sync::lock(&handled));
+    }
+}
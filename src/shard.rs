@@ -0,0 +1,256 @@
+use super::{Receiver, RecvError, Select, SendError, Sender};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::cmp;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// The 'send' side of a sharded channel
+///
+/// Created by `ChannelBuilder::build_sharded`. A sharded channel is split
+/// into queues of its own, its shards, each with its own lock, so Senders on
+/// different shards never contend. Each `ShardedSender` sends every item on
+/// one shard, handed out in turn as the Senders are cloned, so items from any
+/// one Sender are received in the order it sent them. Items from Senders on
+/// different shards may interleave arbitrarily, as with `OrderMode::PerSender`.
+/// Clone at least one Sender per shard to spread the load.
+pub struct ShardedSender<T> {
+    shards: Vec<Sender<T>>,
+    shard: usize,
+    // Shared by the clones, to hand out shards in turn
+    next: Arc<AtomicUsize>,
+}
+
+impl<T> fmt::Debug for ShardedSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ShardedSender")
+            .field("shards", &self.shards.len())
+            .field("shard", &self.shard)
+            .finish()
+    }
+}
+
+impl<'de, T> Clone for ShardedSender<T>
+where
+    T: Serialize + Deserialize<'de>,
+{
+    fn clone(&self) -> ShardedSender<T> {
+        ShardedSender {
+            shards: self.shards.clone(),
+            shard: self.next.fetch_add(1, Ordering::Relaxed) % self.shards.len(),
+            next: Arc::clone(&self.next),
+        }
+    }
+}
+
+impl<T> ShardedSender<T>
+where
+    T: Serialize,
+{
+    /// Send `event` on this Sender's shard
+    ///
+    /// See `Sender::send`.
+    pub fn send(&mut self, event: T) {
+        self.shards[self.shard].send(event)
+    }
+
+    /// Send `event` on this Sender's shard, handing it back if the shard
+    /// refuses it
+    ///
+    /// See `Sender::try_send`.
+    pub fn try_send(&mut self, event: T) -> Result<(), SendError<T>> {
+        self.shards[self.shard].try_send(event)
+    }
+
+    /// Hand any items staged by this Sender to its shard
+    ///
+    /// See `Sender::flush`.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.shards[self.shard].flush()
+    }
+
+    /// The index of the shard this Sender sends on
+    pub fn shard(&self) -> usize {
+        self.shard
+    }
+
+    /// Return the number of shards
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+}
+
+/// The 'receive' side of a sharded channel
+///
+/// Takes items from the channel's shards in turn, one shard after another,
+/// so that a busy shard cannot starve the rest. See `ShardedSender`.
+pub struct ShardedReceiver<T> {
+    shards: Vec<Receiver<T>>,
+    // The shard to look at first on the next receive
+    turn: usize,
+}
+
+impl<T> fmt::Debug for ShardedReceiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ShardedReceiver")
+            .field("shards", &self.shards.len())
+            .field("turn", &self.turn)
+            .finish()
+    }
+}
+
+impl<T> ShardedReceiver<T>
+where
+    T: DeserializeOwned,
+{
+    /// Receive the next item, if one is waiting on any shard
+    ///
+    /// Returns `Ok(None)` once every shard is empty. See `Receiver::try_recv`.
+    pub fn try_recv(&mut self) -> Result<Option<T>, RecvError> {
+        let shards = self.shards.len();
+        for turn in self.turn..self.turn + shards {
+            if let Some(item) = self.shards[turn % shards].try_recv()? {
+                self.turn = (turn + 1) % shards;
+                return Ok(Some(item));
+            }
+        }
+        Ok(None)
+    }
+
+    /// An iterator over the items waiting on the shards, ending once every
+    /// shard is empty
+    pub fn iter(&mut self) -> ShardedIter<'_, T> {
+        ShardedIter { rx: self }
+    }
+
+    /// Receive up to `max` items, waiting up to `timeout` for them to arrive
+    ///
+    /// Takes what is waiting on each shard in turn, then waits on all of
+    /// them at once while the batch is short. See `Receiver::recv_batch`.
+    pub fn recv_batch(&mut self, max: usize, timeout: Duration) -> Vec<T>
+    where
+        T: Send + 'static,
+    {
+        let deadline = Instant::now() + timeout;
+        let mut batch = Vec::with_capacity(cmp::min(max, 1024));
+        let mut sel: Option<Select> = None;
+        loop {
+            let shards = self.shards.len();
+            for turn in self.turn..self.turn + shards {
+                if batch.len() >= max {
+                    break;
+                }
+                let wanted = max - batch.len();
+                let rcv = &mut self.shards[turn % shards];
+                batch.extend(rcv.recv_batch(wanted, Duration::from_millis(0)));
+            }
+            self.turn = (self.turn + 1) % shards;
+            let now = Instant::now();
+            if batch.len() >= max || now >= deadline {
+                return batch;
+            }
+            if sel.is_none() {
+                let mut watch = Select::new();
+                for rcv in &self.shards {
+                    watch.add(rcv);
+                }
+                sel = Some(watch);
+            }
+            if let Some(ref mut sel) = sel {
+                sel.ready_timeout(deadline - now);
+            }
+        }
+    }
+
+    /// The shards' Receivers, in shard order
+    pub fn shards(&mut self) -> &mut [Receiver<T>] {
+        &mut self.shards
+    }
+}
+
+/// An iterator over the items of a `ShardedReceiver`, see
+/// `ShardedReceiver::iter`
+#[derive(Debug)]
+pub struct ShardedIter<'a, T: 'a> {
+    rx: &'a mut ShardedReceiver<T>,
+}
+
+impl<'a, T> Iterator for ShardedIter<'a, T>
+where
+    T: DeserializeOwned,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx
+            .try_recv()
+            .expect("could not decode a queue file record")
+    }
+}
+
+/// Split (Sender, Receiver) pairs, one per shard, into a sharded channel
+pub(crate) fn assemble<T>(
+    pairs: Vec<(Sender<T>, Receiver<T>)>,
+) -> (ShardedSender<T>, ShardedReceiver<T>) {
+    let (senders, receivers) = pairs.into_iter().unzip();
+    (
+        ShardedSender {
+            shards: senders,
+            shard: 0,
+            next: Arc::new(AtomicUsize::new(1)),
+        },
+        ShardedReceiver {
+            shards: receivers,
+            turn: 0,
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::super::ChannelBuilder;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn senders_keep_their_order_across_shards() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (snd, mut rcv) = ChannelBuilder::new("sharded", dir.path())
+            .max_bytes(1024)
+            .build_sharded::<(u64, u64)>(4)
+            .unwrap();
+        assert_eq!(4, snd.shards());
+        assert!(dir.path().join("sharded").join("3").is_dir());
+        let joins: Vec<_> = (0..4u64)
+            .map(|producer| {
+                let mut snd = snd.clone();
+                assert_eq!((producer as usize + 1) % 4, snd.shard());
+                thread::spawn(move || for i in 0..2000 {
+                    snd.send((producer, i));
+                })
+            })
+            .collect();
+        drop(snd);
+        for jh in joins {
+            jh.join().unwrap();
+        }
+
+        let mut next = [0; 4];
+        let mut received = 0;
+        while received < 8000 {
+            let batch = rcv.recv_batch(512, Duration::from_millis(100));
+            assert!(!batch.is_empty());
+            for (producer, i) in batch {
+                assert_eq!(next[producer as usize], i);
+                next[producer as usize] += 1;
+                received += 1;
+            }
+        }
+        assert_eq!(None, rcv.iter().next());
+    }
+}
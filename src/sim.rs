@@ -0,0 +1,267 @@
+//! A deterministic executor for tests of channels' background work
+//!
+//! A channel's flusher, read-ahead and retention collector run on threads of
+//! their own or on a `Runtime`'s pool, paced by the real clock, and a test of
+//! them has to sleep and hope the work has been done by the time it wakes. A
+//! `Simulation` is a `Runtime` with no threads and a clock of its own. Build
+//! channels on it with `ChannelBuilder::simulation` and their jobs, and any
+//! the test adds with `spawn`, run on the test's thread only as `advance` and
+//! `run_until_idle` drive them, in the order they come due on the
+//! simulation's clock and, where several are due at once, in the order they
+//! were spawned. The same channel clock stamps items for a TTL, brings items
+//! sent with `Sender::send_after` due and ages retained files, so a run goes
+//! the same way every time.
+//!
+//! The waits of a channel built on a simulation run its jobs rather than
+//! block. `Select` and `Receiver::recv_batch` run them until a Receiver has
+//! data, measuring their timeouts on the simulation's clock. A send to a full
+//! channel whose `OverflowPolicy` is `OverflowPolicy::Block` runs them until
+//! the channel has room. With no job left to run, and so nothing that could
+//! end the wait, a wait without a timeout panics rather than hang. Rate
+//! limits, `ChannelBuilder::coalesce_wakes` and the channels' own blocking on
+//! their locks follow the real clock as ever.
+//!
+//! # Example
+//! ```
+//! extern crate tempdir;
+//! extern crate hopper;
+//!
+//! use hopper::ChannelBuilder;
+//! use hopper::sim::Simulation;
+//! use std::time::Duration;
+//!
+//! let dir = tempdir::TempDir::new("hopper").unwrap();
+//! let sim = Simulation::new();
+//! let (mut snd, mut rcv) = ChannelBuilder::new("sim", dir.path())
+//!     .simulation(&sim)
+//!     .ttl(Duration::from_secs(30))
+//!     .build()
+//!     .unwrap();
+//!
+//! snd.send(1);
+//! sim.advance(Duration::from_secs(31));
+//! snd.send(2);
+//! // No time passes in the real world while the Receiver waits.
+//! assert_eq!(vec![2], rcv.recv_batch(2, Duration::from_secs(60)));
+//! assert_eq!(Duration::from_secs(91), sim.elapsed());
+//! ```
+use clock::Clock;
+use runtime::Runtime;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use testing::ManualClock;
+
+pub use runtime::Next;
+
+/// A thread-less Runtime on a virtual clock, see the module documentation
+///
+/// Clones refer to the same simulation.
+#[derive(Debug, Clone)]
+pub struct Simulation {
+    runtime: Runtime,
+    clock: Arc<ManualClock>,
+    started: Instant,
+}
+
+impl Default for Simulation {
+    fn default() -> Simulation {
+        Simulation::new()
+    }
+}
+
+impl Simulation {
+    /// A simulation with no jobs, its clock standing at the current time
+    pub fn new() -> Simulation {
+        let clock = Arc::new(ManualClock::new());
+        Simulation {
+            runtime: Runtime::driven(Arc::clone(&clock)),
+            started: clock.now(),
+            clock: clock,
+        }
+    }
+
+    /// The simulation as a `Runtime`, for `ChannelBuilder::runtime`
+    pub fn runtime(&self) -> &Runtime {
+        &self.runtime
+    }
+
+    /// The simulation's clock, for `ChannelBuilder::clock`
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        Arc::clone(&self.clock) as Arc<dyn Clock>
+    }
+
+    /// How far the simulation's clock has moved on since it was made
+    pub fn elapsed(&self) -> Duration {
+        self.clock.now() - self.started
+    }
+
+    /// Return the number of jobs the simulation has, channels' and spawned
+    pub fn jobs(&self) -> usize {
+        self.runtime.jobs()
+    }
+
+    /// Run `task` now and then again whenever it asks to be, as the
+    /// simulation is driven
+    ///
+    /// A task stands in for a producer or consumer of the test's channels:
+    /// it does a turn of its work and returns when it wants its next turn,
+    /// `Next::After` a delay on the simulation's clock or `Next::Done`. A
+    /// task waiting by `Next::Woken` is never woken.
+    pub fn spawn<F>(&self, task: F)
+    where
+        F: FnMut() -> Next + Send + 'static,
+    {
+        self.runtime.spawn(task);
+    }
+
+    /// Run every job due now, and any they make due now in turn, returning the
+    /// number of turns run
+    ///
+    /// A job that asks to run again after no time at all runs on until it
+    /// asks for more.
+    pub fn run_until_idle(&self) -> usize {
+        let mut turns = 0;
+        while self.runtime.run_due() {
+            turns += 1;
+        }
+        turns
+    }
+
+    /// Move the clock on by `by`, running each job as it comes due on the
+    /// way, and return the number of turns run
+    pub fn advance(&self, by: Duration) -> usize {
+        let until = self.clock.now() + by;
+        let mut turns = self.run_until_idle();
+        while let Some(at) = self.runtime.next_due() {
+            if at > until {
+                break;
+            }
+            self.runtime.advance_to(at);
+            turns += self.run_until_idle();
+        }
+        self.runtime.advance_to(until);
+        turns
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::*;
+    use super::super::{ChannelBuilder, OverflowPolicy, Select};
+    use std::sync::Mutex;
+
+    #[test]
+    fn jobs_run_in_due_order_on_the_virtual_clock() {
+        let sim = Simulation::new();
+        let runs = Arc::new(Mutex::new(Vec::new()));
+        for &(name, every) in &[("fast", 10), ("slow", 25)] {
+            let runs = Arc::clone(&runs);
+            let sim_clock = sim.clone();
+            sim.spawn(move || {
+                runs.lock().unwrap().push((name, sim_clock.elapsed().as_millis()));
+                Next::After(Duration::from_millis(every))
+            });
+        }
+        assert_eq!(2, sim.run_until_idle());
+        assert_eq!(0, sim.run_until_idle());
+        assert_eq!(5, sim.advance(Duration::from_millis(40)));
+        assert_eq!(Duration::from_millis(40), sim.elapsed());
+        assert_eq!(
+            vec![
+                ("fast", 0),
+                ("slow", 0),
+                ("fast", 10),
+                ("fast", 20),
+                ("slow", 25),
+                ("fast", 30),
+                ("fast", 40),
+            ],
+            *runs.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn waits_on_a_simulated_channel_run_its_jobs() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let sim = Simulation::new();
+        let (snd, mut rcv) = ChannelBuilder::new("sim_waits", dir.path())
+            .simulation(&sim)
+            .build()
+            .unwrap();
+
+        // A timeout with nothing to run passes on the simulation's clock.
+        assert!(rcv.recv_batch(4, Duration::from_secs(3600)).is_empty());
+        assert_eq!(Duration::from_secs(3600), sim.elapsed());
+
+        // A producer sending every second is run by the wait for its items.
+        let mut producer = snd.clone();
+        let mut sent = 0;
+        sim.spawn(move || {
+            producer.send(sent);
+            sent += 1;
+            if sent == 10 {
+                Next::Done
+            } else {
+                Next::After(Duration::from_secs(1))
+            }
+        });
+        let mut sel = Select::new();
+        sel.add(&rcv);
+        assert_eq!(Some(0), sel.ready_timeout(Duration::from_secs(5)));
+        assert_eq!(vec![0, 1, 2, 3], rcv.recv_batch(4, Duration::from_secs(60)));
+        assert_eq!(Duration::from_secs(3603), sim.elapsed());
+        assert_eq!(
+            (4..10).collect::<Vec<_>>(),
+            rcv.recv_batch(100, Duration::from_secs(60))
+        );
+        assert_eq!(Duration::from_secs(3603 + 60), sim.elapsed());
+
+        // A blocking send to a full channel runs the consumer that makes room.
+        let (mut snd, rcv) = ChannelBuilder::new("sim_full", dir.path())
+            .simulation(&sim)
+            .memory_only(true)
+            .overflow_policy(OverflowPolicy::Block)
+            .build()
+            .unwrap();
+        let rcv = Arc::new(Mutex::new(rcv));
+        let consumer = Arc::clone(&rcv);
+        let taken = Arc::new(Mutex::new(Vec::new()));
+        let consumer_taken = Arc::clone(&taken);
+        sim.spawn(move || {
+            consumer_taken.lock().unwrap().extend(consumer.lock().unwrap().try_iter().take(256));
+            Next::After(Duration::from_secs(1))
+        });
+        let before = sim.elapsed();
+        for i in 0..2068u64 {
+            snd.send(i);
+        }
+        // The full channel has room again once its in-memory tier, 1024
+        // items, has drained: four turns of the consumer, a second apart, and
+        // a fifth to take from the disk buffer moved up in its place.
+        assert_eq!(Duration::from_secs(4), sim.elapsed() - before);
+        let mut received = taken.lock().unwrap().clone();
+        assert_eq!(5 * 256, received.len());
+        received.extend(rcv.lock().unwrap().try_iter());
+        assert_eq!((0..2068).collect::<Vec<_>>(), received);
+    }
+
+    #[test]
+    fn read_ahead_runs_as_the_receiver_waits_for_it() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let sim = Simulation::new();
+        let (mut snd, mut rcv) = ChannelBuilder::new("sim_read_ahead", dir.path())
+            .simulation(&sim)
+            .max_bytes(4096)
+            .read_ahead(512)
+            .build()
+            .unwrap();
+        for i in 0..5000u64 {
+            snd.send(i);
+        }
+        assert!(rcv.metrics().records_written > 0);
+        assert_eq!((0..5000).collect::<Vec<_>>(), rcv.try_iter().collect::<Vec<_>>());
+        assert_eq!(Duration::from_secs(0), sim.elapsed());
+    }
+}
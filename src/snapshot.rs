@@ -0,0 +1,303 @@
+//! Portable snapshots of a channel's backlog
+//!
+//! `Receiver::export_snapshot` captures the items waiting in a channel in a
+//! single file that stands alone: it does not depend on the channel's queue
+//! files, TTL stamps or encryption key. Integers are big-endian.
+//!
+//! ```text
+//! magic      8 bytes    b"HOPSNAP\0"
+//! version    u32        VERSION
+//! type_len   u32
+//! type       type_len bytes, the name of the item type, UTF-8
+//! records    each a u32 length followed by the item's bincode encoding, in
+//!            the order the Receiver would have delivered them
+//! count      u64        the number of records
+//! checksum   u32        CRC-32 (IEEE) of every byte before it
+//! ```
+//!
+//! `ChannelBuilder::restore_from` reads a snapshot back into a new channel.
+use bincode;
+use serde::de::DeserializeOwned;
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+use super::Error;
+
+/// The bytes every snapshot starts with
+pub const MAGIC: &[u8; 8] = b"HOPSNAP\0";
+
+/// The snapshot format version written by this hopper
+pub const VERSION: u32 = 1;
+
+// Writes a snapshot, keeping the checksum as it goes.
+pub(crate) struct Writer<W: Write> {
+    out: W,
+    crc: u32,
+    count: u64,
+}
+
+impl<W: Write> Writer<W> {
+    /// Start a snapshot of items of the type named `type_name`
+    pub fn new(out: W, type_name: &str) -> io::Result<Writer<W>> {
+        let mut writer = Writer {
+            out: out,
+            crc: 0,
+            count: 0,
+        };
+        writer.write(MAGIC)?;
+        writer.write(&VERSION.to_be_bytes())?;
+        writer.write(&(type_name.len() as u32).to_be_bytes())?;
+        writer.write(type_name.as_bytes())?;
+        Ok(writer)
+    }
+
+    /// Append the record whose payload is the bincode encoding `item`
+    pub fn record(&mut self, item: &[u8]) -> io::Result<()> {
+        self.write(&(item.len() as u32).to_be_bytes())?;
+        self.write(item)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Write the trailer, returning the underlying writer and the number of
+    /// records written
+    pub fn finish(mut self) -> io::Result<(W, u64)> {
+        let count = self.count;
+        self.write(&count.to_be_bytes())?;
+        let crc = self.crc;
+        self.out.write_all(&crc.to_be_bytes())?;
+        Ok((self.out, count))
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.crc = crc32(self.crc, bytes);
+        self.out.write_all(bytes)
+    }
+}
+
+// Reads the items of a snapshot back, once the snapshot as a whole has been
+// found sound.
+pub(crate) struct Reader<T> {
+    input: BufReader<File>,
+    remaining: u64,
+    buf: Vec<u8>,
+    item: PhantomData<T>,
+}
+
+impl<T> Reader<T>
+where
+    T: DeserializeOwned,
+{
+    /// Open the snapshot at `path` of items of the type named `type_name`
+    ///
+    /// The whole file is read through once to check its layout and checksum
+    /// before any item is given out. Returns `Error::InvalidSnapshot` if the
+    /// file cannot be read, is damaged or is of another type or version.
+    pub fn open(path: &Path, type_name: &str) -> Result<Reader<T>, Error> {
+        let (start, count) = check(path, type_name).map_err(|_| Error::InvalidSnapshot)?;
+        let mut input = File::open(path)
+            .map(BufReader::new)
+            .map_err(|_| Error::InvalidSnapshot)?;
+        io::copy(&mut (&mut input).take(start), &mut io::sink())
+            .map_err(|_| Error::InvalidSnapshot)?;
+        Ok(Reader {
+            input: input,
+            remaining: count,
+            buf: Vec::new(),
+            item: PhantomData,
+        })
+    }
+
+    /// The next item of the snapshot, None once all have been read
+    ///
+    /// Returns `Error::InvalidSnapshot` if the item does not decode as `T`, or
+    /// if the file has changed since it was opened.
+    pub fn next(&mut self) -> Result<Option<T>, Error> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        let len = read_u32(&mut self.input).map_err(|_| Error::InvalidSnapshot)?;
+        self.buf.resize(len as usize, 0);
+        self.input
+            .read_exact(&mut self.buf)
+            .map_err(|_| Error::InvalidSnapshot)?;
+        self.remaining -= 1;
+        bincode::deserialize(&self.buf)
+            .map(Some)
+            .map_err(|_| Error::InvalidSnapshot)
+    }
+}
+
+// Walk the snapshot at `path`, returning the offset its records start at and
+// their number if it is sound.
+fn check(path: &Path, type_name: &str) -> io::Result<(u64, u64)> {
+    fn invalid() -> io::Error {
+        io::Error::new(ErrorKind::InvalidData, "invalid snapshot")
+    }
+
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut input = Checked {
+        input: BufReader::new(file),
+        crc: 0,
+    };
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC || read_u32(&mut input)? != VERSION {
+        return Err(invalid());
+    }
+    let name_len = u64::from(read_u32(&mut input)?);
+    if name_len != type_name.len() as u64 {
+        return Err(invalid());
+    }
+    let mut name = vec![0; type_name.len()];
+    input.read_exact(&mut name)?;
+    if name != type_name.as_bytes() {
+        return Err(invalid());
+    }
+    let start = 16 + name_len;
+    // The trailer is the last twelve bytes, the records everything between.
+    let end = match len.checked_sub(12) {
+        Some(end) if end >= start => end,
+        _ => return Err(invalid()),
+    };
+    let mut at = start;
+    let mut count = 0;
+    while at < end {
+        if end - at < 4 {
+            return Err(invalid());
+        }
+        let record_len = u64::from(read_u32(&mut input)?);
+        if end - at - 4 < record_len {
+            return Err(invalid());
+        }
+        io::copy(&mut (&mut input).take(record_len), &mut io::sink())?;
+        at += 4 + record_len;
+        count += 1;
+    }
+    let mut stored = [0; 8];
+    input.read_exact(&mut stored)?;
+    if u64::from_be_bytes(stored) != count {
+        return Err(invalid());
+    }
+    let crc = input.crc;
+    if read_u32(&mut input.input)? != crc {
+        return Err(invalid());
+    }
+    Ok((start, count))
+}
+
+// A reader keeping the checksum of what has been read through it.
+struct Checked<R> {
+    input: R,
+    crc: u32,
+}
+
+impl<R: Read> Read for Checked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.input.read(buf)?;
+        self.crc = crc32(self.crc, &buf[..n]);
+        Ok(n)
+    }
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+// Extend the CRC-32 `crc` of some bytes over `bytes`.
+pub(crate) fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &b in bytes {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::*;
+    use std::fs;
+
+    fn write_u64s(path: &Path, items: &[u64]) {
+        let mut writer = Writer::new(Vec::new(), "u64").unwrap();
+        for item in items {
+            writer.record(&bincode::serialize(item, bincode::Infinite).unwrap()).unwrap();
+        }
+        fs::write(path, writer.finish().unwrap().0).unwrap();
+    }
+
+    #[test]
+    fn crc32_matches_the_standard_check_value() {
+        assert_eq!(0xCBF4_3926, crc32(0, b"123456789"));
+        assert_eq!(crc32(0, b"123456789"), crc32(crc32(0, b"1234"), b"56789"));
+    }
+
+    #[test]
+    fn snapshot_layout() {
+        let mut writer = Writer::new(Vec::new(), "u8").unwrap();
+        writer.record(&[7]).unwrap();
+        let (buf, count) = writer.finish().unwrap();
+        assert_eq!(1, count);
+
+        let mut expected = Vec::new();
+        expected.extend_from_slice(MAGIC);
+        expected.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2, b'u', b'8']);
+        expected.extend_from_slice(&[0, 0, 0, 1, 7]);
+        expected.extend_from_slice(&1u64.to_be_bytes());
+        let crc = crc32(0, &expected);
+        expected.extend_from_slice(&crc.to_be_bytes());
+        assert_eq!(expected, buf);
+    }
+
+    #[test]
+    fn reader_gives_back_what_was_written() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let path = dir.path().join("snap");
+        write_u64s(&path, &[1, 2, 3]);
+
+        let mut reader = Reader::<u64>::open(&path, "u64").unwrap();
+        assert_eq!(Ok(Some(1)), reader.next());
+        assert_eq!(Ok(Some(2)), reader.next());
+        assert_eq!(Ok(Some(3)), reader.next());
+        assert_eq!(Ok(None), reader.next());
+    }
+
+    #[test]
+    fn reader_refuses_damaged_or_foreign_snapshots() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let path = dir.path().join("snap");
+        write_u64s(&path, &[1, 2, 3]);
+        let good = fs::read(&path).unwrap();
+        let open = |bytes: &[u8], name: &str| {
+            fs::write(&path, bytes).unwrap();
+            Reader::<u64>::open(&path, name).err()
+        };
+
+        assert_eq!(Some(Error::InvalidSnapshot), open(&good, "u32"));
+        for at in 0..good.len() {
+            let mut flipped = good.clone();
+            flipped[at] ^= 0x10;
+            assert_eq!(Some(Error::InvalidSnapshot), open(&flipped, "u64"));
+        }
+        for len in 0..good.len() {
+            assert_eq!(Some(Error::InvalidSnapshot), open(&good[..len], "u64"));
+        }
+        let mut newer = good.clone();
+        newer[11] = 2;
+        let crc = crc32(0, &newer[..newer.len() - 4]);
+        let at = newer.len() - 4;
+        newer[at..].copy_from_slice(&crc.to_be_bytes());
+        assert_eq!(Some(Error::InvalidSnapshot), open(&newer, "u64"));
+        assert!(Reader::<u64>::open(&dir.path().join("missing"), "u64").is_err());
+    }
+}
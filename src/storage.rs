@@ -0,0 +1,105 @@
+//! The file layer under cross-process queue files
+//!
+//! `ProcessSender` writes its queue files through `Io` rather than straight
+//! to `fs::File` so that tests can put a faulty file underneath it and check
+//! that what survives a crash is always recoverable. The faults are offered
+//! to other crates' tests too, through `testing`, with the `harness` feature.
+//! Readers open queue files directly: whatever a fault left behind is what
+//! they find on disk.
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+/// A queue file open for appending
+pub trait Io: Write + Send {
+    /// Make everything written so far durable
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl Io for fs::File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+/// Opens the queue file at a path for appending, creating it if need be
+pub type Opener = Arc<dyn Fn(&Path) -> io::Result<Box<dyn Io>> + Send + Sync>;
+
+/// The `Opener` for real files
+pub fn files() -> Opener {
+    Arc::new(|path: &Path| {
+        let fp = fs::OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(Box::new(fp) as Box<dyn Io>)
+    })
+}
+
+#[cfg(any(test, feature = "harness"))]
+pub mod faults {
+    //! An `Io` that misbehaves on request
+    use super::{Io, Opener};
+    use std::fs;
+    use std::io::{self, Write};
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    /// The faults to inject, shared by every file an `Opener` opens
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct Faults {
+        /// Accept at most this many bytes per write call
+        pub short_writes: Option<usize>,
+        /// Fail every `sync`
+        pub fail_sync: bool,
+        /// Lose power once this many bytes have been written across all
+        /// files: the bytes past it are reported written but never land
+        pub power_loss_at: Option<u64>,
+        written: u64,
+    }
+
+    struct FaultyFile {
+        inner: fs::File,
+        faults: Arc<Mutex<Faults>>,
+    }
+
+    impl Write for FaultyFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut faults = self.faults.lock().unwrap();
+            let len = faults.short_writes.map_or(buf.len(), |n| n.min(buf.len()));
+            let landed = match faults.power_loss_at {
+                Some(at) => (at.saturating_sub(faults.written) as usize).min(len),
+                None => len,
+            };
+            self.inner.write_all(&buf[..landed])?;
+            faults.written += len as u64;
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    impl Io for FaultyFile {
+        fn sync(&mut self) -> io::Result<()> {
+            if self.faults.lock().unwrap().fail_sync {
+                Err(io::Error::other("injected sync failure"))
+            } else {
+                self.inner.sync_data()
+            }
+        }
+    }
+
+    /// An `Opener` whose files suffer `faults`
+    pub fn opener(faults: Faults) -> (Opener, Arc<Mutex<Faults>>) {
+        let faults = Arc::new(Mutex::new(faults));
+        let shared = Arc::clone(&faults);
+        let opener: Opener = Arc::new(move |path: &Path| {
+            let inner = fs::OpenOptions::new().append(true).create(true).open(path)?;
+            Ok(Box::new(FaultyFile {
+                inner: inner,
+                faults: Arc::clone(&shared),
+            }) as Box<dyn Io>)
+        });
+        (opener, faults)
+    }
+}
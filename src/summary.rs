@@ -0,0 +1,211 @@
+//! Summaries of sealed queue files
+//!
+//! As a channel's Sender seals a queue file it writes a summary of it
+//! alongside, named for the queue file with a `.summary` extension: the
+//! number of records and of items the file holds, the place in the channel
+//! of its first and last item, the send times of its oldest and newest items
+//! and the bytes of its records as stored and before packing. Tools, retention and eviction read the summary
+//! rather than the whole file. The summary is one line of whitespace
+//! separated decimal fields in that order, a send time `-` where the channel
+//! does not stamp its items.
+//!
+//! A summary goes wherever its queue file does: into the archive, into
+//! retention and away with the file. Only files sealed by a channel built
+//! with `ChannelBuilder` have one, and a file rewritten in place--by
+//! `Sender::rekey` or `repair`--loses its summary, so readers fall back to
+//! reading the file itself where there is none.
+use std::cmp;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The extension of the summary written alongside a sealed queue file
+pub const EXTENSION: &str = "summary";
+
+/// What a sealed queue file holds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SegmentSummary {
+    /// The number of records in the file, a packed block counted once
+    pub records: u64,
+    /// The number of items in the file, those packed in blocks included
+    pub items: u64,
+    /// The place of the file's first item among the items the channel has
+    /// paged out since it was built, counting from 0
+    pub first_seq: u64,
+    /// The place of the file's last item, as `first_seq`
+    pub last_seq: u64,
+    /// The send time of the file's oldest item, in milliseconds since the
+    /// UNIX epoch, if the channel stamps its items
+    pub first_stamp: Option<u64>,
+    /// The send time of the file's newest item, as `first_stamp`
+    pub last_stamp: Option<u64>,
+    /// The bytes of the records written to the file, after any packing and
+    /// compression
+    pub stored_bytes: u64,
+    /// The bytes of the file's items as records before packing and
+    /// compression
+    pub item_bytes: u64,
+}
+
+impl SegmentSummary {
+    /// The send time of the file's newest item, if known
+    pub fn newest(&self) -> Option<SystemTime> {
+        self.last_stamp.map(|ms| UNIX_EPOCH + Duration::from_millis(ms))
+    }
+
+    /// Count a batch of `items` items written to the file as `records`
+    /// records, `seq` the place of the first item, `stamps` their send times
+    /// and `item_bytes` and `stored_bytes` their size before and after
+    /// packing
+    pub(crate) fn add<I>(
+        &mut self,
+        seq: u64,
+        items: u64,
+        records: u64,
+        stamps: I,
+        item_bytes: u64,
+        stored_bytes: u64,
+    ) where
+        I: IntoIterator<Item = u64>,
+    {
+        if items == 0 {
+            return;
+        }
+        if self.items == 0 {
+            self.first_seq = seq;
+        }
+        self.items += items;
+        self.records += records;
+        self.last_seq = seq + items - 1;
+        for stamp in stamps {
+            self.first_stamp = Some(self.first_stamp.map_or(stamp, |s| cmp::min(s, stamp)));
+            self.last_stamp = Some(self.last_stamp.map_or(stamp, |s| cmp::max(s, stamp)));
+        }
+        self.item_bytes += item_bytes;
+        self.stored_bytes += stored_bytes;
+    }
+
+    fn encode(&self) -> String {
+        let stamp = |stamp: Option<u64>| stamp.map_or("-".to_string(), |s| s.to_string());
+        format!(
+            "{} {} {} {} {} {} {} {}\n",
+            self.records,
+            self.items,
+            self.first_seq,
+            self.last_seq,
+            stamp(self.first_stamp),
+            stamp(self.last_stamp),
+            self.stored_bytes,
+            self.item_bytes
+        )
+    }
+
+    fn decode(line: &str) -> Option<SegmentSummary> {
+        let mut fields = line.split_whitespace();
+        let mut next = || fields.next();
+        let stamp = |field: &str| -> Option<Option<u64>> {
+            if field == "-" {
+                Some(None)
+            } else {
+                field.parse().ok().map(Some)
+            }
+        };
+        Some(SegmentSummary {
+            records: next()?.parse().ok()?,
+            items: next()?.parse().ok()?,
+            first_seq: next()?.parse().ok()?,
+            last_seq: next()?.parse().ok()?,
+            first_stamp: stamp(next()?)?,
+            last_stamp: stamp(next()?)?,
+            stored_bytes: next()?.parse().ok()?,
+            item_bytes: next()?.parse().ok()?,
+        })
+    }
+}
+
+/// The path of the summary of the queue file at `segment`
+pub fn path(segment: &Path) -> PathBuf {
+    let mut name = segment
+        .file_name()
+        .map(|n| n.to_os_string())
+        .unwrap_or_default();
+    name.push(".");
+    name.push(EXTENSION);
+    segment.with_file_name(name)
+}
+
+/// Read the summary of the queue file at `segment`, if it has one
+pub fn read(segment: &Path) -> Option<SegmentSummary> {
+    fs::read_to_string(path(segment))
+        .ok()
+        .and_then(|line| SegmentSummary::decode(&line))
+}
+
+/// The send time of the newest item in the queue file at `segment`, by its
+/// summary where that records one, else the time the file was last written
+/// to, which is no earlier
+pub fn newest_item(segment: &Path) -> Option<SystemTime> {
+    read(segment)
+        .and_then(|summary| summary.newest())
+        .or_else(|| fs::metadata(segment).and_then(|md| md.modified()).ok())
+}
+
+/// Write `summary` alongside the queue file at `segment`
+///
+/// The summary is written aside and moved into place, so a reader finds
+/// either all of it or none.
+pub fn write(segment: &Path, summary: &SegmentSummary) -> io::Result<()> {
+    let dest = path(segment);
+    let tmp = dest.with_extension(format!("{}.tmp", EXTENSION));
+    fs::write(&tmp, summary.encode())?;
+    fs::rename(&tmp, dest)
+}
+
+/// Move the summary of the queue file at `from`, if it has one, to go with
+/// the file at `to`
+pub fn rename(from: &Path, to: &Path) {
+    let _ = fs::rename(path(from), path(to));
+}
+
+/// Remove the summary of the queue file at `segment`, if it has one
+pub fn remove(segment: &Path) {
+    let _ = fs::remove_file(path(segment));
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::*;
+
+    #[test]
+    fn summaries_add_up_and_round_trip() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let segment = dir.path().join("3");
+        assert_eq!(dir.path().join("3.summary"), path(&segment));
+        assert_eq!(None, read(&segment));
+
+        let mut summary = SegmentSummary::default();
+        summary.add(10, 2, 2, vec![500, 400], 20, 20);
+        summary.add(12, 0, 0, vec![], 0, 0);
+        summary.add(12, 3, 1, vec![600, 550, 700], 30, 12);
+        assert_eq!((3, 5), (summary.records, summary.items));
+        assert_eq!((10, 14), (summary.first_seq, summary.last_seq));
+        assert_eq!((Some(400), Some(700)), (summary.first_stamp, summary.last_stamp));
+        assert_eq!((32, 50), (summary.stored_bytes, summary.item_bytes));
+
+        write(&segment, &summary).unwrap();
+        assert_eq!(Some(summary), read(&segment));
+        let unstamped = SegmentSummary {
+            records: 1,
+            items: 1,
+            ..SegmentSummary::default()
+        };
+        write(&segment, &unstamped).unwrap();
+        assert_eq!(Some(unstamped), read(&segment));
+
+        remove(&segment);
+        assert_eq!(None, read(&segment));
+    }
+}
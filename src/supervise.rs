@@ -0,0 +1,371 @@
+//! Supervision of a channel's background tasks
+//!
+//! Each task a channel runs in the background--its flusher, retention
+//! collector, scrubber, read-ahead and the like--runs under the channel's
+//! `Supervisor`. A task's thread is named for the task and the channel. A
+//! panic in a task is caught rather than left to end its thread: it is
+//! recorded for `Sender::health`, handed to the channel's observer as
+//! `QueueEvent::TaskPanicked` and the task started again after a pause, up to
+//! `RESTART_LIMIT` times. A task that panics more is given up on, and the
+//! channel goes on without it as it would have before.
+use event::QueueEvent;
+use private::Observer;
+use runtime::Next;
+use std::any::Any;
+use std::fmt;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+use sync::{self, Mutex};
+
+/// How many times a task is started again after panicking
+pub const RESTART_LIMIT: u32 = 3;
+
+// The pause before a task's first restart, doubled for each after.
+const RESTART_DELAY: Duration = Duration::from_millis(100);
+
+/// A background task of a channel, as reported in `Health`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Task {
+    /// The flusher started by `Sender::spawn_flusher`
+    Flusher,
+    /// The collector of retained queue files kept for a while
+    Collector,
+    /// The scrubber started by `ChannelBuilder::scrub_retained`
+    Scrubber,
+    /// The Receiver's `ChannelBuilder::read_ahead`
+    ReadAhead,
+    /// The memory scaler or pressure watcher started by `pressure`
+    Pressure,
+    /// The resizer of a `ChannelBuilder::cgroup_budget`
+    Resizer,
+    /// The timer waking the Receiver as items sent with `Sender::send_after`
+    /// come due
+    Timer,
+    /// The mover of sealed queue files into `ChannelBuilder::archive_dir`
+    Archiver,
+}
+
+impl Task {
+    fn name(self) -> &'static str {
+        match self {
+            Task::Flusher => "flusher",
+            Task::Collector => "collector",
+            Task::Scrubber => "scrubber",
+            Task::ReadAhead => "read-ahead",
+            Task::Pressure => "pressure",
+            Task::Resizer => "resizer",
+            Task::Timer => "timer",
+            Task::Archiver => "archiver",
+        }
+    }
+}
+
+impl fmt::Display for Task {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// How one of a channel's background tasks has fared
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskHealth {
+    /// The task
+    pub task: Task,
+    /// The number of times it has panicked
+    pub panics: u32,
+    /// What it said when it last panicked, if it said anything
+    pub last_panic: Option<String>,
+    /// Whether it panicked too often to be started again
+    pub failed: bool,
+}
+
+/// Whether a channel is in working order, as returned by `Sender::health`
+/// and `Receiver::health`
+///
+/// Cheap enough for a readiness probe: taking it locks the channel briefly
+/// and writes a byte to the channel's directory. Tasks appear from when they
+/// are started until they finish. Those of a channel's priority lanes are
+/// included.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Health {
+    /// The channel's name
+    pub channel: String,
+    /// Whether a file could be written in the channel's directory just now
+    pub disk_writable: bool,
+    /// The bytes left before queue files reach the channel's
+    /// `ChannelBuilder::max_disk_bytes`, `None` if it has no quota
+    pub quota_headroom: Option<u64>,
+    /// The error of the last write to a queue file that failed, if paging
+    /// out has not succeeded since
+    ///
+    /// A regular channel does not sync its queue files, so this covers
+    /// writes and flushes to the operating system. See
+    /// `ChannelBuilder::retry_writes` for what the channel does about it.
+    pub last_write_error: Option<String>,
+    /// The channel's running and failed tasks, in the order they started
+    pub tasks: Vec<TaskHealth>,
+}
+
+impl Health {
+    /// Whether the channel's directory takes writes, paging out is not
+    /// failing and none of the channel's tasks has been given up on
+    ///
+    /// A task that panicked and was started again counts as healthy, as does
+    /// a channel at its disk quota, which holds items in memory or refuses
+    /// them as its `OverflowPolicy` says.
+    pub fn is_healthy(&self) -> bool {
+        self.disk_writable && self.last_write_error.is_none()
+            && self.tasks.iter().all(|task| !task.failed)
+    }
+}
+
+// Why a task's run came to an end
+#[derive(Debug, Clone, Copy)]
+pub enum Stopped {
+    // It panicked and should be run again once the pause has passed
+    Restart(Duration),
+    // It panicked too often to be run again
+    GiveUp,
+}
+
+/// Runs a channel's background tasks, see the module documentation
+///
+/// Clones refer to the same channel's tasks.
+#[derive(Clone)]
+pub struct Supervisor {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    channel: String,
+    observer: Option<Observer>,
+    // Each task started, under the id it was registered with
+    tasks: Mutex<Vec<(u64, TaskHealth)>>,
+    next_id: AtomicU64,
+}
+
+impl fmt::Debug for Supervisor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Supervisor")
+            .field("channel", &self.inner.channel)
+            .finish()
+    }
+}
+
+/// A task registered with a `Supervisor`, dropped from its health once the
+/// task finishes
+#[derive(Debug)]
+pub struct Registration {
+    supervisor: Supervisor,
+    id: u64,
+    task: Task,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut tasks = sync::lock(&self.supervisor.inner.tasks);
+        if let Some(at) = tasks.iter().position(|&(id, ref t)| id == self.id && !t.failed) {
+            tasks.remove(at);
+        }
+    }
+}
+
+impl Registration {
+    /// Run `body` once, catching a panic and saying whether to run it again
+    pub fn attempt<R, F>(&self, body: F) -> Result<R, Stopped>
+    where
+        F: FnOnce() -> R,
+    {
+        let payload = match panic::catch_unwind(AssertUnwindSafe(body)) {
+            Ok(out) => return Ok(out),
+            Err(payload) => payload,
+        };
+        let inner = &self.supervisor.inner;
+        let panics = {
+            let mut tasks = sync::lock(&inner.tasks);
+            match tasks.iter_mut().find(|&&mut (id, _)| id == self.id) {
+                Some(&mut (_, ref mut health)) => {
+                    health.panics += 1;
+                    health.last_panic = message(&*payload);
+                    health.failed = health.panics > RESTART_LIMIT;
+                    health.panics
+                }
+                None => RESTART_LIMIT + 1,
+            }
+        };
+        let restarting = panics <= RESTART_LIMIT;
+        trace_event!(
+            channel = %inner.channel,
+            task = self.task.name(),
+            restarting = restarting,
+            "background task panicked"
+        );
+        if let Some(ref observer) = inner.observer {
+            observer(QueueEvent::TaskPanicked {
+                task: self.task,
+                restarting: restarting,
+            });
+        }
+        if restarting {
+            Err(Stopped::Restart(RESTART_DELAY * (1 << (panics - 1))))
+        } else {
+            Err(Stopped::GiveUp)
+        }
+    }
+}
+
+// The message a panic was raised with, if it was given one.
+fn message(payload: &(dyn Any + Send)) -> Option<String> {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        Some(message.to_string())
+    } else {
+        payload.downcast_ref::<String>().cloned()
+    }
+}
+
+impl Supervisor {
+    /// The supervisor of channel `channel`'s tasks, reporting panics to
+    /// `observer` if given
+    pub fn new(channel: &str, observer: Option<Observer>) -> Supervisor {
+        Supervisor {
+            inner: Arc::new(Inner {
+                channel: channel.to_string(),
+                observer: observer,
+                tasks: Mutex::new(Vec::new()),
+                next_id: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Record that `task` has started, until the `Registration` is dropped
+    pub fn register(&self, task: Task) -> Registration {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        sync::lock(&self.inner.tasks).push((
+            id,
+            TaskHealth {
+                task: task,
+                panics: 0,
+                last_panic: None,
+                failed: false,
+            },
+        ));
+        Registration {
+            supervisor: self.clone(),
+            id: id,
+            task: task,
+        }
+    }
+
+    /// The name of the thread running `task`
+    pub fn thread_name(&self, task: Task) -> String {
+        format!("hopper-{}:{}", task.name(), self.inner.channel)
+    }
+
+    /// Run `body` as `task` on a thread of its own until it returns, starting
+    /// it again should it panic
+    pub fn spawn<F>(&self, task: Task, mut body: F) -> io::Result<thread::JoinHandle<()>>
+    where
+        F: FnMut() + Send + 'static,
+    {
+        let registration = self.register(task);
+        thread::Builder::new()
+            .name(self.thread_name(task))
+            .spawn(move || loop {
+                match registration.attempt(&mut body) {
+                    Ok(()) | Err(Stopped::GiveUp) => return,
+                    Err(Stopped::Restart(pause)) => thread::sleep(pause),
+                }
+            })
+    }
+
+    /// Wrap `job`, a runtime job running `task`, so that a panic in a run is
+    /// caught and the job run again after a pause
+    pub fn job<F>(&self, task: Task, mut job: F) -> impl FnMut() -> Next + Send + 'static
+    where
+        F: FnMut() -> Next + Send + 'static,
+    {
+        let registration = self.register(task);
+        move || match registration.attempt(&mut job) {
+            Ok(next) => next,
+            Err(Stopped::Restart(pause)) => Next::After(pause),
+            Err(Stopped::GiveUp) => Next::Done,
+        }
+    }
+
+    /// How the channel's tasks have fared, the rest of its health left as
+    /// default
+    pub fn health(&self) -> Health {
+        Health {
+            channel: self.inner.channel.clone(),
+            tasks: sync::lock(&self.inner.tasks)
+                .iter()
+                .map(|(_, health)| health.clone())
+                .collect(),
+            ..Health::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn panicking_tasks_are_restarted_then_given_up_on() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&events);
+        let observer: Observer = Arc::new(move |event| sync::lock(&seen).push(event));
+        let supervisor = Supervisor::new("tasks", Some(observer));
+
+        // A task that panics twice and then finishes leaves no trace
+        let runs = Arc::new(AtomicUsize::new(0));
+        let thr_runs = Arc::clone(&runs);
+        let jh = supervisor
+            .spawn(Task::Flusher, move || {
+                assert_eq!("hopper-flusher:tasks", thread::current().name().unwrap());
+                if thr_runs.fetch_add(1, Ordering::SeqCst) < 2 {
+                    panic!("flusher failed");
+                }
+            })
+            .unwrap();
+        jh.join().unwrap();
+        assert_eq!(3, runs.load(Ordering::SeqCst));
+        assert!(supervisor.health().tasks.is_empty());
+
+        // A task that keeps panicking is given up on
+        let mut job = supervisor.job(Task::Scrubber, || -> Next { panic!("scrubber failed") });
+        for _ in 0..RESTART_LIMIT {
+            match job() {
+                Next::After(_) => {}
+                next => panic!("not restarted: {:?}", next),
+            }
+        }
+        assert_eq!(Next::Done, job());
+        let health = supervisor.health();
+        assert!(!Health { disk_writable: true, ..health.clone() }.is_healthy());
+        assert_eq!(
+            vec![TaskHealth {
+                task: Task::Scrubber,
+                panics: RESTART_LIMIT + 1,
+                last_panic: Some("scrubber failed".to_string()),
+                failed: true,
+            }],
+            health.tasks
+        );
+        let events = sync::lock(&events);
+        assert_eq!(2 + RESTART_LIMIT as usize + 1, events.len());
+        assert_eq!(
+            Some(&QueueEvent::TaskPanicked {
+                task: Task::Scrubber,
+                restarting: false,
+            }),
+            events.last()
+        );
+    }
+}
@@ -0,0 +1,97 @@
+//! The mutex and condition variable behind a channel's locks
+//!
+//! These are std's by default. With the `parking_lot` feature they are
+//! `parking_lot`'s instead, which are smaller, keep no poisoning and hold up
+//! better under contention on some platforms. The two differ in how they are
+//! locked and waited on, so hopper goes through the functions here rather
+//! than their methods. A std lock poisoned by a panicking thread is used as
+//! that thread left it, as `parking_lot` has no poisoning to refuse it with:
+//! see `private::lock` for why hopper carries on.
+#[cfg(not(feature = "parking_lot"))]
+pub use std::sync::{Condvar, Mutex, MutexGuard};
+#[cfg(feature = "parking_lot")]
+pub use parking_lot::{Condvar, Mutex, MutexGuard};
+#[cfg(not(feature = "parking_lot"))]
+use std::sync::{PoisonError, TryLockError};
+use std::time::Duration;
+
+/// Lock `mutex`, waiting for it
+#[cfg(not(feature = "parking_lot"))]
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Lock `mutex`, waiting for it
+#[cfg(feature = "parking_lot")]
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock()
+}
+
+/// Lock `mutex` if no one else holds it
+#[cfg(not(feature = "parking_lot"))]
+pub fn try_lock<T>(mutex: &Mutex<T>) -> Option<MutexGuard<'_, T>> {
+    match mutex.try_lock() {
+        Ok(guard) => Some(guard),
+        Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+        Err(TryLockError::WouldBlock) => None,
+    }
+}
+
+/// Lock `mutex` if no one else holds it
+#[cfg(feature = "parking_lot")]
+pub fn try_lock<T>(mutex: &Mutex<T>) -> Option<MutexGuard<'_, T>> {
+    mutex.try_lock()
+}
+
+/// Take the value out of `mutex`
+#[cfg(not(feature = "parking_lot"))]
+pub fn into_inner<T>(mutex: Mutex<T>) -> T {
+    mutex.into_inner().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Take the value out of `mutex`
+#[cfg(feature = "parking_lot")]
+pub fn into_inner<T>(mutex: Mutex<T>) -> T {
+    mutex.into_inner()
+}
+
+/// Release `guard` and wait on `cond`, locking again once woken
+///
+/// Wakes spuriously now and then, as with std's.
+#[cfg(not(feature = "parking_lot"))]
+pub fn wait<'a, T>(cond: &Condvar, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+    cond.wait(guard).unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Release `guard` and wait on `cond`, locking again once woken
+///
+/// Wakes spuriously now and then, as with std's.
+#[cfg(feature = "parking_lot")]
+pub fn wait<'a, T>(cond: &Condvar, mut guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+    cond.wait(&mut guard);
+    guard
+}
+
+/// As `wait`, giving up once `timeout` has passed
+#[cfg(not(feature = "parking_lot"))]
+pub fn wait_timeout<'a, T>(
+    cond: &Condvar,
+    guard: MutexGuard<'a, T>,
+    timeout: Duration,
+) -> MutexGuard<'a, T> {
+    match cond.wait_timeout(guard, timeout) {
+        Ok((guard, _)) => guard,
+        Err(e) => e.into_inner().0,
+    }
+}
+
+/// As `wait`, giving up once `timeout` has passed
+#[cfg(feature = "parking_lot")]
+pub fn wait_timeout<'a, T>(
+    cond: &Condvar,
+    mut guard: MutexGuard<'a, T>,
+    timeout: Duration,
+) -> MutexGuard<'a, T> {
+    cond.wait_for(&mut guard, timeout);
+    guard
+}
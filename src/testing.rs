@@ -0,0 +1,468 @@
+//! Test-support helpers for exercising hopper and its consumers
+//!
+//! `crash` is intended for integration tests that want to assert what survives
+//! a crash without orchestrating real process kills. `loopback` feeds a channel
+//! from a generator for load-testing downstream consumers. Both operate on live
+//! channels inside a single process. `ManualClock` stands in for the real
+//! clock of a channel whose TTL, delays or retention are under test.
+//!
+//! With the `harness` feature, `kill_child_at` runs a test's writer in a child
+//! process and kills it at a point the writer marks with `crash_point`, for
+//! crash tests of the cross-process channels in `process`, the one kind of
+//! hopper channel whose items outlive their Sender's process. `faulty_sender`
+//! puts the faults hopper's own crash tests inject under a `ProcessSender`,
+//! and `assert_recovered` checks what a Receiver finds afterwards.
+use super::{channel, channel_with_max_bytes, Clock, Error, Receiver, Sender};
+#[cfg(feature = "harness")]
+use process::{self, ProcessSender};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+#[cfg(feature = "harness")]
+use std::env;
+#[cfg(feature = "harness")]
+use std::fmt;
+#[cfg(feature = "harness")]
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+#[cfg(feature = "harness")]
+use std::path::PathBuf;
+#[cfg(feature = "harness")]
+use std::process::{Child, Command, ExitStatus, Stdio};
+#[cfg(feature = "harness")]
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+#[cfg(feature = "harness")]
+pub use storage::faults::Faults;
+#[cfg(feature = "harness")]
+use storage::faults;
+
+// Names the directory a `CrashChild` hands its child process
+#[cfg(feature = "harness")]
+const CHILD_DIR: &str = "HOPPER_CRASH_DIR";
+// Names the crash point a child stops at to be killed
+#[cfg(feature = "harness")]
+const CRASH_AT: &str = "HOPPER_CRASH_AT";
+// Leads the line a child prints on reaching that crash point
+#[cfg(feature = "harness")]
+const CRASH_POINT: &str = "hopper-crash-point: ";
+// How long a child waits at its crash point for its parent to kill it
+#[cfg(feature = "harness")]
+const ORPHAN_WAIT: Duration = Duration::from_secs(60);
+
+/// Simulate a crash of a live channel and reopen it from disk
+///
+/// The given Sender and Receiver are torn down as if their process had died:
+/// items held in the in-memory buffer, items staged for the next disk write
+/// and any bytes sitting unflushed in the sender's write buffer are abandoned.
+/// A fresh (Sender, Receiver) pair is then opened against the same queue
+/// directory using the same name and `max_bytes`, exactly as a restarting
+/// process would.
+///
+/// Clones of `sender` that are still alive elsewhere keep the abandoned
+/// in-memory state to themselves and are not connected to the recovered
+/// channel.
+///
+/// # Example
+/// ```
+/// extern crate tempdir;
+/// extern crate hopper;
+///
+/// let dir = tempdir::TempDir::new("hopper").unwrap();
+/// let (mut snd, rcv) = hopper::channel("example", dir.path()).unwrap();
+///
+/// snd.send(9);
+/// let (_, mut rcv) = hopper::testing::crash(snd, rcv).unwrap();
+/// assert_eq!(None, rcv.try_iter().next());
+/// ```
+pub fn crash<T>(sender: Sender<T>, receiver: Receiver<T>) -> Result<(Sender<T>, Receiver<T>), Error>
+where
+    T: Serialize + DeserializeOwned,
+{
+    let name = sender.name().to_string();
+    let max_bytes = sender.max_bytes();
+    let data_dir = match sender.root().parent() {
+        Some(dir) => dir.to_path_buf(),
+        None => return Err(Error::NoSuchDirectory),
+    };
+    sender.abandon();
+    drop(sender);
+    drop(receiver);
+    channel_with_max_bytes(&name, &data_dir, max_bytes)
+}
+
+/// Open a channel whose items are synthesized by `generator`
+///
+/// A background thread calls `generator` with 0, 1, 2 and so on and sends
+/// each item it returns into a fresh channel named `name` under `data_dir`,
+/// paced to `per_second` items per second. A rate of zero sends as fast as
+/// the channel accepts. The returned Receiver is an ordinary hopper Receiver
+/// so downstream consumers can be load-tested through exactly the API they
+/// use in production, including disk paging when they fall behind.
+///
+/// The stream ends when `generator` returns `None`, hanging the channel up,
+/// and the thread exits once the stream has ended or the Receiver has been
+/// dropped.
+///
+/// # Example
+/// ```
+/// extern crate tempdir;
+/// extern crate hopper;
+///
+/// let dir = tempdir::TempDir::new("hopper").unwrap();
+/// let mut rcv = hopper::testing::loopback("example", dir.path(), 0, |i| {
+///     if i < 3 { Some(i * 10) } else { None }
+/// }).unwrap();
+///
+/// let received: Vec<u64> = rcv.iter().collect();
+/// assert_eq!(vec![0, 10, 20], received);
+/// ```
+pub fn loopback<T, F>(
+    name: &str,
+    data_dir: &Path,
+    per_second: u32,
+    mut generator: F,
+) -> Result<Receiver<T>, Error>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+    F: FnMut(u64) -> Option<T> + Send + 'static,
+{
+    let (mut snd, rcv) = channel(name, data_dir)?;
+    thread::spawn(move || {
+        let start = Instant::now();
+        let mut seq: u64 = 0;
+        while !snd.is_orphaned() {
+            if per_second > 0 {
+                let due = start + Duration::from_secs(seq) / per_second;
+                let now = Instant::now();
+                if due > now {
+                    thread::sleep(due - now);
+                }
+            }
+            match generator(seq) {
+                Some(item) => snd.send(item),
+                None => break,
+            }
+            seq += 1;
+        }
+    });
+    Ok(rcv)
+}
+
+/// A `Clock` that stands still until moved on
+///
+/// Starts at the real time it was made and moves only by `advance`. Give one
+/// to `ChannelBuilder::clock`, wrapped in an `Arc` kept to advance it by, to
+/// expire items, bring delayed items due or age retained queue files without
+/// waiting on the real clock.
+///
+/// # Example
+/// ```
+/// extern crate tempdir;
+/// extern crate hopper;
+///
+/// use hopper::testing::ManualClock;
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// let dir = tempdir::TempDir::new("hopper").unwrap();
+/// let clock = Arc::new(ManualClock::new());
+/// let (mut snd, mut rcv) = hopper::ChannelBuilder::new("example", dir.path())
+///     .clock(clock.clone())
+///     .build()
+///     .unwrap();
+///
+/// snd.send_after(9, Duration::from_secs(60));
+/// assert_eq!(None, rcv.try_iter().next());
+/// clock.advance(Duration::from_secs(60));
+/// assert_eq!(Some(9), rcv.try_iter().next());
+/// ```
+#[derive(Debug)]
+pub struct ManualClock {
+    start: Instant,
+    start_wall: SystemTime,
+    elapsed: Mutex<Duration>,
+}
+
+impl ManualClock {
+    /// A clock standing at the current time
+    pub fn new() -> ManualClock {
+        ManualClock {
+            start: Instant::now(),
+            start_wall: SystemTime::now(),
+            elapsed: Mutex::new(Duration::from_secs(0)),
+        }
+    }
+
+    /// Move the clock on by `by`
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().expect("ManualClock poisoned") += by;
+    }
+
+    fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().expect("ManualClock poisoned")
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> ManualClock {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn wall(&self) -> SystemTime {
+        self.start_wall + self.elapsed()
+    }
+}
+
+/// Run `test` of the current test binary in a child process handed `dir`,
+/// and kill it once it reaches the crash point `label`
+///
+/// Requires the `harness` feature. The child runs the same test again, which
+/// asks `child_dir` which side it is on: the child's side writes to a channel
+/// under the directory it is handed and marks where it may die with
+/// `crash_point`, and the parent's side, once this returns, opens the channel
+/// to see what survived. `test` is the test's full path within its binary, as
+/// the test harness prints it, and is run alone. The child's standard error
+/// is the parent's.
+///
+/// The kill is a SIGKILL or its like: the child gets no chance to flush or
+/// drop anything. Returns an error of kind `UnexpectedEof` if the child exits
+/// without reaching `label`.
+///
+/// # Example
+/// ```no_run
+/// extern crate tempdir;
+/// extern crate hopper;
+///
+/// use hopper::{process, testing};
+///
+/// // The body of the test `crash_after_a_thousand`
+/// if let Some(dir) = testing::child_dir() {
+///     let mut snd = process::sender::<u64>("crash", &dir, 4096).unwrap();
+///     for i in 0..1000 {
+///         snd.send(i);
+///     }
+///     testing::crash_point("sent");
+///     return;
+/// }
+/// let dir = tempdir::TempDir::new("hopper").unwrap();
+/// testing::kill_child_at("crash_after_a_thousand", dir.path(), "sent").unwrap();
+///
+/// let mut rcv = process::receiver::<u64>("crash", dir.path()).unwrap();
+/// let mut recovered = Vec::new();
+/// while let Some(i) = rcv.try_recv().unwrap() {
+///     recovered.push(i);
+/// }
+/// testing::assert_recovered(&(0..1000).collect::<Vec<u64>>(), &recovered, 1000);
+/// ```
+#[cfg(feature = "harness")]
+pub fn kill_child_at(test: &str, dir: &Path, label: &str) -> io::Result<()> {
+    let mut child = spawn_child(test, dir, Some(label))?;
+    let stdout = child.stdout.take().expect("child stdout is piped");
+    let marker = format!("{}{}", CRASH_POINT, label);
+    let mut lines = BufReader::new(stdout).lines();
+    loop {
+        match lines.next() {
+            Some(line) => if line?.trim_end() == marker {
+                break;
+            },
+            None => {
+                let _ = child.wait();
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("child exited before crash point {}", label),
+                ));
+            }
+        }
+    }
+    child.kill()?;
+    child.wait().map(|_| ())
+}
+
+/// Run `test` of the current test binary in a child process handed `dir`,
+/// to the end
+///
+/// Requires the `harness` feature. As `kill_child_at`, with the child passing
+/// over its crash points.
+#[cfg(feature = "harness")]
+pub fn run_child(test: &str, dir: &Path) -> io::Result<ExitStatus> {
+    spawn_child(test, dir, None)?.wait()
+}
+
+#[cfg(feature = "harness")]
+fn spawn_child(test: &str, dir: &Path, crash_at: Option<&str>) -> io::Result<Child> {
+    let mut cmd = Command::new(env::current_exe()?);
+    cmd.args([test, "--exact", "--nocapture", "--test-threads", "1"])
+        .env(CHILD_DIR, dir)
+        .env_remove(CRASH_AT)
+        .stdout(Stdio::piped());
+    if let Some(label) = crash_at {
+        cmd.env(CRASH_AT, label);
+    }
+    cmd.spawn()
+}
+
+/// The directory the parent handed this process, if it is a child of
+/// `kill_child_at` or `run_child`
+///
+/// Requires the `harness` feature. `None` in the parent.
+#[cfg(feature = "harness")]
+pub fn child_dir() -> Option<PathBuf> {
+    env::var_os(CHILD_DIR).map(PathBuf::from)
+}
+
+/// Mark the crash point `label` in a child of `kill_child_at`
+///
+/// Requires the `harness` feature. The child stops here, to be killed, if
+/// this is the point its parent waits for, and otherwise carries on. Outside
+/// a child this does nothing.
+#[cfg(feature = "harness")]
+pub fn crash_point(label: &str) {
+    match env::var(CRASH_AT) {
+        Ok(ref at) if at == label => {}
+        _ => return,
+    }
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    // The test harness may have left its own line open.
+    let _ = writeln!(stdout, "\n{}{}", CRASH_POINT, label);
+    let _ = stdout.flush();
+    drop(stdout);
+    thread::sleep(ORPHAN_WAIT);
+    // The parent is gone without killing us.
+    ::std::process::exit(1);
+}
+
+/// Open a `ProcessSender` whose queue files suffer `faults`
+///
+/// Requires the `harness` feature. As `process::sender`, with every queue file
+/// written through a file that misbehaves as `faults` says. The faults are
+/// handed back to be changed while the Sender is open.
+#[cfg(feature = "harness")]
+pub fn faulty_sender<T>(
+    name: &str,
+    data_dir: &Path,
+    max_bytes: usize,
+    faults: Faults,
+) -> Result<(ProcessSender<T>, Arc<Mutex<Faults>>), Error>
+where
+    T: Serialize,
+{
+    let (opener, faults) = faults::opener(faults);
+    Ok((process::sender_with(name, data_dir, max_bytes, opener)?, faults))
+}
+
+/// Assert that `recovered` is what a crash may leave of `sent`
+///
+/// Requires the `harness` feature. That is, the first items of `sent`, whole
+/// and in order, with nothing repeated or made up, and at least `durable` of
+/// them: those sent or synced before the crash. Panics otherwise, naming the
+/// first item out of place.
+#[cfg(feature = "harness")]
+pub fn assert_recovered<T>(sent: &[T], recovered: &[T], durable: usize)
+where
+    T: PartialEq + fmt::Debug,
+{
+    assert!(
+        recovered.len() <= sent.len(),
+        "recovered {} items of {} sent",
+        recovered.len(),
+        sent.len()
+    );
+    if let Some(at) = sent.iter().zip(recovered).position(|(s, r)| s != r) {
+        panic!(
+            "recovered item {} is {:?}, sent as {:?}",
+            at, recovered[at], sent[at]
+        );
+    }
+    assert!(
+        recovered.len() >= durable,
+        "recovered {} items, {} were durable",
+        recovered.len(),
+        durable
+    );
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::{crash, loopback};
+    use super::super::{channel, channel_with_max_bytes};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn crash_loses_in_memory_items() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, rcv) = channel("crash_loses_in_memory_items", dir.path()).unwrap();
+
+        for i in 0..10 {
+            snd.send(i);
+        }
+
+        let (mut snd, mut rcv) = crash(snd, rcv).unwrap();
+        assert_eq!(None, rcv.try_iter().next());
+
+        snd.send(10);
+        assert_eq!(Some(10), rcv.try_iter().next());
+        assert_eq!(None, rcv.try_iter().next());
+    }
+
+    #[test]
+    fn crash_loses_spilled_items() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, rcv) =
+            channel_with_max_bytes("crash_loses_spilled_items", dir.path(), 128).unwrap();
+
+        for i in 0..4096 {
+            snd.send(i);
+        }
+
+        let (mut snd, mut rcv) = crash(snd, rcv).unwrap();
+        assert_eq!(None, rcv.try_iter().next());
+
+        for i in 0..4096 {
+            snd.send(i);
+        }
+        for i in 0..4096 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        assert_eq!(None, rcv.try_iter().next());
+    }
+
+    #[test]
+    fn loopback_delivers_generated_items_in_order() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let mut rcv = loopback("loopback_in_order", dir.path(), 0, |i| {
+            if i < 5000 {
+                Some(i)
+            } else {
+                None
+            }
+        }).unwrap();
+
+        let received: Vec<u64> = rcv.iter().collect();
+        assert_eq!((0..5000).collect::<Vec<u64>>(), received);
+    }
+
+    #[test]
+    fn loopback_paces_to_rate() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let start = Instant::now();
+        let mut rcv = loopback("loopback_paces", dir.path(), 100, Some).unwrap();
+
+        let mut received: Vec<u64> = Vec::new();
+        while received.len() < 21 {
+            received.extend(rcv.try_iter());
+        }
+        assert_eq!((0..21).collect::<Vec<u64>>(), received[..21].to_vec());
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,189 @@
+use super::OrderMode;
+use metrics::Metrics;
+use std::cmp;
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+
+// Every channel built in this process, held weakly so that the list never keeps
+// a channel's state alive. Dead entries are swept whenever the list is read.
+static CHANNELS: Mutex<Vec<(ChannelDescription, Weak<Metrics>)>> = Mutex::new(Vec::new());
+
+/// The configuration of one live channel, as reported by `topology`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelDescription {
+    /// The channel's name
+    pub name: String,
+    /// The directory the channel's queue files are stored in
+    pub data_dir: PathBuf,
+    /// The number of items held in memory before paging to disk
+    pub in_memory_capacity: usize,
+    /// The configured maximum queue file size
+    pub max_bytes: usize,
+    /// The adaptive queue file size bounds, if enabled
+    pub adaptive_max_bytes: Option<(usize, usize)>,
+    /// The channel's ordering guarantee
+    pub order: OrderMode,
+}
+
+/// A description of every hopper channel open in this process
+///
+/// Hopper channels are independent of one another--there are no topics,
+/// partitions or forwarders connecting them--so the topology is a flat list.
+/// A channel is included from the moment it is built until its last Sender
+/// and Receiver are dropped.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Topology {
+    /// The live channels, in the order they were built
+    pub channels: Vec<ChannelDescription>,
+}
+
+pub fn register(description: ChannelDescription, liveness: Weak<Metrics>) {
+    let mut channels = CHANNELS.lock().expect("topology poisoned");
+    channels.retain(|(_, alive)| alive.upgrade().is_some());
+    channels.push((description, liveness));
+}
+
+// Record the new in-memory capacity of the channel whose metrics are
+// `metrics`, as changed by `Sender::reconfigure`.
+pub fn set_capacity(metrics: &Arc<Metrics>, capacity: usize) {
+    let mut channels = CHANNELS.lock().expect("topology poisoned");
+    let alive = Arc::downgrade(metrics);
+    for &mut (ref mut desc, ref liveness) in channels.iter_mut() {
+        if liveness.ptr_eq(&alive) {
+            desc.in_memory_capacity = cmp::max(1, capacity);
+        }
+    }
+}
+
+/// Describe the hopper channels open in this process
+pub fn topology() -> Topology {
+    let mut channels = CHANNELS.lock().expect("topology poisoned");
+    channels.retain(|(_, alive)| alive.upgrade().is_some());
+    Topology {
+        channels: channels.iter().map(|(desc, _)| desc.clone()).collect(),
+    }
+}
+
+fn json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+impl Topology {
+    /// Render the topology as a JSON document
+    ///
+    /// The document has the shape
+    ///
+    /// ```text
+    /// {"channels":[{"name":"a","data_dir":"/var/lib/a","in_memory_capacity":1024,
+    ///   "max_bytes":104857600,"adaptive_max_bytes":null,"order":"global"}]}
+    /// ```
+    ///
+    /// with `adaptive_max_bytes` either `null` or `{"min":..,"max":..}` and
+    /// `order` one of `"global"` or `"per_sender"`.
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\"channels\":[");
+        for (idx, chan) in self.channels.iter().enumerate() {
+            if idx > 0 {
+                out.push(',');
+            }
+            out.push_str("{\"name\":");
+            json_string(&mut out, &chan.name);
+            out.push_str(",\"data_dir\":");
+            json_string(&mut out, &chan.data_dir.to_string_lossy());
+            let _ = write!(
+                out,
+                ",\"in_memory_capacity\":{},\"max_bytes\":{}",
+                chan.in_memory_capacity, chan.max_bytes
+            );
+            match chan.adaptive_max_bytes {
+                Some((min, max)) => {
+                    let _ = write!(
+                        out,
+                        ",\"adaptive_max_bytes\":{{\"min\":{},\"max\":{}}}",
+                        min, max
+                    );
+                }
+                None => out.push_str(",\"adaptive_max_bytes\":null"),
+            }
+            out.push_str(match chan.order {
+                OrderMode::Global => ",\"order\":\"global\"}",
+                OrderMode::PerSender => ",\"order\":\"per_sender\"}",
+            });
+        }
+        out.push_str("]}");
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::topology;
+    use super::super::{channel, ChannelBuilder, OrderMode};
+
+    #[test]
+    fn topology_lists_live_channels() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (snd, rcv) = channel::<u64>("topology_live_a", dir.path()).unwrap();
+        let (_snd_b, _rcv_b) = ChannelBuilder::new("topology_live_b", dir.path())
+            .max_bytes(4096)
+            .adaptive_max_bytes(1024, 8192)
+            .order(OrderMode::PerSender)
+            .build::<u64>()
+            .unwrap();
+
+        let found: Vec<_> = topology()
+            .channels
+            .into_iter()
+            .filter(|c| c.name.starts_with("topology_live_"))
+            .collect();
+        assert_eq!(2, found.len());
+        assert_eq!(dir.path(), found[0].data_dir.as_path());
+        assert_eq!(Some((1024, 8192)), found[1].adaptive_max_bytes);
+
+        drop(snd);
+        drop(rcv);
+        let names: Vec<_> = topology()
+            .channels
+            .into_iter()
+            .map(|c| c.name)
+            .filter(|n| n.starts_with("topology_live_"))
+            .collect();
+        assert_eq!(vec!["topology_live_b".to_string()], names);
+    }
+
+    #[test]
+    fn topology_renders_json() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (_snd, _rcv) = ChannelBuilder::new("topology_\"json\"", dir.path())
+            .max_bytes(4096)
+            .build::<u64>()
+            .unwrap();
+
+        let mut topo = topology();
+        topo.channels.retain(|c| c.name == "topology_\"json\"");
+        let expected = format!(
+            "{{\"channels\":[{{\"name\":\"topology_\\\"json\\\"\",\"data_dir\":\"{}\",\
+             \"in_memory_capacity\":1024,\"max_bytes\":4096,\"adaptive_max_bytes\":null,\
+             \"order\":\"global\"}}]}}",
+            dir.path().display()
+        );
+        assert_eq!(expected, topo.to_json());
+    }
+}
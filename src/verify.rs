@@ -0,0 +1,403 @@
+//! Integrity checks of a channel directory as it is opened
+//!
+//! A channel built with `ChannelBuilder::verify_on_open` looks its directory
+//! over before it opens it and reports what it finds in an
+//! `IntegrityReport`, read from `Receiver::integrity_report`, rather than
+//! leaving damage to surface as misread items later on. The checks go as far
+//! as the `VerifyLevel` asks: a fast check reads the metadata file, the lock
+//! file, the names of the queue files and their headers, and reads whole only
+//! the newest queue file, the one the Sender goes on appending to; a full
+//! check reads every queue file whole, holds each sealed one to its summary
+//! and, where the channel packs its records, checks every block's checksum.
+//!
+//! A channel built with `ChannelBuilder::repair_on_open` too cuts damaged
+//! queue files back as `repair` would, before the channel opens them, unless
+//! `process::backup` is copying them. The report keeps what was found along
+//! with what the repair did.
+use block;
+use layout;
+use private;
+use repair::RepairReport;
+use segment::{self, ParseError};
+use std::fs;
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+use summary;
+
+/// How closely a channel directory is checked as it is opened
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VerifyLevel {
+    /// Check nothing. This is the default.
+    #[default]
+    None,
+    /// Check the metadata and lock files, that no queue file is missing
+    /// between the first and last and that each starts with a header this
+    /// hopper reads, and read the newest queue file whole
+    Fast,
+    /// As `Fast`, reading every queue file whole, holding sealed ones to their
+    /// summaries and checking the checksum of every packed block
+    Full,
+}
+
+/// Something wrong with a channel directory, found as it was opened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Finding {
+    /// A process asked for the directory's lock with `process::take_over`,
+    /// and the request still stands
+    HandoverRequested,
+    /// `process::backup` is copying the directory's queue files
+    Pinned,
+    /// The directory holds queue files and no metadata file
+    MissingMetadata,
+    /// The metadata file was written by an older hopper, in format `version`.
+    /// Opening the channel moves it on to the current format.
+    OutdatedMetadata {
+        /// The format version recorded
+        version: u32,
+    },
+    /// The metadata file belongs to another item type or a newer hopper, or
+    /// cannot be read. Opening the channel fails with
+    /// `Error::MetadataMismatch`.
+    ForeignMetadata,
+    /// Queue files are missing between the two that are there
+    MissingSegments {
+        /// The queue file before the gap
+        after: usize,
+        /// The queue file after the gap
+        before: usize,
+    },
+    /// The queue file's header is not one this hopper reads
+    UnknownFormat {
+        /// The queue file's sequence number
+        segment: usize,
+    },
+    /// The queue file stops reading whole part way through
+    TornRecord {
+        /// The queue file's sequence number
+        segment: usize,
+        /// The start of the first record that is not whole
+        offset: u64,
+    },
+    /// A block of packed records fails its checksum
+    BadChecksum {
+        /// The queue file's sequence number
+        segment: usize,
+        /// The start of the block's record
+        offset: u64,
+    },
+    /// A sealed queue file holds other than its summary says: a record
+    /// count, or bytes of records, that differ
+    SummaryMismatch {
+        /// The queue file's sequence number
+        segment: usize,
+    },
+}
+
+impl Finding {
+    /// Whether the finding is damage to a queue file's records, which a
+    /// repair cuts away
+    pub fn is_damage(&self) -> bool {
+        matches!(*self, Finding::TornRecord { .. } | Finding::BadChecksum { .. })
+    }
+}
+
+/// What checking a channel directory found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// How closely the directory was checked
+    pub level: VerifyLevel,
+    /// The number of queue files looked at
+    pub segments_checked: usize,
+    /// What was found, the directory's own state first and then its queue
+    /// files', oldest first
+    pub findings: Vec<Finding>,
+    /// What the repair made of the damage, if the channel repairs on open and
+    /// there was damage to repair
+    pub repair: Option<RepairReport>,
+}
+
+impl IntegrityReport {
+    /// Whether nothing was found
+    pub fn is_clean(&self) -> bool {
+        self.findings.is_empty()
+    }
+
+    /// Whether a queue file's records were found damaged
+    pub fn is_damaged(&self) -> bool {
+        self.findings.iter().any(Finding::is_damage)
+    }
+}
+
+/// Check the channel directory `root`, holding its lock, to `level`, for a
+/// channel of item type `type_name` that packs its records in blocks if
+/// `packed`
+///
+/// Only reads: the metadata file is left for `layout::claim` to upgrade.
+pub fn check(root: &Path, level: VerifyLevel, type_name: &str, packed: bool) -> IntegrityReport {
+    let mut report = IntegrityReport {
+        level: level,
+        ..IntegrityReport::default()
+    };
+    if level == VerifyLevel::None {
+        return report;
+    }
+    let findings = &mut report.findings;
+    if layout::handover_requested(root) {
+        findings.push(Finding::HandoverRequested);
+    }
+    if layout::share_lock_file(root, layout::PIN_FILE).is_err() {
+        findings.push(Finding::Pinned);
+    }
+
+    let mut ids = private::segment_ids(root, None);
+    ids.sort();
+    match fs::read_to_string(root.join(layout::METADATA_FILE)) {
+        Ok(found) => findings.extend(metadata(&found, type_name)),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {
+            if !ids.is_empty() {
+                findings.push(Finding::MissingMetadata);
+            }
+        }
+        Err(_) => findings.push(Finding::ForeignMetadata),
+    }
+    for pair in ids.windows(2) {
+        if pair[1] != pair[0] + 1 {
+            findings.push(Finding::MissingSegments {
+                after: pair[0],
+                before: pair[1],
+            });
+        }
+    }
+
+    let newest = ids.last().cloned();
+    for id in ids {
+        let path = root.join(format!("{}", id));
+        report.segments_checked += 1;
+        if level == VerifyLevel::Full || Some(id) == newest {
+            findings.extend(read_whole(&path, id, packed));
+        } else if !header_reads(&path) {
+            findings.push(Finding::UnknownFormat { segment: id });
+        }
+    }
+    report
+}
+
+// What is wrong with the metadata file holding `found`, for a channel of
+// `type_name`
+fn metadata(found: &str, type_name: &str) -> Option<Finding> {
+    let mut version = None;
+    let mut found_type = None;
+    for line in found.lines() {
+        if let Some(v) = line.strip_prefix("format_version ") {
+            version = v.parse::<u32>().ok();
+        } else if let Some(t) = line.strip_prefix("type ") {
+            found_type = Some(t);
+        }
+    }
+    match (version, found_type) {
+        (Some(version), Some(found_type)) if found_type == type_name => {
+            if version == layout::FORMAT_VERSION {
+                None
+            } else if (layout::OLDEST_FORMAT_VERSION..layout::FORMAT_VERSION).contains(&version) {
+                Some(Finding::OutdatedMetadata { version: version })
+            } else {
+                Some(Finding::ForeignMetadata)
+            }
+        }
+        _ => Some(Finding::ForeignMetadata),
+    }
+}
+
+// Whether the queue file at `path` starts with a header this hopper reads.
+// An empty file, as one just created, has yet to be written one.
+fn header_reads(path: &Path) -> bool {
+    let mut header = Vec::with_capacity(layout::SEGMENT_HEADER_LEN);
+    let read = fs::File::open(path)
+        .and_then(|fp| fp.take(layout::SEGMENT_HEADER_LEN as u64).read_to_end(&mut header));
+    match read {
+        Ok(_) => header.is_empty() || layout::segment_format(&header).is_some(),
+        Err(_) => false,
+    }
+}
+
+// What reading queue file `id` at `path` whole finds wrong with it
+fn read_whole(path: &Path, id: usize, packed: bool) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let buf = match fs::read(path) {
+        Ok(buf) => buf,
+        Err(_) => {
+            findings.push(Finding::UnknownFormat { segment: id });
+            return findings;
+        }
+    };
+    let mut records = match segment::parse(&buf) {
+        Ok(records) => records,
+        Err(_) => {
+            findings.push(Finding::UnknownFormat { segment: id });
+            return findings;
+        }
+    };
+    let start = records.offset();
+    let mut count = 0;
+    for record in records.by_ref() {
+        match record {
+            Ok(record) => {
+                if packed && !block::intact(record.payload) {
+                    findings.push(Finding::BadChecksum {
+                        segment: id,
+                        offset: record.offset as u64,
+                    });
+                    return findings;
+                }
+                count += 1;
+            }
+            Err(ParseError::TornRecord { offset, .. })
+            | Err(ParseError::MissingSchemaVersion { offset }) => {
+                findings.push(Finding::TornRecord {
+                    segment: id,
+                    offset: offset as u64,
+                });
+                return findings;
+            }
+            Err(ParseError::UnknownFormat) => {
+                findings.push(Finding::UnknownFormat { segment: id });
+                return findings;
+            }
+        }
+    }
+    let bytes = (records.offset() - start) as u64;
+    if let Some(summary) = summary::read(path) {
+        if summary.records != count || summary.stored_bytes != bytes {
+            findings.push(Finding::SummaryMismatch { segment: id });
+        }
+    }
+    findings
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::*;
+    use super::super::{channel_with_max_bytes, ChannelBuilder};
+    use std::fs;
+    use std::io::Write;
+
+    const TYPE: &str = "u64";
+
+    #[test]
+    fn fast_check_finds_gaps_and_a_torn_tail() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let root = dir.path().join("verify");
+        {
+            let (mut snd, _rcv) = channel_with_max_bytes::<u64>("verify", dir.path(), 64).unwrap();
+            for i in 0..2048 {
+                snd.send(i);
+            }
+            snd.flush().unwrap();
+        }
+        let mut ids = private::segment_ids(&root, None);
+        ids.sort();
+        assert!(ids.len() > 3);
+        let report = check(&root, VerifyLevel::Fast, TYPE, false);
+        assert!(report.is_clean(), "{:?}", report);
+        assert_eq!(ids.len(), report.segments_checked);
+
+        private::remove_segment(&root.join(format!("{}", ids[1]))).unwrap();
+        let newest = *ids.last().unwrap();
+        let mut fp = fs::OpenOptions::new()
+            .append(true)
+            .open(root.join(format!("{}", newest)))
+            .unwrap();
+        fp.write_all(&[0, 0, 0, 9, 1]).unwrap();
+
+        let report = check(&root, VerifyLevel::Fast, TYPE, false);
+        assert_eq!(
+            Finding::MissingSegments {
+                after: ids[0],
+                before: ids[2],
+            },
+            report.findings[0]
+        );
+        match report.findings[1] {
+            Finding::TornRecord { segment, .. } => assert_eq!(newest, segment),
+            ref other => panic!("unexpected finding {:?}", other),
+        }
+        assert!(report.is_damaged());
+        assert_eq!(VerifyLevel::None, check(&root, VerifyLevel::None, TYPE, false).level);
+    }
+
+    #[test]
+    fn full_check_holds_files_to_their_summaries() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let root = dir.path().join("verify");
+        {
+            let (mut snd, _rcv) = channel_with_max_bytes::<u64>("verify", dir.path(), 64).unwrap();
+            for i in 0..2048 {
+                snd.send(i);
+            }
+            snd.flush().unwrap();
+        }
+        let mut ids = private::segment_ids(&root, None);
+        ids.sort();
+        let oldest = root.join(format!("{}", ids[0]));
+        let mut summary = summary::read(&oldest).unwrap();
+        summary.records += 1;
+        summary::write(&oldest, &summary).unwrap();
+
+        assert!(check(&root, VerifyLevel::Fast, TYPE, false).is_clean());
+        let report = check(&root, VerifyLevel::Full, TYPE, false);
+        assert_eq!(vec![Finding::SummaryMismatch { segment: ids[0] }], report.findings);
+        assert!(!report.is_damaged());
+    }
+
+    #[test]
+    fn metadata_of_another_type_or_version_is_reported() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        fs::write(dir.path().join(layout::METADATA_FILE), "format_version 1\ntype u64\n").unwrap();
+        assert_eq!(
+            vec![Finding::OutdatedMetadata { version: 1 }],
+            check(dir.path(), VerifyLevel::Fast, TYPE, false).findings
+        );
+        assert_eq!(
+            vec![Finding::ForeignMetadata],
+            check(dir.path(), VerifyLevel::Fast, "String", false).findings
+        );
+    }
+
+    #[test]
+    fn repair_on_open_cuts_the_torn_tail() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let root = dir.path().join("verify");
+        {
+            let (mut snd, _rcv) = ChannelBuilder::new("verify", dir.path())
+                .build::<u64>()
+                .unwrap();
+            snd.send(1);
+            snd.flush().unwrap();
+        }
+        let mut fp = fs::OpenOptions::new()
+            .append(true)
+            .open(root.join("0"))
+            .unwrap();
+        fp.write_all(&[0, 0, 0, 9, 1]).unwrap();
+        drop(fp);
+
+        let (_snd, rcv) = ChannelBuilder::new("verify", dir.path())
+            .verify_on_open(VerifyLevel::Fast)
+            .repair_on_open(true)
+            .build::<u64>()
+            .unwrap();
+        let report = rcv.integrity_report().unwrap();
+        assert!(report.is_damaged());
+        let repair = report.repair.as_ref().unwrap();
+        assert_eq!(5, repair.lost_bytes());
+        drop((_snd, rcv));
+
+        let (_snd, rcv) = ChannelBuilder::new("verify", dir.path())
+            .verify_on_open(VerifyLevel::Full)
+            .build::<u64>()
+            .unwrap();
+        assert!(rcv.integrity_report().unwrap().is_clean());
+    }
+}
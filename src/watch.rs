@@ -0,0 +1,317 @@
+//! Waking a `ProcessReceiver` as its channel directory changes
+//!
+//! With the `watch` feature a Receiver that has read everything waits on the
+//! operating system's file-change notification rather than sleeping between
+//! polls: inotify on Linux, kqueue on macOS and the BSDs and directory change
+//! notification on Windows. It wakes as the Sender appends to, seals or
+//! creates a queue file. Elsewhere, without the feature or where the watch
+//! cannot be set up, it polls every `POLL_INTERVAL`. A wake says only that
+//! something may have changed; the Receiver reads to find out what.
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// How long a Receiver without a watch sleeps between polls
+pub const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A watch on a channel directory, or the polling that stands in for one
+#[derive(Debug)]
+pub struct Watcher {
+    watch: Option<imp::Watch>,
+}
+
+impl Watcher {
+    /// Watch the channel directory `root`, falling back to polling if the
+    /// platform or the feature set has no watch to offer
+    pub fn new(root: &Path) -> Watcher {
+        Watcher {
+            watch: imp::Watch::new(root).ok(),
+        }
+    }
+
+    /// Whether the directory is watched rather than polled
+    #[cfg(test)]
+    pub fn is_watching(&self) -> bool {
+        self.watch.is_some()
+    }
+
+    /// Wait up to `timeout` for a change to the directory or to `current`,
+    /// the queue file being read
+    ///
+    /// Changes made since the last wait, while the Receiver was reading,
+    /// end the wait at once, so none is missed between a read finding
+    /// nothing and the wait beginning. A watch that fails is given up for
+    /// polling.
+    pub fn wait(&mut self, current: &Path, timeout: Duration) {
+        if let Some(ref mut watch) = self.watch {
+            if watch.wait(current, timeout).is_ok() {
+                return;
+            }
+        } else {
+            thread::sleep(::std::cmp::min(POLL_INTERVAL, timeout));
+            return;
+        }
+        self.watch = None;
+    }
+}
+
+#[cfg(all(feature = "watch", target_os = "linux"))]
+mod imp {
+    use rustix::event::{poll, PollFd, PollFlags};
+    use rustix::fd::OwnedFd;
+    use rustix::fs::inotify::{inotify_add_watch, inotify_init, CreateFlags, WatchFlags};
+    use rustix::io::{read, Errno};
+    use std::cmp;
+    use std::io;
+    use std::path::Path;
+    use std::time::Duration;
+
+    // An inotify instance watching the directory. Events on the files in a
+    // watched directory are reported on the directory, so one watch covers
+    // every queue file.
+    #[derive(Debug)]
+    pub struct Watch {
+        fd: OwnedFd,
+    }
+
+    impl Watch {
+        pub fn new(root: &Path) -> io::Result<Watch> {
+            let fd = inotify_init(CreateFlags::CLOEXEC | CreateFlags::NONBLOCK)?;
+            // Appends, seals--a change of permissions--and new files.
+            let flags = WatchFlags::MODIFY | WatchFlags::ATTRIB | WatchFlags::CREATE
+                | WatchFlags::MOVED_TO;
+            inotify_add_watch(&fd, root, flags)?;
+            Ok(Watch { fd: fd })
+        }
+
+        pub fn wait(&mut self, _current: &Path, timeout: Duration) -> io::Result<()> {
+            let millis = cmp::min(timeout.as_millis(), i32::MAX as u128) as i32;
+            let mut fds = [PollFd::new(&self.fd, PollFlags::IN)];
+            match poll(&mut fds, millis) {
+                Ok(_) | Err(Errno::INTR) => {}
+                Err(e) => return Err(e.into()),
+            }
+            // The events are not needed, only that there were some.
+            let mut buf = [0; 4096];
+            loop {
+                match read(&self.fd, &mut buf) {
+                    Ok(0) | Err(Errno::AGAIN) => return Ok(()),
+                    Ok(_) | Err(Errno::INTR) => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(feature = "watch",
+          any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+              target_os = "netbsd", target_os = "openbsd", target_os = "dragonfly")))]
+mod imp {
+    use rustix::event::kqueue::{kevent, kqueue, Event, EventFilter, EventFlags, VnodeEvents};
+    use rustix::fd::{AsRawFd, OwnedFd};
+    use std::fs;
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    // A kqueue watching the directory, for files created in it, and the
+    // queue file being read, for appends and its seal. kqueue reports only
+    // on what it has a descriptor for, so the queue file's watch moves with
+    // the Receiver.
+    #[derive(Debug)]
+    pub struct Watch {
+        queue: OwnedFd,
+        dir: fs::File,
+        current: Option<(PathBuf, fs::File)>,
+    }
+
+    impl Watch {
+        pub fn new(root: &Path) -> io::Result<Watch> {
+            let queue = kqueue()?;
+            let dir = fs::File::open(root)?;
+            let watch = Watch {
+                queue: queue,
+                dir: dir,
+                current: None,
+            };
+            watch.register(watch.dir.as_raw_fd())?;
+            Ok(watch)
+        }
+
+        // Add a watch on the open descriptor `fd`, removed by the kernel as
+        // it is closed.
+        fn register(&self, fd: i32) -> io::Result<()> {
+            let flags = VnodeEvents::WRITE | VnodeEvents::EXTEND | VnodeEvents::ATTRIBUTES;
+            let change = Event::new(
+                EventFilter::Vnode {
+                    vnode: fd,
+                    flags: flags,
+                },
+                EventFlags::ADD | EventFlags::CLEAR,
+                0,
+            );
+            let mut events = Vec::new();
+            // SAFETY: `fd` belongs to a file this Watch keeps open for as
+            // long as the registration lasts.
+            #[allow(unsafe_code)]
+            unsafe {
+                kevent(&self.queue, &[change], &mut events, Some(Duration::new(0, 0)))?;
+            }
+            Ok(())
+        }
+
+        pub fn wait(&mut self, current: &Path, timeout: Duration) -> io::Result<()> {
+            let moved = self.current.as_ref().map_or(true, |&(ref path, _)| path != current);
+            if moved {
+                // A queue file not yet created is picked up by the watch on
+                // the directory.
+                self.current = None;
+                if let Ok(fp) = fs::File::open(current) {
+                    let fd = fp.as_raw_fd();
+                    self.current = Some((current.to_path_buf(), fp));
+                    self.register(fd)?;
+                }
+            }
+            let mut events = Vec::with_capacity(4);
+            // SAFETY: every registered descriptor is still open, see
+            // `register`.
+            #[allow(unsafe_code)]
+            unsafe {
+                kevent(&self.queue, &[], &mut events, Some(timeout))?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(all(feature = "watch", windows))]
+mod imp {
+    use std::io;
+    use std::iter;
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::Path;
+    use std::time::Duration;
+    use windows_sys::Win32::Foundation::{HANDLE, INVALID_HANDLE_VALUE, WAIT_FAILED};
+    use windows_sys::Win32::Storage::FileSystem::{FindCloseChangeNotification,
+                                                  FindFirstChangeNotificationW,
+                                                  FindNextChangeNotification,
+                                                  FILE_NOTIFY_CHANGE_ATTRIBUTES,
+                                                  FILE_NOTIFY_CHANGE_FILE_NAME,
+                                                  FILE_NOTIFY_CHANGE_LAST_WRITE,
+                                                  FILE_NOTIFY_CHANGE_SIZE};
+    use windows_sys::Win32::System::Threading::WaitForSingleObject;
+
+    // A change notification handle on the directory, which is signalled as
+    // its files are created, grow or change attributes. The handle is kept
+    // as an integer so that the Receiver holding it stays `Send`.
+    #[derive(Debug)]
+    pub struct Watch {
+        handle: isize,
+    }
+
+    impl Watch {
+        pub fn new(root: &Path) -> io::Result<Watch> {
+            let wide: Vec<u16> = root.as_os_str().encode_wide().chain(iter::once(0)).collect();
+            let filter = FILE_NOTIFY_CHANGE_FILE_NAME | FILE_NOTIFY_CHANGE_SIZE
+                | FILE_NOTIFY_CHANGE_LAST_WRITE | FILE_NOTIFY_CHANGE_ATTRIBUTES;
+            // SAFETY: `wide` is a NUL-terminated path that outlives the call.
+            #[allow(unsafe_code)]
+            let handle = unsafe { FindFirstChangeNotificationW(wide.as_ptr(), 0, filter) };
+            if handle == INVALID_HANDLE_VALUE {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Watch {
+                handle: handle as isize,
+            })
+        }
+
+        pub fn wait(&mut self, _current: &Path, timeout: Duration) -> io::Result<()> {
+            let millis = ::std::cmp::min(timeout.as_millis(), u128::from(u32::MAX - 1));
+            let handle = self.handle as HANDLE;
+            // SAFETY: the handle is open until this Watch is dropped.
+            #[allow(unsafe_code)]
+            let waited = unsafe { WaitForSingleObject(handle, millis as u32) };
+            if waited == WAIT_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            // Rearm the notification for the next wait. A change before then
+            // signals it again.
+            // SAFETY: as above.
+            #[allow(unsafe_code)]
+            let rearmed = unsafe { FindNextChangeNotification(handle) };
+            if rearmed == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for Watch {
+        fn drop(&mut self) {
+            // SAFETY: the handle is open and closed only here.
+            #[allow(unsafe_code)]
+            unsafe {
+                FindCloseChangeNotification(self.handle as HANDLE);
+            }
+        }
+    }
+}
+
+#[cfg(not(all(feature = "watch",
+              any(target_os = "linux", target_os = "macos", target_os = "ios",
+                  target_os = "freebsd", target_os = "netbsd", target_os = "openbsd",
+                  target_os = "dragonfly", windows))))]
+mod imp {
+    use std::io;
+    use std::path::Path;
+    use std::time::Duration;
+
+    // No watch is to be had here: the Receiver polls.
+    #[derive(Debug)]
+    pub enum Watch {}
+
+    impl Watch {
+        pub fn new(_root: &Path) -> io::Result<Watch> {
+            Err(io::Error::other("directory watches are unsupported"))
+        }
+
+        pub fn wait(&mut self, _current: &Path, _timeout: Duration) -> io::Result<()> {
+            match *self {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::Watcher;
+    use std::fs;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn wait_ends_at_a_change_or_the_timeout() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let current = dir.path().join("0");
+        fs::write(&current, b"").unwrap();
+        let mut watcher = Watcher::new(dir.path());
+
+        let started = Instant::now();
+        watcher.wait(&current, Duration::from_millis(20));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let path = current.clone();
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            fs::write(&path, b"appended").unwrap();
+        });
+        let started = Instant::now();
+        watcher.wait(&current, Duration::from_secs(5));
+        if watcher.is_watching() {
+            assert!(started.elapsed() < Duration::from_secs(5));
+        }
+        writer.join().unwrap();
+    }
+}
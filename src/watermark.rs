@@ -0,0 +1,136 @@
+//! High and low watermarks on a channel's depth
+//!
+//! A channel built with `ChannelBuilder::watermarks` or
+//! `ChannelBuilder::byte_watermarks` raises `QueueEvent::HighWatermark` once
+//! its depth reaches a high mark and `QueueEvent::LowWatermark` once it has
+//! fallen back to the low marks, so producers can be throttled without
+//! polling the channel's metrics. The gap between the marks keeps a depth
+//! hovering around one of them from raising a stream of events.
+use event::QueueEvent;
+
+/// The watermarks set on a channel and which side of them it is on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Watermarks {
+    /// The (high, low) marks on items sent but not yet received
+    pub items: Option<(usize, usize)>,
+    /// The (high, low) marks on bytes held in queue files
+    pub bytes: Option<(u64, u64)>,
+    above: bool,
+}
+
+impl Watermarks {
+    /// Whether any watermark is set
+    pub fn is_set(&self) -> bool {
+        self.items.is_some() || self.bytes.is_some()
+    }
+
+    /// Note the channel's depth, returning the event to raise if it has
+    /// crossed a watermark
+    ///
+    /// The channel goes above its watermarks when either depth reaches its
+    /// high mark and back below once every depth is at or under its low mark.
+    pub fn cross(&mut self, items: usize, disk_bytes: u64) -> Option<QueueEvent> {
+        if !self.above {
+            let high = self.items.is_some_and(|(high, _)| items >= high)
+                || self.bytes.is_some_and(|(high, _)| disk_bytes >= high);
+            if high {
+                self.above = true;
+                return Some(QueueEvent::HighWatermark {
+                    items: items,
+                    disk_bytes: disk_bytes,
+                });
+            }
+        } else {
+            let low = self.items.is_none_or(|(_, low)| items <= low)
+                && self.bytes.is_none_or(|(_, low)| disk_bytes <= low);
+            if low {
+                self.above = false;
+                return Some(QueueEvent::LowWatermark {
+                    items: items,
+                    disk_bytes: disk_bytes,
+                });
+            }
+        }
+        None
+    }
+
+    /// How far the channel's depth has climbed from its low marks towards
+    /// its high marks, from 0 at or under every low mark to 1 at or over a
+    /// high mark, by the depth that has climbed furthest
+    ///
+    /// 0 if no watermark is set. Used by adaptive sampling.
+    pub fn pressure(&self, items: usize, disk_bytes: u64) -> f64 {
+        fn climbed(depth: f64, high: f64, low: f64) -> f64 {
+            if depth >= high {
+                1.0
+            } else if depth <= low {
+                0.0
+            } else {
+                (depth - low) / (high - low)
+            }
+        }
+        let items = self.items.map_or(0.0, |(high, low)| {
+            climbed(items as f64, high as f64, low as f64)
+        });
+        let bytes = self.bytes.map_or(0.0, |(high, low)| {
+            climbed(disk_bytes as f64, high as f64, low as f64)
+        });
+        items.max(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn crossings_are_reported_once() {
+        let mut marks = Watermarks {
+            items: Some((10, 2)),
+            ..Watermarks::default()
+        };
+        assert_eq!(None, marks.cross(9, 0));
+        assert_eq!(
+            Some(QueueEvent::HighWatermark {
+                items: 10,
+                disk_bytes: 0,
+            }),
+            marks.cross(10, 0)
+        );
+        assert_eq!(None, marks.cross(11, 0));
+        assert_eq!(None, marks.cross(3, 0));
+        assert_eq!(
+            Some(QueueEvent::LowWatermark {
+                items: 2,
+                disk_bytes: 0,
+            }),
+            marks.cross(2, 0)
+        );
+        assert_eq!(None, marks.cross(0, 0));
+    }
+
+    #[test]
+    fn every_depth_must_fall_to_its_low_mark() {
+        let mut marks = Watermarks {
+            items: Some((10, 2)),
+            bytes: Some((100, 50)),
+            above: false,
+        };
+        assert!(marks.cross(0, 100).is_some());
+        assert_eq!(None, marks.cross(0, 60));
+        assert_eq!(None, marks.cross(20, 10));
+        assert!(marks.cross(1, 10).is_some());
+    }
+
+    #[test]
+    fn pressure_climbs_from_low_to_high() {
+        let mut marks = Watermarks::default();
+        assert_eq!(0.0, marks.pressure(1_000, 1_000));
+        marks.items = Some((10, 2));
+        assert_eq!(0.0, marks.pressure(2, 0));
+        assert_eq!(0.5, marks.pressure(6, 0));
+        assert_eq!(1.0, marks.pressure(12, 0));
+        marks.bytes = Some((100, 0));
+        assert_eq!(0.75, marks.pressure(6, 75));
+    }
+}
@@ -0,0 +1,233 @@
+use super::Receiver;
+use serde::de::DeserializeOwned;
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use sync::{self, Mutex};
+
+// How long a waiting Worker holds the Receiver before looking for leases
+// handed back by other Workers.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+struct Shared<T> {
+    rcv: Mutex<Receiver<T>>,
+    // Items whose leases were dropped unacknowledged, delivered again first
+    returned: Mutex<VecDeque<T>>,
+    in_flight: AtomicUsize,
+    redelivered: AtomicU64,
+}
+
+/// One of several consumers competing for the items of one channel
+///
+/// Created by `Receiver::into_worker`. Clone a `Worker` for each thread that
+/// should take items: each item goes to whichever Worker asks first, so items
+/// are not handled in the order they were sent. An item is handed out as a
+/// `Lease`, acknowledged once handled. A lease dropped unacknowledged--as when
+/// the thread holding it panics--returns its item to the channel, to be handed
+/// out again ahead of the rest, so no item is lost to a worker dying partway
+/// through. An item may therefore be handled more than once.
+///
+/// Leases live only as long as the process, as the channel's in-memory items
+/// do.
+pub struct Worker<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> fmt::Debug for Worker<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Worker")
+            .field("in_flight", &self.in_flight())
+            .field("redelivered", &self.redelivered())
+            .finish()
+    }
+}
+
+impl<T> Clone for Worker<T> {
+    fn clone(&self) -> Worker<T> {
+        Worker {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> Worker<T> {
+    pub(crate) fn new(rcv: Receiver<T>) -> Worker<T> {
+        Worker {
+            shared: Arc::new(Shared {
+                rcv: Mutex::new(rcv),
+                returned: Mutex::new(VecDeque::new()),
+                in_flight: AtomicUsize::new(0),
+                redelivered: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// The number of items leased out and not yet acknowledged
+    pub fn in_flight(&self) -> usize {
+        self.shared.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// The number of items handed back by unacknowledged leases
+    pub fn redelivered(&self) -> u64 {
+        self.shared.redelivered.load(Ordering::Relaxed)
+    }
+
+    /// Hand back the channel's Receiver, if this is the last Worker
+    ///
+    /// Items handed back by unacknowledged leases are lost. Returns the
+    /// Worker if others remain.
+    pub fn into_receiver(self) -> Result<Receiver<T>, Worker<T>> {
+        match Arc::try_unwrap(self.shared) {
+            Ok(shared) => Ok(sync::into_inner(shared.rcv)),
+            Err(shared) => Err(Worker { shared: shared }),
+        }
+    }
+
+    fn lease(&self, item: T) -> Lease<T> {
+        self.shared.in_flight.fetch_add(1, Ordering::Relaxed);
+        Lease {
+            shared: Arc::clone(&self.shared),
+            item: Some(item),
+        }
+    }
+}
+
+impl<T> Worker<T>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    /// Lease the next item, if one is waiting
+    pub fn try_take(&self) -> Option<Lease<T>> {
+        let returned = sync::lock(&self.shared.returned).pop_front();
+        let item = match returned {
+            Some(item) => Some(item),
+            None => sync::lock(&self.shared.rcv).recv_batch(1, Duration::from_millis(0)).pop(),
+        };
+        item.map(|item| self.lease(item))
+    }
+
+    /// Lease the next item, waiting up to `timeout` for one
+    pub fn take_timeout(&self, timeout: Duration) -> Option<Lease<T>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(lease) = self.try_take() {
+                return Some(lease);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            let wait = if deadline - now < POLL_INTERVAL {
+                deadline - now
+            } else {
+                POLL_INTERVAL
+            };
+            let item = sync::lock(&self.shared.rcv).recv_batch(1, wait).pop();
+            if let Some(item) = item {
+                return Some(self.lease(item));
+            }
+            // Give Workers waiting on the Receiver a turn at it.
+            thread::yield_now();
+        }
+    }
+}
+
+/// An item leased to a `Worker`, returned to the channel unless acknowledged
+///
+/// Returned by `Worker::try_take` and `Worker::take_timeout`. Dereferences to
+/// the item.
+pub struct Lease<T> {
+    shared: Arc<Shared<T>>,
+    // Taken by `ack`
+    item: Option<T>,
+}
+
+impl<T> fmt::Debug for Lease<T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Lease").field("item", &self.item).finish()
+    }
+}
+
+impl<T> Lease<T> {
+    /// Acknowledge the item as handled, taking it out of the channel for good
+    pub fn ack(mut self) -> T {
+        self.item.take().expect("leased item already acknowledged")
+    }
+}
+
+impl<T> Deref for Lease<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.item.as_ref().expect("leased item already acknowledged")
+    }
+}
+
+impl<T> Drop for Lease<T> {
+    fn drop(&mut self) {
+        self.shared.in_flight.fetch_sub(1, Ordering::Relaxed);
+        if let Some(item) = self.item.take() {
+            self.shared.redelivered.fetch_add(1, Ordering::Relaxed);
+            sync::lock(&self.shared.returned).push_back(item);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::super::channel_with_max_bytes;
+    use std::collections::BTreeSet;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn workers_share_items_and_take_back_abandoned_leases() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, rcv) = channel_with_max_bytes::<u64>("work", dir.path(), 1024).unwrap();
+        for i in 0..4000 {
+            snd.send(i);
+        }
+        let worker = rcv.into_worker();
+
+        // A worker dying mid-item hands it back
+        let dying = worker.clone();
+        let died = thread::spawn(move || {
+            let _lease = dying.try_take().unwrap();
+            panic!("worker died");
+        });
+        assert!(died.join().is_err());
+        assert_eq!(1, worker.redelivered());
+        assert_eq!(0, worker.in_flight());
+
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let joins: Vec<_> = (0..4)
+            .map(|_| {
+                let worker = worker.clone();
+                let handled = Arc::clone(&handled);
+                thread::spawn(move || {
+                    while let Some(lease) = worker.take_timeout(Duration::from_millis(100)) {
+                        handled.lock().unwrap().push(lease.ack());
+                    }
+                })
+            })
+            .collect();
+        for jh in joins {
+            jh.join().unwrap();
+        }
+        let handled = handled.lock().unwrap();
+        assert_eq!(4000, handled.len());
+        let distinct: BTreeSet<u64> = handled.iter().cloned().collect();
+        assert_eq!((0..4000).collect::<BTreeSet<u64>>(), distinct);
+        assert!(worker.into_receiver().is_ok());
+    }
+}
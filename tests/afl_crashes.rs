@@ -52,7 +52,7 @@ mod integration {
                 let dur = time::Duration::from_millis(1);
                 for _ in 0..100 {
                     thread::sleep(dur);
-                    for i in rcv.iter() {
+                    for i in rcv.try_iter() {
                         count += 1;
                         if max_thrs == 1 {
                             assert_eq!(i, nxt);
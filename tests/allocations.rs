@@ -0,0 +1,64 @@
+// Checks that paging items out to disk does not allocate once a channel has
+// warmed up. The counting allocator only counts on threads that ask it to, so
+// the test harness's own allocations do not interfere.
+mod integration {
+    extern crate hopper;
+    extern crate tempdir;
+
+    use self::hopper::channel_with_max_bytes;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Counting;
+
+    static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+    thread_local! {
+        static COUNTING: Cell<bool> = const { Cell::new(false) };
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            if COUNTING.with(|c| c.get()) {
+                ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            }
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            if COUNTING.with(|c| c.get()) {
+                ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            }
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: Counting = Counting;
+
+    #[test]
+    fn steady_state_spills_do_not_allocate() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, _rcv) = channel_with_max_bytes("alloc", dir.path(), 1 << 30).unwrap();
+
+        // Fill the in-memory tier and page out one batch to warm up.
+        for i in 0..2048u64 {
+            snd.send(i);
+        }
+        assert_eq!(1, snd.metrics().spill_events);
+
+        COUNTING.with(|c| c.set(true));
+        for i in 0..(4 * 1024u64) {
+            snd.send(i);
+        }
+        COUNTING.with(|c| c.set(false));
+
+        assert_eq!(5, snd.metrics().spill_events);
+        assert_eq!(0, ALLOCATIONS.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,82 @@
+// Kills a writer process partway through and checks what its cross-process
+// channel holds afterwards, through the harness in `hopper::testing`. Each
+// test is run twice: once as the parent and once, in the child, as the
+// writer being killed.
+#![cfg(feature = "harness")]
+mod integration {
+    extern crate hopper;
+    extern crate tempdir;
+
+    use self::hopper::process;
+    use self::hopper::testing::{self, Faults};
+
+    fn drain(name: &str, dir: &::std::path::Path) -> Vec<u64> {
+        let mut rcv = process::receiver::<u64>(name, dir).unwrap();
+        let mut items = Vec::new();
+        while let Some(i) = rcv.try_recv().unwrap() {
+            items.push(i);
+        }
+        items
+    }
+
+    #[test]
+    fn killed_writer_leaves_what_it_sent() {
+        if let Some(dir) = testing::child_dir() {
+            let mut snd = process::sender::<u64>("killed", &dir, 1024).unwrap();
+            for i in 0..2000 {
+                snd.send(i);
+            }
+            testing::crash_point("sent");
+            for i in 2000..4000 {
+                snd.send(i);
+            }
+            return;
+        }
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let test = "integration::killed_writer_leaves_what_it_sent";
+        testing::kill_child_at(test, dir.path(), "sent").unwrap();
+
+        let sent: Vec<u64> = (0..2000).collect();
+        testing::assert_recovered(&sent, &drain("killed", dir.path()), 2000);
+
+        // The directory's lock died with the writer, so another may open it.
+        let mut snd = process::sender::<u64>("killed", dir.path(), 1024).unwrap();
+        snd.send(2000);
+        assert_eq!(vec![2000], drain("killed", dir.path()));
+    }
+
+    #[test]
+    fn short_writes_are_recovered_whole() {
+        if let Some(dir) = testing::child_dir() {
+            let mut short = Faults::default();
+            short.short_writes = Some(3);
+            let (mut snd, _) = testing::faulty_sender::<u64>("short", &dir, 256, short).unwrap();
+            for i in 0..500 {
+                snd.send(i);
+            }
+            testing::crash_point("sent");
+            return;
+        }
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let test = "integration::short_writes_are_recovered_whole";
+        testing::kill_child_at(test, dir.path(), "sent").unwrap();
+        let sent: Vec<u64> = (0..500).collect();
+        testing::assert_recovered(&sent, &drain("short", dir.path()), 500);
+
+        // Run to the end the child passes over the crash point.
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        assert!(testing::run_child(test, dir.path()).unwrap().success());
+        assert_eq!(sent, drain("short", dir.path()));
+    }
+
+    #[test]
+    fn missing_crash_point_is_an_error() {
+        if testing::child_dir().is_some() {
+            return;
+        }
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let test = "integration::missing_crash_point_is_an_error";
+        let err = testing::kill_child_at(test, dir.path(), "never").unwrap_err();
+        assert_eq!(::std::io::ErrorKind::UnexpectedEof, err.kind());
+    }
+}
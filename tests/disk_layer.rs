@@ -0,0 +1,60 @@
+// Exercises the parts of the disk layer that differ between platforms:
+// rotating queue files, deleting them once consumed and clearing out stale
+// files on reopen. Keep these free of unix-only assumptions so they run on
+// Windows too.
+mod integration {
+    extern crate hopper;
+    extern crate tempdir;
+
+    use self::hopper::channel_with_max_bytes;
+    use std::fs;
+    use std::path::Path;
+
+    fn segments(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|de| {
+                de.as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_str()
+                    .is_some_and(|n| n.parse::<usize>().is_ok())
+            })
+            .count()
+    }
+
+    #[test]
+    fn consumed_segments_are_deleted() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) =
+            channel_with_max_bytes("consumed_segments", dir.path(), 128).unwrap();
+
+        for i in 0..4096u64 {
+            snd.send(i);
+        }
+        assert!(segments(&dir.path().join("consumed_segments")) > 1);
+        for i in 0..4096u64 {
+            assert_eq!(Some(i), rcv.try_iter().next());
+        }
+        assert_eq!(None, rcv.try_iter().next());
+        assert_eq!(1, segments(&dir.path().join("consumed_segments")));
+    }
+
+    #[test]
+    fn reopen_clears_sealed_segments() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        {
+            let (mut snd, _rcv) =
+                channel_with_max_bytes("reopen_segments", dir.path(), 128).unwrap();
+            for i in 0..4096u64 {
+                snd.send(i);
+            }
+        }
+        let (mut snd, mut rcv) =
+            channel_with_max_bytes("reopen_segments", dir.path(), 128).unwrap();
+        assert_eq!(1, segments(&dir.path().join("reopen_segments")));
+
+        snd.send(7u64);
+        assert_eq!(Some(7), rcv.try_iter().next());
+    }
+}
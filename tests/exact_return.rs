@@ -14,7 +14,7 @@ mod integration {
         let (mut snd, mut rcv) =
             channel_with_max_bytes("zero_item_round_trip", dir.path(), 1_048_576).unwrap();
 
-        assert_eq!(None, rcv.iter().next());
+        assert_eq!(None, rcv.try_iter().next());
 
         let max = 10;
 
@@ -23,7 +23,7 @@ mod integration {
         }
 
         let mut count = 0;
-        for (nxt, i) in rcv.iter().enumerate() {
+        for (nxt, i) in rcv.try_iter().enumerate() {
             count += 1;
             assert_eq!(i, nxt);
         }
@@ -54,7 +54,7 @@ mod integration {
                 let dur = time::Duration::from_millis(1);
                 for _ in 0..250 {
                     thread::sleep(dur);
-                    for _ in rcv.iter() {
+                    for _ in rcv.try_iter() {
                         count += 1;
                     }
                 }
@@ -105,7 +105,7 @@ mod integration {
             let dur = time::Duration::from_millis(10);
             for _ in 0..250 {
                 thread::sleep(dur);
-                for _ in rcv.iter() {
+                for _ in rcv.try_iter() {
                     count += 1;
                 }
             }
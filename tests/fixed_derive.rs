@@ -0,0 +1,54 @@
+#![cfg(feature = "derive")]
+
+extern crate hopper;
+extern crate tempdir;
+
+use hopper::fixed::{FixedCodec, FixedLayout};
+
+#[derive(Debug, Clone, Copy, PartialEq, FixedLayout)]
+struct Quote {
+    #[doc(hidden)]
+    pub symbol: [u8; 4],
+    pub(crate) bid: f64,
+    ask: f64,
+    size: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, FixedLayout)]
+struct Pair(u16, pub i64);
+
+#[derive(Debug, Clone, Copy, PartialEq, FixedLayout)]
+struct Marker;
+
+#[test]
+fn derived_layouts_pack_fields_in_order() {
+    assert_eq!(4 + 8 + 8 + 4, Quote::SIZE);
+    assert_eq!(10, Pair::SIZE);
+    assert_eq!(0, Marker::SIZE);
+
+    let pair = Pair(0x0102, -1);
+    let mut buf = [0; 10];
+    pair.encode(&mut buf);
+    assert_eq!([0x02, 0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff], buf);
+    assert_eq!(pair, Pair::decode(&buf));
+    assert_eq!(Marker, Marker::decode(&[]));
+}
+
+#[test]
+fn derived_layouts_cross_the_disk_tier() {
+    let dir = tempdir::TempDir::new("hopper").unwrap();
+    let (mut snd, mut rcv) =
+        hopper::channel_with_max_bytes("fixed_derive", dir.path(), 1024).unwrap();
+    let quote = |i: u32| Quote {
+        symbol: *b"HOPR",
+        bid: f64::from(i),
+        ask: f64::from(i) + 0.5,
+        size: i,
+    };
+    for i in 0..3000 {
+        snd.send(FixedCodec(quote(i)));
+    }
+    for i in 0..3000 {
+        assert_eq!(Some(FixedCodec(quote(i))), rcv.try_iter().next());
+    }
+}
@@ -0,0 +1,111 @@
+// Opens channel directories written by older hoppers, kept under
+// resources/, and checks they are read where they are while new queue files
+// are written in the current format. Each fixture is copied out before use so
+// the checked-in copy is never changed.
+mod integration {
+    extern crate hopper;
+    extern crate tempdir;
+
+    use self::hopper::{process, repair};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn fixture(version: &str, name: &str, into: &Path) -> PathBuf {
+        let mut src = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        src.push("resources");
+        src.push(version);
+        src.push(name);
+        let dest = into.join(name);
+        fs::create_dir_all(&dest).unwrap();
+        for entry in fs::read_dir(&src).unwrap() {
+            let entry = entry.unwrap();
+            fs::copy(entry.path(), dest.join(entry.file_name())).unwrap();
+        }
+        dest
+    }
+
+    fn seal(path: &Path) {
+        let mut permissions = fs::metadata(path).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(path, permissions).unwrap();
+    }
+
+    #[test]
+    fn version_1_process_channel_is_read_and_moved_on() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let root = fixture("format_v1", "xproc", dir.path());
+        // Git does not keep permissions: queue file 0 was sealed when written.
+        seal(&root.join("0"));
+
+        assert!(repair::repair(&root).unwrap().is_clean());
+        let mut rcv = process::receiver::<u64>("xproc", dir.path()).unwrap();
+        for i in 0..15u64 {
+            assert_eq!(Some(i), rcv.try_recv().unwrap());
+        }
+        assert_eq!(None, rcv.try_recv().unwrap());
+        assert_eq!(
+            "format_version 3\ntype u64\n",
+            fs::read_to_string(root.join("hopper.meta")).unwrap()
+        );
+
+        let mut snd = process::sender::<u64>("xproc", dir.path(), 64).unwrap();
+        for i in 15..30u64 {
+            snd.send(i);
+        }
+        for i in 15..30u64 {
+            assert_eq!(Some(i), rcv.try_recv().unwrap());
+        }
+        assert_eq!(None, rcv.try_recv().unwrap());
+
+        // The old files are left as they were; new ones carry a header.
+        assert_eq!(&[0, 0, 0, 8], &fs::read(root.join("1")).unwrap()[..4]);
+        let newest = fs::read(root.join("2")).unwrap();
+        assert_eq!(b"HOPQ\0\0\0\x03", &newest[..8]);
+        drop(snd);
+        drop(rcv);
+        assert!(repair::repair(&root).unwrap().is_clean());
+    }
+
+    #[test]
+    fn version_1_process_channel_compacts_in_place() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let root = fixture("format_v1", "xproc", dir.path());
+        seal(&root.join("0"));
+
+        let bytes = fs::metadata(root.join("0")).unwrap().len();
+        let mut rcv = process::receiver::<u64>("xproc", dir.path()).unwrap();
+        for i in 0..4u64 {
+            assert_eq!(Some(i), rcv.try_recv().unwrap());
+        }
+        assert_eq!(4 * (4 + 8), rcv.compact());
+        assert_eq!(bytes - 4 * (4 + 8), fs::metadata(root.join("0")).unwrap().len());
+        drop(rcv);
+
+        let mut rcv = process::receiver::<u64>("xproc", dir.path()).unwrap();
+        for i in 4..15u64 {
+            assert_eq!(Some(i), rcv.try_recv().unwrap());
+        }
+        assert_eq!(None, rcv.try_recv().unwrap());
+    }
+
+    #[test]
+    fn version_2_records_report_schema_version_0() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let root = fixture("format_v2", "xproc", dir.path());
+        seal(&root.join("0"));
+
+        let mut rcv = process::receiver::<u64>("xproc", dir.path()).unwrap();
+        let mut snd = process::sender::<u64>("xproc", dir.path(), 1024).unwrap();
+        snd.set_schema_version(2);
+        snd.send(15);
+        // Records with schema versions are not appended to the open version 2
+        // file, which is sealed instead.
+        assert_eq!(b"HOPQ\0\0\0\x03", &fs::read(root.join("2")).unwrap()[..8]);
+
+        for i in 0..15u64 {
+            assert_eq!(Some((0, i.to_le_bytes().to_vec())), rcv.recv_raw());
+        }
+        assert_eq!(Some((2, 15u64.to_le_bytes().to_vec())), rcv.recv_raw());
+        assert_eq!(None, rcv.recv_raw());
+    }
+}